@@ -0,0 +1,43 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use cubestore::metastore::{Column, ColumnType, IdRow, Index};
+use cubestore::queryplanner::query_executor::{CubeTable, IndexColumnPositions};
+
+fn wide_index(columns: usize) -> IdRow<Index> {
+    let columns = (0..columns)
+        .map(|i| Column::new(format!("column_{}", i), ColumnType::Int, i))
+        .collect();
+    IdRow::new(
+        1,
+        Index::try_new("default".to_string(), 1, columns, 1).unwrap(),
+    )
+}
+
+fn project_to_index_positions(c: &mut Criterion) {
+    for width in [10, 100, 1000].iter() {
+        let index = wide_index(*width);
+        // Every other column in reverse order, half of them in upper case
+        let projection = (0..*width)
+            .rev()
+            .step_by(2)
+            .map(|i| {
+                let name = format!("column_{}", i);
+                let name = if i % 4 == 0 {
+                    name.to_uppercase()
+                } else {
+                    name
+                };
+                Column::new(name, ColumnType::Int, i)
+            })
+            .collect::<Vec<_>>();
+        c.bench_function(&format!("project_to_index_positions {}", width), |b| {
+            b.iter(|| CubeTable::project_to_index_positions(black_box(&projection), &index))
+        });
+        let positions = IndexColumnPositions::new(index.get_row());
+        c.bench_function(&format!("IndexColumnPositions::project {}", width), |b| {
+            b.iter(|| positions.project(black_box(&projection)))
+        });
+    }
+}
+
+criterion_group!(benches, project_to_index_positions);
+criterion_main!(benches);
@@ -1,8 +1,9 @@
 use cubestore::config::Config;
+use cubestore::flight::FlightServer;
 use cubestore::mysql::MySqlServer;
 use cubestore::telemetry::{track_event, ReportingLogger};
-use log::debug;
 use log::Level;
+use log::{debug, error};
 use simple_logger::SimpleLogger;
 use std::collections::HashMap;
 use std::env;
@@ -49,6 +50,21 @@ fn main() {
 
         track_event("Cube Store Start".to_string(), HashMap::new()).await;
 
+        if let Some(flight_port) = config.config_obj().flight_port() {
+            let address = format!("{}:{}", config.config_obj().bind_address(), flight_port);
+            let sql_service = services.sql_service.clone();
+            let max_result_rows = config.config_obj().flight_max_result_rows();
+            let max_result_bytes = config.config_obj().flight_max_result_bytes();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    FlightServer::listen(address, sql_service, max_result_rows, max_result_bytes)
+                        .await
+                {
+                    error!("Error in Arrow Flight server: {}", e);
+                }
+            });
+        }
+
         MySqlServer::listen(
             format!(
                 "{}:{}",
@@ -56,6 +72,7 @@ fn main() {
                 config.config_obj().bind_port()
             ),
             services.sql_service.clone(),
+            config.config_obj().max_prepared_statements(),
         )
         .await
         .unwrap();
@@ -0,0 +1,159 @@
+use crate::cluster::{Cluster, JobResultListener, SelectItem, SelectStream};
+use crate::queryplanner::serialized_plan::SerializedPlan;
+use crate::CubeError;
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use futures::future::{self, BoxFuture};
+use futures::{FutureExt, Stream};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::watch;
+
+/// Cancels a running query along with selects it sent to workers.
+pub struct QueryCancellation {
+    sender: watch::Sender<bool>,
+    receiver: watch::Receiver<bool>,
+}
+
+impl QueryCancellation {
+    pub fn new() -> QueryCancellation {
+        let (sender, receiver) = watch::channel(false);
+        QueryCancellation { sender, receiver }
+    }
+
+    pub fn cancel(&self) {
+        // Nobody waits for the query if receivers are gone
+        let _ = self.sender.broadcast(true);
+    }
+
+    /// Resolves once the query is cancelled. Never resolves otherwise.
+    pub fn cancelled(&self) -> BoxFuture<'static, ()> {
+        let mut receiver = self.receiver.clone();
+        async move {
+            while let Some(cancelled) = receiver.recv().await {
+                if cancelled {
+                    return;
+                }
+            }
+            future::pending::<()>().await
+        }
+        .boxed()
+    }
+}
+
+/// Cluster of a single query. Selects sent through it fail once the query is cancelled, so
+/// tasks running them stop and drop their worker streams even if nothing awaits them anymore.
+pub struct CancellableCluster {
+    cluster: Arc<dyn Cluster>,
+    cancellation: Arc<QueryCancellation>,
+}
+
+impl CancellableCluster {
+    pub fn new(
+        cluster: Arc<dyn Cluster>,
+        cancellation: Arc<QueryCancellation>,
+    ) -> Arc<CancellableCluster> {
+        Arc::new(CancellableCluster {
+            cluster,
+            cancellation,
+        })
+    }
+}
+
+fn cancelled_error() -> CubeError {
+    CubeError::user("Query was cancelled".to_string())
+}
+
+#[async_trait]
+impl Cluster for CancellableCluster {
+    async fn notify_job_runner(&self, node_name: String) -> Result<(), CubeError> {
+        self.cluster.notify_job_runner(node_name).await
+    }
+
+    async fn run_select(
+        &self,
+        node_name: String,
+        plan_node: SerializedPlan,
+    ) -> Result<Vec<RecordBatch>, CubeError> {
+        tokio::select! {
+            res = self.cluster.run_select(node_name, plan_node) => res,
+            _ = self.cancellation.cancelled() => Err(cancelled_error()),
+        }
+    }
+
+    async fn run_select_stream(
+        &self,
+        node_name: String,
+        plan_node: SerializedPlan,
+    ) -> Result<SelectStream, CubeError> {
+        let input = tokio::select! {
+            res = self.cluster.run_select_stream(node_name, plan_node) => res?,
+            _ = self.cancellation.cancelled() => return Err(cancelled_error()),
+        };
+        Ok(Box::pin(CancellableStream {
+            input: Some(input),
+            cancelled: self.cancellation.cancelled(),
+        }))
+    }
+
+    async fn available_nodes(&self) -> Result<Vec<String>, CubeError> {
+        self.cluster.available_nodes().await
+    }
+
+    async fn node_wire_format_version(&self, node_name: String) -> Result<u32, CubeError> {
+        self.cluster.node_wire_format_version(node_name).await
+    }
+
+    fn server_name(&self) -> &str {
+        self.cluster.server_name()
+    }
+
+    async fn download(&self, remote_path: &str) -> Result<String, CubeError> {
+        self.cluster.download(remote_path).await
+    }
+
+    async fn is_downloaded(&self, remote_path: &str) -> Result<bool, CubeError> {
+        self.cluster.is_downloaded(remote_path).await
+    }
+
+    async fn warm_up_partition(
+        &self,
+        node_name: String,
+        partition_file: String,
+        superseded_files: Vec<String>,
+    ) -> Result<(), CubeError> {
+        self.cluster
+            .warm_up_partition(node_name, partition_file, superseded_files)
+            .await
+    }
+
+    fn job_result_listener(&self) -> JobResultListener {
+        self.cluster.job_result_listener()
+    }
+}
+
+/// Ends with an error once the query is cancelled. The select stream is dropped right away.
+struct CancellableStream {
+    input: Option<SelectStream>,
+    cancelled: BoxFuture<'static, ()>,
+}
+
+impl Stream for CancellableStream {
+    type Item = Result<SelectItem, CubeError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.input.is_none() {
+            return Poll::Ready(None);
+        }
+        if self.cancelled.poll_unpin(cx).is_ready() {
+            self.input = None;
+            return Poll::Ready(Some(Err(cancelled_error())));
+        }
+        let res = self.input.as_mut().unwrap().as_mut().poll_next(cx);
+        if let Poll::Ready(None) = res {
+            self.input = None;
+        }
+        res
+    }
+}
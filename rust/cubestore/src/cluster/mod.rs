@@ -1,29 +1,38 @@
+pub mod cancellation;
+pub mod request_limiter;
 pub mod worker_pool;
 
+use crate::cluster::request_limiter::NodeRequestLimiter;
 use crate::cluster::worker_pool::{MessageProcessor, WorkerPool};
 use crate::config::{Config, ConfigObj};
 use crate::import::ImportService;
 use crate::metastore::job::{Job, JobStatus, JobType};
 use crate::metastore::{IdRow, MetaStore, RowKey, TableId};
 use crate::queryplanner::query_executor::{QueryExecutor, SerializedRecordBatchStream};
-use crate::queryplanner::serialized_plan::SerializedPlan;
+use crate::queryplanner::scan_metrics::ScanStats;
+use crate::queryplanner::serialized_plan::{SerializedPlan, WIRE_FORMAT_VERSION};
+use crate::remotefs::queue::DownloadQueue;
 use crate::remotefs::RemoteFs;
 use crate::store::compaction::CompactionService;
 use crate::store::ChunkDataStore;
 use crate::CubeError;
+use arrow::datatypes::SchemaRef;
 use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
 use chrono::Utc;
 use core::mem;
 use futures::future::join_all;
-use futures::FutureExt;
+use futures::stream;
+use futures::{FutureExt, Stream, StreamExt};
 use itertools::Itertools;
 use log::{debug, error, info};
 use mockall::automock;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::iter;
 use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 use std::time::SystemTime;
@@ -36,6 +45,16 @@ use tokio::sync::{broadcast, oneshot, Notify, RwLock};
 use tokio::time::timeout;
 use tokio::{fs, time};
 
+/// Batches of a select forwarded as they arrive from the node.
+pub type SelectStream = Pin<Box<dyn Stream<Item = Result<SelectItem, CubeError>> + Send>>;
+
+#[derive(Debug)]
+pub enum SelectItem {
+    Batch(RecordBatch),
+    /// Scans of the node's plan that produced the batches before it.
+    ScanStats(ScanStats),
+}
+
 #[automock]
 #[async_trait]
 pub trait Cluster: Send + Sync {
@@ -47,12 +66,35 @@ pub trait Cluster: Send + Sync {
         plan_node: SerializedPlan,
     ) -> Result<Vec<RecordBatch>, CubeError>;
 
+    /// Same as `run_select` but batches are yielded one by one so the caller doesn't have to
+    /// hold the whole result of the node.
+    async fn run_select_stream(
+        &self,
+        node_name: String,
+        plan_node: SerializedPlan,
+    ) -> Result<SelectStream, CubeError>;
+
     async fn available_nodes(&self) -> Result<Vec<String>, CubeError>;
 
+    /// Wire format version of SerializedPlan and SerializedRecordBatchStream supported by the node.
+    async fn node_wire_format_version(&self, node_name: String) -> Result<u32, CubeError>;
+
     fn server_name(&self) -> &str;
 
     async fn download(&self, remote_path: &str) -> Result<String, CubeError>;
 
+    /// Whether `remote_path` has a local copy on this node and can be scanned without a download.
+    async fn is_downloaded(&self, remote_path: &str) -> Result<bool, CubeError>;
+
+    /// Makes the node download `partition_file` of a partition created by compaction ahead of
+    /// queries and drop its local copies of `superseded_files` the partition replaced.
+    async fn warm_up_partition(
+        &self,
+        node_name: String,
+        partition_file: String,
+        superseded_files: Vec<String>,
+    ) -> Result<(), CubeError>;
+
     fn job_result_listener(&self) -> JobResultListener;
 }
 
@@ -82,6 +124,8 @@ pub struct ClusterImpl {
     >,
     config_obj: Arc<dyn ConfigObj>,
     query_executor: Arc<dyn QueryExecutor>,
+    download_queue: Arc<DownloadQueue>,
+    select_limiter: Arc<NodeRequestLimiter>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -91,24 +135,77 @@ pub enum WorkerMessage {
 
 pub struct WorkerProcessor;
 
+impl WorkerProcessor {
+    fn run_select(
+        plan_node: SerializedPlan,
+        remote_to_local_names: HashMap<String, String>,
+    ) -> Result<(SchemaRef, Vec<RecordBatch>, ScanStats), CubeError> {
+        plan_node.check_format_version()?;
+        debug!("Running select in worker started: {:?}", plan_node);
+        let handle = Handle::current();
+        let plan_node_to_send = plan_node.clone();
+        let res = handle.block_on(async move {
+            Config::current_worker_services()
+                .query_executor
+                .execute_worker_plan(plan_node_to_send, remote_to_local_names)
+                .await
+        });
+        debug!("Running select in worker completed: {:?}", plan_node);
+        res
+    }
+}
+
 impl MessageProcessor<WorkerMessage, SerializedRecordBatchStream> for WorkerProcessor {
     fn process(args: WorkerMessage) -> Result<SerializedRecordBatchStream, CubeError> {
         match args {
             WorkerMessage::Select(plan_node, remote_to_local_names) => {
-                debug!("Running select in worker started: {:?}", plan_node);
-                let handle = Handle::current();
-                let plan_node_to_send = plan_node.clone();
-                let res = handle.block_on(async move {
-                    Config::current_worker_services()
-                        .query_executor
-                        .execute_worker_plan(plan_node_to_send, remote_to_local_names)
-                        .await
-                });
-                debug!("Running select in worker completed: {:?}", plan_node);
-                SerializedRecordBatchStream::write(res?)
+                let format_version = plan_node.format_version();
+                let (schema, batches, scan_stats) =
+                    Self::run_select(plan_node, remote_to_local_names)?;
+                Ok(
+                    SerializedRecordBatchStream::write(&schema, batches, format_version)?
+                        .with_scan_stats(scan_stats),
+                )
             }
         }
     }
+
+    /// Batches of a select are sent one per part, so the router doesn't receive the whole
+    /// result at once. Scan stats come in the last part which has no batches.
+    fn process_parts(
+        args: WorkerMessage,
+        send: &mut dyn FnMut(SerializedRecordBatchStream) -> Result<(), CubeError>,
+    ) -> Result<(), CubeError> {
+        match args {
+            WorkerMessage::Select(plan_node, remote_to_local_names) => {
+                let format_version = plan_node.format_version();
+                let (schema, batches, scan_stats) =
+                    Self::run_select(plan_node, remote_to_local_names)?;
+                for batch in batches {
+                    send(SerializedRecordBatchStream::write(
+                        &schema,
+                        vec![batch],
+                        format_version,
+                    )?)?;
+                }
+                send(
+                    SerializedRecordBatchStream::write(&schema, Vec::new(), format_version)?
+                        .with_scan_stats(scan_stats),
+                )
+            }
+        }
+    }
+
+    /// Plans are sent in the version negotiated by the router.
+    fn args_version(args: &WorkerMessage) -> u32 {
+        match args {
+            WorkerMessage::Select(plan_node, _) => plan_node.format_version(),
+        }
+    }
+
+    fn part_version(part: &SerializedRecordBatchStream) -> u32 {
+        part.format_version()
+    }
 }
 
 pub struct JobRunner {
@@ -143,9 +240,38 @@ impl Cluster for ClusterImpl {
         node_name: String,
         plan_node: SerializedPlan,
     ) -> Result<Vec<RecordBatch>, CubeError> {
+        // TODO timeout config
+        timeout(Duration::from_secs(120), async {
+            let mut stream = self.run_select_stream(node_name, plan_node).await?;
+            let mut batches = Vec::new();
+            while let Some(item) = stream.next().await {
+                if let SelectItem::Batch(batch) = item? {
+                    batches.push(batch);
+                }
+            }
+            Ok(batches)
+        })
+        .await?
+    }
+
+    async fn run_select_stream(
+        &self,
+        node_name: String,
+        plan_node: SerializedPlan,
+    ) -> Result<SelectStream, CubeError> {
+        // Selects beyond the limit of the node wait for running ones to finish
+        let permit = self.select_limiter.acquire(&node_name).await;
         if self.server_name == node_name {
-            // TODO timeout config
-            timeout(Duration::from_secs(120), self.run_local_select(plan_node)).await?
+            let stream = timeout(
+                Duration::from_secs(120),
+                self.run_local_select_stream(plan_node),
+            )
+            .await??;
+            // The select takes the slot until its stream is dropped
+            Ok(Box::pin(stream.map(move |item| {
+                let _permit = &permit;
+                item
+            })))
         } else {
             unimplemented!()
         }
@@ -155,12 +281,46 @@ impl Cluster for ClusterImpl {
         Ok(vec![self.server_name.to_string()])
     }
 
+    async fn node_wire_format_version(&self, node_name: String) -> Result<u32, CubeError> {
+        if self.server_name == node_name {
+            Ok(WIRE_FORMAT_VERSION)
+        } else {
+            unimplemented!()
+        }
+    }
+
     fn server_name(&self) -> &str {
         self.server_name.as_str()
     }
 
     async fn download(&self, remote_path: &str) -> Result<String, CubeError> {
-        self.remote_fs.download_file(remote_path).await
+        self.download_queue.wait_for(remote_path).await
+    }
+
+    async fn is_downloaded(&self, remote_path: &str) -> Result<bool, CubeError> {
+        let local_path = self.remote_fs.local_file(remote_path).await?;
+        Ok(fs::metadata(&local_path).await.is_ok())
+    }
+
+    async fn warm_up_partition(
+        &self,
+        node_name: String,
+        partition_file: String,
+        superseded_files: Vec<String>,
+    ) -> Result<(), CubeError> {
+        if self.server_name == node_name {
+            for remote_path in superseded_files.iter() {
+                let local_path = self.remote_fs.local_file(remote_path).await?;
+                if fs::metadata(&local_path).await.is_ok() {
+                    debug!("Dropping local copy of {}", remote_path);
+                    fs::remove_file(&local_path).await?;
+                }
+            }
+            self.download_queue.wait_for(&partition_file).await?;
+            Ok(())
+        } else {
+            unimplemented!()
+        }
     }
 
     fn job_result_listener(&self) -> JobResultListener {
@@ -341,6 +501,16 @@ impl JobRunner {
                     Self::fail_job_row_key(job);
                 }
             }
+            JobType::IndexBackfill => {
+                if let RowKey::Table(TableId::Indexes, index_id) = job.row_reference() {
+                    let chunk_store = self.chunk_store.clone();
+                    let index_id = *index_id;
+                    tokio::spawn(async move { chunk_store.backfill_index(index_id).await })
+                        .await??
+                } else {
+                    Self::fail_job_row_key(job);
+                }
+            }
             JobType::TableImport => {
                 if let RowKey::Table(TableId::Tables, table_id) = job.row_reference() {
                     let import_service = self.import_service.clone();
@@ -374,6 +544,12 @@ impl ClusterImpl {
         query_executor: Arc<dyn QueryExecutor>,
     ) -> Arc<ClusterImpl> {
         let (sender, receiver) = broadcast::channel(10000); // TODO config
+        let download_queue = DownloadQueue::new(
+            remote_fs.clone(),
+            config_obj.download_concurrency(),
+            config_obj.download_bandwidth_limit(),
+        );
+        let select_limiter = NodeRequestLimiter::new(config_obj.max_concurrent_selects_per_node());
         Arc::new(ClusterImpl {
             server_name,
             server_addresses,
@@ -390,9 +566,16 @@ impl ClusterImpl {
             select_process_pool: RwLock::new(None),
             config_obj,
             query_executor,
+            download_queue,
+            select_limiter,
         })
     }
 
+    /// Downloads of remote files for selects run by this node.
+    pub fn download_queue(&self) -> Arc<DownloadQueue> {
+        self.download_queue.clone()
+    }
+
     pub async fn start_processing_loops(&self) {
         if self.config_obj.select_worker_pool_size() > 0 {
             let mut pool = self.select_process_pool.write().await;
@@ -432,46 +615,73 @@ impl ClusterImpl {
         Ok(())
     }
 
-    async fn run_local_select(
+    /// Batches of the select followed by stats of its scans. Select processes send batches as
+    /// they're serialized, see `WorkerProcessor::process_parts`.
+    async fn run_local_select_stream(
         &self,
         plan_node: SerializedPlan,
-    ) -> Result<Vec<RecordBatch>, CubeError> {
+    ) -> Result<SelectStream, CubeError> {
         let start = SystemTime::now();
+        plan_node.check_format_version()?;
         debug!("Running select: {:?}", plan_node);
-        let to_download = plan_node.files_to_download();
-        let file_futures = to_download
-            .iter()
-            .map(|remote| self.remote_fs.download_file(remote))
-            .collect::<Vec<_>>();
-        let remote_to_local_names = to_download
-            .clone()
+        // Files the plan doesn't scan are downloaded in the background for queries to come
+        self.download_queue.enqueue(&plan_node.files_to_download());
+        let to_scan = plan_node.files_to_scan();
+        let local_names = join_all(
+            to_scan
+                .iter()
+                .map(|remote| self.download_queue.wait_for(remote)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+        let remote_to_local_names = to_scan
             .into_iter()
-            .zip(
-                join_all(file_futures)
-                    .await
-                    .into_iter()
-                    .collect::<Result<Vec<_>, _>>()?
-                    .into_iter(),
-            )
+            .zip(local_names.into_iter())
             .collect::<HashMap<_, _>>();
+        debug!(
+            "Download queue depth: {}",
+            self.download_queue.queue_depth()
+        );
         let pool_option = self.select_process_pool.read().await.clone();
-        let res = if let Some(pool) = pool_option {
-            let serialized_plan_node = plan_node.clone();
-            pool.process(WorkerMessage::Select(
-                serialized_plan_node,
-                remote_to_local_names,
-            ))
-            .await
-        } else {
-            // TODO optimize for no double conversion
-            SerializedRecordBatchStream::write(
-                self.query_executor
-                    .execute_worker_plan(plan_node.clone(), remote_to_local_names)
-                    .await?,
+        let stream: SelectStream = if let Some(pool) = pool_option {
+            let node_name = self.server_name.clone();
+            let parts = pool.process_parts(WorkerMessage::Select(plan_node, remote_to_local_names));
+            Box::pin(
+                parts
+                    .map(move |part| -> Result<Vec<SelectItem>, CubeError> {
+                        let part = part?;
+                        let scan_stats = part.scan_stats().clone();
+                        let mut items = part
+                            .read(node_name.as_str())?
+                            .into_iter()
+                            .map(SelectItem::Batch)
+                            .collect::<Vec<_>>();
+                        items.push(SelectItem::ScanStats(scan_stats));
+                        Ok(items)
+                    })
+                    .flat_map(|items| {
+                        stream::iter(match items {
+                            Ok(items) => items.into_iter().map(Ok).collect::<Vec<_>>(),
+                            Err(e) => vec![Err(e)],
+                        })
+                    }),
             )
+        } else {
+            let (_, batches, scan_stats) = self
+                .query_executor
+                .execute_worker_plan(plan_node, remote_to_local_names)
+                .await?;
+            Box::pin(stream::iter(
+                batches
+                    .into_iter()
+                    .map(SelectItem::Batch)
+                    .chain(iter::once(SelectItem::ScanStats(scan_stats)))
+                    .map(Ok),
+            ))
         };
-        info!("Running select completed ({:?})", start.elapsed()?);
-        res?.read()
+        info!("Running select started ({:?})", start.elapsed()?);
+        Ok(stream)
     }
 
     pub async fn try_to_connect(&mut self) -> Result<(), CubeError> {
@@ -553,6 +763,8 @@ mod tests {
     use crate::import::MockImportService;
     use crate::metastore::{table::Table, Chunk, IdRow, RocksMetaStore, WAL};
     use crate::queryplanner::query_executor::QueryExecutorImpl;
+    use crate::queryplanner::serialized_plan::MIN_WIRE_FORMAT_VERSION;
+    use crate::queryplanner::wire_format::{self, Versioned};
     use crate::remotefs::LocalDirRemoteFs;
     use crate::store::{DataFrame, WALDataStore};
     use async_trait::async_trait;
@@ -606,6 +818,34 @@ mod tests {
         async fn delete_remote_chunk(&self, _chunk: IdRow<Chunk>) -> Result<(), CubeError> {
             unimplemented!()
         }
+
+        async fn merge_chunks(
+            &self,
+            _partition_id: u64,
+            _chunks: Vec<IdRow<Chunk>>,
+        ) -> Result<IdRow<Chunk>, CubeError> {
+            unimplemented!()
+        }
+
+        async fn add_tombstones(&self, _table_id: u64, _data: DataFrame) -> Result<(), CubeError> {
+            unimplemented!()
+        }
+
+        async fn add_inactive_chunks(
+            &self,
+            _table_id: u64,
+            _data: DataFrame,
+        ) -> Result<Vec<u64>, CubeError> {
+            unimplemented!()
+        }
+
+        async fn backfill_index(&self, _index_id: u64) -> Result<(), CubeError> {
+            unimplemented!()
+        }
+
+        fn evict_in_memory_chunks(&self, _chunk_ids: Vec<u64>) {
+            unimplemented!()
+        }
     }
 
     struct MockCompaction;
@@ -651,7 +891,7 @@ mod tests {
             meta_store.clone(),
             Arc::new(MockImportService::new()),
             config.config_obj(),
-            Arc::new(QueryExecutorImpl),
+            QueryExecutorImpl::new(config.config_obj()),
         );
 
         let bar = ClusterImpl::new(
@@ -664,7 +904,7 @@ mod tests {
             meta_store.clone(),
             Arc::new(MockImportService::new()),
             config.config_obj(),
-            Arc::new(QueryExecutorImpl),
+            QueryExecutorImpl::new(config.config_obj()),
         );
 
         remote_fs.drop_local_path().await.unwrap();
@@ -673,4 +913,58 @@ mod tests {
         assert_eq!(foo.elect_leader().await.unwrap(), "foo");
         assert_eq!(foo.elect_leader().await.unwrap(), "foo");
     }
+
+    fn versioned_message(plan: SerializedPlan) -> Versioned<WorkerMessage> {
+        let message = WorkerMessage::Select(plan, HashMap::new());
+        Versioned {
+            version: WorkerProcessor::args_version(&message),
+            payload: message,
+        }
+    }
+
+    #[test]
+    fn worker_message_of_oldest_version() {
+        let plan = SerializedPlan::empty_for_test()
+            .with_query_id("q1".to_string())
+            .with_partition_id_to_execute(vec![1].into_iter().collect())
+            .with_format_version(MIN_WIRE_FORMAT_VERSION)
+            .unwrap();
+        let bytes = bincode::serialize(&versioned_message(plan)).unwrap();
+
+        // Workers of the oldest version don't know about fields added later
+        let (version, message) = wire_format::with_payload_version(MIN_WIRE_FORMAT_VERSION, || {
+            bincode::deserialize::<(u32, WorkerMessage)>(&bytes)
+        })
+        .unwrap();
+        assert_eq!(version, MIN_WIRE_FORMAT_VERSION);
+        let WorkerMessage::Select(plan, _) = message;
+        assert_eq!(plan.format_version(), MIN_WIRE_FORMAT_VERSION);
+        assert_eq!(
+            plan.partition_ids_to_execute(),
+            vec![1].into_iter().collect()
+        );
+        assert_eq!(plan.query_id(), "");
+
+        // Current workers read the version before the message
+        let message = bincode::deserialize::<Versioned<WorkerMessage>>(&bytes).unwrap();
+        assert_eq!(message.version, MIN_WIRE_FORMAT_VERSION);
+        let WorkerMessage::Select(plan, _) = message.payload;
+        assert_eq!(
+            plan.partition_ids_to_execute(),
+            vec![1].into_iter().collect()
+        );
+        assert_eq!(plan.query_id(), "");
+
+        // Plans older workers would execute wrong aren't sent
+        let plan = SerializedPlan::empty_for_test()
+            .with_split_branch(0)
+            .with_format_version(MIN_WIRE_FORMAT_VERSION)
+            .unwrap();
+        let err = bincode::serialize(&versioned_message(plan)).unwrap_err();
+        assert!(
+            err.to_string().contains("requires wire format version"),
+            "{}",
+            err
+        );
+    }
 }
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Caps the number of requests running on each node at once so the router doesn't flood a node
+/// with selects. Zero `max_per_node` disables the limit.
+pub struct NodeRequestLimiter {
+    max_per_node: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl NodeRequestLimiter {
+    pub fn new(max_per_node: usize) -> Arc<NodeRequestLimiter> {
+        Arc::new(NodeRequestLimiter {
+            max_per_node,
+            semaphores: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Waits until `node` has a free slot. The slot is taken until the permit is dropped.
+    pub async fn acquire(&self, node: &str) -> Option<OwnedSemaphorePermit> {
+        if self.max_per_node == 0 {
+            return None;
+        }
+        let semaphore = self
+            .semaphores
+            .lock()
+            .unwrap()
+            .entry(node.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_node)))
+            .clone();
+        Some(semaphore.acquire_owned().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn limits_concurrent_requests_per_node() {
+        let limiter = NodeRequestLimiter::new(2);
+        let running = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+        let max_running = Arc::new(Mutex::new(HashMap::<String, usize>::new()));
+        let completed = Arc::new(AtomicUsize::new(0));
+        let requests = (0..20).map(|i| {
+            let limiter = limiter.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            let completed = completed.clone();
+            let node = format!("node{}", i % 2);
+            tokio::spawn(async move {
+                let _permit = limiter.acquire(&node).await;
+                {
+                    let mut running = running.lock().unwrap();
+                    let count = running.entry(node.clone()).or_insert(0);
+                    *count += 1;
+                    let mut max_running = max_running.lock().unwrap();
+                    let max = max_running.entry(node.clone()).or_insert(0);
+                    *max = (*max).max(*count);
+                }
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+                *running.lock().unwrap().get_mut(&node).unwrap() -= 1;
+                completed.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        join_all(requests).await;
+
+        assert_eq!(completed.load(Ordering::SeqCst), 20);
+        let max_running = max_running.lock().unwrap();
+        assert_eq!(max_running.get("node0"), Some(&2));
+        assert_eq!(max_running.get("node1"), Some(&2));
+    }
+
+    #[tokio::test]
+    async fn zero_limit_is_unlimited() {
+        let limiter = NodeRequestLimiter::new(0);
+        let permits = join_all((0..100).map(|_| limiter.acquire("node"))).await;
+        assert!(permits.iter().all(|p| p.is_none()));
+    }
+}
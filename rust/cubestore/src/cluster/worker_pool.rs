@@ -1,3 +1,5 @@
+use crate::queryplanner::serialized_plan::WIRE_FORMAT_VERSION;
+use crate::queryplanner::wire_format::Versioned;
 use crate::CubeError;
 use deadqueue::unlimited;
 use ipc_channel::ipc;
@@ -11,8 +13,7 @@ use std::marker::PhantomData;
 use std::panic;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::oneshot::Sender;
-use tokio::sync::{oneshot, watch, Notify, RwLock};
+use tokio::sync::{mpsc, watch, Notify, RwLock};
 
 pub struct WorkerPool<
     T: Debug + Serialize + DeserializeOwned + Sync + Send + 'static,
@@ -31,7 +32,7 @@ pub struct Message<
     R: Serialize + DeserializeOwned + Sync + Send + 'static,
 > {
     message: T,
-    sender: Sender<Result<R, CubeError>>,
+    sender: mpsc::Sender<Result<R, CubeError>>,
 }
 
 pub trait MessageProcessor<
@@ -40,6 +41,24 @@ pub trait MessageProcessor<
 >
 {
     fn process(args: T) -> Result<R, CubeError>;
+
+    /// Same as `process` but the result is passed to `send` in parts as they're ready.
+    fn process_parts(
+        args: T,
+        send: &mut dyn FnMut(R) -> Result<(), CubeError>,
+    ) -> Result<(), CubeError> {
+        send(Self::process(args)?)
+    }
+
+    /// Wire format version `args` are sent to the process in.
+    fn args_version(_args: &T) -> u32 {
+        WIRE_FORMAT_VERSION
+    }
+
+    /// Wire format version parts of the result are sent back in.
+    fn part_version(_part: &R) -> u32 {
+        WIRE_FORMAT_VERSION
+    }
 }
 
 impl<
@@ -72,13 +91,23 @@ impl<
         }
     }
 
+    /// Returns the first part of the result, see `process_parts`.
     pub async fn process(&self, message: T) -> Result<R, CubeError> {
-        let (tx, rx) = oneshot::channel();
+        self.process_parts(message)
+            .recv()
+            .await
+            .ok_or_else(|| CubeError::internal("Worker sent no result".to_string()))?
+    }
+
+    /// Parts of the result in the order the worker sends them. The worker waits for a part to
+    /// be taken before the next one is read from it.
+    pub fn process_parts(&self, message: T) -> mpsc::Receiver<Result<R, CubeError>> {
+        let (tx, rx) = mpsc::channel(1);
         self.queue.push(Message {
             message,
             sender: tx,
         });
-        Ok(rx.await??)
+        rx
     }
 
     pub async fn stop_workers(&self) -> Result<(), CubeError> {
@@ -129,7 +158,10 @@ impl<
             match process {
                 Ok((mut args_tx, mut res_rx, mut handle)) => loop {
                     let mut stopped_rx = self.stopped_rx.write().await;
-                    let Message { message, sender } = tokio::select! {
+                    let Message {
+                        message,
+                        mut sender,
+                    } = tokio::select! {
                         stopped = stopped_rx.recv() => {
                             if let Some(x) = stopped {
                                 if x {
@@ -146,7 +178,7 @@ impl<
                     };
                     let process_message_res_timeout = tokio::time::timeout(
                         self.timeout,
-                        self.process_message(message, args_tx, res_rx),
+                        self.process_message(message, &mut sender, args_tx, res_rx),
                     )
                     .await;
                     let process_message_res = match process_message_res_timeout {
@@ -157,18 +189,14 @@ impl<
                         ))),
                     };
                     match process_message_res {
-                        Ok((res, a, r)) => {
-                            if sender.send(Ok(res)).is_err() {
-                                error!("Error during worker message processing: Send Error");
-                            }
+                        Ok((a, r)) => {
                             args_tx = a;
                             res_rx = r;
                         }
                         Err(e) => {
                             error!("Error during worker message processing: {}", e);
-                            if sender.send(Err(e.clone())).is_err() {
-                                error!("Error during worker message processing: Send Error");
-                            }
+                            // The caller may be gone already
+                            let _ = sender.send(Err(e.clone())).await;
                             <WorkerProcess<T, R, P>>::kill(&mut handle);
                             break;
                         }
@@ -187,23 +215,44 @@ impl<
         }
     }
 
+    /// Forwards parts of the result to `sender` until the process sends `None`.
     async fn process_message(
         &self,
         message: T,
-        args_tx: IpcSender<T>,
-        res_rx: IpcReceiver<Result<R, CubeError>>,
-    ) -> Result<(R, IpcSender<T>, IpcReceiver<Result<R, CubeError>>), CubeError> {
-        args_tx.send(message)?;
-        let (res, res_rx) = tokio::task::spawn_blocking(move || (res_rx.recv(), res_rx)).await?;
-        Ok((res??, args_tx, res_rx))
+        sender: &mut mpsc::Sender<Result<R, CubeError>>,
+        args_tx: IpcSender<Versioned<T>>,
+        mut res_rx: IpcReceiver<Result<Option<Versioned<R>>, CubeError>>,
+    ) -> Result<
+        (
+            IpcSender<Versioned<T>>,
+            IpcReceiver<Result<Option<Versioned<R>>, CubeError>>,
+        ),
+        CubeError,
+    > {
+        args_tx.send(Versioned {
+            version: P::args_version(&message),
+            payload: message,
+        })?;
+        loop {
+            let (res, rx) = tokio::task::spawn_blocking(move || (res_rx.recv(), res_rx)).await?;
+            res_rx = rx;
+            match res?? {
+                Some(part) => {
+                    // Parts are read to the end even if the caller is gone so the process is
+                    // ready for the next message
+                    let _ = sender.send(Ok(part.payload)).await;
+                }
+                None => return Ok((args_tx, res_rx)),
+            }
+        }
     }
 
     fn spawn_process(
         &self,
     ) -> Result<
         (
-            IpcSender<T>,
-            IpcReceiver<Result<R, CubeError>>,
+            IpcSender<Versioned<T>>,
+            IpcReceiver<Result<Option<Versioned<R>>, CubeError>>,
             JoinHandle<()>,
         ),
         CubeError,
@@ -214,7 +263,17 @@ impl<
             let res = rx.recv();
             match res {
                 Ok(args) => {
-                    let send_res = tx.send(P::process(args));
+                    let res = P::process_parts(args.payload, &mut |part| {
+                        let part = Versioned {
+                            version: P::part_version(&part),
+                            payload: part,
+                        };
+                        Ok(tx.send(Ok(Some(part)))?)
+                    });
+                    let send_res = match res {
+                        Ok(()) => tx.send(Ok(None)),
+                        Err(e) => tx.send(Err(e)),
+                    };
                     if let Err(e) = send_res {
                         error!("Worker message send error: {:?}", e);
                         return;
@@ -259,6 +318,7 @@ mod tests {
     #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
     pub enum Message {
         Delay(u64),
+        Parts(u64),
     }
 
     #[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
@@ -275,6 +335,24 @@ mod tests {
                     thread::sleep(Duration::from_millis(x));
                     Ok(Response::Foo(x))
                 }
+                Message::Parts(_) => Err(CubeError::internal(
+                    "Parts are sent by process_parts".to_string(),
+                )),
+            }
+        }
+
+        fn process_parts(
+            args: Message,
+            send: &mut dyn FnMut(Response) -> Result<(), CubeError>,
+        ) -> Result<(), CubeError> {
+            match args {
+                Message::Parts(n) => {
+                    for i in 0..n {
+                        send(Response::Foo(i))?;
+                    }
+                    Ok(())
+                }
+                args => send(Self::process(args)?),
             }
         }
     }
@@ -303,6 +381,30 @@ mod tests {
         pool.stop_workers().await.unwrap();
     }
 
+    #[tokio::test]
+    async fn test_parts() {
+        let pool = WorkerPool::<Message, Response, Processor>::new(1, Duration::from_millis(1000));
+        let mut parts = pool.process_parts(Message::Parts(3));
+        let mut received = Vec::new();
+        while let Some(part) = parts.recv().await {
+            received.push(part.unwrap());
+        }
+        assert_eq!(
+            received,
+            vec![Response::Foo(0), Response::Foo(1), Response::Foo(2)]
+        );
+        // Parts nobody takes don't hold up the process
+        assert_eq!(
+            pool.process(Message::Parts(3)).await.unwrap(),
+            Response::Foo(0)
+        );
+        assert_eq!(
+            pool.process(Message::Delay(10)).await.unwrap(),
+            Response::Foo(10)
+        );
+        pool.stop_workers().await.unwrap();
+    }
+
     #[tokio::test]
     async fn test_timeout() {
         let pool = WorkerPool::<Message, Response, Processor>::new(4, Duration::from_millis(450));
@@ -1,4 +1,5 @@
 use crate::cluster::ClusterImpl;
+use crate::import::s3::S3ImportProgress;
 use crate::import::ImportServiceImpl;
 use crate::metastore::RocksMetaStore;
 use crate::queryplanner::query_executor::{QueryExecutor, QueryExecutorImpl};
@@ -8,16 +9,19 @@ use crate::remotefs::{LocalDirRemoteFs, RemoteFs};
 use crate::scheduler::SchedulerImpl;
 use crate::sql::{SqlService, SqlServiceImpl};
 use crate::store::compaction::CompactionServiceImpl;
+use crate::store::insert_buffer::InsertBuffer;
+use crate::store::memory_chunks::MemoryChunkStore;
 use crate::store::{ChunkStore, WALStore};
 use crate::telemetry::{start_track_event_loop, stop_track_event_loop};
 use crate::CubeError;
-use log::Level;
+use log::{Level, LevelFilter, Log, Metadata, Record};
 use mockall::automock;
 use rocksdb::{Options, DB};
 use simple_logger::SimpleLogger;
+use std::cell::RefCell;
 use std::future::Future;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Once};
 use std::{env, fs};
 use tokio::sync::broadcast;
 use tokio::time::Duration;
@@ -43,6 +47,12 @@ impl CubeServices {
         tokio::spawn(async move { meta_store.run_upload_loop().await });
         let scheduler = self.scheduler.clone();
         tokio::spawn(async move { scheduler.run_scheduler().await });
+        let scheduler = self.scheduler.clone();
+        tokio::spawn(async move { scheduler.run_retention_loop().await });
+        let scheduler = self.scheduler.clone();
+        tokio::spawn(async move { scheduler.run_file_deletion_loop().await });
+        let sql_service = self.sql_service.clone();
+        tokio::spawn(async move { sql_service.run_insert_buffer_loop().await });
         start_track_event_loop().await;
         Ok(())
     }
@@ -51,6 +61,7 @@ impl CubeServices {
         self.cluster.stop_processing_loops().await?;
         self.meta_store.stop_processing_loops().await;
         self.scheduler.stop_processing_loops()?;
+        self.sql_service.stop_processing_loops()?;
         stop_track_event_loop().await;
         Ok(())
     }
@@ -63,6 +74,16 @@ pub enum FileStoreProvider {
     S3 { region: String, bucket_name: String },
 }
 
+/// Region and credentials of S3 import locations. Missing keys are resolved by the default AWS
+/// provider chain.
+#[derive(Debug, Clone)]
+pub struct S3ImportConfig {
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub session_token: Option<String>,
+}
+
 pub struct Config {
     config_obj: Arc<ConfigObjImpl>,
 }
@@ -71,10 +92,14 @@ pub struct Config {
 pub trait ConfigObj: Send + Sync {
     fn partition_split_threshold(&self) -> u64;
 
+    fn partition_size_split_threshold(&self) -> u64;
+
     fn compaction_chunks_total_size_threshold(&self) -> u64;
 
     fn compaction_chunks_count_threshold(&self) -> u64;
 
+    fn compaction_medium_chunks_count_threshold(&self) -> u64;
+
     fn select_worker_pool_size(&self) -> usize;
 
     fn bind_port(&self) -> u16;
@@ -83,20 +108,128 @@ pub trait ConfigObj: Send + Sync {
 
     fn query_timeout(&self) -> u64;
 
+    /// Statements each MySQL connection can keep prepared at once.
+    fn max_prepared_statements(&self) -> usize;
+
     fn not_used_timeout(&self) -> u64;
+
+    /// Maximum total size in bytes of worker results kept in the cache. Disabled if 0.
+    fn worker_result_cache_size(&self) -> u64;
+
+    fn local_execution_row_threshold(&self) -> u64;
+
+    fn single_node_local_execution(&self) -> bool;
+
+    fn parquet_read_parallelism(&self) -> usize;
+
+    fn parquet_warm_up_concurrency(&self) -> usize;
+
+    fn parquet_split_readers(&self) -> usize;
+
+    fn strict_casts(&self) -> bool;
+
+    /// Boolean result columns are returned as 0/1 integers for clients without a boolean type.
+    fn booleans_as_ints(&self) -> bool;
+
+    /// Maximum number of rows in a query result. Not limited if 0.
+    fn query_max_result_rows(&self) -> u64;
+
+    /// Port of the Arrow Flight endpoint. The endpoint isn't started if not set.
+    fn flight_port(&self) -> Option<u16>;
+
+    /// Maximum number of rows streamed by Arrow Flight. Not limited if 0.
+    fn flight_max_result_rows(&self) -> u64;
+
+    /// Maximum size in bytes of Arrow IPC messages streamed by Arrow Flight. Not limited if 0.
+    fn flight_max_result_bytes(&self) -> u64;
+
+    fn count_distinct_memory_limit(&self) -> usize;
+
+    fn in_memory_chunks_max_size(&self) -> u64;
+
+    /// Rows of INSERTs are buffered until this many are collected for a table. Disabled if 0.
+    fn insert_buffer_max_rows(&self) -> usize;
+
+    fn insert_buffer_max_bytes(&self) -> u64;
+
+    /// Seconds buffered rows are kept before they're written regardless of their count.
+    fn insert_buffer_max_age(&self) -> u64;
+
+    /// SELECTs read buffered rows along with their tables so they see all acknowledged inserts.
+    fn insert_buffer_read_your_writes(&self) -> bool;
+
+    fn download_concurrency(&self) -> usize;
+
+    fn download_bandwidth_limit(&self) -> u64;
+
+    fn max_cluster_send_partitions(&self) -> usize;
+
+    fn max_concurrent_selects_per_node(&self) -> usize;
+
+    fn import_s3(&self) -> &S3ImportConfig;
+
+    fn import_download_concurrency(&self) -> usize;
+
+    /// Retries of each failed S3 or HTTP request of an import.
+    fn import_download_retries(&self) -> u32;
+
+    /// Seconds to wait for a connection or the next chunk of an HTTP(S) import.
+    fn import_http_timeout(&self) -> u64;
+
+    /// Maximum size in bytes of an HTTP(S) import. Not limited if 0.
+    fn import_max_size(&self) -> u64;
+
+    fn retention_check_interval(&self) -> u64;
+
+    fn file_deletion_grace_period(&self) -> u64;
+
+    fn metastore_snapshot_interval(&self) -> u64;
 }
 
 #[derive(Debug, Clone)]
 pub struct ConfigObjImpl {
     pub partition_split_threshold: u64,
+    pub partition_size_split_threshold: u64,
     pub compaction_chunks_total_size_threshold: u64,
     pub compaction_chunks_count_threshold: u64,
+    pub compaction_medium_chunks_count_threshold: u64,
     pub data_dir: PathBuf,
     pub store_provider: FileStoreProvider,
     pub select_worker_pool_size: usize,
     pub bind_port: u16,
     pub bind_address: String,
     pub query_timeout: u64,
+    pub max_prepared_statements: usize,
+    pub worker_result_cache_size: u64,
+    pub local_execution_row_threshold: u64,
+    pub single_node_local_execution: bool,
+    pub parquet_read_parallelism: usize,
+    pub parquet_warm_up_concurrency: usize,
+    pub parquet_split_readers: usize,
+    pub strict_casts: bool,
+    pub booleans_as_ints: bool,
+    pub query_max_result_rows: u64,
+    pub flight_port: Option<u16>,
+    pub flight_max_result_rows: u64,
+    pub flight_max_result_bytes: u64,
+    pub count_distinct_memory_limit: usize,
+    pub in_memory_chunks_max_size: u64,
+    pub insert_buffer_max_rows: usize,
+    pub insert_buffer_max_bytes: u64,
+    pub insert_buffer_max_age: u64,
+    pub insert_buffer_read_your_writes: bool,
+    pub download_concurrency: usize,
+    pub download_bandwidth_limit: u64,
+    pub max_cluster_send_partitions: usize,
+    pub max_concurrent_selects_per_node: usize,
+    pub import_s3: S3ImportConfig,
+    pub import_download_concurrency: usize,
+    pub import_download_retries: u32,
+    pub import_http_timeout: u64,
+    pub import_max_size: u64,
+    pub retention_check_interval: u64,
+    pub file_deletion_grace_period: u64,
+    pub metastore_snapshot_interval: u64,
 }
 
 impl ConfigObj for ConfigObjImpl {
@@ -104,6 +237,10 @@ impl ConfigObj for ConfigObjImpl {
         self.partition_split_threshold
     }
 
+    fn partition_size_split_threshold(&self) -> u64 {
+        self.partition_size_split_threshold
+    }
+
     fn compaction_chunks_total_size_threshold(&self) -> u64 {
         self.compaction_chunks_total_size_threshold
     }
@@ -112,6 +249,10 @@ impl ConfigObj for ConfigObjImpl {
         self.compaction_chunks_count_threshold
     }
 
+    fn compaction_medium_chunks_count_threshold(&self) -> u64 {
+        self.compaction_medium_chunks_count_threshold
+    }
+
     fn select_worker_pool_size(&self) -> usize {
         self.select_worker_pool_size
     }
@@ -128,9 +269,133 @@ impl ConfigObj for ConfigObjImpl {
         self.query_timeout
     }
 
+    fn max_prepared_statements(&self) -> usize {
+        self.max_prepared_statements
+    }
+
     fn not_used_timeout(&self) -> u64 {
         self.query_timeout * 2
     }
+
+    fn worker_result_cache_size(&self) -> u64 {
+        self.worker_result_cache_size
+    }
+
+    fn local_execution_row_threshold(&self) -> u64 {
+        self.local_execution_row_threshold
+    }
+
+    fn single_node_local_execution(&self) -> bool {
+        self.single_node_local_execution
+    }
+
+    fn parquet_read_parallelism(&self) -> usize {
+        self.parquet_read_parallelism
+    }
+
+    fn parquet_warm_up_concurrency(&self) -> usize {
+        self.parquet_warm_up_concurrency
+    }
+
+    fn parquet_split_readers(&self) -> usize {
+        self.parquet_split_readers
+    }
+
+    fn strict_casts(&self) -> bool {
+        self.strict_casts
+    }
+
+    fn booleans_as_ints(&self) -> bool {
+        self.booleans_as_ints
+    }
+
+    fn query_max_result_rows(&self) -> u64 {
+        self.query_max_result_rows
+    }
+
+    fn flight_port(&self) -> Option<u16> {
+        self.flight_port
+    }
+
+    fn flight_max_result_rows(&self) -> u64 {
+        self.flight_max_result_rows
+    }
+
+    fn flight_max_result_bytes(&self) -> u64 {
+        self.flight_max_result_bytes
+    }
+
+    fn count_distinct_memory_limit(&self) -> usize {
+        self.count_distinct_memory_limit
+    }
+
+    fn in_memory_chunks_max_size(&self) -> u64 {
+        self.in_memory_chunks_max_size
+    }
+
+    fn insert_buffer_max_rows(&self) -> usize {
+        self.insert_buffer_max_rows
+    }
+
+    fn insert_buffer_max_bytes(&self) -> u64 {
+        self.insert_buffer_max_bytes
+    }
+
+    fn insert_buffer_max_age(&self) -> u64 {
+        self.insert_buffer_max_age
+    }
+
+    fn insert_buffer_read_your_writes(&self) -> bool {
+        self.insert_buffer_read_your_writes
+    }
+
+    fn download_concurrency(&self) -> usize {
+        self.download_concurrency
+    }
+
+    fn download_bandwidth_limit(&self) -> u64 {
+        self.download_bandwidth_limit
+    }
+
+    fn max_cluster_send_partitions(&self) -> usize {
+        self.max_cluster_send_partitions
+    }
+
+    fn max_concurrent_selects_per_node(&self) -> usize {
+        self.max_concurrent_selects_per_node
+    }
+
+    fn import_s3(&self) -> &S3ImportConfig {
+        &self.import_s3
+    }
+
+    fn import_download_concurrency(&self) -> usize {
+        self.import_download_concurrency
+    }
+
+    fn import_download_retries(&self) -> u32 {
+        self.import_download_retries
+    }
+
+    fn import_http_timeout(&self) -> u64 {
+        self.import_http_timeout
+    }
+
+    fn import_max_size(&self) -> u64 {
+        self.import_max_size
+    }
+
+    fn retention_check_interval(&self) -> u64 {
+        self.retention_check_interval
+    }
+
+    fn file_deletion_grace_period(&self) -> u64 {
+        self.file_deletion_grace_period
+    }
+
+    fn metastore_snapshot_interval(&self) -> u64 {
+        self.metastore_snapshot_interval
+    }
 }
 
 lazy_static! {
@@ -138,9 +403,59 @@ lazy_static! {
         std::sync::RwLock::new(None);
 }
 
-lazy_static! {
-    pub static ref TEST_LOGGING_INITIALIZED: tokio::sync::RwLock<bool> =
-        tokio::sync::RwLock::new(false);
+static TEST_LOGGING_INIT: Once = Once::new();
+
+thread_local! {
+    static CAPTURED_TEST_LOGS: RefCell<Option<Vec<String>>> = RefCell::new(None);
+}
+
+/// Logger used by tests: prints everything like SimpleLogger and additionally keeps
+/// messages logged on the current thread while capturing is enabled.
+struct TestLogger {
+    logger: SimpleLogger,
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.logger.log(record);
+        CAPTURED_TEST_LOGS.with(|logs| {
+            if let Some(logs) = logs.borrow_mut().as_mut() {
+                logs.push(record.args().to_string());
+            }
+        });
+    }
+
+    fn flush(&self) {
+        self.logger.flush()
+    }
+}
+
+pub fn init_test_logger() {
+    TEST_LOGGING_INIT.call_once(|| {
+        let logger = SimpleLogger::new()
+            .with_level(Level::Error.to_level_filter())
+            .with_module_level("cubestore", Level::Trace.to_level_filter());
+        log::set_boxed_logger(Box::new(TestLogger { logger })).unwrap();
+        log::set_max_level(LevelFilter::Trace);
+    });
+}
+
+/// Starts collecting log messages emitted on the current thread.
+pub fn start_capturing_test_logs() {
+    init_test_logger();
+    CAPTURED_TEST_LOGS.with(|logs| *logs.borrow_mut() = Some(Vec::new()));
+}
+
+/// Stops collecting and returns messages logged since `start_capturing_test_logs`.
+pub fn take_captured_test_logs() -> Vec<String> {
+    CAPTURED_TEST_LOGS.with(|logs| logs.borrow_mut().take().unwrap_or_default())
 }
 
 impl Config {
@@ -148,8 +463,23 @@ impl Config {
         Config {
             config_obj: Arc::new(ConfigObjImpl {
                 data_dir: env::current_dir().unwrap().join(".cubestore").join("data"),
-                partition_split_threshold: 1000000,
+                partition_split_threshold: env::var("CUBESTORE_PARTITION_SPLIT_THRESHOLD")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(1000000),
+                partition_size_split_threshold: env::var(
+                    "CUBESTORE_PARTITION_SIZE_SPLIT_THRESHOLD",
+                )
+                .ok()
+                .map(|v| v.parse::<u64>().unwrap())
+                .unwrap_or(512 * 1024 * 1024),
                 compaction_chunks_count_threshold: 4,
+                compaction_medium_chunks_count_threshold: env::var(
+                    "CUBESTORE_COMPACTION_MEDIUM_CHUNKS_COUNT_THRESHOLD",
+                )
+                .ok()
+                .map(|v| v.parse::<u64>().unwrap())
+                .unwrap_or(4),
                 compaction_chunks_total_size_threshold: 500000,
                 store_provider: {
                     if let Ok(bucket_name) = env::var("CUBESTORE_S3_BUCKET") {
@@ -182,6 +512,137 @@ impl Config {
                     .ok()
                     .map(|v| v.parse::<u64>().unwrap())
                     .unwrap_or(120),
+                max_prepared_statements: env::var("CUBESTORE_MAX_PREPARED_STATEMENTS")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(256),
+                worker_result_cache_size: env::var("CUBESTORE_WORKER_RESULT_CACHE_SIZE")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                local_execution_row_threshold: env::var("CUBESTORE_LOCAL_EXECUTION_ROW_THRESHOLD")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                single_node_local_execution: env::var("CUBESTORE_SINGLE_NODE_LOCAL_EXECUTION")
+                    .ok()
+                    .map(|v| v.parse::<bool>().unwrap())
+                    .unwrap_or(false),
+                parquet_read_parallelism: env::var("CUBESTORE_PARQUET_READ_PARALLELISM")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(1),
+                parquet_warm_up_concurrency: env::var("CUBESTORE_PARQUET_WARM_UP_CONCURRENCY")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(16),
+                parquet_split_readers: env::var("CUBESTORE_PARQUET_SPLIT_READERS")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(1),
+                strict_casts: env::var("CUBESTORE_STRICT_CASTS")
+                    .ok()
+                    .map(|v| v.parse::<bool>().unwrap())
+                    .unwrap_or(false),
+                booleans_as_ints: env::var("CUBESTORE_BOOLEANS_AS_INTS")
+                    .ok()
+                    .map(|v| v.parse::<bool>().unwrap())
+                    .unwrap_or(false),
+                query_max_result_rows: env::var("CUBESTORE_QUERY_MAX_RESULT_ROWS")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                flight_port: env::var("CUBESTORE_FLIGHT_PORT")
+                    .ok()
+                    .map(|v| v.parse::<u16>().unwrap()),
+                flight_max_result_rows: env::var("CUBESTORE_FLIGHT_MAX_RESULT_ROWS")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                flight_max_result_bytes: env::var("CUBESTORE_FLIGHT_MAX_RESULT_BYTES")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                count_distinct_memory_limit: env::var("CUBESTORE_COUNT_DISTINCT_MEMORY_LIMIT")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(64 * 1024 * 1024),
+                in_memory_chunks_max_size: env::var("CUBESTORE_IN_MEMORY_CHUNKS_MAX_SIZE")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                insert_buffer_max_rows: env::var("CUBESTORE_INSERT_BUFFER_ROWS")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(0),
+                insert_buffer_max_bytes: env::var("CUBESTORE_INSERT_BUFFER_BYTES")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(16 * 1024 * 1024),
+                insert_buffer_max_age: env::var("CUBESTORE_INSERT_BUFFER_AGE")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(5),
+                insert_buffer_read_your_writes: env::var(
+                    "CUBESTORE_INSERT_BUFFER_READ_YOUR_WRITES",
+                )
+                .ok()
+                .map(|v| v.parse::<bool>().unwrap())
+                .unwrap_or(true),
+                download_concurrency: env::var("CUBESTORE_DOWNLOAD_CONCURRENCY")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(16),
+                download_bandwidth_limit: env::var("CUBESTORE_DOWNLOAD_BANDWIDTH_LIMIT")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                max_cluster_send_partitions: env::var("CUBESTORE_MAX_CLUSTER_SEND_PARTITIONS")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(10000),
+                max_concurrent_selects_per_node: env::var(
+                    "CUBESTORE_MAX_CONCURRENT_SELECTS_PER_NODE",
+                )
+                .ok()
+                .map(|v| v.parse::<usize>().unwrap())
+                .unwrap_or(16),
+                import_s3: S3ImportConfig {
+                    region: env::var("CUBESTORE_IMPORT_S3_REGION")
+                        .or_else(|_| env::var("CUBESTORE_S3_REGION"))
+                        .unwrap_or("us-east-1".to_string()),
+                    access_key_id: env::var("CUBESTORE_IMPORT_AWS_ACCESS_KEY_ID").ok(),
+                    secret_access_key: env::var("CUBESTORE_IMPORT_AWS_SECRET_ACCESS_KEY").ok(),
+                    session_token: env::var("CUBESTORE_IMPORT_AWS_SESSION_TOKEN").ok(),
+                },
+                import_download_concurrency: env::var("CUBESTORE_IMPORT_DOWNLOAD_CONCURRENCY")
+                    .ok()
+                    .map(|v| v.parse::<usize>().unwrap())
+                    .unwrap_or(8),
+                import_download_retries: env::var("CUBESTORE_IMPORT_DOWNLOAD_RETRIES")
+                    .ok()
+                    .map(|v| v.parse::<u32>().unwrap())
+                    .unwrap_or(3),
+                import_http_timeout: env::var("CUBESTORE_IMPORT_HTTP_TIMEOUT")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(60),
+                import_max_size: env::var("CUBESTORE_IMPORT_MAX_SIZE")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(0),
+                retention_check_interval: env::var("CUBESTORE_RETENTION_CHECK_INTERVAL")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(600),
+                file_deletion_grace_period: env::var("CUBESTORE_FILE_DELETION_GRACE_PERIOD")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(300),
+                metastore_snapshot_interval: env::var("CUBESTORE_METASTORE_SNAPSHOT_INTERVAL")
+                    .ok()
+                    .map(|v| v.parse::<u64>().unwrap())
+                    .unwrap_or(60),
             }),
         }
     }
@@ -193,7 +654,9 @@ impl Config {
                     .unwrap()
                     .join(format!("{}-local-store", name)),
                 partition_split_threshold: 20,
+                partition_size_split_threshold: 0,
                 compaction_chunks_count_threshold: 1,
+                compaction_medium_chunks_count_threshold: 0,
                 compaction_chunks_total_size_threshold: 10,
                 store_provider: FileStoreProvider::Filesystem {
                     remote_dir: env::current_dir()
@@ -204,6 +667,42 @@ impl Config {
                 bind_port: 3306,
                 bind_address: "0.0.0.0".to_string(),
                 query_timeout: 60,
+                max_prepared_statements: 256,
+                worker_result_cache_size: 0,
+                local_execution_row_threshold: 0,
+                single_node_local_execution: false,
+                parquet_read_parallelism: 1,
+                parquet_warm_up_concurrency: 16,
+                parquet_split_readers: 1,
+                strict_casts: false,
+                booleans_as_ints: false,
+                query_max_result_rows: 0,
+                flight_port: None,
+                flight_max_result_rows: 0,
+                flight_max_result_bytes: 0,
+                count_distinct_memory_limit: 64 * 1024 * 1024,
+                in_memory_chunks_max_size: 0,
+                insert_buffer_max_rows: 0,
+                insert_buffer_max_bytes: 16 * 1024 * 1024,
+                insert_buffer_max_age: 5,
+                insert_buffer_read_your_writes: true,
+                download_concurrency: 16,
+                download_bandwidth_limit: 0,
+                max_cluster_send_partitions: 10000,
+                max_concurrent_selects_per_node: 16,
+                import_s3: S3ImportConfig {
+                    region: "us-east-1".to_string(),
+                    access_key_id: None,
+                    secret_access_key: None,
+                    session_token: None,
+                },
+                import_download_concurrency: 8,
+                import_download_retries: 3,
+                import_http_timeout: 60,
+                import_max_size: 0,
+                retention_check_interval: 600,
+                file_deletion_grace_period: 0,
+                metastore_snapshot_interval: 60,
             }),
         }
     }
@@ -223,17 +722,7 @@ impl Config {
         T: Future + Send + 'static,
         T::Output: Send + 'static,
     {
-        if !*TEST_LOGGING_INITIALIZED.read().await {
-            let mut initialized = TEST_LOGGING_INITIALIZED.write().await;
-            if !*initialized {
-                SimpleLogger::new()
-                    .with_level(Level::Error.to_level_filter())
-                    .with_module_level("cubestore", Level::Trace.to_level_filter())
-                    .init()
-                    .unwrap();
-            }
-            *initialized = true;
-        }
+        init_test_logger();
 
         let store_path = self.local_dir().clone();
         let remote_store_path = self.remote_dir().clone();
@@ -309,11 +798,13 @@ impl Config {
         .unwrap();
         meta_store.add_listener(event_sender).await;
         let wal_store = WALStore::new(meta_store.clone(), remote_fs.clone(), 500000);
+        let memory_chunks = MemoryChunkStore::new(self.config_obj.in_memory_chunks_max_size());
         let chunk_store = ChunkStore::new(
             meta_store.clone(),
             remote_fs.clone(),
             wal_store.clone(),
             262144,
+            memory_chunks.clone(),
         );
         let compaction_service = CompactionServiceImpl::new(
             meta_store.clone(),
@@ -321,9 +812,17 @@ impl Config {
             remote_fs.clone(),
             self.config_obj.clone(),
         );
-        let import_service = ImportServiceImpl::new(meta_store.clone(), wal_store.clone());
-        let query_planner = QueryPlannerImpl::new(meta_store.clone());
-        let query_executor = Arc::new(QueryExecutorImpl);
+        let s3_import_progress = S3ImportProgress::new();
+        let import_service = ImportServiceImpl::new(
+            meta_store.clone(),
+            wal_store.clone(),
+            chunk_store.clone(),
+            self.config_obj.clone(),
+            self.config_obj.data_dir.join("import"),
+            s3_import_progress.clone(),
+        );
+        let query_executor =
+            QueryExecutorImpl::with_memory_chunks(self.config_obj.clone(), memory_chunks);
         let cluster = ClusterImpl::new(
             "localhost".to_string(),
             vec!["localhost".to_string()],
@@ -336,13 +835,31 @@ impl Config {
             self.config_obj.clone(),
             query_executor.clone(),
         );
+        let query_planner = QueryPlannerImpl::with_download_queue(
+            meta_store.clone(),
+            remote_fs.clone(),
+            self.config_obj.clone(),
+            s3_import_progress,
+            cluster.download_queue(),
+        );
 
-        let sql_service = SqlServiceImpl::new(
+        let insert_buffer = InsertBuffer::new(
+            self.config_obj.data_dir.join("insert-buffer"),
+            self.config_obj.insert_buffer_max_rows(),
+            self.config_obj.insert_buffer_max_bytes(),
+            Duration::from_secs(self.config_obj.insert_buffer_max_age()),
+            self.config_obj.insert_buffer_read_your_writes(),
+        )
+        .unwrap();
+        let sql_service = SqlServiceImpl::with_insert_buffer(
             meta_store.clone(),
             wal_store.clone(),
+            chunk_store.clone(),
             query_planner.clone(),
             query_executor.clone(),
             cluster.clone(),
+            insert_buffer,
+            self.config_obj.clone(),
         );
         let scheduler = SchedulerImpl::new(
             meta_store.clone(),
@@ -364,7 +881,7 @@ impl Config {
     pub fn configure_worker(&self) {
         let mut services = WORKER_SERVICES.write().unwrap();
         *services = Some(WorkerServices {
-            query_executor: Arc::new(QueryExecutorImpl),
+            query_executor: QueryExecutorImpl::new(self.config_obj.clone()),
         })
     }
 
@@ -0,0 +1,404 @@
+use crate::sql::SqlService;
+use crate::CubeError;
+use arrow::ipc::writer::IpcWriteOptions;
+use arrow_flight::flight_descriptor::DescriptorType;
+use arrow_flight::flight_service_server::{FlightService, FlightServiceServer};
+use arrow_flight::utils::{
+    flight_data_from_arrow_batch, flight_data_from_arrow_schema, flight_schema_from_arrow_schema,
+};
+use arrow_flight::{
+    Action, ActionType, Criteria, Empty, FlightData, FlightDescriptor, FlightEndpoint, FlightInfo,
+    HandshakeRequest, HandshakeResponse, PutResult, SchemaResult, Ticket,
+};
+use async_trait::async_trait;
+use datafusion::physical_plan::RecordBatchStream;
+use futures::future::{abortable, AbortHandle};
+use futures::{Stream, StreamExt};
+use log::{debug, info};
+use std::iter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+type BoxedStream<T> = Pin<Box<dyn Stream<Item = Result<T, Status>> + Send + Sync + 'static>>;
+
+/// Arrow Flight endpoint streaming results of SQL queries as Arrow IPC messages. Tickets and
+/// command descriptors hold the SQL text of a query.
+pub struct FlightServer;
+
+impl FlightServer {
+    pub async fn listen(
+        address: String,
+        sql_service: Arc<dyn SqlService>,
+        max_result_rows: u64,
+        max_result_bytes: u64,
+    ) -> Result<(), CubeError> {
+        let listener = TcpListener::bind(address.clone()).await?;
+
+        info!("Arrow Flight port open on {}", address);
+
+        FlightServer::serve(listener, sql_service, max_result_rows, max_result_bytes).await
+    }
+
+    pub async fn serve(
+        mut listener: TcpListener,
+        sql_service: Arc<dyn SqlService>,
+        max_result_rows: u64,
+        max_result_bytes: u64,
+    ) -> Result<(), CubeError> {
+        let service = FlightServiceImpl {
+            sql_service,
+            max_result_rows,
+            max_result_bytes,
+        };
+        Server::builder()
+            .add_service(FlightServiceServer::new(service))
+            .serve_with_incoming(listener.incoming())
+            .await?;
+        Ok(())
+    }
+}
+
+struct FlightServiceImpl {
+    sql_service: Arc<dyn SqlService>,
+    /// Not limited if 0.
+    max_result_rows: u64,
+    /// Not limited if 0.
+    max_result_bytes: u64,
+}
+
+impl FlightServiceImpl {
+    async fn schema_result(&self, descriptor: &FlightDescriptor) -> Result<SchemaResult, Status> {
+        let query = descriptor_query(descriptor)?;
+        let schema = self.sql_service.query_stream_schema(&query).await?;
+        Ok(flight_schema_from_arrow_schema(
+            schema.as_ref(),
+            &IpcWriteOptions::default(),
+        ))
+    }
+}
+
+#[async_trait]
+impl FlightService for FlightServiceImpl {
+    type HandshakeStream = BoxedStream<HandshakeResponse>;
+    type ListFlightsStream = BoxedStream<FlightInfo>;
+    type DoGetStream = BoxedStream<FlightData>;
+    type DoPutStream = BoxedStream<PutResult>;
+    type DoActionStream = BoxedStream<arrow_flight::Result>;
+    type ListActionsStream = BoxedStream<ActionType>;
+    type DoExchangeStream = BoxedStream<FlightData>;
+
+    async fn handshake(
+        &self,
+        _request: Request<Streaming<HandshakeRequest>>,
+    ) -> Result<Response<Self::HandshakeStream>, Status> {
+        Err(Status::unimplemented("Handshake isn't required"))
+    }
+
+    async fn list_flights(
+        &self,
+        _request: Request<Criteria>,
+    ) -> Result<Response<Self::ListFlightsStream>, Status> {
+        Err(Status::unimplemented("Flights can't be listed"))
+    }
+
+    async fn get_flight_info(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<FlightInfo>, Status> {
+        let descriptor = request.into_inner();
+        let schema = self.schema_result(&descriptor).await?;
+        let endpoint = FlightEndpoint {
+            ticket: Some(Ticket {
+                ticket: descriptor.cmd.clone(),
+            }),
+            location: Vec::new(),
+        };
+        Ok(Response::new(FlightInfo {
+            schema: schema.schema,
+            flight_descriptor: Some(descriptor),
+            endpoint: vec![endpoint],
+            total_records: -1,
+            total_bytes: -1,
+        }))
+    }
+
+    async fn get_schema(
+        &self,
+        request: Request<FlightDescriptor>,
+    ) -> Result<Response<SchemaResult>, Status> {
+        Ok(Response::new(self.schema_result(request.get_ref()).await?))
+    }
+
+    async fn do_get(
+        &self,
+        request: Request<Ticket>,
+    ) -> Result<Response<Self::DoGetStream>, Status> {
+        let query = String::from_utf8(request.into_inner().ticket)
+            .map_err(|e| Status::invalid_argument(format!("Ticket isn't a SQL query: {}", e)))?;
+        let stream = self.sql_service.exec_query_stream(&query).await?;
+        // Two messages are buffered so the query doesn't get ahead of a slow client
+        let (tx, rx) = mpsc::channel(2);
+        let (send, query_handle) = abortable(send_results(
+            stream,
+            tx,
+            self.max_result_rows,
+            self.max_result_bytes,
+        ));
+        tokio::spawn(send);
+        Ok(Response::new(Box::pin(ResultStream {
+            messages: rx,
+            query_handle,
+        })))
+    }
+
+    async fn do_put(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoPutStream>, Status> {
+        Err(Status::unimplemented(
+            "Data can't be put through Arrow Flight",
+        ))
+    }
+
+    async fn do_action(
+        &self,
+        _request: Request<Action>,
+    ) -> Result<Response<Self::DoActionStream>, Status> {
+        Err(Status::unimplemented("No actions are supported"))
+    }
+
+    async fn list_actions(
+        &self,
+        _request: Request<Empty>,
+    ) -> Result<Response<Self::ListActionsStream>, Status> {
+        Ok(Response::new(Box::pin(futures::stream::empty())))
+    }
+
+    async fn do_exchange(
+        &self,
+        _request: Request<Streaming<FlightData>>,
+    ) -> Result<Response<Self::DoExchangeStream>, Status> {
+        Err(Status::unimplemented(
+            "Data can't be exchanged through Arrow Flight",
+        ))
+    }
+}
+
+fn descriptor_query(descriptor: &FlightDescriptor) -> Result<String, Status> {
+    if descriptor.r#type != DescriptorType::Cmd as i32 {
+        return Err(Status::invalid_argument(
+            "Only command descriptors with a SQL query are supported",
+        ));
+    }
+    String::from_utf8(descriptor.cmd.clone())
+        .map_err(|e| Status::invalid_argument(format!("Command isn't a SQL query: {}", e)))
+}
+
+/// IPC messages sent by a query task. The task and so the query is cancelled once the client
+/// disconnects and the response is dropped.
+struct ResultStream {
+    messages: mpsc::Receiver<Result<FlightData, Status>>,
+    query_handle: AbortHandle,
+}
+
+impl Stream for ResultStream {
+    type Item = Result<FlightData, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.messages.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for ResultStream {
+    fn drop(&mut self) {
+        self.query_handle.abort();
+    }
+}
+
+/// Sends the schema and then batches of `stream`. An error is sent as the last message.
+async fn send_results(
+    mut stream: Pin<Box<dyn RecordBatchStream + Send>>,
+    mut tx: mpsc::Sender<Result<FlightData, Status>>,
+    max_rows: u64,
+    max_bytes: u64,
+) {
+    let res = send_batches(&mut stream, &mut tx, max_rows, max_bytes).await;
+    match res {
+        Ok(true) => {}
+        Ok(false) => debug!("Arrow Flight client disconnected before the end of the result"),
+        Err(e) => {
+            let _ = tx.send(Err(e)).await;
+        }
+    }
+}
+
+/// Returns `false` if the client disconnected before all batches were sent.
+async fn send_batches(
+    stream: &mut Pin<Box<dyn RecordBatchStream + Send>>,
+    tx: &mut mpsc::Sender<Result<FlightData, Status>>,
+    max_rows: u64,
+    max_bytes: u64,
+) -> Result<bool, Status> {
+    let options = IpcWriteOptions::default();
+    let schema = flight_data_from_arrow_schema(stream.schema().as_ref(), &options);
+    if tx.send(Ok(schema)).await.is_err() {
+        return Ok(false);
+    }
+    let mut rows = 0;
+    let mut bytes = 0;
+    while let Some(batch) = stream.next().await {
+        let batch = batch.map_err(|e| Status::from(CubeError::from(e)))?;
+        rows += batch.num_rows() as u64;
+        if max_rows > 0 && rows > max_rows {
+            return Err(Status::resource_exhausted(format!(
+                "Query result exceeds the limit of {} rows. Consider adding LIMIT to the query",
+                max_rows
+            )));
+        }
+        let (dictionaries, data) = flight_data_from_arrow_batch(&batch, &options);
+        for data in dictionaries.into_iter().chain(iter::once(data)) {
+            bytes += (data.data_header.len() + data.data_body.len()) as u64;
+            if max_bytes > 0 && bytes > max_bytes {
+                return Err(Status::resource_exhausted(format!(
+                    "Query result exceeds the limit of {} bytes. Consider adding LIMIT to the query",
+                    max_bytes
+                )));
+            }
+            if tx.send(Ok(data)).await.is_err() {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::queryplanner::query_executor::batch_to_dataframe;
+    use arrow::datatypes::Schema;
+    use arrow::record_batch::RecordBatch;
+    use arrow_flight::flight_service_client::FlightServiceClient;
+    use arrow_flight::utils::flight_data_to_arrow_batch;
+    use std::convert::TryFrom;
+    use tonic::Code;
+
+    async fn start(sql_service: Arc<dyn SqlService>, max_rows: u64, max_bytes: u64) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(FlightServer::serve(
+            listener,
+            sql_service,
+            max_rows,
+            max_bytes,
+        ));
+        url
+    }
+
+    async fn fetch(url: &str, query: &str) -> Result<Vec<RecordBatch>, Status> {
+        let mut client = FlightServiceClient::connect(url.to_string()).await.unwrap();
+        let ticket = Ticket {
+            ticket: query.as_bytes().to_vec(),
+        };
+        let mut messages = client.do_get(ticket).await?.into_inner();
+        let schema = messages.message().await?.unwrap();
+        let schema = Arc::new(Schema::try_from(&schema).unwrap());
+        let mut batches = Vec::new();
+        while let Some(data) = messages.message().await? {
+            batches.push(flight_data_to_arrow_batch(&data, schema.clone(), &[]).unwrap());
+        }
+        Ok(batches)
+    }
+
+    #[tokio::test]
+    async fn stream_query_results() {
+        Config::run_test("flight_stream_query_results", async move |services| {
+            let service = services.sql_service;
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service
+                .exec_query("CREATE TABLE foo.orders (id int, city text, amount decimal(10, 2))")
+                .await
+                .unwrap();
+            // Every insert is a separate chunk and so a separate batch
+            for i in 0..3 {
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.orders (id, city, amount) \
+                         VALUES ({}, 'a', {}.15), ({}, NULL, -0.01)",
+                        i * 2,
+                        i,
+                        i * 2 + 1
+                    ))
+                    .await
+                    .unwrap();
+            }
+            let query = "SELECT id, city, amount FROM foo.orders ORDER BY id";
+            let expected = service.exec_query(query).await.unwrap();
+
+            let url = start(service.clone(), 0, 0).await;
+            let mut client = FlightServiceClient::connect(url.clone()).await.unwrap();
+            let descriptor = FlightDescriptor {
+                r#type: DescriptorType::Cmd as i32,
+                cmd: query.as_bytes().to_vec(),
+                path: Vec::new(),
+            };
+            let info = client
+                .get_flight_info(descriptor)
+                .await
+                .unwrap()
+                .into_inner();
+            let ticket = info.endpoint[0].ticket.clone().unwrap();
+            assert_eq!(ticket.ticket, query.as_bytes());
+            let schema = client
+                .get_schema(FlightDescriptor {
+                    r#type: DescriptorType::Cmd as i32,
+                    cmd: query.as_bytes().to_vec(),
+                    path: Vec::new(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            let schema = Schema::try_from(&schema).unwrap();
+            assert_eq!(
+                schema
+                    .fields()
+                    .iter()
+                    .map(|f| f.name().as_str())
+                    .collect::<Vec<_>>(),
+                vec!["id", "city", "amount"]
+            );
+
+            let batches = fetch(&url, "SELECT id, city, amount FROM foo.orders")
+                .await
+                .unwrap();
+            assert!(batches.len() > 1, "{:?}", batches);
+            let batches = fetch(&url, query).await.unwrap();
+            let data_frame = batch_to_dataframe(&batches).unwrap();
+            assert_eq!(data_frame.get_columns(), expected.get_columns());
+            assert_eq!(data_frame.get_rows(), expected.get_rows());
+
+            let error = fetch(&url, "CREATE SCHEMA bar").await.unwrap_err();
+            assert_eq!(error.code(), Code::InvalidArgument, "{}", error);
+
+            let url = start(service.clone(), 5, 0).await;
+            let batches = fetch(&url, "SELECT id FROM foo.orders LIMIT 5")
+                .await
+                .unwrap();
+            assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 5);
+            let error = fetch(&url, query).await.unwrap_err();
+            assert_eq!(error.code(), Code::ResourceExhausted, "{}", error);
+            assert!(error.message().contains("limit of 5 rows"), "{}", error);
+
+            let url = start(service, 0, 100).await;
+            let error = fetch(&url, query).await.unwrap_err();
+            assert!(error.message().contains("limit of 100 bytes"), "{}", error);
+        })
+        .await;
+    }
+}
@@ -0,0 +1,141 @@
+//! Hashes stored in the metastore or compared between nodes must not change with a Rust release,
+//! unlike the ones of `DefaultHasher` whose algorithm is unspecified.
+use std::hash::Hasher;
+
+/// SipHash 1-3 with zero keys. Gives the same hashes as `DefaultHasher::new()` of the Rust
+/// releases CubeStore has been built with, so hashes persisted before stay valid.
+#[derive(Debug, Clone)]
+pub struct StableHasher {
+    v0: u64,
+    v1: u64,
+    v2: u64,
+    v3: u64,
+    tail: u64,
+    ntail: usize,
+    length: usize,
+}
+
+impl StableHasher {
+    pub fn new() -> StableHasher {
+        StableHasher {
+            v0: 0x736f6d6570736575,
+            v1: 0x646f72616e646f6d,
+            v2: 0x6c7967656e657261,
+            v3: 0x7465646279746573,
+            tail: 0,
+            ntail: 0,
+            length: 0,
+        }
+    }
+
+    fn round(&mut self) {
+        self.v0 = self.v0.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(13);
+        self.v1 ^= self.v0;
+        self.v0 = self.v0.rotate_left(32);
+        self.v2 = self.v2.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(16);
+        self.v3 ^= self.v2;
+        self.v0 = self.v0.wrapping_add(self.v3);
+        self.v3 = self.v3.rotate_left(21);
+        self.v3 ^= self.v0;
+        self.v2 = self.v2.wrapping_add(self.v1);
+        self.v1 = self.v1.rotate_left(17);
+        self.v1 ^= self.v2;
+        self.v2 = self.v2.rotate_left(32);
+    }
+
+    fn compress(&mut self, m: u64) {
+        self.v3 ^= m;
+        self.round();
+        self.v0 ^= m;
+    }
+}
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher::new()
+    }
+}
+
+/// Little endian value of up to 8 bytes.
+fn load_le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .enumerate()
+        .fold(0, |v, (i, b)| v | ((*b as u64) << (8 * i)))
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.length += bytes.len();
+        let mut bytes = bytes;
+        if self.ntail != 0 {
+            let needed = (8 - self.ntail).min(bytes.len());
+            self.tail |= load_le(&bytes[..needed]) << (8 * self.ntail);
+            self.ntail += needed;
+            bytes = &bytes[needed..];
+            if self.ntail < 8 {
+                return;
+            }
+            let tail = self.tail;
+            self.compress(tail);
+            self.tail = 0;
+            self.ntail = 0;
+        }
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.compress(load_le(word));
+        }
+        let rest = words.remainder();
+        self.tail = load_le(rest);
+        self.ntail = rest.len();
+    }
+
+    fn finish(&self) -> u64 {
+        let mut state = self.clone();
+        let b = ((self.length as u64 & 0xff) << 56) | self.tail;
+        state.compress(b);
+        state.v2 ^= 0xff;
+        state.round();
+        state.round();
+        state.round();
+        state.v0 ^ state.v1 ^ state.v2 ^ state.v3
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::hash::Hash;
+
+    fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+        let mut hasher = StableHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn hashes_are_fixed() {
+        assert_eq!(hash(&Vec::<u8>::new()), 13646096770106105413);
+        assert_eq!(hash(&b"cubestore".to_vec()), 6339887417838180913);
+        assert_eq!(
+            hash("a string longer than eight bytes"),
+            8399710069457398803
+        );
+        assert_eq!(hash(&-42i64), 17964655908796450167);
+    }
+
+    #[test]
+    fn split_writes_give_same_hash() {
+        let bytes = (0..40u8).collect::<Vec<_>>();
+        let mut whole = StableHasher::new();
+        whole.write(&bytes);
+        for split in 0..bytes.len() {
+            let mut parts = StableHasher::new();
+            parts.write(&bytes[..split]);
+            parts.write(&bytes[split..]);
+            assert_eq!(parts.finish(), whole.finish(), "{}", split);
+        }
+    }
+}
@@ -4,12 +4,15 @@ use log::debug;
 use serde_derive::Deserialize;
 use warp::{Filter, Rejection};
 
-use crate::sql::SqlService;
+use crate::sql::{QueryOptions, SqlService};
 use crate::CubeError;
 
 #[derive(Deserialize, Debug)]
 pub struct SqlQueryBody {
     query: String,
+    /// Skips partitions failing on workers and reports them in warnings instead of failing.
+    #[serde(default)]
+    best_effort: bool,
 }
 
 pub async fn run_server(sql_service: Arc<dyn SqlService>) -> Result<(), CubeError> {
@@ -38,7 +41,13 @@ pub async fn post_query(
     query_body: SqlQueryBody,
     sql_service: Arc<dyn SqlService>,
 ) -> Result<String, Rejection> {
-    let res = sql_service.exec_query(&query_body.query).await?;
+    let options = QueryOptions {
+        best_effort: query_body.best_effort,
+        ..QueryOptions::default()
+    };
+    let res = sql_service
+        .exec_query_with_options(&query_body.query, options)
+        .await?;
     debug!("Query result is {:?}", res);
     debug!("Post query: {:?}", query_body);
     Ok(format!("{:?}", res))
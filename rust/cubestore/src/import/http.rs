@@ -0,0 +1,364 @@
+use crate::CubeError;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+use log::warn;
+use reqwest::header::{CONTENT_ENCODING, CONTENT_RANGE, RANGE};
+use reqwest::{Client, Response, StatusCode, Url};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::mem;
+use std::time::Duration;
+use tokio::io::{stream_reader, AsyncBufRead};
+use tokio::time::{delay_for, timeout};
+
+pub fn is_url_location(location: &str) -> bool {
+    let location = location.to_lowercase();
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpOptions {
+    /// Limits connecting and waiting for each chunk of the body rather than the whole download.
+    pub timeout: Duration,
+    /// Maximum size of the body in bytes. Not limited if 0.
+    pub max_size: u64,
+    /// Retries of failed requests, each one resumes the body from the last received byte.
+    pub retries: u32,
+    pub retry_delay: Duration,
+    /// Expected hex SHA-256 of the body as it's served.
+    pub sha256: Option<String>,
+}
+
+/// Body of an HTTP(S) location. Redirects are followed and if the connection drops the body is
+/// requested again from the last received byte with a `Range` header.
+pub struct HttpBody {
+    client: Client,
+    url: Url,
+    options: HttpOptions,
+    response: Option<Response>,
+    /// Bytes of the body received so far.
+    offset: u64,
+    sha256: Sha256,
+    gzip: bool,
+    done: bool,
+}
+
+impl HttpBody {
+    /// Sends the first request so missing locations fail before any rows are read.
+    pub async fn open(location: &str, options: HttpOptions) -> Result<HttpBody, CubeError> {
+        let url = Url::parse(location)
+            .map_err(|e| CubeError::user(format!("Invalid URL {}: {}", location, e)))?;
+        let client = Client::builder().connect_timeout(options.timeout).build()?;
+        let mut body = HttpBody {
+            client,
+            url,
+            options,
+            response: None,
+            offset: 0,
+            sha256: Sha256::new(),
+            gzip: false,
+            done: false,
+        };
+        let response = body
+            .send()
+            .await
+            .map_err(|e| CubeError::user(format!("Can't download {}: {}", body.url, e)))?;
+        body.response = Some(body.check(response)?);
+        Ok(body)
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Body is sent with `Content-Encoding: gzip` and should be decompressed.
+    pub fn gzip(&self) -> bool {
+        self.gzip
+    }
+
+    pub fn into_reader(self) -> Box<dyn AsyncBufRead + Send + Unpin> {
+        let chunks = stream::unfold(self, |mut body| async move {
+            if body.done {
+                return None;
+            }
+            match body.next_chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), body)),
+                Ok(None) => None,
+                Err(e) => {
+                    body.done = true;
+                    Some((
+                        Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+                        body,
+                    ))
+                }
+            }
+        });
+        Box::new(stream_reader(chunks.boxed()))
+    }
+
+    async fn next_chunk(&mut self) -> Result<Option<Bytes>, CubeError> {
+        let mut attempt = 0;
+        loop {
+            let res = match self.response.take() {
+                Some(mut response) => {
+                    let res = match timeout(self.options.timeout, response.chunk()).await {
+                        Ok(res) => res.map_err(|e| e.to_string()),
+                        Err(_) => Err(format!("no data in {:?}", self.options.timeout)),
+                    };
+                    self.response = Some(response);
+                    res
+                }
+                None => match self.send().await {
+                    Ok(response) => {
+                        self.response = Some(self.check(response)?);
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match res {
+                Ok(Some(chunk)) => {
+                    self.offset += chunk.len() as u64;
+                    self.check_size(self.offset)?;
+                    self.sha256.update(&chunk);
+                    return Ok(Some(chunk));
+                }
+                Ok(None) => {
+                    self.done = true;
+                    self.check_sha256()?;
+                    return Ok(None);
+                }
+                Err(e) if attempt < self.options.retries => {
+                    attempt += 1;
+                    warn!(
+                        "Resuming download of {} from byte {} after error: {}",
+                        self.url, self.offset, e
+                    );
+                    self.response = None;
+                    delay_for(self.options.retry_delay * 2u32.pow(attempt - 1)).await;
+                }
+                Err(e) => {
+                    return Err(CubeError::user(format!(
+                        "Can't download {} after {} attempts: {}",
+                        self.url,
+                        attempt + 1,
+                        e
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Errors returned here are transient and the request is retried.
+    async fn send(&self) -> Result<Response, String> {
+        let mut request = self.client.get(self.url.clone());
+        if self.offset > 0 {
+            request = request.header(RANGE, format!("bytes={}-", self.offset));
+        }
+        let response = match timeout(self.options.timeout, request.send()).await {
+            Ok(response) => response.map_err(|e| e.to_string())?,
+            Err(_) => return Err(format!("no response in {:?}", self.options.timeout)),
+        };
+        if response.status().is_server_error() {
+            return Err(format!("server responded with {}", response.status()));
+        }
+        Ok(response)
+    }
+
+    fn check(&mut self, response: Response) -> Result<Response, CubeError> {
+        let status = response.status();
+        if self.offset == 0 {
+            if !status.is_success() {
+                return Err(CubeError::user(format!(
+                    "Can't download {}: server responded with {}",
+                    self.url, status
+                )));
+            }
+            if let Some(length) = response.content_length() {
+                self.check_size(length)?;
+            }
+            self.gzip = response
+                .headers()
+                .get(CONTENT_ENCODING)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.trim().eq_ignore_ascii_case("gzip"))
+                .unwrap_or(false);
+            return Ok(response);
+        }
+        let range_start = response
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("bytes "))
+            .and_then(|v| v.split('-').next())
+            .and_then(|v| v.parse::<u64>().ok());
+        if status != StatusCode::PARTIAL_CONTENT || range_start != Some(self.offset) {
+            return Err(CubeError::user(format!(
+                "Can't resume download of {} from byte {}: server responded with {} and range {:?}",
+                self.url, self.offset, status, range_start
+            )));
+        }
+        Ok(response)
+    }
+
+    fn check_size(&self, size: u64) -> Result<(), CubeError> {
+        if self.options.max_size > 0 && size > self.options.max_size {
+            return Err(CubeError::user(format!(
+                "Size of {} exceeds the import limit of {} bytes",
+                self.url, self.options.max_size
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_sha256(&mut self) -> Result<(), CubeError> {
+        let expected = match &self.options.sha256 {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let actual = format!("{:x}", mem::take(&mut self.sha256).finalize());
+        if !actual.eq_ignore_ascii_case(expected.trim()) {
+            return Err(CubeError::user(format!(
+                "SHA-256 of {} is {} but {} is expected",
+                self.url, actual, expected
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Local server for import tests.
+#[cfg(test)]
+pub mod test_server {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves `body` at every path. The first response is cut off after `drop_after` bytes and
+    /// the following ones honor `Range` headers. `/redirect/path` is redirected to `/path`.
+    /// Returns the server URL and the number of body responses.
+    pub async fn serve(
+        body: Vec<u8>,
+        content_encoding: Option<&'static str>,
+        drop_after: usize,
+    ) -> (String, Arc<AtomicUsize>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let responses = Arc::new(AtomicUsize::new(0));
+        let responses_to_move = responses.clone();
+        let dropped = Arc::new(AtomicBool::new(false));
+        let body = Arc::new(body);
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let body = body.clone();
+                let responses = responses_to_move.clone();
+                let dropped = dropped.clone();
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match socket.read(&mut buf).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+                    let request = String::from_utf8_lossy(&request).to_string();
+                    let path = request.split_whitespace().nth(1).unwrap_or("/");
+                    if let Some(target) = path.strip_prefix("/redirect") {
+                        let response = format!(
+                            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            target
+                        );
+                        let _ = socket.write_all(response.as_bytes()).await;
+                        return;
+                    }
+                    responses.fetch_add(1, Ordering::SeqCst);
+                    let start = request.lines().find_map(|l| {
+                        let l = l.to_lowercase();
+                        l.strip_prefix("range: bytes=")
+                            .and_then(|r| r.trim().trim_end_matches('-').parse::<usize>().ok())
+                    });
+                    let mut head = match start {
+                        Some(start) => format!(
+                            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\n",
+                            start,
+                            body.len() - 1,
+                            body.len(),
+                            body.len() - start
+                        ),
+                        None => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n", body.len()),
+                    };
+                    if let Some(encoding) = content_encoding {
+                        head += &format!("Content-Encoding: {}\r\n", encoding);
+                    }
+                    head += "Connection: close\r\n\r\n";
+                    let start = start.unwrap_or(0);
+                    let end = if dropped.swap(true, Ordering::SeqCst) {
+                        body.len()
+                    } else {
+                        body.len().min(start + drop_after)
+                    };
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    let _ = socket.write_all(&body[start..end]).await;
+                });
+            }
+        });
+        (url, responses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_server::serve;
+    use super::*;
+    use std::sync::atomic::Ordering;
+    use tokio::io::AsyncReadExt;
+
+    fn options(sha256: Option<String>, max_size: u64) -> HttpOptions {
+        HttpOptions {
+            timeout: Duration::from_secs(5),
+            max_size,
+            retries: 2,
+            retry_delay: Duration::from_millis(1),
+            sha256,
+        }
+    }
+
+    async fn read(location: &str, options: HttpOptions) -> Result<String, CubeError> {
+        let mut res = String::new();
+        HttpBody::open(location, options)
+            .await?
+            .into_reader()
+            .read_to_string(&mut res)
+            .await?;
+        Ok(res)
+    }
+
+    #[tokio::test]
+    async fn resume_after_disconnect() {
+        let data = (0..100).map(|i| format!("{},a\n", i)).collect::<String>();
+        let (url, responses) = serve(data.as_bytes().to_vec(), None, 100).await;
+        let sha256 = format!("{:x}", Sha256::digest(data.as_bytes()));
+
+        let location = format!("{}/redirect/data.csv?signature=1", url);
+        let body = read(&location, options(Some(sha256.to_uppercase()), 0)).await;
+        assert_eq!(body.unwrap(), data);
+        assert_eq!(responses.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn checksum_and_size_validation() {
+        let data = "1,a\n2,b\n".as_bytes().to_vec();
+        let (url, _) = serve(data.clone(), None, data.len()).await;
+        let location = format!("{}/data.csv", url);
+
+        let error = read(&location, options(Some("00".to_string()), 0))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("SHA-256"), "{}", error);
+
+        let error = read(&location, options(None, 4)).await.unwrap_err();
+        assert!(error.to_string().contains("import limit"), "{}", error);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::metastore::{Column, ColumnType, JsonOptions};
+use crate::table::{Row, TableValue, TimestampValue};
+use crate::CubeError;
+use bigdecimal::{BigDecimal, Num};
+use datafusion::physical_plan::datetime_expressions::string_to_timestamp_nanos;
+use futures::{future, Stream, StreamExt};
+use serde_json::{Map, Value};
+use std::pin::Pin;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+/// Rows of newline-delimited JSON objects. Columns are read from top-level fields of the same
+/// name or from nested fields by `paths` of the options. Missing fields are NULL.
+pub fn json_row_stream(
+    reader: Box<dyn AsyncBufRead + Send + Unpin>,
+    columns: Vec<Column>,
+    options: JsonOptions,
+) -> Pin<Box<dyn Stream<Item = Result<Row, CubeError>> + Send>> {
+    let paths = columns
+        .iter()
+        .map(|c| {
+            options
+                .paths
+                .iter()
+                .find(|(column, _)| column == c.get_name())
+                .map(|(_, path)| path.split('.').map(|s| s.to_string()).collect())
+                .unwrap_or_else(|| vec![c.get_name().to_string()])
+        })
+        .collect::<Vec<Vec<_>>>();
+    reader
+        .lines()
+        .enumerate()
+        .filter_map(move |(i, line)| {
+            let line_number = i + 1;
+            future::ready(match line {
+                Ok(line) if line.trim().is_empty() => None,
+                Ok(line) => Some(parse_line(&line, &columns, &paths, options.strict).map_err(
+                    |e| {
+                        CubeError::corrupted_data(format!(
+                            "Malformed JSON at line {}: {}",
+                            line_number, e
+                        ))
+                    },
+                )),
+                Err(e) => Some(Err(CubeError::user(format!(
+                    "Can't read line {}: {}",
+                    line_number, e
+                )))),
+            })
+        })
+        .boxed()
+}
+
+fn parse_line(
+    line: &str,
+    columns: &Vec<Column>,
+    paths: &Vec<Vec<String>>,
+    strict: bool,
+) -> Result<Row, String> {
+    let object = match serde_json::from_str::<Value>(line).map_err(|e| e.to_string())? {
+        Value::Object(object) => object,
+        v => return Err(format!("expected an object but found {}", v)),
+    };
+    if strict {
+        if let Some(field) = object
+            .keys()
+            .find(|k| !paths.iter().any(|p| field_matches(k, &p[0])))
+        {
+            return Err(format!("field '{}' doesn't match any column", field));
+        }
+    }
+    let mut row = Vec::with_capacity(columns.len());
+    for (column, path) in columns.iter().zip(paths.iter()) {
+        let value = match nested_value(&object, path) {
+            None | Some(Value::Null) => TableValue::Null,
+            Some(value) => match convert_value(column.get_column_type(), value) {
+                Ok(value) => value,
+                Err(e) if strict => {
+                    return Err(format!("column '{}' {}", column.get_name(), e));
+                }
+                Err(_) => TableValue::Null,
+            },
+        };
+        row.push(value);
+    }
+    Ok(Row::new(row))
+}
+
+/// Field names are matched like CSV headers: an exact match is preferred to a match ignoring case.
+fn field_matches(field: &str, name: &str) -> bool {
+    field == name || field.to_lowercase() == name.to_lowercase()
+}
+
+fn nested_value<'a>(object: &'a Map<String, Value>, path: &[String]) -> Option<&'a Value> {
+    let value = object.get(&path[0]).or_else(|| {
+        object
+            .iter()
+            .find(|(k, _)| field_matches(k, &path[0]))
+            .map(|(_, v)| v)
+    })?;
+    if path.len() == 1 {
+        return Some(value);
+    }
+    match value {
+        Value::Object(object) => nested_value(object, &path[1..]),
+        _ => None,
+    }
+}
+
+fn convert_value(column_type: &ColumnType, value: &Value) -> Result<TableValue, String> {
+    let mismatch = || format!("can't be {} value {}", column_type, value);
+    Ok(match (column_type, value) {
+        (ColumnType::String, Value::String(s)) => TableValue::String(s.clone()),
+        (ColumnType::String, Value::Number(n)) => TableValue::String(n.to_string()),
+        (ColumnType::String, Value::Bool(b)) => TableValue::String(b.to_string()),
+        (ColumnType::Int, Value::Number(n)) => TableValue::Int(
+            n.as_i64()
+                .or_else(|| {
+                    n.as_f64()
+                        .filter(|f| f.fract() == 0.0 && f.abs() < i64::MAX as f64)
+                        .map(|f| f as i64)
+                })
+                .ok_or_else(mismatch)?,
+        ),
+        (ColumnType::Int, Value::String(s)) => {
+            TableValue::Int(s.trim().parse().map_err(|_| mismatch())?)
+        }
+        (ColumnType::Decimal { .. }, Value::Number(n)) => {
+            decimal(&n.to_string()).ok_or_else(mismatch)?
+        }
+        (ColumnType::Decimal { .. }, Value::String(s)) => decimal(s.trim()).ok_or_else(mismatch)?,
+        (ColumnType::Timestamp, Value::String(s)) => TableValue::Timestamp(TimestampValue::new(
+            string_to_timestamp_nanos(s).map_err(|_| mismatch())?,
+        )),
+        (ColumnType::Boolean, Value::Bool(b)) => TableValue::Boolean(*b),
+        (ColumnType::Boolean, Value::String(s)) => match s.to_lowercase().as_str() {
+            "true" => TableValue::Boolean(true),
+            "false" => TableValue::Boolean(false),
+            _ => return Err(mismatch()),
+        },
+        _ => return Err(mismatch()),
+    })
+}
+
+fn decimal(value: &str) -> Option<TableValue> {
+    BigDecimal::from_str_radix(value, 10)
+        .ok()
+        .map(|d| TableValue::Decimal(d.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("id".to_string(), ColumnType::Int, 0),
+            Column::new("plan".to_string(), ColumnType::String, 1),
+            Column::new(
+                "amount".to_string(),
+                ColumnType::Decimal {
+                    scale: 5,
+                    precision: 18,
+                },
+                2,
+            ),
+            Column::new("ts".to_string(), ColumnType::Timestamp, 3),
+        ]
+    }
+
+    async fn read(data: &'static str, options: JsonOptions) -> Vec<Result<Row, CubeError>> {
+        json_row_stream(
+            Box::new(BufReader::new(data.as_bytes())),
+            columns(),
+            options,
+        )
+        .collect()
+        .await
+    }
+
+    fn nested_plan(strict: bool) -> JsonOptions {
+        JsonOptions {
+            strict,
+            paths: vec![("plan".to_string(), "properties.plan".to_string())],
+            max_errors: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn missing_fields_are_null() {
+        let rows = read(
+            "{\"id\": 1, \"amount\": 10.5, \"extra\": [1]}\n\n{\"ID\": \"2\", \"ts\": \"2021-01-01T00:00:00Z\"}\n",
+            JsonOptions::default(),
+        )
+        .await;
+        assert_eq!(
+            rows.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![
+                Row::new(vec![
+                    TableValue::Int(1),
+                    TableValue::Null,
+                    TableValue::Decimal("10.5".to_string()),
+                    TableValue::Null,
+                ]),
+                Row::new(vec![
+                    TableValue::Int(2),
+                    TableValue::Null,
+                    TableValue::Null,
+                    TableValue::Timestamp(TimestampValue::new(1609459200000000000)),
+                ]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_fields_by_path() {
+        let rows = read(
+            "{\"id\": 1, \"properties\": {\"plan\": \"pro\", \"seats\": 3}}\n{\"id\": 2, \"properties\": \"none\"}\n",
+            nested_plan(true),
+        )
+        .await;
+        assert_eq!(
+            rows.into_iter()
+                .map(|r| r.unwrap().values()[..2].to_vec())
+                .collect::<Vec<_>>(),
+            vec![
+                vec![TableValue::Int(1), TableValue::String("pro".to_string())],
+                vec![TableValue::Int(2), TableValue::Null],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn type_mismatch() {
+        let data = "{\"id\": 1.5, \"properties\": {\"plan\": \"pro\"}}\n{\"id\": 2, \"unknown\": 1}\nnot json\n";
+
+        let rows = read(data, nested_plan(false)).await;
+        assert_eq!(rows.len(), 3);
+        assert_eq!(
+            rows[0].as_ref().unwrap().values()[..2].to_vec(),
+            vec![TableValue::Null, TableValue::String("pro".to_string())]
+        );
+        assert_eq!(rows[1].as_ref().unwrap().values()[0], TableValue::Int(2));
+        let error = rows[2].as_ref().unwrap_err();
+        assert!(error.is_corrupted_data());
+        assert!(error.to_string().contains("line 3"), "{}", error);
+
+        let rows = read(data, nested_plan(true)).await;
+        let errors = rows
+            .iter()
+            .map(|r| r.as_ref().unwrap_err().to_string())
+            .collect::<Vec<_>>();
+        assert!(errors[0].contains("line 1: column 'id'"), "{}", errors[0]);
+        assert!(
+            errors[1].contains("line 2: field 'unknown'"),
+            "{}",
+            errors[1]
+        );
+        assert!(errors[2].contains("line 3"), "{}", errors[2]);
+    }
+}
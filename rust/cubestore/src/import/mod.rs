@@ -1,16 +1,32 @@
-use crate::metastore::{Column, ColumnType, ImportFormat, MetaStore};
-use crate::store::{DataFrame, WALDataStore};
-use crate::table::{Row, TableValue};
+pub mod http;
+mod json;
+mod parquet;
+pub mod s3;
+
+use crate::config::ConfigObj;
+use crate::import::http::{is_url_location, HttpBody, HttpOptions};
+use crate::import::json::json_row_stream;
+use crate::import::parquet::parquet_row_stream;
+use crate::import::s3::{is_s3_location, RustS3Client, S3Downloader, S3ImportProgress, S3Location};
+use crate::metastore::table::Table;
+use crate::metastore::{Column, ColumnType, CsvOptions, IdRow, ImportFormat, MetaStore};
+use crate::store::{ChunkDataStore, DataFrame, WALDataStore};
+use crate::table::{Row, TableValue, TimestampValue};
 use crate::CubeError;
+use async_compression::tokio_02::bufread::{GzipDecoder, ZstdDecoder};
 use async_trait::async_trait;
 use bigdecimal::{BigDecimal, Num};
 use core::mem;
-use futures::StreamExt;
+use datafusion::physical_plan::datetime_expressions::string_to_timestamp_nanos;
+use futures::{stream, StreamExt};
+use log::warn;
 use mockall::automock;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines};
 use tokio::stream::Stream;
 
 impl ImportFormat {
@@ -20,49 +36,260 @@ impl ImportFormat {
         columns: Vec<Column>,
     ) -> Result<Pin<Box<dyn Stream<Item = Result<Row, CubeError>> + Send>>, CubeError> {
         match self {
-            ImportFormat::CSV => {
-                let file = File::open(location).await?;
-                let lines = BufReader::new(file).lines();
-                let rows = lines.map(move |line| -> Result<Row, CubeError> {
-                    let str = line?;
-                    let mut remaining: &str = str.as_str();
-                    let mut row = Vec::with_capacity(columns.len());
-                    for column in columns.iter() {
-                        let value = if remaining.chars().nth(0) == Some('"') {
-                            let closing_index = remaining
-                                .find("\"")
-                                .ok_or(CubeError::user(format!("Malformed CSV string: {}", str)))?;
-                            let res: &str = remaining[1..closing_index].as_ref();
-                            remaining = remaining[closing_index..].as_ref();
-                            res
-                        } else {
-                            let next_comma = remaining.find(",").unwrap_or(remaining.len());
-                            let res: &str = remaining[0..next_comma].as_ref();
-                            remaining = remaining[next_comma..].as_ref();
-                            res
-                        };
-
-                        row.push(match column.get_column_type() {
-                            ColumnType::String => TableValue::String(value.to_string()),
-                            ColumnType::Int => value
-                                .parse()
-                                .map(|v| TableValue::Int(v))
-                                .unwrap_or(TableValue::Null),
-                            ColumnType::Decimal { .. } => BigDecimal::from_str_radix(value, 10)
-                                .map(|d| TableValue::Decimal(d.to_string()))
-                                .unwrap_or(TableValue::Null),
-                            x => panic!("CSV import for {:?} is not implemented", x),
-                        });
-
-                        if remaining.chars().nth(0) == Some(',') {
-                            remaining = remaining[1..].as_ref()
-                        }
+            ImportFormat::Parquet => Ok(parquet_row_stream(location, columns)),
+            _ => {
+                let reader = open_decompressed(&location).await?;
+                self.reader_row_stream(location, reader, columns).await
+            }
+        }
+    }
+
+    /// Rows of formats which are read sequentially.
+    async fn reader_row_stream(
+        &self,
+        location: String,
+        reader: Box<dyn AsyncBufRead + Send + Unpin>,
+        columns: Vec<Column>,
+    ) -> Result<Pin<Box<dyn Stream<Item = Result<Row, CubeError>> + Send>>, CubeError> {
+        match self {
+            ImportFormat::CSV | ImportFormat::CSVWithOptions(_) => {
+                let mut records = CsvRecords::new(reader, self.csv_options());
+                // Positions of table columns in CSV records
+                let (positions, fields_count) = if records.options.with_header {
+                    let (_, header) = records.next_record().await.unwrap_or_else(|| {
+                        Err(CubeError::user(format!(
+                            "CSV header is missing in {}",
+                            location
+                        )))
+                    })?;
+                    let positions = columns
+                        .iter()
+                        .map(|c| {
+                            header_position(&header, c.get_name()).ok_or(CubeError::user(format!(
+                                "Column '{}' is not found in CSV header of {}: {}",
+                                c.get_name(),
+                                location,
+                                header.join(", ")
+                            )))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    (positions, header.len())
+                } else {
+                    ((0..columns.len()).collect(), columns.len())
+                };
+                let rows = stream::unfold(records, |mut records| async move {
+                    let record = records.next_record().await?;
+                    Some((record, records))
+                })
+                .map(move |record| -> Result<Row, CubeError> {
+                    let (line, fields) = record?;
+                    if fields.len() != fields_count {
+                        return Err(CubeError::corrupted_data(format!(
+                            "Malformed CSV row at line {}: expected {} fields but found {}",
+                            line,
+                            fields_count,
+                            fields.len()
+                        )));
                     }
+                    let row = columns
+                        .iter()
+                        .zip(positions.iter())
+                        .map(|(column, position)| parse_value(column, &fields[*position]))
+                        .collect();
                     Ok(Row::new(row))
                 });
                 Ok(rows.boxed())
             }
+            ImportFormat::JSONLines(options) => {
+                Ok(json_row_stream(reader, columns, options.clone()))
+            }
+            ImportFormat::Parquet => Err(CubeError::internal(format!(
+                "Parquet can't be read sequentially: {}",
+                location
+            ))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Detects compression by the file extension falling back to magic bytes of the file start.
+    fn detect(location: &str, head: &[u8]) -> Compression {
+        let location = location.to_lowercase();
+        if location.ends_with(".gz") || location.ends_with(".gzip") {
+            Compression::Gzip
+        } else if location.ends_with(".zst") || location.ends_with(".zstd") {
+            Compression::Zstd
+        } else if head.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if head.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+}
+
+/// Opens the file decompressing it on the fly so memory doesn't depend on the uncompressed size.
+async fn open_decompressed(
+    location: &str,
+) -> Result<Box<dyn AsyncBufRead + Send + Unpin>, CubeError> {
+    let file = BufReader::new(File::open(location).await?);
+    decompressed(location, Box::new(file)).await
+}
+
+/// Concatenated gzip and zstd members are read as a single stream.
+async fn decompressed(
+    location: &str,
+    mut reader: Box<dyn AsyncBufRead + Send + Unpin>,
+) -> Result<Box<dyn AsyncBufRead + Send + Unpin>, CubeError> {
+    let compression = Compression::detect(location, reader.fill_buf().await?);
+    Ok(match compression {
+        Compression::None => reader,
+        Compression::Gzip => {
+            let mut decoder = GzipDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+        Compression::Zstd => {
+            let mut decoder = ZstdDecoder::new(reader);
+            decoder.multiple_members(true);
+            Box::new(BufReader::new(decoder))
+        }
+    })
+}
+
+fn header_position(header: &Vec<String>, column_name: &str) -> Option<usize> {
+    header.iter().position(|h| h == column_name).or_else(|| {
+        header
+            .iter()
+            .position(|h| h.to_lowercase() == column_name.to_lowercase())
+    })
+}
+
+fn parse_value(column: &Column, value: &str) -> TableValue {
+    match column.get_column_type() {
+        ColumnType::String => TableValue::String(value.to_string()),
+        ColumnType::Int => value
+            .parse()
+            .map(|v| TableValue::Int(v))
+            .unwrap_or(TableValue::Null),
+        ColumnType::Decimal { .. } => BigDecimal::from_str_radix(value, 10)
+            .map(|d| TableValue::Decimal(d.to_string()))
+            .unwrap_or(TableValue::Null),
+        // Offsets are applied while parsing so values are stored as UTC
+        ColumnType::Timestamp => string_to_timestamp_nanos(value)
+            .map(|v| TableValue::Timestamp(TimestampValue::new(v)))
+            .unwrap_or(TableValue::Null),
+        x => panic!("CSV import for {:?} is not implemented", x),
+    }
+}
+
+/// Reads CSV records line by line. Quoted fields can contain delimiters and span several lines.
+struct CsvRecords<R: AsyncBufRead + Unpin> {
+    lines: Lines<R>,
+    options: CsvOptions,
+    line_number: usize,
+}
+
+impl<R: AsyncBufRead + Unpin> CsvRecords<R> {
+    fn new(reader: R, options: CsvOptions) -> Self {
+        CsvRecords {
+            lines: reader.lines(),
+            options,
+            line_number: 0,
+        }
+    }
+
+    /// Returns the line number the record starts at along with its fields. Empty lines are skipped.
+    async fn next_record(&mut self) -> Option<Result<(usize, Vec<String>), CubeError>> {
+        let mut line = match self.next_line().await? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(e)),
+        };
+        while line.is_empty() {
+            line = match self.next_line().await? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e)),
+            };
+        }
+        let start_line = self.line_number;
+
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut quoted = false;
+        let mut error = None;
+        loop {
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                if in_quotes {
+                    if Some(c) == self.options.escape && c != self.options.quote {
+                        match chars.next() {
+                            Some(escaped) => field.push(escaped),
+                            None => field.push(c),
+                        }
+                    } else if c == self.options.quote {
+                        if chars.peek() == Some(&self.options.quote) {
+                            field.push(chars.next().unwrap());
+                        } else {
+                            in_quotes = false;
+                        }
+                    } else {
+                        field.push(c);
+                    }
+                } else if c == self.options.delimiter {
+                    fields.push(mem::take(&mut field));
+                    quoted = false;
+                } else if c == self.options.quote && field.is_empty() && !quoted {
+                    in_quotes = true;
+                    quoted = true;
+                } else {
+                    if quoted && error.is_none() {
+                        error = Some(format!(
+                            "unexpected {:?} after closing quote at line {}",
+                            c, self.line_number
+                        ));
+                    }
+                    field.push(c);
+                }
+            }
+            if !in_quotes {
+                break;
+            }
+            field.push('\n');
+            line = match self.next_line().await {
+                Some(Ok(line)) => line,
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    error = Some("quoted field is not closed at the end of file".to_string());
+                    break;
+                }
+            };
         }
+        fields.push(field);
+
+        Some(match error {
+            Some(error) => Err(CubeError::corrupted_data(format!(
+                "Malformed CSV row at line {}: {}",
+                start_line, error
+            ))),
+            None => Ok((start_line, fields)),
+        })
+    }
+
+    async fn next_line(&mut self) -> Option<Result<String, CubeError>> {
+        let line = self.lines.next().await?;
+        self.line_number += 1;
+        let line_number = self.line_number;
+        // Decompression errors such as garbage after the last member are reported here as well
+        Some(line.map_err(|e| CubeError::user(format!("Can't read line {}: {}", line_number, e))))
     }
 }
 
@@ -75,18 +302,223 @@ pub trait ImportService: Send + Sync {
 pub struct ImportServiceImpl {
     meta_store: Arc<dyn MetaStore>,
     wal_store: Arc<dyn WALDataStore>,
+    chunk_store: Arc<dyn ChunkDataStore>,
+    config: Arc<dyn ConfigObj>,
+    /// Objects of S3 locations are downloaded here before they're imported.
+    import_dir: PathBuf,
+    s3_progress: Arc<S3ImportProgress>,
 }
 
 impl ImportServiceImpl {
     pub fn new(
         meta_store: Arc<dyn MetaStore>,
         wal_store: Arc<dyn WALDataStore>,
+        chunk_store: Arc<dyn ChunkDataStore>,
+        config: Arc<dyn ConfigObj>,
+        import_dir: PathBuf,
+        s3_progress: Arc<S3ImportProgress>,
     ) -> Arc<ImportServiceImpl> {
         Arc::new(ImportServiceImpl {
             meta_store,
             wal_store,
+            chunk_store,
+            config,
+            import_dir,
+            s3_progress,
         })
     }
+
+    /// Downloads are kept if the import fails so they aren't downloaded again when it's retried.
+    async fn import_s3(
+        &self,
+        table: &IdRow<Table>,
+        format: &ImportFormat,
+        location: &str,
+    ) -> Result<(), CubeError> {
+        self.s3_progress.start(table.get_id(), location);
+        let dir = self.import_dir.join(table.get_id().to_string());
+        let res = async {
+            let downloader = S3Downloader::new(
+                RustS3Client::new(self.config.import_s3())?,
+                self.s3_progress.clone(),
+                self.config.import_download_concurrency(),
+                self.config.import_download_retries(),
+            );
+            let files = downloader
+                .download(table.get_id(), &S3Location::parse(location)?, &dir)
+                .await?;
+            self.s3_progress.set_status(table.get_id(), "importing");
+            let files = files
+                .iter()
+                .map(|f| f.to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            self.import_files(table, format, location, &files).await
+        }
+        .await;
+        match &res {
+            Ok(()) => {
+                self.s3_progress.set_status(table.get_id(), "done");
+                tokio::fs::remove_dir_all(&dir).await?;
+            }
+            Err(e) => self
+                .s3_progress
+                .set_status(table.get_id(), &format!("failed: {}", e)),
+        }
+        res
+    }
+
+    /// Body of the URL is parsed while it's downloaded except for parquet which is saved to a file
+    /// first as its metadata is at the end.
+    async fn import_url(
+        &self,
+        table: &IdRow<Table>,
+        format: &ImportFormat,
+        location: &str,
+    ) -> Result<(), CubeError> {
+        let body = HttpBody::open(
+            location,
+            HttpOptions {
+                timeout: Duration::from_secs(self.config.import_http_timeout()),
+                max_size: self.config.import_max_size(),
+                retries: self.config.import_download_retries(),
+                retry_delay: Duration::from_millis(200),
+                sha256: table.get_row().location_sha256().clone(),
+            },
+        )
+        .await?;
+        let path = body.url().path().to_string();
+        let mut reader = if body.gzip() {
+            let mut decoder = GzipDecoder::new(body.into_reader());
+            decoder.multiple_members(true);
+            // Extension of a content encoded file doesn't tell if it's compressed once more
+            decompressed("", Box::new(BufReader::new(decoder))).await?
+        } else {
+            decompressed(&path, body.into_reader()).await?
+        };
+        if let ImportFormat::Parquet = format {
+            let file = self.import_dir.join(format!("{}.parquet", table.get_id()));
+            let res = async {
+                tokio::fs::create_dir_all(&self.import_dir).await?;
+                tokio::io::copy(&mut reader, &mut File::create(&file).await?).await?;
+                let files = [file.to_string_lossy().to_string()];
+                self.import_files(table, format, location, &files).await
+            }
+            .await;
+            let _ = tokio::fs::remove_file(&file).await;
+            return res;
+        }
+        let columns = table.get_row().get_columns().clone();
+        let rows = format
+            .reader_row_stream(location.to_string(), reader, columns)
+            .await?;
+        self.import_rows(table, format.max_errors(), location, rows)
+            .await
+    }
+
+    async fn import_files(
+        &self,
+        table: &IdRow<Table>,
+        format: &ImportFormat,
+        location: &str,
+        files: &[String],
+    ) -> Result<(), CubeError> {
+        let columns = table.get_row().get_columns().clone();
+        // Files are opened one by one as rows of the previous ones are read
+        let rows = stream::iter(files.to_vec())
+            .then(move |file| {
+                let columns = columns.clone();
+                async move {
+                    match format.row_stream(file, columns).await {
+                        Ok(rows) => rows,
+                        Err(e) => stream::once(async move { Err(e) }).boxed(),
+                    }
+                }
+            })
+            .flatten();
+        self.import_rows(table, format.max_errors(), location, Box::pin(rows))
+            .await
+    }
+
+    /// Rows are written to inactive chunks which are activated together once the whole location
+    /// is read, so a download that fails or doesn't match its SHA-256 leaves none of its rows.
+    async fn import_rows(
+        &self,
+        table: &IdRow<Table>,
+        max_errors: u64,
+        location: &str,
+        row_stream: Pin<Box<dyn Stream<Item = Result<Row, CubeError>> + Send + '_>>,
+    ) -> Result<(), CubeError> {
+        let mut chunk_ids = Vec::new();
+        let res = match self
+            .write_imported_rows(table, max_errors, location, row_stream, &mut chunk_ids)
+            .await
+        {
+            Ok(()) => {
+                self.meta_store
+                    .swap_chunks(Vec::new(), chunk_ids.clone())
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            for chunk_id in chunk_ids.iter() {
+                if let Err(e) = self.meta_store.delete_chunk(*chunk_id).await {
+                    warn!("Can't delete chunk {} of failed import: {}", chunk_id, e);
+                }
+            }
+            self.chunk_store.evict_in_memory_chunks(chunk_ids);
+        }
+        res
+    }
+
+    /// Ids of the written chunks are collected to `chunk_ids` even if writing fails.
+    async fn write_imported_rows(
+        &self,
+        table: &IdRow<Table>,
+        max_errors: u64,
+        location: &str,
+        mut row_stream: Pin<Box<dyn Stream<Item = Result<Row, CubeError>> + Send + '_>>,
+        chunk_ids: &mut Vec<u64>,
+    ) -> Result<(), CubeError> {
+        let chunk_len = self.wal_store.get_wal_chunk_size();
+        let mut errors = 0;
+        let mut rows = Vec::new();
+        loop {
+            let done = match row_stream.next().await {
+                Some(Err(e)) if e.is_corrupted_data() && errors < max_errors => {
+                    errors += 1;
+                    warn!("Skipping row while importing {}: {}", location, e);
+                    continue;
+                }
+                Some(Err(e)) if e.is_corrupted_data() => {
+                    return Err(CubeError::user(format!(
+                        "Import of {} aborted after {} malformed rows: {}",
+                        location,
+                        errors + 1,
+                        e
+                    )));
+                }
+                Some(row) => {
+                    rows.push(row?);
+                    false
+                }
+                None => true,
+            };
+            if rows.len() >= chunk_len || (done && !rows.is_empty()) {
+                let data =
+                    DataFrame::new(table.get_row().get_columns().clone(), mem::take(&mut rows));
+                chunk_ids.append(
+                    &mut self
+                        .chunk_store
+                        .add_inactive_chunks(table.get_id(), data)
+                        .await?,
+                );
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    }
 }
 
 #[async_trait]
@@ -109,31 +541,161 @@ impl ImportService for ImportServiceImpl {
                 "Trying to import table without location: {:?}",
                 table
             )))?;
-        let mut row_stream = format
-            .row_stream(location.to_string(), table.get_row().get_columns().clone())
-            .await?;
-        let mut rows = Vec::new();
-        while let Some(row) = row_stream.next().await {
-            rows.push(row?);
-            if rows.len() >= 500000 {
-                let mut to_add = Vec::new();
-                mem::swap(&mut rows, &mut to_add);
-                self.wal_store
-                    .add_wal(
-                        table.clone(),
-                        DataFrame::new(table.get_row().get_columns().clone(), to_add),
-                    )
-                    .await?;
-            }
+        if is_s3_location(location) {
+            self.import_s3(&table, format, location).await
+        } else if is_url_location(location) {
+            self.import_url(&table, format, location).await
+        } else {
+            self.import_files(&table, format, location, &[location.to_string()])
+                .await
         }
+    }
+}
 
-        self.wal_store
-            .add_wal(
-                table.clone(),
-                DataFrame::new(table.get_row().get_columns().clone(), rows),
-            )
-            .await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio_02::bufread::{GzipEncoder, ZstdEncoder};
+    use std::env;
+    use tokio::io::AsyncReadExt;
+
+    async fn read_records(
+        data: &str,
+        options: CsvOptions,
+    ) -> Vec<Result<(usize, Vec<String>), CubeError>> {
+        let mut records = CsvRecords::new(BufReader::new(data.as_bytes()), options);
+        let mut res = Vec::new();
+        while let Some(record) = records.next_record().await {
+            res.push(record);
+        }
+        res
+    }
+
+    fn fields(fields: &[&str]) -> Vec<String> {
+        fields.iter().map(|f| f.to_string()).collect()
+    }
+
+    async fn gzip(data: &str) -> Vec<u8> {
+        let mut res = Vec::new();
+        GzipEncoder::new(data.as_bytes())
+            .read_to_end(&mut res)
+            .await
+            .unwrap();
+        res
+    }
+
+    async fn read_file_records(
+        name: &str,
+        content: &[u8],
+    ) -> Vec<Result<(usize, Vec<String>), CubeError>> {
+        let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+        std::fs::write(&path, content).unwrap();
+        let mut records = CsvRecords::new(
+            open_decompressed(&path).await.unwrap(),
+            CsvOptions::default(),
+        );
+        let mut res = Vec::new();
+        while let Some(record) = records.next_record().await {
+            res.push(record);
+        }
+        std::fs::remove_file(path).unwrap();
+        res
+    }
+
+    #[test]
+    fn detect_compression() {
+        assert_eq!(Compression::detect("a.csv.gz", b"1,2"), Compression::Gzip);
+        assert_eq!(Compression::detect("A.CSV.ZST", b"1,2"), Compression::Zstd);
+        assert_eq!(Compression::detect("a.csv", b"1,2"), Compression::None);
+        assert_eq!(
+            Compression::detect("a", &[0x1f, 0x8b, 8]),
+            Compression::Gzip
+        );
+        assert_eq!(
+            Compression::detect("a", &[0x28, 0xb5, 0x2f, 0xfd]),
+            Compression::Zstd
+        );
+    }
+
+    #[tokio::test]
+    async fn compressed_files() {
+        let mut zstd = Vec::new();
+        ZstdEncoder::new("1,a\n2,b\n".as_bytes())
+            .read_to_end(&mut zstd)
+            .await
+            .unwrap();
+        let records = read_file_records("import_compressed.csv.zst", &zstd).await;
+        assert_eq!(
+            records.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![(1, fields(&["1", "a"])), (2, fields(&["2", "b"]))]
+        );
+
+        let mut multi_member = gzip("1,a\n").await;
+        multi_member.extend(gzip("2,b\n").await);
+        // Compression is detected by magic bytes
+        let records = read_file_records("import_multi_member.csv", &multi_member).await;
+        assert_eq!(
+            records.into_iter().map(|r| r.unwrap()).collect::<Vec<_>>(),
+            vec![(1, fields(&["1", "a"])), (2, fields(&["2", "b"]))]
+        );
+
+        let mut trailing_garbage = gzip("1,a\n").await;
+        trailing_garbage.extend(b"garbage\n");
+        let records = read_file_records("import_trailing_garbage.csv.gz", &trailing_garbage).await;
+        let error = records
+            .iter()
+            .find_map(|r| r.as_ref().err())
+            .expect("trailing garbage should be rejected");
+        assert!(error.to_string().contains("Can't read line"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn quoted_fields_span_lines() {
+        let records = read_records(
+            "1,\"San Francisco, CA\"\n2,\"multi\nline \"\"quoted\"\"\ntext\"\n\n3,\n",
+            CsvOptions::default(),
+        )
+        .await
+        .into_iter()
+        .map(|r| r.unwrap())
+        .collect::<Vec<_>>();
+        assert_eq!(
+            records,
+            vec![
+                (1, fields(&["1", "San Francisco, CA"])),
+                (2, fields(&["2", "multi\nline \"quoted\"\ntext"])),
+                (6, fields(&["3", ""])),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn escaped_tab_separated_fields() {
+        let options = CsvOptions {
+            delimiter: '\t',
+            quote: '\'',
+            escape: Some('\\'),
+            ..CsvOptions::default()
+        };
+        let records = read_records("a\t'it\\'s\tquoted'\tc\n", options)
+            .await
+            .into_iter()
+            .map(|r| r.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records, vec![(1, fields(&["a", "it's\tquoted", "c"]))]);
+    }
 
-        Ok(())
+    #[tokio::test]
+    async fn malformed_records_report_line_numbers() {
+        let records =
+            read_records("1,\"a\"b\n2,ok\n3,\"never\nclosed\n", CsvOptions::default()).await;
+        assert_eq!(records.len(), 3);
+        let error = records[0].as_ref().unwrap_err();
+        assert!(error.is_corrupted_data());
+        assert!(error.to_string().contains("line 1"), "{}", error);
+        assert_eq!(records[1].as_ref().unwrap(), &(2, fields(&["2", "ok"])));
+        let error = records[2].as_ref().unwrap_err();
+        assert!(error.to_string().contains("line 3"), "{}", error);
+        assert!(error.to_string().contains("not closed"), "{}", error);
     }
 }
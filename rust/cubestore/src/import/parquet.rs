@@ -0,0 +1,184 @@
+use crate::metastore::{Column, ColumnType};
+use crate::queryplanner::query_executor::batches_to_rows;
+use crate::queryplanner::row_group_scan::check_compression;
+use crate::table::Row;
+use crate::CubeError;
+use arrow::array::ArrayRef;
+use arrow::compute::kernels::cast::cast;
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::record_batch::RecordBatch;
+use futures::channel::mpsc::{channel, Sender};
+use futures::{stream, SinkExt, Stream, StreamExt};
+use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use std::fs::File;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+const BATCH_SIZE: usize = 4096;
+
+/// Rows of the parquet file with values of `columns` matched to file columns by name.
+/// Rows are yielded in file order, they're sorted by the index key while WAL is partitioned.
+pub fn parquet_row_stream(
+    location: String,
+    columns: Vec<Column>,
+) -> Pin<Box<dyn Stream<Item = Result<Row, CubeError>> + Send>> {
+    let (mut sender, receiver) = channel(2);
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = read_parquet(&location, &columns, &mut sender) {
+            let _ = futures::executor::block_on(sender.send(Err(e)));
+        }
+    });
+    receiver
+        .flat_map(|rows: Result<Vec<Row>, CubeError>| {
+            stream::iter(match rows {
+                Ok(rows) => rows.into_iter().map(Ok).collect::<Vec<_>>(),
+                Err(e) => vec![Err(e)],
+            })
+        })
+        .boxed()
+}
+
+fn read_parquet(
+    location: &str,
+    columns: &Vec<Column>,
+    sender: &mut Sender<Result<Vec<Row>, CubeError>>,
+) -> Result<(), CubeError> {
+    let reader = SerializedFileReader::new(File::open(location)?)?;
+    check_compression(location, reader.metadata())?;
+    let mut arrow_reader = ParquetFileArrowReader::new(Rc::new(reader));
+    let file_schema = arrow_reader.get_schema()?;
+    let positions = column_positions(location, &file_schema, columns)?;
+    let schema = Arc::new(Schema::new(
+        columns
+            .iter()
+            .zip(positions.iter())
+            .map(|(c, p)| {
+                let data_type = import_data_type(file_schema.field(*p).data_type());
+                Field::new(c.get_name(), data_type, true)
+            })
+            .collect(),
+    ));
+    for batch in arrow_reader.get_record_reader(BATCH_SIZE)? {
+        let batch = batch?;
+        let arrays = positions
+            .iter()
+            .zip(schema.fields().iter())
+            .map(|(p, f)| -> Result<ArrayRef, CubeError> {
+                let array = batch.column(*p);
+                if array.data_type() == f.data_type() {
+                    Ok(array.clone())
+                } else {
+                    Ok(cast(array, f.data_type())?)
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+        let rows = batches_to_rows(&[batch]).collect::<Result<Vec<_>, _>>()?;
+        // Receiver is dropped when the import fails
+        if futures::executor::block_on(sender.send(Ok(rows))).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Positions of file columns for `columns`. Errors for all mismatching columns are reported at once.
+fn column_positions(
+    location: &str,
+    file_schema: &Schema,
+    columns: &Vec<Column>,
+) -> Result<Vec<usize>, CubeError> {
+    let mut positions = Vec::with_capacity(columns.len());
+    let mut errors = Vec::new();
+    for column in columns.iter() {
+        let fields = file_schema.fields();
+        let position = fields
+            .iter()
+            .position(|f| f.name() == column.get_name())
+            .or_else(|| {
+                fields
+                    .iter()
+                    .position(|f| f.name().to_lowercase() == column.get_name().to_lowercase())
+            });
+        match position {
+            None => errors.push(format!("column '{}' is not found", column.get_name())),
+            Some(p) => match check_type(column.get_column_type(), fields[p].data_type()) {
+                Ok(()) => positions.push(p),
+                Err(e) => errors.push(format!("column '{}' {}", column.get_name(), e)),
+            },
+        }
+    }
+    if !errors.is_empty() {
+        return Err(CubeError::user(format!(
+            "Can't import parquet file {}: {}",
+            location,
+            errors.join(", ")
+        )));
+    }
+    Ok(positions)
+}
+
+fn check_type(column_type: &ColumnType, data_type: &DataType) -> Result<(), String> {
+    let supported = match (column_type, data_type) {
+        (_, DataType::Dictionary(_, value_type)) => return check_type(column_type, value_type),
+        (ColumnType::String, DataType::Utf8) => true,
+        (ColumnType::Int, t) => is_integer(t),
+        (ColumnType::Decimal { scale, .. }, DataType::Decimal(_, s)) => {
+            return check_scale(column_type, *scale, *s as i32)
+        }
+        (ColumnType::Decimal { scale, .. }, DataType::Int64Decimal(s)) => {
+            return check_scale(column_type, *scale, *s as i32)
+        }
+        (ColumnType::Decimal { .. }, DataType::Float32)
+        | (ColumnType::Decimal { .. }, DataType::Float64) => true,
+        (ColumnType::Timestamp, DataType::Timestamp(_, _)) => true,
+        (ColumnType::Boolean, DataType::Boolean) => true,
+        _ => false,
+    };
+    if supported {
+        Ok(())
+    } else {
+        Err(format!(
+            "of type {:?} can't be imported as {}",
+            data_type, column_type
+        ))
+    }
+}
+
+fn check_scale(column_type: &ColumnType, scale: i32, file_scale: i32) -> Result<(), String> {
+    if file_scale > scale {
+        Err(format!(
+            "with scale {} doesn't fit {} without rounding",
+            file_scale, column_type
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn is_integer(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64 => true,
+        _ => false,
+    }
+}
+
+/// Type of the column passed to `batches_to_rows` which supports only a subset of arrow types.
+fn import_data_type(data_type: &DataType) -> DataType {
+    match data_type {
+        DataType::Dictionary(_, value_type) => import_data_type(value_type),
+        DataType::UInt64 => DataType::UInt64,
+        t if is_integer(t) => DataType::Int64,
+        DataType::Float32 => DataType::Float64,
+        t => t.clone(),
+    }
+}
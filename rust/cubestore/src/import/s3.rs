@@ -0,0 +1,614 @@
+use crate::config::S3ImportConfig;
+use crate::CubeError;
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use log::{debug, warn};
+use regex::Regex;
+use s3::creds::Credentials;
+use s3::region::Region;
+use s3::Bucket;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::fs;
+
+pub fn is_s3_location(location: &str) -> bool {
+    location.starts_with("s3://")
+}
+
+/// `s3://bucket/prefix/*.csv.gz` location. `*` matches any characters except `/`, `?` matches
+/// a single one. Objects are listed by the key part preceding the first wildcard.
+#[derive(Debug, Clone)]
+pub struct S3Location {
+    location: String,
+    pub bucket: String,
+    pub prefix: String,
+    pattern: Regex,
+}
+
+impl S3Location {
+    pub fn parse(location: &str) -> Result<S3Location, CubeError> {
+        let path = location
+            .strip_prefix("s3://")
+            .ok_or_else(|| CubeError::user(format!("Not an S3 location: {}", location)))?;
+        let (bucket, key) = match path.find('/') {
+            Some(i) if i > 0 && i + 1 < path.len() => (&path[..i], &path[i + 1..]),
+            _ => {
+                return Err(CubeError::user(format!(
+                    "S3 location should be s3://bucket/key but {} found",
+                    location
+                )))
+            }
+        };
+        let prefix = key
+            .find(|c| c == '*' || c == '?')
+            .map(|i| &key[..i])
+            .unwrap_or(key);
+        let mut pattern = "^".to_string();
+        for c in key.chars() {
+            match c {
+                '*' => pattern.push_str("[^/]*"),
+                '?' => pattern.push_str("[^/]"),
+                c => pattern.push_str(&regex::escape(&c.to_string())),
+            }
+        }
+        pattern.push('$');
+        Ok(S3Location {
+            location: location.to_string(),
+            bucket: bucket.to_string(),
+            prefix: prefix.to_string(),
+            pattern: Regex::new(&pattern)?,
+        })
+    }
+
+    pub fn matches(&self, key: &str) -> bool {
+        self.pattern.is_match(key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3Object {
+    pub key: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct S3ListPage {
+    pub objects: Vec<S3Object>,
+    /// Set if there're more objects to list.
+    pub continuation_token: Option<String>,
+}
+
+#[async_trait]
+pub trait S3Client: Send + Sync {
+    async fn list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<S3ListPage, CubeError>;
+
+    async fn download(&self, bucket: &str, key: &str, path: &Path) -> Result<(), CubeError>;
+}
+
+pub struct RustS3Client {
+    region: Region,
+    credentials: Credentials,
+}
+
+impl RustS3Client {
+    /// Credentials missing in `config` are resolved by the default provider chain: environment
+    /// variables, the profile file and the instance profile. They're resolved on every import so
+    /// temporary credentials aren't kept after they expire.
+    pub fn new(config: &S3ImportConfig) -> Result<Arc<RustS3Client>, CubeError> {
+        let credentials = Credentials::new(
+            config.access_key_id.as_deref(),
+            config.secret_access_key.as_deref(),
+            None,
+            config.session_token.as_deref(),
+            None,
+        )?;
+        Ok(Arc::new(RustS3Client {
+            region: config.region.parse()?,
+            credentials,
+        }))
+    }
+
+    fn bucket(&self, bucket: &str) -> Result<Bucket, CubeError> {
+        Ok(Bucket::new(
+            bucket,
+            self.region.clone(),
+            self.credentials.clone(),
+        )?)
+    }
+}
+
+#[async_trait]
+impl S3Client for RustS3Client {
+    async fn list_page(
+        &self,
+        bucket: &str,
+        prefix: &str,
+        continuation_token: Option<String>,
+    ) -> Result<S3ListPage, CubeError> {
+        let (result, status_code) = self
+            .bucket(bucket)?
+            .list_page(prefix.to_string(), None, continuation_token, None, None)
+            .await?;
+        if status_code != 200 {
+            return Err(CubeError::user(format!(
+                "S3 list returned non OK status: {}",
+                status_code
+            )));
+        }
+        Ok(S3ListPage {
+            objects: result
+                .contents
+                .iter()
+                .map(|o| S3Object {
+                    key: o.key.clone(),
+                    size: o.size,
+                })
+                .collect(),
+            continuation_token: if result.is_truncated {
+                result.next_continuation_token
+            } else {
+                None
+            },
+        })
+    }
+
+    async fn download(&self, bucket: &str, key: &str, path: &Path) -> Result<(), CubeError> {
+        let mut file = std::fs::File::create(path)?;
+        let status_code = self
+            .bucket(bucket)?
+            .get_object_stream(format!("/{}", key), &mut file)
+            .await?;
+        if status_code != 200 {
+            return Err(CubeError::user(format!(
+                "S3 download returned non OK status: {}",
+                status_code
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// State of an S3 import shown by `system.s3_imports`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct S3ImportState {
+    pub table_id: u64,
+    pub location: String,
+    pub objects_total: u64,
+    pub objects_done: u64,
+    pub bytes_total: u64,
+    pub bytes_done: u64,
+    /// Failed download attempts which were retried.
+    pub retries: u64,
+    pub status: String,
+}
+
+/// Progress of S3 imports by table id. The last state is kept after the import finishes.
+pub struct S3ImportProgress {
+    imports: Mutex<HashMap<u64, S3ImportState>>,
+}
+
+impl S3ImportProgress {
+    pub fn new() -> Arc<S3ImportProgress> {
+        Arc::new(S3ImportProgress {
+            imports: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn start(&self, table_id: u64, location: &str) {
+        self.imports.lock().unwrap().insert(
+            table_id,
+            S3ImportState {
+                table_id,
+                location: location.to_string(),
+                objects_total: 0,
+                objects_done: 0,
+                bytes_total: 0,
+                bytes_done: 0,
+                retries: 0,
+                status: "listing".to_string(),
+            },
+        );
+    }
+
+    pub fn update(&self, table_id: u64, f: impl FnOnce(&mut S3ImportState)) {
+        if let Some(state) = self.imports.lock().unwrap().get_mut(&table_id) {
+            f(state);
+        }
+    }
+
+    pub fn set_status(&self, table_id: u64, status: &str) {
+        self.update(table_id, |s| s.status = status.to_string());
+    }
+
+    pub fn all(&self) -> Vec<S3ImportState> {
+        let mut res = self
+            .imports
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        res.sort_by_key(|s| s.table_id);
+        res
+    }
+}
+
+pub struct S3Downloader {
+    client: Arc<dyn S3Client>,
+    progress: Arc<S3ImportProgress>,
+    concurrency: usize,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl S3Downloader {
+    pub fn new(
+        client: Arc<dyn S3Client>,
+        progress: Arc<S3ImportProgress>,
+        concurrency: usize,
+        retries: u32,
+    ) -> S3Downloader {
+        S3Downloader {
+            client,
+            progress,
+            concurrency,
+            retries,
+            retry_delay: Duration::from_millis(200),
+        }
+    }
+
+    pub fn with_retry_delay(self, retry_delay: Duration) -> S3Downloader {
+        S3Downloader {
+            retry_delay,
+            ..self
+        }
+    }
+
+    /// Downloads objects matching `location` into `dir` returning their paths ordered by keys.
+    /// Failed downloads are retried one by one. Objects already downloaded into `dir` by an
+    /// interrupted import of the same table aren't downloaded again.
+    pub async fn download(
+        &self,
+        table_id: u64,
+        location: &S3Location,
+        dir: &Path,
+    ) -> Result<Vec<PathBuf>, CubeError> {
+        let objects = self.list(location).await?;
+        if objects.is_empty() {
+            return Err(CubeError::user(format!(
+                "No objects match {}",
+                location.location
+            )));
+        }
+        self.progress.update(table_id, |s| {
+            s.objects_total = objects.len() as u64;
+            s.bytes_total = objects.iter().map(|o| o.size).sum();
+            s.status = "downloading".to_string();
+        });
+        stream::iter(objects.into_iter())
+            .map(|object| self.download_object(table_id, &location.bucket, object, dir))
+            .buffered(self.concurrency.max(1))
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    async fn list(&self, location: &S3Location) -> Result<Vec<S3Object>, CubeError> {
+        let mut objects = Vec::new();
+        let mut continuation_token = None;
+        loop {
+            let page = self
+                .with_retries(
+                    &format!("list s3://{}/{}", location.bucket, location.prefix),
+                    || {
+                        self.client.list_page(
+                            &location.bucket,
+                            &location.prefix,
+                            continuation_token.clone(),
+                        )
+                    },
+                )
+                .await?;
+            objects.extend(
+                page.objects
+                    .into_iter()
+                    .filter(|o| location.matches(&o.key)),
+            );
+            continuation_token = page.continuation_token;
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+        objects.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(objects)
+    }
+
+    async fn download_object(
+        &self,
+        table_id: u64,
+        bucket: &str,
+        object: S3Object,
+        dir: &Path,
+    ) -> Result<PathBuf, CubeError> {
+        if object.key.split('/').any(|s| s == "..") {
+            return Err(CubeError::user(format!(
+                "Can't import S3 object with relative path: {}",
+                object.key
+            )));
+        }
+        let path = dir.join(&object.key);
+        let downloaded = fs::metadata(&path)
+            .await
+            .map(|m| m.len() == object.size)
+            .unwrap_or(false);
+        if downloaded {
+            debug!("Resuming import of s3://{}/{}", bucket, object.key);
+        } else {
+            fs::create_dir_all(path.parent().unwrap()).await?;
+            // Partial downloads aren't taken for complete ones when the import is resumed
+            let temp_path = PathBuf::from(format!("{}.download", path.display()));
+            self.with_retries(&format!("download s3://{}/{}", bucket, object.key), || {
+                self.download_to(table_id, bucket, &object, &temp_path)
+            })
+            .await?;
+            fs::rename(&temp_path, &path).await?;
+        }
+        self.progress.update(table_id, |s| {
+            s.objects_done += 1;
+            s.bytes_done += object.size;
+        });
+        Ok(path)
+    }
+
+    async fn download_to(
+        &self,
+        table_id: u64,
+        bucket: &str,
+        object: &S3Object,
+        path: &Path,
+    ) -> Result<(), CubeError> {
+        let res = self.client.download(bucket, &object.key, path).await;
+        let res = match res {
+            Ok(()) => {
+                let size = fs::metadata(path).await?.len();
+                if size == object.size {
+                    Ok(())
+                } else {
+                    Err(CubeError::user(format!(
+                        "expected {} bytes but {} received",
+                        object.size, size
+                    )))
+                }
+            }
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            self.progress.update(table_id, |s| s.retries += 1);
+        }
+        res
+    }
+
+    async fn with_retries<T, F, Fut>(&self, action: &str, f: F) -> Result<T, CubeError>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, CubeError>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match f().await {
+                Ok(res) => return Ok(res),
+                Err(e) if attempt < self.retries => {
+                    warn!("Can't {}, retrying: {}", action, e);
+                    tokio::time::delay_for(self.retry_delay * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                Err(e) => {
+                    return Err(CubeError::user(format!(
+                        "Can't {} after {} attempts: {}",
+                        action,
+                        attempt + 1,
+                        e
+                    )))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Lists `objects` by pages of `page_size`. Downloads of `failing` key fail `failures` times.
+    struct FakeS3 {
+        objects: Vec<S3Object>,
+        page_size: usize,
+        failing: String,
+        failures: AtomicUsize,
+        list_calls: AtomicUsize,
+        download_calls: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl S3Client for FakeS3 {
+        async fn list_page(
+            &self,
+            bucket: &str,
+            prefix: &str,
+            continuation_token: Option<String>,
+        ) -> Result<S3ListPage, CubeError> {
+            assert_eq!(bucket, "bucket");
+            self.list_calls.fetch_add(1, Ordering::SeqCst);
+            let start = continuation_token.map(|t| t.parse().unwrap()).unwrap_or(0);
+            let matching = self
+                .objects
+                .iter()
+                .filter(|o| o.key.starts_with(prefix))
+                .collect::<Vec<_>>();
+            let end = (start + self.page_size).min(matching.len());
+            Ok(S3ListPage {
+                objects: matching[start..end].iter().cloned().cloned().collect(),
+                continuation_token: if end < matching.len() {
+                    Some(end.to_string())
+                } else {
+                    None
+                },
+            })
+        }
+
+        async fn download(&self, _: &str, key: &str, path: &Path) -> Result<(), CubeError> {
+            self.download_calls.fetch_add(1, Ordering::SeqCst);
+            let object = self.objects.iter().find(|o| o.key == key).unwrap();
+            if key == self.failing && self.failures.load(Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, Ordering::SeqCst);
+                // Partially written file of a dropped connection
+                std::fs::write(path, "1")?;
+                return Err(CubeError::user("connection reset".to_string()));
+            }
+            std::fs::write(path, "x".repeat(object.size as usize))?;
+            Ok(())
+        }
+    }
+
+    fn fake_s3(count: usize, failing: &str, failures: usize) -> Arc<FakeS3> {
+        let mut objects = (0..count)
+            .map(|i| S3Object {
+                key: format!("data/part-{:04}.csv.gz", i),
+                size: (i % 10 + 1) as u64,
+            })
+            .collect::<Vec<_>>();
+        objects.push(S3Object {
+            key: "data/nested/part-0000.csv.gz".to_string(),
+            size: 1,
+        });
+        objects.push(S3Object {
+            key: "data/_SUCCESS".to_string(),
+            size: 0,
+        });
+        Arc::new(FakeS3 {
+            objects,
+            page_size: 100,
+            failing: failing.to_string(),
+            failures: AtomicUsize::new(failures),
+            list_calls: AtomicUsize::new(0),
+            download_calls: AtomicUsize::new(0),
+        })
+    }
+
+    fn downloader(client: Arc<FakeS3>, progress: Arc<S3ImportProgress>) -> S3Downloader {
+        S3Downloader::new(client, progress, 4, 2).with_retry_delay(Duration::from_millis(1))
+    }
+
+    #[test]
+    fn parse_location() {
+        let location = S3Location::parse("s3://bucket/data/part-*.csv.gz").unwrap();
+        assert_eq!(location.bucket, "bucket");
+        assert_eq!(location.prefix, "data/part-");
+        assert!(location.matches("data/part-0001.csv.gz"));
+        assert!(!location.matches("data/part-0001.csv"));
+        assert!(!location.matches("data/part-a/b.csv.gz"));
+
+        let location = S3Location::parse("s3://bucket/data/file.csv").unwrap();
+        assert_eq!(location.prefix, "data/file.csv");
+        assert!(location.matches("data/file.csv"));
+        assert!(!location.matches("data/file.csv.gz"));
+
+        assert!(S3Location::parse("s3://bucket").is_err());
+        assert!(S3Location::parse("s3:///key").is_err());
+    }
+
+    #[tokio::test]
+    async fn download_paginated_listing() {
+        let dir = env::temp_dir().join("s3_download_paginated_listing");
+        let _ = std::fs::remove_dir_all(&dir);
+        let client = fake_s3(1050, "", 0);
+        let progress = S3ImportProgress::new();
+        progress.start(1, "s3://bucket/data/*.csv.gz");
+
+        let location = S3Location::parse("s3://bucket/data/*.csv.gz").unwrap();
+        let paths = downloader(client.clone(), progress.clone())
+            .download(1, &location, &dir)
+            .await
+            .unwrap();
+
+        assert_eq!(client.list_calls.load(Ordering::SeqCst), 11);
+        assert_eq!(paths.len(), 1050);
+        assert_eq!(paths[0], dir.join("data/part-0000.csv.gz"));
+        assert_eq!(paths[1049], dir.join("data/part-1049.csv.gz"));
+        let state = progress.all().pop().unwrap();
+        assert_eq!(state.objects_total, 1050);
+        assert_eq!(state.objects_done, 1050);
+        assert_eq!(state.bytes_total, 5775);
+        assert_eq!(state.bytes_done, 5775);
+        assert_eq!(state.retries, 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn retry_transient_download_error() {
+        let dir = env::temp_dir().join("s3_retry_transient_download_error");
+        let _ = std::fs::remove_dir_all(&dir);
+        let client = fake_s3(20, "data/part-0010.csv.gz", 1);
+        let progress = S3ImportProgress::new();
+        progress.start(1, "s3://bucket/data/*.csv.gz");
+        let location = S3Location::parse("s3://bucket/data/*.csv.gz").unwrap();
+
+        let paths = downloader(client.clone(), progress.clone())
+            .download(1, &location, &dir)
+            .await
+            .unwrap();
+        assert_eq!(paths.len(), 20);
+        // Only the failed object is downloaded again
+        assert_eq!(client.download_calls.load(Ordering::SeqCst), 21);
+        assert_eq!(
+            std::fs::read(&paths[10]).unwrap().len(),
+            client.objects[10].size as usize
+        );
+        let state = progress.all().pop().unwrap();
+        assert_eq!((state.objects_done, state.retries), (20, 1));
+
+        // Objects downloaded by the interrupted import are reused
+        std::fs::remove_file(&paths[3]).unwrap();
+        let paths = downloader(client.clone(), progress.clone())
+            .download(1, &location, &dir)
+            .await
+            .unwrap();
+        assert_eq!(paths.len(), 20);
+        assert_eq!(client.download_calls.load(Ordering::SeqCst), 22);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn download_fails_after_retries() {
+        let dir = env::temp_dir().join("s3_download_fails_after_retries");
+        let _ = std::fs::remove_dir_all(&dir);
+        let client = fake_s3(5, "data/part-0002.csv.gz", 10);
+        let location = S3Location::parse("s3://bucket/data/part-0002.csv.gz").unwrap();
+        let err = downloader(client.clone(), S3ImportProgress::new())
+            .download(1, &location, &dir)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("download s3://bucket/data/part-0002.csv.gz after 3 attempts"),
+            "{}",
+            err
+        );
+        assert!(err.contains("connection reset"), "{}", err);
+        assert_eq!(client.download_calls.load(Ordering::SeqCst), 3);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -26,6 +26,8 @@ use tokio::sync::mpsc::error::SendError;
 
 pub mod cluster;
 pub mod config;
+pub mod flight;
+pub mod hash;
 pub mod http;
 pub mod import;
 pub mod metastore;
@@ -48,6 +50,8 @@ pub struct CubeError {
 pub enum CubeErrorCauseType {
     User,
     Internal,
+    CorruptedData,
+    SchemaDrift,
 }
 
 impl CubeError {
@@ -65,6 +69,34 @@ impl CubeError {
         }
     }
 
+    fn corrupted_data(message: String) -> CubeError {
+        CubeError {
+            message,
+            cause: CubeErrorCauseType::CorruptedData,
+        }
+    }
+
+    pub fn is_corrupted_data(&self) -> bool {
+        match self.cause {
+            CubeErrorCauseType::CorruptedData => true,
+            _ => false,
+        }
+    }
+
+    fn schema_drift(message: String) -> CubeError {
+        CubeError {
+            message,
+            cause: CubeErrorCauseType::SchemaDrift,
+        }
+    }
+
+    pub fn is_schema_drift(&self) -> bool {
+        match self.cause {
+            CubeErrorCauseType::SchemaDrift => true,
+            _ => false,
+        }
+    }
+
     fn from_error<E: fmt::Display>(error: E) -> CubeError {
         CubeError {
             message: format!("{}\n{}", error, Backtrace::capture()),
@@ -117,6 +149,21 @@ impl From<CubeError> for warp::reject::Rejection {
     }
 }
 
+impl From<CubeError> for tonic::Status {
+    fn from(v: CubeError) -> Self {
+        match v.cause {
+            CubeErrorCauseType::User => tonic::Status::invalid_argument(v.message),
+            _ => tonic::Status::internal(v.message),
+        }
+    }
+}
+
+impl From<tonic::transport::Error> for CubeError {
+    fn from(v: tonic::transport::Error) -> Self {
+        CubeError::from_error(v)
+    }
+}
+
 impl From<ParquetError> for CubeError {
     fn from(v: ParquetError) -> Self {
         CubeError::internal(v.to_string())
@@ -147,21 +194,51 @@ impl From<tokio::time::Elapsed> for CubeError {
     }
 }
 
+impl std::error::Error for CubeError {}
+
+impl CubeError {
+    /// Errors raised inside execution plans are passed through DataFusion as external ones.
+    fn from_external(v: Box<dyn std::error::Error + Send + Sync>) -> CubeError {
+        match v.downcast::<CubeError>() {
+            Ok(e) => *e,
+            Err(v) => match v.downcast::<datafusion::error::DataFusionError>() {
+                Ok(e) => CubeError::from(*e),
+                Err(v) => CubeError::internal(v.to_string()),
+            },
+        }
+    }
+}
+
 impl From<datafusion::error::DataFusionError> for CubeError {
     fn from(v: datafusion::error::DataFusionError) -> Self {
-        CubeError::from_error(v)
+        match v {
+            datafusion::error::DataFusionError::ArrowError(ArrowError::ExternalError(e)) => {
+                CubeError::from_external(e)
+            }
+            v => CubeError::from_error(v),
+        }
     }
 }
 
+/// Kept as an external error so the cause survives execution, e.g. schema drift.
 impl From<CubeError> for datafusion::error::DataFusionError {
     fn from(v: CubeError) -> Self {
-        datafusion::error::DataFusionError::Execution(v.to_string())
+        datafusion::error::DataFusionError::ArrowError(ArrowError::from(v))
+    }
+}
+
+impl From<CubeError> for ArrowError {
+    fn from(v: CubeError) -> Self {
+        ArrowError::ExternalError(Box::new(v))
     }
 }
 
 impl From<arrow::error::ArrowError> for CubeError {
     fn from(v: ArrowError) -> Self {
-        CubeError::internal(v.to_string())
+        match v {
+            ArrowError::ExternalError(e) => CubeError::from_external(e),
+            v => CubeError::internal(v.to_string()),
+        }
     }
 }
 
@@ -2,6 +2,7 @@ use super::{BaseRocksSecondaryIndex, Chunk, IndexId, RocksSecondaryIndex, RocksT
 use crate::base_rocks_secondary_index;
 use crate::metastore::{IdRow, MetaStoreEvent};
 use crate::rocks_table_impl;
+use crate::table::Row;
 use byteorder::{BigEndian, WriteBytesExt};
 use chrono::Utc;
 use rocksdb::DB;
@@ -17,9 +18,57 @@ impl Chunk {
             uploaded: false,
             active: false,
             last_used: None,
+            min_value: None,
+            max_value: None,
+            level: 0,
+            tombstone: false,
+            sequence: None,
         }
     }
 
+    /// Sort key values of the first and the last rows of the chunk. Unlike partition bounds
+    /// both are inclusive.
+    pub fn with_min_max(self, min_value: Option<Row>, max_value: Option<Row>) -> Chunk {
+        Chunk {
+            min_value,
+            max_value,
+            ..self
+        }
+    }
+
+    /// Chunks written from inserted rows are at level 0, chunks merged from them are at level 1.
+    pub fn with_level(self, level: u64) -> Chunk {
+        Chunk { level, ..self }
+    }
+
+    pub fn get_level(&self) -> u64 {
+        self.level
+    }
+
+    /// Chunk of deleted rows. Scans skip rows of the partition matching them and compaction
+    /// drops such rows for good.
+    pub fn with_tombstone(self, tombstone: bool) -> Chunk {
+        Chunk { tombstone, ..self }
+    }
+
+    pub fn is_tombstone(&self) -> bool {
+        self.tombstone
+    }
+
+    /// Chunk taking the place of chunks written up to `sequence`, e.g. merged from them or moved
+    /// from another partition.
+    pub fn with_sequence(self, sequence: Option<u64>) -> Chunk {
+        Chunk { sequence, ..self }
+    }
+
+    pub fn get_min_val(&self) -> &Option<Row> {
+        &self.min_value
+    }
+
+    pub fn get_max_val(&self) -> &Option<Row> {
+        &self.max_value
+    }
+
     pub fn get_row_count(&self) -> u64 {
         self.row_count
     }
@@ -39,6 +88,11 @@ impl Chunk {
             uploaded,
             active: uploaded,
             last_used: self.last_used.clone(),
+            min_value: self.min_value.clone(),
+            max_value: self.max_value.clone(),
+            level: self.level,
+            tombstone: self.tombstone,
+            sequence: self.sequence,
         }
     }
 
@@ -49,6 +103,11 @@ impl Chunk {
             uploaded: self.uploaded,
             active: false,
             last_used: self.last_used.clone(),
+            min_value: self.min_value.clone(),
+            max_value: self.max_value.clone(),
+            level: self.level,
+            tombstone: self.tombstone,
+            sequence: self.sequence,
         }
     }
 
@@ -73,6 +132,14 @@ impl Chunk {
     }
 }
 
+impl IdRow<Chunk> {
+    /// Order in which data was written to the partition. Tombstones delete only rows of chunks
+    /// with smaller sequences and of the partition file which is older than all chunks.
+    pub fn sequence(&self) -> u64 {
+        self.get_row().sequence.unwrap_or(self.get_id())
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum ChunkRocksIndex {
     PartitionId = 1,
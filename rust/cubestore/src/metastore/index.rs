@@ -26,13 +26,28 @@ impl Index {
             table_id,
             columns,
             sort_key_size,
+            building: false,
         })
     }
 
+    /// Index created over existing rows is building until they're copied from the default index.
+    /// Queries don't use building indexes while new rows are written to them.
+    pub fn with_building(self, building: bool) -> Index {
+        Index { building, ..self }
+    }
+
+    pub fn is_building(&self) -> bool {
+        self.building
+    }
+
     pub fn get_name(&self) -> &String {
         &self.name
     }
 
+    pub fn table_id(&self) -> u64 {
+        self.table_id
+    }
+
     pub fn columns(&self) -> &Vec<Column> {
         &self.columns
     }
@@ -45,6 +60,13 @@ impl Index {
     pub fn sort_key_size(&self) -> u64 {
         self.sort_key_size
     }
+
+    /// Appends `column` after the columns of the index so positions of existing ones are kept.
+    pub fn add_column(&self, column: Column) -> Index {
+        let mut index = self.clone();
+        index.columns.push(column.replace_index(self.columns.len()));
+        index
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
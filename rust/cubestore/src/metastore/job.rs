@@ -14,6 +14,7 @@ pub enum JobType {
     PartitionCompaction,
     TableImport,
     Repartition,
+    IndexBackfill,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Hash)]
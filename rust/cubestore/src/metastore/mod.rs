@@ -16,11 +16,12 @@ use rocksdb::{
 };
 use serde::{Deserialize, Deserializer, Serialize};
 use std::hash::{Hash, Hasher};
-use std::{collections::hash_map::DefaultHasher, env, io::Cursor, sync::Arc, time};
+use std::{env, io::Cursor, sync::Arc, time};
 use tokio::fs;
 use tokio::sync::{Notify, RwLock};
 
 use crate::config::{Config, ConfigObj};
+use crate::hash::StableHasher;
 use crate::metastore::chunks::{ChunkIndexKey, ChunkRocksIndex};
 use crate::metastore::index::IndexIndexKey;
 use crate::metastore::job::{Job, JobIndexKey, JobRocksIndex, JobRocksTable, JobStatus};
@@ -50,13 +51,13 @@ use regex::Regex;
 use rocksdb::checkpoint::Checkpoint;
 use schema::{SchemaRocksIndex, SchemaRocksTable};
 use smallvec::alloc::fmt::Formatter;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Mutex;
 use std::time::SystemTime;
-use table::Table;
+use table::{Retention, Table};
 use table::{TableRocksIndex, TableRocksTable};
 use tokio::fs::File;
 use tokio::sync::broadcast::Sender;
@@ -376,9 +377,9 @@ impl Into<Field> for Column {
     }
 }
 
-impl fmt::Display for Column {
+impl fmt::Display for ColumnType {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        let column_type = match &self.column_type {
+        let column_type = match self {
             ColumnType::String => "STRING".to_string(),
             ColumnType::Int => "INT".to_string(),
             ColumnType::Timestamp => "TIMESTAMP".to_string(),
@@ -388,13 +389,76 @@ impl fmt::Display for Column {
             }
             ColumnType::Bytes => "BYTES".to_string(),
         };
-        f.write_fmt(format_args!("{} {}", self.name, column_type))
+        f.write_str(&column_type)
+    }
+}
+
+impl fmt::Display for Column {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{} {}", self.name, self.column_type))
     }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
 pub enum ImportFormat {
     CSV,
+    /// CSV with a non-default dialect set by `WITH (...)` options of the table
+    CSVWithOptions(CsvOptions),
+    Parquet,
+    /// Newline-delimited JSON objects
+    JSONLines(JsonOptions),
+}
+
+impl ImportFormat {
+    pub fn csv_options(&self) -> CsvOptions {
+        match self {
+            ImportFormat::CSVWithOptions(options) => options.clone(),
+            _ => CsvOptions::default(),
+        }
+    }
+
+    /// Number of malformed rows skipped before the import is aborted.
+    pub fn max_errors(&self) -> u64 {
+        match self {
+            ImportFormat::CSV | ImportFormat::Parquet => 0,
+            ImportFormat::CSVWithOptions(options) => options.max_errors,
+            ImportFormat::JSONLines(options) => options.max_errors,
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash, Default)]
+pub struct JsonOptions {
+    /// Lines with fields not mapped to columns or values not matching column types are malformed.
+    /// Otherwise such fields are ignored and such values are imported as NULL.
+    pub strict: bool,
+    /// Dotted paths of nested values by column name, e.g. `properties.plan`.
+    pub paths: Vec<(String, String)>,
+    pub max_errors: u64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
+pub struct CsvOptions {
+    pub delimiter: char,
+    pub quote: char,
+    /// Escapes the next character inside quoted fields. Doubled quotes are used if not set.
+    pub escape: Option<char>,
+    /// First record contains column names which are matched to table columns by name.
+    pub with_header: bool,
+    /// Number of malformed rows skipped before the import is aborted.
+    pub max_errors: u64,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: ',',
+            quote: '"',
+            escape: None,
+            with_header: false,
+            max_errors: 0,
+        }
+    }
 }
 
 data_frame_from! {
@@ -410,7 +474,9 @@ pub struct Index {
     name: String,
     table_id: u64,
     columns: Vec<Column>,
-    sort_key_size: u64
+    sort_key_size: u64,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    building: bool
 }
 }
 
@@ -430,7 +496,10 @@ pub struct Partition {
     active: bool,
     main_table_row_count: u64,
     #[serde(default)]
-    last_used: Option<DateTime<Utc>>
+    last_used: Option<DateTime<Utc>>,
+    /// Size of the partition file in bytes. Unknown for files written before it was recorded.
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    file_size: Option<u64>
 }
 }
 
@@ -442,7 +511,18 @@ pub struct Chunk {
     uploaded: bool,
     active: bool,
     #[serde(default)]
-    last_used: Option<DateTime<Utc>>
+    last_used: Option<DateTime<Utc>>,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    min_value: Option<Row>,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    max_value: Option<Row>,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    level: u64,
+    #[serde(default, with = "crate::queryplanner::wire_format::required_since_v2")]
+    tombstone: bool,
+    /// Position among writes to the partition if it differs from the id, see `IdRow::sequence`.
+    #[serde(default, with = "crate::queryplanner::wire_format::required_since_v2")]
+    sequence: Option<u64>
 }
 }
 
@@ -611,7 +691,10 @@ pub trait MetaStore: Send + Sync {
         columns: Vec<Column>,
         location: Option<String>,
         import_format: Option<ImportFormat>,
+        location_sha256: Option<String>,
         indexes: Vec<IndexDef>,
+        unique_key_columns: Option<Vec<String>>,
+        tenant_column: Option<String>,
     ) -> Result<IdRow<Table>, CubeError>;
     async fn get_table(
         &self,
@@ -622,6 +705,19 @@ pub trait MetaStore: Send + Sync {
     async fn get_tables(&self) -> Result<Vec<IdRow<Table>>, CubeError>;
     async fn get_tables_with_path(&self) -> Result<Vec<TablePath>, CubeError>;
     async fn drop_table(&self, table_id: u64) -> Result<IdRow<Table>, CubeError>;
+    async fn set_table_retention(
+        &self,
+        table_id: u64,
+        retention: Option<Retention>,
+    ) -> Result<IdRow<Table>, CubeError>;
+    /// Appends `column` to the table and all of its indexes. Files already written aren't
+    /// changed and `default_value` is read in place of the column from them.
+    async fn add_column(
+        &self,
+        table_id: u64,
+        column: Column,
+        default_value: TableValue,
+    ) -> Result<IdRow<Table>, CubeError>;
 
     fn partition_table(&self) -> PartitionMetaStoreTable;
     async fn create_partition(&self, partition: Partition) -> Result<IdRow<Partition>, CubeError>;
@@ -630,6 +726,9 @@ pub trait MetaStore: Send + Sync {
         &self,
         partition_id: u64,
     ) -> Result<(IdRow<Partition>, IdRow<Index>), CubeError>;
+    /// Deactivates `partition_id` along with its chunks and activates an empty partition with
+    /// the same key range in its place.
+    async fn expire_partition(&self, partition_id: u64) -> Result<IdRow<Partition>, CubeError>;
     async fn get_partition_chunk_sizes(&self, partition_id: u64) -> Result<u64, CubeError>;
     async fn swap_active_partitions(
         &self,
@@ -637,6 +736,7 @@ pub trait MetaStore: Send + Sync {
         new_active: Vec<u64>,
         compacted_chunk_ids: Vec<u64>,
         new_active_min_max: Vec<(u64, (Option<Row>, Option<Row>))>,
+        new_active_file_sizes: Vec<Option<u64>>,
     ) -> Result<(), CubeError>;
     async fn is_partition_used(&self, partition_id: u64) -> Result<bool, CubeError>;
 
@@ -653,18 +753,36 @@ pub trait MetaStore: Send + Sync {
         &self,
         index_id: u64,
     ) -> Result<Vec<IdRow<Partition>>, CubeError>;
+    async fn get_partitions_by_index_id(
+        &self,
+        index_id: u64,
+    ) -> Result<Vec<IdRow<Partition>>, CubeError>;
 
     async fn get_active_partitions_and_chunks_by_index_id_for_select(
         &self,
         index_id: u64,
     ) -> Result<Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>, CubeError>;
+    /// Same as `get_active_partitions_and_chunks_by_index_id_for_select` for each of `index_ids`
+    /// read at once, so compaction can't change some of the indexes between reads.
+    async fn get_active_partitions_and_chunks_by_index_ids_for_select(
+        &self,
+        index_ids: Vec<u64>,
+    ) -> Result<Vec<Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>>, CubeError>;
+    /// Data of the default index to copy into the building `index_id`. Chunks of `index_id`
+    /// activated so far are deactivated as their rows are part of the returned data.
+    async fn start_index_backfill(
+        &self,
+        index_id: u64,
+    ) -> Result<Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>, CubeError>;
+    /// Activates backfilled `chunk_ids` along with `index_id` so queries can use it.
+    async fn finish_index_backfill(
+        &self,
+        index_id: u64,
+        chunk_ids: Vec<u64>,
+    ) -> Result<IdRow<Index>, CubeError>;
 
     fn chunks_table(&self) -> ChunkMetaStoreTable;
-    async fn create_chunk(
-        &self,
-        partition_id: u64,
-        row_count: usize,
-    ) -> Result<IdRow<Chunk>, CubeError>;
+    async fn create_chunk(&self, chunk: Chunk) -> Result<IdRow<Chunk>, CubeError>;
     async fn get_chunk(&self, chunk_id: u64) -> Result<IdRow<Chunk>, CubeError>;
     async fn get_chunks_by_partition(
         &self,
@@ -702,6 +820,9 @@ pub trait MetaStore: Send + Sync {
     ) -> Result<Option<IdRow<Job>>, CubeError>;
     async fn update_status(&self, job_id: u64, status: JobStatus) -> Result<IdRow<Job>, CubeError>;
     async fn update_heart_beat(&self, job_id: u64) -> Result<IdRow<Job>, CubeError>;
+
+    /// Replaces metastore state with the latest snapshot uploaded to the remote fs.
+    async fn restore_from_remote(&self) -> Result<(), CubeError>;
 }
 
 #[derive(Clone, Debug)]
@@ -867,7 +988,7 @@ trait BaseRocksSecondaryIndex<T>: Debug {
     }
 
     fn hash_bytes(&self, key_bytes: &Vec<u8>) -> u64 {
-        let mut hasher = DefaultHasher::new();
+        let mut hasher = StableHasher::new();
         key_bytes.hash(&mut hasher);
         hasher.finish()
     }
@@ -1419,12 +1540,7 @@ impl RocksMetaStore {
         remote_fs: Arc<dyn RemoteFs>,
         config: Arc<dyn ConfigObj>,
     ) -> RocksMetaStore {
-        let mut opts = Options::default();
-        opts.create_if_missing(true);
-        opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(13));
-        opts.set_merge_operator("meta_store merge", meta_store_merge, None);
-
-        let db = DB::open(&opts, path).unwrap();
+        let db = RocksMetaStore::open_db(path).unwrap();
         let db_arc = Arc::new(db);
 
         let meta_store = RocksMetaStore {
@@ -1443,6 +1559,15 @@ impl RocksMetaStore {
         meta_store
     }
 
+    fn open_db(path: impl AsRef<Path>) -> Result<DB, CubeError> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(13));
+        opts.set_merge_operator("meta_store merge", meta_store_merge, None);
+
+        Ok(DB::open(&opts, path)?)
+    }
+
     pub fn new(
         path: impl AsRef<Path>,
         remote_fs: Arc<dyn RemoteFs>,
@@ -1457,71 +1582,18 @@ impl RocksMetaStore {
         config: Arc<dyn ConfigObj>,
     ) -> Result<Arc<RocksMetaStore>, CubeError> {
         if !fs::metadata(path.as_ref()).await.is_ok() {
-            let re = Regex::new(r"^metastore-(\d+)").unwrap();
-
-            if remote_fs.list("metastore-current").await?.iter().len() > 0 {
+            if let Some(snapshot) = RocksMetaStore::current_snapshot(remote_fs.as_ref()).await? {
                 info!("Downloading remote metastore");
-                let current_metastore_file = remote_fs.local_file("metastore-current").await?;
-                if fs::metadata(current_metastore_file.as_str()).await.is_ok() {
-                    fs::remove_file(current_metastore_file.as_str()).await?;
-                }
-                remote_fs.download_file("metastore-current").await?;
-
-                let mut file = File::open(current_metastore_file.as_str()).await?;
-                let mut buffer = Vec::new();
-                tokio::io::AsyncReadExt::read_to_end(&mut file, &mut buffer).await?;
-                let last_metastore_snapshot = {
-                    let parse_result = re
-                        .captures(&String::from_utf8(buffer)?)
-                        .map(|c| c.get(1).unwrap().as_str())
-                        .map(|p| u128::from_str(p));
-                    if let Some(Ok(millis)) = parse_result {
-                        Some(millis)
-                    } else {
-                        None
-                    }
-                };
-
-                if let Some(snapshot) = last_metastore_snapshot {
-                    let to_load = remote_fs.list(&format!("metastore-{}", snapshot)).await?;
-                    let meta_store_path = remote_fs.local_file("metastore").await?;
-                    fs::create_dir_all(meta_store_path.to_string()).await?;
-                    for file in to_load.iter() {
-                        remote_fs.download_file(file).await?;
-                        let local = remote_fs.local_file(file).await?;
-                        let path = Path::new(&local);
-                        fs::copy(
-                            path,
-                            PathBuf::from(&meta_store_path)
-                                .join(path.file_name().unwrap().to_str().unwrap()),
-                        )
-                        .await?;
-                    }
+                RocksMetaStore::download_snapshot(remote_fs.as_ref(), snapshot, path.as_ref())
+                    .await?;
 
-                    let meta_store = Self::new(path.as_ref(), remote_fs.clone(), config);
-
-                    let logs_to_batch = remote_fs
-                        .list(&format!("metastore-{}-logs", snapshot))
-                        .await?;
-                    for log_file in logs_to_batch.iter() {
-                        let path_to_log = remote_fs.local_file(log_file).await?;
-                        let batch = WriteBatchContainer::read_from_file(&path_to_log).await;
-                        if let Ok(batch) = batch {
-                            let db = meta_store.db.write().await;
-                            db.write(batch.write_batch())?;
-                        } else if let Err(e) = batch {
-                            error!(
-                                "Corrupted metastore WAL file. Discarding: {:?} {}",
-                                log_file, e
-                            );
-                            break;
-                        }
-                    }
-
-                    return Ok(meta_store);
+                let meta_store = Self::new(path.as_ref(), remote_fs.clone(), config);
+                {
+                    let db = meta_store.db.write().await;
+                    RocksMetaStore::apply_snapshot_logs(&db, remote_fs.as_ref(), snapshot).await?;
                 }
-            } else {
-                trace!("Can't find metastore-current in {:?}", remote_fs);
+
+                return Ok(meta_store);
             }
             info!(
                 "Creating metastore from scratch in {}",
@@ -1537,6 +1609,139 @@ impl RocksMetaStore {
         Ok(Self::new(path, remote_fs, config))
     }
 
+    /// Millis of the latest uploaded snapshot referenced by `metastore-current`.
+    async fn current_snapshot(remote_fs: &dyn RemoteFs) -> Result<Option<u128>, CubeError> {
+        if remote_fs.list("metastore-current").await?.iter().len() == 0 {
+            trace!("Can't find metastore-current in {:?}", remote_fs);
+            return Ok(None);
+        }
+        let current_metastore_file = remote_fs.local_file("metastore-current").await?;
+        if fs::metadata(current_metastore_file.as_str()).await.is_ok() {
+            fs::remove_file(current_metastore_file.as_str()).await?;
+        }
+        remote_fs.download_file("metastore-current").await?;
+
+        let mut file = File::open(current_metastore_file.as_str()).await?;
+        let mut buffer = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut file, &mut buffer).await?;
+        let re = Regex::new(r"^metastore-(\d+)").unwrap();
+        let parse_result = re
+            .captures(&String::from_utf8(buffer)?)
+            .map(|c| c.get(1).unwrap().as_str())
+            .map(|p| u128::from_str(p));
+        if let Some(Ok(millis)) = parse_result {
+            Ok(Some(millis))
+        } else {
+            Ok(None)
+        }
+    }
+
+    async fn download_snapshot(
+        remote_fs: &dyn RemoteFs,
+        snapshot: u128,
+        meta_store_path: &Path,
+    ) -> Result<(), CubeError> {
+        let to_load = remote_fs.list(&format!("metastore-{}", snapshot)).await?;
+        fs::create_dir_all(meta_store_path).await?;
+        for file in to_load.iter() {
+            remote_fs.download_file(file).await?;
+            let local = remote_fs.local_file(file).await?;
+            let path = Path::new(&local);
+            fs::copy(
+                path,
+                meta_store_path.join(path.file_name().unwrap().to_str().unwrap()),
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Replays logs uploaded after `snapshot` was taken in the order they were written.
+    async fn apply_snapshot_logs(
+        db: &DB,
+        remote_fs: &dyn RemoteFs,
+        snapshot: u128,
+    ) -> Result<(), CubeError> {
+        let logs_to_batch = remote_fs
+            .list(&format!("metastore-{}-logs", snapshot))
+            .await?
+            .into_iter()
+            .sorted_by_key(|log_file| {
+                Path::new(log_file)
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| u64::from_str(s).ok())
+            })
+            .collect::<Vec<_>>();
+        for log_file in logs_to_batch.iter() {
+            remote_fs.download_file(log_file).await?;
+            let path_to_log = remote_fs.local_file(log_file).await?;
+            let batch = WriteBatchContainer::read_from_file(&path_to_log).await;
+            if let Ok(batch) = batch {
+                db.write(batch.write_batch())?;
+            } else if let Err(e) = batch {
+                error!(
+                    "Corrupted metastore WAL file. Discarding: {:?} {}",
+                    log_file, e
+                );
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the local metastore with the latest snapshot and logs from the remote fs.
+    async fn restore_from_remote_impl(&self) -> Result<(), CubeError> {
+        let mut checkpoint_time = self.last_checkpoint_time.write().await;
+        let snapshot = RocksMetaStore::current_snapshot(self.remote_fs.as_ref())
+            .await?
+            .ok_or_else(|| CubeError::user("Metastore snapshot is not found".to_string()))?;
+        let mut db = self.db.write().await;
+        let path = db.path().to_path_buf();
+
+        let restore_path = PathBuf::from(format!("{}-restore", path.to_string_lossy()));
+        if fs::metadata(&restore_path).await.is_ok() {
+            fs::remove_dir_all(&restore_path).await?;
+        }
+        RocksMetaStore::download_snapshot(self.remote_fs.as_ref(), snapshot, &restore_path).await?;
+        {
+            let restored = RocksMetaStore::open_db(&restore_path)?;
+            RocksMetaStore::apply_snapshot_logs(&restored, self.remote_fs.as_ref(), snapshot)
+                .await?;
+        }
+
+        // Current DB should be closed before its directory is replaced.
+        let placeholder_path = PathBuf::from(format!("{}-placeholder", path.to_string_lossy()));
+        let current = mem::replace(
+            &mut *db,
+            Arc::new(RocksMetaStore::open_db(&placeholder_path)?),
+        );
+        if Arc::strong_count(&current) > 1 {
+            *db = current;
+            fs::remove_dir_all(&placeholder_path).await?;
+            return Err(CubeError::internal(
+                "Metastore is in use and can't be restored".to_string(),
+            ));
+        }
+        mem::drop(current);
+        fs::remove_dir_all(&path).await?;
+        fs::rename(&restore_path, &path).await?;
+        *db = Arc::new(RocksMetaStore::open_db(&path)?);
+        fs::remove_dir_all(&placeholder_path).await?;
+
+        self.seq_store.lock()?.clear();
+        let seq = db.latest_sequence_number();
+        *self.last_upload_seq.write().await = seq;
+        *self.last_check_seq.write().await = seq;
+
+        *checkpoint_time = SystemTime::now();
+        RocksMetaStore::upload_checkpoint(db.clone(), self.remote_fs.clone(), &checkpoint_time)
+            .await?;
+        self.write_completed_notify.notify();
+        info!("Metastore restored from snapshot metastore-{}", snapshot);
+        Ok(())
+    }
+
     pub async fn add_listener(&self, listener: Sender<MetaStoreEvent>) {
         self.listeners.write().await.push(listener);
     }
@@ -1641,7 +1846,10 @@ impl RocksMetaStore {
         }
 
         let last_checkpoint_time: SystemTime = self.last_checkpoint_time.read().await.clone();
-        if last_checkpoint_time + time::Duration::from_secs(60) < SystemTime::now() {
+        if last_checkpoint_time
+            + time::Duration::from_secs(self.config.metastore_snapshot_interval())
+            < SystemTime::now()
+        {
             self.upload_check_point().await?;
         }
 
@@ -1846,7 +2054,15 @@ impl RocksMetaStore {
         index_cols: &Vec<Column>,
         table_id: &IdRow<Table>,
         index_def: IndexDef,
+        building: bool,
     ) -> Result<IdRow<Index>, CubeError> {
+        if table_id.get_row().unique_key_columns().is_some() {
+            return Err(CubeError::user(format!(
+                "Can't create '{}' index because '{}' table has a unique key",
+                index_def.name,
+                table_id.get_row().get_table_name()
+            )));
+        }
         if let Some(not_found) = index_def
             .columns
             .iter()
@@ -1867,6 +2083,7 @@ impl RocksMetaStore {
                     .find(|dc| c.name.as_str() == dc.as_str())
                     .is_some()
             });
+        RocksMetaStore::lead_with_tenant_column(table_id.get_row(), &mut sorted, &mut unsorted);
         let sorted_key_size = sorted.len() as u64;
         sorted.append(&mut unsorted);
         let index = Index::try_new(
@@ -1878,13 +2095,33 @@ impl RocksMetaStore {
                 .map(|(i, c)| c.replace_index(i))
                 .collect::<Vec<_>>(),
             sorted_key_size,
-        )?;
+        )?
+        .with_building(building);
         let index_id = rocks_index.insert(index, batch_pipe)?;
         let partition = Partition::new(index_id.id, None, None);
         let _ = rocks_partition.insert(partition, batch_pipe)?;
         Ok(index_id)
     }
 
+    /// Partitions are split by the tenant column so it has to lead sort keys of all indexes.
+    fn lead_with_tenant_column(
+        table: &Table,
+        sorted: &mut Vec<Column>,
+        unsorted: &mut Vec<Column>,
+    ) {
+        if let Some(tenant_column) = table.tenant_column() {
+            let column = if let Some(i) = sorted.iter().position(|c| c.get_name() == tenant_column)
+            {
+                sorted.remove(i)
+            } else if let Some(i) = unsorted.iter().position(|c| c.get_name() == tenant_column) {
+                unsorted.remove(i)
+            } else {
+                return;
+            };
+            sorted.insert(0, column);
+        }
+    }
+
     fn get_table_by_name(
         schema_name: String,
         table_name: String,
@@ -1898,6 +2135,45 @@ impl RocksMetaStore {
         Ok(table)
     }
 
+    fn active_partitions_and_chunks_for_select(
+        index_id: u64,
+        rocks_chunk: &ChunkRocksTable,
+        rocks_partition: &PartitionRocksTable,
+        batch_pipe: &mut BatchPipe,
+    ) -> Result<Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>, CubeError> {
+        // TODO iterate over range
+        let result = rocks_partition
+            .get_rows_by_index(
+                &PartitionIndexKey::ByIndexId(index_id),
+                &PartitionRocksIndex::IndexId,
+            )?
+            .into_iter()
+            .filter(|r| r.get_row().active)
+            .map(|p| -> Result<_, CubeError> {
+                let chunks = Self::chunks_by_partitioned_with_non_repartitioned(
+                    p.get_id(),
+                    rocks_chunk,
+                    rocks_partition,
+                )?;
+                Ok((p, chunks))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // update last used
+        for (partition, chunks) in result.iter() {
+            rocks_partition.update_with_fn(
+                partition.get_id(),
+                |p| p.update_last_used(),
+                batch_pipe,
+            )?;
+            for chunk in chunks.iter() {
+                rocks_chunk.update_with_fn(chunk.get_id(), |c| c.update_last_used(), batch_pipe)?;
+            }
+        }
+
+        Ok(result)
+    }
+
     fn chunks_by_partitioned_with_non_repartitioned(
         partition_id: u64,
         table: &ChunkRocksTable,
@@ -2080,7 +2356,10 @@ impl MetaStore for RocksMetaStore {
         columns: Vec<Column>,
         location: Option<String>,
         import_format: Option<ImportFormat>,
+        location_sha256: Option<String>,
         indexes: Vec<IndexDef>,
+        unique_key_columns: Option<Vec<String>>,
+        tenant_column: Option<String>,
     ) -> Result<IdRow<Table>, CubeError> {
         self.write_operation(move |db_ref, batch_pipe| {
             let rocks_table = TableRocksTable::new(db_ref.clone());
@@ -2097,7 +2376,39 @@ impl MetaStore for RocksMetaStore {
                 columns,
                 location,
                 import_format,
-            );
+            )
+            .with_unique_key(unique_key_columns.clone())
+            .with_location_sha256(location_sha256)
+            .with_tenant_column(tenant_column.clone());
+            if let Some(tenant_column) = &tenant_column {
+                match index_cols.iter().find(|c| c.get_name() == tenant_column) {
+                    Some(c) => match c.get_column_type() {
+                        ColumnType::Int | ColumnType::String => {}
+                        _ => {
+                            return Err(CubeError::user(format!(
+                                "Tenant column {} should be an int or a string",
+                                tenant_column
+                            )))
+                        }
+                    },
+                    None => {
+                        return Err(CubeError::user(format!(
+                            "Tenant column {} not found in table {}",
+                            tenant_column,
+                            table.get_table_name()
+                        )))
+                    }
+                }
+                // Otherwise rows of different tenants would replace each other
+                if let Some(key) = &unique_key_columns {
+                    if !key.contains(tenant_column) {
+                        return Err(CubeError::user(format!(
+                            "Tenant column {} should be a part of unique key",
+                            tenant_column
+                        )));
+                    }
+                }
+            }
             let table_id = rocks_table.insert(table, batch_pipe)?;
             for index_def in indexes.into_iter() {
                 RocksMetaStore::add_index(
@@ -2107,17 +2418,49 @@ impl MetaStore for RocksMetaStore {
                     &index_cols,
                     &table_id,
                     index_def,
+                    false,
                 )?;
             }
 
-            let (mut sorted, mut unsorted) =
+            // Rows with the same unique key have equal sort keys so they always share a partition
+            let (mut sorted, mut unsorted) = if let Some(key) = unique_key_columns {
+                let mut sorted = Vec::new();
+                for name in key.iter() {
+                    match index_cols.iter().find(|c| c.get_name() == name) {
+                        Some(c) => match c.get_column_type() {
+                            ColumnType::Decimal { .. } | ColumnType::Bytes => {
+                                return Err(CubeError::user(format!(
+                                    "Column {} can't be a part of unique key",
+                                    c
+                                )))
+                            }
+                            _ => sorted.push(c.clone()),
+                        },
+                        None => {
+                            return Err(CubeError::user(format!(
+                                "Unique key column {} not found in table {}",
+                                name,
+                                table_id.get_row().get_table_name()
+                            )))
+                        }
+                    }
+                }
+                let unsorted = index_cols
+                    .iter()
+                    .filter(|c| !key.contains(c.get_name()))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                (sorted, unsorted)
+            } else {
                 index_cols.clone().into_iter().partition::<Vec<_>, _>(|c| {
                     match c.get_column_type() {
                         ColumnType::Decimal { .. } | ColumnType::Bytes => false,
                         _ => true,
                     }
-                });
+                })
+            };
 
+            RocksMetaStore::lead_with_tenant_column(table_id.get_row(), &mut sorted, &mut unsorted);
             let sorted_key_size = sorted.len() as u64;
             sorted.append(&mut unsorted);
 
@@ -2215,6 +2558,61 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    async fn set_table_retention(
+        &self,
+        table_id: u64,
+        retention: Option<Retention>,
+    ) -> Result<IdRow<Table>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            Ok(TableRocksTable::new(db_ref).update_with_fn(
+                table_id,
+                |t| t.update_retention(retention),
+                batch_pipe,
+            )?)
+        })
+        .await
+    }
+
+    async fn add_column(
+        &self,
+        table_id: u64,
+        column: Column,
+        default_value: TableValue,
+    ) -> Result<IdRow<Table>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let tables_table = TableRocksTable::new(db_ref.clone());
+            let indexes_table = IndexRocksTable::new(db_ref);
+            let table = tables_table.get_row_or_not_found(table_id)?;
+            if table
+                .get_row()
+                .get_columns()
+                .iter()
+                .any(|c| c.get_name() == column.get_name())
+            {
+                return Err(CubeError::user(format!(
+                    "Column {} already exists in table {}",
+                    column.get_name(),
+                    table.get_row().get_table_name()
+                )));
+            }
+            let indexes = indexes_table
+                .get_rows_by_index(&IndexIndexKey::TableId(table_id), &IndexRocksIndex::TableID)?;
+            for index in indexes.into_iter() {
+                indexes_table.update_with_fn(
+                    index.get_id(),
+                    |i| i.add_column(column.clone()),
+                    batch_pipe,
+                )?;
+            }
+            Ok(tables_table.update_with_fn(
+                table_id,
+                |t| t.add_column(column, default_value),
+                batch_pipe,
+            )?)
+        })
+        .await
+    }
+
     fn partition_table(&self) -> PartitionMetaStoreTable {
         PartitionMetaStoreTable {
             rocks_meta_store: self.clone(),
@@ -2266,6 +2664,46 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    async fn expire_partition(&self, partition_id: u64) -> Result<IdRow<Partition>, CubeError> {
+        trace!("Expiring partition {}", partition_id);
+        self.write_operation(move |db_ref, batch_pipe| {
+            let table = PartitionRocksTable::new(db_ref.clone());
+            let chunk_table = ChunkRocksTable::new(db_ref.clone());
+
+            let partition = table.get_row_or_not_found(partition_id)?;
+            if !partition.get_row().is_active() {
+                return Err(CubeError::internal(format!(
+                    "Tried to expire inactive partition: {:?}",
+                    partition
+                )));
+            }
+            table.update(
+                partition_id,
+                partition.get_row().to_active(false),
+                partition.get_row(),
+                batch_pipe,
+            )?;
+            for chunk in chunk_table.get_rows_by_index(
+                &ChunkIndexKey::ByPartitionId(partition_id),
+                &ChunkRocksIndex::PartitionId,
+            )? {
+                if chunk.get_row().active() {
+                    chunk_table.update_with_fn(chunk.get_id(), |c| c.deactivate(), batch_pipe)?;
+                }
+            }
+            // No parent as there's no file to read
+            Ok(table.insert(
+                Partition::new(
+                    partition.get_row().get_index_id(),
+                    partition.get_row().get_min_val().clone(),
+                    partition.get_row().get_max_val().clone(),
+                ),
+                batch_pipe,
+            )?)
+        })
+        .await
+    }
+
     async fn get_partition_chunk_sizes(&self, partition_id: u64) -> Result<u64, CubeError> {
         let chunks = self.get_chunks_by_partition(partition_id, false).await?;
         Ok(chunks.iter().map(|r| r.get_row().row_count).sum())
@@ -2277,6 +2715,7 @@ impl MetaStore for RocksMetaStore {
         new_active: Vec<u64>,
         compacted_chunk_ids: Vec<u64>,
         new_active_min_max: Vec<(u64, (Option<Row>, Option<Row>))>,
+        new_active_file_sizes: Vec<Option<u64>>,
     ) -> Result<(), CubeError> {
         trace!(
             "Swapping partitions: deactivating ({}), deactivating chunks ({}), activating ({})",
@@ -2315,8 +2754,10 @@ impl MetaStore for RocksMetaStore {
                 deactivated_row_count += current_partition.get_row().main_table_row_count()
             }
 
-            for (new, (count, (min_value, max_value))) in
-                new_active.iter().zip(new_active_min_max.into_iter())
+            for ((new, (count, (min_value, max_value))), file_size) in new_active
+                .iter()
+                .zip(new_active_min_max.into_iter())
+                .zip(new_active_file_sizes.into_iter())
             {
                 let new_partition = table.get_row(*new)?.ok_or(CubeError::internal(format!(
                     "New partition is not found during swap active: {}",
@@ -2333,23 +2774,34 @@ impl MetaStore for RocksMetaStore {
                     new_partition
                         .get_row()
                         .to_active(true)
-                        .update_min_max_and_row_count(min_value, max_value, count),
+                        .update_min_max_and_row_count(min_value, max_value, count)
+                        .with_file_size(file_size),
                     new_partition.get_row(),
                     batch_pipe,
                 )?;
                 activated_row_count += count;
             }
 
+            // Rows of tombstone chunks are dropped from the new partitions
+            let mut deleted_row_count = 0;
             for chunk_id in compacted_chunk_ids.iter() {
-                deactivated_row_count += chunk_table.get_row_or_not_found(*chunk_id)?.get_row().get_row_count();
+                let chunk = chunk_table.get_row_or_not_found(*chunk_id)?;
+                if chunk.get_row().is_tombstone() {
+                    deleted_row_count += chunk.get_row().get_row_count();
+                } else {
+                    deactivated_row_count += chunk.get_row().get_row_count();
+                }
                 chunk_table.update_with_fn(*chunk_id, |row| row.deactivate(), batch_pipe)?;
             }
 
-            if activated_row_count != deactivated_row_count {
+            // Tombstones delete only rows written before them and replaced rows of tables with
+            // a unique key are dropped so fewer rows than deactivated ones are left
+            if activated_row_count > deactivated_row_count {
                 return Err(CubeError::internal(format!(
-                    "Deactivated row count ({}) doesn't match activated row count ({}) during swap of partition ({}) and ({}) chunks to new partitions ({})",
+                    "Deactivated row count ({}) is less than activated row count ({}), deleted row count is {}, during swap of partition ({}) and ({}) chunks to new partitions ({})",
                     deactivated_row_count,
                     activated_row_count,
+                    deleted_row_count,
                     current_active.iter().join(", "),
                     compacted_chunk_ids.iter().join(", "),
                     new_active.iter().join(", ")
@@ -2396,14 +2848,8 @@ impl MetaStore for RocksMetaStore {
                 rocks_schema,
             )?;
 
-            if *table.get_row().has_data() {
-                return Err(CubeError::user(format!(
-                    "Can't create '{}' index because '{}' table already has data",
-                    index_def.name,
-                    table.get_row().get_table_name()
-                )));
-            }
-
+            // Existing rows are copied from the default index by a backfill job
+            let building = *table.get_row().has_data();
             Ok(RocksMetaStore::add_index(
                 batch_pipe,
                 &rocks_index,
@@ -2411,6 +2857,7 @@ impl MetaStore for RocksMetaStore {
                 table.get_row().get_columns(),
                 &table,
                 index_def,
+                building,
             )?)
         })
         .await
@@ -2462,6 +2909,19 @@ impl MetaStore for RocksMetaStore {
         .await
     }
 
+    async fn get_partitions_by_index_id(
+        &self,
+        index_id: u64,
+    ) -> Result<Vec<IdRow<Partition>>, CubeError> {
+        self.read_operation(move |db_ref| {
+            PartitionRocksTable::new(db_ref).get_rows_by_index(
+                &PartitionIndexKey::ByIndexId(index_id),
+                &PartitionRocksIndex::IndexId,
+            )
+        })
+        .await
+    }
+
     async fn get_active_partitions_and_chunks_by_index_id_for_select(
         &self,
         index_id: u64,
@@ -2469,26 +2929,106 @@ impl MetaStore for RocksMetaStore {
         self.write_operation(move |db_ref, batch_pipe| {
             let rocks_chunk = ChunkRocksTable::new(db_ref.clone());
             let rocks_partition = PartitionRocksTable::new(db_ref);
-            // TODO iterate over range
-            let result = rocks_partition
-                .get_rows_by_index(
-                    &PartitionIndexKey::ByIndexId(index_id),
-                    &PartitionRocksIndex::IndexId,
-                )?
+            Self::active_partitions_and_chunks_for_select(
+                index_id,
+                &rocks_chunk,
+                &rocks_partition,
+                batch_pipe,
+            )
+        })
+        .await
+    }
+
+    async fn get_active_partitions_and_chunks_by_index_ids_for_select(
+        &self,
+        index_ids: Vec<u64>,
+    ) -> Result<Vec<Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let rocks_chunk = ChunkRocksTable::new(db_ref.clone());
+            let rocks_partition = PartitionRocksTable::new(db_ref);
+            index_ids
                 .into_iter()
-                .filter(|r| r.get_row().active)
-                .map(|p| -> Result<_, CubeError> {
-                    let chunks = Self::chunks_by_partitioned_with_non_repartitioned(
-                        p.get_id(),
+                .map(|index_id| {
+                    Self::active_partitions_and_chunks_for_select(
+                        index_id,
                         &rocks_chunk,
                         &rocks_partition,
-                    )?;
-                    Ok((p, chunks))
+                        batch_pipe,
+                    )
                 })
-                .collect::<Result<Vec<_>, _>>()?;
+                .collect()
+        })
+        .await
+    }
 
-            // update last used
-            for (partition, chunks) in result.iter() {
+    async fn start_index_backfill(
+        &self,
+        index_id: u64,
+    ) -> Result<Vec<(IdRow<Partition>, Vec<IdRow<Chunk>>)>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let rocks_index = IndexRocksTable::new(db_ref.clone());
+            let rocks_chunk = ChunkRocksTable::new(db_ref.clone());
+            let rocks_partition = PartitionRocksTable::new(db_ref);
+            let index = rocks_index.get_row_or_not_found(index_id)?;
+            if !index.get_row().is_building() {
+                return Err(CubeError::internal(format!(
+                    "Index {} is already built",
+                    index.get_row().get_name()
+                )));
+            }
+            let default_index = rocks_index
+                .get_rows_by_index(
+                    &IndexIndexKey::Name(index.get_row().table_id(), "default".to_string()),
+                    &IndexRocksIndex::Name,
+                )?
+                .into_iter()
+                .next()
+                .ok_or_else(|| {
+                    CubeError::internal(format!(
+                        "Missing default index for table {}",
+                        index.get_row().table_id()
+                    ))
+                })?;
+
+            // WALs activated from now on are written to both indexes
+            for partition in rocks_partition.get_rows_by_index(
+                &PartitionIndexKey::ByIndexId(index_id),
+                &PartitionRocksIndex::IndexId,
+            )? {
+                for chunk in rocks_chunk.get_rows_by_index(
+                    &ChunkIndexKey::ByPartitionId(partition.get_id()),
+                    &ChunkRocksIndex::PartitionId,
+                )? {
+                    if chunk.get_row().uploaded() && chunk.get_row().active() {
+                        rocks_chunk.update_with_fn(
+                            chunk.get_id(),
+                            |c| c.deactivate(),
+                            batch_pipe,
+                        )?;
+                    }
+                }
+            }
+
+            // Chunks of compacted partitions are listed for each of their children
+            let mut seen_chunks = HashSet::new();
+            let mut result = Vec::new();
+            for partition in rocks_partition
+                .get_rows_by_index(
+                    &PartitionIndexKey::ByIndexId(default_index.get_id()),
+                    &PartitionRocksIndex::IndexId,
+                )?
+                .into_iter()
+                .filter(|p| p.get_row().active)
+            {
+                let chunks = Self::chunks_by_partitioned_with_non_repartitioned(
+                    partition.get_id(),
+                    &rocks_chunk,
+                    &rocks_partition,
+                )?
+                .into_iter()
+                .filter(|c| seen_chunks.insert(c.get_id()))
+                .collect::<Vec<_>>();
+                // Files are kept as if a query read them
                 rocks_partition.update_with_fn(
                     partition.get_id(),
                     |p| p.update_last_used(),
@@ -2501,22 +3041,33 @@ impl MetaStore for RocksMetaStore {
                         batch_pipe,
                     )?;
                 }
+                result.push((partition, chunks));
             }
-
             Ok(result)
         })
         .await
     }
 
-    async fn create_chunk(
+    async fn finish_index_backfill(
         &self,
-        partition_id: u64,
-        row_count: usize,
-    ) -> Result<IdRow<Chunk>, CubeError> {
+        index_id: u64,
+        chunk_ids: Vec<u64>,
+    ) -> Result<IdRow<Index>, CubeError> {
+        self.write_operation(move |db_ref, batch_pipe| {
+            let rocks_index = IndexRocksTable::new(db_ref.clone());
+            let rocks_chunk = ChunkRocksTable::new(db_ref);
+            for id in chunk_ids.iter() {
+                rocks_chunk.update_with_fn(*id, |c| c.set_uploaded(true), batch_pipe)?;
+            }
+            Ok(rocks_index.update_with_fn(index_id, |i| i.with_building(false), batch_pipe)?)
+        })
+        .await
+    }
+
+    async fn create_chunk(&self, chunk: Chunk) -> Result<IdRow<Chunk>, CubeError> {
         self.write_operation(move |db_ref, batch_pipe| {
             let rocks_chunk = ChunkRocksTable::new(db_ref.clone());
 
-            let chunk = Chunk::new(partition_id, row_count);
             let id_row = rocks_chunk.insert(chunk, batch_pipe)?;
 
             Ok(id_row)
@@ -2594,7 +3145,21 @@ impl MetaStore for RocksMetaStore {
             let table = ChunkRocksTable::new(db_ref.clone());
             let mut activated_row_count = 0;
 
-            let deactivated_row_count = wal_table.get_row_or_not_found(wal_id_to_delete)?.get_row().get_row_count();
+            let wal = wal_table.get_row_or_not_found(wal_id_to_delete)?;
+            let deactivated_row_count = wal.get_row().get_row_count();
+            // Indexes created during partitioning would miss rows of the WAL
+            let table_index_count = IndexRocksTable::new(db_ref.clone())
+                .get_rows_by_index(
+                    &IndexIndexKey::TableId(wal.get_row().table_id()),
+                    &IndexRocksIndex::TableID,
+                )?
+                .len() as u64;
+            if table_index_count != index_count {
+                return Err(CubeError::internal(format!(
+                    "Table has {} indexes instead of {} after partitioning of WAL {}",
+                    table_index_count, index_count, wal_id_to_delete
+                )));
+            }
             wal_table.delete(wal_id_to_delete, batch_pipe)?;
 
             for id in uploaded_ids.iter() {
@@ -2831,6 +3396,10 @@ impl MetaStore for RocksMetaStore {
         })
         .await
     }
+
+    async fn restore_from_remote(&self) -> Result<(), CubeError> {
+        self.restore_from_remote_impl().await
+    }
 }
 
 #[cfg(test)]
@@ -3118,7 +3687,10 @@ mod tests {
                     columns.clone(),
                     None,
                     None,
+                    None,
                     vec![],
+                    None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -3132,7 +3704,10 @@ mod tests {
                     columns.clone(),
                     None,
                     None,
-                    vec![]
+                    None,
+                    vec![],
+                    None,
+                    None,
                 )
                 .await
                 .is_err());
@@ -21,6 +21,7 @@ impl Partition {
             active: true,
             main_table_row_count: 0,
             last_used: None,
+            file_size: None,
         }
     }
 
@@ -33,6 +34,7 @@ impl Partition {
             active: false,
             main_table_row_count: 0,
             last_used: None,
+            file_size: None,
         }
     }
 
@@ -58,6 +60,7 @@ impl Partition {
             active,
             main_table_row_count: self.main_table_row_count,
             last_used: self.last_used.clone(),
+            file_size: self.file_size,
         }
     }
 
@@ -75,9 +78,16 @@ impl Partition {
             active: self.active,
             main_table_row_count,
             last_used: self.last_used.clone(),
+            file_size: self.file_size,
         }
     }
 
+    pub fn with_file_size(&self, file_size: Option<u64>) -> Partition {
+        let mut new = self.clone();
+        new.file_size = file_size;
+        new
+    }
+
     pub fn update_last_used(&self) -> Self {
         let mut new = self.clone();
         new.last_used = Some(Utc::now());
@@ -100,6 +110,21 @@ impl Partition {
         self.main_table_row_count
     }
 
+    pub fn file_size(&self) -> Option<u64> {
+        self.file_size
+    }
+
+    /// Size of the partition file once `chunk_rows` more rows are merged into it, assuming they
+    /// take as much space as rows already in the file.
+    pub fn estimated_file_size(&self, chunk_rows: u64) -> Option<u64> {
+        match self.file_size {
+            Some(size) if self.main_table_row_count > 0 => {
+                Some(size * (self.main_table_row_count + chunk_rows) / self.main_table_row_count)
+            }
+            _ => None,
+        }
+    }
+
     pub fn is_used(&self, timeout: u64) -> bool {
         self.last_used
             .map(|time| Utc::now().sub(time.clone()).num_seconds() < timeout as i64)
@@ -24,10 +24,89 @@ pub struct Table {
     location: Option<String>,
     import_format: Option<ImportFormat>,
     #[serde(default)]
-    has_data: bool
+    has_data: bool,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    retention: Option<Retention>,
+    #[serde(default, with = "crate::queryplanner::wire_format::required_since_v2")]
+    unique_key_columns: Option<Vec<String>>,
+    #[serde(default, with = "crate::queryplanner::wire_format::required_since_v2")]
+    added_columns: Vec<AddedColumn>,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    location_sha256: Option<String>,
+    #[serde(default, with = "crate::queryplanner::wire_format::since_v2")]
+    tenant_column: Option<String>
 }
 }
 
+/// Column added by `ALTER TABLE ... ADD COLUMN`. Files written before the table `version` it was
+/// added in don't have it and it reads as `default_value` there.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
+pub struct AddedColumn {
+    name: String,
+    version: u64,
+    default_value: TableValue,
+}
+
+impl AddedColumn {
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn default_value(&self) -> &TableValue {
+        &self.default_value
+    }
+}
+
+impl DataFrameValue<String> for Vec<AddedColumn> {
+    fn value(v: &Self) -> String {
+        v.iter()
+            .map(|c| format!("{} (v{}) DEFAULT {:?}", c.name, c.version, c.default_value))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Rows whose `column` is more than `millis` in the past age out of the table.
+#[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq, Hash)]
+pub struct Retention {
+    column: String,
+    millis: u64,
+}
+
+impl Retention {
+    pub fn new(column: String, millis: u64) -> Retention {
+        Retention { column, millis }
+    }
+
+    pub fn column(&self) -> &String {
+        &self.column
+    }
+
+    pub fn millis(&self) -> u64 {
+        self.millis
+    }
+}
+
+impl DataFrameValue<String> for Option<Retention> {
+    fn value(v: &Self) -> String {
+        v.as_ref()
+            .map(|v| format!("{} ms by {}", v.millis, v.column))
+            .unwrap_or("NULL".to_string())
+    }
+}
+
+impl DataFrameValue<String> for Option<Vec<String>> {
+    fn value(v: &Self) -> String {
+        v.as_ref()
+            .map(|v| v.join(", "))
+            .unwrap_or("NULL".to_string())
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct TablePath {
     pub table: IdRow<Table>,
@@ -57,8 +136,39 @@ impl Table {
             location,
             import_format,
             has_data: false,
+            retention: None,
+            unique_key_columns: None,
+            added_columns: Vec::new(),
+            location_sha256: None,
+            tenant_column: None,
         }
     }
+
+    /// Rows with equal `unique_key_columns` replace each other, the last written one wins.
+    pub fn with_unique_key(self, unique_key_columns: Option<Vec<String>>) -> Table {
+        Table {
+            unique_key_columns,
+            ..self
+        }
+    }
+
+    /// Expected SHA-256 of the file at `location`, it's validated when the table is imported.
+    pub fn with_location_sha256(self, location_sha256: Option<String>) -> Table {
+        Table {
+            location_sha256,
+            ..self
+        }
+    }
+
+    /// `tenant_column` leads sort keys of all indexes and partitions are split so they never mix
+    /// its values.
+    pub fn with_tenant_column(self, tenant_column: Option<String>) -> Table {
+        Table {
+            tenant_column,
+            ..self
+        }
+    }
+
     pub fn get_columns(&self) -> &Vec<Column> {
         &self.columns
     }
@@ -75,6 +185,10 @@ impl Table {
         &self.location
     }
 
+    pub fn location_sha256(&self) -> &Option<String> {
+        &self.location_sha256
+    }
+
     pub fn get_table_name(&self) -> &String {
         &self.table_name
     }
@@ -91,6 +205,55 @@ impl Table {
             location: self.location.clone(),
             import_format: self.import_format.clone(),
             has_data,
+            retention: self.retention.clone(),
+            unique_key_columns: self.unique_key_columns.clone(),
+            added_columns: self.added_columns.clone(),
+            location_sha256: self.location_sha256.clone(),
+            tenant_column: self.tenant_column.clone(),
+        }
+    }
+
+    pub fn retention(&self) -> &Option<Retention> {
+        &self.retention
+    }
+
+    pub fn unique_key_columns(&self) -> &Option<Vec<String>> {
+        &self.unique_key_columns
+    }
+
+    pub fn tenant_column(&self) -> &Option<String> {
+        &self.tenant_column
+    }
+
+    pub fn added_columns(&self) -> &Vec<AddedColumn> {
+        &self.added_columns
+    }
+
+    /// Value of `column` in rows written before it was added to the table.
+    pub fn column_default(&self, column: &str) -> TableValue {
+        self.added_columns
+            .iter()
+            .find(|c| c.name == column)
+            .map(|c| c.default_value.clone())
+            .unwrap_or(TableValue::Null)
+    }
+
+    /// Appends `column` to the table. Every alteration bumps the table version.
+    pub fn add_column(&self, column: Column, default_value: TableValue) -> Self {
+        let mut table = self.clone();
+        table.added_columns.push(AddedColumn {
+            name: column.get_name().clone(),
+            version: self.added_columns.len() as u64 + 1,
+            default_value,
+        });
+        table.columns.push(column.replace_index(self.columns.len()));
+        table
+    }
+
+    pub fn update_retention(&self, retention: Option<Retention>) -> Self {
+        Self {
+            retention,
+            ..self.clone()
         }
     }
 }
@@ -1,16 +1,99 @@
-use crate::sql::SqlService;
-use crate::table::TableValue;
+use crate::sql::prepared::PreparedStatement;
+use crate::sql::{QueryOptions, SqlService};
+use crate::store::DataFrame;
+use crate::table::{Row, TableValue, TimestampValue};
 use crate::{metastore, CubeError};
 use async_trait::async_trait;
+use chrono::NaiveDate;
+use futures::future;
 use log::{error, info, warn};
 use msql_srv::*;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
 use std::io;
-use std::sync::Arc;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
 use std::time::SystemTime;
-use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
 
 struct Backend {
     sql_service: Arc<dyn SqlService>,
+    /// Statements prepared by the connection by their ids.
+    statements: HashMap<u32, PreparedStatement>,
+    next_statement_id: u32,
+    max_prepared_statements: usize,
+    /// Set by `SET <option> = <value>` and applied to following queries of the connection.
+    options: QueryOptions,
+    connection_id: String,
+    socket: SharedSocket,
+}
+
+impl Backend {
+    /// Runs `query` of the connection. Queries of the connection are cancelled if the client
+    /// disconnects meanwhile.
+    async fn run(
+        &self,
+        query: impl Future<Output = Result<DataFrame, CubeError>>,
+    ) -> Result<DataFrame, CubeError> {
+        tokio::pin!(query);
+        tokio::select! {
+            res = &mut query => res,
+            _ = self.socket.closed() => {
+                info!("Connection {} closed while running a query", self.connection_id);
+                self.sql_service.cancel_connection(&self.connection_id);
+                query.await
+            }
+        }
+    }
+}
+
+/// Socket of a connection shared with its `Backend` to notice the client is gone while a query
+/// runs. The protocol reads the socket only between queries, so both never wait for reads.
+#[derive(Clone)]
+struct SharedSocket(Arc<Mutex<TcpStream>>);
+
+impl SharedSocket {
+    /// Resolves once the client closes the connection. Never resolves if the client sends
+    /// something instead as that is read by the protocol after the query.
+    async fn closed(&self) {
+        let mut buf = [0u8; 1];
+        let res = future::poll_fn(|cx| self.0.lock().unwrap().poll_peek(cx, &mut buf)).await;
+        match res {
+            Ok(0) | Err(_) => {}
+            Ok(_) => future::pending().await,
+        }
+    }
+}
+
+impl AsyncRead for SharedSocket {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SharedSocket {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.0.lock().unwrap()).poll_shutdown(cx)
+    }
 }
 
 #[async_trait]
@@ -19,25 +102,77 @@ impl<W: io::Write + Send> AsyncMysqlShim<W> for Backend {
 
     async fn on_prepare<'a>(
         &'a mut self,
-        _query: &'a str,
+        query: &'a str,
         info: StatementMetaWriter<'a, W>,
     ) -> Result<(), Self::Error> {
-        info.reply(42, &[], &[])
+        if self.statements.len() >= self.max_prepared_statements {
+            let message = format!(
+                "Can't prepare more than {} statements per connection",
+                self.max_prepared_statements
+            );
+            return info.error(
+                ErrorKind::ER_MAX_PREPARED_STMT_COUNT_REACHED,
+                message.as_bytes(),
+            );
+        }
+        let statement = match PreparedStatement::parse(query) {
+            Ok(statement) => statement,
+            Err(e) => {
+                error!("Error during preparing {}: {}", query, e.message);
+                return info.error(ErrorKind::ER_PARSE_ERROR, e.message.as_bytes());
+            }
+        };
+        // Types of parameters and result columns are known only once they're bound
+        let params = (0..statement.param_count())
+            .map(|_| Column {
+                table: String::new(),
+                column: "?".to_string(),
+                coltype: ColumnType::MYSQL_TYPE_STRING,
+                colflags: ColumnFlags::empty(),
+            })
+            .collect::<Vec<_>>();
+        self.next_statement_id += 1;
+        let id = self.next_statement_id;
+        self.statements.insert(id, statement);
+        info.reply(id, &params, &[])
     }
 
     async fn on_execute<'a>(
         &'a mut self,
-        _id: u32,
-        _params: ParamParser<'a>,
+        id: u32,
+        params: ParamParser<'a>,
         results: QueryResultWriter<'a, W>,
     ) -> Result<(), Self::Error> {
-        results.completed(0, 0)
+        let start = SystemTime::now();
+        let statement = match self.statements.get(&id) {
+            Some(statement) => statement,
+            None => {
+                let message = format!("Unknown prepared statement {}", id);
+                return results.error(ErrorKind::ER_UNKNOWN_STMT_HANDLER, message.as_bytes());
+            }
+        };
+        let res = match params
+            .into_iter()
+            .map(param_value)
+            .collect::<Result<Vec<_>, _>>()
+        {
+            Ok(params) => {
+                self.run(
+                    self.sql_service
+                        .exec_prepared(statement, &params, self.options.clone()),
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        write_result(statement.query(), start, res, results)
     }
 
-    async fn on_close<'a>(&'a mut self, _stmt: u32)
+    async fn on_close<'a>(&'a mut self, stmt: u32)
     where
         W: 'async_trait,
     {
+        self.statements.remove(&stmt);
     }
 
     async fn on_query<'a>(
@@ -46,57 +181,186 @@ impl<W: io::Write + Send> AsyncMysqlShim<W> for Backend {
         results: QueryResultWriter<'a, W>,
     ) -> Result<(), Self::Error> {
         let start = SystemTime::now();
-        let res = self.sql_service.exec_query(query).await;
-        if let Err(e) = res {
-            error!("Error during processing {}: {}", query, e.message);
-            results.error(ErrorKind::ER_INTERNAL_ERROR, e.message.as_bytes())?;
-            return Ok(());
+        let res = match system_variables(query) {
+            Some(data_frame) => Ok(data_frame),
+            None => match set_query_option(query, &mut self.options) {
+                Some(res) => res.map(|_| DataFrame::new(vec![], vec![])),
+                None => {
+                    self.run(
+                        self.sql_service
+                            .exec_query_with_options(query, self.options.clone()),
+                    )
+                    .await
+                }
+            },
+        };
+        write_result(query, start, res, results)
+    }
+}
+
+fn write_result<W: io::Write>(
+    query: &str,
+    start: SystemTime,
+    res: Result<DataFrame, CubeError>,
+    results: QueryResultWriter<'_, W>,
+) -> io::Result<()> {
+    if let Err(e) = res {
+        error!("Error during processing {}: {}", query, e.message);
+        results.error(ErrorKind::ER_INTERNAL_ERROR, e.message.as_bytes())?;
+        return Ok(());
+    }
+    let data_frame = res.unwrap();
+    let columns = data_frame
+        .get_columns()
+        .iter()
+        .map(|c| Column {
+            table: "result".to_string(), // TODO
+            column: c.get_name().to_string(),
+            coltype: match c.get_column_type() {
+                metastore::ColumnType::String => ColumnType::MYSQL_TYPE_STRING,
+                metastore::ColumnType::Timestamp => ColumnType::MYSQL_TYPE_STRING,
+                metastore::ColumnType::Int => ColumnType::MYSQL_TYPE_LONGLONG,
+                metastore::ColumnType::Decimal { .. } => ColumnType::MYSQL_TYPE_DECIMAL,
+                metastore::ColumnType::Boolean => ColumnType::MYSQL_TYPE_STRING,
+                x => panic!("Unsupported type in MySQL adapter: {:?}", x),
+            },
+            colflags: ColumnFlags::empty(),
+        })
+        .collect::<Vec<_>>();
+
+    let mut rw = results.start(&columns)?;
+    for row in data_frame.get_rows().iter() {
+        for value in row.values().iter() {
+            match value {
+                TableValue::String(s) => rw.write_col(s)?,
+                TableValue::Timestamp(s) => rw.write_col(s.to_string())?,
+                TableValue::Int(i) => rw.write_col(i)?,
+                TableValue::Decimal(v) => rw.write_col(v.to_string())?,
+                TableValue::Boolean(v) => rw.write_col(v.to_string())?,
+                TableValue::Null => rw.write_col(Option::<String>::None)?,
+                x => panic!("Table value is not supported for MySQL: {:?}", x),
+            }
         }
-        let data_frame = res.unwrap();
-        let columns = data_frame
-            .get_columns()
-            .iter()
-            .map(|c| Column {
-                table: "result".to_string(), // TODO
-                column: c.get_name().to_string(),
-                coltype: match c.get_column_type() {
-                    metastore::ColumnType::String => ColumnType::MYSQL_TYPE_STRING,
-                    metastore::ColumnType::Timestamp => ColumnType::MYSQL_TYPE_STRING,
-                    metastore::ColumnType::Int => ColumnType::MYSQL_TYPE_LONGLONG,
-                    metastore::ColumnType::Decimal { .. } => ColumnType::MYSQL_TYPE_DECIMAL,
-                    metastore::ColumnType::Boolean => ColumnType::MYSQL_TYPE_STRING,
-                    x => panic!("Unsupported type in MySQL adapter: {:?}", x),
-                },
-                colflags: ColumnFlags::empty(),
-            })
-            .collect::<Vec<_>>();
+        rw.end_row()?;
+    }
+    rw.finish()?;
+    if start.elapsed().unwrap().as_millis() > 200 && query.to_lowercase().starts_with("select") {
+        warn!(
+            "Slow Query SQL ({:?}):\n{}",
+            start.elapsed().unwrap(),
+            query
+        );
+    }
+    Ok(())
+}
+
+/// Answers `SELECT @@<variable>, ...` queries clients send on connect. Variables other than
+/// limits of the connection are `NULL`.
+fn system_variables(query: &str) -> Option<DataFrame> {
+    let query = query.trim().trim_end_matches(';').to_lowercase();
+    let names = query
+        .strip_prefix("select ")?
+        .split(',')
+        .map(|v| v.trim().strip_prefix("@@"))
+        .collect::<Option<Vec<_>>>()?;
+    let mut columns = Vec::new();
+    let mut values = Vec::new();
+    for (i, name) in names.into_iter().enumerate() {
+        let value = match name.trim_start_matches("session.") {
+            "max_allowed_packet" => TableValue::Int(64 * 1024 * 1024),
+            "wait_timeout" => TableValue::Int(28800),
+            _ => TableValue::Null,
+        };
+        let column_type = match value {
+            TableValue::Int(_) => metastore::ColumnType::Int,
+            _ => metastore::ColumnType::String,
+        };
+        columns.push(metastore::Column::new(
+            format!("@@{}", name),
+            column_type,
+            i,
+        ));
+        values.push(value);
+    }
+    Some(DataFrame::new(columns, vec![Row::new(values)]))
+}
 
-        let mut rw = results.start(&columns)?;
-        for row in data_frame.get_rows().iter() {
-            for value in row.values().iter() {
-                match value {
-                    TableValue::String(s) => rw.write_col(s)?,
-                    TableValue::Timestamp(s) => rw.write_col(s.to_string())?,
-                    TableValue::Int(i) => rw.write_col(i)?,
-                    TableValue::Decimal(v) => rw.write_col(v.to_string())?,
-                    TableValue::Boolean(v) => rw.write_col(v.to_string())?,
-                    TableValue::Null => rw.write_col(Option::<String>::None)?,
-                    x => panic!("Table value is not supported for MySQL: {:?}", x),
+/// Handles `SET best_effort = 1|0` of the connection. Other variables are left to the SQL service.
+fn set_query_option(query: &str, options: &mut QueryOptions) -> Option<Result<(), CubeError>> {
+    let query = query.trim().trim_end_matches(';').to_lowercase();
+    let mut assignment = query.strip_prefix("set ")?.splitn(2, '=');
+    let name = assignment.next()?.trim();
+    let value = assignment.next()?.trim();
+    if name != "best_effort" {
+        return None;
+    }
+    options.best_effort = match value {
+        "1" | "true" | "on" => true,
+        "0" | "false" | "off" => false,
+        _ => {
+            return Some(Err(CubeError::user(format!(
+                "best_effort can be set to 1 or 0 but {} was given",
+                value
+            ))))
+        }
+    };
+    Some(Ok(()))
+}
+
+/// Value a parameter of `COM_STMT_EXECUTE` is bound as.
+fn param_value(param: ParamValue) -> Result<TableValue, CubeError> {
+    let coltype = param.coltype;
+    Ok(match param.value.into_inner() {
+        ValueInner::NULL => TableValue::Null,
+        ValueInner::Int(v) => TableValue::Int(v),
+        ValueInner::UInt(v) => match i64::try_from(v) {
+            Ok(v) => TableValue::Int(v),
+            Err(_) => TableValue::Decimal(v.to_string()),
+        },
+        // Floats are widened to doubles by the protocol parser, so they're printed as floats to
+        // bind 0.1 rather than 0.10000000149011612
+        ValueInner::Double(v) if v.is_finite() => match coltype {
+            ColumnType::MYSQL_TYPE_FLOAT => TableValue::Decimal((v as f32).to_string()),
+            _ => TableValue::Decimal(v.to_string()),
+        },
+        ValueInner::Bytes(v) => {
+            let v = String::from_utf8(v.to_vec())
+                .map_err(|e| CubeError::user(format!("Can't bind binary parameter: {}", e)))?;
+            match coltype {
+                ColumnType::MYSQL_TYPE_DECIMAL | ColumnType::MYSQL_TYPE_NEWDECIMAL => {
+                    TableValue::Decimal(v)
                 }
+                _ => TableValue::String(v),
             }
-            rw.end_row()?;
         }
-        rw.finish()?;
-        if start.elapsed().unwrap().as_millis() > 200 && query.to_lowercase().starts_with("select")
-        {
-            warn!(
-                "Slow Query SQL ({:?}):\n{}",
-                start.elapsed().unwrap(),
-                query
-            );
+        ValueInner::Date(v) | ValueInner::Datetime(v) => TableValue::Timestamp(timestamp(v)?),
+        v => {
+            return Err(CubeError::user(format!(
+                "Can't bind {:?} parameter of type {:?}",
+                v, coltype
+            )))
         }
-        Ok(())
-    }
+    })
+}
+
+/// Decodes a date or a datetime of the binary protocol: year, month, day, then optionally hour,
+/// minute, second and microseconds.
+fn timestamp(v: &[u8]) -> Result<TimestampValue, CubeError> {
+    let field = |i: usize| v.get(i).cloned().unwrap_or(0) as u32;
+    let year = if v.len() >= 2 {
+        u16::from_le_bytes([v[0], v[1]]) as i32
+    } else {
+        0
+    };
+    let micros = if v.len() >= 11 {
+        u32::from_le_bytes([v[7], v[8], v[9], v[10]])
+    } else {
+        0
+    };
+    let datetime = NaiveDate::from_ymd_opt(year, field(2), field(3))
+        .and_then(|d| d.and_hms_micro_opt(field(4), field(5), field(6), micros))
+        .ok_or_else(|| CubeError::user(format!("Can't bind invalid datetime {:?}", v)))?;
+    Ok(TimestampValue::new(datetime.timestamp_nanos()))
 }
 
 pub struct MySqlServer;
@@ -105,27 +369,157 @@ impl MySqlServer {
     pub async fn listen(
         address: String,
         sql_service: Arc<dyn SqlService>,
+        max_prepared_statements: usize,
     ) -> Result<(), CubeError> {
-        let mut listener = TcpListener::bind(address.clone()).await?;
+        let listener = TcpListener::bind(address.clone()).await?;
 
         info!("MySQL port open on {}", address);
 
+        MySqlServer::serve(listener, sql_service, max_prepared_statements).await
+    }
+
+    pub async fn serve(
+        mut listener: TcpListener,
+        sql_service: Arc<dyn SqlService>,
+        max_prepared_statements: usize,
+    ) -> Result<(), CubeError> {
+        let mut next_connection_id = 0u64;
         loop {
             let (socket, _) = listener.accept().await?;
+            next_connection_id += 1;
+            let connection_id = format!("mysql-{}", next_connection_id);
+            let socket = SharedSocket(Arc::new(Mutex::new(socket)));
 
             let sql_service_clone = sql_service.clone();
             tokio::spawn(async move {
-                if let Err(e) = AsyncMysqlIntermediary::run_on(
-                    Backend {
-                        sql_service: sql_service_clone,
+                let backend = Backend {
+                    sql_service: sql_service_clone.clone(),
+                    statements: HashMap::new(),
+                    next_statement_id: 0,
+                    max_prepared_statements,
+                    options: QueryOptions {
+                        connection_id: Some(connection_id.clone()),
+                        ..QueryOptions::default()
                     },
-                    socket,
-                )
-                .await
-                {
+                    connection_id: connection_id.clone(),
+                    socket: socket.clone(),
+                };
+                if let Err(e) = AsyncMysqlIntermediary::run_on(backend, socket).await {
                     error!("Error during processing MySQL connection: {}", e);
                 }
+                // Queries still running have nobody to return results to
+                sql_service_clone.cancel_connection(&connection_id);
             });
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use mysql::prelude::Queryable;
+    use mysql::{Conn, OptsBuilder, Value};
+
+    type Order = (i64, Option<String>, String);
+
+    #[test]
+    fn best_effort_is_set_per_connection() {
+        let mut options = QueryOptions::default();
+        assert!(set_query_option("SET best_effort = 1;", &mut options)
+            .unwrap()
+            .is_ok());
+        assert!(options.best_effort);
+        assert!(set_query_option("set BEST_EFFORT=off", &mut options)
+            .unwrap()
+            .is_ok());
+        assert!(!options.best_effort);
+        assert!(set_query_option("SET best_effort = maybe", &mut options)
+            .unwrap()
+            .is_err());
+        assert!(set_query_option("SET names = utf8", &mut options).is_none());
+        assert!(set_query_option("SELECT 1", &mut options).is_none());
+    }
+
+    #[tokio::test]
+    async fn prepared_statements() {
+        Config::run_test("mysql_prepared_statements", async move |services| {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(MySqlServer::serve(listener, services.sql_service, 2));
+            tokio::task::spawn_blocking(move || run_prepared_statements(port))
+                .await
+                .unwrap();
+        })
+        .await;
+    }
+
+    fn run_prepared_statements(port: u16) {
+        let opts = OptsBuilder::new()
+            .ip_or_hostname(Some("127.0.0.1"))
+            .tcp_port(port)
+            .prefer_socket(false);
+        let mut conn = Conn::new(opts).unwrap();
+        conn.query_drop("CREATE SCHEMA foo").unwrap();
+        conn.query_drop(
+            "CREATE TABLE foo.orders (id int, city text, amount decimal(18, 10), t timestamp)",
+        )
+        .unwrap();
+
+        let insert = conn
+            .prep("INSERT INTO foo.orders (id, city, amount, t) VALUES (?, ?, ?, ?)")
+            .unwrap();
+        let t = Value::Date(2021, 1, 1, 0, 0, 0, 0);
+        conn.exec_drop(&insert, (1i64, "Kyiv", 10.5f64, t)).unwrap();
+        let t = Value::Date(2021, 1, 2, 12, 30, 0, 0);
+        conn.exec_drop(&insert, (2u64, "O'Brien", "20.25", t))
+            .unwrap();
+        // Floats are bound as written rather than widened to doubles
+        let t = Value::Date(2021, 1, 3, 0, 0, 0, 500_000);
+        conn.exec_drop(&insert, (3i64, Value::NULL, 0.1f32, t))
+            .unwrap();
+        conn.close(insert).unwrap();
+
+        let select = conn
+            .prep(
+                "SELECT id, city, amount FROM foo.orders \
+                 WHERE id >= ? AND t >= ? ORDER BY id",
+            )
+            .unwrap();
+        let t = Value::Date(2021, 1, 2, 0, 0, 0, 0);
+        let rows: Vec<Order> = conn.exec(&select, (1i64, t)).unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (2, Some("O'Brien".to_string()), "20.25".to_string()),
+                (3, None, "0.1".to_string())
+            ]
+        );
+        let t = Value::Date(2021, 1, 3, 0, 0, 0, 500_000);
+        let rows: Vec<Order> = conn.exec(&select, (3i64, t)).unwrap();
+        assert_eq!(rows, vec![(3, None, "0.1".to_string())]);
+        let rows: Vec<Order> = conn.exec(&select, (Value::NULL, Value::NULL)).unwrap();
+        assert_eq!(rows, vec![]);
+
+        // The limit of open statements is per connection
+        conn.prep("SELECT count(*) FROM foo.orders WHERE city = ?")
+            .unwrap();
+        let count = "SELECT count(*) FROM foo.orders WHERE id = ?";
+        let error = conn.prep(count).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Can't prepare more than 2 statements"),
+            "{}",
+            error
+        );
+        conn.close(select).unwrap();
+        let count = conn.prep(count).unwrap();
+        let rows: Vec<i64> = conn.exec(&count, (2i64,)).unwrap();
+        assert_eq!(rows, vec![1]);
+        conn.close(count).unwrap();
+
+        let error = conn.prep("SELECT FROM foo.orders WHERE ?").unwrap_err();
+        assert!(error.to_string().contains("Expected"), "{}", error);
+    }
+}
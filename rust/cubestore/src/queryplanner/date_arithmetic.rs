@@ -0,0 +1,216 @@
+use crate::queryplanner::sql_rewrite::function_call;
+use crate::queryplanner::udfs::CubeScalarUDFKind;
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use datafusion::error::DataFusionError;
+use sqlparser::ast::{BinaryOperator, DateTimeField, Expr, Value};
+use std::convert::TryFrom;
+
+/// DataFusion can't add intervals to timestamps so `<expr> +/- INTERVAL '...'` is rewritten
+/// into a `date_add`/`date_sub` call. Intervals are passed as strings so calls can be
+/// serialized and executed on workers like any other scalar function.
+pub fn date_arithmetic_call(left: &Expr, op: &BinaryOperator, right: &Expr) -> Option<Expr> {
+    let (kind, date, interval) = match (op, interval_literal(left), interval_literal(right)) {
+        (BinaryOperator::Plus, None, Some(interval)) => {
+            (CubeScalarUDFKind::DateAdd, left, interval)
+        }
+        (BinaryOperator::Plus, Some(interval), None) => {
+            (CubeScalarUDFKind::DateAdd, right, interval)
+        }
+        (BinaryOperator::Minus, None, Some(interval)) => {
+            (CubeScalarUDFKind::DateSub, left, interval)
+        }
+        _ => return None,
+    };
+    Some(function_call(
+        kind,
+        vec![
+            date.clone(),
+            Expr::Value(Value::SingleQuotedString(interval)),
+        ],
+    ))
+}
+
+/// `EXTRACT(part FROM expr)` rewritten into `date_part('PART', expr)`.
+pub fn extract_call(field: &DateTimeField, expr: &Expr) -> Expr {
+    function_call(
+        CubeScalarUDFKind::DatePart,
+        vec![
+            Expr::Value(Value::SingleQuotedString(field.to_string())),
+            expr.clone(),
+        ],
+    )
+}
+
+fn interval_literal(expr: &Expr) -> Option<String> {
+    match expr {
+        Expr::Value(Value::Interval {
+            value,
+            leading_field,
+            last_field: None,
+            ..
+        }) => Some(match leading_field {
+            Some(field) => format!("{} {}", value, field),
+            None => value.to_string(),
+        }),
+        Expr::Nested(e) => interval_literal(e),
+        _ => None,
+    }
+}
+
+/// Interval split into a calendar part and a fixed length part as months have no fixed length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Interval {
+    pub months: i64,
+    pub millis: i64,
+}
+
+impl Interval {
+    pub fn negate(&self) -> Interval {
+        Interval {
+            months: -self.months,
+            millis: -self.millis,
+        }
+    }
+}
+
+/// Parses intervals like `7 days`, `1 year 2 months` or `-3 hours`.
+pub fn parse_interval(interval: &str) -> Result<Interval, DataFusionError> {
+    let invalid = || DataFusionError::Execution(format!("Invalid interval: '{}'", interval));
+    let parts = interval.split_whitespace().collect::<Vec<_>>();
+    if parts.is_empty() || parts.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    let mut result = Interval {
+        months: 0,
+        millis: 0,
+    };
+    for pair in parts.chunks(2) {
+        let value = pair[0].parse::<i64>().map_err(|_| invalid())?;
+        let unit = pair[1].to_lowercase();
+        let (months, millis) = match unit.trim_end_matches('s') {
+            "year" => (12, 0),
+            "month" | "mon" => (1, 0),
+            "week" => (0, 7 * 86_400_000),
+            "day" => (0, 86_400_000),
+            "hour" => (0, 3_600_000),
+            "minute" | "min" => (0, 60_000),
+            "second" | "sec" => (0, 1_000),
+            "millisecond" => (0, 1),
+            _ => return Err(invalid()),
+        };
+        result.months = value
+            .checked_mul(months)
+            .and_then(|m| result.months.checked_add(m))
+            .ok_or_else(invalid)?;
+        result.millis = value
+            .checked_mul(millis)
+            .and_then(|m| result.millis.checked_add(m))
+            .ok_or_else(invalid)?;
+    }
+    Ok(result)
+}
+
+/// Formats an interval the way `parse_interval` reads it, e.g. `1 year 2 months 3 hours`.
+pub fn format_interval(months: i64, days: i64, millis: i64) -> String {
+    let millis = days * 86_400_000 + millis;
+    let parts = vec![
+        (months / 12, "year"),
+        (months % 12, "month"),
+        (millis / 86_400_000, "day"),
+        (millis % 86_400_000 / 3_600_000, "hour"),
+        (millis % 3_600_000 / 60_000, "minute"),
+        (millis % 60_000 / 1_000, "second"),
+        (millis % 1_000, "millisecond"),
+    ];
+    let formatted = parts
+        .into_iter()
+        .filter(|(value, _)| *value != 0)
+        .map(|(value, unit)| {
+            format!(
+                "{} {}{}",
+                value,
+                unit,
+                if value.abs() == 1 { "" } else { "s" }
+            )
+        })
+        .collect::<Vec<_>>();
+    if formatted.is_empty() {
+        "0 days".to_string()
+    } else {
+        formatted.join(" ")
+    }
+}
+
+/// Adds months clamping the day to the length of the resulting month, so Jan 31 + 1 month
+/// gives the last day of February.
+pub fn add_months(date_time: NaiveDateTime, months: i64) -> Option<NaiveDateTime> {
+    if months == 0 {
+        return Some(date_time);
+    }
+    let total_months = date_time.year() as i64 * 12 + date_time.month0() as i64 + months;
+    let year = i32::try_from(total_months.div_euclid(12)).ok()?;
+    let month = total_months.rem_euclid(12) as u32 + 1;
+    let day = date_time.day().min(days_in_month(year, month)?);
+    Some(NaiveDate::from_ymd_opt(year, month, day)?.and_time(date_time.time()))
+}
+
+fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let (next_year, next_month) = if month == 12 {
+        (year.checked_add(1)?, 1)
+    } else {
+        (year, month + 1)
+    };
+    Some(
+        NaiveDate::from_ymd_opt(next_year, next_month, 1)?
+            .pred_opt()?
+            .day(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_intervals() {
+        assert_eq!(
+            parse_interval("7 days").unwrap(),
+            Interval {
+                months: 0,
+                millis: 7 * 86_400_000
+            }
+        );
+        assert_eq!(
+            parse_interval("1 YEAR 2 mons -30 minutes").unwrap(),
+            Interval {
+                months: 14,
+                millis: -30 * 60_000
+            }
+        );
+        assert!(parse_interval("7").is_err());
+        assert!(parse_interval("7 fortnights").is_err());
+    }
+
+    #[test]
+    fn formats_intervals() {
+        assert_eq!(format_interval(14, 0, 0), "1 year 2 months");
+        assert_eq!(format_interval(0, 7, 3_600_000), "7 days 1 hour");
+        assert_eq!(format_interval(0, 0, 0), "0 days");
+        let formatted = format_interval(0, 1, 90_061);
+        let parsed = parse_interval(&formatted).unwrap();
+        assert_eq!(parsed.millis, 86_400_000 + 90_061, "{}", formatted);
+    }
+
+    #[test]
+    fn month_arithmetic_clamps_day() {
+        let date_time = NaiveDate::from_ymd(2020, 1, 31).and_hms(10, 0, 0);
+        assert_eq!(
+            add_months(date_time, 1).unwrap(),
+            NaiveDate::from_ymd(2020, 2, 29).and_hms(10, 0, 0)
+        );
+        assert_eq!(
+            add_months(date_time, -2).unwrap(),
+            NaiveDate::from_ymd(2019, 11, 30).and_hms(10, 0, 0)
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use crate::CubeError;
+use std::fmt::Write;
+
+const PRECISION: u32 = 14;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// HyperLogLog sketch estimating the number of distinct 64-bit hashes added to it. 2^14
+/// registers give a standard error of about 0.8%. Sketches built over different parts of the
+/// data are merged into the sketch of the whole data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        HyperLogLog {
+            registers: vec![0; REGISTERS],
+        }
+    }
+
+    pub fn add_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // Sentinel bit caps the rank when all remaining bits are zeros
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        if self.registers[index] < rank {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if *register < *other {
+                *register = *other;
+            }
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|r| 2f64.powi(-(*r as i32)))
+            .sum::<f64>();
+        let estimate = alpha * m * m / sum;
+        let zeros = self.registers.iter().filter(|r| **r == 0).count();
+        // Linear counting is more precise for small cardinalities
+        if estimate <= 2.5 * m && zeros > 0 {
+            (m * (m / zeros as f64).ln()).round() as u64
+        } else {
+            estimate.round() as u64
+        }
+    }
+
+    /// Hex string with `index:rank` pairs of set registers, prefixed with `s`, or all registers,
+    /// prefixed with `d`, whichever is shorter.
+    pub fn to_hex(&self) -> String {
+        let set = self.registers.iter().filter(|r| **r != 0).count();
+        let mut result = String::new();
+        if set * 6 < REGISTERS * 2 {
+            result.reserve(1 + set * 6);
+            result.push('s');
+            for (index, rank) in self.registers.iter().enumerate() {
+                if *rank != 0 {
+                    write!(result, "{:04x}{:02x}", index, rank).unwrap();
+                }
+            }
+        } else {
+            result.reserve(1 + REGISTERS * 2);
+            result.push('d');
+            for rank in self.registers.iter() {
+                write!(result, "{:02x}", rank).unwrap();
+            }
+        }
+        result
+    }
+
+    pub fn from_hex(hex: &str) -> Result<HyperLogLog, CubeError> {
+        let invalid = || CubeError::internal(format!("Invalid HyperLogLog sketch: {}", hex));
+        let parse = |s: &str| usize::from_str_radix(s, 16).map_err(|_| invalid());
+        let mut sketch = HyperLogLog::new();
+        match hex.get(..1) {
+            Some("s") if (hex.len() - 1) % 6 == 0 => {
+                for i in (1..hex.len()).step_by(6) {
+                    let index = parse(hex.get(i..i + 4).ok_or_else(invalid)?)?;
+                    let rank = parse(hex.get(i + 4..i + 6).ok_or_else(invalid)?)?;
+                    *sketch.registers.get_mut(index).ok_or_else(invalid)? = rank as u8;
+                }
+            }
+            Some("d") if hex.len() == 1 + REGISTERS * 2 => {
+                for (index, register) in sketch.registers.iter_mut().enumerate() {
+                    let i = 1 + index * 2;
+                    *register = parse(hex.get(i..i + 2).ok_or_else(invalid)?)? as u8;
+                }
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(sketch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+
+    fn hash(value: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_u64(value);
+        hasher.finish()
+    }
+
+    fn sketch(values: impl Iterator<Item = u64>) -> HyperLogLog {
+        let mut sketch = HyperLogLog::new();
+        for v in values {
+            sketch.add_hash(hash(v));
+        }
+        sketch
+    }
+
+    #[test]
+    fn estimates_within_two_percent() {
+        for n in [1_000u64, 20_000, 500_000].iter() {
+            let count = sketch(0..*n).count() as f64;
+            let error = (count - *n as f64).abs() / *n as f64;
+            assert!(error < 0.02, "{} estimated as {}", n, count);
+        }
+        assert_eq!(HyperLogLog::new().count(), 0);
+    }
+
+    #[test]
+    fn merged_sketches_match_whole() {
+        let whole = sketch((0..50_000).map(|v| v % 30_000));
+        let mut merged = HyperLogLog::new();
+        for part in 0..8 {
+            merged.merge(&sketch(
+                (0..50_000).filter(|v| v % 8 == part).map(|v| v % 30_000),
+            ));
+        }
+        assert_eq!(merged, whole);
+    }
+
+    #[test]
+    fn hex_round_trip() {
+        for sketch in vec![HyperLogLog::new(), sketch(0..10), sketch(0..100_000)] {
+            let hex = sketch.to_hex();
+            assert_eq!(HyperLogLog::from_hex(&hex).unwrap(), sketch);
+        }
+        assert!(sketch(0..10).to_hex().starts_with('s'));
+        assert!(sketch(0..100_000).to_hex().starts_with('d'));
+        assert!(HyperLogLog::from_hex("s12").is_err());
+        assert!(HyperLogLog::from_hex("sffff01").is_err());
+        assert!(HyperLogLog::from_hex("x").is_err());
+    }
+}
@@ -1,18 +1,41 @@
+pub mod date_arithmetic;
+pub mod hll;
+pub mod node_selector;
+pub mod pruning;
 pub mod query_executor;
+pub mod result_cache;
+pub mod rollup;
+pub mod row_group_scan;
+pub mod scan_metrics;
 pub mod serialized_plan;
-
+pub mod split_point;
+pub mod sql_rewrite;
+pub mod tombstones;
+pub mod udfs;
+pub mod unique_key;
+pub mod warm_up;
+pub mod window;
+pub mod wire_format;
+
+use crate::config::ConfigObj;
+use crate::import::s3::{S3ImportProgress, S3ImportState};
 use crate::metastore::table::TablePath;
 use crate::metastore::{MetaStore, MetaStoreTable};
 use crate::queryplanner::query_executor::batch_to_dataframe;
 use crate::queryplanner::serialized_plan::SerializedPlan;
+use crate::queryplanner::sql_rewrite::{rewrite_statement, RewriteOptions};
+use crate::queryplanner::udfs::{CubeAggregateUDFKind, CubeScalarUDFKind};
+use crate::remotefs::queue::DownloadQueue;
+use crate::remotefs::RemoteFs;
+use crate::scheduler::orphan_files;
 use crate::store::DataFrame;
 use crate::CubeError;
-use arrow::array::StringArray;
+use arrow::array::{Int64Array, StringArray};
 use arrow::datatypes::Field;
 use arrow::{array::Array, datatypes::Schema, datatypes::SchemaRef};
 use arrow::{datatypes::DataType, record_batch::RecordBatch};
 use async_trait::async_trait;
-use datafusion::datasource::datasource::Statistics;
+use datafusion::datasource::datasource::{Statistics, TableProviderFilterPushDown};
 use datafusion::error::DataFusionError;
 use datafusion::logical_plan::{Expr, LogicalPlan};
 use datafusion::physical_plan::udaf::AggregateUDF;
@@ -25,7 +48,7 @@ use log::{debug, trace};
 use mockall::automock;
 use serde_derive::{Deserialize, Serialize};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::runtime::Handle;
@@ -39,6 +62,10 @@ pub trait QueryPlanner: Send + Sync {
 
 pub struct QueryPlannerImpl {
     meta_store: Arc<dyn MetaStore>,
+    remote_fs: Arc<dyn RemoteFs>,
+    s3_import_progress: Arc<S3ImportProgress>,
+    download_queue: Arc<DownloadQueue>,
+    rewrite_options: RewriteOptions,
 }
 
 pub enum QueryPlan {
@@ -49,6 +76,10 @@ pub enum QueryPlan {
 #[async_trait]
 impl QueryPlanner for QueryPlannerImpl {
     async fn logical_plan(&self, statement: Statement) -> Result<QueryPlan, CubeError> {
+        let mut statement = statement;
+        if let Statement::Statement(sql_statement) = &mut statement {
+            rewrite_statement(sql_statement, self.rewrite_options);
+        }
         let ctx = self.execution_context().await?;
 
         let schema_provider = MetaStoreSchemaProvider::new(
@@ -93,8 +124,45 @@ impl QueryPlanner for QueryPlannerImpl {
 }
 
 impl QueryPlannerImpl {
-    pub fn new(meta_store: Arc<dyn MetaStore>) -> Arc<QueryPlannerImpl> {
-        Arc::new(QueryPlannerImpl { meta_store })
+    pub fn new(
+        meta_store: Arc<dyn MetaStore>,
+        remote_fs: Arc<dyn RemoteFs>,
+        config: Arc<dyn ConfigObj>,
+        s3_import_progress: Arc<S3ImportProgress>,
+    ) -> Arc<QueryPlannerImpl> {
+        let download_queue = DownloadQueue::new(
+            remote_fs.clone(),
+            config.download_concurrency(),
+            config.download_bandwidth_limit(),
+        );
+        Self::with_download_queue(
+            meta_store,
+            remote_fs,
+            config,
+            s3_import_progress,
+            download_queue,
+        )
+    }
+
+    /// `system.download_queue` shows downloads of `download_queue`, the one of this node's
+    /// cluster.
+    pub fn with_download_queue(
+        meta_store: Arc<dyn MetaStore>,
+        remote_fs: Arc<dyn RemoteFs>,
+        config: Arc<dyn ConfigObj>,
+        s3_import_progress: Arc<S3ImportProgress>,
+        download_queue: Arc<DownloadQueue>,
+    ) -> Arc<QueryPlannerImpl> {
+        Arc::new(QueryPlannerImpl {
+            meta_store,
+            remote_fs,
+            s3_import_progress,
+            download_queue,
+            rewrite_options: RewriteOptions {
+                strict_casts: config.strict_casts(),
+                count_distinct_memory_limit: config.count_distinct_memory_limit(),
+            },
+        })
     }
 }
 
@@ -106,6 +174,9 @@ impl QueryPlannerImpl {
             "information_schema.tables",
             Box::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
                 InfoSchemaTable::Tables,
             )),
         );
@@ -114,10 +185,64 @@ impl QueryPlannerImpl {
             "information_schema.schemata",
             Box::new(InfoSchemaTableProvider::new(
                 self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
                 InfoSchemaTable::Schemata,
             )),
         );
 
+        ctx.register_table(
+            "system.orphan_files",
+            Box::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
+                InfoSchemaTable::OrphanFiles,
+            )),
+        );
+
+        ctx.register_table(
+            "system.compaction_backlog",
+            Box::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
+                InfoSchemaTable::CompactionBacklog,
+            )),
+        );
+
+        ctx.register_table(
+            "system.download_queue",
+            Box::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
+                InfoSchemaTable::DownloadQueue,
+            )),
+        );
+
+        ctx.register_table(
+            "system.s3_imports",
+            Box::new(InfoSchemaTableProvider::new(
+                self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
+                InfoSchemaTable::S3Imports,
+            )),
+        );
+
+        for kind in CubeScalarUDFKind::all() {
+            ctx.register_udf(kind.udf());
+        }
+        for kind in CubeAggregateUDFKind::all() {
+            ctx.register_udaf(kind.udaf());
+        }
+
         Ok(Arc::new(ctx))
     }
 }
@@ -166,18 +291,35 @@ impl ContextProvider for MetaStoreSchemaProvider {
         })
     }
 
-    fn get_function_meta(&self, _name: &str) -> Option<Arc<ScalarUDF>> {
-        None
+    fn get_function_meta(&self, name: &str) -> Option<Arc<ScalarUDF>> {
+        self.information_schema_context
+            .state
+            .lock()
+            .unwrap()
+            .get_function_meta(name)
     }
 
-    fn get_aggregate_meta(&self, _name: &str) -> Option<Arc<AggregateUDF>> {
-        None
+    fn get_aggregate_meta(&self, name: &str) -> Option<Arc<AggregateUDF>> {
+        self.information_schema_context
+            .state
+            .lock()
+            .unwrap()
+            .get_aggregate_meta(name)
     }
 }
 
 pub enum InfoSchemaTable {
     Tables,
     Schemata,
+    /// Data files in the remote storage which no partition, chunk or WAL refers to, e.g. files
+    /// of dropped tables during the deletion grace period.
+    OrphanFiles,
+    /// Progress of imports from S3 locations made by this node.
+    S3Imports,
+    /// Chunks of active partitions waiting to be compacted, by compaction level.
+    CompactionBacklog,
+    /// Files queued or being downloaded for selects run by this node.
+    DownloadQueue,
 }
 
 impl InfoSchemaTable {
@@ -192,10 +334,41 @@ impl InfoSchemaTable {
                 DataType::Utf8,
                 false,
             )])),
+            InfoSchemaTable::OrphanFiles => Arc::new(Schema::new(vec![
+                Field::new("file_name", DataType::Utf8, false),
+                Field::new("updated", DataType::Utf8, false),
+            ])),
+            InfoSchemaTable::S3Imports => Arc::new(Schema::new(vec![
+                Field::new("table_id", DataType::Int64, false),
+                Field::new("location", DataType::Utf8, false),
+                Field::new("objects_done", DataType::Int64, false),
+                Field::new("objects_total", DataType::Int64, false),
+                Field::new("bytes_done", DataType::Int64, false),
+                Field::new("bytes_total", DataType::Int64, false),
+                Field::new("retries", DataType::Int64, false),
+                Field::new("status", DataType::Utf8, false),
+            ])),
+            InfoSchemaTable::CompactionBacklog => Arc::new(Schema::new(vec![
+                Field::new("partition_id", DataType::Int64, false),
+                Field::new("small_chunks", DataType::Int64, false),
+                Field::new("medium_chunks", DataType::Int64, false),
+                Field::new("chunk_rows", DataType::Int64, false),
+            ])),
+            InfoSchemaTable::DownloadQueue => Arc::new(Schema::new(vec![Field::new(
+                "queue_depth",
+                DataType::Int64,
+                false,
+            )])),
         }
     }
 
-    async fn scan(&self, meta_store: Arc<dyn MetaStore>) -> Result<RecordBatch, CubeError> {
+    async fn scan(
+        &self,
+        meta_store: Arc<dyn MetaStore>,
+        remote_fs: Arc<dyn RemoteFs>,
+        s3_import_progress: Arc<S3ImportProgress>,
+        download_queue: Arc<DownloadQueue>,
+    ) -> Result<RecordBatch, CubeError> {
         match self {
             InfoSchemaTable::Tables => {
                 let tables = meta_store.get_tables_with_path().await?;
@@ -227,22 +400,128 @@ impl InfoSchemaTable {
                 ))];
                 Ok(RecordBatch::try_new(schema, columns)?)
             }
+            InfoSchemaTable::OrphanFiles => {
+                let files = orphan_files(meta_store.as_ref(), remote_fs.as_ref()).await?;
+                let schema = self.schema();
+                let columns: Vec<Arc<dyn Array>> = vec![
+                    Arc::new(StringArray::from(
+                        files.iter().map(|f| f.remote_path()).collect::<Vec<_>>(),
+                    )),
+                    Arc::new(StringArray::from(
+                        files
+                            .iter()
+                            .map(|f| f.updated().to_rfc3339())
+                            .collect::<Vec<_>>(),
+                    )),
+                ];
+                Ok(RecordBatch::try_new(schema, columns)?)
+            }
+            InfoSchemaTable::S3Imports => {
+                let imports = s3_import_progress.all();
+                let int_column = |f: fn(&S3ImportState) -> u64| -> Arc<dyn Array> {
+                    Arc::new(Int64Array::from(
+                        imports.iter().map(|i| f(i) as i64).collect::<Vec<_>>(),
+                    ))
+                };
+                let columns: Vec<Arc<dyn Array>> = vec![
+                    int_column(|i| i.table_id),
+                    Arc::new(StringArray::from(
+                        imports
+                            .iter()
+                            .map(|i| i.location.as_str())
+                            .collect::<Vec<_>>(),
+                    )),
+                    int_column(|i| i.objects_done),
+                    int_column(|i| i.objects_total),
+                    int_column(|i| i.bytes_done),
+                    int_column(|i| i.bytes_total),
+                    int_column(|i| i.retries),
+                    Arc::new(StringArray::from(
+                        imports
+                            .iter()
+                            .map(|i| i.status.as_str())
+                            .collect::<Vec<_>>(),
+                    )),
+                ];
+                Ok(RecordBatch::try_new(self.schema(), columns)?)
+            }
+            InfoSchemaTable::CompactionBacklog => {
+                // Partition id to small chunks, medium chunks and rows of chunks
+                let mut backlog = BTreeMap::<u64, (u64, u64, u64)>::new();
+                for chunk in meta_store.chunks_table().all_rows().await? {
+                    let chunk = chunk.get_row();
+                    if !chunk.active() || !chunk.uploaded() {
+                        continue;
+                    }
+                    let entry = backlog.entry(chunk.get_partition_id()).or_default();
+                    if chunk.get_level() == 0 {
+                        entry.0 += 1;
+                    } else {
+                        entry.1 += 1;
+                    }
+                    entry.2 += chunk.get_row_count();
+                }
+                let int_column = |f: fn(&(u64, (u64, u64, u64))) -> u64| -> Arc<dyn Array> {
+                    Arc::new(Int64Array::from(
+                        backlog
+                            .iter()
+                            .map(|(id, b)| f(&(*id, *b)) as i64)
+                            .collect::<Vec<_>>(),
+                    ))
+                };
+                let columns: Vec<Arc<dyn Array>> = vec![
+                    int_column(|(id, _)| *id),
+                    int_column(|(_, b)| b.0),
+                    int_column(|(_, b)| b.1),
+                    int_column(|(_, b)| b.2),
+                ];
+                Ok(RecordBatch::try_new(self.schema(), columns)?)
+            }
+            InfoSchemaTable::DownloadQueue => {
+                let columns: Vec<Arc<dyn Array>> = vec![Arc::new(Int64Array::from(vec![
+                    download_queue.queue_depth() as i64,
+                ]))];
+                Ok(RecordBatch::try_new(self.schema(), columns)?)
+            }
         }
     }
 }
 
 pub struct InfoSchemaTableProvider {
     meta_store: Arc<dyn MetaStore>,
+    remote_fs: Arc<dyn RemoteFs>,
+    s3_import_progress: Arc<S3ImportProgress>,
+    download_queue: Arc<DownloadQueue>,
     table: InfoSchemaTable,
 }
 
 impl InfoSchemaTableProvider {
-    fn new(meta_store: Arc<dyn MetaStore>, table: InfoSchemaTable) -> InfoSchemaTableProvider {
-        InfoSchemaTableProvider { meta_store, table }
+    fn new(
+        meta_store: Arc<dyn MetaStore>,
+        remote_fs: Arc<dyn RemoteFs>,
+        s3_import_progress: Arc<S3ImportProgress>,
+        download_queue: Arc<DownloadQueue>,
+        table: InfoSchemaTable,
+    ) -> InfoSchemaTableProvider {
+        InfoSchemaTableProvider {
+            meta_store,
+            remote_fs,
+            s3_import_progress,
+            download_queue,
+            table,
+        }
     }
 
     async fn mem_table(&self) -> Result<MemTable, DataFusionError> {
-        let batch = self.table.scan(self.meta_store.clone()).await?;
+        let batch = self
+            .table
+            .scan(
+                self.meta_store.clone(),
+                self.remote_fs.clone(),
+                self.s3_import_progress.clone(),
+                self.download_queue.clone(),
+            )
+            .await?;
         MemTable::try_new(batch.schema(), vec![vec![batch]])
     }
 }
@@ -300,6 +579,14 @@ impl TableProvider for CubeTableLogical {
         panic!("scan has been called on CubeTableLogical: serialized plan wasn't preprocessed for select");
     }
 
+    fn supports_filter_pushdown(
+        &self,
+        _filter: &Expr,
+    ) -> Result<TableProviderFilterPushDown, DataFusionError> {
+        // Pushed down filters reach CubeTable on workers where they are used to skip chunks
+        Ok(TableProviderFilterPushDown::Inexact)
+    }
+
     fn statistics(&self) -> Statistics {
         // TODO
         Statistics {
@@ -0,0 +1,60 @@
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Chooses a node to run a worker plan for a set of partitions.
+pub trait NodeSelector: Debug + Send + Sync {
+    /// `available_nodes` is guaranteed to be non empty.
+    fn select(&self, partition_ids: &[u64], available_nodes: &Vec<String>) -> String;
+}
+
+/// Default selector that sends each next request to the next available node.
+#[derive(Debug)]
+pub struct RoundRobinNodeSelector {
+    counter: AtomicUsize,
+}
+
+impl RoundRobinNodeSelector {
+    pub fn new() -> RoundRobinNodeSelector {
+        RoundRobinNodeSelector {
+            counter: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl NodeSelector for RoundRobinNodeSelector {
+    fn select(&self, _partition_ids: &[u64], available_nodes: &Vec<String>) -> String {
+        let index = self.counter.fetch_add(1, Ordering::Relaxed);
+        available_nodes[index % available_nodes.len()].clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn round_robin_distributes_evenly() {
+        let selector = RoundRobinNodeSelector::new();
+        let nodes = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let mut counts = HashMap::new();
+        for partition_id in 0..300 {
+            *counts
+                .entry(selector.select(&[partition_id], &nodes))
+                .or_insert(0) += 1;
+        }
+        assert_eq!(counts.len(), 3);
+        for node in nodes.iter() {
+            assert_eq!(counts[node], 100);
+        }
+    }
+
+    #[test]
+    fn round_robin_single_node() {
+        let selector = RoundRobinNodeSelector::new();
+        let nodes = vec!["a".to_string()];
+        for partition_id in 0..10 {
+            assert_eq!(selector.select(&[partition_id], &nodes), "a");
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use crate::metastore::Column;
+use crate::table::{Row, TableValue, TimestampValue};
+use arrow::datatypes::TimeUnit;
+use datafusion::logical_plan::{Expr, Operator};
+use datafusion::physical_plan::datetime_expressions::string_to_timestamp_nanos;
+use datafusion::physical_plan::functions::BuiltinScalarFunction;
+use datafusion::scalar::ScalarValue;
+use std::collections::HashMap;
+use std::mem::discriminant;
+
+/// Checks whether rows within inclusive `[min, max]` sort key bounds can satisfy all `filters`.
+/// Rows are ordered lexicographically so a column is bounded only if all sort key columns
+/// before it have equal min and max values. Anything that can't be evaluated is assumed to match.
+pub fn can_match(filters: &[Expr], sort_key_columns: &[Column], min: &Row, max: &Row) -> bool {
+    let mut bounds = HashMap::new();
+    for (i, column) in sort_key_columns.iter().enumerate() {
+        let (min_value, max_value) = match (min.values().get(i), max.values().get(i)) {
+            (Some(min_value), Some(max_value)) => (min_value, max_value),
+            _ => break,
+        };
+        bounds.insert(column.get_name().as_str(), (min_value, max_value));
+        if min_value != max_value {
+            break;
+        }
+    }
+    filters.iter().all(|f| expr_can_match(f, &bounds))
+}
+
+/// Checks whether rows can satisfy all `filters` when each of the named columns is within its
+/// own inclusive `[min, max]` bounds like in statistics of a parquet row group.
+pub fn can_match_columns(filters: &[Expr], columns: &[(&str, TableValue, TableValue)]) -> bool {
+    let bounds = columns
+        .iter()
+        .map(|(name, min, max)| (*name, (min, max)))
+        .collect();
+    filters.iter().all(|f| expr_can_match(f, &bounds))
+}
+
+/// Values `column` is restricted to by `filters` with equalities like `column = 1`, their
+/// disjunctions or `IN` lists. `None` if it isn't restricted this way.
+pub fn equality_values(filters: &[Expr], column: &str) -> Option<Vec<TableValue>> {
+    filters.iter().find_map(|f| expr_equality_values(f, column))
+}
+
+/// Checks whether rows within half-open `[min, max)` sort key bounds of a partition can have
+/// `value` in the leading sort key column. Missing bounds are unbounded.
+pub fn can_have_leading_value(min: Option<&Row>, max: Option<&Row>, value: &TableValue) -> bool {
+    // Nulls sort first and are comparable with anything
+    let comparable =
+        |v: &TableValue| v == &TableValue::Null || discriminant(v) == discriminant(value);
+    if let Some(min) = min.and_then(|min| min.values().first()) {
+        if comparable(min) && min > value {
+            return false;
+        }
+    }
+    if let Some(max) = max {
+        match max.values().first() {
+            // The smallest row with `value` has nulls in all the other columns
+            Some(first) if comparable(first) => {
+                let rest_is_null = max.values()[1..].iter().all(|v| v == &TableValue::Null);
+                if value > first || (value == first && rest_is_null) {
+                    return false;
+                }
+            }
+            _ => {}
+        }
+    }
+    true
+}
+
+fn expr_equality_values(expr: &Expr, column: &str) -> Option<Vec<TableValue>> {
+    match expr {
+        Expr::BinaryExpr {
+            left,
+            op: Operator::And,
+            right,
+        } => expr_equality_values(left, column).or_else(|| expr_equality_values(right, column)),
+        Expr::BinaryExpr {
+            left,
+            op: Operator::Or,
+            right,
+        } => {
+            let mut values = expr_equality_values(left, column)?;
+            values.extend(expr_equality_values(right, column)?);
+            Some(values)
+        }
+        Expr::BinaryExpr {
+            left,
+            op: Operator::Eq,
+            right,
+        } => match (left.as_ref(), right.as_ref()) {
+            (Expr::Column(name, _), value) | (value, Expr::Column(name, _)) if name == column => {
+                Some(vec![literal(value)?])
+            }
+            _ => None,
+        },
+        Expr::InList {
+            expr,
+            list,
+            negated: false,
+        } => match expr.as_ref() {
+            Expr::Column(name, _) if name == column => list.iter().map(literal).collect(),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+type Bounds<'a> = HashMap<&'a str, (&'a TableValue, &'a TableValue)>;
+
+fn expr_can_match(expr: &Expr, bounds: &Bounds) -> bool {
+    match expr {
+        Expr::BinaryExpr {
+            left,
+            op: Operator::And,
+            right,
+        } => expr_can_match(left, bounds) && expr_can_match(right, bounds),
+        Expr::BinaryExpr {
+            left,
+            op: Operator::Or,
+            right,
+        } => expr_can_match(left, bounds) || expr_can_match(right, bounds),
+        Expr::BinaryExpr {
+            left,
+            op: Operator::Like,
+            right,
+        } => match (column_bounds(left, bounds), literal(right)) {
+            (Some(b), Some(TableValue::String(pattern))) => {
+                prefix_can_match(b, like_prefix(&pattern))
+            }
+            _ => true,
+        },
+        Expr::BinaryExpr { left, op, right } => {
+            match (column_bounds(left, bounds), literal(right)) {
+                (Some(b), Some(value)) => return compare_can_match(b, op, &value),
+                _ => {}
+            }
+            match (literal(left), column_bounds(right, bounds)) {
+                (Some(value), Some(b)) => match flip(op) {
+                    Some(op) => compare_can_match(b, &op, &value),
+                    None => true,
+                },
+                _ => true,
+            }
+        }
+        Expr::Between {
+            expr,
+            negated: false,
+            low,
+            high,
+        } => match (column_bounds(expr, bounds), literal(low), literal(high)) {
+            (Some(b), Some(low), Some(high)) => {
+                compare_can_match(b, &Operator::GtEq, &low)
+                    && compare_can_match(b, &Operator::LtEq, &high)
+            }
+            _ => true,
+        },
+        // Values are checked in a single pass so long lists stay linear per partition
+        Expr::InList {
+            expr,
+            list,
+            negated: false,
+        } => match column_bounds(expr, bounds) {
+            Some(b) => list.iter().any(|v| match literal(v) {
+                Some(value) => compare_can_match(b, &Operator::Eq, &value),
+                None => true,
+            }),
+            None => true,
+        },
+        _ => true,
+    }
+}
+
+fn compare_can_match(
+    (min, max): (&TableValue, &TableValue),
+    op: &Operator,
+    value: &TableValue,
+) -> bool {
+    // Nulls sort first and never satisfy a comparison so a null min doesn't widen the range
+    let comparable = discriminant(max) == discriminant(value)
+        && (discriminant(min) == discriminant(value) || min == &TableValue::Null);
+    if !comparable {
+        return true;
+    }
+    match op {
+        Operator::Eq => min <= value && value <= max,
+        Operator::NotEq => !(min == max && min == value),
+        Operator::Lt => min < value,
+        Operator::LtEq => min <= value,
+        Operator::Gt => max > value,
+        Operator::GtEq => max >= value,
+        _ => true,
+    }
+}
+
+/// Literal part of a `LIKE` pattern before the first wildcard or escape.
+fn like_prefix(pattern: &str) -> &str {
+    match pattern.find(|c| c == '%' || c == '_' || c == '\\') {
+        Some(i) => &pattern[..i],
+        None => pattern,
+    }
+}
+
+/// Strings starting with `prefix` form the range `[prefix, prefix + 1)`. It intersects
+/// `[min, max]` if `prefix` itself is within bounds or `min` is inside that range.
+fn prefix_can_match((min, max): (&TableValue, &TableValue), prefix: &str) -> bool {
+    if prefix.is_empty() {
+        return true;
+    }
+    match (min, max) {
+        (TableValue::String(min), TableValue::String(max)) => {
+            max.as_str() >= prefix && (min.as_str() <= prefix || min.starts_with(prefix))
+        }
+        // Nulls sort first and never match
+        (TableValue::Null, TableValue::String(max)) => max.as_str() >= prefix,
+        _ => true,
+    }
+}
+
+/// Operator to use when operands are swapped.
+fn flip(op: &Operator) -> Option<Operator> {
+    Some(match op {
+        Operator::Eq => Operator::Eq,
+        Operator::NotEq => Operator::NotEq,
+        Operator::Lt => Operator::Gt,
+        Operator::LtEq => Operator::GtEq,
+        Operator::Gt => Operator::Lt,
+        Operator::GtEq => Operator::LtEq,
+        _ => return None,
+    })
+}
+
+fn column_bounds<'a>(expr: &Expr, bounds: &Bounds<'a>) -> Option<(&'a TableValue, &'a TableValue)> {
+    match expr {
+        Expr::Column(name, _) => bounds.get(name.as_str()).cloned(),
+        _ => None,
+    }
+}
+
+/// Literal converted to the value it's stored as. Decimals are stored as strings which don't
+/// compare numerically, so they are never used for pruning.
+fn literal(expr: &Expr) -> Option<TableValue> {
+    match expr {
+        Expr::Literal(value) => Some(match value {
+            ScalarValue::Int8(Some(v)) => TableValue::Int(*v as i64),
+            ScalarValue::Int16(Some(v)) => TableValue::Int(*v as i64),
+            ScalarValue::Int32(Some(v)) => TableValue::Int(*v as i64),
+            ScalarValue::Int64(Some(v)) => TableValue::Int(*v),
+            ScalarValue::UInt8(Some(v)) => TableValue::Int(*v as i64),
+            ScalarValue::UInt16(Some(v)) => TableValue::Int(*v as i64),
+            ScalarValue::UInt32(Some(v)) => TableValue::Int(*v as i64),
+            ScalarValue::Utf8(Some(v)) | ScalarValue::LargeUtf8(Some(v)) => {
+                TableValue::String(v.clone())
+            }
+            ScalarValue::Boolean(Some(v)) => TableValue::Boolean(*v),
+            ScalarValue::TimestampNanosecond(Some(v)) => {
+                TableValue::Timestamp(TimestampValue::new(*v))
+            }
+            ScalarValue::TimestampMicrosecond(Some(v)) => {
+                TableValue::Timestamp(TimestampValue::from_arrow(*v, &TimeUnit::Microsecond)?)
+            }
+            _ => return None,
+        }),
+        // Constant timestamps are usually written as `to_timestamp('...')`
+        Expr::ScalarFunction {
+            fun: BuiltinScalarFunction::ToTimestamp,
+            args,
+        } => match args.as_slice() {
+            [Expr::Literal(ScalarValue::Utf8(Some(v)))] => Some(TableValue::Timestamp(
+                TimestampValue::new(string_to_timestamp_nanos(v).ok()?),
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::ColumnType;
+    use datafusion::logical_plan::{col, lit};
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("city".to_string(), ColumnType::String, 0),
+            Column::new("id".to_string(), ColumnType::Int, 1),
+        ]
+    }
+
+    fn row(city: &str, id: i64) -> Row {
+        Row::new(vec![
+            TableValue::String(city.to_string()),
+            TableValue::Int(id),
+        ])
+    }
+
+    #[test]
+    fn prunes_by_first_sort_key_column() {
+        let (min, max) = (row("Berlin", 5), row("London", 1));
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(matches(col("city").eq(lit("Berlin"))));
+        assert!(matches(col("city").gt(lit("Kyiv"))));
+        assert!(!matches(col("city").eq(lit("Paris"))));
+        assert!(!matches(col("city").lt(lit("Berlin"))));
+        assert!(!matches(lit("Amsterdam").gt_eq(col("city"))));
+        assert!(matches(
+            col("city").eq(lit("Paris")).or(col("city").eq(lit("Kyiv")))
+        ));
+        assert!(!matches(
+            col("city")
+                .gt(lit("Paris"))
+                .and(col("city").eq(lit("Berlin")))
+        ));
+        // The second column isn't bounded as cities differ
+        assert!(matches(col("id").eq(lit(100i64))));
+    }
+
+    #[test]
+    fn prunes_by_next_column_when_leading_values_equal() {
+        let (min, max) = (row("Berlin", 5), row("Berlin", 10));
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(matches(col("id").eq(lit(7i64))));
+        assert!(!matches(col("id").gt(lit(10i64))));
+        assert!(!matches(Expr::Between {
+            expr: Box::new(col("id")),
+            negated: false,
+            low: Box::new(lit(11i64)),
+            high: Box::new(lit(20i64)),
+        }));
+    }
+
+    #[test]
+    fn selects_partitions_by_leading_value() {
+        let filters = vec![col("id")
+            .gt(lit(1i64))
+            .and(col("city").eq(lit("Kyiv")).or(lit("Paris").eq(col("city"))))];
+        let values = equality_values(&filters, "city").unwrap();
+        assert_eq!(
+            values,
+            vec![
+                TableValue::String("Kyiv".to_string()),
+                TableValue::String("Paris".to_string())
+            ]
+        );
+        assert_eq!(equality_values(&filters, "id"), None);
+        assert_eq!(
+            equality_values(&[col("city").gt(lit("Kyiv"))], "city"),
+            None
+        );
+
+        let kyiv = TableValue::String("Kyiv".to_string());
+        let tenant =
+            |city: &str| Row::new(vec![TableValue::String(city.to_string()), TableValue::Null]);
+        assert!(can_have_leading_value(None, Some(&tenant("London")), &kyiv));
+        assert!(can_have_leading_value(Some(&tenant("Kyiv")), None, &kyiv));
+        assert!(!can_have_leading_value(
+            Some(&tenant("London")),
+            None,
+            &kyiv
+        ));
+        // Rows of the tenant at the max bound belong to the next partition
+        assert!(!can_have_leading_value(
+            Some(&tenant("Berlin")),
+            Some(&tenant("Kyiv")),
+            &kyiv
+        ));
+        assert!(can_have_leading_value(
+            Some(&row("Berlin", 1)),
+            Some(&row("Kyiv", 10)),
+            &kyiv
+        ));
+        assert!(can_have_leading_value(
+            Some(&row("Berlin", 1)),
+            Some(&row("Kyiv", 10)),
+            &TableValue::Int(1)
+        ));
+    }
+
+    #[test]
+    fn prunes_by_in_list() {
+        let in_list = |values: Vec<Expr>, negated: bool| Expr::InList {
+            expr: Box::new(col("city")),
+            list: values,
+            negated,
+        };
+        let (min, max) = (row("Berlin", 5), row("London", 1));
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(!matches(in_list(
+            vec![lit("Amsterdam"), lit("Paris")],
+            false
+        )));
+        assert!(matches(in_list(vec![lit("Amsterdam"), lit("Kyiv")], false)));
+        assert!(matches(in_list(vec![lit("Berlin"), lit("London")], false)));
+        assert!(!matches(in_list(
+            (0..5000).map(|i| lit(format!("Z{}", i))).collect(),
+            false
+        )));
+        // Non-literal values and negated lists can't prune
+        assert!(matches(in_list(vec![lit("Paris"), col("id")], false)));
+        assert!(matches(in_list(vec![lit("Berlin")], true)));
+    }
+
+    #[test]
+    fn prunes_by_like_prefix() {
+        let like = |pattern: &str| Expr::BinaryExpr {
+            left: Box::new(col("city")),
+            op: Operator::Like,
+            right: Box::new(lit(pattern)),
+        };
+        let (min, max) = (row("Berlin", 5), row("London", 1));
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(matches(like("Kyiv%")));
+        assert!(matches(like("L%")));
+        assert!(matches(like("Lo_don")));
+        assert!(!matches(like("Paris%")));
+        assert!(!matches(like("Amsterdam%")));
+        assert!(!matches(like("Londoner%")));
+        // `min` is within the prefix range while the prefix itself is before it
+        assert!(matches(like("Be%")));
+        assert!(matches(like("Berlin%")));
+        assert!(!matches(like("Bera%")));
+        // Leading wildcard can't prune
+        assert!(matches(like("%Paris")));
+        assert!(matches(like("_aris%")));
+
+        // Prefix range straddling the boundary of adjacent partitions
+        let left = (row("Berlin", 1), row("Kyiv", 1));
+        let right = (row("Kyiv", 1), row("London", 1));
+        let matches =
+            |filter: Expr, (min, max): &(Row, Row)| can_match(&[filter], &columns(), min, max);
+        assert!(matches(like("Ky%"), &left));
+        assert!(matches(like("Ky%"), &right));
+        assert!(!matches(like("Kz%"), &left));
+        assert!(matches(like("Kz%"), &right));
+    }
+
+    #[test]
+    fn prunes_by_unicode_like_prefix() {
+        let (min, max) = (row("Київ", 1), row("Львів", 1));
+        let like = |pattern: &str| Expr::BinaryExpr {
+            left: Box::new(col("city")),
+            op: Operator::Like,
+            right: Box::new(lit(pattern)),
+        };
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(matches(like("Ки%")));
+        assert!(matches(like("Луцьк%")));
+        assert!(matches(like("Льв_в")));
+        assert!(!matches(like("Одеса%")));
+        assert!(!matches(like("Ів%")));
+        assert!(!matches(like("Berlin%")));
+
+        // Nulls sort first so a null min only bounds the range from above
+        let (min, max) = (
+            Row::new(vec![TableValue::Null, TableValue::Int(1)]),
+            row("東京", 1),
+        );
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(matches(like("東%")));
+        assert!(!matches(like("東京都%")));
+    }
+
+    #[test]
+    fn prunes_by_independent_column_bounds() {
+        let columns = vec![
+            (
+                "city",
+                TableValue::String("Berlin".to_string()),
+                TableValue::String("London".to_string()),
+            ),
+            ("id", TableValue::Int(5), TableValue::Int(10)),
+        ];
+        let matches = |filter: Expr| can_match_columns(&[filter], &columns);
+        assert!(matches(col("id").eq(lit(7i64))));
+        assert!(!matches(col("id").eq(lit(1i64))));
+        assert!(!matches(
+            col("city").eq(lit("Kyiv")).and(col("id").gt(lit(10i64)))
+        ));
+        assert!(matches(col("other").eq(lit(1i64))));
+    }
+
+    #[test]
+    fn unknown_expressions_match() {
+        let (min, max) = (row("Berlin", 5), row("London", 1));
+        let matches = |filter: Expr| can_match(&[filter], &columns(), &min, &max);
+        assert!(matches(col("city").eq(lit(1i64))));
+        assert!(matches(col("city").eq(col("other"))));
+        assert!(matches(col("city").is_null()));
+        assert!(can_match(
+            &[col("city").eq(lit("Paris"))],
+            &columns(),
+            &Row::new(vec![]),
+            &Row::new(vec![])
+        ));
+    }
+}
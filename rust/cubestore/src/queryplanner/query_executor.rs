@@ -1,52 +1,81 @@
-use crate::cluster::Cluster;
+use crate::cluster::cancellation::{CancellableCluster, QueryCancellation};
+use crate::cluster::{Cluster, SelectItem, SelectStream};
+use crate::config::ConfigObj;
 use crate::metastore::table::Table;
-use crate::metastore::{Column, ColumnType, IdRow, Index, Partition};
-use crate::queryplanner::serialized_plan::{IndexSnapshot, SerializedPlan};
-use crate::store::DataFrame;
+use crate::metastore::{Chunk, Column, ColumnType, IdRow, Index, MetaStore, Partition};
+use crate::queryplanner::date_arithmetic::format_interval;
+use crate::queryplanner::node_selector::{NodeSelector, RoundRobinNodeSelector};
+use crate::queryplanner::result_cache::WorkerResultCache;
+use crate::queryplanner::row_group_scan::{
+    check_compression, file_column_names, matching_row_groups, split_row_groups,
+    MissingColumnsExec, ParquetMetadataCache, ParquetRowGroupsExec,
+};
+use crate::queryplanner::scan_metrics::{MeteredStream, ScanMetrics, ScanStats};
+use crate::queryplanner::serialized_plan::{
+    check_wire_format_version, IndexSnapshot, PartitionSnapshot, SerializedPlan,
+    MIN_WIRE_FORMAT_VERSION, WIRE_FORMAT_VERSION,
+};
+use crate::queryplanner::split_point::default_split_point;
+use crate::queryplanner::tombstones::{TombstoneExec, Tombstones};
+use crate::queryplanner::udfs::{cast_to, coerced_type, CubeAggregateUDFKind, CubeScalarUDFKind};
+use crate::queryplanner::unique_key::LastRowByKeyExec;
+use crate::queryplanner::warm_up::warm_up_parquet_files;
+use crate::queryplanner::wire_format;
+use crate::store::memory_chunks::MemoryChunkStore;
+use crate::store::{DataFrame, ExecutionStats};
 use crate::table::{Row, TableValue, TimestampValue};
 use crate::CubeError;
 use arrow::array::{
-    Array, BooleanArray, Float64Array, Int64Array, Int64Decimal0Array, Int64Decimal10Array,
-    Int64Decimal1Array, Int64Decimal2Array, Int64Decimal3Array, Int64Decimal4Array,
-    Int64Decimal5Array, StringArray, TimestampMicrosecondArray, TimestampNanosecondArray,
-    UInt64Array,
+    Array, ArrayRef, BinaryArray, BooleanArray, DecimalArray, DecimalBuilder, Float16Array,
+    Float64Array, Int64Array, Int64Decimal0Array, Int64Decimal10Array, Int64Decimal1Array,
+    Int64Decimal2Array, Int64Decimal3Array, Int64Decimal4Array, Int64Decimal5Array,
+    IntervalDayTimeArray, IntervalYearMonthArray, StringArray, TimestampMicrosecondArray,
+    TimestampMillisecondArray, TimestampNanosecondArray, TimestampSecondArray, UInt64Array,
 };
-use arrow::datatypes::{DataType, Schema, SchemaRef, TimeUnit};
+use arrow::compute::kernels::cast::cast;
+use arrow::datatypes::{DataType, Field, IntervalUnit, Schema, SchemaRef, TimeUnit};
+use arrow::error::{ArrowError, Result as ArrowResult};
 use arrow::ipc::reader::StreamReader;
-use arrow::ipc::writer::MemStreamWriter;
+use arrow::ipc::writer::{MemStreamWriter, StreamWriter};
 use arrow::record_batch::RecordBatch;
 use async_trait::async_trait;
-use bigdecimal::BigDecimal;
+use bigdecimal::{BigDecimal, Num, ToPrimitive};
 use core::fmt;
-use datafusion::datasource::datasource::Statistics;
+use datafusion::datasource::datasource::{Statistics, TableProviderFilterPushDown};
 use datafusion::datasource::TableProvider;
 use datafusion::error::DataFusionError;
 use datafusion::error::Result as DFResult;
 use datafusion::execution::context::{ExecutionConfig, ExecutionContext};
-use datafusion::logical_plan::{DFSchemaRef, Expr, ToDFSchema};
+use datafusion::logical_plan::{
+    lit, DFSchemaRef, Expr, JoinType, LogicalPlan, LogicalPlanBuilder, ToDFSchema,
+};
 use datafusion::physical_plan::empty::EmptyExec;
-use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
 use datafusion::physical_plan::limit::GlobalLimitExec;
 use datafusion::physical_plan::memory::MemoryExec;
 use datafusion::physical_plan::merge::{MergeExec, UnionExec};
 use datafusion::physical_plan::merge_sort::MergeSortExec;
 use datafusion::physical_plan::parquet::ParquetExec;
-use datafusion::physical_plan::sort::SortExec;
 use datafusion::physical_plan::{collect, ExecutionPlan, Partitioning, RecordBatchStream};
+use futures::future::join_all;
+use futures::{Stream, StreamExt};
 use itertools::Itertools;
 use log::{debug, error, trace, warn};
 use mockall::automock;
+use num::integer::div_ceil;
 use num::BigInt;
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 use std::any::Any;
 use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
-use std::fmt::Formatter;
-use std::io::Cursor;
+use std::fmt::{Debug, Formatter};
+use std::io::{Cursor, Write};
+use std::ops::Range;
 use std::pin::Pin;
-use std::sync::Arc;
-use std::time::SystemTime;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
 
 #[automock]
 #[async_trait]
@@ -57,14 +86,162 @@ pub trait QueryExecutor: Send + Sync {
         cluster: Arc<dyn Cluster>,
     ) -> Result<DataFrame, CubeError>;
 
+    /// Same as `execute_router_plan` but batches are streamed as they're produced instead of
+    /// being collected into a `DataFrame`.
+    async fn execute_router_plan_stream(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, CubeError>;
+
+    /// Schema of the batches `execute_router_plan_stream` produces. The plan is only split, no
+    /// selects are run.
+    async fn router_plan_schema(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<SchemaRef, CubeError>;
+
+    /// Executes `plans` over partitions and chunks of all their indexes resolved in a single
+    /// metastore read, so compaction between planning of the queries isn't visible to some of
+    /// them only.
+    async fn execute_router_plans(
+        &self,
+        plans: Vec<SerializedPlan>,
+        meta_store: Arc<dyn MetaStore>,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Vec<DataFrame>, CubeError>;
+
+    /// Batches along with the schema of the worker plan as there can be no batches at all and
+    /// stats of its scans. Stats are empty if the result is taken from the cache.
     async fn execute_worker_plan(
         &self,
         plan: SerializedPlan,
         remote_to_local_names: HashMap<String, String>,
-    ) -> Result<Vec<RecordBatch>, CubeError>;
+    ) -> Result<(SchemaRef, Vec<RecordBatch>, ScanStats), CubeError>;
+
+    /// Runs `SELECT 1` on the router and on one of available nodes to check the whole query
+    /// pipeline works. No tables are read.
+    async fn self_check(&self, cluster: Arc<dyn Cluster>) -> Result<(), CubeError>;
+
+    /// Same as `execute_router_plan` but the query can be cancelled by `cancel_connection` with
+    /// the same `connection_id` while it runs.
+    async fn execute_router_plan_for_connection(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+        connection_id: String,
+    ) -> Result<DataFrame, CubeError>;
+
+    /// Cancels all running queries of `connection_id`, e.g. after the client disconnected.
+    /// Returns the number of cancelled queries.
+    fn cancel_connection(&self, connection_id: &str) -> usize;
+
+    /// Cost of the partition groups each `ClusterSendExec` of the split plan would send to
+    /// workers. Nothing is executed and workers aren't contacted.
+    async fn estimate_cost(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<QueryCost, CubeError>;
+
+    /// Whether the query would run locally on the router followed by lines of the split plan
+    /// it would execute, one node per line indented by its depth. Nodes executed by workers are
+    /// marked with `[worker]`. Nothing is executed.
+    async fn explain_router_plan(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Vec<String>, CubeError>;
+}
+
+pub struct QueryExecutorImpl {
+    worker_result_cache: WorkerResultCache,
+    node_selector: Arc<dyn NodeSelector>,
+    local_execution_row_threshold: u64,
+    single_node_local_execution: bool,
+    parquet_parallelism: usize,
+    parquet_warm_up_concurrency: usize,
+    parquet_split_readers: usize,
+    memory_chunks: Arc<MemoryChunkStore>,
+    max_cluster_send_partitions: usize,
+    running_queries: Arc<RunningQueries>,
+}
+
+/// Cancellations of running queries by connection and query ids.
+type RunningQueries = Mutex<HashMap<String, HashMap<String, Arc<QueryCancellation>>>>;
+
+/// Removes a query from running ones when it finishes or its future is dropped.
+struct RunningQueryGuard {
+    running_queries: Arc<RunningQueries>,
+    connection_id: String,
+    query_id: String,
+}
+
+impl Drop for RunningQueryGuard {
+    fn drop(&mut self) {
+        let mut running_queries = self.running_queries.lock().unwrap();
+        if let Some(queries) = running_queries.get_mut(&self.connection_id) {
+            queries.remove(&self.query_id);
+            if queries.is_empty() {
+                running_queries.remove(&self.connection_id);
+            }
+        }
+    }
+}
+
+/// Wall-clock time spent in phases of a router query. Planning of local plans includes
+/// downloads of the files they read.
+#[derive(Debug, Default)]
+struct RouterQueryTimings {
+    planning: Duration,
+    split: Duration,
+    execution: Duration,
+}
+
+/// Cost of a query estimated from metadata of the partitions and chunks it scans.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct QueryCost {
+    rows: u64,
+    bytes: u64,
+    partitions: u64,
+    worker_selects: u64,
+}
+
+impl QueryCost {
+    /// Rows of partition files and chunks read by workers.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// Sizes of partition files read by workers, rows of chunks are counted as taking as much
+    /// space as rows of the file. Partitions written before their file sizes were recorded are
+    /// estimated from types of the scanned columns.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Partitions read by workers. A partition read by several selects of a join is counted
+    /// each time.
+    pub fn partitions(&self) -> u64 {
+        self.partitions
+    }
+
+    /// Selects sent to workers, each one reads a group of partitions.
+    pub fn worker_selects(&self) -> u64 {
+        self.worker_selects
+    }
 }
 
-pub struct QueryExecutorImpl;
+impl fmt::Display for RouterQueryTimings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        write!(
+            f,
+            "planning: {:?}, split: {:?}, execution: {:?}",
+            self.planning, self.split, self.execution
+        )
+    }
+}
 
 #[async_trait]
 impl QueryExecutor for QueryExecutorImpl {
@@ -73,227 +250,835 @@ impl QueryExecutor for QueryExecutorImpl {
         plan: SerializedPlan,
         cluster: Arc<dyn Cluster>,
     ) -> Result<DataFrame, CubeError> {
-        let plan_to_move = plan.logical_plan(&HashMap::new())?;
-        let ctx = self.execution_context()?;
-        let plan_ctx = ctx.clone();
-
-        let serialized_plan = Arc::new(plan);
-        let physical_plan = plan_ctx.create_physical_plan(&plan_to_move.clone())?;
-        let available_nodes = cluster.available_nodes().await?;
-        let split_plan = self.get_router_split_plan(
-            physical_plan,
-            serialized_plan.clone(),
-            cluster,
-            available_nodes,
+        // Queries of connections get their id before being registered for cancellation
+        let query_id = match plan.query_id() {
+            "" => Uuid::new_v4().to_string(),
+            id => id.to_string(),
+        };
+        let start_time = SystemTime::now();
+        let plan = plan.with_query_id(query_id.clone());
+        let plan_to_move = plan.logical_plan(
+            &HashMap::new(),
+            self.parquet_parallelism,
+            &HashMap::new(),
+            &ParquetMetadataCache::new(),
         )?;
 
-        trace!("Router Query Physical Plan: {:#?}", &split_plan);
+        let mut timings = RouterQueryTimings::default();
+        let (split_plan, is_local) = self
+            .get_router_plan(&plan, &plan_to_move, cluster, &mut timings)
+            .await?;
+        if is_local {
+            trace!(
+                "Router Query {} Local Physical Plan: {:#?}",
+                query_id,
+                &split_plan
+            );
+        } else {
+            trace!(
+                "Router Query {} Physical Plan: {:#?}",
+                query_id,
+                &split_plan
+            );
+        }
+        trace!(
+            "Router Query {} Index Selection:\n{}",
+            query_id,
+            plan.explain_index_selection()
+        );
 
         let execution_time = SystemTime::now();
         let results = collect(split_plan.clone()).await;
+        timings.execution = execution_time.elapsed()?;
         debug!(
-            "Query data processing time: {:?}",
+            "Query {} data processing time: {:?}",
+            query_id,
             execution_time.elapsed()?
         );
-        if execution_time.elapsed()?.as_millis() > 200 {
+        if start_time.elapsed()?.as_millis() > 200 {
             warn!(
-                "Slow Query ({:?}):\n{:#?}",
-                execution_time.elapsed()?,
+                "Slow Query {} ({:?}, {}):\n{:#?}",
+                query_id,
+                start_time.elapsed()?,
+                timings,
                 plan_to_move
             );
             debug!(
-                "Slow Query Physical Plan ({:?}): {:#?}",
+                "Slow Query {} Physical Plan ({:?}): {:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 &split_plan
             );
         }
         if results.is_err() {
             error!(
-                "Error Query ({:?}):\n{:#?}",
+                "Error Query {} ({:?}):\n{:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 plan_to_move
             );
             error!(
-                "Error Query Physical Plan ({:?}): {:#?}",
+                "Error Query {} Physical Plan ({:?}): {:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 &split_plan
             );
         }
-        let data_frame = batch_to_dataframe(&results?)?;
-        Ok(data_frame)
+        // Schema drift errors of workers keep their cause so the query can be re-planned
+        let results = results?;
+        let warnings = self.cluster_send_warnings(split_plan.clone());
+        for warning in warnings.iter() {
+            warn!("Partial result of query {}: {}", query_id, warning);
+        }
+        let mut stats = if is_local {
+            ExecutionStats::local(plan.all_partition_ids())
+        } else {
+            self.cluster_send_stats(split_plan.clone())
+        };
+        stats.add_scan(&scan_stats(split_plan));
+        Ok(batch_to_dataframe(&results)?
+            .with_warnings(warnings)
+            .with_stats(stats))
+    }
+
+    async fn execute_router_plan_stream(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, CubeError> {
+        let split_plan = self.get_streamed_router_plan(plan, cluster).await?;
+        Ok(split_plan.execute(0).await?)
+    }
+
+    async fn router_plan_schema(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<SchemaRef, CubeError> {
+        let split_plan = self.get_streamed_router_plan(plan, cluster).await?;
+        Ok(split_plan.schema().to_schema_ref())
+    }
+
+    async fn execute_router_plans(
+        &self,
+        plans: Vec<SerializedPlan>,
+        meta_store: Arc<dyn MetaStore>,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Vec<DataFrame>, CubeError> {
+        let index_ids = plans
+            .iter()
+            .flat_map(|p| p.index_snapshots().iter().map(|i| i.index().get_id()))
+            .unique()
+            .collect::<Vec<_>>();
+        let partitions = meta_store
+            .get_active_partitions_and_chunks_by_index_ids_for_select(index_ids.clone())
+            .await?;
+        let partitions = index_ids
+            .into_iter()
+            .zip(partitions.into_iter().map(|partitions| {
+                partitions
+                    .into_iter()
+                    .map(|(partition, chunks)| PartitionSnapshot::new(partition, chunks))
+                    .collect::<Vec<_>>()
+            }))
+            .collect::<HashMap<_, _>>();
+        join_all(plans.iter().map(|plan| {
+            self.execute_router_plan(plan.with_index_partitions(&partitions), cluster.clone())
+        }))
+        .await
+        .into_iter()
+        .collect()
     }
 
     async fn execute_worker_plan(
         &self,
         plan: SerializedPlan,
         remote_to_local_names: HashMap<String, String>,
-    ) -> Result<Vec<RecordBatch>, CubeError> {
-        let plan_to_move = plan.logical_plan(&remote_to_local_names)?;
-        let ctx = self.execution_context()?;
-        let plan_ctx = ctx.clone();
+    ) -> Result<(SchemaRef, Vec<RecordBatch>, ScanStats), CubeError> {
+        let mut scan_stats = ScanStats::default();
+        // Plans with pending chunks are cached apart from the ones without them
+        let (plan, in_memory_chunks) = self.with_in_memory_chunks(plan);
+        let (schema, batches) = self
+            .worker_result_cache
+            .get_or_execute(&plan, async {
+                let (schema, batches, stats) = self
+                    .execute_worker_plan_uncached(
+                        plan.clone(),
+                        remote_to_local_names,
+                        in_memory_chunks,
+                    )
+                    .await?;
+                scan_stats = stats;
+                Ok::<_, CubeError>((schema, batches))
+            })
+            .await?;
+        Ok((schema, batches, scan_stats))
+    }
+
+    async fn self_check(&self, cluster: Arc<dyn Cluster>) -> Result<(), CubeError> {
+        let plan = SerializedPlan::without_tables(
+            &LogicalPlanBuilder::empty(true)
+                .project(vec![lit(1i64)])?
+                .build()?,
+        );
+        let node = cluster
+            .available_nodes()
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| CubeError::internal("No available nodes".to_string()))?;
+        let worker_rows = cluster
+            .run_select(node.clone(), plan.clone())
+            .await?
+            .iter()
+            .map(|b| b.num_rows())
+            .sum::<usize>();
+        if worker_rows != 1 {
+            return Err(CubeError::internal(format!(
+                "Self check on {} returned {} rows instead of 1",
+                node, worker_rows
+            )));
+        }
+        let df = self.execute_router_plan(plan, cluster).await?;
+        if df.get_rows() != &vec![Row::new(vec![TableValue::Int(1)])] {
+            return Err(CubeError::internal(format!(
+                "Self check returned {:?} instead of 1",
+                df.get_rows()
+            )));
+        }
+        Ok(())
+    }
+
+    async fn execute_router_plan_for_connection(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+        connection_id: String,
+    ) -> Result<DataFrame, CubeError> {
+        let query_id = Uuid::new_v4().to_string();
+        let cancellation = Arc::new(QueryCancellation::new());
+        self.running_queries
+            .lock()
+            .unwrap()
+            .entry(connection_id.clone())
+            .or_default()
+            .insert(query_id.clone(), cancellation.clone());
+        let plan = plan.with_query_id(query_id.clone());
+        let _guard = RunningQueryGuard {
+            running_queries: self.running_queries.clone(),
+            connection_id: connection_id.clone(),
+            query_id,
+        };
+        // Worker selects are run by tasks of the plan which outlive the query future
+        let cluster = CancellableCluster::new(cluster, cancellation.clone());
+        tokio::select! {
+            result = self.execute_router_plan(plan, cluster) => result,
+            _ = cancellation.cancelled() => Err(CubeError::user(format!(
+                "Query was cancelled for connection {}",
+                connection_id
+            ))),
+        }
+    }
+
+    async fn estimate_cost(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<QueryCost, CubeError> {
+        let logical_plan = plan.logical_plan(
+            &HashMap::new(),
+            self.parquet_parallelism,
+            &HashMap::new(),
+            &ParquetMetadataCache::new(),
+        )?;
+        let physical_plan = self.create_physical_plan(&logical_plan)?;
+        let mut row_sizes = HashMap::new();
+        scanned_row_sizes(&physical_plan, &mut row_sizes);
+        let serialized_plan = Arc::new(plan);
+        // Nodes are only needed to execute the plan
+        let split_plan = self.get_router_split_plan(
+            physical_plan,
+            serialized_plan.clone(),
+            cluster,
+            Vec::new(),
+        )?;
+        let partitions = serialized_plan
+            .index_snapshots()
+            .iter()
+            .flat_map(|index| index.partitions().iter())
+            .map(|p| (p.partition().get_id(), p))
+            .collect::<HashMap<_, _>>();
+        let mut cost = QueryCost::default();
+        add_cluster_send_cost(&split_plan, &partitions, &row_sizes, &mut cost);
+        Ok(cost)
+    }
+
+    async fn explain_router_plan(
+        &self,
+        plan: SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Vec<String>, CubeError> {
+        let logical_plan = plan.logical_plan(
+            &HashMap::new(),
+            self.parquet_parallelism,
+            &HashMap::new(),
+            &ParquetMetadataCache::new(),
+        )?;
+        let mut timings = RouterQueryTimings::default();
+        let (split_plan, is_local) = self
+            .get_router_plan(&plan, &logical_plan, cluster, &mut timings)
+            .await?;
+        let mut lines = vec![if is_local {
+            "Execution: local".to_string()
+        } else {
+            "Execution: cluster".to_string()
+        }];
+        explain_plan(&split_plan, 0, false, &mut lines);
+        Ok(lines)
+    }
+
+    fn cancel_connection(&self, connection_id: &str) -> usize {
+        let queries = self.running_queries.lock().unwrap().remove(connection_id);
+        let queries = queries.unwrap_or_default();
+        for cancellation in queries.values() {
+            cancellation.cancel();
+        }
+        if !queries.is_empty() {
+            debug!(
+                "Cancelled {} queries of connection {}",
+                queries.len(),
+                connection_id
+            );
+        }
+        queries.len()
+    }
+}
+
+impl QueryExecutorImpl {
+    pub fn new(config: Arc<dyn ConfigObj>) -> Arc<QueryExecutorImpl> {
+        Self::with_node_selector(config, Arc::new(RoundRobinNodeSelector::new()))
+    }
+
+    pub fn with_node_selector(
+        config: Arc<dyn ConfigObj>,
+        node_selector: Arc<dyn NodeSelector>,
+    ) -> Arc<QueryExecutorImpl> {
+        Self::build(config, node_selector, MemoryChunkStore::new(0))
+    }
+
+    /// Scans read chunks kept in `memory_chunks` by this node from memory.
+    pub fn with_memory_chunks(
+        config: Arc<dyn ConfigObj>,
+        memory_chunks: Arc<MemoryChunkStore>,
+    ) -> Arc<QueryExecutorImpl> {
+        Self::build(
+            config,
+            Arc::new(RoundRobinNodeSelector::new()),
+            memory_chunks,
+        )
+    }
+
+    fn build(
+        config: Arc<dyn ConfigObj>,
+        node_selector: Arc<dyn NodeSelector>,
+        memory_chunks: Arc<MemoryChunkStore>,
+    ) -> Arc<QueryExecutorImpl> {
+        Arc::new(QueryExecutorImpl {
+            worker_result_cache: WorkerResultCache::new(config.worker_result_cache_size()),
+            node_selector,
+            local_execution_row_threshold: config.local_execution_row_threshold(),
+            single_node_local_execution: config.single_node_local_execution(),
+            parquet_parallelism: config.parquet_read_parallelism(),
+            parquet_warm_up_concurrency: config.parquet_warm_up_concurrency(),
+            parquet_split_readers: config.parquet_split_readers(),
+            memory_chunks,
+            max_cluster_send_partitions: config.max_cluster_send_partitions(),
+            running_queries: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Number of queries of `connection_id` which are still running.
+    pub fn running_query_count(&self, connection_id: &str) -> usize {
+        self.running_queries
+            .lock()
+            .unwrap()
+            .get(connection_id)
+            .map(|queries| queries.len())
+            .unwrap_or(0)
+    }
+
+    async fn execute_worker_plan_uncached(
+        &self,
+        plan: SerializedPlan,
+        remote_to_local_names: HashMap<String, String>,
+        in_memory_chunks: HashMap<u64, Vec<RecordBatch>>,
+    ) -> Result<(SchemaRef, Vec<RecordBatch>, ScanStats), CubeError> {
+        let query_id = plan.query_id().to_string();
+        let parquet_metadata = ParquetMetadataCache::new();
+        warm_up_parquet_files(
+            remote_to_local_names.values().cloned().collect(),
+            parquet_metadata.clone(),
+            self.parquet_warm_up_concurrency,
+        )
+        .await;
+        let plan_to_move = plan.logical_plan(
+            &remote_to_local_names,
+            self.parquet_parallelism,
+            &in_memory_chunks,
+            &parquet_metadata,
+        )?;
 
-        let physical_plan = plan_ctx.create_physical_plan(&plan_to_move.clone())?;
+        let physical_plan = self.create_physical_plan(&plan_to_move)?;
 
-        let worker_plan = self.get_worker_split_plan(physical_plan);
+        // Workers plan the same query as the router so they arrive at the same split point
+        let worker_plan = self.get_worker_plan(physical_plan, plan.split_branch())?;
 
-        trace!("Partition Query Physical Plan: {:#?}", &worker_plan);
+        trace!(
+            "Partition Query {} Physical Plan: {:#?}",
+            query_id,
+            &worker_plan
+        );
 
         let execution_time = SystemTime::now();
         let results = collect(worker_plan.clone()).await;
+        let worker_scan_stats = scan_stats(worker_plan.clone());
         debug!(
-            "Partition Query data processing time: {:?}",
-            execution_time.elapsed()?
+            "Partition Query {} data processing time: {:?}, scan: {:?}",
+            query_id,
+            execution_time.elapsed()?,
+            worker_scan_stats
         );
         if execution_time.elapsed()?.as_millis() > 200 || results.is_err() {
             warn!(
-                "Slow Partition Query ({:?}):\n{:#?}",
+                "Slow Partition Query {} ({:?}):\n{:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 plan_to_move
             );
             debug!(
-                "Slow Partition Query Physical Plan ({:?}): {:#?}",
+                "Slow Partition Query {} Physical Plan ({:?}): {:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 &worker_plan
             );
         }
         if results.is_err() {
             error!(
-                "Error Partition Query ({:?}):\n{:#?}",
+                "Error Partition Query {} ({:?}):\n{:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 plan_to_move
             );
             error!(
-                "Error Partition Query Physical Plan ({:?}): {:#?}",
+                "Error Partition Query {} Physical Plan ({:?}): {:#?}",
+                query_id,
                 execution_time.elapsed()?,
                 &worker_plan
             );
         }
-        Ok(results?)
-    }
-}
-
-impl QueryExecutorImpl {
-    fn execution_context(&self) -> Result<Arc<ExecutionContext>, CubeError> {
-        let ctx = ExecutionContext::with_config(
-            ExecutionConfig::new()
-                .with_batch_size(4096)
-                .with_concurrency(1),
-        );
-        Ok(Arc::new(ctx))
+        Ok((
+            worker_plan.schema().to_schema_ref(),
+            results?,
+            worker_scan_stats,
+        ))
     }
 
-    fn get_router_split_plan(
+    /// Picks the lowest wire format version advertised by nodes the plan can be sent to.
+    async fn negotiate_format_version(
         &self,
-        execution_plan: Arc<dyn ExecutionPlan>,
-        serialized_plan: Arc<SerializedPlan>,
         cluster: Arc<dyn Cluster>,
-        available_nodes: Vec<String>,
-    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
-        if self.has_node::<HashAggregateExec>(execution_plan.clone()) {
-            self.get_router_split_plan_at(
-                execution_plan,
-                serialized_plan,
-                cluster,
-                available_nodes,
-                |h| h.as_any().downcast_ref::<HashAggregateExec>().is_some(),
-            )
-        } else if self.has_node::<SortExec>(execution_plan.clone()) {
-            self.get_router_split_plan_at(
-                execution_plan,
-                serialized_plan,
-                cluster,
-                available_nodes,
-                |h| h.as_any().downcast_ref::<SortExec>().is_some(),
-            )
-        } else if self.has_node::<GlobalLimitExec>(execution_plan.clone()) {
-            self.get_router_split_plan_at(
-                execution_plan,
-                serialized_plan,
-                cluster,
-                available_nodes,
-                |h| h.as_any().downcast_ref::<GlobalLimitExec>().is_some(),
-            )
-        } else {
-            self.get_router_split_plan_at(
-                execution_plan,
-                serialized_plan,
-                cluster,
-                available_nodes,
-                |_| true,
-            )
+        available_nodes: &Vec<String>,
+    ) -> Result<u32, CubeError> {
+        let mut format_version = WIRE_FORMAT_VERSION;
+        for node in available_nodes.iter() {
+            let node_version = cluster.node_wire_format_version(node.to_string()).await?;
+            if node_version < format_version {
+                format_version = node_version;
+            }
         }
+        if format_version < MIN_WIRE_FORMAT_VERSION {
+            return Err(CubeError::user(format!(
+                "Can't run query: cluster nodes support wire format version {} while at least {} is required. Please upgrade cluster nodes.",
+                format_version, MIN_WIRE_FORMAT_VERSION
+            )));
+        }
+        Ok(format_version)
     }
 
-    fn get_worker_split_plan(
+    /// Queries over a few rows are cheaper to run on the router than to send to workers as long
+    /// as the router has all their files.
+    async fn is_tiny_query(
         &self,
-        execution_plan: Arc<dyn ExecutionPlan>,
-    ) -> Arc<dyn ExecutionPlan> {
-        if self.has_node::<HashAggregateExec>(execution_plan.clone()) {
-            self.get_worker_split_plan_at(execution_plan, |h| {
-                h.as_any().downcast_ref::<HashAggregateExec>().is_some()
-            })
-        } else if self.has_node::<SortExec>(execution_plan.clone()) {
-            self.get_worker_split_plan_at(execution_plan, |h| {
-                h.as_any().downcast_ref::<SortExec>().is_some()
-            })
-        } else if self.has_node::<GlobalLimitExec>(execution_plan.clone()) {
-            self.get_worker_split_plan_at(execution_plan, |h| {
-                h.as_any().downcast_ref::<GlobalLimitExec>().is_some()
-            })
-        } else {
-            self.get_worker_split_plan_at(execution_plan, |_| true)
+        plan: &SerializedPlan,
+        cluster: &Arc<dyn Cluster>,
+    ) -> Result<bool, CubeError> {
+        if self.local_execution_row_threshold == 0
+            || plan.estimated_row_count() > self.local_execution_row_threshold
+        {
+            return Ok(false);
         }
+        for remote_path in self.files_to_scan(plan, &self.in_memory_chunks(plan)) {
+            if !cluster.is_downloaded(&remote_path).await? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
-    fn get_worker_split_plan_at(
+    /// Physical plan the router executes and whether it runs locally. Tiny queries and queries
+    /// to a cluster of the router alone with `single_node_local_execution` set run locally
+    /// without sending anything to workers.
+    async fn get_router_plan(
         &self,
-        execution_plan: Arc<dyn ExecutionPlan>,
-        split_at_fn: impl Fn(Arc<dyn ExecutionPlan>) -> bool,
-    ) -> Arc<dyn ExecutionPlan> {
-        let children = execution_plan.children();
-        assert!(
-            children.len() == 1,
-            "Only one child is expected for {:?}",
-            &execution_plan
-        );
-        if split_at_fn(execution_plan.clone()) {
-            children[0].clone()
-        } else {
-            self.get_worker_split_plan(children[0].clone())
+        plan: &SerializedPlan,
+        logical_plan: &LogicalPlan,
+        cluster: Arc<dyn Cluster>,
+        timings: &mut RouterQueryTimings,
+    ) -> Result<(Arc<dyn ExecutionPlan>, bool), CubeError> {
+        let planning_time = SystemTime::now();
+        if self.is_tiny_query(plan, &cluster).await? {
+            let local_plan = self.get_local_plan(plan, cluster).await?;
+            timings.planning = planning_time.elapsed()?;
+            return Ok((local_plan, true));
+        }
+        let available_nodes = cluster.available_nodes().await?;
+        // Workers can be all down for a while during rolling restarts
+        if available_nodes.is_empty() {
+            return Err(CubeError::internal("no available worker nodes".to_string()));
         }
+        if self.single_node_local_execution
+            && available_nodes.len() == 1
+            && available_nodes[0] == cluster.server_name()
+        {
+            let local_plan = self.get_local_plan(plan, cluster).await?;
+            timings.planning = planning_time.elapsed()?;
+            return Ok((local_plan, true));
+        }
+        let planning_time = SystemTime::now();
+        let physical_plan = self.create_physical_plan(logical_plan)?;
+        timings.planning = planning_time.elapsed()?;
+        let split_time = SystemTime::now();
+        let format_version = self
+            .negotiate_format_version(cluster.clone(), &available_nodes)
+            .await?;
+        let serialized_plan = Arc::new(plan.with_format_version(format_version)?);
+        let split_plan =
+            self.get_router_split_plan(physical_plan, serialized_plan, cluster, available_nodes)?;
+        timings.split = split_time.elapsed()?;
+        Ok((split_plan, false))
     }
 
-    fn get_router_split_plan_at(
+    /// Router plan of a single partition streaming the results of `plan`.
+    async fn get_streamed_router_plan(
         &self,
-        execution_plan: Arc<dyn ExecutionPlan>,
-        serialized_plan: Arc<SerializedPlan>,
+        plan: SerializedPlan,
         cluster: Arc<dyn Cluster>,
-        available_nodes: Vec<String>,
-        split_at_fn: impl Fn(Arc<dyn ExecutionPlan>) -> bool,
     ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
-        if split_at_fn(execution_plan.clone()) {
-            let children = execution_plan.children();
-            self.wrap_with_cluster_send(
-                execution_plan,
-                serialized_plan,
-                cluster,
-                available_nodes,
-                children,
-            )
+        let query_id = Uuid::new_v4().to_string();
+        let plan = plan.with_query_id(query_id.clone());
+        let plan_to_move = plan.logical_plan(
+            &HashMap::new(),
+            self.parquet_parallelism,
+            &HashMap::new(),
+            &ParquetMetadataCache::new(),
+        )?;
+
+        let mut timings = RouterQueryTimings::default();
+        let (split_plan, _) = self
+            .get_router_plan(&plan, &plan_to_move, cluster, &mut timings)
+            .await?;
+        trace!(
+            "Router Query {} Streamed Physical Plan: {:#?}",
+            query_id,
+            &split_plan
+        );
+        if split_plan.output_partitioning().partition_count() == 1 {
+            Ok(split_plan)
         } else {
-            let children = execution_plan
-                .children()
-                .iter()
-                .map(move |c| {
-                    self.get_router_split_plan(
-                        c.clone(),
-                        serialized_plan.clone(),
-                        cluster.clone(),
-                        available_nodes.clone(),
-                    )
-                })
-                .collect::<Result<Vec<_>, _>>()?;
-            Ok(execution_plan.with_new_children(children)?)
+            Ok(Arc::new(MergeExec::new(split_plan)))
+        }
+    }
+
+    /// Whole plan including worker part executed on the router over downloaded files.
+    async fn get_local_plan(
+        &self,
+        plan: &SerializedPlan,
+        cluster: Arc<dyn Cluster>,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let plan = plan.with_partition_id_to_execute(plan.all_partition_ids());
+        let (plan, in_memory_chunks) = self.with_in_memory_chunks(plan);
+        let to_download = self.files_to_scan(&plan, &in_memory_chunks);
+        let local_names = join_all(to_download.iter().map(|remote| cluster.download(remote)))
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        let remote_to_local_names = to_download
+            .into_iter()
+            .zip(local_names.into_iter())
+            .collect::<HashMap<_, _>>();
+        let logical_plan = plan.logical_plan(
+            &remote_to_local_names,
+            self.parquet_parallelism,
+            &in_memory_chunks,
+            &ParquetMetadataCache::new(),
+        )?;
+        self.create_physical_plan(&logical_plan)
+    }
+
+    /// Files of the plan to scan. Chunks kept in memory are scanned without their files.
+    fn files_to_scan(
+        &self,
+        plan: &SerializedPlan,
+        in_memory_chunks: &HashMap<u64, Vec<RecordBatch>>,
+    ) -> Vec<String> {
+        let in_memory_files = plan
+            .index_snapshots()
+            .iter()
+            .flat_map(|i| i.partitions().iter().flat_map(|p| p.chunks().iter()))
+            .filter(|c| in_memory_chunks.contains_key(&c.get_id()))
+            .map(|c| c.get_row().get_full_name(c.get_id()))
+            .collect::<HashSet<_>>();
+        plan.files_to_download()
+            .into_iter()
+            .filter(|f| !in_memory_files.contains(f))
+            .collect()
+    }
+
+    /// Adds chunks this process wrote to partitions of the plan but hasn't activated yet, see
+    /// `MemoryChunkStore::add_pending`. Batches of all chunks of the plan kept in memory are
+    /// returned along with the plan.
+    fn with_in_memory_chunks(
+        &self,
+        plan: SerializedPlan,
+    ) -> (SerializedPlan, HashMap<u64, Vec<RecordBatch>>) {
+        if !self.memory_chunks.is_enabled() {
+            return (plan, HashMap::new());
+        }
+        let partitions = plan
+            .index_snapshots()
+            .iter()
+            .flat_map(|i| i.partitions().iter())
+            .collect::<Vec<_>>();
+        let partition_ids = partitions
+            .iter()
+            .map(|p| p.partition().get_id())
+            .collect::<HashSet<_>>();
+        // Chunks activated since the pending ones were taken are already in the plan
+        let chunk_ids = partitions
+            .iter()
+            .flat_map(|p| p.chunks().iter().map(|c| c.get_id()))
+            .collect::<HashSet<_>>();
+        let mut pending_chunks = HashMap::<u64, Vec<IdRow<Chunk>>>::new();
+        let mut pending_batches = HashMap::new();
+        for (partition_id, chunk_id, batches) in self.memory_chunks.pending_of(&partition_ids) {
+            if chunk_ids.contains(&chunk_id) {
+                continue;
+            }
+            let row_count = batches.iter().map(|b| b.num_rows()).sum::<usize>();
+            pending_chunks
+                .entry(partition_id)
+                .or_default()
+                .push(IdRow::new(chunk_id, Chunk::new(partition_id, row_count)));
+            pending_batches.insert(chunk_id, batches);
+        }
+        let plan = plan.with_pending_chunks(&pending_chunks);
+        let mut in_memory_chunks = self.in_memory_chunks(&plan);
+        in_memory_chunks.extend(pending_batches);
+        (plan, in_memory_chunks)
+    }
+
+    /// Batches of chunks of the plan which this process still keeps in memory.
+    fn in_memory_chunks(&self, plan: &SerializedPlan) -> HashMap<u64, Vec<RecordBatch>> {
+        if !self.memory_chunks.is_enabled() {
+            return HashMap::new();
+        }
+        plan.index_snapshots()
+            .iter()
+            .flat_map(|i| i.partitions().iter().flat_map(|p| p.chunks().iter()))
+            .filter_map(|c| {
+                self.memory_chunks
+                    .get(c.get_id())
+                    .map(|batches| (c.get_id(), batches))
+            })
+            .collect()
+    }
+
+    /// Planning errors carry the logical plan to show which of its nodes failed.
+    fn create_physical_plan(
+        &self,
+        logical_plan: &LogicalPlan,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let plan = self
+            .execution_context()?
+            .create_physical_plan(logical_plan)
+            .map_err(|e| {
+                CubeError::internal(format!(
+                    "Can't create physical plan: {}\nLogical plan:\n{}",
+                    e,
+                    logical_plan.display_indent()
+                ))
+            })?;
+        reconcile_union_schemas(plan)
+    }
+
+    fn execution_context(&self) -> Result<Arc<ExecutionContext>, CubeError> {
+        let mut ctx = ExecutionContext::with_config(
+            ExecutionConfig::new()
+                .with_batch_size(4096)
+                .with_concurrency(1),
+        );
+        // Same functions as the planner registers so deserialized plans resolve them
+        for kind in CubeScalarUDFKind::all() {
+            ctx.register_udf(kind.udf());
+        }
+        for kind in CubeAggregateUDFKind::all() {
+            ctx.register_udaf(kind.udaf());
+        }
+        Ok(Arc::new(ctx))
+    }
+
+    fn get_router_split_plan(
+        &self,
+        execution_plan: Arc<dyn ExecutionPlan>,
+        serialized_plan: Arc<SerializedPlan>,
+        cluster: Arc<dyn Cluster>,
+        available_nodes: Vec<String>,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let split_point = default_split_point(&execution_plan);
+        self.get_router_split_plan_at(
+            execution_plan,
+            serialized_plan,
+            cluster,
+            available_nodes,
+            |h| split_point.map_or(true, |p| p.is_split_node(h.as_ref())),
+        )
+    }
+
+    /// Part of the plan executed by the worker. With `parquet_split_readers` above one, a scan of
+    /// a single file is split into readers of disjoint row group ranges so a worker with one
+    /// large partition uses several cores.
+    fn get_worker_plan(
+        &self,
+        execution_plan: Arc<dyn ExecutionPlan>,
+        split_branch: &[usize],
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let worker_plan = self.get_worker_split_plan(execution_plan, split_branch)?;
+        if self.parquet_split_readers <= 1 {
+            return Ok(worker_plan);
+        }
+        self.split_file_readers(worker_plan)
+    }
+
+    fn split_file_readers(
+        &self,
+        execution_plan: Arc<dyn ExecutionPlan>,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let split = match execution_plan.as_any().downcast_ref::<CubeTableExec>() {
+            Some(cube_table) => Some(cube_table.split_file_readers(self.parquet_split_readers)?),
+            None => None,
+        };
+        match split {
+            Some(Some(split)) => Ok(Arc::new(split)),
+            Some(None) => Ok(execution_plan),
+            None => {
+                let children = execution_plan.children();
+                if children.is_empty() {
+                    return Ok(execution_plan);
+                }
+                let children = children
+                    .into_iter()
+                    .map(|c| self.split_file_readers(c))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(execution_plan.with_new_children(children)?)
+            }
+        }
+    }
+
+    fn get_worker_split_plan(
+        &self,
+        execution_plan: Arc<dyn ExecutionPlan>,
+        split_branch: &[usize],
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let split_point = default_split_point(&execution_plan);
+        self.get_worker_split_plan_at(execution_plan, split_branch, |h| {
+            split_point.map_or(true, |p| p.is_split_node(h.as_ref()))
+        })
+    }
+
+    fn get_worker_split_plan_at(
+        &self,
+        execution_plan: Arc<dyn ExecutionPlan>,
+        split_branch: &[usize],
+        split_at_fn: impl Fn(Arc<dyn ExecutionPlan>) -> bool,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let children = execution_plan.children();
+        if children.len() > 1 && !split_at_fn(execution_plan.clone()) {
+            // Router sends every branch separately and tells which one is to be executed
+            let child = split_branch
+                .first()
+                .and_then(|i| children.get(*i))
+                .ok_or_else(|| {
+                    CubeError::internal(format!(
+                        "Split branch {:?} doesn't match {:?}",
+                        split_branch, execution_plan
+                    ))
+                })?;
+            return self.get_worker_split_plan(child.clone(), &split_branch[1..]);
+        }
+        assert!(
+            children.len() == 1,
+            "Only one child is expected for {:?}",
+            &execution_plan
+        );
+        if split_at_fn(execution_plan.clone()) {
+            // Same branches the router sends to workers
+            match split_union_branches(&children[0])? {
+                Some((worker_part, _)) => Ok(worker_part),
+                None => Ok(children[0].clone()),
+            }
+        } else {
+            self.get_worker_split_plan(children[0].clone(), split_branch)
+        }
+    }
+
+    fn get_router_split_plan_at(
+        &self,
+        execution_plan: Arc<dyn ExecutionPlan>,
+        serialized_plan: Arc<SerializedPlan>,
+        cluster: Arc<dyn Cluster>,
+        available_nodes: Vec<String>,
+        split_at_fn: impl Fn(Arc<dyn ExecutionPlan>) -> bool,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        if split_at_fn(execution_plan.clone()) {
+            let children = execution_plan.children();
+            self.wrap_with_cluster_send(
+                execution_plan,
+                serialized_plan,
+                cluster,
+                available_nodes,
+                children,
+            )
+        } else {
+            let children = execution_plan.children();
+            let branching = children.len() > 1;
+            let children = children
+                .iter()
+                .enumerate()
+                .map(move |(i, c)| {
+                    let serialized_plan = if branching {
+                        Arc::new(serialized_plan.with_split_branch(i))
+                    } else {
+                        serialized_plan.clone()
+                    };
+                    self.get_router_split_plan(
+                        c.clone(),
+                        serialized_plan,
+                        cluster.clone(),
+                        available_nodes.clone(),
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(execution_plan.with_new_children(children)?)
         }
     }
 
@@ -307,34 +1092,58 @@ impl QueryExecutorImpl {
     ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
         let union_snapshots = self.union_snapshots_from_cube_table(execution_plan.clone());
         if !union_snapshots.is_empty() {
-            let cluster_exec = Arc::new(ClusterSendExec::new(
-                children[0].schema(),
-                cluster,
-                serialized_plan,
-                available_nodes,
-                union_snapshots,
-            ));
-            Ok(execution_plan.with_new_children(vec![Arc::new(MergeExec::new(cluster_exec))])?)
+            let branches = split_union_branches(&children[0])?;
+            let worker_plan = match &branches {
+                Some((worker_part, _)) => worker_part.clone(),
+                None => children[0].clone(),
+            };
+            let cluster_exec: Arc<dyn ExecutionPlan> = Arc::new(
+                ClusterSendExec::new(
+                    children[0].schema(),
+                    cluster,
+                    serialized_plan,
+                    available_nodes,
+                    union_snapshots,
+                    self.node_selector.clone(),
+                    serialized_plan.best_effort(),
+                )
+                .with_max_partitions(self.max_cluster_send_partitions)?
+                .with_worker_plan(worker_plan),
+            );
+            let input = match branches {
+                // Workers execute only branches scanning tables, the rest is executed here
+                Some((_, router_part)) => Arc::new(UnionExec::new(vec![cluster_exec, router_part])),
+                None => cluster_exec,
+            };
+            Ok(execution_plan.with_new_children(vec![Arc::new(MergeExec::new(input))])?)
         } else {
-            // TODO .to_schema_ref()
-            Ok(
-                execution_plan.with_new_children(vec![Arc::new(EmptyExec::new(
-                    false,
-                    children[0].schema().to_schema_ref(),
-                ))])?,
-            )
+            // Nothing to send to workers: the plan is executed on the router as is so
+            // empty relations keep producing a row if the planner asked for it.
+            Ok(execution_plan)
         }
     }
 
-    fn has_node<T: Any>(&self, execution_plan: Arc<dyn ExecutionPlan>) -> bool {
-        if execution_plan.as_any().downcast_ref::<T>().is_some() {
-            true
+    fn cluster_send_warnings(&self, execution_plan: Arc<dyn ExecutionPlan>) -> Vec<String> {
+        if let Some(cluster_send) = execution_plan.as_any().downcast_ref::<ClusterSendExec>() {
+            cluster_send.warnings()
         } else {
             execution_plan
                 .children()
                 .into_iter()
-                .find(|c| self.has_node::<T>(c.clone()))
-                .is_some()
+                .flat_map(|c| self.cluster_send_warnings(c))
+                .collect()
+        }
+    }
+
+    fn cluster_send_stats(&self, execution_plan: Arc<dyn ExecutionPlan>) -> ExecutionStats {
+        if let Some(cluster_send) = execution_plan.as_any().downcast_ref::<ClusterSendExec>() {
+            cluster_send.stats()
+        } else {
+            let mut stats = ExecutionStats::default();
+            for child in execution_plan.children() {
+                stats.merge(&self.cluster_send_stats(child));
+            }
+            stats
         }
     }
 
@@ -345,11 +1154,18 @@ impl QueryExecutorImpl {
         if let Some(cube_table) = execution_plan.as_any().downcast_ref::<CubeTableExec>() {
             vec![vec![cube_table.index_snapshot.clone()]]
         } else if let Some(union_exec) = execution_plan.as_any().downcast_ref::<UnionExec>() {
-            vec![union_exec
+            // Branches without tables don't add snapshots and a union of such branches only
+            // isn't sent to workers at all
+            let snapshots = union_exec
                 .children()
                 .iter()
                 .flat_map(|e| self.index_snapshots_from_cube_table(e.clone()))
-                .collect::<Vec<_>>()]
+                .collect::<Vec<_>>();
+            if snapshots.is_empty() {
+                vec![]
+            } else {
+                vec![snapshots]
+            }
         } else {
             execution_plan
                 .children()
@@ -375,12 +1191,286 @@ impl QueryExecutorImpl {
     }
 }
 
+/// Estimated size of rows read by scans of each index by index id. Strings and bytes are
+/// assumed to be 32 bytes long.
+fn scanned_row_sizes(plan: &Arc<dyn ExecutionPlan>, row_sizes: &mut HashMap<u64, u64>) {
+    if let Some(cube_table) = plan.as_any().downcast_ref::<CubeTableExec>() {
+        let index = cube_table.index_snapshot.index();
+        let columns = index.get_row().get_columns();
+        let row_size = cube_table
+            .projection
+            .iter()
+            .map(|i| match columns[*i].get_column_type() {
+                ColumnType::Int | ColumnType::Timestamp | ColumnType::Decimal { .. } => 8,
+                ColumnType::Boolean => 1,
+                ColumnType::String | ColumnType::Bytes => 32,
+            })
+            .sum::<u64>();
+        let size = row_sizes.entry(index.get_id()).or_insert(0);
+        *size = (*size).max(row_size);
+    }
+    for child in plan.children() {
+        scanned_row_sizes(&child, row_sizes);
+    }
+}
+
+fn add_cluster_send_cost(
+    plan: &Arc<dyn ExecutionPlan>,
+    partitions: &HashMap<u64, &PartitionSnapshot>,
+    row_sizes: &HashMap<u64, u64>,
+    cost: &mut QueryCost,
+) {
+    if let Some(cluster_send) = plan.as_any().downcast_ref::<ClusterSendExec>() {
+        for group in cluster_send.partitions.iter() {
+            cost.worker_selects += 1;
+            for partition in group {
+                cost.partitions += 1;
+                // Tombstone chunks are read as well so their rows aren't subtracted
+                let (rows, file_size) =
+                    partitions.get(&partition.get_id()).map_or((0, None), |p| {
+                        let chunk_rows = p
+                            .chunks()
+                            .iter()
+                            .map(|c| c.get_row().get_row_count())
+                            .sum::<u64>();
+                        let file = p.partition().get_row();
+                        (
+                            file.main_table_row_count() + chunk_rows,
+                            file.estimated_file_size(chunk_rows),
+                        )
+                    });
+                let row_size = row_sizes.get(&partition.get_row().get_index_id());
+                cost.rows += rows;
+                cost.bytes += file_size.unwrap_or_else(|| rows * row_size.cloned().unwrap_or(0));
+            }
+        }
+        return;
+    }
+    for child in plan.children() {
+        add_cluster_send_cost(&child, partitions, row_sizes, cost);
+    }
+}
+
+/// Splits the input of the split node having `UNION ALL` of branches which scan tables and
+/// branches which don't into the part executed by workers and the part executed by the router.
+/// The input gives rows of both parts together as workers give rows of their partitions.
+/// `None` unless both parts are non-empty.
+fn split_union_branches(
+    plan: &Arc<dyn ExecutionPlan>,
+) -> Result<Option<(Arc<dyn ExecutionPlan>, Arc<dyn ExecutionPlan>)>, CubeError> {
+    match (union_branches(plan, true)?, union_branches(plan, false)?) {
+        (Some(worker_part), Some(router_part)) => Ok(Some((worker_part, router_part))),
+        _ => Ok(None),
+    }
+}
+
+/// `plan` with only union branches which scan tables if `with_tables` is set or only the ones
+/// which don't otherwise. Nodes with several inputs other than unions are kept as a whole.
+fn union_branches(
+    plan: &Arc<dyn ExecutionPlan>,
+    with_tables: bool,
+) -> Result<Option<Arc<dyn ExecutionPlan>>, CubeError> {
+    let children = plan.children();
+    if plan.as_any().downcast_ref::<UnionExec>().is_some() {
+        let mut branches = Vec::new();
+        for child in children.iter() {
+            branches.extend(union_branches(child, with_tables)?);
+        }
+        return Ok(if branches.is_empty() {
+            None
+        } else {
+            Some(Arc::new(UnionExec::new(branches)))
+        });
+    }
+    if children.len() == 1 {
+        return match union_branches(&children[0], with_tables)? {
+            Some(child) => Ok(Some(plan.with_new_children(vec![child])?)),
+            None => Ok(None),
+        };
+    }
+    if has_cube_table(plan) == with_tables {
+        Ok(Some(plan.clone()))
+    } else {
+        Ok(None)
+    }
+}
+
+fn has_cube_table(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    plan.as_any().downcast_ref::<CubeTableExec>().is_some()
+        || plan.children().iter().any(|c| has_cube_table(c))
+}
+
+/// Stats of all table scans of the plan.
+/// Appends a line per node of `plan` indented by `depth`. Inputs of `ClusterSendExec` are
+/// executed by workers and only their results reach the router.
+fn explain_plan(
+    plan: &Arc<dyn ExecutionPlan>,
+    depth: usize,
+    on_worker: bool,
+    lines: &mut Vec<String>,
+) {
+    let indent = "  ".repeat(depth);
+    if let Some(cluster_send) = plan.as_any().downcast_ref::<ClusterSendExec>() {
+        lines.push(format!(
+            "{}ClusterSendExec, worker selects: {}",
+            indent,
+            cluster_send.partitions.len()
+        ));
+        if let Some(worker_plan) = &cluster_send.worker_plan {
+            explain_plan(worker_plan, depth + 1, true, lines);
+        }
+        return;
+    }
+    // Debug output of execution plans starts with the node name
+    let debug = format!("{:?}", plan);
+    let name = debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    lines.push(if on_worker {
+        format!("{}{} [worker]", indent, name)
+    } else {
+        format!("{}{}", indent, name)
+    });
+    for child in plan.children() {
+        explain_plan(&child, depth + 1, on_worker, lines);
+    }
+}
+
+fn scan_stats(execution_plan: Arc<dyn ExecutionPlan>) -> ScanStats {
+    if let Some(cube_table) = execution_plan.as_any().downcast_ref::<CubeTableExec>() {
+        cube_table.scan_stats()
+    } else {
+        let mut stats = ScanStats::default();
+        for child in execution_plan.children() {
+            stats.merge(&scan_stats(child));
+        }
+        stats
+    }
+}
+
+/// Creates scans of the parquet files of a `CubeTable` and reads metadata the scan is planned
+/// by. `ParquetExecFactory` reads local files, other implementations can serve files from
+/// another storage or fake them in tests.
+pub trait ParquetScanFactory: Send + Sync + Debug {
+    fn scan(
+        &self,
+        path: &str,
+        projection: Option<Vec<usize>>,
+        batch_size: usize,
+        parallelism: usize,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError>;
+
+    /// Single partition scan reading `paths` one after another to save the setup of a scan per
+    /// file. `None` if files can't be read together and should be scanned one by one.
+    fn scan_files(
+        &self,
+        _paths: &[String],
+        _projection: Option<Vec<usize>>,
+        _batch_size: usize,
+    ) -> Result<Option<Arc<dyn ExecutionPlan>>, CubeError> {
+        Ok(None)
+    }
+
+    fn row_group_count(&self, path: &str) -> Result<usize, CubeError>;
+
+    /// Names of the columns the file was written with.
+    fn column_names(&self, path: &str) -> Result<Vec<String>, CubeError>;
+
+    /// Row group ranges whose statistics can satisfy `filters`, `None` if all of them have to be
+    /// read. Ranges are read with `ParquetRowGroupsExec`.
+    fn matching_row_groups(
+        &self,
+        path: &str,
+        columns: &[Column],
+        filters: &[Expr],
+    ) -> Result<Option<Vec<Range<usize>>>, CubeError>;
+}
+
+#[derive(Debug)]
+pub struct ParquetExecFactory {
+    metadata: Arc<ParquetMetadataCache>,
+}
+
+impl ParquetExecFactory {
+    pub fn new(metadata: Arc<ParquetMetadataCache>) -> Arc<ParquetExecFactory> {
+        Arc::new(ParquetExecFactory { metadata })
+    }
+}
+
+impl ParquetScanFactory for ParquetExecFactory {
+    fn scan(
+        &self,
+        path: &str,
+        projection: Option<Vec<usize>>,
+        batch_size: usize,
+        parallelism: usize,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        check_compression(path, &self.metadata.get(path)?)?;
+        Ok(Arc::new(ParquetExec::try_from_path(
+            path,
+            projection,
+            batch_size,
+            parallelism,
+        )?))
+    }
+
+    fn scan_files(
+        &self,
+        paths: &[String],
+        projection: Option<Vec<usize>>,
+        batch_size: usize,
+    ) -> Result<Option<Arc<dyn ExecutionPlan>>, CubeError> {
+        for path in paths {
+            check_compression(path, &self.metadata.get(path)?)?;
+        }
+        let paths = paths.iter().map(|p| p.as_str()).collect::<Vec<_>>();
+        // Files of the single partition are read sequentially
+        Ok(Some(Arc::new(ParquetExec::try_from_files(
+            &paths, projection, batch_size, 1,
+        )?)))
+    }
+
+    fn row_group_count(&self, path: &str) -> Result<usize, CubeError> {
+        Ok(self.metadata.get(path)?.num_row_groups())
+    }
+
+    fn column_names(&self, path: &str) -> Result<Vec<String>, CubeError> {
+        Ok(file_column_names(&self.metadata.get(path)?))
+    }
+
+    fn matching_row_groups(
+        &self,
+        path: &str,
+        columns: &[Column],
+        filters: &[Expr],
+    ) -> Result<Option<Vec<Range<usize>>>, CubeError> {
+        let metadata = self.metadata.get(path)?;
+        check_compression(path, &metadata)?;
+        Ok(matching_row_groups(&metadata, columns, filters))
+    }
+}
+
+fn default_scan_factory() -> Arc<dyn ParquetScanFactory> {
+    ParquetExecFactory::new(ParquetMetadataCache::new())
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct CubeTable {
     index_snapshot: IndexSnapshot,
     remote_to_local_names: HashMap<String, String>,
     worker_partition_ids: HashSet<u64>,
     schema: SchemaRef,
+    /// Max concurrency of each `ParquetExec` created by the scan.
+    parquet_parallelism: usize,
+    /// Batches of chunks scanned from memory instead of their files.
+    #[serde(skip)]
+    in_memory_chunks: HashMap<u64, Vec<RecordBatch>>,
+    #[serde(skip, default = "default_scan_factory")]
+    scan_factory: Arc<dyn ParquetScanFactory>,
+    /// Max number of rows the query reads from the table if it's read without filters.
+    #[serde(default)]
+    limit: Option<usize>,
 }
 
 impl CubeTable {
@@ -388,6 +1478,7 @@ impl CubeTable {
         index_snapshot: IndexSnapshot,
         remote_to_local_names: HashMap<String, String>,
         worker_partition_ids: HashSet<u64>,
+        parquet_parallelism: usize,
     ) -> Result<Self, CubeError> {
         let schema = Arc::new(Schema::new(
             index_snapshot
@@ -403,19 +1494,59 @@ impl CubeTable {
             schema,
             remote_to_local_names,
             worker_partition_ids,
+            parquet_parallelism,
+            in_memory_chunks: HashMap::new(),
+            scan_factory: default_scan_factory(),
+            limit: None,
         })
     }
 
+    pub fn with_scan_factory(self, scan_factory: Arc<dyn ParquetScanFactory>) -> Self {
+        Self {
+            scan_factory,
+            ..self
+        }
+    }
+
+    /// Partitions of a plain scan stop reading once `limit` rows are read.
+    pub fn with_limit_hint(self, limit: Option<usize>) -> Self {
+        Self { limit, ..self }
+    }
+
+    /// Chunks of the table found in `in_memory_chunks` are scanned with `MemoryExec`.
+    pub fn with_in_memory_chunks(self, in_memory_chunks: &HashMap<u64, Vec<RecordBatch>>) -> Self {
+        let in_memory_chunks = self
+            .index_snapshot
+            .partitions()
+            .iter()
+            .flat_map(|p| p.chunks().iter())
+            .filter_map(|c| {
+                in_memory_chunks
+                    .get(&c.get_id())
+                    .map(|batches| (c.get_id(), batches.clone()))
+            })
+            .collect();
+        Self {
+            in_memory_chunks,
+            ..self
+        }
+    }
+
+    pub fn parquet_parallelism(&self) -> usize {
+        self.parquet_parallelism
+    }
+
     fn async_scan(
         &self,
         projection: &Option<Vec<usize>>,
         batch_size: usize,
+        filters: &[Expr],
     ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
         let table = self.index_snapshot.table();
         let index = self.index_snapshot.index();
-        let partition_snapshots = self.index_snapshot.partitions();
 
         let mut partition_execs = Vec::<Arc<dyn ExecutionPlan>>::new();
+        let mut row_groups_read = Vec::new();
 
         let mapped_projection = projection.as_ref().map(|p| {
             CubeTable::project_to_index_positions(&CubeTable::project_to_table(&table, p), &index)
@@ -424,51 +1555,10 @@ impl CubeTable {
                 .collect::<Vec<_>>()
         });
 
-        for partition_snapshot in partition_snapshots {
-            if !self
-                .worker_partition_ids
-                .contains(&partition_snapshot.partition().get_id())
-            {
-                continue;
-            }
-            let partition = partition_snapshot.partition();
-
-            if let Some(remote_path) = partition.get_row().get_full_name(partition.get_id()) {
-                let local_path = self
-                    .remote_to_local_names
-                    .get(remote_path.as_str())
-                    .expect(format!("Missing remote path {}", remote_path).as_str());
-                let arc: Arc<dyn ExecutionPlan> = Arc::new(ParquetExec::try_from_path(
-                    &local_path,
-                    mapped_projection.clone(),
-                    batch_size,
-                    1,
-                )?);
-                partition_execs.push(arc);
-            }
-
-            let chunks = partition_snapshot.chunks();
-            for chunk in chunks {
-                let remote_path = chunk.get_row().get_full_name(chunk.get_id());
-                let local_path = self
-                    .remote_to_local_names
-                    .get(&remote_path)
-                    .expect(format!("Missing remote path {}", remote_path).as_str());
-                let node = Arc::new(ParquetExec::try_from_path(
-                    local_path,
-                    mapped_projection.clone(),
-                    batch_size,
-                    1,
-                )?);
-                partition_execs.push(node);
-            }
-        }
-
-        if partition_execs.len() == 0 {
-            partition_execs.push(Arc::new(EmptyExec::new(false, self.schema.clone())));
-        }
-
-        let projected_schema = if let Some(p) = mapped_projection {
+        let projection = mapped_projection
+            .clone()
+            .unwrap_or_else(|| (0..self.schema.fields().len()).collect());
+        let projected_schema = if let Some(p) = mapped_projection.clone() {
             Arc::new(Schema::new(
                 self.schema
                     .fields()
@@ -481,41 +1571,458 @@ impl CubeTable {
             self.schema.clone()
         };
 
-        let plan: Arc<dyn ExecutionPlan> = if let Some(join_columns) = self.index_snapshot.join_on()
-        {
-            Arc::new(MergeSortExec::try_new(
-                Arc::new(CubeTableExec {
-                    schema: projected_schema.to_dfschema_ref()?,
-                    partition_execs,
-                    index_snapshot: self.index_snapshot.clone(),
-                }),
-                join_columns.clone(),
-            )?)
+        let (partitions, tombstone_files) = self.data_to_scan(filters)?;
+        let files = partitions
+            .iter()
+            .flatten()
+            .filter_map(|source| match source {
+                ScanSource::File(local_path) | ScanSource::ChunkFile(local_path, _) => {
+                    Some(local_path.clone())
+                }
+                ScanSource::InMemory(..) => None,
+            })
+            .collect::<Vec<_>>();
+        if let Some(unique_key) = table.get_row().unique_key_columns() {
+            // Files and chunks of a partition are merged by key to keep the last row of each key,
+            // so they're read with all columns
+            let key_columns = unique_key
+                .iter()
+                .map(|c| self.schema.index_of(c))
+                .collect::<Result<Vec<_>, _>>()?;
+            let all_columns = (0..self.schema.fields().len()).collect::<Vec<_>>();
+            for (sources, tombstone_files) in partitions.into_iter().zip(tombstone_files) {
+                if sources.is_empty() {
+                    continue;
+                }
+                // Rows older than a tombstone of their key are dropped before deduplication
+                let tombstones = if tombstone_files.is_empty() {
+                    None
+                } else {
+                    Some(Tombstones::new(
+                        tombstone_files,
+                        Some(key_columns.clone()),
+                        batch_size,
+                    ))
+                };
+                let mut inputs = Vec::<Arc<dyn ExecutionPlan>>::new();
+                let mut row_groups = 0;
+                for source in sources {
+                    let sequence = source.sequence();
+                    let (input, source_row_groups) = self.all_columns_scan(source, batch_size)?;
+                    row_groups += source_row_groups;
+                    let input: Arc<dyn ExecutionPlan> = match &tombstones {
+                        Some(tombstones) => Arc::new(TombstoneExec::new(
+                            vec![(input, sequence)],
+                            tombstones.clone(),
+                            all_columns.clone(),
+                            self.schema.to_dfschema_ref()?,
+                        )),
+                        None => input,
+                    };
+                    inputs.push(input);
+                }
+                row_groups_read.push(row_groups);
+                partition_execs.push(Arc::new(LastRowByKeyExec::new(
+                    inputs,
+                    key_columns.clone(),
+                    projection.clone(),
+                    projected_schema.to_dfschema_ref()?,
+                )));
+            }
+        } else {
+            // Rows of partitions with tombstones are matched to deleted ones with all columns
+            let mut plain_partitions = Vec::new();
+            for (sources, tombstone_files) in partitions.into_iter().zip(tombstone_files) {
+                if tombstone_files.is_empty() {
+                    plain_partitions.push(sources);
+                    continue;
+                }
+                let mut inputs = Vec::new();
+                let mut row_groups = 0;
+                for source in sources {
+                    let sequence = source.sequence();
+                    let (input, source_row_groups) = self.all_columns_scan(source, batch_size)?;
+                    row_groups += source_row_groups;
+                    inputs.push((input, sequence));
+                }
+                row_groups_read.push(row_groups);
+                partition_execs.push(Arc::new(TombstoneExec::new(
+                    inputs,
+                    Tombstones::new(tombstone_files, None, batch_size),
+                    projection.clone(),
+                    projected_schema.to_dfschema_ref()?,
+                )));
+            }
+            let partitions = plain_partitions;
+
+            // Chunks are small so ones read as a whole are scanned together unless each
+            // partition of the scan has to be sorted for a merge join
+            let combine_chunks = self.index_snapshot.join_on().is_none();
+            let mut chunk_files = Vec::new();
+            for source in partitions.iter().flatten() {
+                let (local_path, is_chunk) = match source {
+                    ScanSource::File(local_path) => (local_path, false),
+                    ScanSource::ChunkFile(local_path, _) => (local_path, true),
+                    ScanSource::InMemory(..) => continue,
+                };
+                if let Some(exec) = self.missing_columns_scan(
+                    local_path,
+                    &projection,
+                    batch_size,
+                    &projected_schema,
+                )? {
+                    partition_execs.push(exec);
+                    row_groups_read.push(self.scan_factory.row_group_count(local_path)? as u64);
+                    continue;
+                }
+                // Row groups whose statistics rule out filters aren't read
+                match self.scan_factory.matching_row_groups(
+                    local_path,
+                    index.get_row().get_columns(),
+                    filters,
+                )? {
+                    None if is_chunk && combine_chunks => chunk_files.push(local_path.clone()),
+                    None => {
+                        partition_execs.push(self.scan_factory.scan(
+                            local_path,
+                            mapped_projection.clone(),
+                            batch_size,
+                            self.parquet_parallelism,
+                        )?);
+                        row_groups_read.push(self.scan_factory.row_group_count(local_path)? as u64);
+                    }
+                    Some(ranges) => {
+                        for row_groups in ranges {
+                            row_groups_read.push(row_groups.len() as u64);
+                            partition_execs.push(Arc::new(ParquetRowGroupsExec::new(
+                                local_path.clone(),
+                                row_groups,
+                                projection.clone(),
+                                batch_size,
+                                projected_schema.to_dfschema_ref()?,
+                            )));
+                        }
+                    }
+                }
+            }
+            self.push_chunk_scans(
+                chunk_files,
+                &mapped_projection,
+                batch_size,
+                &mut partition_execs,
+                &mut row_groups_read,
+            )?;
+
+            for source in partitions.into_iter().flatten() {
+                if let ScanSource::InMemory(batches, _) = source {
+                    row_groups_read.push(0);
+                    partition_execs.push(self.in_memory_scan(
+                        batches,
+                        &projection,
+                        &projected_schema,
+                    )?);
+                }
+            }
+        }
+
+        // Rows of a plain scan are neither deduplicated nor merged by key so any `limit` rows of
+        // a partition will do
+        if let Some(limit) = self.limit {
+            if table.get_row().unique_key_columns().is_none()
+                && self.index_snapshot.join_on().is_none()
+            {
+                partition_execs = partition_execs
+                    .into_iter()
+                    .map(|exec| -> Arc<dyn ExecutionPlan> {
+                        let exec: Arc<dyn ExecutionPlan> =
+                            if exec.output_partitioning().partition_count() == 1 {
+                                exec
+                            } else {
+                                Arc::new(MergeExec::new(exec))
+                            };
+                        Arc::new(GlobalLimitExec::new(exec, limit, 1))
+                    })
+                    .collect();
+            }
+        }
+
+        // Table without files has no rows so it never produces a placeholder row
+        if partition_execs.len() == 0 {
+            partition_execs.push(Arc::new(EmptyExec::new(false, self.schema.clone())));
+        }
+
+        let cube_table_exec = Arc::new(CubeTableExec {
+            schema: projected_schema.to_dfschema_ref()?,
+            partition_execs,
+            index_snapshot: self.index_snapshot.clone(),
+            files,
+            row_groups_read,
+            projection,
+            batch_size,
+            metrics: Arc::new(ScanMetrics::default()),
+        });
+        let plan: Arc<dyn ExecutionPlan> = if let Some(join_columns) = self.index_snapshot.join_on()
+        {
+            Arc::new(MergeSortExec::try_new(
+                cube_table_exec,
+                join_columns.clone(),
+            )?)
         } else {
-            Arc::new(MergeExec::new(Arc::new(CubeTableExec {
-                schema: projected_schema.to_dfschema_ref()?,
-                partition_execs,
-                index_snapshot: self.index_snapshot.clone(),
-            })))
+            Arc::new(MergeExec::new(cube_table_exec))
         };
 
         Ok(plan)
     }
 
+    /// Scan of `source` with all columns along with the number of row groups it reads.
+    fn all_columns_scan(
+        &self,
+        source: ScanSource,
+        batch_size: usize,
+    ) -> Result<(Arc<dyn ExecutionPlan>, u64), CubeError> {
+        let all_columns = (0..self.schema.fields().len()).collect::<Vec<_>>();
+        match source {
+            ScanSource::File(local_path) | ScanSource::ChunkFile(local_path, _) => {
+                let row_groups = self.scan_factory.row_group_count(&local_path)? as u64;
+                let exec = match self.missing_columns_scan(
+                    &local_path,
+                    &all_columns,
+                    batch_size,
+                    &self.schema,
+                )? {
+                    Some(exec) => exec,
+                    None => self.scan_factory.scan(
+                        &local_path,
+                        None,
+                        batch_size,
+                        self.parquet_parallelism,
+                    )?,
+                };
+                Ok((exec, row_groups))
+            }
+            ScanSource::InMemory(batches, _) => {
+                Ok((self.in_memory_scan(batches, &all_columns, &self.schema)?, 0))
+            }
+        }
+    }
+
+    /// Scans `chunk_files` together if there's more than one of them and the scan factory can
+    /// read them at once, otherwise one by one.
+    fn push_chunk_scans(
+        &self,
+        chunk_files: Vec<String>,
+        projection: &Option<Vec<usize>>,
+        batch_size: usize,
+        partition_execs: &mut Vec<Arc<dyn ExecutionPlan>>,
+        row_groups_read: &mut Vec<u64>,
+    ) -> Result<(), CubeError> {
+        if chunk_files.len() > 1 {
+            if let Some(exec) =
+                self.scan_factory
+                    .scan_files(&chunk_files, projection.clone(), batch_size)?
+            {
+                let mut row_groups = 0;
+                for local_path in chunk_files.iter() {
+                    row_groups += self.scan_factory.row_group_count(local_path)? as u64;
+                }
+                partition_execs.push(exec);
+                row_groups_read.push(row_groups);
+                return Ok(());
+            }
+        }
+        for local_path in chunk_files.iter() {
+            partition_execs.push(self.scan_factory.scan(
+                local_path,
+                projection.clone(),
+                batch_size,
+                self.parquet_parallelism,
+            )?);
+            row_groups_read.push(self.scan_factory.row_group_count(local_path)? as u64);
+        }
+        Ok(())
+    }
+
+    /// Scan of a file written before some of `projection` columns were added to the index.
+    /// Columns are read by their names and missing ones are filled with their defaults. `None` if
+    /// the file has all of the columns.
+    fn missing_columns_scan(
+        &self,
+        local_path: &String,
+        projection: &Vec<usize>,
+        batch_size: usize,
+        scan_schema: &SchemaRef,
+    ) -> Result<Option<Arc<dyn ExecutionPlan>>, CubeError> {
+        let index_columns = self.index_snapshot.index().get_row().get_columns();
+        let file_columns = self.scan_factory.column_names(local_path)?;
+        if projection
+            .iter()
+            .all(|i| file_columns.contains(index_columns[*i].get_name()))
+        {
+            return Ok(None);
+        }
+        let mut file_projection = projection
+            .iter()
+            .filter_map(|i| {
+                file_columns
+                    .iter()
+                    .position(|c| c == index_columns[*i].get_name())
+            })
+            .collect::<Vec<_>>();
+        // Reading at least one column keeps row count of the file
+        if file_projection.is_empty() {
+            file_projection.push(0);
+        }
+        Ok(Some(Arc::new(MissingColumnsExec::new(
+            self.scan_factory.scan(
+                local_path,
+                Some(file_projection),
+                batch_size,
+                self.parquet_parallelism,
+            )?,
+            scan_schema.to_dfschema_ref()?,
+            self.column_defaults(scan_schema),
+        ))))
+    }
+
+    /// Scan of an in-memory chunk. Chunks created before columns were added to the index have
+    /// their defaults filled in.
+    fn in_memory_scan(
+        &self,
+        batches: Vec<RecordBatch>,
+        projection: &Vec<usize>,
+        scan_schema: &SchemaRef,
+    ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+        let batch_schema = match batches.first() {
+            Some(batch) if batch.num_columns() < self.schema.fields().len() => batch.schema(),
+            _ => {
+                return Ok(Arc::new(MemoryExec::try_new(
+                    &vec![batches],
+                    self.schema.clone(),
+                    Some(projection.clone()),
+                )?))
+            }
+        };
+        Ok(Arc::new(MissingColumnsExec::new(
+            Arc::new(MemoryExec::try_new(&vec![batches], batch_schema, None)?),
+            scan_schema.to_dfschema_ref()?,
+            self.column_defaults(scan_schema),
+        )))
+    }
+
+    /// Batch of buffered rows of a partition, see `PartitionSnapshot::buffered_rows`.
+    fn buffered_batch(&self, rows: &[Row]) -> Result<RecordBatch, CubeError> {
+        let rows = rows.to_vec();
+        let arrays = self
+            .index_snapshot
+            .index()
+            .get_row()
+            .get_columns()
+            .iter()
+            .zip(self.schema.fields().iter())
+            .enumerate()
+            .map(|(i, (column, field))| {
+                column_to_array(&rows, &column.replace_index(i), field.data_type())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RecordBatch::try_new(self.schema.clone(), arrays)?)
+    }
+
+    fn column_defaults(&self, schema: &SchemaRef) -> Vec<TableValue> {
+        let table = self.index_snapshot.table().get_row();
+        schema
+            .fields()
+            .iter()
+            .map(|f| table.column_default(f.name()))
+            .collect()
+    }
+
+    /// Data of partitions to scan in the order it was written: the partition file followed by
+    /// chunks in the order of their sequences and buffered rows. Local files of tombstone chunks
+    /// of each partition are returned separately along with their sequences.
+    /// The same file can be referenced more than once after compaction races and it's scanned
+    /// only once to avoid double counting. Partitions and chunks which can't satisfy `filters`
+    /// according to their min/max stats are skipped.
+    fn data_to_scan(
+        &self,
+        filters: &[Expr],
+    ) -> Result<(Vec<Vec<ScanSource>>, Vec<Vec<(String, u64)>>), CubeError> {
+        let mut partitions = Vec::new();
+        let mut tombstone_paths = Vec::new();
+        let mut seen = HashSet::new();
+        for (partition, chunks) in self
+            .index_snapshot
+            .to_scan(&self.worker_partition_ids, filters)
+        {
+            let mut sources = Vec::new();
+            let mut partition_tombstones = Vec::new();
+            let mut push_file = |remote_path: String,
+                                 chunk_sequence: Option<u64>,
+                                 sources: &mut Vec<ScanSource>|
+             -> Result<(), CubeError> {
+                let local_path = self.local_path(&remote_path)?;
+                if seen.insert(local_path.clone()) {
+                    sources.push(match chunk_sequence {
+                        Some(sequence) => ScanSource::ChunkFile(local_path.clone(), sequence),
+                        None => ScanSource::File(local_path.clone()),
+                    });
+                } else {
+                    warn!(
+                        "Skipping duplicate file {} ({}) in scan of {}",
+                        local_path,
+                        remote_path,
+                        self.index_snapshot.table_name()
+                    );
+                }
+                Ok(())
+            };
+            if let Some(remote_path) = partition.get_row().get_full_name(partition.get_id()) {
+                push_file(remote_path, None, &mut sources)?;
+            }
+            for chunk in chunks.into_iter().sorted_by_key(|c| c.sequence()) {
+                let remote_path = chunk.get_row().get_full_name(chunk.get_id());
+                if chunk.get_row().is_tombstone() {
+                    partition_tombstones
+                        .push((self.local_path(&remote_path)?.clone(), chunk.sequence()));
+                    continue;
+                }
+                match self.in_memory_chunks.get(&chunk.get_id()) {
+                    Some(batches) => {
+                        sources.push(ScanSource::InMemory(batches.clone(), chunk.sequence()))
+                    }
+                    None => push_file(remote_path, Some(chunk.sequence()), &mut sources)?,
+                }
+            }
+            let buffered_rows = self.index_snapshot.buffered_rows(partition.get_id());
+            if !buffered_rows.is_empty() {
+                // Buffered rows are written after all chunks
+                sources.push(ScanSource::InMemory(
+                    vec![self.buffered_batch(buffered_rows)?],
+                    u64::MAX,
+                ));
+            }
+            partitions.push(sources);
+            tombstone_paths.push(partition_tombstones);
+        }
+        Ok((partitions, tombstone_paths))
+    }
+
+    fn local_path(&self, remote_path: &String) -> Result<&String, CubeError> {
+        self.remote_to_local_names.get(remote_path).ok_or_else(|| {
+            CubeError::internal(format!(
+                "File {} of {} wasn't downloaded",
+                remote_path,
+                self.index_snapshot.table_name()
+            ))
+        })
+    }
+
+    /// Columns are matched by name ignoring case like SQL identifiers. An exact match is
+    /// preferred for columns which differ only in case.
     pub fn project_to_index_positions(
         projection_columns: &Vec<Column>,
         i: &IdRow<Index>,
     ) -> Vec<Option<usize>> {
-        projection_columns
-            .iter()
-            .map(|pc| {
-                i.get_row()
-                    .get_columns()
-                    .iter()
-                    .find_position(|c| c.get_name() == pc.get_name())
-                    .map(|(p, _)| p)
-            })
-            .collect::<Vec<_>>()
+        IndexColumnPositions::new(i.get_row()).project(projection_columns)
     }
 
     pub fn project_to_table(
@@ -529,11 +2036,120 @@ impl CubeTable {
     }
 }
 
+/// Positions of index columns by name. Build it once per index to match several projections
+/// to the index without walking its columns for every projected one.
+pub struct IndexColumnPositions {
+    exact: HashMap<String, usize>,
+    lowercase: HashMap<String, usize>,
+}
+
+impl IndexColumnPositions {
+    pub fn new(index: &Index) -> IndexColumnPositions {
+        let mut exact = HashMap::new();
+        let mut lowercase = HashMap::new();
+        // The first of the columns with the same name is matched
+        for (i, c) in index.get_columns().iter().enumerate() {
+            exact.entry(c.get_name().to_string()).or_insert(i);
+            lowercase.entry(c.get_name().to_lowercase()).or_insert(i);
+        }
+        IndexColumnPositions { exact, lowercase }
+    }
+
+    /// Position of the `name` column, see `CubeTable::project_to_index_positions`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.exact
+            .get(name)
+            .or_else(|| self.lowercase.get(&name.to_lowercase()))
+            .cloned()
+    }
+
+    pub fn project(&self, projection_columns: &Vec<Column>) -> Vec<Option<usize>> {
+        projection_columns
+            .iter()
+            .map(|c| self.position(c.get_name()))
+            .collect()
+    }
+}
+
+/// Partition file or chunk to scan. Chunks come with their sequences.
+enum ScanSource {
+    File(String),
+    /// Chunk file which can be scanned along with other chunks.
+    ChunkFile(String, u64),
+    InMemory(Vec<RecordBatch>, u64),
+}
+
+impl ScanSource {
+    /// Sequence of the data among writes to the partition. The partition file precedes chunks.
+    fn sequence(&self) -> u64 {
+        match self {
+            ScanSource::File(_) => 0,
+            ScanSource::ChunkFile(_, sequence) | ScanSource::InMemory(_, sequence) => *sequence,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct CubeTableExec {
     schema: DFSchemaRef,
     index_snapshot: IndexSnapshot,
     partition_execs: Vec<Arc<dyn ExecutionPlan>>,
+    /// Files read by `partition_execs` with the projected columns and batch size of the reads.
+    files: Vec<String>,
+    /// Number of row groups read by each of `partition_execs`.
+    row_groups_read: Vec<u64>,
+    projection: Vec<usize>,
+    batch_size: usize,
+    metrics: Arc<ScanMetrics>,
+}
+
+impl CubeTableExec {
+    pub fn scan_stats(&self) -> ScanStats {
+        self.metrics.stats()
+    }
+
+    /// Scan of a single file split into at most `readers` readers of row group ranges.
+    /// `None` if the scan reads several files, the file has a single row group or some of its
+    /// row groups are already skipped by filters.
+    fn split_file_readers(&self, readers: usize) -> Result<Option<CubeTableExec>, CubeError> {
+        let (path, row_groups) = match (
+            self.files.as_slice(),
+            self.partition_execs.as_slice(),
+            self.row_groups_read.as_slice(),
+        ) {
+            ([path], [exec], [row_groups])
+                if exec.as_any().downcast_ref::<ParquetExec>().is_some() =>
+            {
+                (path, *row_groups as usize)
+            }
+            _ => return Ok(None),
+        };
+        let ranges = split_row_groups(row_groups, readers);
+        if ranges.len() <= 1 {
+            return Ok(None);
+        }
+        Ok(Some(CubeTableExec {
+            schema: self.schema.clone(),
+            index_snapshot: self.index_snapshot.clone(),
+            row_groups_read: ranges.iter().map(|r| r.len() as u64).collect(),
+            partition_execs: ranges
+                .into_iter()
+                .map(|row_groups| -> Arc<dyn ExecutionPlan> {
+                    Arc::new(ParquetRowGroupsExec::new(
+                        path.clone(),
+                        row_groups,
+                        self.projection.clone(),
+                        self.batch_size,
+                        self.schema.clone(),
+                    ))
+                })
+                .collect(),
+            files: self.files.clone(),
+            projection: self.projection.clone(),
+            batch_size: self.batch_size,
+            metrics: self.metrics.clone(),
+        }))
+    }
 }
 
 #[async_trait]
@@ -562,6 +2178,11 @@ impl ExecutionPlan for CubeTableExec {
             schema: self.schema.clone(),
             partition_execs: children,
             index_snapshot: self.index_snapshot.clone(),
+            files: self.files.clone(),
+            row_groups_read: self.row_groups_read.clone(),
+            projection: self.projection.clone(),
+            batch_size: self.batch_size,
+            metrics: self.metrics.clone(),
         }))
     }
 
@@ -569,7 +2190,24 @@ impl ExecutionPlan for CubeTableExec {
         &self,
         partition: usize,
     ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
-        self.partition_execs[partition].execute(0).await
+        if partition >= self.partition_execs.len() {
+            return Err(DataFusionError::Execution(format!(
+                "CubeTableExec partition index {} is out of range: {} partitions are available",
+                partition,
+                self.partition_execs.len()
+            )));
+        }
+        let exec = &self.partition_execs[partition];
+        if exec.as_any().downcast_ref::<EmptyExec>().is_none()
+            && exec.as_any().downcast_ref::<MemoryExec>().is_none()
+        {
+            self.metrics
+                .file_opened(self.row_groups_read.get(partition).cloned().unwrap_or(0));
+        }
+        Ok(Box::pin(MeteredStream::new(
+            exec.execute(0).await?,
+            self.metrics.clone(),
+        )))
     }
 }
 
@@ -579,6 +2217,13 @@ pub struct ClusterSendExec {
     cluster: Arc<dyn Cluster>,
     available_nodes: Vec<String>,
     serialized_plan: Arc<SerializedPlan>,
+    node_selector: Arc<dyn NodeSelector>,
+    /// Failed partitions produce no rows and a warning instead of failing the whole query.
+    best_effort: bool,
+    warnings: Arc<Mutex<Vec<String>>>,
+    stats: Arc<Mutex<ExecutionStats>>,
+    /// Part of the router plan the workers execute, only used to explain the plan.
+    worker_plan: Option<Arc<dyn ExecutionPlan>>,
 }
 
 impl ClusterSendExec {
@@ -588,36 +2233,294 @@ impl ClusterSendExec {
         serialized_plan: Arc<SerializedPlan>,
         available_nodes: Vec<String>,
         union_snapshots: Vec<Vec<IndexSnapshot>>,
+        node_selector: Arc<dyn NodeSelector>,
+        best_effort: bool,
     ) -> Self {
-        let to_multiply = union_snapshots
-            .into_iter()
-            .map(|union| {
-                union
-                    .iter()
-                    .flat_map(|index| {
-                        index
-                            .partitions()
-                            .iter()
-                            .map(|p| p.partition().clone())
-                            .collect::<Vec<_>>()
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .collect::<Vec<_>>();
-        let partitions = to_multiply
-            .into_iter()
-            .multi_cartesian_product()
-            .collect::<Vec<Vec<_>>>();
+        let partitions = match Self::join_type(&union_snapshots) {
+            Some(join_type) => {
+                Self::join_partition_groups(&union_snapshots[0], &union_snapshots[1], join_type)
+            }
+            None => union_snapshots
+                .iter()
+                .map(|union| {
+                    union
+                        .iter()
+                        .flat_map(|index| index.partitions().iter().map(|p| p.partition().clone()))
+                        .collect::<Vec<_>>()
+                })
+                .multi_cartesian_product()
+                .collect::<Vec<Vec<_>>>(),
+        };
         Self {
             schema,
             partitions,
             cluster,
             available_nodes,
             serialized_plan,
+            node_selector,
+            best_effort,
+            warnings: Arc::new(Mutex::new(Vec::new())),
+            stats: Arc::new(Mutex::new(ExecutionStats::default())),
+            worker_plan: None,
+        }
+    }
+
+    pub fn with_worker_plan(self, worker_plan: Arc<dyn ExecutionPlan>) -> Self {
+        Self {
+            worker_plan: Some(worker_plan),
+            ..self
+        }
+    }
+
+    /// Type of the join if the sides of a single join are all there is to send to workers.
+    fn join_type(union_snapshots: &Vec<Vec<IndexSnapshot>>) -> Option<JoinType> {
+        if union_snapshots.len() != 2 {
+            return None;
+        }
+        let mut join_types = union_snapshots.iter().flatten().map(|i| i.join_type());
+        let join_type = join_types.next()??;
+        if join_types.all(|t| t == Some(join_type)) {
+            Some(join_type.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Groups partitions of two joined tables. For inner joins only pairs of non-empty partitions
+    /// with intersecting join key ranges are sent to workers. Rows of a preserved side of an outer
+    /// join are joined on workers that have no rows to match them to, so every partition of that
+    /// side is sent once along with all partitions of the other side it can match, or alone if
+    /// there are none. Both sides of a full join are sent together.
+    fn join_partition_groups(
+        left: &Vec<IndexSnapshot>,
+        right: &Vec<IndexSnapshot>,
+        join_type: JoinType,
+    ) -> Vec<Vec<IdRow<Partition>>> {
+        let keep_left = !matches!(join_type, JoinType::Inner | JoinType::Right);
+        let keep_right = !matches!(join_type, JoinType::Inner | JoinType::Left);
+        let with_ranges = |union: &Vec<IndexSnapshot>, keep_empty: bool| {
+            union
+                .iter()
+                .flat_map(|index| {
+                    index
+                        .partitions()
+                        .iter()
+                        .filter(|p| keep_empty || !p.is_empty())
+                        .map(|p| {
+                            (
+                                p.partition().clone(),
+                                JoinKeyRange::for_partition(index, p.partition()),
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let left = with_ranges(left, keep_left);
+        let right = with_ranges(right, keep_right);
+        let matching =
+            |(partition, range): &(IdRow<Partition>, Option<JoinKeyRange>),
+             other: &Vec<(IdRow<Partition>, Option<JoinKeyRange>)>| {
+                let mut group = vec![partition.clone()];
+                group.extend(
+                    other
+                        .iter()
+                        .filter(|(_, other_range)| JoinKeyRange::intersect(range, other_range))
+                        .map(|(p, _)| p.clone()),
+                );
+                group
+            };
+        match (keep_left, keep_right) {
+            (false, false) => left
+                .iter()
+                .cartesian_product(right.iter())
+                .filter(|((_, l), (_, r))| JoinKeyRange::intersect(l, r))
+                .map(|((l, _), (r, _))| vec![l.clone(), r.clone()])
+                .collect(),
+            (true, false) => left.iter().map(|l| matching(l, &right)).collect(),
+            (false, true) => right.iter().map(|r| matching(r, &left)).collect(),
+            (true, true) => {
+                let all = left
+                    .into_iter()
+                    .chain(right.into_iter())
+                    .map(|(p, _)| p)
+                    .collect::<Vec<_>>();
+                if all.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![all]
+                }
+            }
+        }
+    }
+
+    /// Limits the number of `run_select` calls. Partitions of a single table are merged into
+    /// `max_partitions` groups of adjacent partitions. Partition groups of a join can't be merged
+    /// without joining partitions of different groups so such plans fail instead.
+    pub fn with_max_partitions(self, max_partitions: usize) -> Result<Self, CubeError> {
+        if self.partitions.len() <= max_partitions {
+            return Ok(self);
+        }
+        if max_partitions == 0 || self.partitions.iter().any(|p| p.len() > 1) {
+            return Err(CubeError::user(format!(
+                "Query requires {} worker selects while at most {} are allowed. Please narrow down the query or raise CUBESTORE_MAX_CLUSTER_SEND_PARTITIONS.",
+                self.partitions.len(),
+                max_partitions
+            )));
+        }
+        let group_size = div_ceil(self.partitions.len(), max_partitions);
+        let partitions = self
+            .partitions
+            .chunks(group_size)
+            .map(|group| group.iter().flatten().cloned().collect())
+            .collect();
+        Ok(Self { partitions, ..self })
+    }
+
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().unwrap().clone()
+    }
+
+    /// Partitions and nodes successfully queried so far.
+    pub fn stats(&self) -> ExecutionStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// Batches of `partition` from the node selected to run it. They're counted in stats and
+    /// adapted to the plan schema as they arrive.
+    async fn run_partition_select(&self, partition: usize) -> Result<ClusterSendStream, CubeError> {
+        let partition_ids = self.partitions[partition]
+            .iter()
+            .map(|p| p.get_id())
+            .collect::<HashSet<_>>();
+        let plan = self
+            .serialized_plan
+            .with_partition_id_to_execute(partition_ids.clone())
+            .prune_to_partitions(&partition_ids);
+        if self.available_nodes.is_empty() {
+            return Err(CubeError::internal(
+                "No available nodes to run select".to_string(),
+            ));
+        }
+        let selected_node = self.node_selector.select(
+            &self.partitions[partition]
+                .iter()
+                .map(|p| p.get_id())
+                .collect::<Vec<_>>(),
+            &self.available_nodes,
+        );
+        // Selected node goes first and the rest are used to retry on corrupted responses
+        let nodes_in_order = vec![selected_node.clone()]
+            .into_iter()
+            .chain(
+                self.available_nodes
+                    .iter()
+                    .filter(|n| *n != &selected_node)
+                    .cloned(),
+            )
+            .collect::<Vec<_>>();
+        let mut nodes = nodes_in_order.iter();
+        let (node, input) = loop {
+            let node = nodes.next().ok_or_else(|| {
+                CubeError::internal("No available nodes to run select".to_string())
+            })?;
+            match self
+                .cluster
+                .run_select_stream(node.clone(), plan.clone())
+                .await
+            {
+                Err(e) if e.is_corrupted_data() && nodes.len() > 0 => {
+                    warn!(
+                        "Retrying select of query {} on another node: {}",
+                        self.serialized_plan.query_id(),
+                        e
+                    );
+                }
+                res => break (node, res?),
+            }
+        };
+        self.stats
+            .lock()
+            .unwrap()
+            .add_select(partition_ids, node, 0);
+        Ok(ClusterSendStream {
+            input,
+            // TODO .to_schema_ref()
+            schema: self.schema.to_schema_ref(),
+            node: node.clone(),
+            stats: self.stats.clone(),
+            table_names: self
+                .serialized_plan
+                .index_snapshots()
+                .iter()
+                .map(|i| i.table_name())
+                .join(", "),
+        })
+    }
+}
+
+/// Batches of a worker select forwarded to the router plan one by one.
+struct ClusterSendStream {
+    input: SelectStream,
+    schema: SchemaRef,
+    node: String,
+    stats: Arc<Mutex<ExecutionStats>>,
+    /// Tables of the plan for schema drift errors.
+    table_names: String,
+}
+
+impl ClusterSendStream {
+    fn receive(&self, batch: RecordBatch) -> Result<RecordBatch, CubeError> {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.add_select(
+                Vec::new(),
+                &self.node,
+                batch
+                    .columns()
+                    .iter()
+                    .map(|c| c.get_array_memory_size() as u64)
+                    .sum(),
+            );
+            stats.add_received_batches(batch.num_rows() as u64, 1);
+        }
+        let mut batches = adapt_batches_to_schema(vec![batch], &self.schema).map_err(|e| {
+            CubeError::schema_drift(format!(
+                "{} for {}: {}",
+                SCHEMA_DRIFT_ERROR, self.table_names, e
+            ))
+        })?;
+        Ok(batches.remove(0))
+    }
+}
+
+impl Stream for ClusterSendStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            return match self.input.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(SelectItem::ScanStats(scan)))) => {
+                    self.stats.lock().unwrap().add_scan(&scan);
+                    continue;
+                }
+                Poll::Ready(Some(Ok(SelectItem::Batch(batch)))) => {
+                    Poll::Ready(Some(self.receive(batch).map_err(ArrowError::from)))
+                }
+                Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(e.into()))),
+                Poll::Ready(None) => Poll::Ready(None),
+                Poll::Pending => Poll::Pending,
+            };
         }
     }
 }
 
+impl RecordBatchStream for ClusterSendStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
 #[async_trait]
 impl ExecutionPlan for ClusterSendExec {
     fn as_any(&self) -> &dyn Any {
@@ -649,6 +2552,11 @@ impl ExecutionPlan for ClusterSendExec {
             cluster: self.cluster.clone(),
             available_nodes: self.available_nodes.clone(),
             serialized_plan: self.serialized_plan.clone(),
+            node_selector: self.node_selector.clone(),
+            best_effort: self.best_effort,
+            warnings: self.warnings.clone(),
+            stats: self.stats.clone(),
+            worker_plan: self.worker_plan.clone(),
         }))
     }
 
@@ -656,57 +2564,431 @@ impl ExecutionPlan for ClusterSendExec {
         &self,
         partition: usize,
     ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
-        let record_batches = self
-            .cluster
-            .run_select(
-                self.available_nodes[0].clone(), // TODO find node by partition
-                self.serialized_plan.with_partition_id_to_execute(
+        // Batches can't be taken back once forwarded so best effort selects skip only errors
+        // occurring before the node starts sending batches
+        match self.run_partition_select(partition).await {
+            // Schema drift is left to fail the query so it can be re-planned
+            Err(e) if self.best_effort && !e.is_schema_drift() => {
+                self.warnings.lock().unwrap().push(format!(
+                    "Partitions {:?} were skipped: {}",
                     self.partitions[partition]
                         .iter()
                         .map(|p| p.get_id())
-                        .collect(),
-                ),
-            )
-            .await?;
-        // TODO .to_schema_ref()
-        let memory_exec =
-            MemoryExec::try_new(&vec![record_batches], self.schema.to_schema_ref(), None)?;
-        memory_exec.execute(0).await
+                        .collect::<Vec<_>>(),
+                    e
+                ));
+                // TODO .to_schema_ref()
+                let schema = self.schema.to_schema_ref();
+                MemoryExec::try_new(&vec![Vec::new()], schema, None)?
+                    .execute(0)
+                    .await
+            }
+            res => Ok(Box::pin(res?)),
+        }
     }
 }
 
-impl fmt::Debug for ClusterSendExec {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
-        f.write_fmt(format_args!(
-            "ClusterSendExec: {:?}: {:?}",
-            self.schema, self.partitions
-        ))
-    }
+/// Inclusive range of the first join column values in a partition, `None` bounds are unbounded.
+#[derive(Debug, Clone)]
+struct JoinKeyRange {
+    min: Option<TableValue>,
+    max: Option<TableValue>,
 }
 
-impl TableProvider for CubeTable {
-    fn as_any(&self) -> &dyn Any {
-        self
+impl JoinKeyRange {
+    /// Defined only if partitions of the index are sorted by the first join column.
+    fn for_partition(index: &IndexSnapshot, partition: &IdRow<Partition>) -> Option<Self> {
+        let first_join_column = index.join_on()?.first()?;
+        let first_index_column = index.index().get_row().get_columns().first()?;
+        if first_index_column.get_name() != first_join_column {
+            return None;
+        }
+        // Decimals are stored as strings in partition bounds and don't keep numeric order
+        if let ColumnType::Decimal { .. } = first_index_column.get_column_type() {
+            return None;
+        }
+        let first_value =
+            |row: &Option<Row>| row.as_ref().and_then(|r| r.values().first().cloned());
+        Some(JoinKeyRange {
+            min: first_value(partition.get_row().get_min_val()),
+            max: first_value(partition.get_row().get_max_val()),
+        })
     }
 
-    fn schema(&self) -> SchemaRef {
-        self.schema.clone()
+    /// Unknown ranges are considered intersecting with anything.
+    fn intersect(a: &Option<Self>, b: &Option<Self>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => !Self::is_less(&a.max, &b.min) && !Self::is_less(&b.max, &a.min),
+            _ => true,
+        }
+    }
+
+    fn is_less(a: &Option<TableValue>, b: &Option<TableValue>) -> bool {
+        match (a, b) {
+            (Some(a), Some(b)) => std::mem::discriminant(a) == std::mem::discriminant(b) && a < b,
+            _ => false,
+        }
+    }
+}
+
+const SCHEMA_DRIFT_ERROR: &str = "Worker batches schema doesn't match router plan schema";
+
+/// Branches of `UNION ALL` are executed separately and their scans produce columns in the order of
+/// the index they read, so branches that are bare table scans having the same columns in another
+/// order are matched by name. Other branches are matched by position as SQL requires. Columns are named and typed as in the first branch as
+/// the plans above the union are, so columns of other branches are cast to the first branch type
+/// if it's wider, e.g. integers to floats. Nullability is relaxed to fit all branches.
+fn check_union_schemas(children: &[Arc<dyn ExecutionPlan>]) -> Result<UnionSchema, CubeError> {
+    let first = children[0].schema().to_schema_ref();
+    let mut fields = first.fields().clone();
+    let mut positions = Vec::with_capacity(children.len());
+    for (i, child) in children.iter().enumerate() {
+        let schema = child.schema().to_schema_ref();
+        if schema.fields().len() != first.fields().len() {
+            return Err(CubeError::user(format!(
+                "UNION ALL branch {} has {} columns while the first one has {}",
+                i + 1,
+                schema.fields().len(),
+                first.fields().len()
+            )));
+        }
+        let branch_positions = if is_table_scan(&children[0]) && is_table_scan(child) {
+            union_branch_positions(&first, &schema)
+        } else {
+            (0..first.fields().len()).collect()
+        };
+        for (field, position) in fields.iter_mut().zip(branch_positions.iter()) {
+            let branch_field = schema.field(*position);
+            let data_type = if branch_field.data_type() == field.data_type()
+                || is_dictionary_of(branch_field.data_type(), field.data_type())
+            {
+                field.data_type().clone()
+            } else if is_dictionary_of(field.data_type(), branch_field.data_type()) {
+                branch_field.data_type().clone()
+            } else if coerced_type(&[field.data_type().clone(), branch_field.data_type().clone()])
+                .as_ref()
+                == Some(field.data_type())
+            {
+                field.data_type().clone()
+            } else {
+                return Err(CubeError::user(format!(
+                    "UNION ALL column '{}' is {:?} in branch {} and can't be converted to {:?} of \
+                     the first one, cast it explicitly",
+                    field.name(),
+                    branch_field.data_type(),
+                    i + 1,
+                    field.data_type()
+                )));
+            };
+            *field = Field::new(
+                field.name(),
+                data_type,
+                field.is_nullable() || branch_field.is_nullable(),
+            );
+        }
+        positions.push(branch_positions);
+    }
+    let schema = Arc::new(Schema::new(fields));
+    let columns = children
+        .iter()
+        .zip(positions.into_iter())
+        .map(|(child, positions)| {
+            let child_schema = child.schema().to_schema_ref();
+            let matches = positions.iter().enumerate().all(|(i, p)| i == *p)
+                && child_schema
+                    .fields()
+                    .iter()
+                    .zip(schema.fields().iter())
+                    .all(|(c, u)| {
+                        c.data_type() == u.data_type() && c.is_nullable() == u.is_nullable()
+                    });
+            if matches {
+                None
+            } else {
+                Some(positions)
+            }
+        })
+        .collect();
+    Ok(UnionSchema { schema, columns })
+}
+
+/// Columns of the plan are in the order of the index it scans rather than the order of the query.
+fn is_table_scan(plan: &Arc<dyn ExecutionPlan>) -> bool {
+    plan.as_any().downcast_ref::<CubeTableExec>().is_some()
+}
+
+/// Positions of the `first` branch columns in `branch` if it has the same column names in any
+/// order, positions of the same index otherwise.
+fn union_branch_positions(first: &Schema, branch: &Schema) -> Vec<usize> {
+    let by_name = first
+        .fields()
+        .iter()
+        .map(|f| {
+            let mut matching = branch
+                .fields()
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| b.name() == f.name())
+                .map(|(i, _)| i);
+            match (matching.next(), matching.next()) {
+                (Some(i), None) => Some(i),
+                _ => None,
+            }
+        })
+        .collect::<Option<Vec<_>>>()
+        .filter(|p| p.iter().collect::<HashSet<_>>().len() == p.len());
+    by_name.unwrap_or_else(|| (0..first.fields().len()).collect())
+}
+
+#[derive(Debug)]
+struct UnionSchema {
+    schema: SchemaRef,
+    /// Positions of the union columns in each branch, `None` if the branch matches the schema.
+    columns: Vec<Option<Vec<usize>>>,
+}
+
+/// Wraps branches of each `UNION ALL` of the plan which don't match the union schema with
+/// `SchemaAdapterExec`.
+fn reconcile_union_schemas(
+    plan: Arc<dyn ExecutionPlan>,
+) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+    let children = plan.children();
+    if children.is_empty() {
+        return Ok(plan);
+    }
+    let mut new_children = children
+        .iter()
+        .map(|c| reconcile_union_schemas(c.clone()))
+        .collect::<Result<Vec<_>, _>>()?;
+    if plan.as_any().downcast_ref::<UnionExec>().is_some() {
+        let union_schema = check_union_schemas(&new_children)?;
+        let schema = union_schema.schema.to_dfschema_ref()?;
+        new_children = new_children
+            .into_iter()
+            .zip(union_schema.columns.into_iter())
+            .map(|(child, columns)| -> Arc<dyn ExecutionPlan> {
+                match columns {
+                    Some(columns) => {
+                        Arc::new(SchemaAdapterExec::new(child, schema.clone(), columns))
+                    }
+                    None => child,
+                }
+            })
+            .collect();
+    }
+    if new_children
+        .iter()
+        .zip(children.iter())
+        .all(|(n, c)| Arc::ptr_eq(n, c))
+    {
+        return Ok(plan);
+    }
+    Ok(plan.with_new_children(new_children)?)
+}
+
+/// Projects the input to `schema`. `columns` are positions of the schema columns in the input.
+/// Dictionary encoded columns are decoded if the schema has their value type and numbers are
+/// widened to the schema type.
+#[derive(Debug)]
+pub struct SchemaAdapterExec {
+    input: Arc<dyn ExecutionPlan>,
+    schema: DFSchemaRef,
+    columns: Vec<usize>,
+}
+
+impl SchemaAdapterExec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        schema: DFSchemaRef,
+        columns: Vec<usize>,
+    ) -> SchemaAdapterExec {
+        SchemaAdapterExec {
+            input,
+            schema,
+            columns,
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for SchemaAdapterExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> DFSchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Internal(
+                "SchemaAdapterExec expects a single child".to_string(),
+            ));
+        }
+        Ok(Arc::new(SchemaAdapterExec::new(
+            children[0].clone(),
+            self.schema.clone(),
+            self.columns.clone(),
+        )))
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
+        Ok(Box::pin(SchemaAdapterStream {
+            input: self.input.execute(partition).await?,
+            schema: self.schema.to_schema_ref(),
+            columns: self.columns.clone(),
+        }))
+    }
+}
+
+struct SchemaAdapterStream {
+    input: Pin<Box<dyn RecordBatchStream + Send>>,
+    schema: SchemaRef,
+    columns: Vec<usize>,
+}
+
+impl Stream for SchemaAdapterStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let schema = self.schema.clone();
+        let columns = self.columns.clone();
+        self.input.poll_next_unpin(cx).map(|item| {
+            item.map(|batch| {
+                let batch = batch?;
+                let arrays = schema
+                    .fields()
+                    .iter()
+                    .zip(columns.iter())
+                    .map(|(field, i)| {
+                        let column = batch.column(*i);
+                        cast_to(column, field.data_type())
+                            .map_err(|e| ArrowError::ExternalError(Box::new(e)))
+                    })
+                    .collect::<ArrowResult<Vec<_>>>()?;
+                RecordBatch::try_new(schema, arrays)
+            })
+        })
+    }
+}
+
+impl RecordBatchStream for SchemaAdapterStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+/// Adapts batches received from a worker to the schema expected by the router plan.
+/// Only differences in column order, nullability and dictionary encoding can be adapted.
+pub fn adapt_batches_to_schema(
+    batches: Vec<RecordBatch>,
+    schema: &SchemaRef,
+) -> Result<Vec<RecordBatch>, String> {
+    batches
+        .into_iter()
+        .map(|batch| {
+            if batch.schema().as_ref() == schema.as_ref() {
+                return Ok(batch);
+            }
+            let batch_schema = batch.schema();
+            let incompatible = || {
+                format!(
+                    "expected {:?} but found {:?}",
+                    schema.fields(),
+                    batch_schema.fields()
+                )
+            };
+            if batch_schema.fields().len() != schema.fields().len() {
+                return Err(incompatible());
+            }
+            let columns = schema
+                .fields()
+                .iter()
+                .map(|field| {
+                    let i = batch_schema
+                        .fields()
+                        .iter()
+                        .position(|f| {
+                            f.name() == field.name()
+                                && (f.data_type() == field.data_type()
+                                    || is_dictionary_of(f.data_type(), field.data_type()))
+                        })
+                        .ok_or_else(incompatible)?;
+                    decode_dictionary(batch.column(i)).map_err(|e| e.to_string())
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            RecordBatch::try_new(schema.clone(), columns).map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+fn is_dictionary_of(data_type: &DataType, value_type: &DataType) -> bool {
+    match data_type {
+        DataType::Dictionary(_, v) => v.as_ref() == value_type,
+        _ => false,
+    }
+}
+
+/// Values of dictionary encoded arrays, other arrays as is.
+fn decode_dictionary(array: &ArrayRef) -> Result<ArrayRef, ArrowError> {
+    match array.data_type() {
+        DataType::Dictionary(_, value_type) => cast(array, value_type),
+        _ => Ok(array.clone()),
+    }
+}
+
+impl fmt::Debug for ClusterSendExec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> Result<(), fmt::Error> {
+        f.write_fmt(format_args!(
+            "ClusterSendExec: {:?}: {:?}",
+            self.schema, self.partitions
+        ))
+    }
+}
+
+impl TableProvider for CubeTable {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
     }
 
     fn scan(
         &self,
         projection: &Option<Vec<usize>>,
         batch_size: usize,
-        _filters: &[Expr],
+        filters: &[Expr],
     ) -> DFResult<Arc<dyn ExecutionPlan>> {
-        let res = self.async_scan(projection, batch_size)?;
+        let res = self.async_scan(projection, batch_size, filters)?;
         Ok(res)
     }
 
+    fn supports_filter_pushdown(&self, _filter: &Expr) -> DFResult<TableProviderFilterPushDown> {
+        // Filters are only used to skip partitions and chunks so they are still applied to
+        // scanned rows by the filter above the scan which is a part of the worker plan
+        Ok(TableProviderFilterPushDown::Inexact)
+    }
+
     fn statistics(&self) -> Statistics {
-        // TODO
         Statistics {
-            num_rows: None,
+            num_rows: Some(self.index_snapshot.row_count() as usize),
             total_byte_size: None,
             column_statistics: None,
         }
@@ -714,18 +2996,14 @@ impl TableProvider for CubeTable {
 }
 
 macro_rules! convert_array {
-    ($ARRAY:expr, $NUM_ROWS:expr, $ROWS:expr, $ARRAY_TYPE: ident, Decimal, $SCALE: expr, $CUT_TRAILING_ZEROS: expr) => {{
+    ($ARRAY:expr, $NUM_ROWS:expr, $ROWS:expr, $ARRAY_TYPE: ident, Decimal, $SCALE: expr) => {{
         let a = $ARRAY.as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
         for i in 0..$NUM_ROWS {
             $ROWS[i].push(if a.is_null(i) {
                 TableValue::Null
             } else {
-                let decimal = BigDecimal::new(BigInt::from(a.value(i) as i64), $SCALE).to_string();
-                TableValue::Decimal(
-                    $CUT_TRAILING_ZEROS
-                        .replace(&decimal.to_string(), "$1$3")
-                        .to_string(),
-                )
+                let decimal = BigDecimal::new(BigInt::from(a.value(i)), $SCALE).to_string();
+                TableValue::Decimal(cut_trailing_zeros(&decimal))
             });
         }
     }};
@@ -741,168 +3019,295 @@ macro_rules! convert_array {
     }};
 }
 
-pub fn batch_to_dataframe(batches: &Vec<RecordBatch>) -> Result<DataFrame, CubeError> {
-    let mut cols = vec![];
-    let mut all_rows = vec![];
-
-    for batch in batches.iter() {
-        if cols.len() == 0 {
-            let schema = batch.schema().clone();
-            for (i, field) in schema.fields().iter().enumerate() {
-                cols.push(Column::new(
-                    field.name().clone(),
-                    arrow_to_column_type(field.data_type().clone())?,
-                    i,
-                ));
-            }
+macro_rules! convert_timestamp_array {
+    ($ARRAY:expr, $NUM_ROWS:expr, $ROWS:expr, $ARRAY_TYPE: ident, $UNIT: expr, $COLUMN_NAME: expr) => {{
+        let a = $ARRAY.as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
+        for i in 0..$NUM_ROWS {
+            $ROWS[i].push(if a.is_null(i) {
+                TableValue::Null
+            } else {
+                let timestamp =
+                    TimestampValue::from_arrow(a.value(i), &$UNIT).ok_or_else(|| {
+                        CubeError::user(format!(
+                            "Timestamp {} of type {:?} in column '{}' is out of range",
+                            a.value(i),
+                            $ARRAY.data_type(),
+                            $COLUMN_NAME
+                        ))
+                    })?;
+                TableValue::Timestamp(timestamp)
+            });
         }
-        if batch.num_rows() == 0 {
-            continue;
+    }};
+}
+
+pub fn batch_to_dataframe(batches: &Vec<RecordBatch>) -> Result<DataFrame, CubeError> {
+    let cols = match batches.first() {
+        Some(batch) => batch_columns(batch.schema().as_ref())?,
+        None => vec![],
+    };
+    // Rows are converted by column positions so a union of different schemas would be garbled
+    if let Some(first) = batches.first() {
+        let same_fields = |a: &Schema, b: &Schema| {
+            a.fields().len() == b.fields().len()
+                && a.fields()
+                    .iter()
+                    .zip(b.fields().iter())
+                    .all(|(a, b)| a.name() == b.name() && a.data_type() == b.data_type())
+        };
+        if let Some((i, batch)) = batches
+            .iter()
+            .enumerate()
+            .find(|(_, b)| !same_fields(first.schema().as_ref(), b.schema().as_ref()))
+        {
+            return Err(CubeError::internal(format!(
+                "Batch {} schema {:?} doesn't match schema of the first batch {:?}",
+                i,
+                batch.schema().fields(),
+                first.schema().fields()
+            )));
         }
-        let mut rows = vec![];
+    }
+    let rows = batches_to_rows(batches).collect::<Result<Vec<_>, _>>()?;
+    Ok(DataFrame::new(cols, rows))
+}
+
+pub fn batch_columns(schema: &Schema) -> Result<Vec<Column>, CubeError> {
+    schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            Ok(Column::new(
+                field.name().clone(),
+                arrow_to_column_type(field.data_type().clone())?,
+                i,
+            ))
+        })
+        .collect()
+}
 
-        for _ in 0..batch.num_rows() {
-            rows.push(Row::new(Vec::with_capacity(batch.num_columns())));
+/// Yields rows of `batches` lazily so results can be written out without materializing a
+/// `DataFrame`. Only rows of the batch being read are held in memory.
+pub fn batches_to_rows(batches: &[RecordBatch]) -> BatchRowsIter<'_> {
+    BatchRowsIter {
+        batches: batches.iter(),
+        rows: Vec::new().into_iter(),
+    }
+}
+
+pub struct BatchRowsIter<'a> {
+    batches: std::slice::Iter<'a, RecordBatch>,
+    rows: std::vec::IntoIter<Row>,
+}
+
+impl<'a> Iterator for BatchRowsIter<'a> {
+    type Item = Result<Row, CubeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Some(Ok(row));
+            }
+            match batch_to_rows(self.batches.next()?) {
+                Ok(rows) => self.rows = rows.into_iter(),
+                Err(e) => {
+                    self.batches = (&[]).iter();
+                    return Some(Err(e));
+                }
+            }
         }
+    }
+}
 
-        let cut_trailing_zeros = Regex::new(r"^(-?\d+\.[1-9]+)([0]+)$|^(-?\d+)(\.[0]+)$").unwrap();
+lazy_static! {
+    static ref TRAILING_ZEROS: Regex = Regex::new(r"^(-?\d+)(?:(\.\d*[1-9])0*|\.0*)$").unwrap();
+}
 
-        for column_index in 0..batch.num_columns() {
-            let array = batch.column(column_index);
-            let num_rows = batch.num_rows();
-            match array.data_type() {
-                DataType::UInt64 => convert_array!(array, num_rows, rows, UInt64Array, Int, i64),
-                DataType::Int64 => convert_array!(array, num_rows, rows, Int64Array, Int, i64),
-                DataType::Float64 => {
-                    let a = array.as_any().downcast_ref::<Float64Array>().unwrap();
-                    for i in 0..num_rows {
-                        rows[i].push(if a.is_null(i) {
-                            TableValue::Null
-                        } else {
-                            let decimal = BigDecimal::try_from(a.value(i) as f64)?;
-                            TableValue::Decimal(
-                                cut_trailing_zeros
-                                    .replace(&decimal.to_string(), "$1$3")
-                                    .to_string(),
-                            )
-                        });
-                    }
+/// Drops zeros after the last significant fractional digit along with a dangling dot, so
+/// `5.000` becomes `5` and `-0.0` becomes `0`. Integers are left as is.
+fn cut_trailing_zeros(decimal: &str) -> String {
+    let result = TRAILING_ZEROS.replace(decimal, "$1$2");
+    if result == "-0" {
+        "0".to_string()
+    } else {
+        result.to_string()
+    }
+}
+
+fn float_to_decimal(value: f64, column_name: &str) -> Result<TableValue, CubeError> {
+    // NaN and infinities have no decimal representation
+    let decimal = BigDecimal::try_from(value).map_err(|e| {
+        CubeError::user(format!(
+            "Can't convert {} in column '{}' to decimal: {}",
+            value, column_name, e
+        ))
+    })?;
+    Ok(TableValue::Decimal(cut_trailing_zeros(
+        &decimal.to_string(),
+    )))
+}
+
+fn batch_to_rows(batch: &RecordBatch) -> Result<Vec<Row>, CubeError> {
+    if batch.num_rows() == 0 {
+        return Ok(vec![]);
+    }
+    let mut rows = vec![];
+
+    for _ in 0..batch.num_rows() {
+        rows.push(Row::new(Vec::with_capacity(batch.num_columns())));
+    }
+
+    for column_index in 0..batch.num_columns() {
+        // Low cardinality columns can be dictionary encoded, values are resolved here
+        let array = decode_dictionary(batch.column(column_index))?;
+        let num_rows = batch.num_rows();
+        let column_name = batch.schema().field(column_index).name().clone();
+        match array.data_type() {
+            DataType::UInt64 => convert_array!(array, num_rows, rows, UInt64Array, Int, i64),
+            DataType::Int64 => convert_array!(array, num_rows, rows, Int64Array, Int, i64),
+            DataType::Float64 => {
+                let a = array.as_any().downcast_ref::<Float64Array>().unwrap();
+                for i in 0..num_rows {
+                    rows[i].push(if a.is_null(i) {
+                        TableValue::Null
+                    } else {
+                        float_to_decimal(a.value(i), &column_name)?
+                    });
                 }
-                DataType::Int64Decimal(0) => convert_array!(
-                    array,
-                    num_rows,
-                    rows,
-                    Int64Decimal0Array,
-                    Decimal,
-                    0,
-                    cut_trailing_zeros
-                ),
-                DataType::Int64Decimal(1) => convert_array!(
-                    array,
-                    num_rows,
-                    rows,
-                    Int64Decimal1Array,
-                    Decimal,
-                    1,
-                    cut_trailing_zeros
-                ),
-                DataType::Int64Decimal(2) => convert_array!(
-                    array,
-                    num_rows,
-                    rows,
-                    Int64Decimal2Array,
-                    Decimal,
-                    2,
-                    cut_trailing_zeros
-                ),
-                DataType::Int64Decimal(3) => convert_array!(
-                    array,
-                    num_rows,
-                    rows,
-                    Int64Decimal3Array,
-                    Decimal,
-                    3,
-                    cut_trailing_zeros
-                ),
-                DataType::Int64Decimal(4) => convert_array!(
+            }
+            // Widening is exact so values are shown as stored: half floats keep about 3
+            // significant digits and e.g. 0.1 becomes 0.0999755859375
+            DataType::Float16 => {
+                let a = array.as_any().downcast_ref::<Float16Array>().unwrap();
+                for i in 0..num_rows {
+                    rows[i].push(if a.is_null(i) {
+                        TableValue::Null
+                    } else {
+                        float_to_decimal(a.value(i).to_f64(), &column_name)?
+                    });
+                }
+            }
+            DataType::Int64Decimal(0) => {
+                convert_array!(array, num_rows, rows, Int64Decimal0Array, Decimal, 0)
+            }
+            DataType::Int64Decimal(1) => {
+                convert_array!(array, num_rows, rows, Int64Decimal1Array, Decimal, 1)
+            }
+            DataType::Int64Decimal(2) => {
+                convert_array!(array, num_rows, rows, Int64Decimal2Array, Decimal, 2)
+            }
+            DataType::Int64Decimal(3) => {
+                convert_array!(array, num_rows, rows, Int64Decimal3Array, Decimal, 3)
+            }
+            DataType::Int64Decimal(4) => {
+                convert_array!(array, num_rows, rows, Int64Decimal4Array, Decimal, 4)
+            }
+            DataType::Int64Decimal(5) => {
+                convert_array!(array, num_rows, rows, Int64Decimal5Array, Decimal, 5)
+            }
+            DataType::Int64Decimal(10) => {
+                convert_array!(array, num_rows, rows, Int64Decimal10Array, Decimal, 10)
+            }
+            DataType::Decimal(_, scale) => {
+                convert_array!(array, num_rows, rows, DecimalArray, Decimal, *scale as i64)
+            }
+            // Arrow timestamps are UTC instants and the time zone is only a display hint,
+            // so tz-annotated arrays are stored as UTC by dropping the annotation.
+            DataType::Timestamp(TimeUnit::Second, _) => {
+                convert_timestamp_array!(
                     array,
                     num_rows,
                     rows,
-                    Int64Decimal4Array,
-                    Decimal,
-                    4,
-                    cut_trailing_zeros
-                ),
-                DataType::Int64Decimal(5) => convert_array!(
+                    TimestampSecondArray,
+                    TimeUnit::Second,
+                    column_name
+                )
+            }
+            DataType::Timestamp(TimeUnit::Millisecond, _) => convert_timestamp_array!(
+                array,
+                num_rows,
+                rows,
+                TimestampMillisecondArray,
+                TimeUnit::Millisecond,
+                column_name
+            ),
+            DataType::Timestamp(TimeUnit::Microsecond, _) => {
+                convert_timestamp_array!(
                     array,
                     num_rows,
                     rows,
-                    Int64Decimal5Array,
-                    Decimal,
-                    5,
-                    cut_trailing_zeros
-                ),
-                DataType::Int64Decimal(10) => convert_array!(
+                    TimestampMicrosecondArray,
+                    TimeUnit::Microsecond,
+                    column_name
+                )
+            }
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                convert_timestamp_array!(
                     array,
                     num_rows,
                     rows,
-                    Int64Decimal10Array,
-                    Decimal,
-                    10,
-                    cut_trailing_zeros
-                ),
-                DataType::Timestamp(TimeUnit::Microsecond, None) => {
-                    let a = array
-                        .as_any()
-                        .downcast_ref::<TimestampMicrosecondArray>()
-                        .unwrap();
-                    for i in 0..num_rows {
-                        rows[i].push(if a.is_null(i) {
-                            TableValue::Null
-                        } else {
-                            TableValue::Timestamp(TimestampValue::new(a.value(i) * 1000 as i64))
-                        });
-                    }
+                    TimestampNanosecondArray,
+                    TimeUnit::Nanosecond,
+                    column_name
+                )
+            }
+            DataType::Utf8 => {
+                let a = array.as_any().downcast_ref::<StringArray>().unwrap();
+                for i in 0..num_rows {
+                    rows[i].push(if a.is_null(i) {
+                        TableValue::Null
+                    } else {
+                        TableValue::String(a.value(i).to_string())
+                    });
                 }
-                DataType::Timestamp(TimeUnit::Nanosecond, None) => {
-                    let a = array
-                        .as_any()
-                        .downcast_ref::<TimestampNanosecondArray>()
-                        .unwrap();
-                    for i in 0..num_rows {
-                        rows[i].push(if a.is_null(i) {
-                            TableValue::Null
-                        } else {
-                            TableValue::Timestamp(TimestampValue::new(a.value(i)))
-                        });
-                    }
+            }
+            DataType::Boolean => {
+                let a = array.as_any().downcast_ref::<BooleanArray>().unwrap();
+                for i in 0..num_rows {
+                    rows[i].push(if a.is_null(i) {
+                        TableValue::Null
+                    } else {
+                        TableValue::Boolean(a.value(i))
+                    });
                 }
-                DataType::Utf8 => {
-                    let a = array.as_any().downcast_ref::<StringArray>().unwrap();
-                    for i in 0..num_rows {
-                        rows[i].push(if a.is_null(i) {
-                            TableValue::Null
-                        } else {
-                            TableValue::String(a.value(i).to_string())
-                        });
-                    }
+            }
+            DataType::Interval(IntervalUnit::YearMonth) => {
+                let a = array
+                    .as_any()
+                    .downcast_ref::<IntervalYearMonthArray>()
+                    .unwrap();
+                for i in 0..num_rows {
+                    rows[i].push(if a.is_null(i) {
+                        TableValue::Null
+                    } else {
+                        TableValue::String(format_interval(a.value(i) as i64, 0, 0))
+                    });
                 }
-                DataType::Boolean => {
-                    let a = array.as_any().downcast_ref::<BooleanArray>().unwrap();
-                    for i in 0..num_rows {
-                        rows[i].push(if a.is_null(i) {
-                            TableValue::Null
-                        } else {
-                            TableValue::Boolean(a.value(i))
-                        });
-                    }
+            }
+            DataType::Interval(IntervalUnit::DayTime) => {
+                let a = array
+                    .as_any()
+                    .downcast_ref::<IntervalDayTimeArray>()
+                    .unwrap();
+                for i in 0..num_rows {
+                    rows[i].push(if a.is_null(i) {
+                        TableValue::Null
+                    } else {
+                        // Days are stored in the upper 32 bits and milliseconds in the lower
+                        let value = a.value(i);
+                        TableValue::String(format_interval(
+                            0,
+                            (value >> 32) as i32 as i64,
+                            value as i32 as i64,
+                        ))
+                    });
                 }
-                x => panic!("Unsupported data type: {:?}", x),
             }
+            x => panic!("Unsupported data type: {:?}", x),
         }
-        all_rows.append(&mut rows);
     }
-    Ok(DataFrame::new(cols, all_rows))
+    Ok(rows)
 }
 
 pub fn arrow_to_column_type(arrow_type: DataType) -> Result<ColumnType, CubeError> {
@@ -917,7 +3322,13 @@ pub fn arrow_to_column_type(arrow_type: DataType) -> Result<ColumnType, CubeErro
             scale: scale as i32,
             precision: 18,
         }),
+        DataType::Decimal(precision, scale) => Ok(ColumnType::Decimal {
+            scale: scale as i32,
+            precision: precision as i32,
+        }),
         DataType::Boolean => Ok(ColumnType::Boolean),
+        DataType::Interval(_) => Ok(ColumnType::String),
+        DataType::Dictionary(_, value_type) => arrow_to_column_type(*value_type),
         DataType::Int8
         | DataType::Int16
         | DataType::Int32
@@ -930,27 +3341,2877 @@ pub fn arrow_to_column_type(arrow_type: DataType) -> Result<ColumnType, CubeErro
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SerializedRecordBatchStream {
-    record_batch_file: Vec<u8>,
+/// Inverse of `batch_to_dataframe`. Arrow types are restored from column types the same way
+/// CubeStore stores them, so e.g. `Timestamp(Nanosecond)` comes back as `Timestamp(Microsecond)`.
+pub fn dataframe_to_batches(df: &DataFrame) -> Result<Vec<RecordBatch>, CubeError> {
+    if df.get_columns().is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut fields = Vec::with_capacity(df.get_columns().len());
+    let mut arrays = Vec::with_capacity(df.get_columns().len());
+    for column in df.get_columns().iter() {
+        let data_type = column_type_to_arrow(column.get_column_type());
+        arrays.push(column_to_array(df.get_rows(), column, &data_type)?);
+        fields.push(Field::new(column.get_name(), data_type, true));
+    }
+    Ok(vec![RecordBatch::try_new(
+        Arc::new(Schema::new(fields)),
+        arrays,
+    )?])
 }
 
-impl SerializedRecordBatchStream {
-    pub fn write(record_batches: Vec<RecordBatch>) -> Result<Self, CubeError> {
-        let file = Vec::new();
-        let mut writer = MemStreamWriter::try_new(Cursor::new(file), &record_batches[0].schema())?;
-        for batch in record_batches.iter() {
-            writer.write(batch)?;
+/// Streams rows of `df` as a single batch. Used for results combined on the router that can't
+/// be streamed as they're produced.
+pub fn dataframe_to_stream(
+    df: &DataFrame,
+) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, CubeError> {
+    let batches = dataframe_to_batches(df)?;
+    let schema = match batches.first() {
+        Some(batch) => batch.schema(),
+        None => Arc::new(Schema::empty()),
+    };
+    Ok(Box::pin(BatchesStream {
+        schema,
+        batches: batches.into_iter(),
+    }))
+}
+
+struct BatchesStream {
+    schema: SchemaRef,
+    batches: std::vec::IntoIter<RecordBatch>,
+}
+
+impl Stream for BatchesStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Poll::Ready(self.batches.next().map(Ok))
+    }
+}
+
+impl RecordBatchStream for BatchesStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+pub fn column_type_to_arrow(column_type: &ColumnType) -> DataType {
+    match column_type {
+        ColumnType::String => DataType::Utf8,
+        ColumnType::Int => DataType::Int64,
+        ColumnType::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, None),
+        ColumnType::Boolean => DataType::Boolean,
+        ColumnType::Bytes => DataType::Binary,
+        ColumnType::Decimal { scale, precision } if *precision > 18 => {
+            DataType::Decimal(*precision as usize, *scale as usize)
         }
-        let cursor = writer.finish()?;
-        Ok(Self {
-            record_batch_file: cursor.into_inner(),
-        })
+        ColumnType::Decimal { .. } => DataType::Int64Decimal(column_type.target_scale() as usize),
     }
+}
 
-    pub fn read(self) -> Result<Vec<RecordBatch>, CubeError> {
+fn column_values<'a, T>(
+    rows: &'a Vec<Row>,
+    column: &Column,
+    convert: impl Fn(&'a TableValue) -> Option<Result<T, CubeError>>,
+) -> Result<Vec<Option<T>>, CubeError> {
+    rows.iter()
+        .map(|row| match &row.values()[column.get_index()] {
+            TableValue::Null => Ok(None),
+            value => convert(value)
+                .ok_or_else(|| {
+                    CubeError::internal(format!("Unexpected value for {}: {:?}", column, value))
+                })?
+                .map(Some),
+        })
+        .collect()
+}
+
+fn scaled_decimal(value: &str, scale: i64) -> Result<BigInt, CubeError> {
+    Ok(BigDecimal::from_str_radix(value, 10)?
+        .with_scale(scale)
+        .as_bigint_and_exponent()
+        .0)
+}
+
+fn decimal_to_i64(value: &TableValue, scale: i64) -> Option<Result<i64, CubeError>> {
+    match value {
+        TableValue::Decimal(v) => Some(scaled_decimal(v, scale).and_then(|d| {
+            d.to_i64()
+                .ok_or_else(|| CubeError::internal(format!("Can't convert to i64 decimal: {}", v)))
+        })),
+        _ => None,
+    }
+}
+
+fn column_to_array(
+    rows: &Vec<Row>,
+    column: &Column,
+    data_type: &DataType,
+) -> Result<Arc<dyn Array>, CubeError> {
+    Ok(match data_type {
+        DataType::Utf8 => Arc::new(StringArray::from(column_values(
+            rows,
+            column,
+            |v| match v {
+                TableValue::String(s) => Some(Ok(s.as_str())),
+                _ => None,
+            },
+        )?)),
+        DataType::Int64 => Arc::new(Int64Array::from(column_values(
+            rows,
+            column,
+            |v| match v {
+                TableValue::Int(i) => Some(Ok(*i)),
+                _ => None,
+            },
+        )?)),
+        DataType::Timestamp(TimeUnit::Microsecond, None) => Arc::new(
+            TimestampMicrosecondArray::from(column_values(rows, column, |v| match v {
+                TableValue::Timestamp(t) => Some(Ok(t.get_time_stamp() / 1000)),
+                _ => None,
+            })?),
+        ),
+        DataType::Boolean => Arc::new(BooleanArray::from(column_values(
+            rows,
+            column,
+            |v| match v {
+                TableValue::Boolean(b) => Some(Ok(*b)),
+                _ => None,
+            },
+        )?)),
+        DataType::Binary => Arc::new(BinaryArray::from(column_values(
+            rows,
+            column,
+            |v| match v {
+                TableValue::Bytes(b) => Some(Ok(b.as_slice())),
+                _ => None,
+            },
+        )?)),
+        DataType::Int64Decimal(scale) => {
+            let values = column_values(rows, column, |v| decimal_to_i64(v, *scale as i64))?;
+            match scale {
+                0 => Arc::new(Int64Decimal0Array::from(values)),
+                1 => Arc::new(Int64Decimal1Array::from(values)),
+                2 => Arc::new(Int64Decimal2Array::from(values)),
+                3 => Arc::new(Int64Decimal3Array::from(values)),
+                4 => Arc::new(Int64Decimal4Array::from(values)),
+                5 => Arc::new(Int64Decimal5Array::from(values)),
+                10 => Arc::new(Int64Decimal10Array::from(values)),
+                x => {
+                    return Err(CubeError::internal(format!(
+                        "Unsupported Int64Decimal scale of {}: {}",
+                        column, x
+                    )))
+                }
+            }
+        }
+        DataType::Decimal(precision, scale) => {
+            let values = column_values(rows, column, |v| match v {
+                TableValue::Decimal(d) => Some(scaled_decimal(d, *scale as i64).and_then(|d| {
+                    d.to_i128().ok_or_else(|| {
+                        CubeError::internal(format!("Can't convert to i128 decimal: {}", d))
+                    })
+                })),
+                _ => None,
+            })?;
+            let mut builder = DecimalBuilder::new(values.len(), *precision, *scale);
+            for value in values {
+                match value {
+                    Some(v) => builder.append_value(v)?,
+                    None => builder.append_null()?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        x => {
+            return Err(CubeError::internal(format!(
+                "Unsupported data type of {}: {:?}",
+                column, x
+            )))
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SerializedRecordBatchStream {
+    /// Should always go first so it can be read before the rest of the payload.
+    format_version: u32,
+    record_batch_file: Vec<u8>,
+    /// CRC32 of `record_batch_file`. Written by every format version.
+    checksum: u32,
+    /// Fingerprint of the schema batches were written with. Written by every format version.
+    schema_fingerprint: u32,
+    #[serde(default, with = "wire_format::since_v2")]
+    scan_stats: ScanStats,
+}
+
+impl SerializedRecordBatchStream {
+    /// The schema is written in the stream header so it's known even if there are no batches.
+    pub fn write(
+        schema: &SchemaRef,
+        record_batches: Vec<RecordBatch>,
+        format_version: u32,
+    ) -> Result<Self, CubeError> {
+        check_wire_format_version(format_version, "SerializedRecordBatchStream")?;
+        let file = Vec::new();
+        let mut writer = MemStreamWriter::try_new(Cursor::new(file), schema)?;
+        for batch in record_batches.iter() {
+            writer.write(batch)?;
+        }
+        let cursor = writer.finish()?;
+        let record_batch_file = cursor.into_inner();
+        Ok(Self {
+            format_version,
+            checksum: crc32fast::hash(&record_batch_file),
+            schema_fingerprint: Self::schema_fingerprint(schema),
+            record_batch_file,
+            scan_stats: ScanStats::default(),
+        })
+    }
+
+    /// Stats of the scans producing the batches for the router to report them.
+    pub fn with_scan_stats(self, scan_stats: ScanStats) -> Self {
+        Self { scan_stats, ..self }
+    }
+
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    pub fn scan_stats(&self) -> &ScanStats {
+        &self.scan_stats
+    }
+
+    /// Same as `write` but bytes of the stream are flushed to `sink` as batches are encoded
+    /// instead of being buffered, so large results can be sent without holding them in memory.
+    /// Bytes written to `sink` are what `write` puts into `record_batch_file`.
+    pub fn write_to<W: Write>(
+        schema: &SchemaRef,
+        record_batches: impl IntoIterator<Item = RecordBatch>,
+        format_version: u32,
+        sink: W,
+    ) -> Result<StreamedRecordBatches, CubeError> {
+        check_wire_format_version(format_version, "SerializedRecordBatchStream")?;
+        let mut sink = ChecksumWriter {
+            inner: sink,
+            hasher: crc32fast::Hasher::new(),
+            bytes_written: 0,
+        };
+        {
+            let mut writer = StreamWriter::try_new(&mut sink, schema)?;
+            for batch in record_batches {
+                writer.write(&batch)?;
+            }
+            writer.finish()?;
+        }
+        sink.flush()?;
+        Ok(StreamedRecordBatches {
+            format_version,
+            checksum: sink.hasher.finalize(),
+            schema_fingerprint: Self::schema_fingerprint(schema),
+            bytes_written: sink.bytes_written,
+        })
+    }
+
+    pub fn read(self, node_name: &str) -> Result<Vec<RecordBatch>, CubeError> {
+        check_wire_format_version(self.format_version, "SerializedRecordBatchStream")?;
+        let actual = crc32fast::hash(&self.record_batch_file);
+        if actual != self.checksum {
+            return Err(CubeError::corrupted_data(format!(
+                "Checksum mismatch for record batches received from node {}: expected {:x} but found {:x}",
+                node_name, self.checksum, actual
+            )));
+        }
         let cursor = Cursor::new(self.record_batch_file);
-        let reader = StreamReader::try_new(cursor)?;
+        let reader = StreamReader::try_new(cursor).map_err(|e| {
+            CubeError::corrupted_data(format!(
+                "Can't read record batches received from node {}: {}",
+                node_name, e
+            ))
+        })?;
+        let actual = Self::schema_fingerprint(&reader.schema());
+        if actual != self.schema_fingerprint {
+            return Err(CubeError::corrupted_data(format!(
+                "Schema fingerprint mismatch for record batches received from node {}: expected {:x} but found {:x}",
+                node_name, self.schema_fingerprint, actual
+            )));
+        }
         Ok(reader.collect::<Result<Vec<_>, _>>()?)
     }
+
+    pub fn schema(&self) -> Result<SchemaRef, CubeError> {
+        let reader = StreamReader::try_new(Cursor::new(self.record_batch_file.as_slice()))
+            .map_err(|e| {
+                CubeError::corrupted_data(format!("Can't read record batches schema: {}", e))
+            })?;
+        Ok(reader.schema())
+    }
+
+    fn schema_fingerprint(schema: &SchemaRef) -> u32 {
+        let mut hasher = crc32fast::Hasher::new();
+        for field in schema.fields() {
+            hasher.update(field.name().as_bytes());
+            hasher.update(format!("{:?}", field.data_type()).as_bytes());
+            hasher.update(&[field.is_nullable() as u8]);
+        }
+        hasher.finalize()
+    }
+}
+
+/// Header of record batches written by `SerializedRecordBatchStream::write_to`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamedRecordBatches {
+    pub format_version: u32,
+    pub checksum: u32,
+    pub schema_fingerprint: u32,
+    pub bytes_written: u64,
+}
+
+struct ChecksumWriter<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+    bytes_written: u64,
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.bytes_written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::MockCluster;
+    use crate::config::{start_capturing_test_logs, take_captured_test_logs, Config};
+    use crate::metastore::table::TablePath;
+    use crate::metastore::Chunk;
+    use crate::metastore::Schema as MetaSchema;
+    use crate::table::parquet::ParquetTableStore;
+    use crate::table::TableStore;
+    use arrow::array::DictionaryArray;
+    use arrow::datatypes::Int32Type;
+    use datafusion::logical_plan::col;
+    use half::f16;
+    use parquet::arrow::ArrowWriter;
+    use parquet::basic::Compression;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use std::ops::Range;
+    use std::{env, fs};
+
+    fn test_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]))
+    }
+
+    fn test_batches() -> Vec<RecordBatch> {
+        vec![RecordBatch::try_new(
+            test_schema(),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2, 3])),
+                Arc::new(StringArray::from(vec![Some("a"), None, Some("c")])),
+            ],
+        )
+        .unwrap()]
+    }
+
+    fn test_columns() -> Vec<Column> {
+        vec![
+            Column::new("id".to_string(), ColumnType::Int, 0),
+            Column::new("name".to_string(), ColumnType::String, 1),
+        ]
+    }
+
+    /// Rows of the test index with ids from `ids`.
+    fn test_rows(ids: Range<i64>) -> Vec<Row> {
+        ids.map(|i| {
+            Row::new(vec![
+                TableValue::Int(i),
+                TableValue::String(format!("n{}", i)),
+            ])
+        })
+        .collect()
+    }
+
+    /// Table of a single partition with a chunk of each of `chunks`, ids of chunks start from 7.
+    /// Chunk files have the columns of the test index and row groups of 10 rows while `columns`
+    /// of the table may have more. Paths of the files are returned to remove them after the test.
+    fn single_partition_table(
+        test_name: &str,
+        columns: Vec<Column>,
+        chunks: Vec<Vec<Row>>,
+    ) -> (CubeTable, Vec<String>) {
+        let store = ParquetTableStore::new(
+            SerializedPlan::index_snapshot_for_test(test_columns(), Vec::new())
+                .index()
+                .get_row()
+                .clone(),
+            10,
+        );
+        let mut chunk_rows = Vec::new();
+        let mut remote_to_local_names = HashMap::new();
+        let mut paths = Vec::new();
+        for (i, rows) in chunks.into_iter().enumerate() {
+            let id = 7 + i as u64;
+            let path = env::temp_dir()
+                .join(format!("{}_{}.parquet", test_name, id))
+                .to_str()
+                .unwrap()
+                .to_string();
+            chunk_rows.push(IdRow::new(id, Chunk::new(1, rows.len() as u64)));
+            store.merge_rows(None, vec![path.clone()], rows, 1).unwrap();
+            remote_to_local_names.insert(format!("{}.chunk.parquet", id), path.clone());
+            paths.push(path);
+        }
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            chunk_rows,
+        )];
+        let table = CubeTable::try_new(
+            SerializedPlan::index_snapshot_for_test(columns, partitions),
+            remote_to_local_names,
+            vec![1].into_iter().collect(),
+            1,
+        )
+        .unwrap();
+        (table, paths)
+    }
+
+    fn remove_files(paths: Vec<String>) {
+        for path in paths {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    fn files_to_scan(table: &CubeTable, filters: &[Expr]) -> Vec<String> {
+        table
+            .data_to_scan(filters)
+            .unwrap()
+            .0
+            .into_iter()
+            .flatten()
+            .filter_map(|source| match source {
+                ScanSource::File(local_path) | ScanSource::ChunkFile(local_path, _) => {
+                    Some(local_path)
+                }
+                ScanSource::InMemory(..) => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn projection_matches_index_columns_ignoring_case() {
+        let index = IdRow::new(
+            1,
+            Index::try_new(
+                "default".to_string(),
+                1,
+                vec![
+                    Column::new("foo".to_string(), ColumnType::Int, 0),
+                    Column::new("Bar".to_string(), ColumnType::String, 1),
+                    Column::new("bar".to_string(), ColumnType::String, 2),
+                ],
+                1,
+            )
+            .unwrap(),
+        );
+        let projection = |names: &[&str]| {
+            names
+                .iter()
+                .enumerate()
+                .map(|(i, n)| Column::new(n.to_string(), ColumnType::Int, i))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            CubeTable::project_to_index_positions(&projection(&["FOO", "bar", "Bar"]), &index),
+            vec![Some(0), Some(2), Some(1)]
+        );
+        assert_eq!(
+            CubeTable::project_to_index_positions(&projection(&["baz"]), &index),
+            vec![None]
+        );
+    }
+
+    /// Matching of projected columns before positions were looked up by name.
+    fn linear_index_positions(
+        projection_columns: &Vec<Column>,
+        index_columns: &Vec<Column>,
+    ) -> Vec<Option<usize>> {
+        projection_columns
+            .iter()
+            .map(|pc| {
+                index_columns
+                    .iter()
+                    .position(|c| c.get_name() == pc.get_name())
+                    .or_else(|| {
+                        index_columns.iter().position(|c| {
+                            c.get_name().to_lowercase() == pc.get_name().to_lowercase()
+                        })
+                    })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn index_column_positions_match_linear_search() {
+        let names = (0..200)
+            .map(|i| match i % 4 {
+                0 => format!("col{}", i),
+                1 => format!("Col{}", i - 1),
+                2 => format!("COL{}", i - 2),
+                _ => format!("c{}", i / 8),
+            })
+            .collect::<Vec<_>>();
+        let index_columns = names
+            .iter()
+            .enumerate()
+            .map(|(i, n)| Column::new(n.to_string(), ColumnType::Int, i))
+            .collect::<Vec<_>>();
+        let index = Index::try_new("default".to_string(), 1, index_columns.clone(), 1).unwrap();
+        let projection = names
+            .iter()
+            .map(|n| n.to_string())
+            .chain(names.iter().map(|n| n.to_uppercase()))
+            .chain(names.iter().map(|n| n.to_lowercase()))
+            .chain(vec!["missing".to_string()])
+            .enumerate()
+            .map(|(i, n)| Column::new(n, ColumnType::Int, i))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            IndexColumnPositions::new(&index).project(&projection),
+            linear_index_positions(&projection, &index_columns)
+        );
+    }
+
+    #[test]
+    fn scan_skips_duplicate_files() {
+        let chunk = IdRow::new(7, Chunk::new(1, 10));
+        let partitions = vec![
+            PartitionSnapshot::new(
+                IdRow::new(1, Partition::new(1, None, None)),
+                vec![chunk.clone(), chunk.clone()],
+            ),
+            PartitionSnapshot::new(
+                IdRow::new(2, Partition::new(1, None, None)),
+                vec![IdRow::new(8, Chunk::new(2, 10))],
+            ),
+        ];
+        let remote_to_local_names = vec![
+            (
+                "7.chunk.parquet".to_string(),
+                "/local/7.chunk.parquet".to_string(),
+            ),
+            (
+                "8.chunk.parquet".to_string(),
+                "/local/7.chunk.parquet".to_string(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let table = CubeTable::try_new(
+            SerializedPlan::index_snapshot_for_test(test_columns(), partitions),
+            remote_to_local_names,
+            vec![1, 2].into_iter().collect(),
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            files_to_scan(&table, &[]),
+            vec!["/local/7.chunk.parquet".to_string()]
+        );
+    }
+
+    #[test]
+    fn scan_skips_chunks_by_stats() {
+        let chunk = |id: u64, min_max: Option<(i64, i64)>| {
+            let (min, max) = match min_max {
+                Some((min, max)) => (
+                    Some(Row::new(vec![TableValue::Int(min)])),
+                    Some(Row::new(vec![TableValue::Int(max)])),
+                ),
+                None => (None, None),
+            };
+            IdRow::new(id, Chunk::new(1, 10).with_min_max(min, max))
+        };
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            vec![
+                chunk(1, Some((1, 10))),
+                chunk(2, Some((11, 20))),
+                chunk(3, None),
+            ],
+        )];
+        let remote_to_local_names = (1..=3)
+            .map(|id| {
+                (
+                    format!("{}.chunk.parquet", id),
+                    format!("/local/{}.chunk.parquet", id),
+                )
+            })
+            .collect();
+        let table = CubeTable::try_new(
+            SerializedPlan::index_snapshot_for_test(test_columns(), partitions),
+            remote_to_local_names,
+            vec![1].into_iter().collect(),
+            1,
+        )
+        .unwrap();
+
+        let all = vec![
+            "/local/1.chunk.parquet".to_string(),
+            "/local/2.chunk.parquet".to_string(),
+            "/local/3.chunk.parquet".to_string(),
+        ];
+        assert_eq!(files_to_scan(&table, &[]), all);
+        assert_eq!(
+            files_to_scan(&table, &[col("id").gt_eq(lit(12i64))]),
+            vec![
+                "/local/2.chunk.parquet".to_string(),
+                "/local/3.chunk.parquet".to_string(),
+            ]
+        );
+        assert_eq!(
+            files_to_scan(
+                &table,
+                &[col("id").gt(lit(5i64)).and(col("id").lt(lit(15i64)))]
+            ),
+            all
+        );
+        assert_eq!(
+            files_to_scan(&table, &[col("id").eq(lit(30i64))]),
+            vec!["/local/3.chunk.parquet".to_string()]
+        );
+        // Only sort key columns are used for pruning
+        assert_eq!(files_to_scan(&table, &[col("name").eq(lit("a"))]), all);
+    }
+
+    /// Serves empty files with the index schema and records the paths scanned. Files scanned
+    /// together are recorded as one entry.
+    #[derive(Debug)]
+    struct FakeScanFactory {
+        schema: SchemaRef,
+        paths: Mutex<Vec<String>>,
+        combine_files: bool,
+    }
+
+    impl ParquetScanFactory for FakeScanFactory {
+        fn scan(
+            &self,
+            path: &str,
+            projection: Option<Vec<usize>>,
+            _batch_size: usize,
+            _parallelism: usize,
+        ) -> Result<Arc<dyn ExecutionPlan>, CubeError> {
+            self.paths.lock().unwrap().push(path.to_string());
+            Ok(Arc::new(MemoryExec::try_new(
+                &vec![vec![]],
+                self.schema.clone(),
+                projection,
+            )?))
+        }
+
+        fn scan_files(
+            &self,
+            paths: &[String],
+            projection: Option<Vec<usize>>,
+            _batch_size: usize,
+        ) -> Result<Option<Arc<dyn ExecutionPlan>>, CubeError> {
+            if !self.combine_files {
+                return Ok(None);
+            }
+            self.paths.lock().unwrap().push(paths.join(" + "));
+            Ok(Some(Arc::new(MemoryExec::try_new(
+                &vec![vec![]],
+                self.schema.clone(),
+                projection,
+            )?)))
+        }
+
+        fn row_group_count(&self, _path: &str) -> Result<usize, CubeError> {
+            Ok(1)
+        }
+
+        fn column_names(&self, _path: &str) -> Result<Vec<String>, CubeError> {
+            Ok(self
+                .schema
+                .fields()
+                .iter()
+                .map(|f| f.name().clone())
+                .collect())
+        }
+
+        fn matching_row_groups(
+            &self,
+            _path: &str,
+            _columns: &[Column],
+            _filters: &[Expr],
+        ) -> Result<Option<Vec<Range<usize>>>, CubeError> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn scan_uses_scan_factory() {
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            vec![
+                IdRow::new(1, Chunk::new(1, 10)),
+                IdRow::new(2, Chunk::new(1, 10)),
+            ],
+        )];
+        let remote_to_local_names = (1..=2)
+            .map(|id| {
+                (
+                    format!("{}.chunk.parquet", id),
+                    format!("/fake/{}.chunk.parquet", id),
+                )
+            })
+            .collect();
+        let table = CubeTable::try_new(
+            SerializedPlan::index_snapshot_for_test(test_columns(), partitions),
+            remote_to_local_names,
+            vec![1].into_iter().collect(),
+            1,
+        )
+        .unwrap();
+        let factory = Arc::new(FakeScanFactory {
+            schema: table.schema(),
+            paths: Mutex::new(Vec::new()),
+            combine_files: false,
+        });
+        let table = table.with_scan_factory(factory.clone());
+
+        let plan = table.scan(&Some(vec![1]), 4096, &[]).unwrap();
+        assert_eq!(
+            *factory.paths.lock().unwrap(),
+            vec![
+                "/fake/1.chunk.parquet".to_string(),
+                "/fake/2.chunk.parquet".to_string(),
+            ]
+        );
+        let schema = plan.schema().to_schema_ref();
+        assert_eq!(schema.fields().len(), 1);
+        assert_eq!(schema.field(0).name(), "name");
+    }
+
+    #[test]
+    fn scan_combines_chunk_files() {
+        let partition = Partition::new(1, None, None).child(1);
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(2, partition),
+            (1..=3)
+                .map(|id| IdRow::new(id, Chunk::new(2, 10)))
+                .collect(),
+        )];
+        let remote_to_local_names = vec!["2.parquet".to_string()]
+            .into_iter()
+            .chain((1..=3).map(|id| format!("{}.chunk.parquet", id)))
+            .map(|name| (name.clone(), format!("/fake/{}", name)))
+            .collect();
+        let table = CubeTable::try_new(
+            SerializedPlan::index_snapshot_for_test(test_columns(), partitions),
+            remote_to_local_names,
+            vec![2].into_iter().collect(),
+            1,
+        )
+        .unwrap();
+        let factory = Arc::new(FakeScanFactory {
+            schema: table.schema(),
+            paths: Mutex::new(Vec::new()),
+            combine_files: true,
+        });
+        let table = table.with_scan_factory(factory.clone());
+
+        let plan = table.scan(&Some(vec![1]), 4096, &[]).unwrap();
+        assert_eq!(
+            *factory.paths.lock().unwrap(),
+            vec![
+                "/fake/2.parquet".to_string(),
+                "/fake/1.chunk.parquet + /fake/2.chunk.parquet + /fake/3.chunk.parquet".to_string(),
+            ]
+        );
+        assert_eq!(
+            plan.children()[0].output_partitioning().partition_count(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn cube_table_exec_partition_out_of_range() {
+        let index_snapshot = SerializedPlan::index_snapshot_for_test(test_columns(), Vec::new());
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        let exec = CubeTableExec {
+            schema: schema.to_dfschema_ref().unwrap(),
+            index_snapshot,
+            partition_execs: vec![Arc::new(EmptyExec::new(false, schema.clone()))],
+            files: Vec::new(),
+            row_groups_read: Vec::new(),
+            projection: vec![0],
+            batch_size: 4096,
+            metrics: Arc::new(ScanMetrics::default()),
+        };
+        assert!(exec.execute(0).await.is_ok());
+        match exec.execute(1).await {
+            Err(e) => {
+                let message = e.to_string();
+                assert!(message.contains("index 1"), "{}", message);
+                assert!(message.contains("1 partitions"), "{}", message);
+            }
+            Ok(_) => panic!("Out of range partition is expected to fail"),
+        }
+    }
+
+    #[tokio::test]
+    async fn worker_plan_splits_single_file_scan() {
+        let (table, paths) = single_partition_table(
+            "worker_plan_splits_single_file_scan",
+            test_columns(),
+            vec![test_rows(0..100)],
+        );
+
+        let worker_plan = |split_readers: usize| {
+            let config =
+                Config::test("worker_plan_splits_single_file_scan").update_config(|mut c| {
+                    c.parquet_split_readers = split_readers;
+                    c
+                });
+            QueryExecutorImpl::new(config.config_obj())
+                .get_worker_plan(table.scan(&None, 16, &[]).unwrap(), &[])
+                .unwrap()
+        };
+
+        let single = worker_plan(1);
+        assert_eq!(single.children().len(), 1);
+        assert!(single.children()[0]
+            .as_any()
+            .downcast_ref::<ParquetExec>()
+            .is_some());
+
+        let split = worker_plan(3);
+        let readers = split
+            .children()
+            .iter()
+            .map(|c| {
+                c.as_any()
+                    .downcast_ref::<ParquetRowGroupsExec>()
+                    .unwrap()
+                    .row_groups()
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(readers, vec![0..4, 4..8, 8..10]);
+        let batches = collect(Arc::new(MergeExec::new(split))).await.unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 100);
+
+        remove_files(paths);
+    }
+
+    #[tokio::test]
+    async fn scan_fills_columns_missing_in_file_with_nulls() {
+        let mut columns = test_columns();
+        columns.push(Column::new("amount".to_string(), ColumnType::Int, 2));
+        let (table, paths) = single_partition_table(
+            "scan_fills_columns_missing_in_file_with_nulls",
+            columns,
+            vec![test_rows(0..3)],
+        );
+        let batches = collect(table.scan(&None, 16, &[]).unwrap()).await.unwrap();
+        let rows = batches_to_rows(&batches)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            (0..3)
+                .map(|i| Row::new(vec![
+                    TableValue::Int(i),
+                    TableValue::String(format!("n{}", i)),
+                    TableValue::Null,
+                ]))
+                .collect::<Vec<_>>()
+        );
+
+        remove_files(paths);
+    }
+
+    fn write_compressed(path: &str, compression: Compression) {
+        let props = WriterProperties::builder()
+            .set_compression(compression)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(fs::File::create(path).unwrap(), test_schema(), Some(props))
+                .unwrap();
+        for batch in test_batches() {
+            writer.write(&batch).unwrap();
+        }
+        writer.close().unwrap();
+    }
+
+    fn compressed_file_table(path: &str) -> CubeTable {
+        CubeTable::try_new(
+            SerializedPlan::index_snapshot_for_test(
+                test_columns(),
+                vec![PartitionSnapshot::new(
+                    IdRow::new(1, Partition::new(1, None, None)),
+                    vec![IdRow::new(7, Chunk::new(1, 3))],
+                )],
+            ),
+            vec![("7.chunk.parquet".to_string(), path.to_string())]
+                .into_iter()
+                .collect(),
+            vec![1].into_iter().collect(),
+            1,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn scan_reads_compressed_files() {
+        for compression in vec![Compression::SNAPPY, Compression::GZIP] {
+            let path = env::temp_dir()
+                .join(format!(
+                    "scan_reads_compressed_files_{}.parquet",
+                    compression
+                ))
+                .to_str()
+                .unwrap()
+                .to_string();
+            write_compressed(&path, compression);
+            let table = compressed_file_table(&path);
+            let batches = collect(table.scan(&None, 16, &[]).unwrap()).await.unwrap();
+            assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 3);
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn scan_fails_on_unsupported_codec() {
+        let path = env::temp_dir()
+            .join("scan_fails_on_unsupported_codec.parquet")
+            .to_str()
+            .unwrap()
+            .to_string();
+        write_compressed(&path, Compression::SNAPPY);
+        // LZO can't be written, so the codec of the `id` column chunk is replaced in the footer:
+        // path_in_schema ["id"] is followed by the thrift compact encoded codec field.
+        let mut bytes = fs::read(&path).unwrap();
+        let snappy = b"\x18\x02id\x15\x02";
+        let positions = bytes
+            .windows(snappy.len())
+            .positions(|w| w == snappy)
+            .collect::<Vec<_>>();
+        assert_eq!(positions.len(), 1);
+        // zigzag encoded 3, which is LZO
+        bytes[positions[0] + snappy.len() - 1] = 0x06;
+        fs::write(&path, bytes).unwrap();
+
+        let table = compressed_file_table(&path);
+        let error = table.scan(&None, 16, &[]).unwrap_err().to_string();
+        assert!(error.contains(&path), "{}", error);
+        assert!(error.contains("LZO"), "{}", error);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn scan_skips_row_groups_by_statistics() {
+        let (table, paths) = single_partition_table(
+            "scan_skips_row_groups_by_statistics",
+            test_columns(),
+            vec![test_rows(0..100)],
+        );
+
+        let scan = table.scan(&None, 16, &[col("id").eq(lit(42i64))]).unwrap();
+        let cube_table = scan.children()[0].clone();
+        let row_groups = cube_table
+            .children()
+            .iter()
+            .map(|c| {
+                c.as_any()
+                    .downcast_ref::<ParquetRowGroupsExec>()
+                    .unwrap()
+                    .row_groups()
+                    .clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(row_groups, vec![4..5]);
+
+        let file_reader = SerializedFileReader::new(fs::File::open(&paths[0]).unwrap()).unwrap();
+        let metadata = file_reader.metadata();
+        let bytes = |row_groups: Range<usize>| {
+            row_groups
+                .map(|i| metadata.row_group(i).compressed_size())
+                .sum::<i64>()
+        };
+        assert!(bytes(4..5) * 5 < bytes(0..metadata.num_row_groups()));
+        let batches = collect(scan).await.unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 10);
+
+        // Statistics of a string column and filters excluding every row group
+        let scan = table.scan(&None, 16, &[col("name").eq(lit("m1"))]).unwrap();
+        assert!(scan.children()[0].children()[0]
+            .as_any()
+            .downcast_ref::<EmptyExec>()
+            .is_some());
+        let scan = table.scan(&None, 16, &[col("name").lt(lit("n1"))]).unwrap();
+        assert_eq!(scan.children()[0].children().len(), 1);
+
+        remove_files(paths);
+    }
+
+    #[tokio::test]
+    async fn scan_limits_partitions_by_limit_hint() {
+        let (table, paths) = single_partition_table(
+            "scan_limits_partitions_by_limit_hint",
+            test_columns(),
+            vec![test_rows(0..30)],
+        );
+
+        fn has_limit(plan: &Arc<dyn ExecutionPlan>) -> bool {
+            plan.as_any().downcast_ref::<GlobalLimitExec>().is_some()
+                || plan.children().iter().any(has_limit)
+        }
+
+        let scan = table.scan(&None, 16, &[]).unwrap();
+        assert!(!has_limit(&scan));
+        let batches = collect(scan).await.unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 30);
+
+        let scan = table
+            .clone()
+            .with_limit_hint(Some(5))
+            .scan(&None, 16, &[])
+            .unwrap();
+        assert!(has_limit(&scan));
+        let batches = collect(scan).await.unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 5);
+
+        remove_files(paths);
+    }
+
+    #[tokio::test]
+    async fn scan_counts_files_and_rows() {
+        let (table, paths) = single_partition_table(
+            "scan_counts_files_and_rows",
+            test_columns(),
+            vec![test_rows(0..30), test_rows(30..50)],
+        );
+
+        let scan = table.scan(&None, 16, &[]).unwrap();
+        let batches = collect(scan.clone()).await.unwrap();
+        let stats = scan_stats(scan);
+        assert_eq!(stats.files_opened(), 2);
+        assert_eq!(stats.row_groups_read(), 5);
+        assert_eq!(stats.rows(), 50);
+        assert_eq!(
+            stats.rows(),
+            batches.iter().map(|b| b.num_rows() as u64).sum::<u64>()
+        );
+        assert_eq!(stats.batches(), batches.len() as u64);
+
+        remove_files(paths);
+    }
+
+    fn has_memory_exec(plan: &Arc<dyn ExecutionPlan>) -> bool {
+        plan.as_any().downcast_ref::<MemoryExec>().is_some()
+            || plan.children().iter().any(has_memory_exec)
+    }
+
+    #[tokio::test]
+    async fn in_memory_chunk_scanned_without_file() {
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            vec![IdRow::new(7, Chunk::new(1, 3))],
+        )];
+        let index_snapshot = SerializedPlan::index_snapshot_for_test(test_columns(), partitions);
+        let memory_chunks = MemoryChunkStore::new(1 << 20);
+        memory_chunks.add(7, test_batches());
+
+        let table = CubeTable::try_new(
+            index_snapshot.clone(),
+            HashMap::new(),
+            vec![1].into_iter().collect(),
+            1,
+        )
+        .unwrap()
+        .with_in_memory_chunks(&vec![(7, test_batches())].into_iter().collect());
+        let scan = table.scan(&None, 16, &[]).unwrap();
+        assert!(has_memory_exec(&scan), "{:?}", scan);
+
+        let query_executor = QueryExecutorImpl::with_memory_chunks(
+            Config::test("in_memory_chunk_scanned_without_file").config_obj(),
+            memory_chunks,
+        );
+        // No local file is passed for the chunk
+        let (_, batches, _) = query_executor
+            .execute_worker_plan(
+                SerializedPlan::scan_for_test(index_snapshot)
+                    .with_partition_id_to_execute(vec![1].into_iter().collect()),
+                HashMap::new(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            batches_to_rows(&batches)
+                .map(|r| r.unwrap().values()[0].clone())
+                .collect::<Vec<_>>(),
+            vec![TableValue::Int(1), TableValue::Int(2), TableValue::Int(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn local_plan_reads_in_memory_chunk_without_download() {
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            vec![IdRow::new(7, Chunk::new(1, 3))],
+        )];
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            partitions,
+        ));
+        let memory_chunks = MemoryChunkStore::new(1 << 20);
+        memory_chunks.add(7, test_batches());
+        let query_executor = QueryExecutorImpl::with_memory_chunks(
+            Config::test("local_plan_reads_in_memory_chunk_without_download").config_obj(),
+            memory_chunks,
+        );
+        // Chunk is read from memory so its file isn't downloaded
+        let mut cluster = MockCluster::new();
+        cluster.expect_download().times(0);
+
+        let local_plan = query_executor
+            .get_local_plan(&plan, Arc::new(cluster))
+            .await
+            .unwrap();
+        assert!(has_memory_exec(&local_plan), "{:?}", local_plan);
+        let batches = collect(local_plan).await.unwrap();
+        assert_eq!(
+            batches_to_rows(&batches)
+                .map(|r| r.unwrap().values()[0].clone())
+                .collect::<Vec<_>>(),
+            vec![TableValue::Int(1), TableValue::Int(2), TableValue::Int(3)]
+        );
+    }
+
+    #[tokio::test]
+    async fn pending_chunk_scanned_until_activated() {
+        // Chunk 7 is written to the partition but isn't activated yet
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            Vec::new(),
+        )];
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            partitions,
+        ))
+        .with_partition_id_to_execute(vec![1].into_iter().collect());
+        let memory_chunks = MemoryChunkStore::new(1 << 20);
+        memory_chunks.add(7, test_batches());
+        memory_chunks.add_pending(7, 1);
+        let query_executor = QueryExecutorImpl::with_memory_chunks(
+            Config::test("pending_chunk_scanned_until_activated").config_obj(),
+            memory_chunks.clone(),
+        );
+        let first_values = |batches: &Vec<RecordBatch>| {
+            batches_to_rows(batches)
+                .map(|r| r.unwrap().values()[0].clone())
+                .collect::<Vec<_>>()
+        };
+
+        let mut cluster = MockCluster::new();
+        cluster.expect_download().times(0);
+        let local_plan = query_executor
+            .get_local_plan(&plan, Arc::new(cluster))
+            .await
+            .unwrap();
+        assert_eq!(
+            first_values(&collect(local_plan).await.unwrap()),
+            vec![TableValue::Int(1), TableValue::Int(2), TableValue::Int(3)]
+        );
+        let (_, batches, _) = query_executor
+            .execute_worker_plan(plan.clone(), HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(
+            first_values(&batches),
+            vec![TableValue::Int(1), TableValue::Int(2), TableValue::Int(3)]
+        );
+
+        // Plans resolved before the activation don't have the chunk anymore
+        memory_chunks.activated(&[7]);
+        let (_, batches, _) = query_executor
+            .execute_worker_plan(plan, HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(first_values(&batches), Vec::<TableValue>::new());
+    }
+
+    #[tokio::test]
+    async fn repeated_worker_plan_hits_result_cache() {
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            vec![IdRow::new(7, Chunk::new(1, 3))],
+        )];
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            partitions,
+        ))
+        .with_partition_id_to_execute(vec![1].into_iter().collect());
+        let memory_chunks = MemoryChunkStore::new(1 << 20);
+        memory_chunks.add(7, test_batches());
+        let config =
+            Config::test("repeated_worker_plan_hits_result_cache").update_config(|mut c| {
+                c.worker_result_cache_size = 1 << 20;
+                c
+            });
+        let query_executor =
+            QueryExecutorImpl::with_memory_chunks(config.config_obj(), memory_chunks.clone());
+
+        let (_, batches, _) = query_executor
+            .execute_worker_plan(plan.with_query_id("q1".to_string()), HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(batches_to_rows(&batches).count(), 3);
+
+        // The chunk has no file either so scanning it again would fail
+        memory_chunks.remove(&[7]);
+        let (_, batches, _) = query_executor
+            .execute_worker_plan(plan.with_query_id("q2".to_string()), HashMap::new())
+            .await
+            .unwrap();
+        assert_eq!(batches_to_rows(&batches).count(), 3);
+
+        // A plan of another chunk is a miss and scans the chunk
+        let other_plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            vec![PartitionSnapshot::new(
+                IdRow::new(1, Partition::new(1, None, None)),
+                vec![IdRow::new(8, Chunk::new(1, 3))],
+            )],
+        ))
+        .with_partition_id_to_execute(vec![1].into_iter().collect());
+        let err = query_executor
+            .execute_worker_plan(other_plan, HashMap::new())
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("wasn't downloaded"), "{}", err);
+    }
+
+    /// Plan of `SELECT a, count(a) FROM t1 GROUP BY a UNION ALL SELECT b, count(b) FROM t2 GROUP BY b`
+    fn union_of_aggregates(second_type: DataType) -> Arc<dyn ExecutionPlan> {
+        use datafusion::datasource::MemTable;
+        use datafusion::logical_plan::count;
+
+        let mut ctx = ExecutionContext::new();
+        for (name, column, data_type) in
+            vec![("t1", "a", DataType::Int64), ("t2", "b", second_type)]
+        {
+            let schema = Arc::new(Schema::new(vec![Field::new(column, data_type, false)]));
+            ctx.register_table(
+                name,
+                Box::new(MemTable::try_new(schema, vec![vec![]]).unwrap()),
+            );
+        }
+        let first = ctx
+            .table("t1")
+            .unwrap()
+            .aggregate(vec![col("a")], vec![count(col("a"))])
+            .unwrap()
+            .to_logical_plan();
+        let second = ctx
+            .table("t2")
+            .unwrap()
+            .aggregate(vec![col("b")], vec![count(col("b"))])
+            .unwrap()
+            .to_logical_plan();
+        let union = LogicalPlan::Union {
+            schema: first.schema().clone(),
+            inputs: vec![first, second],
+            alias: None,
+        };
+        ctx.create_physical_plan(&union).unwrap()
+    }
+
+    #[test]
+    fn worker_plan_follows_split_branch() {
+        let plan = union_of_aggregates(DataType::Int64);
+        let executor =
+            QueryExecutorImpl::new(Config::test("worker_plan_follows_split_branch").config_obj());
+        // Final aggregate of the branch is the split node
+        let branch_input = |branch: usize| plan.children()[branch].children()[0].clone();
+        for branch in 0..2 {
+            let worker_plan = executor
+                .get_worker_split_plan(plan.clone(), &[branch])
+                .unwrap();
+            assert!(Arc::ptr_eq(&worker_plan, &branch_input(branch)));
+        }
+        assert!(executor.get_worker_split_plan(plan.clone(), &[]).is_err());
+
+        assert!(check_union_schemas(&plan.children()).is_ok());
+        let mismatch = union_of_aggregates(DataType::Utf8);
+        let err = check_union_schemas(&mismatch.children()).unwrap_err();
+        assert!(err.to_string().contains("UNION ALL column 'a'"), "{}", err);
+        // Narrower numbers are cast to the first branch type but not the other way around
+        let narrower = union_of_aggregates(DataType::Int32);
+        let union_schema = check_union_schemas(&narrower.children()).unwrap();
+        assert_eq!(union_schema.schema.field(0).name(), "a");
+        assert_eq!(union_schema.schema.field(0).data_type(), &DataType::Int64);
+        assert_eq!(union_schema.columns[0], None);
+        assert_eq!(union_schema.columns[1], Some(vec![0, 1]));
+        let wider = union_of_aggregates(DataType::Float64);
+        let err = check_union_schemas(&wider.children()).unwrap_err();
+        assert!(err.to_string().contains("cast it explicitly"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn union_of_reordered_branches() {
+        let first_schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        let second_schema = Arc::new(Schema::new(vec![
+            Field::new("name", DataType::Utf8, true),
+            Field::new("id", DataType::Int64, false),
+        ]));
+        let first = RecordBatch::try_new(
+            first_schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2])),
+                Arc::new(StringArray::from(vec!["a", "b"])),
+            ],
+        )
+        .unwrap();
+        let second = RecordBatch::try_new(
+            second_schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec![Some("c"), None])),
+                Arc::new(Int64Array::from(vec![3, 4])),
+            ],
+        )
+        .unwrap();
+        let union: Arc<dyn ExecutionPlan> =
+            Arc::new(UnionExec::new(vec![scan_of(first), scan_of(second)]));
+
+        let plan = reconcile_union_schemas(union).unwrap();
+        let expected = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+        ]));
+        assert_eq!(plan.schema().to_schema_ref(), expected);
+        // The first branch is adapted to nullable names and the second one to the column order
+        assert!(plan
+            .children()
+            .iter()
+            .all(|c| c.as_any().downcast_ref::<SchemaAdapterExec>().is_some()));
+        let batches = collect(plan).await.unwrap();
+        assert!(batches.iter().all(|b| b.schema() == expected));
+        let mut rows = batch_to_dataframe(&batches).unwrap().get_rows().clone();
+        rows.sort_by_key(|r| format!("{:?}", r.values()[0]));
+        assert_eq!(
+            rows,
+            vec![
+                Row::new(vec![
+                    TableValue::Int(1),
+                    TableValue::String("a".to_string())
+                ]),
+                Row::new(vec![
+                    TableValue::Int(2),
+                    TableValue::String("b".to_string())
+                ]),
+                Row::new(vec![
+                    TableValue::Int(3),
+                    TableValue::String("c".to_string())
+                ]),
+                Row::new(vec![TableValue::Int(4), TableValue::Null]),
+            ]
+        );
+
+        // Branches matching the union schema are kept as is
+        let plan = union_of_aggregates(DataType::Int64);
+        assert!(Arc::ptr_eq(
+            &reconcile_union_schemas(plan.clone()).unwrap(),
+            &plan
+        ));
+
+        // Columns selected in another order are matched by position
+        let ab = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int64, false),
+            Field::new("b", DataType::Int64, false),
+        ]));
+        let ba = Arc::new(Schema::new(vec![
+            Field::new("b", DataType::Int64, false),
+            Field::new("a", DataType::Int64, false),
+        ]));
+        let plan: Arc<dyn ExecutionPlan> = Arc::new(UnionExec::new(vec![
+            Arc::new(MemoryExec::try_new(&vec![vec![]], ab, None).unwrap()),
+            Arc::new(MemoryExec::try_new(&vec![vec![]], ba, None).unwrap()),
+        ]));
+        assert!(Arc::ptr_eq(
+            &reconcile_union_schemas(plan.clone()).unwrap(),
+            &plan
+        ));
+    }
+
+    /// Table scan producing `batch`.
+    fn scan_of(batch: RecordBatch) -> Arc<dyn ExecutionPlan> {
+        let schema = batch.schema();
+        Arc::new(CubeTableExec {
+            schema: schema.clone().to_dfschema_ref().unwrap(),
+            index_snapshot: SerializedPlan::index_snapshot_for_test(test_columns(), Vec::new()),
+            partition_execs: vec![Arc::new(
+                MemoryExec::try_new(&vec![vec![batch]], schema.clone(), None).unwrap(),
+            )],
+            files: Vec::new(),
+            row_groups_read: Vec::new(),
+            projection: (0..schema.fields().len()).collect(),
+            batch_size: 4096,
+            metrics: Arc::new(ScanMetrics::default()),
+        })
+    }
+
+    #[test]
+    fn decimal128_to_dataframe() {
+        let mut builder = DecimalBuilder::new(4, 38, 2);
+        builder
+            .append_value(123456789012345678901234567890i128)
+            .unwrap();
+        builder.append_value(-9223372036854775808000i128).unwrap();
+        builder.append_value(1050).unwrap();
+        builder.append_null().unwrap();
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "value",
+            DataType::Decimal(38, 2),
+            true,
+        )]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(builder.finish())]).unwrap();
+
+        let data_frame = batch_to_dataframe(&vec![batch]).unwrap();
+        assert_eq!(
+            data_frame.get_columns()[0].get_column_type(),
+            &ColumnType::Decimal {
+                scale: 2,
+                precision: 38
+            }
+        );
+        assert_eq!(
+            data_frame.get_rows(),
+            &vec![
+                Row::new(vec![TableValue::Decimal(
+                    "1234567890123456789012345678.9".to_string()
+                )]),
+                Row::new(vec![TableValue::Decimal(
+                    "-92233720368547758080".to_string()
+                )]),
+                Row::new(vec![TableValue::Decimal("10.5".to_string())]),
+                Row::new(vec![TableValue::Null]),
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps_to_dataframe() {
+        let second = 1_600_000_000i64;
+        let cases: Vec<(TimeUnit, ArrayRef, i64)> = vec![
+            (
+                TimeUnit::Second,
+                Arc::new(TimestampSecondArray::from(vec![Some(second), None])),
+                i64::MAX / 1_000_000_000,
+            ),
+            (
+                TimeUnit::Millisecond,
+                Arc::new(TimestampMillisecondArray::from(vec![
+                    Some(second * 1_000),
+                    None,
+                ])),
+                i64::MAX / 1_000_000,
+            ),
+            (
+                TimeUnit::Microsecond,
+                Arc::new(TimestampMicrosecondArray::from(vec![
+                    Some(second * 1_000_000),
+                    None,
+                ])),
+                i64::MAX / 1_000,
+            ),
+            (
+                TimeUnit::Nanosecond,
+                Arc::new(TimestampNanosecondArray::from(vec![
+                    Some(second * 1_000_000_000),
+                    None,
+                ])),
+                i64::MAX,
+            ),
+        ];
+        let timestamp_batch = |unit: &TimeUnit, array: ArrayRef| {
+            RecordBatch::try_new(
+                Arc::new(Schema::new(vec![Field::new(
+                    "ts",
+                    DataType::Timestamp(unit.clone(), None),
+                    true,
+                )])),
+                vec![array],
+            )
+            .unwrap()
+        };
+        for (unit, array, max_value) in cases {
+            let data_frame = batch_to_dataframe(&vec![timestamp_batch(&unit, array)]).unwrap();
+            assert_eq!(
+                data_frame.get_rows(),
+                &vec![
+                    Row::new(vec![TableValue::Timestamp(TimestampValue::new(
+                        second * 1_000_000_000
+                    ))]),
+                    Row::new(vec![TableValue::Null]),
+                ],
+                "{:?}",
+                unit
+            );
+
+            let boundary: ArrayRef = match unit {
+                TimeUnit::Second => Arc::new(TimestampSecondArray::from(vec![max_value])),
+                TimeUnit::Millisecond => Arc::new(TimestampMillisecondArray::from(vec![max_value])),
+                TimeUnit::Microsecond => Arc::new(TimestampMicrosecondArray::from(vec![max_value])),
+                TimeUnit::Nanosecond => Arc::new(TimestampNanosecondArray::from(vec![max_value])),
+            };
+            assert!(
+                batch_to_dataframe(&vec![timestamp_batch(&unit, boundary)]).is_ok(),
+                "{:?}",
+                unit
+            );
+            if unit != TimeUnit::Nanosecond {
+                let overflow: ArrayRef = match unit {
+                    TimeUnit::Second => Arc::new(TimestampSecondArray::from(vec![max_value + 1])),
+                    TimeUnit::Millisecond => {
+                        Arc::new(TimestampMillisecondArray::from(vec![max_value + 1]))
+                    }
+                    _ => Arc::new(TimestampMicrosecondArray::from(vec![max_value + 1])),
+                };
+                let err = batch_to_dataframe(&vec![timestamp_batch(&unit, overflow)]).unwrap_err();
+                assert!(err.to_string().contains("out of range"), "{}", err);
+            }
+        }
+    }
+
+    #[test]
+    fn timestamps_with_timezone_to_dataframe() {
+        // 2020-01-01T02:00:00+02:00
+        let utc_millis = 1_577_836_800_000i64;
+        let tz = Some(Arc::new("+02:00".to_string()));
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new(
+                "ts",
+                DataType::Timestamp(TimeUnit::Millisecond, tz.clone()),
+                true,
+            )])),
+            vec![Arc::new(TimestampMillisecondArray::from_opt_vec(
+                vec![Some(utc_millis), None],
+                tz,
+            ))],
+        )
+        .unwrap();
+
+        let data_frame = batch_to_dataframe(&vec![batch]).unwrap();
+        assert_eq!(
+            data_frame.get_columns()[0].get_column_type(),
+            &ColumnType::Timestamp
+        );
+        assert_eq!(
+            data_frame.get_rows(),
+            &vec![
+                Row::new(vec![TableValue::Timestamp(TimestampValue::new(
+                    utc_millis * 1_000_000
+                ))]),
+                Row::new(vec![TableValue::Null]),
+            ]
+        );
+    }
+
+    #[test]
+    fn intervals_to_dataframe() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("ym", DataType::Interval(IntervalUnit::YearMonth), true),
+            Field::new("dt", DataType::Interval(IntervalUnit::DayTime), true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(IntervalYearMonthArray::from(vec![Some(14), None])),
+                Arc::new(IntervalDayTimeArray::from(vec![
+                    Some((7i64 << 32) + 3_600_000),
+                    None,
+                ])),
+            ],
+        )
+        .unwrap();
+
+        let data_frame = batch_to_dataframe(&vec![batch]).unwrap();
+        assert_eq!(
+            data_frame.get_columns()[0].get_column_type(),
+            &ColumnType::String
+        );
+        assert_eq!(
+            data_frame.get_rows(),
+            &vec![
+                Row::new(vec![
+                    TableValue::String("1 year 2 months".to_string()),
+                    TableValue::String("7 days 1 hour".to_string()),
+                ]),
+                Row::new(vec![TableValue::Null, TableValue::Null]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dictionary_to_dataframe() {
+        let dictionary = vec![Some("a"), None, Some("b"), Some("a")]
+            .into_iter()
+            .collect::<DictionaryArray<Int32Type>>();
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "category",
+            DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+            true,
+        )]));
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(dictionary)]).unwrap();
+
+        let data_frame = batch_to_dataframe(&vec![batch]).unwrap();
+        assert_eq!(
+            data_frame.get_columns()[0].get_column_type(),
+            &ColumnType::String
+        );
+        assert_eq!(
+            data_frame.get_rows(),
+            &vec![
+                Row::new(vec![TableValue::String("a".to_string())]),
+                Row::new(vec![TableValue::Null]),
+                Row::new(vec![TableValue::String("b".to_string())]),
+                Row::new(vec![TableValue::String("a".to_string())]),
+            ]
+        );
+    }
+
+    #[test]
+    fn dictionary_and_plain_strings_produce_same_dataframe() {
+        let values = vec![Some("low"), Some("high"), None, Some("low")];
+        let plain = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("level", DataType::Utf8, true)])),
+            vec![Arc::new(StringArray::from(values.clone()))],
+        )
+        .unwrap();
+        let dictionary = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new(
+                "level",
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8)),
+                true,
+            )])),
+            vec![Arc::new(
+                values.into_iter().collect::<DictionaryArray<Int32Type>>(),
+            )],
+        )
+        .unwrap();
+        assert_eq!(
+            batch_to_dataframe(&vec![dictionary.clone()]).unwrap(),
+            batch_to_dataframe(&vec![plain.clone()]).unwrap()
+        );
+
+        let adapted = adapt_batches_to_schema(vec![dictionary], &plain.schema()).unwrap();
+        assert_eq!(adapted[0].schema(), plain.schema());
+        assert_eq!(
+            batch_to_dataframe(&adapted).unwrap(),
+            batch_to_dataframe(&vec![plain]).unwrap()
+        );
+    }
+
+    #[test]
+    fn dataframe_to_batches_round_trip() {
+        let mut decimal128 = DecimalBuilder::new(3, 38, 2);
+        decimal128
+            .append_value(123456789012345678901234567890i128)
+            .unwrap();
+        decimal128.append_null().unwrap();
+        decimal128.append_value(-1050).unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("int", DataType::Int64, true),
+            Field::new("string", DataType::Utf8, true),
+            Field::new("decimal", DataType::Int64Decimal(2), true),
+            Field::new("decimal128", DataType::Decimal(38, 2), true),
+            Field::new(
+                "timestamp",
+                DataType::Timestamp(TimeUnit::Microsecond, None),
+                true,
+            ),
+            Field::new("boolean", DataType::Boolean, true),
+        ]));
+        let batches = vec![RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int64Array::from(vec![Some(1), None, Some(-3)])),
+                Arc::new(StringArray::from(vec![Some("a"), Some(""), None])),
+                Arc::new(Int64Decimal2Array::from(vec![None, Some(12345), Some(-10)])),
+                Arc::new(decimal128.finish()),
+                Arc::new(TimestampMicrosecondArray::from(vec![
+                    Some(1_600_000_000_000_000),
+                    None,
+                    Some(0),
+                ])),
+                Arc::new(BooleanArray::from(vec![Some(true), Some(false), None])),
+            ],
+        )
+        .unwrap()];
+
+        let df = batch_to_dataframe(&batches).unwrap();
+        let round_trip = dataframe_to_batches(&df).unwrap();
+        assert_eq!(round_trip.len(), 1);
+        assert_eq!(round_trip[0].schema(), schema);
+        assert_eq!(batch_to_dataframe(&round_trip).unwrap(), df);
+    }
+
+    #[test]
+    fn dataframe_to_batches_unsupported_types() {
+        let column = Column::new("amount".to_string(), ColumnType::Int, 0);
+        let rows = vec![Row::new(vec![TableValue::Null])];
+        for data_type in vec![DataType::Float64, DataType::Int64Decimal(7)] {
+            let err = column_to_array(&rows, &column, &data_type).unwrap_err();
+            assert!(err.to_string().contains("amount"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn conversion_errors_mention_column() {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("ratio", DataType::Float64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int64Array::from(vec![1, 2])),
+                Arc::new(Float64Array::from(vec![Some(0.5), Some(f64::NAN)])),
+            ],
+        )
+        .unwrap();
+        let err = batch_to_dataframe(&vec![batch]).unwrap_err();
+        assert!(err.to_string().contains("'ratio'"), "{}", err);
+        assert!(err.to_string().contains("NaN"), "{}", err);
+    }
+
+    #[test]
+    fn batches_with_different_schemas() {
+        let batch = |field: Field, column: ArrayRef| {
+            RecordBatch::try_new(Arc::new(Schema::new(vec![field])), vec![column]).unwrap()
+        };
+        let batches = vec![
+            batch(
+                Field::new("id", DataType::Int64, false),
+                Arc::new(Int64Array::from(vec![1, 2])),
+            ),
+            batch(
+                Field::new("id", DataType::Utf8, false),
+                Arc::new(StringArray::from(vec!["3"])),
+            ),
+        ];
+        let err = batch_to_dataframe(&batches).unwrap_err().to_string();
+        assert!(err.contains("Batch 1 schema"), "{}", err);
+        assert!(err.contains("Utf8"), "{}", err);
+
+        // Nullability doesn't affect conversion
+        let batches = vec![
+            batch(
+                Field::new("id", DataType::Int64, false),
+                Arc::new(Int64Array::from(vec![1])),
+            ),
+            batch(
+                Field::new("id", DataType::Int64, true),
+                Arc::new(Int64Array::from(vec![Some(2), None])),
+            ),
+        ];
+        assert_eq!(batch_to_dataframe(&batches).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn trailing_zeros() {
+        for (decimal, expected) in vec![
+            ("5.000", "5"),
+            ("5.100", "5.1"),
+            ("5.1010", "5.101"),
+            ("-1.05", "-1.05"),
+            ("-0.0", "0"),
+            ("0.000", "0"),
+            ("100.", "100"),
+            ("123", "123"),
+            ("1200", "1200"),
+        ] {
+            assert_eq!(cut_trailing_zeros(decimal), expected, "{}", decimal);
+        }
+    }
+
+    #[test]
+    fn half_floats_to_dataframe() {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "ratio",
+            DataType::Float16,
+            true,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Float16Array::from(vec![
+                Some(f16::from_f32(1.5)),
+                None,
+                Some(f16::from_f32(-2.25)),
+                Some(f16::from_f32(1.0)),
+                // Smallest subnormal, 2^-24
+                Some(f16::from_bits(1)),
+            ]))],
+        )
+        .unwrap();
+        let df = batch_to_dataframe(&vec![batch]).unwrap();
+        let values = df
+            .get_rows()
+            .iter()
+            .map(|r| r.values()[0].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values[0..4].to_vec(),
+            vec![
+                TableValue::Decimal("1.5".to_string()),
+                TableValue::Null,
+                TableValue::Decimal("-2.25".to_string()),
+                TableValue::Decimal("1".to_string()),
+            ]
+        );
+        match &values[4] {
+            TableValue::Decimal(v) => {
+                let v = v.parse::<f64>().unwrap();
+                assert!((v - 2f64.powi(-24)).abs() < 1e-20, "{}", v);
+            }
+            v => panic!("Unexpected value: {:?}", v),
+        }
+    }
+
+    #[test]
+    fn streamed_rows_match_dataframe() {
+        let mut batches = test_batches();
+        batches.push(RecordBatch::new_empty(batches[0].schema()));
+        batches.push(
+            RecordBatch::try_new(
+                batches[0].schema(),
+                vec![
+                    Arc::new(Int64Array::from(vec![4])),
+                    Arc::new(StringArray::from(vec![Some("d")])),
+                ],
+            )
+            .unwrap(),
+        );
+
+        let streamed = batches_to_rows(&batches)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let df = batch_to_dataframe(&batches).unwrap();
+        assert_eq!(streamed.len(), 4);
+        assert_eq!(&streamed, df.get_rows());
+        assert_eq!(
+            &batch_columns(batches[0].schema().as_ref()).unwrap(),
+            df.get_columns()
+        );
+
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "t",
+            DataType::Timestamp(TimeUnit::Second, None),
+            false,
+        )]));
+        let timestamps = |value: i64| {
+            RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(TimestampSecondArray::from(vec![value]))],
+            )
+            .unwrap()
+        };
+        let batches = vec![timestamps(0), timestamps(i64::MAX), timestamps(1)];
+        let mut rows = batches_to_rows(&batches);
+        assert!(rows.next().unwrap().is_ok());
+        assert!(rows.next().unwrap().is_err());
+        assert!(rows.next().is_none());
+    }
+
+    #[test]
+    fn adapt_reordered_batches() {
+        let expected = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, false),
+        ]));
+        let batch = test_batches().remove(0);
+        let reordered = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![
+                Field::new("name", DataType::Utf8, true),
+                Field::new("id", DataType::Int64, false),
+            ])),
+            vec![batch.column(1).clone(), batch.column(0).clone()],
+        )
+        .unwrap();
+        let adapted = adapt_batches_to_schema(vec![reordered], &expected).unwrap();
+        assert_eq!(adapted[0].schema(), expected);
+        assert_eq!(
+            batch_to_dataframe(&adapted).unwrap().get_rows(),
+            batch_to_dataframe(&vec![batch]).unwrap().get_rows()
+        );
+    }
+
+    #[test]
+    fn adapt_incompatible_batches() {
+        let expected = Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("name", DataType::Int64, false),
+        ]));
+        let err = adapt_batches_to_schema(test_batches(), &expected).unwrap_err();
+        assert!(err.contains("expected"), "{}", err);
+
+        let expected = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)]));
+        assert!(adapt_batches_to_schema(test_batches(), &expected).is_err());
+    }
+
+    #[test]
+    fn serialized_stream_round_trip() {
+        let stream =
+            SerializedRecordBatchStream::write(&test_schema(), test_batches(), WIRE_FORMAT_VERSION)
+                .unwrap();
+        let batches = stream.read("node1").unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].num_rows(), 3);
+    }
+
+    #[test]
+    fn serialized_stream_write_to_sink() {
+        struct ChunkSink(Vec<Vec<u8>>);
+        impl Write for ChunkSink {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.push(buf.to_vec());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let batches = (0..100).flat_map(|_| test_batches()).collect::<Vec<_>>();
+        let buffered = SerializedRecordBatchStream::write(
+            &test_schema(),
+            batches.clone(),
+            WIRE_FORMAT_VERSION,
+        )
+        .unwrap();
+        let mut sink = ChunkSink(Vec::new());
+        let streamed = SerializedRecordBatchStream::write_to(
+            &test_schema(),
+            batches,
+            WIRE_FORMAT_VERSION,
+            &mut sink,
+        )
+        .unwrap();
+
+        assert_eq!(sink.0.concat(), buffered.record_batch_file);
+        assert_eq!(
+            streamed,
+            StreamedRecordBatches {
+                format_version: WIRE_FORMAT_VERSION,
+                checksum: buffered.checksum,
+                schema_fingerprint: buffered.schema_fingerprint,
+                bytes_written: buffered.record_batch_file.len() as u64,
+            }
+        );
+        assert!(
+            SerializedRecordBatchStream::write_to(&test_schema(), vec![], 0, Vec::new()).is_err()
+        );
+    }
+
+    #[test]
+    fn serialized_stream_without_batches() {
+        let stream =
+            SerializedRecordBatchStream::write(&test_schema(), vec![], WIRE_FORMAT_VERSION)
+                .unwrap();
+        assert_eq!(stream.schema().unwrap(), test_schema());
+        assert!(stream.read("node1").unwrap().is_empty());
+    }
+
+    #[test]
+    fn serialized_stream_checksum_mismatch() {
+        let mut stream =
+            SerializedRecordBatchStream::write(&test_schema(), test_batches(), WIRE_FORMAT_VERSION)
+                .unwrap();
+        let last = stream.record_batch_file.len() - 10;
+        stream.record_batch_file[last] ^= 0xff;
+        let err = stream.read("node1").unwrap_err();
+        assert!(err.is_corrupted_data());
+        assert!(err.to_string().contains("node1"), "{}", err);
+    }
+
+    #[test]
+    fn serialized_stream_schema_fingerprint_mismatch() {
+        let mut stream =
+            SerializedRecordBatchStream::write(&test_schema(), test_batches(), WIRE_FORMAT_VERSION)
+                .unwrap();
+        stream.schema_fingerprint += 1;
+        let err = stream.read("node1").unwrap_err();
+        assert!(err.is_corrupted_data());
+    }
+
+    #[test]
+    fn serialized_stream_format_version_mismatch() {
+        let mut stream =
+            SerializedRecordBatchStream::write(&test_schema(), test_batches(), WIRE_FORMAT_VERSION)
+                .unwrap();
+        stream.format_version = WIRE_FORMAT_VERSION + 1;
+        let err = stream.read("node1").unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("format version {}", WIRE_FORMAT_VERSION + 1)),
+            "{}",
+            err
+        );
+        assert!(SerializedRecordBatchStream::write(&test_schema(), test_batches(), 0).is_err());
+    }
+
+    #[test]
+    fn serialized_stream_oldest_format_version() {
+        let stream = SerializedRecordBatchStream::write(
+            &test_schema(),
+            test_batches(),
+            MIN_WIRE_FORMAT_VERSION,
+        )
+        .unwrap();
+        let bytes = bincode::serialize(&stream).unwrap();
+        let restored = bincode::deserialize::<SerializedRecordBatchStream>(&bytes).unwrap();
+        assert_eq!(restored.checksum, stream.checksum);
+        assert_eq!(restored.read("node1").unwrap()[0].num_rows(), 3);
+    }
+
+    #[test]
+    fn schema_drift_survives_execution() {
+        let drift = || CubeError::schema_drift(SCHEMA_DRIFT_ERROR.to_string());
+        assert!(CubeError::from(DataFusionError::from(drift())).is_schema_drift());
+        // Errors of partitions are wrapped once more by MergeExec
+        let merged = DataFusionError::ArrowError(ArrowError::ExternalError(Box::new(
+            DataFusionError::from(drift()),
+        )));
+        assert!(CubeError::from(merged).is_schema_drift());
+        let stream_error = ArrowError::from(drift());
+        assert!(CubeError::from(DataFusionError::from(stream_error)).is_schema_drift());
+        assert!(
+            !CubeError::from(DataFusionError::Execution(SCHEMA_DRIFT_ERROR.to_string()))
+                .is_schema_drift()
+        );
+    }
+
+    fn cluster_send_exec(
+        cluster: MockCluster,
+        partition_count: u64,
+        best_effort: bool,
+    ) -> ClusterSendExec {
+        let partitions = (1..=partition_count)
+            .map(|id| {
+                PartitionSnapshot::new(IdRow::new(id, Partition::new(1, None, None)), Vec::new())
+            })
+            .collect::<Vec<_>>();
+        ClusterSendExec::new(
+            test_batches()[0].schema().to_dfschema_ref().unwrap(),
+            Arc::new(cluster),
+            Arc::new(SerializedPlan::empty_for_test()),
+            vec!["node1".to_string()],
+            vec![vec![SerializedPlan::index_snapshot_for_test(
+                test_columns(),
+                partitions,
+            )]],
+            Arc::new(RoundRobinNodeSelector::new()),
+            best_effort,
+        )
+    }
+
+    fn select_stream(batches: Vec<RecordBatch>) -> SelectStream {
+        Box::pin(futures::stream::iter(
+            batches.into_iter().map(|b| Ok(SelectItem::Batch(b))),
+        ))
+    }
+
+    fn cluster_failing_partition(failing_partition: u64) -> MockCluster {
+        let mut cluster = MockCluster::new();
+        cluster
+            .expect_run_select_stream()
+            .returning(move |_, plan| {
+                if plan.partition_ids_to_execute().contains(&failing_partition) {
+                    Err(CubeError::internal("Worker is down".to_string()))
+                } else {
+                    Ok(select_stream(test_batches()))
+                }
+            });
+        cluster
+    }
+
+    fn join_index_snapshot(
+        table_id: u64,
+        partition_ranges: Vec<(Option<i64>, Option<i64>)>,
+        join_column: &str,
+    ) -> IndexSnapshot {
+        join_index_snapshot_with_row_count(table_id, partition_ranges, join_column, 10)
+    }
+
+    fn join_index_snapshot_with_row_count(
+        table_id: u64,
+        partition_ranges: Vec<(Option<i64>, Option<i64>)>,
+        join_column: &str,
+        row_count: u64,
+    ) -> IndexSnapshot {
+        let columns = vec![
+            Column::new("id".to_string(), ColumnType::Int, 0),
+            Column::new("name".to_string(), ColumnType::String, 1),
+        ];
+        let bound = |v: Option<i64>| v.map(|v| Row::new(vec![TableValue::Int(v)]));
+        let partitions = partition_ranges
+            .into_iter()
+            .enumerate()
+            .map(|(i, (min, max))| {
+                PartitionSnapshot::new(
+                    IdRow::new(
+                        table_id * 1000 + i as u64,
+                        Partition::new(table_id, None, None).update_min_max_and_row_count(
+                            bound(min),
+                            bound(max),
+                            row_count,
+                        ),
+                    ),
+                    Vec::new(),
+                )
+            })
+            .collect();
+        IndexSnapshot::new(
+            TablePath {
+                table: IdRow::new(
+                    table_id,
+                    Table::new(format!("t{}", table_id), 1, columns.clone(), None, None),
+                ),
+                schema: Arc::new(IdRow::new(1, MetaSchema::new("foo".to_string()))),
+            },
+            IdRow::new(
+                table_id,
+                Index::try_new("by_id".to_string(), table_id, columns, 1).unwrap(),
+            ),
+            partitions,
+            Some(vec![join_column.to_string()]),
+        )
+        .with_join_type(Some(JoinType::Inner))
+    }
+
+    fn join_partitions(left: IndexSnapshot, right: IndexSnapshot) -> Vec<Vec<u64>> {
+        ClusterSendExec::new(
+            test_batches()[0].schema().to_dfschema_ref().unwrap(),
+            Arc::new(MockCluster::new()),
+            Arc::new(SerializedPlan::empty_for_test()),
+            vec!["node1".to_string()],
+            vec![vec![left], vec![right]],
+            Arc::new(RoundRobinNodeSelector::new()),
+            false,
+        )
+        .partitions
+        .iter()
+        .map(|ps| ps.iter().map(|p| p.get_id()).collect())
+        .collect()
+    }
+
+    #[test]
+    fn join_partitions_with_overlapping_key_ranges() {
+        let left = join_index_snapshot(
+            1,
+            vec![(None, Some(9)), (Some(10), Some(19)), (Some(20), None)],
+            "id",
+        );
+        let right = join_index_snapshot(
+            2,
+            vec![(None, Some(4)), (Some(5), Some(14)), (Some(25), None)],
+            "id",
+        );
+        assert_eq!(
+            join_partitions(left, right),
+            vec![
+                vec![1000, 2000],
+                vec![1000, 2001],
+                vec![1001, 2001],
+                vec![1002, 2002]
+            ]
+        );
+    }
+
+    #[test]
+    fn join_partitions_not_sorted_by_join_key() {
+        let ranges = vec![(None, Some(9)), (Some(10), None)];
+        let left = join_index_snapshot(1, ranges.clone(), "name");
+        let right = join_index_snapshot(2, ranges, "name");
+        assert_eq!(join_partitions(left, right).len(), 4);
+    }
+
+    #[test]
+    fn join_partitions_of_co_partitioned_tables() {
+        let ranges = (0..100)
+            .map(|i| (Some(i * 10), Some(i * 10 + 9)))
+            .collect::<Vec<_>>();
+        let left = join_index_snapshot(1, ranges.clone(), "id");
+        let right = join_index_snapshot(2, ranges.clone(), "id");
+        assert_eq!(join_partitions(left, right).len(), 100);
+
+        let shifted = (0..100)
+            .map(|i| (Some(i * 10 + 5), Some(i * 10 + 14)))
+            .collect::<Vec<_>>();
+        let left = join_index_snapshot(1, ranges, "id");
+        let right = join_index_snapshot(2, shifted, "id");
+        assert_eq!(join_partitions(left, right).len(), 199);
+    }
+
+    #[test]
+    fn join_partitions_skip_empty_side() {
+        let left = join_index_snapshot(1, vec![(None, Some(9)), (Some(10), None)], "id");
+        let empty_right = join_index_snapshot_with_row_count(2, vec![(None, None)], "id", 0);
+        assert!(join_partitions(left.clone(), empty_right).is_empty());
+
+        let right = join_index_snapshot(2, vec![(None, None)], "id");
+        assert_eq!(
+            join_partitions(left, right),
+            vec![vec![1000, 2000], vec![1001, 2000]]
+        );
+    }
+
+    #[test]
+    fn left_join_partitions_with_empty_right_side() {
+        let left = join_index_snapshot(1, vec![(None, Some(9)), (Some(10), None)], "id")
+            .with_join_type(Some(JoinType::Left));
+        let empty_right = join_index_snapshot_with_row_count(2, vec![(None, None)], "id", 0)
+            .with_join_type(Some(JoinType::Left));
+        assert_eq!(
+            join_partitions(left, empty_right),
+            vec![vec![1000], vec![1001]]
+        );
+    }
+
+    #[test]
+    fn left_join_partitions_with_partially_covered_left_side() {
+        let left = join_index_snapshot(
+            1,
+            vec![(None, Some(9)), (Some(10), Some(19)), (Some(20), None)],
+            "id",
+        )
+        .with_join_type(Some(JoinType::Left));
+        let right = join_index_snapshot(2, vec![(Some(5), Some(12)), (Some(13), Some(14))], "id")
+            .with_join_type(Some(JoinType::Left));
+        // Every left partition is sent once with all right partitions it can match
+        assert_eq!(
+            join_partitions(left.clone(), right.clone()),
+            vec![vec![1000, 2000], vec![1001, 2000, 2001], vec![1002]]
+        );
+
+        let right_join = |s: IndexSnapshot| s.with_join_type(Some(JoinType::Right));
+        assert_eq!(
+            join_partitions(right_join(left), right_join(right)),
+            vec![vec![2000, 1000, 1001], vec![2001, 1001]]
+        );
+    }
+
+    #[test]
+    fn scans_without_join_type_use_cartesian_product() {
+        let ranges = vec![(None, Some(9)), (Some(10), None)];
+        let left = join_index_snapshot(1, ranges.clone(), "id").with_join_type(None);
+        let right = join_index_snapshot(2, ranges, "id").with_join_type(None);
+        assert_eq!(join_partitions(left, right).len(), 4);
+    }
+
+    #[test]
+    fn max_cluster_send_partitions() {
+        let exec = cluster_send_exec(MockCluster::new(), 10, false)
+            .with_max_partitions(3)
+            .unwrap();
+        assert_eq!(
+            exec.partitions
+                .iter()
+                .map(|ps| ps.iter().map(|p| p.get_id()).collect::<Vec<_>>())
+                .collect::<Vec<_>>(),
+            vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8], vec![9, 10]]
+        );
+        assert_eq!(
+            cluster_send_exec(MockCluster::new(), 10, false)
+                .with_max_partitions(10)
+                .unwrap()
+                .partitions
+                .len(),
+            10
+        );
+
+        let ranges = vec![(None, Some(9)), (Some(10), None)];
+        let join = ClusterSendExec::new(
+            test_batches()[0].schema().to_dfschema_ref().unwrap(),
+            Arc::new(MockCluster::new()),
+            Arc::new(SerializedPlan::empty_for_test()),
+            vec!["node1".to_string()],
+            vec![
+                vec![join_index_snapshot(1, ranges.clone(), "name")],
+                vec![join_index_snapshot(2, ranges, "name")],
+            ],
+            Arc::new(RoundRobinNodeSelector::new()),
+            false,
+        );
+        let err = join.with_max_partitions(3).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("Query requires 4 worker selects while at most 3 are allowed"));
+    }
+
+    #[tokio::test]
+    async fn estimate_cost_from_snapshot() {
+        let partition = |id: u64, rows: u64, file_size: Option<u64>, chunk_rows: Vec<u64>| {
+            PartitionSnapshot::new(
+                IdRow::new(
+                    id,
+                    Partition::new(1, None, None)
+                        .update_min_max_and_row_count(None, None, rows)
+                        .with_file_size(file_size),
+                ),
+                chunk_rows
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, rows)| IdRow::new(id * 10 + i as u64, Chunk::new(id, rows)))
+                    .collect(),
+            )
+        };
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            vec![
+                partition(1, 100, Some(1000), vec![10, 5]),
+                partition(2, 200, Some(4000), Vec::new()),
+                partition(3, 0, None, vec![7]),
+            ],
+        ));
+        let config = Config::test("estimate_cost_from_snapshot").update_config(|mut c| {
+            c.max_cluster_send_partitions = 2;
+            c
+        });
+        let query_executor = QueryExecutorImpl::new(config.config_obj());
+        // Cluster has no expectations as it isn't contacted
+        let cost = query_executor
+            .estimate_cost(plan, Arc::new(MockCluster::new()))
+            .await
+            .unwrap();
+        assert_eq!(cost.rows(), 322);
+        // Chunks add 15% to the first file while the last partition has no file so its int id
+        // and string name are counted
+        assert_eq!(cost.bytes(), 1150 + 4000 + 7 * (8 + 32));
+        assert_eq!(cost.partitions(), 3);
+        assert_eq!(cost.worker_selects(), 2);
+    }
+
+    #[tokio::test]
+    async fn no_available_nodes() {
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            Vec::new(),
+        )];
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            partitions,
+        ));
+        let mut cluster = MockCluster::new();
+        cluster.expect_available_nodes().returning(|| Ok(vec![]));
+        cluster.expect_run_select_stream().times(0);
+        let query_executor =
+            QueryExecutorImpl::new(Config::test("no_available_nodes").config_obj());
+        let err = query_executor
+            .execute_router_plan(plan, Arc::new(cluster))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("no available worker nodes"),
+            "{}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn tiny_query_runs_on_router() {
+        let config = Config::test("tiny_query_runs_on_router").update_config(|mut c| {
+            c.local_execution_row_threshold = 1000;
+            c
+        });
+        let query_executor = QueryExecutorImpl::new(config.config_obj());
+        let mut cluster = MockCluster::new();
+        cluster.expect_run_select_stream().times(0);
+        cluster.expect_available_nodes().times(0);
+        let df = query_executor
+            .execute_router_plan(SerializedPlan::empty_for_test(), Arc::new(cluster))
+            .await
+            .unwrap();
+        assert_eq!(df.len(), 0);
+        assert!(df.get_stats().is_local());
+
+        let mut cluster = MockCluster::new();
+        cluster.expect_run_select_stream().times(0);
+        cluster.expect_available_nodes().times(0);
+        let explanation = query_executor
+            .explain_router_plan(SerializedPlan::empty_for_test(), Arc::new(cluster))
+            .await
+            .unwrap();
+        assert_eq!(explanation[0], "Execution: local");
+        assert!(
+            explanation.iter().all(|l| !l.contains("ClusterSendExec")),
+            "{:?}",
+            explanation
+        );
+    }
+
+    #[tokio::test]
+    async fn tiny_query_with_remote_files_runs_on_workers() {
+        let partition = PartitionSnapshot::new(
+            IdRow::new(2, Partition::new(1, None, None).child(1)),
+            Vec::new(),
+        );
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            vec![partition],
+        ));
+        let config =
+            Config::test("tiny_query_with_remote_files_runs_on_workers").update_config(|mut c| {
+                c.local_execution_row_threshold = 1000;
+                c
+            });
+        let query_executor = QueryExecutorImpl::new(config.config_obj());
+        let mut cluster = MockCluster::new();
+        cluster
+            .expect_is_downloaded()
+            .withf(|remote_path| remote_path == "2.parquet")
+            .returning(|_| Ok(false));
+        cluster.expect_download().times(0);
+        cluster
+            .expect_available_nodes()
+            .returning(|| Ok(vec!["node1".to_string()]));
+        cluster
+            .expect_node_wire_format_version()
+            .returning(|_| Ok(WIRE_FORMAT_VERSION));
+        cluster
+            .expect_run_select_stream()
+            .times(1)
+            .returning(|_, _| Ok(select_stream(test_batches())));
+        query_executor
+            .execute_router_plan(plan, Arc::new(cluster))
+            .await
+            .unwrap();
+    }
+
+    fn has_cluster_send(plan: &Arc<dyn ExecutionPlan>) -> bool {
+        plan.as_any().downcast_ref::<ClusterSendExec>().is_some()
+            || plan.children().iter().any(has_cluster_send)
+    }
+
+    #[tokio::test]
+    async fn single_node_runs_without_cluster_send() {
+        let partitions = vec![PartitionSnapshot::new(
+            IdRow::new(1, Partition::new(1, None, None)),
+            Vec::new(),
+        )];
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            partitions,
+        ));
+        let logical_plan = plan
+            .logical_plan(
+                &HashMap::new(),
+                1,
+                &HashMap::new(),
+                &ParquetMetadataCache::new(),
+            )
+            .unwrap();
+        let single_node_cluster = || {
+            let mut cluster = MockCluster::new();
+            cluster
+                .expect_available_nodes()
+                .returning(|| Ok(vec!["node1".to_string()]));
+            cluster
+                .expect_server_name()
+                .return_const("node1".to_string());
+            cluster
+                .expect_node_wire_format_version()
+                .returning(|_| Ok(WIRE_FORMAT_VERSION));
+            Arc::new(cluster)
+        };
+
+        let config = Config::test("single_node_runs_without_cluster_send");
+        let query_executor = QueryExecutorImpl::new(config.config_obj());
+        let (router_plan, is_local) = query_executor
+            .get_router_plan(
+                &plan,
+                &logical_plan,
+                single_node_cluster(),
+                &mut RouterQueryTimings::default(),
+            )
+            .await
+            .unwrap();
+        assert!(!is_local);
+        assert!(has_cluster_send(&router_plan));
+
+        let config = config.update_config(|mut c| {
+            c.single_node_local_execution = true;
+            c
+        });
+        let query_executor = QueryExecutorImpl::new(config.config_obj());
+        let (router_plan, is_local) = query_executor
+            .get_router_plan(
+                &plan,
+                &logical_plan,
+                single_node_cluster(),
+                &mut RouterQueryTimings::default(),
+            )
+            .await
+            .unwrap();
+        assert!(is_local);
+        assert!(!has_cluster_send(&router_plan));
+    }
+
+    #[tokio::test]
+    async fn planning_error_includes_logical_plan() {
+        let valid = LogicalPlanBuilder::empty(true)
+            .project(vec![lit(1i64).alias("answer")])
+            .unwrap()
+            .build()
+            .unwrap();
+        // Projection of a column its input doesn't have
+        let plan = LogicalPlan::Projection {
+            expr: vec![col("answer")],
+            input: Arc::new(LogicalPlanBuilder::empty(true).build().unwrap()),
+            schema: valid.schema().clone(),
+        };
+        let query_executor = QueryExecutorImpl::new(
+            Config::test("planning_error_includes_logical_plan").config_obj(),
+        );
+        let error = query_executor
+            .execute_worker_plan(SerializedPlan::without_tables(&plan), HashMap::new())
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("Can't create physical plan"), "{}", error);
+        assert!(error.contains("Projection: #answer"), "{}", error);
+        assert!(error.contains("EmptyRelation"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn cancel_connection_queries() {
+        let mut cluster = MockCluster::new();
+        cluster
+            .expect_available_nodes()
+            .returning(|| Ok(vec!["node1".to_string()]));
+        cluster
+            .expect_node_wire_format_version()
+            .returning(|_| Ok(WIRE_FORMAT_VERSION));
+        // Workers never respond. Streams of their selects are counted until dropped.
+        let open_streams = Arc::new(());
+        let streams = open_streams.clone();
+        let worker_query_ids = Arc::new(Mutex::new(HashSet::new()));
+        let query_ids = worker_query_ids.clone();
+        cluster
+            .expect_run_select_stream()
+            .returning(move |_, plan| {
+                query_ids
+                    .lock()
+                    .unwrap()
+                    .insert(plan.query_id().to_string());
+                let stream = streams.clone();
+                Ok(Box::pin(futures::stream::pending().map(move |item| {
+                    let _stream = &stream;
+                    item
+                })))
+            });
+        let cluster: Arc<dyn Cluster> = Arc::new(cluster);
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            vec![PartitionSnapshot::new(
+                IdRow::new(1, Partition::new(1, None, None)),
+                Vec::new(),
+            )],
+        ));
+        let query_executor =
+            QueryExecutorImpl::new(Config::test("cancel_connection_queries").config_obj());
+
+        let queries = (0..2)
+            .map(|_| {
+                let query_executor = query_executor.clone();
+                let cluster = cluster.clone();
+                let plan = plan.clone();
+                tokio::spawn(async move {
+                    query_executor
+                        .execute_router_plan_for_connection(plan, cluster, "conn1".to_string())
+                        .await
+                })
+            })
+            .collect::<Vec<_>>();
+        while query_executor.running_query_count("conn1") < 2
+            || worker_query_ids.lock().unwrap().len() < 2
+        {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        // Workers get the ids the queries are registered with
+        let running_ids = query_executor.running_queries.lock().unwrap()["conn1"]
+            .keys()
+            .cloned()
+            .collect::<HashSet<_>>();
+        assert_eq!(running_ids, *worker_query_ids.lock().unwrap());
+
+        assert_eq!(query_executor.cancel_connection("conn2"), 0);
+        assert_eq!(query_executor.cancel_connection("conn1"), 2);
+        for query in queries {
+            let err = query.await.unwrap().unwrap_err();
+            assert!(err.to_string().contains("cancelled"), "{}", err);
+        }
+        assert_eq!(query_executor.running_query_count("conn1"), 0);
+        assert_eq!(query_executor.cancel_connection("conn1"), 0);
+        // Tasks of the plans reading the streams stop as well
+        let mut attempts = 0;
+        while Arc::strong_count(&open_streams) > 2 && attempts < 100 {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            attempts += 1;
+        }
+        assert_eq!(Arc::strong_count(&open_streams), 2);
+    }
+
+    #[tokio::test]
+    async fn query_id_in_logs() {
+        let config = Config::test("query_id_in_logs").update_config(|mut c| {
+            c.local_execution_row_threshold = 1000;
+            c
+        });
+        let query_executor = QueryExecutorImpl::new(config.config_obj());
+
+        start_capturing_test_logs();
+        query_executor
+            .execute_router_plan(
+                SerializedPlan::empty_for_test(),
+                Arc::new(MockCluster::new()),
+            )
+            .await
+            .unwrap();
+        let logs = take_captured_test_logs();
+        let query_id = logs
+            .iter()
+            .find_map(|l| {
+                l.strip_prefix("Query ")
+                    .and_then(|l| l.split(' ').next())
+                    .map(|id| id.to_string())
+            })
+            .unwrap_or_else(|| panic!("No router query log in {:?}", logs));
+        assert!(Uuid::parse_str(&query_id).is_ok(), "{}", query_id);
+
+        let worker_plan = LogicalPlanBuilder::empty(true)
+            .project(vec![lit(1i64)])
+            .unwrap()
+            .build()
+            .unwrap();
+        start_capturing_test_logs();
+        query_executor
+            .execute_worker_plan(
+                SerializedPlan::without_tables(&worker_plan).with_query_id(query_id.clone()),
+                HashMap::new(),
+            )
+            .await
+            .unwrap();
+        let logs = take_captured_test_logs();
+        assert!(
+            logs.iter().any(|l| l.starts_with(&format!(
+                "Partition Query {} data processing time",
+                query_id
+            ))),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[tokio::test]
+    async fn slow_query_log_has_phase_timings() {
+        let partition =
+            PartitionSnapshot::new(IdRow::new(1, Partition::new(1, None, None)), Vec::new());
+        let plan = SerializedPlan::scan_for_test(SerializedPlan::index_snapshot_for_test(
+            test_columns(),
+            vec![partition],
+        ));
+        let mut cluster = MockCluster::new();
+        cluster
+            .expect_available_nodes()
+            .returning(|| Ok(vec!["node1".to_string()]));
+        cluster
+            .expect_node_wire_format_version()
+            .returning(|_| Ok(WIRE_FORMAT_VERSION));
+        cluster.expect_run_select_stream().returning(|_, _| {
+            std::thread::sleep(std::time::Duration::from_millis(250));
+            Ok(select_stream(test_batches()))
+        });
+        let query_executor =
+            QueryExecutorImpl::new(Config::test("slow_query_log_has_phase_timings").config_obj());
+
+        start_capturing_test_logs();
+        query_executor
+            .execute_router_plan(plan, Arc::new(cluster))
+            .await
+            .unwrap();
+        let logs = take_captured_test_logs();
+        let slow_query = logs
+            .iter()
+            .find(|l| l.starts_with("Slow Query "))
+            .unwrap_or_else(|| panic!("No slow query log in {:?}", logs));
+        for phase in &["planning: ", "split: ", "execution: "] {
+            assert!(slow_query.contains(phase), "{}", slow_query);
+        }
+    }
+
+    #[tokio::test]
+    async fn scalar_query_returns_one_row() {
+        let plan = LogicalPlanBuilder::empty(true)
+            .project(vec![lit(1i64)])
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut cluster = MockCluster::new();
+        cluster
+            .expect_available_nodes()
+            .returning(|| Ok(vec!["node1".to_string()]));
+        cluster
+            .expect_node_wire_format_version()
+            .returning(|_| Ok(WIRE_FORMAT_VERSION));
+        cluster.expect_run_select().times(0);
+        let query_executor =
+            QueryExecutorImpl::new(Config::test("scalar_query_returns_one_row").config_obj());
+        let df = query_executor
+            .execute_router_plan(SerializedPlan::without_tables(&plan), Arc::new(cluster))
+            .await
+            .unwrap();
+        assert_eq!(df.get_rows(), &vec![Row::new(vec![TableValue::Int(1)])]);
+    }
+
+    #[tokio::test]
+    async fn self_check_runs_scalar_query() {
+        let query_executor =
+            QueryExecutorImpl::new(Config::test("self_check_runs_scalar_query").config_obj());
+        let cluster = |worker_result: fn() -> Result<Vec<RecordBatch>, CubeError>| {
+            let mut cluster = MockCluster::new();
+            cluster
+                .expect_available_nodes()
+                .returning(|| Ok(vec!["node1".to_string()]));
+            cluster
+                .expect_node_wire_format_version()
+                .returning(|_| Ok(WIRE_FORMAT_VERSION));
+            cluster
+                .expect_run_select()
+                .times(1)
+                .returning(move |_, _| worker_result());
+            Arc::new(cluster)
+        };
+        query_executor
+            .self_check(cluster(|| {
+                Ok(vec![RecordBatch::try_new(
+                    Arc::new(Schema::new(vec![Field::new("1", DataType::Int64, false)])),
+                    vec![Arc::new(Int64Array::from(vec![1]))],
+                )
+                .unwrap()])
+            }))
+            .await
+            .unwrap();
+
+        let error = query_executor
+            .self_check(cluster(|| {
+                Err(CubeError::internal("Worker is down".to_string()))
+            }))
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Worker is down"), "{}", error);
+
+        let mut down = MockCluster::new();
+        down.expect_available_nodes()
+            .returning(|| Err(CubeError::internal("Cluster is down".to_string())));
+        down.expect_run_select().times(0);
+        let error = query_executor.self_check(Arc::new(down)).await.unwrap_err();
+        assert!(error.to_string().contains("Cluster is down"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn cluster_send_forwards_batches_incrementally() {
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let receiver = Mutex::new(Some(receiver));
+        let mut cluster = MockCluster::new();
+        cluster.expect_run_select_stream().times(1).returning(
+            move |_, _| -> Result<SelectStream, CubeError> {
+                Ok(Box::pin(receiver.lock().unwrap().take().unwrap()))
+            },
+        );
+        let exec = cluster_send_exec(cluster, 1, false);
+        let mut stream = exec.execute(0).await.unwrap();
+
+        for i in 1..=3 {
+            // Worker hasn't sent the batch yet
+            assert!(futures::poll!(stream.next()).is_pending());
+            sender
+                .unbounded_send(Ok(SelectItem::Batch(test_batches()[0].clone())))
+                .unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap().num_rows(), 3);
+            assert_eq!(exec.stats().rows_received(), 3 * i);
+        }
+        // Scan stats of the worker follow its last batch
+        let worker_scans = ScanMetrics::default();
+        worker_scans.file_opened(4);
+        worker_scans.batch_read(9, Duration::from_millis(5));
+        sender
+            .unbounded_send(Ok(SelectItem::ScanStats(worker_scans.stats())))
+            .unwrap();
+        drop(sender);
+        assert!(stream.next().await.is_none());
+        let stats = exec.stats();
+        assert_eq!(stats.scan().files_opened(), 1);
+        assert_eq!(stats.scan().row_groups_read(), 4);
+        assert_eq!(stats.scan().rows(), 9);
+        assert_eq!(stats.rows_received(), 9);
+    }
+
+    #[tokio::test]
+    async fn best_effort_skips_failed_partition() {
+        let exec = Arc::new(cluster_send_exec(cluster_failing_partition(2), 3, true));
+        let batches = collect(Arc::new(MergeExec::new(exec.clone())))
+            .await
+            .unwrap();
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 6);
+        let warnings = exec.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("[2]"), "{}", warnings[0]);
+        assert!(warnings[0].contains("Worker is down"), "{}", warnings[0]);
+    }
+
+    #[tokio::test]
+    async fn strict_mode_fails_on_failed_partition() {
+        let exec = Arc::new(cluster_send_exec(cluster_failing_partition(2), 3, false));
+        assert!(collect(Arc::new(MergeExec::new(exec.clone())))
+            .await
+            .is_err());
+        assert!(exec.warnings().is_empty());
+    }
+
+    #[tokio::test]
+    async fn stats_count_scanned_partitions() {
+        let exec = Arc::new(cluster_send_exec(cluster_failing_partition(0), 4, false));
+        collect(Arc::new(MergeExec::new(exec.clone())))
+            .await
+            .unwrap();
+        let stats = exec.stats();
+        assert_eq!(stats.partition_count(), 4);
+        assert_eq!(stats.nodes().iter().collect::<Vec<_>>(), vec!["node1"]);
+        assert!(stats.bytes_received() > 0);
+
+        let exec = Arc::new(cluster_send_exec(cluster_failing_partition(2), 4, true));
+        collect(Arc::new(MergeExec::new(exec.clone())))
+            .await
+            .unwrap();
+        assert_eq!(exec.stats().partition_count(), 3);
+    }
 }
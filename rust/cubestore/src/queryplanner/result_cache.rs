@@ -0,0 +1,255 @@
+use crate::queryplanner::serialized_plan::SerializedPlan;
+use crate::store::memory_chunks::batches_size;
+use crate::CubeError;
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use log::trace;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::sync::Mutex;
+
+/// LRU cache of worker plan results.
+/// Partition and chunk files are immutable and named by their ids so any change to the
+/// underlying data produces a different plan snapshot and as a result a different cache key.
+/// Keys are whole serialized plans so different plans never share an entry.
+/// Least recently used entries are evicted once batches of all entries take more than `max_size`
+/// bytes. Zero `max_size` disables the cache.
+pub struct WorkerResultCache {
+    max_size: u64,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    entries: HashMap<Vec<u8>, ((SchemaRef, Vec<RecordBatch>), u64)>,
+    lru: VecDeque<Vec<u8>>,
+    size: u64,
+}
+
+impl WorkerResultCache {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            max_size,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                lru: VecDeque::new(),
+                size: 0,
+            }),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    pub fn plan_key(plan: &SerializedPlan) -> Result<Vec<u8>, CubeError> {
+        // HashSet serialization order isn't stable so partition ids are sorted and appended.
+        // Query id is unique per query and doesn't affect results.
+        let mut key = bincode::serialize(
+            &plan
+                .with_partition_id_to_execute(HashSet::new())
+                .with_query_id(String::new()),
+        )?;
+        let mut partition_ids = plan
+            .partition_ids_to_execute()
+            .into_iter()
+            .collect::<Vec<_>>();
+        partition_ids.sort();
+        key.extend(bincode::serialize(&partition_ids)?);
+        Ok(key)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<(SchemaRef, Vec<RecordBatch>)> {
+        let mut state = self.state.lock().unwrap();
+        let res = state.entries.get(key).map(|(result, _)| result.clone());
+        if res.is_some() {
+            state.touch(key);
+        }
+        res
+    }
+
+    /// Results larger than `max_size` aren't kept.
+    pub fn insert(&self, key: Vec<u8>, result: (SchemaRef, Vec<RecordBatch>)) {
+        let size = batches_size(&result.1);
+        if !self.is_enabled() || size > self.max_size {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.remove(&key);
+        while state.size + size > self.max_size {
+            match state.lru.front().cloned() {
+                Some(evicted) => {
+                    trace!(
+                        "Evicting worker result cache entry of {} bytes",
+                        state.entries[&evicted].1
+                    );
+                    state.remove(&evicted);
+                }
+                None => break,
+            }
+        }
+        state.lru.push_back(key.clone());
+        state.entries.insert(key, (result, size));
+        state.size += size;
+    }
+
+    pub async fn get_or_execute<F>(
+        &self,
+        plan: &SerializedPlan,
+        execute: F,
+    ) -> Result<(SchemaRef, Vec<RecordBatch>), CubeError>
+    where
+        F: Future<Output = Result<(SchemaRef, Vec<RecordBatch>), CubeError>>,
+    {
+        if !self.is_enabled() {
+            return execute.await;
+        }
+        let key = Self::plan_key(plan)?;
+        if let Some(result) = self.get(&key) {
+            trace!("Worker result cache hit for query {}", plan.query_id());
+            return Ok(result);
+        }
+        let result = execute.await?;
+        self.insert(key, result.clone());
+        Ok(result)
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Total size of cached batches in bytes.
+    pub fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+}
+
+impl CacheState {
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(pos) = self.lru.iter().position(|k| k.as_slice() == key) {
+            self.lru.remove(pos);
+        }
+        self.lru.push_back(key.to_vec());
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some((_, size)) = self.entries.remove(key) {
+            self.size -= size;
+            if let Some(pos) = self.lru.iter().position(|k| k.as_slice() == key) {
+                self.lru.remove(pos);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn result(value: i64) -> (SchemaRef, Vec<RecordBatch>) {
+        result_of_rows(value, 1)
+    }
+
+    fn result_of_rows(value: i64, rows: usize) -> (SchemaRef, Vec<RecordBatch>) {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![value; rows]))],
+        )
+        .unwrap();
+        (schema, vec![batch])
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let cache = WorkerResultCache::new(2 * batches_size(&result(1).1));
+        cache.insert(vec![1], result(1));
+        cache.insert(vec![2], result(2));
+        assert!(cache.get(&[1]).is_some());
+        cache.insert(vec![3], result(3));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&[1]).is_some());
+        assert!(cache.get(&[2]).is_none());
+        assert!(cache.get(&[3]).is_some());
+    }
+
+    #[test]
+    fn eviction_by_size() {
+        let small_size = batches_size(&result(1).1);
+        let large = result_of_rows(4, 1000);
+        let large_size = batches_size(&large.1);
+        assert!(large_size > 2 * small_size);
+        let cache = WorkerResultCache::new(large_size + small_size);
+        cache.insert(vec![1], result(1));
+        cache.insert(vec![2], result(2));
+        cache.insert(vec![3], result(3));
+        assert_eq!(cache.len(), 3);
+        assert_eq!(cache.size(), 3 * small_size);
+
+        // Room for the large result is made by evicting least recently used entries
+        assert!(cache.get(&[1]).is_some());
+        cache.insert(vec![4], large.clone());
+        assert_eq!(cache.size(), large_size + small_size);
+        assert!(cache.get(&[1]).is_some());
+        assert!(cache.get(&[2]).is_none());
+        assert!(cache.get(&[3]).is_none());
+        assert!(cache.get(&[4]).is_some());
+
+        // Result larger than the whole cache isn't kept and doesn't evict anything
+        cache.insert(vec![5], result_of_rows(5, 2000));
+        assert!(cache.get(&[5]).is_none());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn disabled_cache_keeps_nothing() {
+        let cache = WorkerResultCache::new(0);
+        cache.insert(vec![1], result(1));
+        assert_eq!(cache.len(), 0);
+        assert!(cache.get(&[1]).is_none());
+    }
+
+    #[tokio::test]
+    async fn second_execution_hits_cache() {
+        let cache = WorkerResultCache::new(1 << 20);
+        let plan = SerializedPlan::empty_for_test();
+        let scans = Arc::new(AtomicUsize::new(0));
+        for _ in 0..2 {
+            let scans_to_move = scans.clone();
+            let res = cache
+                .get_or_execute(&plan, async move {
+                    scans_to_move.fetch_add(1, Ordering::SeqCst);
+                    Ok(result(42))
+                })
+                .await
+                .unwrap();
+            assert_eq!(res.1.len(), 1);
+        }
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
+
+        let other_query = plan.with_query_id("other".to_string());
+        let scans_to_move = scans.clone();
+        cache
+            .get_or_execute(&other_query, async move {
+                scans_to_move.fetch_add(1, Ordering::SeqCst);
+                Ok(result(42))
+            })
+            .await
+            .unwrap();
+        assert_eq!(scans.load(Ordering::SeqCst), 1);
+
+        let other_partitions = plan.with_partition_id_to_execute(vec![1].into_iter().collect());
+        let scans_to_move = scans.clone();
+        cache
+            .get_or_execute(&other_partitions, async move {
+                scans_to_move.fetch_add(1, Ordering::SeqCst);
+                Ok(result(42))
+            })
+            .await
+            .unwrap();
+        assert_eq!(scans.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,335 @@
+use crate::queryplanner::window::{column_name, compare_rows, SortKey};
+use crate::store::{DataFrame, ExecutionStats};
+use crate::CubeError;
+use sqlparser::ast::{Expr, Function, Ident, Query, SelectItem, SetExpr, Value};
+
+/// `GROUP BY ROLLUP(k1, ..., kn)` expanded into a separate aggregation for each of the
+/// `k1, ..., ki` prefixes. Every branch runs through the regular router and worker split and
+/// results are concatenated on the router. Keys rolled up in a branch are selected as `NULL` and
+/// `GROUPING(k)` selects `1` for them to tell subtotals from `NULL` keys in the data.
+#[derive(Debug)]
+pub struct RollupPlan {
+    /// Branches from the most detailed to the grand total.
+    branches: Vec<Query>,
+    order_by: Vec<SortKey>,
+    limit: Option<usize>,
+}
+
+impl RollupPlan {
+    /// `None` if `query` doesn't group by `ROLLUP`.
+    pub fn extract(query: &Query) -> Result<Option<RollupPlan>, CubeError> {
+        let select = match &query.body {
+            SetExpr::Select(select) => select,
+            _ => return Ok(None),
+        };
+        let rollups = select
+            .group_by
+            .iter()
+            .filter(|e| function_call(e, "rollup").is_some())
+            .count();
+        if rollups == 0 {
+            return Ok(None);
+        }
+        if rollups > 1 {
+            return Err(CubeError::user(
+                "Only one ROLLUP is supported in GROUP BY".to_string(),
+            ));
+        }
+        if query.offset.is_some() || query.fetch.is_some() {
+            return Err(CubeError::user(
+                "OFFSET and FETCH can't be used along with ROLLUP".to_string(),
+            ));
+        }
+        let keys = select
+            .group_by
+            .iter()
+            .find_map(|e| function_call(e, "rollup"))
+            .unwrap()
+            .args
+            .clone();
+        let key_columns = keys
+            .iter()
+            .map(|key| resolve_column(key, &select.projection))
+            .collect::<Vec<_>>();
+        // ROLLUP key each column selects and the key of each `GROUPING()` column.
+        let rolled_up = (0..select.projection.len())
+            .map(|column| key_columns.iter().position(|c| *c == Some(column)))
+            .collect::<Vec<_>>();
+        let groupings = select
+            .projection
+            .iter()
+            .map(|item| match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    match function_call(expr, "grouping") {
+                        Some(grouping) => {
+                            grouping_key(grouping, &keys, &key_columns, &select.projection)
+                                .map(Some)
+                        }
+                        None => Ok(None),
+                    }
+                }
+                _ => Ok(None),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let names = select
+            .projection
+            .iter()
+            .map(column_name)
+            .collect::<Vec<_>>();
+
+        let mut order_by = Vec::new();
+        for o in query.order_by.iter() {
+            let column = match &o.expr {
+                Expr::Value(Value::Number(n)) => n
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|p| *p >= 1 && *p <= names.len())
+                    .map(|p| p - 1),
+                Expr::Identifier(ident) => names
+                    .iter()
+                    .position(|name| name.as_deref() == Some(ident.value.as_str())),
+                _ => None,
+            };
+            let column = column.ok_or_else(|| {
+                CubeError::user(format!(
+                    "ORDER BY of ROLLUP query is expected to refer to a selected column but found {}",
+                    o.expr
+                ))
+            })?;
+            order_by.push(SortKey::new(o, column));
+        }
+        let limit = match &query.limit {
+            Some(Expr::Value(Value::Number(n))) => Some(n.parse::<usize>().map_err(|_| {
+                CubeError::user(format!("LIMIT is expected to be a number but found {}", n))
+            })?),
+            Some(e) => {
+                return Err(CubeError::user(format!(
+                    "LIMIT is expected to be a number but found {}",
+                    e
+                )))
+            }
+            None => None,
+        };
+
+        let mut branches = Vec::new();
+        for grouped in (0..=keys.len()).rev() {
+            let mut branch = query.clone();
+            branch.order_by.clear();
+            branch.limit = None;
+            let select = match &mut branch.body {
+                SetExpr::Select(select) => select,
+                _ => unreachable!(),
+            };
+            select.group_by = select
+                .group_by
+                .iter()
+                .flat_map(|e| match function_call(e, "rollup") {
+                    Some(_) => keys[..grouped].to_vec(),
+                    None => vec![e.clone()],
+                })
+                .collect();
+            for (column, item) in select.projection.iter_mut().enumerate() {
+                let value = match (groupings[column], rolled_up[column]) {
+                    (Some(key), _) if key < grouped => Value::Number("0".to_string()),
+                    (Some(_), _) => Value::Number("1".to_string()),
+                    (None, Some(key)) if key >= grouped => Value::Null,
+                    _ => continue,
+                };
+                let alias = match item {
+                    SelectItem::UnnamedExpr(expr) => Ident::new(expr.to_string()),
+                    SelectItem::ExprWithAlias { alias, .. } => alias.clone(),
+                    _ => continue,
+                };
+                *item = SelectItem::ExprWithAlias {
+                    expr: Expr::Value(value),
+                    alias,
+                };
+            }
+            branches.push(branch);
+        }
+        Ok(Some(RollupPlan {
+            branches,
+            order_by,
+            limit,
+        }))
+    }
+
+    pub fn branches(&self) -> &Vec<Query> {
+        &self.branches
+    }
+
+    /// Concatenates results of `branches` and applies `ORDER BY` and `LIMIT` of the query.
+    pub fn combine(&self, results: Vec<DataFrame>) -> Result<DataFrame, CubeError> {
+        let mut columns = None;
+        let mut warnings = Vec::new();
+        let mut stats = ExecutionStats::default();
+        let mut rows = Vec::new();
+        for result in results {
+            for warning in result.get_warnings() {
+                if !warnings.contains(warning) {
+                    warnings.push(warning.clone());
+                }
+            }
+            stats.merge(result.get_stats());
+            if columns.is_none() {
+                columns = Some(result.get_columns().clone());
+            }
+            rows.extend(result.into_rows());
+        }
+        rows.sort_by(|a, b| compare_rows(a.values(), b.values(), &self.order_by));
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        Ok(DataFrame::new(columns.unwrap_or_default(), rows)
+            .with_warnings(warnings)
+            .with_stats(stats))
+    }
+}
+
+fn function_call<'a>(expr: &'a Expr, name: &str) -> Option<&'a Function> {
+    match expr {
+        Expr::Function(function)
+            if function.over.is_none() && function.name.to_string().to_lowercase() == name =>
+        {
+            Some(function)
+        }
+        _ => None,
+    }
+}
+
+/// Index of the `ROLLUP` key `GROUPING(key)` refers to.
+fn grouping_key(
+    grouping: &Function,
+    keys: &[Expr],
+    key_columns: &[Option<usize>],
+    projection: &[SelectItem],
+) -> Result<usize, CubeError> {
+    let key = match grouping.args.as_slice() {
+        [arg] => {
+            let column = resolve_column(arg, projection);
+            keys.iter()
+                .zip(key_columns.iter())
+                .position(|(key, key_column)| {
+                    key == arg || (column.is_some() && *key_column == column)
+                })
+        }
+        _ => None,
+    };
+    key.ok_or_else(|| {
+        CubeError::user(format!(
+            "GROUPING is expected to take one of ROLLUP keys but found {}",
+            grouping
+        ))
+    })
+}
+
+/// Selected column `expr` refers to by position, alias or the same expression.
+fn resolve_column(expr: &Expr, projection: &[SelectItem]) -> Option<usize> {
+    if let Expr::Value(Value::Number(n)) = expr {
+        return n
+            .parse::<usize>()
+            .ok()
+            .filter(|p| *p >= 1 && *p <= projection.len())
+            .map(|p| p - 1);
+    }
+    projection.iter().position(|item| match item {
+        SelectItem::UnnamedExpr(e) => e == expr,
+        SelectItem::ExprWithAlias { expr: e, alias } => {
+            e == expr || matches!(expr, Expr::Identifier(ident) if ident.value == alias.value)
+        }
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::{Column, ColumnType};
+    use crate::table::{Row, TableValue};
+    use sqlparser::ast::Statement;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn extract(sql: &str) -> Result<Option<RollupPlan>, CubeError> {
+        match Parser::parse_sql(&GenericDialect {}, sql).unwrap().pop() {
+            Some(Statement::Query(query)) => RollupPlan::extract(&query),
+            _ => panic!("Query expected"),
+        }
+    }
+
+    #[test]
+    fn expands_rollup() {
+        let plan = extract(
+            "SELECT country, city, sum(amount) total, GROUPING(city) gc FROM s.t \
+             GROUP BY ROLLUP(country, 2) ORDER BY 1, gc LIMIT 5",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            plan.branches()
+                .iter()
+                .map(|q| q.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "SELECT country, city, sum(amount) AS total, 0 AS gc FROM s.t GROUP BY country, 2",
+                "SELECT country, NULL AS city, sum(amount) AS total, 1 AS gc FROM s.t GROUP BY country",
+                "SELECT NULL AS country, NULL AS city, sum(amount) AS total, 1 AS gc FROM s.t",
+            ]
+        );
+        assert_eq!(plan.limit, Some(5));
+
+        assert!(extract("SELECT country, sum(amount) FROM s.t GROUP BY 1")
+            .unwrap()
+            .is_none());
+        assert!(
+            extract("SELECT country, GROUPING(city) FROM s.t GROUP BY ROLLUP(country)")
+                .unwrap_err()
+                .to_string()
+                .contains("GROUPING")
+        );
+    }
+
+    #[test]
+    fn combines_branches() {
+        let plan = extract(
+            "SELECT city, sum(amount) total, GROUPING(city) g FROM s.t \
+             GROUP BY ROLLUP(city) ORDER BY 1, 3",
+        )
+        .unwrap()
+        .unwrap();
+        let columns = ["city", "total", "g"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Column::new(name.to_string(), ColumnType::Int, i))
+            .collect::<Vec<_>>();
+        let row = |city: Option<i64>, total: i64, g: i64| {
+            Row::new(vec![
+                city.map(TableValue::Int).unwrap_or(TableValue::Null),
+                TableValue::Int(total),
+                TableValue::Int(g),
+            ])
+        };
+        let result = plan
+            .combine(vec![
+                DataFrame::new(
+                    columns.clone(),
+                    vec![row(Some(2), 5, 0), row(None, 3, 0), row(Some(1), 4, 0)],
+                )
+                .with_warnings(vec!["w".to_string()]),
+                DataFrame::new(columns.clone(), vec![row(None, 12, 1)])
+                    .with_warnings(vec!["w".to_string()]),
+            ])
+            .unwrap();
+        assert_eq!(result.get_columns(), &columns);
+        assert_eq!(result.get_warnings(), &vec!["w".to_string()]);
+        assert_eq!(
+            result.get_rows(),
+            &vec![
+                row(Some(1), 4, 0),
+                row(Some(2), 5, 0),
+                row(None, 3, 0),
+                row(None, 12, 1),
+            ]
+        );
+    }
+}
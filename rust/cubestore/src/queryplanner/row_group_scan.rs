@@ -0,0 +1,569 @@
+use crate::metastore::{Column, ColumnType};
+use crate::queryplanner::pruning::can_match_columns;
+use crate::queryplanner::udfs::int64_decimal_array;
+use crate::table::{TableValue, TimestampValue};
+use crate::CubeError;
+use arrow::array::{
+    new_null_array, ArrayRef, BinaryArray, BooleanArray, Int64Array, StringArray,
+    TimestampMicrosecondArray,
+};
+use arrow::datatypes::{DataType, SchemaRef, TimeUnit};
+use arrow::error::{ArrowError, Result as ArrowResult};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use bigdecimal::{BigDecimal, Num, ToPrimitive};
+use datafusion::error::DataFusionError;
+use datafusion::logical_plan::{DFSchemaRef, Expr};
+use datafusion::physical_plan::{ExecutionPlan, Partitioning, RecordBatchStream};
+use futures::channel::mpsc::{channel, Receiver, Sender};
+use futures::{SinkExt, Stream, StreamExt};
+use parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use parquet::compression::create_codec;
+use parquet::file::metadata::ParquetMetaData;
+use parquet::file::reader::{FileReader, RowGroupReader, SerializedFileReader};
+use parquet::file::statistics::Statistics;
+use parquet::record::reader::RowIter;
+use parquet::schema::types::Type;
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::ops::Range;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// Splits row groups of a file into at most `readers` contiguous ranges of about the same size.
+pub fn split_row_groups(row_groups: usize, readers: usize) -> Vec<Range<usize>> {
+    if row_groups == 0 {
+        return Vec::new();
+    }
+    let per_reader = (row_groups + readers.max(1) - 1) / readers.max(1);
+    (0..row_groups)
+        .step_by(per_reader)
+        .map(|start| start..(start + per_reader).min(row_groups))
+        .collect()
+}
+
+/// Footers of parquet files a query reads. Each footer is parsed once no matter how many times
+/// planning looks at it.
+pub struct ParquetMetadataCache {
+    metadata: Mutex<HashMap<String, Arc<ParquetMetaData>>>,
+}
+
+impl ParquetMetadataCache {
+    pub fn new() -> Arc<ParquetMetadataCache> {
+        Arc::new(ParquetMetadataCache {
+            metadata: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn get(&self, path: &str) -> Result<Arc<ParquetMetaData>, CubeError> {
+        if let Some(metadata) = self.metadata.lock().unwrap().get(path) {
+            return Ok(metadata.clone());
+        }
+        let metadata = Arc::new(read_metadata(path)?);
+        self.metadata
+            .lock()
+            .unwrap()
+            .insert(path.to_string(), metadata.clone());
+        Ok(metadata)
+    }
+}
+
+impl fmt::Debug for ParquetMetadataCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParquetMetadataCache")
+            .field("files", &self.metadata.lock().unwrap().len())
+            .finish()
+    }
+}
+
+pub fn read_metadata(path: &str) -> Result<ParquetMetaData, CubeError> {
+    let file_reader = SerializedFileReader::new(File::open(path)?)?;
+    let metadata = file_reader.metadata();
+    Ok(ParquetMetaData::new(
+        metadata.file_metadata().clone(),
+        metadata.row_groups().to_vec(),
+    ))
+}
+
+/// Fails with an error naming the file and the codec if any of its column chunks is compressed
+/// with a codec the reader can't decompress.
+pub fn check_compression(path: &str, metadata: &ParquetMetaData) -> Result<(), CubeError> {
+    for row_group in metadata.row_groups() {
+        for column in row_group.columns() {
+            if let Err(e) = create_codec(column.compression()) {
+                return Err(CubeError::user(format!(
+                    "Can't read parquet file {} compressed with {} codec: {}",
+                    path,
+                    column.compression(),
+                    e
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Names of the columns a parquet file was written with.
+pub fn file_column_names(metadata: &ParquetMetaData) -> Vec<String> {
+    metadata
+        .file_metadata()
+        .schema_descr()
+        .root_schema()
+        .get_fields()
+        .iter()
+        .map(|f| f.name().to_string())
+        .collect()
+}
+
+/// Contiguous ranges of row groups of a file with `columns` whose statistics can satisfy
+/// `filters`. `None` if all row groups have to be read.
+pub fn matching_row_groups(
+    metadata: &ParquetMetaData,
+    columns: &[Column],
+    filters: &[Expr],
+) -> Option<Vec<Range<usize>>> {
+    if filters.is_empty() {
+        return None;
+    }
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for (i, row_group) in metadata.row_groups().iter().enumerate() {
+        let bounds = columns
+            .iter()
+            .enumerate()
+            .filter(|(c, _)| *c < row_group.num_columns())
+            .filter_map(|(c, column)| {
+                let statistics = row_group.column(c).statistics()?;
+                let (min, max) = statistics_bounds(column.get_column_type(), statistics)?;
+                Some((column.get_name().as_str(), min, max))
+            })
+            .collect::<Vec<_>>();
+        if !can_match_columns(filters, &bounds) {
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    if ranges == vec![0..metadata.num_row_groups()] {
+        return None;
+    }
+    Some(ranges)
+}
+
+/// Min and max of a column chunk as values of the column. Nulls aren't counted in statistics.
+fn statistics_bounds(
+    column_type: &ColumnType,
+    statistics: &Statistics,
+) -> Option<(TableValue, TableValue)> {
+    if !statistics.has_min_max_set() {
+        return None;
+    }
+    let timestamp = |micros: i64| {
+        Some(TableValue::Timestamp(TimestampValue::from_arrow(
+            micros,
+            &TimeUnit::Microsecond,
+        )?))
+    };
+    Some(match (column_type, statistics) {
+        (ColumnType::Int, Statistics::Int64(s)) => {
+            (TableValue::Int(*s.min()), TableValue::Int(*s.max()))
+        }
+        (ColumnType::Timestamp, Statistics::Int64(s)) => {
+            (timestamp(*s.min())?, timestamp(*s.max())?)
+        }
+        (ColumnType::String, Statistics::ByteArray(s)) => (
+            TableValue::String(s.min().as_utf8().ok()?.to_string()),
+            TableValue::String(s.max().as_utf8().ok()?.to_string()),
+        ),
+        (ColumnType::Boolean, Statistics::Boolean(s)) => {
+            (TableValue::Boolean(*s.min()), TableValue::Boolean(*s.max()))
+        }
+        _ => return None,
+    })
+}
+
+/// Reads a range of row groups of a parquet file. Several of these over disjoint ranges
+/// scan a file in parallel where a single `ParquetExec` would read it in one stream.
+#[derive(Debug, Clone)]
+pub struct ParquetRowGroupsExec {
+    path: String,
+    row_groups: Range<usize>,
+    projection: Vec<usize>,
+    batch_size: usize,
+    schema: DFSchemaRef,
+}
+
+impl ParquetRowGroupsExec {
+    pub fn new(
+        path: String,
+        row_groups: Range<usize>,
+        projection: Vec<usize>,
+        batch_size: usize,
+        schema: DFSchemaRef,
+    ) -> ParquetRowGroupsExec {
+        ParquetRowGroupsExec {
+            path,
+            row_groups,
+            projection,
+            batch_size,
+            schema,
+        }
+    }
+
+    pub fn row_groups(&self) -> &Range<usize> {
+        &self.row_groups
+    }
+
+    fn read(&self, sender: &mut Sender<ArrowResult<RecordBatch>>) -> Result<(), CubeError> {
+        let reader = RowGroupRangeReader::try_new(&self.path, self.row_groups.clone())?;
+        let mut arrow_reader = ParquetFileArrowReader::new(Rc::new(reader));
+        let batches =
+            arrow_reader.get_record_reader_by_columns(self.projection.clone(), self.batch_size)?;
+        for batch in batches {
+            // Receiver is dropped when the query doesn't need more rows
+            if futures::executor::block_on(sender.send(batch)).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for ParquetRowGroupsExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> DFSchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        if !children.is_empty() {
+            return Err(DataFusionError::Internal(
+                "ParquetRowGroupsExec expects no children".to_string(),
+            ));
+        }
+        Ok(Arc::new(self.clone()))
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
+        if partition != 0 {
+            return Err(DataFusionError::Execution(format!(
+                "ParquetRowGroupsExec has a single partition but {} is requested",
+                partition
+            )));
+        }
+        let (mut sender, receiver) = channel(2);
+        let exec = self.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = exec.read(&mut sender) {
+                let error = ArrowError::ParquetError(format!("Can't read {}: {}", exec.path, e));
+                let _ = futures::executor::block_on(sender.send(Err(error)));
+            }
+        });
+        Ok(Box::pin(RowGroupsStream {
+            schema: self.schema.to_schema_ref(),
+            receiver,
+        }))
+    }
+}
+
+struct RowGroupsStream {
+    schema: SchemaRef,
+    receiver: Receiver<ArrowResult<RecordBatch>>,
+}
+
+impl Stream for RowGroupsStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+impl RecordBatchStream for RowGroupsStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+/// Scan of a file written before some columns were added to the index. Columns are matched
+/// by name and the ones the file lacks are filled with `defaults` of the schema fields.
+#[derive(Debug)]
+pub struct MissingColumnsExec {
+    input: Arc<dyn ExecutionPlan>,
+    schema: DFSchemaRef,
+    defaults: Vec<TableValue>,
+}
+
+impl MissingColumnsExec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        schema: DFSchemaRef,
+        defaults: Vec<TableValue>,
+    ) -> MissingColumnsExec {
+        MissingColumnsExec {
+            input,
+            schema,
+            defaults,
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for MissingColumnsExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> DFSchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Internal(
+                "MissingColumnsExec expects a single child".to_string(),
+            ));
+        }
+        Ok(Arc::new(MissingColumnsExec::new(
+            children[0].clone(),
+            self.schema.clone(),
+            self.defaults.clone(),
+        )))
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
+        Ok(Box::pin(MissingColumnsStream {
+            input: self.input.execute(partition).await?,
+            schema: self.schema.to_schema_ref(),
+            defaults: self.defaults.clone(),
+        }))
+    }
+}
+
+struct MissingColumnsStream {
+    input: Pin<Box<dyn RecordBatchStream + Send>>,
+    schema: SchemaRef,
+    defaults: Vec<TableValue>,
+}
+
+impl Stream for MissingColumnsStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let schema = self.schema.clone();
+        let defaults = self.defaults.clone();
+        self.input.poll_next_unpin(cx).map(|item| {
+            item.map(|batch| {
+                fill_missing_columns(&batch?, &schema, &defaults)
+                    .map_err(|e| ArrowError::ComputeError(e.to_string()))
+            })
+        })
+    }
+}
+
+impl RecordBatchStream for MissingColumnsStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+fn fill_missing_columns(
+    batch: &RecordBatch,
+    schema: &SchemaRef,
+    defaults: &[TableValue],
+) -> Result<RecordBatch, CubeError> {
+    let batch_schema = batch.schema();
+    let columns = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| match batch_schema.index_of(field.name()) {
+            Ok(batch_index) => Ok(batch.column(batch_index).clone()),
+            Err(_) => constant_array(
+                defaults.get(i).unwrap_or(&TableValue::Null),
+                field.data_type(),
+                batch.num_rows(),
+            ),
+        })
+        .collect::<Result<Vec<_>, CubeError>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+/// Array of `len` copies of `value`.
+fn constant_array(
+    value: &TableValue,
+    data_type: &DataType,
+    len: usize,
+) -> Result<ArrayRef, CubeError> {
+    Ok(match (value, data_type) {
+        (TableValue::Null, _) => new_null_array(data_type, len),
+        (TableValue::String(v), DataType::Utf8) => {
+            Arc::new(StringArray::from(vec![v.as_str(); len]))
+        }
+        (TableValue::Int(v), DataType::Int64) => Arc::new(Int64Array::from(vec![*v; len])),
+        (TableValue::Boolean(v), DataType::Boolean) => Arc::new(BooleanArray::from(vec![*v; len])),
+        (TableValue::Timestamp(t), DataType::Timestamp(TimeUnit::Microsecond, None)) => {
+            Arc::new(TimestampMicrosecondArray::from(vec![
+                t.get_time_stamp()
+                    / 1000;
+                len
+            ]))
+        }
+        (TableValue::Bytes(v), DataType::Binary) => {
+            Arc::new(BinaryArray::from(vec![v.as_slice(); len]))
+        }
+        (TableValue::Decimal(v), DataType::Int64Decimal(scale)) => {
+            let value = BigDecimal::from_str_radix(v, 10)?
+                .with_scale(*scale as i64)
+                .as_bigint_and_exponent()
+                .0
+                .to_i64()
+                .ok_or_else(|| {
+                    CubeError::user(format!("Can't convert {} to {:?}", v, data_type))
+                })?;
+            int64_decimal_array(vec![Some(value); len], *scale)?
+        }
+        (v, t) => {
+            return Err(CubeError::internal(format!(
+                "Can't fill column of type {:?} with {:?}",
+                t, v
+            )))
+        }
+    })
+}
+
+/// Presents a range of row groups of a file to the arrow reader as a whole file.
+struct RowGroupRangeReader {
+    file_reader: SerializedFileReader<File>,
+    metadata: ParquetMetaData,
+    first_row_group: usize,
+}
+
+impl RowGroupRangeReader {
+    fn try_new(path: &str, row_groups: Range<usize>) -> Result<RowGroupRangeReader, CubeError> {
+        let file_reader = SerializedFileReader::new(File::open(path)?)?;
+        let file_metadata = file_reader.metadata();
+        let row_group_metadata = file_metadata
+            .row_groups()
+            .get(row_groups.clone())
+            .ok_or_else(|| {
+                CubeError::internal(format!(
+                    "Row groups {:?} are out of range of {} row groups in {}",
+                    row_groups,
+                    file_metadata.num_row_groups(),
+                    path
+                ))
+            })?
+            .to_vec();
+        let metadata =
+            ParquetMetaData::new(file_metadata.file_metadata().clone(), row_group_metadata);
+        Ok(RowGroupRangeReader {
+            file_reader,
+            metadata,
+            first_row_group: row_groups.start,
+        })
+    }
+}
+
+impl FileReader for RowGroupRangeReader {
+    fn metadata(&self) -> &ParquetMetaData {
+        &self.metadata
+    }
+
+    fn num_row_groups(&self) -> usize {
+        self.metadata.num_row_groups()
+    }
+
+    fn get_row_group(&self, i: usize) -> parquet::errors::Result<Box<dyn RowGroupReader + '_>> {
+        self.file_reader.get_row_group(self.first_row_group + i)
+    }
+
+    fn get_row_iter(&self, projection: Option<Type>) -> parquet::errors::Result<RowIter> {
+        RowIter::from_file(projection, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Array;
+    use arrow::datatypes::Field;
+    use arrow::datatypes::Schema;
+
+    #[test]
+    fn splits_row_groups_evenly() {
+        assert_eq!(split_row_groups(10, 3), vec![0..4, 4..8, 8..10]);
+        assert_eq!(split_row_groups(4, 4), vec![0..1, 1..2, 2..3, 3..4]);
+        assert_eq!(split_row_groups(2, 8), vec![0..1, 1..2]);
+        assert_eq!(split_row_groups(5, 1), vec![0..5]);
+        assert_eq!(split_row_groups(5, 0), vec![0..5]);
+        assert!(split_row_groups(0, 4).is_empty());
+    }
+
+    #[test]
+    fn fills_missing_columns_with_defaults() {
+        let batch = RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)])),
+            vec![Arc::new(Int64Array::from(vec![1, 2]))],
+        )
+        .unwrap();
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("amount", DataType::Int64, true),
+            Field::new("id", DataType::Int64, false),
+            Field::new("status", DataType::Utf8, true),
+        ]));
+        let defaults = vec![
+            TableValue::Null,
+            TableValue::Null,
+            TableValue::String("new".to_string()),
+        ];
+        let filled = fill_missing_columns(&batch, &schema, &defaults).unwrap();
+        assert_eq!(filled.schema(), schema);
+        assert_eq!(filled.column(0).null_count(), 2);
+        assert_eq!(filled.column(1).as_ref(), batch.column(0).as_ref());
+        let status = filled
+            .column(2)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        assert_eq!(status.value(0), "new");
+        assert_eq!(status.value(1), "new");
+    }
+}
@@ -0,0 +1,132 @@
+use arrow::datatypes::SchemaRef;
+use arrow::error::Result as ArrowResult;
+use arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::RecordBatchStream;
+use futures::{Stream, StreamExt};
+use serde_derive::{Deserialize, Serialize};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Counters of a table scan updated by its concurrent readers. Values are only read once the
+/// scan is over, so relaxed atomics are enough.
+#[derive(Debug, Default)]
+pub struct ScanMetrics {
+    files_opened: AtomicU64,
+    row_groups_read: AtomicU64,
+    rows: AtomicU64,
+    batches: AtomicU64,
+    wait_nanos: AtomicU64,
+}
+
+impl ScanMetrics {
+    pub fn file_opened(&self, row_groups: u64) {
+        self.files_opened.fetch_add(1, Ordering::Relaxed);
+        self.row_groups_read
+            .fetch_add(row_groups, Ordering::Relaxed);
+    }
+
+    pub fn batch_read(&self, rows: u64, wait_time: Duration) {
+        self.rows.fetch_add(rows, Ordering::Relaxed);
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.wait_nanos
+            .fetch_add(wait_time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ScanStats {
+        ScanStats {
+            files_opened: self.files_opened.load(Ordering::Relaxed),
+            row_groups_read: self.row_groups_read.load(Ordering::Relaxed),
+            rows: self.rows.load(Ordering::Relaxed),
+            batches: self.batches.load(Ordering::Relaxed),
+            wait_nanos: self.wait_nanos.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Values of `ScanMetrics` summed over scans of a query.
+#[derive(Serialize, Deserialize, Clone, Default, Eq, PartialEq, Debug)]
+pub struct ScanStats {
+    files_opened: u64,
+    row_groups_read: u64,
+    rows: u64,
+    batches: u64,
+    wait_nanos: u64,
+}
+
+impl ScanStats {
+    pub fn merge(&mut self, other: &ScanStats) {
+        self.files_opened += other.files_opened;
+        self.row_groups_read += other.row_groups_read;
+        self.rows += other.rows;
+        self.batches += other.batches;
+        self.wait_nanos += other.wait_nanos;
+    }
+
+    pub fn files_opened(&self) -> u64 {
+        self.files_opened
+    }
+
+    pub fn row_groups_read(&self) -> u64 {
+        self.row_groups_read
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn batches(&self) -> u64 {
+        self.batches
+    }
+
+    /// Time consumers of the scan waited for batches to be ready. Covers reading and decoding
+    /// of the files as well as waiting for the readers to be scheduled.
+    pub fn wait_time(&self) -> Duration {
+        Duration::from_nanos(self.wait_nanos)
+    }
+}
+
+/// Stream of a file reader counting batches it produces into `metrics`.
+pub struct MeteredStream {
+    inner: Pin<Box<dyn RecordBatchStream + Send>>,
+    metrics: Arc<ScanMetrics>,
+    waiting_since: Option<Instant>,
+}
+
+impl MeteredStream {
+    pub fn new(
+        inner: Pin<Box<dyn RecordBatchStream + Send>>,
+        metrics: Arc<ScanMetrics>,
+    ) -> MeteredStream {
+        MeteredStream {
+            inner,
+            metrics,
+            waiting_since: None,
+        }
+    }
+}
+
+impl Stream for MeteredStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let waiting_since = *self.waiting_since.get_or_insert_with(Instant::now);
+        let poll = self.inner.poll_next_unpin(cx);
+        if let Poll::Ready(item) = &poll {
+            self.waiting_since = None;
+            if let Some(Ok(batch)) = item {
+                self.metrics
+                    .batch_read(batch.num_rows() as u64, waiting_since.elapsed());
+            }
+        }
+        poll
+    }
+}
+
+impl RecordBatchStream for MeteredStream {
+    fn schema(&self) -> SchemaRef {
+        self.inner.schema()
+    }
+}
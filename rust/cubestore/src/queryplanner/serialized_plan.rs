@@ -1,25 +1,68 @@
 use crate::metastore::table::{Table, TablePath};
 use crate::metastore::{Chunk, IdRow, Index, MetaStore, Partition};
-use crate::queryplanner::query_executor::CubeTable;
+use crate::queryplanner::pruning::{can_have_leading_value, can_match, equality_values};
+use crate::queryplanner::query_executor::{CubeTable, IndexColumnPositions, ParquetExecFactory};
+use crate::queryplanner::row_group_scan::ParquetMetadataCache;
+use crate::queryplanner::udfs::{checked_sum_udaf, CubeAggregateUDFKind, CubeScalarUDFKind};
+use crate::queryplanner::wire_format;
 use crate::queryplanner::CubeTableLogical;
+use crate::store::DataFrame;
+use crate::table::{Row, TableValue};
 use crate::CubeError;
 use arrow::datatypes::DataType;
-use datafusion::logical_plan::{DFSchemaRef, Expr, JoinType, LogicalPlan, Operator, Partitioning};
+use arrow::record_batch::RecordBatch;
+use datafusion::datasource::TableProvider;
+use datafusion::logical_plan::{
+    DFSchema, DFSchemaRef, Expr, JoinType, LogicalPlan, Operator, Partitioning,
+};
 use datafusion::physical_plan::{aggregates, functions};
 use datafusion::scalar::ScalarValue;
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use itertools::Itertools;
+use log::trace;
 use serde_derive::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::sync::Arc;
 
+/// Version of the SerializedPlan and SerializedRecordBatchStream wire format.
+/// Should be bumped on any incompatible change so nodes running different versions
+/// can agree on the format during rolling upgrades.
+pub const WIRE_FORMAT_VERSION: u32 = 2;
+
+/// The oldest wire format version this node is able to produce and read. Fields added by
+/// version 2 are gated on the version of the payload, see `wire_format`.
+pub const MIN_WIRE_FORMAT_VERSION: u32 = 1;
+
+pub fn check_wire_format_version(version: u32, payload: &str) -> Result<(), CubeError> {
+    if version < MIN_WIRE_FORMAT_VERSION || version > WIRE_FORMAT_VERSION {
+        return Err(CubeError::user(format!(
+            "Unsupported {} format version {}: this node supports versions {} to {}. Make sure all cluster nodes are upgraded to compatible versions.",
+            payload, version, MIN_WIRE_FORMAT_VERSION, WIRE_FORMAT_VERSION
+        )));
+    }
+    Ok(())
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct SerializedPlan {
+    /// Should always go first so it can be read before the rest of the payload.
+    format_version: u32,
     logical_plan: Arc<SerializedLogicalPlan>,
     schema_snapshot: Arc<SchemaSnapshot>,
     partition_ids_to_execute: HashSet<u64>,
+    /// Correlates router and worker logs of the same query.
+    #[serde(default, with = "wire_format::since_v2")]
+    query_id: String,
+    /// Children taken at nodes with several inputs above the split node, e.g. `UNION ALL` of
+    /// aggregates, as every branch is sent to workers separately.
+    #[serde(default, with = "wire_format::required_since_v2")]
+    split_branch: Vec<usize>,
+    /// Partitions failing on workers are skipped with a warning instead of failing the query.
+    /// Used by the router only.
+    #[serde(skip)]
+    best_effort: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -33,9 +76,45 @@ pub struct IndexSnapshot {
     index: IdRow<Index>,
     partitions: Vec<PartitionSnapshot>,
     join_on: Option<Vec<String>>,
+    /// Why the planner picked `index` for the table scan.
+    #[serde(default, with = "wire_format::since_v2")]
+    selection: String,
+    /// Values of the tenant column `partitions` were selected by. Only known on the router.
+    #[serde(skip)]
+    tenant_values: Option<Vec<TableValue>>,
+    /// Type of the join the scan is a side of. Only known on the router.
+    #[serde(skip)]
+    join_type: Option<JoinType>,
 }
 
 impl IndexSnapshot {
+    pub fn new(
+        table_path: TablePath,
+        index: IdRow<Index>,
+        partitions: Vec<PartitionSnapshot>,
+        join_on: Option<Vec<String>>,
+    ) -> Self {
+        Self {
+            table_path,
+            index,
+            partitions,
+            join_on,
+            selection: String::new(),
+            tenant_values: None,
+            join_type: None,
+        }
+    }
+
+    pub fn with_tenant_values(mut self, tenant_values: Option<Vec<TableValue>>) -> Self {
+        self.tenant_values = tenant_values;
+        self
+    }
+
+    pub fn with_join_type(mut self, join_type: Option<JoinType>) -> Self {
+        self.join_type = join_type;
+        self
+    }
+
     pub fn table_name(&self) -> String {
         self.table_path.table_name()
     }
@@ -55,15 +134,108 @@ impl IndexSnapshot {
     pub fn join_on(&self) -> Option<&Vec<String>> {
         self.join_on.as_ref()
     }
+
+    pub fn join_type(&self) -> Option<&JoinType> {
+        self.join_type.as_ref()
+    }
+
+    /// Explanation of the index choice, e.g. matched columns, join columns and partition count.
+    pub fn selection(&self) -> &str {
+        &self.selection
+    }
+
+    pub fn row_count(&self) -> u64 {
+        self.partitions.iter().map(|p| p.row_count()).sum()
+    }
+
+    /// Partitions among `partition_ids` and their chunks which can satisfy `filters` according
+    /// to their min/max stats.
+    pub fn to_scan(
+        &self,
+        partition_ids: &HashSet<u64>,
+        filters: &[Expr],
+    ) -> Vec<(&IdRow<Partition>, Vec<&IdRow<Chunk>>)> {
+        let index = self.index.get_row();
+        let sort_key_columns =
+            &index.get_columns()[..(index.sort_key_size() as usize).min(index.get_columns().len())];
+        let mut to_scan = Vec::new();
+        for partition_snapshot in self.partitions.iter() {
+            let partition = &partition_snapshot.partition;
+            if !partition_ids.contains(&partition.get_id()) {
+                continue;
+            }
+            // Chunks are routed to the partition by its key range so they are skipped as well
+            let partition_matches = match (
+                partition.get_row().get_min_val(),
+                partition.get_row().get_max_val(),
+            ) {
+                (Some(min), Some(max)) => can_match(filters, sort_key_columns, min, max),
+                _ => true,
+            };
+            if !partition_matches {
+                trace!(
+                    "Skipping partition {} of {} by filters",
+                    partition.get_id(),
+                    self.table_name()
+                );
+                continue;
+            }
+            let chunks = partition_snapshot
+                .chunks
+                .iter()
+                .filter(|chunk| {
+                    let matches =
+                        match (chunk.get_row().get_min_val(), chunk.get_row().get_max_val()) {
+                            (Some(min), Some(max)) => {
+                                can_match(filters, sort_key_columns, min, max)
+                            }
+                            // Chunks written before stats were collected
+                            _ => true,
+                        };
+                    if !matches {
+                        trace!(
+                            "Skipping chunk {} of {} by filters",
+                            chunk.get_id(),
+                            self.table_name()
+                        );
+                    }
+                    matches
+                })
+                .collect();
+            to_scan.push((partition, chunks));
+        }
+        to_scan
+    }
+
+    /// Rows of the partition in the router's insert buffer.
+    pub fn buffered_rows(&self, partition_id: u64) -> &[Row] {
+        self.partitions
+            .iter()
+            .find(|p| p.partition.get_id() == partition_id)
+            .map(|p| p.buffered_rows.as_slice())
+            .unwrap_or(&[])
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct PartitionSnapshot {
     partition: IdRow<Partition>,
     chunks: Vec<IdRow<Chunk>>,
+    /// Rows of the partition in the router's insert buffer, see `InsertBuffer`. They're written
+    /// after all chunks and sorted by the index key.
+    #[serde(default, with = "wire_format::required_since_v2")]
+    buffered_rows: Vec<Row>,
 }
 
 impl PartitionSnapshot {
+    pub fn new(partition: IdRow<Partition>, chunks: Vec<IdRow<Chunk>>) -> Self {
+        Self {
+            partition,
+            chunks,
+            buffered_rows: Vec::new(),
+        }
+    }
+
     pub fn partition(&self) -> &IdRow<Partition> {
         &self.partition
     }
@@ -71,6 +243,32 @@ impl PartitionSnapshot {
     pub fn chunks(&self) -> &Vec<IdRow<Chunk>> {
         &self.chunks
     }
+
+    pub fn buffered_rows(&self) -> &Vec<Row> {
+        &self.buffered_rows
+    }
+
+    /// Rows of tombstone chunks are deleted from the rest of the partition.
+    pub fn row_count(&self) -> u64 {
+        let (deleted, added): (Vec<_>, Vec<_>) =
+            self.chunks.iter().partition(|c| c.get_row().is_tombstone());
+        (self.partition.get_row().main_table_row_count()
+            + added
+                .iter()
+                .map(|c| c.get_row().get_row_count())
+                .sum::<u64>()
+            + self.buffered_rows.len() as u64)
+            .saturating_sub(
+                deleted
+                    .iter()
+                    .map(|c| c.get_row().get_row_count())
+                    .sum::<u64>(),
+            )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.row_count() == 0
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -135,11 +333,47 @@ pub enum SerializePartitioning {
 }
 
 impl SerializedLogicalPlan {
+    fn inputs(&self) -> Vec<&Arc<SerializedLogicalPlan>> {
+        match self {
+            SerializedLogicalPlan::Projection { input, .. }
+            | SerializedLogicalPlan::Filter { input, .. }
+            | SerializedLogicalPlan::Aggregate { input, .. }
+            | SerializedLogicalPlan::Sort { input, .. }
+            | SerializedLogicalPlan::Limit { input, .. }
+            | SerializedLogicalPlan::Repartition { input, .. } => vec![input],
+            SerializedLogicalPlan::Union { inputs, .. } => inputs.iter().collect(),
+            SerializedLogicalPlan::Join { left, right, .. } => vec![left, right],
+            SerializedLogicalPlan::TableScan { .. }
+            | SerializedLogicalPlan::EmptyRelation { .. } => {
+                vec![]
+            }
+        }
+    }
+
+    /// Tables scanned by the plan along with filters pushed down to their scans.
+    fn table_scans(&self) -> Vec<(&TablePath, Vec<Expr>)> {
+        match self {
+            SerializedLogicalPlan::TableScan {
+                source: SerializedTableSource::CubeTable(v),
+                filters,
+                ..
+            } => vec![(&v.table, filters.iter().map(|e| e.expr()).collect())],
+            _ => self
+                .inputs()
+                .into_iter()
+                .flat_map(|i| i.table_scans())
+                .collect(),
+        }
+    }
+
     fn logical_plan(
         &self,
         index_snapshots: &Vec<IndexSnapshot>,
         remote_to_local_names: &HashMap<String, String>,
         worker_partition_ids: &HashSet<u64>,
+        parquet_parallelism: usize,
+        in_memory_chunks: &HashMap<u64, Vec<RecordBatch>>,
+        parquet_metadata: &Arc<ParquetMetadataCache>,
     ) -> Result<LogicalPlan, CubeError> {
         Ok(match self {
             SerializedLogicalPlan::Projection {
@@ -152,6 +386,9 @@ impl SerializedLogicalPlan {
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
                 )?),
                 schema: schema.clone(),
             },
@@ -161,6 +398,9 @@ impl SerializedLogicalPlan {
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
                 )?),
             },
             SerializedLogicalPlan::Aggregate {
@@ -168,22 +408,34 @@ impl SerializedLogicalPlan {
                 group_expr,
                 aggr_expr,
                 schema,
-            } => LogicalPlan::Aggregate {
-                group_expr: group_expr.iter().map(|e| e.expr()).collect(),
-                aggr_expr: aggr_expr.iter().map(|e| e.expr()).collect(),
-                input: Arc::new(input.logical_plan(
+            } => {
+                let input = Arc::new(input.logical_plan(
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
-                )?),
-                schema: schema.clone(),
-            },
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
+                )?);
+                LogicalPlan::Aggregate {
+                    group_expr: group_expr.iter().map(|e| e.expr()).collect(),
+                    aggr_expr: aggr_expr
+                        .iter()
+                        .map(|e| with_checked_sum(e.expr(), input.schema()))
+                        .collect::<Result<Vec<_>, _>>()?,
+                    input,
+                    schema: schema.clone(),
+                }
+            }
             SerializedLogicalPlan::Sort { expr, input } => LogicalPlan::Sort {
                 expr: expr.iter().map(|e| e.expr()).collect(),
                 input: Arc::new(input.logical_plan(
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
                 )?),
             },
             SerializedLogicalPlan::Union {
@@ -198,6 +450,9 @@ impl SerializedLogicalPlan {
                             index_snapshots,
                             remote_to_local_names,
                             worker_partition_ids,
+                            parquet_parallelism,
+                            in_memory_chunks,
+                            parquet_metadata,
                         )?))
                     })
                     .collect::<Result<Vec<_>, _>>()?,
@@ -214,20 +469,25 @@ impl SerializedLogicalPlan {
             } => LogicalPlan::TableScan {
                 table_name: table_name.clone(),
                 source: match source {
-                    SerializedTableSource::CubeTable(v) => Arc::new(CubeTable::try_new(
-                        index_snapshots
-                            .iter()
-                            .find(|i| i.table_path.table_name() == v.table.table_name())
-                            .ok_or_else(|| {
-                                CubeError::internal(format!(
-                                    "Logical table {:?} not found in index snapshots: {:?}",
-                                    v, index_snapshots
-                                ))
-                            })?
-                            .clone(),
-                        remote_to_local_names.clone(),
-                        worker_partition_ids.clone(),
-                    )?),
+                    SerializedTableSource::CubeTable(v) => Arc::new(
+                        CubeTable::try_new(
+                            index_snapshots
+                                .iter()
+                                .find(|i| i.table_path.table_name() == v.table.table_name())
+                                .ok_or_else(|| {
+                                    CubeError::internal(format!(
+                                        "Logical table {:?} not found in index snapshots: {:?}",
+                                        v, index_snapshots
+                                    ))
+                                })?
+                                .clone(),
+                            remote_to_local_names.clone(),
+                            worker_partition_ids.clone(),
+                            parquet_parallelism,
+                        )?
+                        .with_in_memory_chunks(in_memory_chunks)
+                        .with_scan_factory(ParquetExecFactory::new(parquet_metadata.clone())),
+                    ),
                 },
                 projection: projection.clone(),
                 projected_schema: projected_schema.clone(),
@@ -243,11 +503,17 @@ impl SerializedLogicalPlan {
             },
             SerializedLogicalPlan::Limit { n, input } => LogicalPlan::Limit {
                 n: *n,
-                input: Arc::new(input.logical_plan(
-                    index_snapshots,
-                    remote_to_local_names,
-                    worker_partition_ids,
-                )?),
+                input: Arc::new(with_scan_limit(
+                    input.logical_plan(
+                        index_snapshots,
+                        remote_to_local_names,
+                        worker_partition_ids,
+                        parquet_parallelism,
+                        in_memory_chunks,
+                        parquet_metadata,
+                    )?,
+                    *n,
+                )),
             },
             SerializedLogicalPlan::Join {
                 left,
@@ -260,11 +526,17 @@ impl SerializedLogicalPlan {
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
                 )?),
                 right: Arc::new(right.logical_plan(
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
                 )?),
                 on: on.clone(),
                 join_type: join_type.clone(),
@@ -278,6 +550,9 @@ impl SerializedLogicalPlan {
                     index_snapshots,
                     remote_to_local_names,
                     worker_partition_ids,
+                    parquet_parallelism,
+                    in_memory_chunks,
+                    parquet_metadata,
                 )?),
                 partitioning_scheme: match partitioning_scheme {
                     SerializePartitioning::RoundRobinBatch(s) => Partitioning::RoundRobinBatch(*s),
@@ -338,6 +613,43 @@ pub enum SerializedExpr {
         distinct: bool,
     },
     Wildcard,
+    /// Appended last to keep variant tags of older payloads stable.
+    ScalarUDF {
+        fun: CubeScalarUDFKind,
+        args: Vec<SerializedExpr>,
+    },
+    AggregateUDF {
+        fun: CubeAggregateUDFKind,
+        args: Vec<SerializedExpr>,
+    },
+}
+
+/// `SUM` of `Int64` and `Int64Decimal` values is computed by `checked_sum_udaf` so partial sums
+/// beyond the i64 range don't wrap around and a total beyond it fails the query.
+fn with_checked_sum(expr: Expr, input_schema: &DFSchema) -> Result<Expr, CubeError> {
+    Ok(match expr {
+        Expr::AggregateFunction {
+            fun: aggregates::AggregateFunction::Sum,
+            args,
+            distinct: false,
+        } => match args[0].get_type(input_schema)? {
+            data_type @ DataType::Int64 | data_type @ DataType::Int64Decimal(_) => {
+                Expr::AggregateUDF {
+                    fun: Arc::new(checked_sum_udaf(data_type)),
+                    args,
+                }
+            }
+            _ => Expr::AggregateFunction {
+                fun: aggregates::AggregateFunction::Sum,
+                args,
+                distinct: false,
+            },
+        },
+        Expr::Alias(expr, alias) => {
+            Expr::Alias(Box::new(with_checked_sum(*expr, input_schema)?), alias)
+        }
+        expr => expr,
+    })
 }
 
 impl SerializedExpr {
@@ -372,6 +684,10 @@ impl SerializedExpr {
                 fun: fun.clone(),
                 args: args.iter().map(|e| e.expr()).collect(),
             },
+            SerializedExpr::ScalarUDF { fun, args } => Expr::ScalarUDF {
+                fun: Arc::new(fun.udf()),
+                args: args.iter().map(|e| e.expr()).collect(),
+            },
             SerializedExpr::AggregateFunction {
                 fun,
                 args,
@@ -381,6 +697,10 @@ impl SerializedExpr {
                 args: args.iter().map(|e| e.expr()).collect(),
                 distinct: *distinct,
             },
+            SerializedExpr::AggregateUDF { fun, args } => Expr::AggregateUDF {
+                fun: Arc::new(fun.udaf()),
+                args: args.iter().map(|e| e.expr()).collect(),
+            },
             SerializedExpr::Case {
                 expr,
                 else_expr,
@@ -424,32 +744,329 @@ impl SerializedPlan {
         let index_snapshots =
             Self::index_snapshots_from_plan(Arc::new(plan), meta_store, Vec::new(), None).await?;
         Ok(SerializedPlan {
+            format_version: WIRE_FORMAT_VERSION,
             logical_plan: Arc::new(serialized_logical_plan),
             schema_snapshot: Arc::new(SchemaSnapshot { index_snapshots }),
             partition_ids_to_execute: HashSet::new(),
+            query_id: String::new(),
+            split_branch: Vec::new(),
+            best_effort: false,
         })
     }
 
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// Downgrades the plan to the format understood by nodes advertising `format_version`.
+    pub fn with_format_version(&self, format_version: u32) -> Result<Self, CubeError> {
+        check_wire_format_version(format_version, "SerializedPlan")?;
+        let mut plan = self.clone();
+        plan.format_version = format_version;
+        Ok(plan)
+    }
+
+    pub fn check_format_version(&self) -> Result<(), CubeError> {
+        check_wire_format_version(self.format_version, "SerializedPlan")
+    }
+
+    /// Fields the format version of the plan doesn't have aren't written.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, CubeError> {
+        Ok(wire_format::with_payload_version(
+            self.format_version,
+            || bincode::serialize(self),
+        )?)
+    }
+
+    /// Reads the format version before the rest of the payload so incompatible plans fail
+    /// with a readable error instead of a deserialization one.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CubeError> {
+        let format_version = bincode::deserialize::<u32>(bytes)?;
+        check_wire_format_version(format_version, "SerializedPlan")?;
+        Ok(wire_format::with_payload_version(format_version, || {
+            bincode::deserialize(bytes)
+        })?)
+    }
+
     pub fn with_partition_id_to_execute(&self, partition_ids_to_execute: HashSet<u64>) -> Self {
         Self {
+            format_version: self.format_version,
             logical_plan: self.logical_plan.clone(),
             schema_snapshot: self.schema_snapshot.clone(),
             partition_ids_to_execute,
+            query_id: self.query_id.clone(),
+            split_branch: self.split_branch.clone(),
+            best_effort: self.best_effort,
+        }
+    }
+
+    /// Drops partition and chunk snapshots that aren't in `partition_ids` so only data
+    /// required by a worker is sent over the wire. Index and table metadata is kept intact.
+    pub fn prune_to_partitions(&self, partition_ids: &HashSet<u64>) -> Self {
+        let index_snapshots = self
+            .index_snapshots()
+            .iter()
+            .map(|index_snapshot| IndexSnapshot {
+                table_path: index_snapshot.table_path.clone(),
+                index: index_snapshot.index.clone(),
+                partitions: index_snapshot
+                    .partitions
+                    .iter()
+                    .filter(|p| partition_ids.contains(&p.partition.get_id()))
+                    .cloned()
+                    .collect(),
+                join_on: index_snapshot.join_on.clone(),
+                selection: index_snapshot.selection.clone(),
+                tenant_values: index_snapshot.tenant_values.clone(),
+                join_type: index_snapshot.join_type.clone(),
+            })
+            .collect();
+        Self {
+            format_version: self.format_version,
+            logical_plan: self.logical_plan.clone(),
+            schema_snapshot: Arc::new(SchemaSnapshot { index_snapshots }),
+            partition_ids_to_execute: self.partition_ids_to_execute.clone(),
+            query_id: self.query_id.clone(),
+            split_branch: self.split_branch.clone(),
+            best_effort: self.best_effort,
+        }
+    }
+
+    /// Replaces partitions and chunks of indexes found in `partitions`, keyed by index id, so
+    /// plans resolved at different times read partitions of the same metastore read. Only
+    /// partitions of the selected tenants are kept and buffered rows are moved to the new
+    /// partitions their keys fall into.
+    pub fn with_index_partitions(&self, partitions: &HashMap<u64, Vec<PartitionSnapshot>>) -> Self {
+        let index_snapshots = self
+            .index_snapshots()
+            .iter()
+            .map(|index_snapshot| {
+                let mut index_snapshot = index_snapshot.clone();
+                if let Some(partitions) = partitions.get(&index_snapshot.index.get_id()) {
+                    let mut rows = index_snapshot
+                        .partitions
+                        .iter_mut()
+                        .flat_map(|p| p.buffered_rows.drain(..))
+                        .collect::<Vec<_>>();
+                    index_snapshot.partitions = partitions.clone();
+                    if let Some(values) = index_snapshot.tenant_values.as_ref() {
+                        retain_tenant_partitions(&mut index_snapshot.partitions, values);
+                    }
+                    let sort_key_size = index_snapshot.index.get_row().sort_key_size();
+                    rows.sort_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)));
+                    distribute_buffered_rows(&mut index_snapshot.partitions, rows, sort_key_size);
+                }
+                index_snapshot
+            })
+            .collect();
+        let mut plan = self.clone();
+        plan.schema_snapshot = Arc::new(SchemaSnapshot { index_snapshots });
+        plan
+    }
+
+    /// Adds `chunks`, keyed by partition id, to the partitions of the plan's indexes.
+    pub fn with_pending_chunks(&self, chunks: &HashMap<u64, Vec<IdRow<Chunk>>>) -> Self {
+        if chunks.is_empty() {
+            return self.clone();
+        }
+        let index_snapshots = self
+            .index_snapshots()
+            .iter()
+            .map(|index_snapshot| {
+                let mut index_snapshot = index_snapshot.clone();
+                for partition_snapshot in index_snapshot.partitions.iter_mut() {
+                    if let Some(chunks) = chunks.get(&partition_snapshot.partition.get_id()) {
+                        partition_snapshot.chunks.extend(chunks.iter().cloned());
+                    }
+                }
+                index_snapshot
+            })
+            .collect();
+        let mut plan = self.clone();
+        plan.schema_snapshot = Arc::new(SchemaSnapshot { index_snapshots });
+        plan
+    }
+
+    /// Adds rows of `buffered`, keyed by table id and holding all columns of the table, to the
+    /// partitions of the plan's indexes whose key range they fall into.
+    pub fn with_buffered_rows(
+        &self,
+        buffered: &HashMap<u64, DataFrame>,
+    ) -> Result<Self, CubeError> {
+        if buffered.is_empty() {
+            return Ok(self.clone());
+        }
+        let mut index_snapshots = Vec::with_capacity(self.index_snapshots().len());
+        for index_snapshot in self.index_snapshots().iter() {
+            let mut index_snapshot = index_snapshot.clone();
+            if let Some(data) = buffered.get(&index_snapshot.table().get_id()) {
+                let index = index_snapshot.index.get_row();
+                let sort_key_size = index.sort_key_size();
+                let mut rows = data.remap_columns(index.get_columns().clone())?.into_rows();
+                // Stable sort keeps rows of the same key in the order they were inserted
+                rows.sort_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)));
+                distribute_buffered_rows(&mut index_snapshot.partitions, rows, sort_key_size);
+            }
+            index_snapshots.push(index_snapshot);
         }
+        let mut plan = self.clone();
+        plan.schema_snapshot = Arc::new(SchemaSnapshot { index_snapshots });
+        Ok(plan)
     }
 
     pub fn partition_ids_to_execute(&self) -> HashSet<u64> {
         self.partition_ids_to_execute.clone()
     }
 
+    pub fn with_query_id(&self, query_id: String) -> Self {
+        let mut plan = self.clone();
+        plan.query_id = query_id;
+        plan
+    }
+
+    pub fn query_id(&self) -> &str {
+        &self.query_id
+    }
+
+    pub fn with_best_effort(&self, best_effort: bool) -> Self {
+        let mut plan = self.clone();
+        plan.best_effort = best_effort;
+        plan
+    }
+
+    pub fn best_effort(&self) -> bool {
+        self.best_effort
+    }
+
+    /// Plan of the `child` branch of a node with several inputs.
+    pub fn with_split_branch(&self, child: usize) -> Self {
+        let mut plan = self.clone();
+        plan.split_branch.push(child);
+        plan
+    }
+
+    pub fn split_branch(&self) -> &[usize] {
+        &self.split_branch
+    }
+
+    #[cfg(test)]
+    pub fn empty_for_test() -> Self {
+        use datafusion::logical_plan::ToDFSchema;
+        SerializedPlan {
+            format_version: WIRE_FORMAT_VERSION,
+            logical_plan: Arc::new(SerializedLogicalPlan::EmptyRelation {
+                produce_one_row: false,
+                schema: arrow::datatypes::Schema::empty().to_dfschema_ref().unwrap(),
+            }),
+            schema_snapshot: Arc::new(SchemaSnapshot {
+                index_snapshots: Vec::new(),
+            }),
+            partition_ids_to_execute: HashSet::new(),
+            query_id: String::new(),
+            split_branch: Vec::new(),
+            best_effort: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn scan_for_test(index_snapshot: IndexSnapshot) -> Self {
+        use crate::queryplanner::query_executor::column_type_to_arrow;
+        use arrow::datatypes::{Field, Schema as ArrowSchema};
+        use datafusion::logical_plan::ToDFSchema;
+        let schema = Arc::new(ArrowSchema::new(
+            index_snapshot
+                .index
+                .get_row()
+                .get_columns()
+                .iter()
+                .map(|c| {
+                    Field::new(
+                        c.get_name(),
+                        column_type_to_arrow(c.get_column_type()),
+                        true,
+                    )
+                })
+                .collect(),
+        ));
+        SerializedPlan {
+            format_version: WIRE_FORMAT_VERSION,
+            logical_plan: Arc::new(SerializedLogicalPlan::TableScan {
+                table_name: index_snapshot.table_name(),
+                source: SerializedTableSource::CubeTable(CubeTableLogical {
+                    table: index_snapshot.table_path.clone(),
+                    schema: schema.clone(),
+                }),
+                projection: None,
+                projected_schema: schema.to_dfschema_ref().unwrap(),
+                filters: Vec::new(),
+                alias: None,
+            }),
+            schema_snapshot: Arc::new(SchemaSnapshot {
+                index_snapshots: vec![index_snapshot],
+            }),
+            partition_ids_to_execute: HashSet::new(),
+            query_id: String::new(),
+            split_branch: Vec::new(),
+            best_effort: false,
+        }
+    }
+
+    /// Default index of the `foo.orders` table with `columns`, ids of the schema, table and index
+    /// are 1.
+    #[cfg(test)]
+    pub fn index_snapshot_for_test(
+        columns: Vec<crate::metastore::Column>,
+        partitions: Vec<PartitionSnapshot>,
+    ) -> IndexSnapshot {
+        use crate::metastore::Schema;
+        IndexSnapshot::new(
+            TablePath {
+                table: IdRow::new(
+                    1,
+                    Table::new("orders".to_string(), 1, columns.clone(), None, None),
+                ),
+                schema: Arc::new(IdRow::new(1, Schema::new("foo".to_string()))),
+            },
+            IdRow::new(
+                1,
+                Index::try_new("default".to_string(), 1, columns, 1).unwrap(),
+            ),
+            partitions,
+            None,
+        )
+    }
+
+    /// Plan of a query that doesn't read any table.
+    pub fn without_tables(plan: &LogicalPlan) -> Self {
+        SerializedPlan {
+            format_version: WIRE_FORMAT_VERSION,
+            logical_plan: Arc::new(Self::serialized_logical_plan(plan)),
+            schema_snapshot: Arc::new(SchemaSnapshot {
+                index_snapshots: Vec::new(),
+            }),
+            partition_ids_to_execute: HashSet::new(),
+            query_id: String::new(),
+            split_branch: Vec::new(),
+            best_effort: false,
+        }
+    }
+
+    /// Chunks found in `in_memory_chunks` are scanned from memory instead of their files. Scans
+    /// share footers of the files they read through `parquet_metadata`.
     pub fn logical_plan(
         &self,
         remote_to_local_names: &HashMap<String, String>,
+        parquet_parallelism: usize,
+        in_memory_chunks: &HashMap<u64, Vec<RecordBatch>>,
+        parquet_metadata: &Arc<ParquetMetadataCache>,
     ) -> Result<LogicalPlan, CubeError> {
         self.logical_plan.logical_plan(
             self.index_snapshots(),
             remote_to_local_names,
             &self.partition_ids_to_execute(),
+            parquet_parallelism,
+            in_memory_chunks,
+            parquet_metadata,
         )
     }
 
@@ -457,6 +1074,26 @@ impl SerializedPlan {
         &self.schema_snapshot.index_snapshots
     }
 
+    /// Why indexes of the plan's table scans were chosen, one line per scan.
+    pub fn explain_index_selection(&self) -> String {
+        self.index_snapshots()
+            .iter()
+            .map(|i| i.selection())
+            .join("\n")
+    }
+
+    /// Row count of all partitions and chunks referenced by the plan.
+    pub fn estimated_row_count(&self) -> u64 {
+        self.index_snapshots().iter().map(|i| i.row_count()).sum()
+    }
+
+    pub fn all_partition_ids(&self) -> HashSet<u64> {
+        self.index_snapshots()
+            .iter()
+            .flat_map(|i| i.partitions().iter().map(|p| p.partition().get_id()))
+            .collect()
+    }
+
     pub fn files_to_download(&self) -> Vec<String> {
         let indexes = self.index_snapshots();
 
@@ -481,6 +1118,30 @@ impl SerializedPlan {
         files
     }
 
+    /// Files of partitions to execute and their chunks which scans of the plan can't skip by
+    /// filters. Other files of `files_to_download` aren't read by the plan.
+    pub fn files_to_scan(&self) -> Vec<String> {
+        let partition_ids = self.partition_ids_to_execute();
+        let mut files = Vec::new();
+        for (table, filters) in self.logical_plan.table_scans() {
+            for index in self
+                .index_snapshots()
+                .iter()
+                .filter(|i| i.table_path.table_name() == table.table_name())
+            {
+                for (partition, chunks) in index.to_scan(&partition_ids, &filters) {
+                    files.extend(partition.get_row().get_full_name(partition.get_id()));
+                    files.extend(
+                        chunks
+                            .into_iter()
+                            .map(|c| c.get_row().get_full_name(c.get_id())),
+                    );
+                }
+            }
+        }
+        files.into_iter().unique().collect()
+    }
+
     fn index_snapshots_from_plan_boxed(
         plan: Arc<LogicalPlan>,
         meta_store: Arc<dyn MetaStore>,
@@ -502,6 +1163,7 @@ impl SerializedPlan {
             LogicalPlan::TableScan {
                 table_name,
                 projection,
+                filters,
                 ..
             } => {
                 let name_split = table_name.split(".").collect::<Vec<_>>();
@@ -512,13 +1174,17 @@ impl SerializedPlan {
                     .get_schema_by_id(table.get_row().get_schema_id())
                     .await?;
                 let default_index = meta_store.get_default_index(table.get_id()).await?;
-                let index = if let Some(projection_column_indices) = projection {
+                let (index, reason) = if let Some(projection_column_indices) = projection {
                     let projection_columns =
                         CubeTable::project_to_table(&table, &projection_column_indices);
                     let indexes = meta_store.get_table_indexes(table.get_id()).await?;
-                    if let Some((index, _)) = indexes
+                    let index_count = indexes.len();
+                    let candidates = indexes
                         .into_iter()
+                        // Building indexes don't have rows written before their creation yet
+                        .filter(|i| !i.get_row().is_building())
                         .filter_map(|i| {
+                            let positions = IndexColumnPositions::new(i.get_row());
                             if let Some(join_on_columns) = join_on.as_ref() {
                                 let join_columns_in_index = join_on_columns
                                     .iter()
@@ -533,12 +1199,11 @@ impl SerializedPlan {
                                 if join_columns_in_index.iter().any(|c| c.is_none()) {
                                     return None;
                                 }
-                                let join_columns_indices = CubeTable::project_to_index_positions(
+                                let join_columns_indices = positions.project(
                                     &join_columns_in_index
                                         .into_iter()
                                         .map(|c| c.unwrap().clone())
                                         .collect(),
-                                    &i,
                                 );
                                 if (0..join_columns_indices.len())
                                     .map(|i| Some(i))
@@ -548,16 +1213,23 @@ impl SerializedPlan {
                                     return None;
                                 }
                             }
-                            let projected_index_positions =
-                                CubeTable::project_to_index_positions(&projection_columns, &i);
+                            let projected_index_positions = positions.project(&projection_columns);
                             let score = projected_index_positions
                                 .into_iter()
                                 .fold_options(0, |a, b| a + b);
                             score.map(|s| (i, s))
                         })
-                        .min_by_key(|(_, s)| *s)
-                    {
-                        index
+                        .collect::<Vec<_>>();
+                    let candidate_count = candidates.len();
+                    if let Some((index, score)) = candidates.into_iter().min_by_key(|(_, s)| *s) {
+                        let reason = format!(
+                            "covers columns {} with sort key position sum {}, the lowest of {} covering out of {} indexes",
+                            projection_columns.iter().map(|c| c.get_name()).join(", "),
+                            score,
+                            candidate_count,
+                            index_count
+                        );
+                        (index, reason)
                     } else {
                         if let Some(join_on_columns) = join_on {
                             return Err(CubeError::user(format!(
@@ -570,7 +1242,12 @@ impl SerializedPlan {
                                 join_on_columns.join(", ")
                             )));
                         }
-                        default_index
+                        let reason = format!(
+                            "default index as none of {} indexes covers columns {}",
+                            index_count,
+                            projection_columns.iter().map(|c| c.get_name()).join(", ")
+                        );
+                        (default_index, reason)
                     }
                 } else {
                     if let Some(join_on_columns) = join_on {
@@ -580,7 +1257,10 @@ impl SerializedPlan {
                             join_on_columns.join(", ")
                         )));
                     }
-                    default_index
+                    (
+                        default_index,
+                        "default index as the scan has no projection".to_string(),
+                    )
                 };
 
                 let partitions = meta_store
@@ -590,9 +1270,36 @@ impl SerializedPlan {
                 let mut partition_snapshots = Vec::new();
 
                 for (partition, chunks) in partitions.into_iter() {
-                    partition_snapshots.push(PartitionSnapshot { chunks, partition });
+                    partition_snapshots.push(PartitionSnapshot::new(partition, chunks));
                 }
 
+                let mut tenant_selection = String::new();
+                let mut tenant_values = None;
+                if let Some(tenant_column) = table.get_row().tenant_column() {
+                    if let Some(mut values) = equality_values(filters, tenant_column) {
+                        values.sort();
+                        values.dedup();
+                        let partition_count = partition_snapshots.len();
+                        retain_tenant_partitions(&mut partition_snapshots, &values);
+                        tenant_selection =
+                            format!(" of {} by tenant column {}", partition_count, tenant_column);
+                        tenant_values = Some(values);
+                    }
+                }
+
+                let selection = format!(
+                    "{}.{}: index {} {}; join on: {}; {} partitions{}",
+                    schema.get_row().get_name(),
+                    table.get_row().get_table_name(),
+                    index.get_row().get_name(),
+                    reason,
+                    join_on
+                        .as_ref()
+                        .map(|c| c.join(", "))
+                        .unwrap_or("none".to_string()),
+                    partition_snapshots.len(),
+                    tenant_selection
+                );
                 index_snapshots.push(IndexSnapshot {
                     index,
                     partitions: partition_snapshots,
@@ -601,6 +1308,9 @@ impl SerializedPlan {
                         schema: Arc::new(schema),
                     },
                     join_on,
+                    selection,
+                    tenant_values,
+                    join_type: None,
                 });
 
                 Ok(index_snapshots)
@@ -667,9 +1377,14 @@ impl SerializedPlan {
                 Ok(snapshots)
             }
             LogicalPlan::Join {
-                left, right, on, ..
+                left,
+                right,
+                on,
+                join_type,
+                ..
             } => {
                 let mut snapshots = index_snapshots;
+                let first_join_snapshot = snapshots.len();
                 snapshots = Self::index_snapshots_from_plan_boxed(
                     left.clone(),
                     meta_store.clone(),
@@ -692,6 +1407,12 @@ impl SerializedPlan {
                     ),
                 )
                 .await?;
+                // Scans of nested joins keep the type of the innermost join they're a side of
+                for snapshot in snapshots[first_join_snapshot..].iter_mut() {
+                    if snapshot.join_type.is_none() {
+                        snapshot.join_type = Some(join_type.clone());
+                    }
+                }
                 Ok(snapshots)
             }
             LogicalPlan::Repartition { input, .. } => {
@@ -711,7 +1432,7 @@ impl SerializedPlan {
             LogicalPlan::EmptyRelation { .. } => false,
             LogicalPlan::TableScan { table_name, .. } => {
                 let name_split = table_name.split(".").collect::<Vec<_>>();
-                name_split[0].to_string() != "information_schema"
+                name_split[0] != "information_schema" && name_split[0] != "system"
             }
             LogicalPlan::Projection { input, .. } => Self::is_data_select_query(input),
             LogicalPlan::Filter { input, .. } => Self::is_data_select_query(input),
@@ -876,7 +1597,11 @@ impl SerializedPlan {
                 fun: fun.clone(),
                 args: args.iter().map(|e| Self::serialized_expr(&e)).collect(),
             },
-            Expr::ScalarUDF { .. } => unimplemented!(),
+            Expr::ScalarUDF { fun, args } => SerializedExpr::ScalarUDF {
+                fun: CubeScalarUDFKind::from_name(&fun.name)
+                    .unwrap_or_else(|| panic!("Unknown scalar UDF: {}", fun.name)),
+                args: args.iter().map(|e| Self::serialized_expr(&e)).collect(),
+            },
             Expr::AggregateFunction {
                 fun,
                 args,
@@ -886,7 +1611,11 @@ impl SerializedPlan {
                 args: args.iter().map(|e| Self::serialized_expr(&e)).collect(),
                 distinct: *distinct,
             },
-            Expr::AggregateUDF { .. } => unimplemented!(),
+            Expr::AggregateUDF { fun, args } => SerializedExpr::AggregateUDF {
+                fun: CubeAggregateUDFKind::from_name(&fun.name)
+                    .unwrap_or_else(|| panic!("Unknown aggregate UDF: {}", fun.name)),
+                args: args.iter().map(|e| Self::serialized_expr(&e)).collect(),
+            },
             Expr::Case {
                 expr,
                 when_then_expr,
@@ -924,3 +1653,390 @@ impl SerializedPlan {
         }
     }
 }
+
+/// Passes `limit` to the scan of a table read as is, i.e. without filters, aggregation or
+/// sorting between the scan and the limit.
+fn with_scan_limit(plan: LogicalPlan, limit: usize) -> LogicalPlan {
+    match plan {
+        LogicalPlan::Projection {
+            expr,
+            input,
+            schema,
+        } => LogicalPlan::Projection {
+            expr,
+            input: Arc::new(with_scan_limit(input.as_ref().clone(), limit)),
+            schema,
+        },
+        LogicalPlan::TableScan {
+            table_name,
+            source,
+            projection,
+            projected_schema,
+            filters,
+            alias,
+        } if filters.is_empty() => {
+            let cube_table = source
+                .as_any()
+                .downcast_ref::<CubeTable>()
+                .map(|t| t.clone().with_limit_hint(Some(limit)));
+            let source: Arc<dyn TableProvider> = match cube_table {
+                Some(cube_table) => Arc::new(cube_table),
+                None => source,
+            };
+            LogicalPlan::TableScan {
+                table_name,
+                source,
+                projection,
+                projected_schema,
+                filters,
+                alias,
+            }
+        }
+        plan => plan,
+    }
+}
+
+/// Partitions never mix tenants so only partitions of the queried ones are sent to workers.
+fn retain_tenant_partitions(partitions: &mut Vec<PartitionSnapshot>, values: &[TableValue]) {
+    partitions.retain(|p| {
+        values.iter().any(|v| {
+            can_have_leading_value(
+                p.partition.get_row().get_min_val().as_ref(),
+                p.partition.get_row().get_max_val().as_ref(),
+                v,
+            )
+        })
+    });
+}
+
+/// Assigns `rows`, sorted by the index key, to the partitions whose key range they fall into.
+fn distribute_buffered_rows(
+    partitions: &mut [PartitionSnapshot],
+    mut rows: Vec<Row>,
+    sort_key_size: u64,
+) {
+    for partition_snapshot in partitions.iter_mut() {
+        let partition = partition_snapshot.partition.get_row();
+        let (in_partition, rest) = rows.into_iter().partition::<Vec<_>, _>(|r| {
+            partition
+                .get_min_val()
+                .as_ref()
+                .map(|min| r.sort_key(sort_key_size) >= min.sort_key(sort_key_size))
+                .unwrap_or(true)
+                && partition
+                    .get_max_val()
+                    .as_ref()
+                    .map(|max| r.sort_key(sort_key_size) < max.sort_key(sort_key_size))
+                    .unwrap_or(true)
+        });
+        partition_snapshot.buffered_rows = in_partition;
+        rows = rest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::{Column, ColumnType};
+    use crate::table::{Row, TableValue};
+    use datafusion::logical_plan::{col, lit};
+
+    fn index_snapshot_with_partitions(partition_count: u64) -> IndexSnapshot {
+        let columns = vec![
+            Column::new("id".to_string(), ColumnType::Int, 0),
+            Column::new("name".to_string(), ColumnType::String, 1),
+        ];
+        let partitions = (0..partition_count)
+            .map(|id| {
+                PartitionSnapshot::new(
+                    IdRow::new(id, Partition::new(1, None, None)),
+                    vec![
+                        IdRow::new(id * 2, Chunk::new(id, 100)),
+                        IdRow::new(id * 2 + 1, Chunk::new(id, 100)),
+                    ],
+                )
+            })
+            .collect();
+        SerializedPlan::index_snapshot_for_test(columns, partitions)
+    }
+
+    #[test]
+    fn prune_to_partitions() {
+        let empty = SerializedPlan::empty_for_test();
+        let plan = SerializedPlan {
+            format_version: WIRE_FORMAT_VERSION,
+            logical_plan: empty.logical_plan.clone(),
+            schema_snapshot: Arc::new(SchemaSnapshot {
+                index_snapshots: vec![index_snapshot_with_partitions(1000)],
+            }),
+            partition_ids_to_execute: HashSet::new(),
+            query_id: String::new(),
+            split_branch: Vec::new(),
+            best_effort: false,
+        };
+        let to_execute = vec![42].into_iter().collect::<HashSet<_>>();
+        let pruned = plan
+            .with_partition_id_to_execute(to_execute.clone())
+            .prune_to_partitions(&to_execute);
+
+        assert_eq!(pruned.index_snapshots().len(), 1);
+        let partitions = pruned.index_snapshots()[0].partitions();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].partition().get_id(), 42);
+        assert_eq!(partitions[0].chunks().len(), 2);
+        assert_eq!(pruned.partition_ids_to_execute(), to_execute);
+        assert_eq!(
+            pruned.files_to_download(),
+            vec![
+                "84.chunk.parquet".to_string(),
+                "85.chunk.parquet".to_string()
+            ]
+        );
+
+        let full_size = bincode::serialize(&plan).unwrap().len();
+        let pruned_size = bincode::serialize(&pruned).unwrap().len();
+        assert!(
+            pruned_size * 50 < full_size,
+            "Pruned plan size {} is expected to be a small fraction of {}",
+            pruned_size,
+            full_size
+        );
+    }
+
+    #[test]
+    fn parquet_parallelism_reaches_cube_table() {
+        let plan = SerializedPlan::scan_for_test(index_snapshot_with_partitions(1));
+        match plan
+            .logical_plan(
+                &HashMap::new(),
+                4,
+                &HashMap::new(),
+                &ParquetMetadataCache::new(),
+            )
+            .unwrap()
+        {
+            LogicalPlan::TableScan { source, .. } => {
+                let cube_table = source.as_any().downcast_ref::<CubeTable>().unwrap();
+                assert_eq!(cube_table.parquet_parallelism(), 4);
+            }
+            x => panic!("Unexpected plan: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn query_id_round_trip() {
+        let plan = SerializedPlan::empty_for_test().with_query_id("q1".to_string());
+        let restored = SerializedPlan::from_bytes(&plan.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.query_id(), "q1");
+        let narrowed = restored.with_partition_id_to_execute(vec![1].into_iter().collect());
+        assert_eq!(narrowed.query_id(), "q1");
+        assert_eq!(
+            narrowed.prune_to_partitions(&HashSet::new()).query_id(),
+            "q1"
+        );
+    }
+
+    #[test]
+    fn null_handling_functions_round_trip() {
+        use datafusion::logical_plan::{lit, LogicalPlanBuilder};
+
+        let udf_call = |kind: CubeScalarUDFKind| Expr::ScalarUDF {
+            fun: Arc::new(kind.udf()),
+            args: vec![Expr::Literal(ScalarValue::Int64(None)), lit(1i64)],
+        };
+        let plan = LogicalPlanBuilder::empty(true)
+            .project(vec![
+                udf_call(CubeScalarUDFKind::Coalesce),
+                udf_call(CubeScalarUDFKind::NullIf),
+                udf_call(CubeScalarUDFKind::IfNull),
+            ])
+            .unwrap()
+            .build()
+            .unwrap();
+        let serialized = SerializedPlan::without_tables(&plan);
+        let restored = SerializedPlan::from_bytes(&serialized.to_bytes().unwrap()).unwrap();
+        match restored
+            .logical_plan(
+                &HashMap::new(),
+                1,
+                &HashMap::new(),
+                &ParquetMetadataCache::new(),
+            )
+            .unwrap()
+        {
+            LogicalPlan::Projection { expr, .. } => {
+                let names = expr
+                    .iter()
+                    .map(|e| match e {
+                        Expr::ScalarUDF { fun, args } => {
+                            assert_eq!(args.len(), 2);
+                            fun.name.clone()
+                        }
+                        x => panic!("Unexpected expression: {:?}", x),
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(names, vec!["coalesce", "nullif", "ifnull"]);
+            }
+            x => panic!("Unexpected plan: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn format_version_mismatch() {
+        let plan = SerializedPlan::empty_for_test();
+        let restored = SerializedPlan::from_bytes(&plan.to_bytes().unwrap()).unwrap();
+        assert_eq!(restored.format_version(), WIRE_FORMAT_VERSION);
+
+        let mut newer = plan.clone();
+        newer.format_version = WIRE_FORMAT_VERSION + 1;
+        let mut bytes = newer.to_bytes().unwrap();
+        // Simulate a layout change by a newer version
+        bytes.truncate(8);
+        let err = SerializedPlan::from_bytes(&bytes).unwrap_err().to_string();
+        assert!(
+            err.contains(&format!("format version {}", WIRE_FORMAT_VERSION + 1)),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains(&format!(
+                "{} to {}",
+                MIN_WIRE_FORMAT_VERSION, WIRE_FORMAT_VERSION
+            )),
+            "{}",
+            err
+        );
+        assert!(newer.check_format_version().is_err());
+        assert!(plan.with_format_version(WIRE_FORMAT_VERSION + 1).is_err());
+        assert!(plan.with_format_version(MIN_WIRE_FORMAT_VERSION).is_ok());
+    }
+
+    #[test]
+    fn older_format_versions_round_trip() {
+        let id = |v: i64| Some(Row::new(vec![TableValue::Int(v)]));
+        let mut snapshot = index_snapshot_with_partitions(1);
+        snapshot.partitions[0].chunks[0] =
+            IdRow::new(0, Chunk::new(0, 100).with_min_max(id(1), id(5)));
+        let plan = SerializedPlan::scan_for_test(snapshot).with_query_id("q1".to_string());
+
+        for version in MIN_WIRE_FORMAT_VERSION..=WIRE_FORMAT_VERSION {
+            let older = plan.with_format_version(version).unwrap();
+            let restored = SerializedPlan::from_bytes(&older.to_bytes().unwrap()).unwrap();
+            assert_eq!(restored.format_version(), version);
+            let chunk = restored.index_snapshots()[0].partitions()[0].chunks()[0].get_row();
+            if version >= 2 {
+                assert_eq!(chunk.get_min_val(), &id(1));
+            } else {
+                assert_eq!(chunk.get_min_val(), &None);
+            }
+            let query_id = if version >= 2 { "q1" } else { "" };
+            assert_eq!(restored.query_id(), query_id);
+            assert_eq!(
+                restored.index_snapshots()[0].partitions()[0].chunks().len(),
+                2
+            );
+        }
+
+        // Older nodes would ignore the branch and return rows of every one
+        let mut with_branch = plan.clone();
+        with_branch.split_branch = vec![1];
+        let err = with_branch
+            .with_format_version(1)
+            .unwrap()
+            .to_bytes()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("requires wire format version 2"), "{}", err);
+        assert!(with_branch
+            .with_format_version(2)
+            .unwrap()
+            .to_bytes()
+            .is_ok());
+    }
+
+    #[test]
+    fn index_partitions_keep_tenant_selection_and_buffered_rows() {
+        let id = |v: i64| Some(Row::new(vec![TableValue::Int(v)]));
+        let mut snapshot =
+            index_snapshot_with_partitions(1).with_tenant_values(Some(vec![TableValue::Int(1)]));
+        snapshot.partitions[0].buffered_rows = vec![
+            Row::new(vec![
+                TableValue::Int(1),
+                TableValue::String("b".to_string()),
+            ]),
+            Row::new(vec![
+                TableValue::Int(1),
+                TableValue::String("a".to_string()),
+            ]),
+        ];
+        let plan = SerializedPlan::scan_for_test(snapshot);
+        // Partition 0 is split into 10 and 11 after the plan is resolved
+        let partitions = vec![(
+            1,
+            vec![
+                PartitionSnapshot::new(IdRow::new(10, Partition::new(1, None, id(2))), Vec::new()),
+                PartitionSnapshot::new(IdRow::new(11, Partition::new(1, id(2), None)), Vec::new()),
+            ],
+        )]
+        .into_iter()
+        .collect();
+
+        let plan = plan.with_index_partitions(&partitions);
+        let partitions = plan.index_snapshots()[0].partitions();
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].partition().get_id(), 10);
+        assert_eq!(
+            partitions[0].buffered_rows(),
+            &vec![
+                Row::new(vec![
+                    TableValue::Int(1),
+                    TableValue::String("b".to_string())
+                ]),
+                Row::new(vec![
+                    TableValue::Int(1),
+                    TableValue::String("a".to_string())
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_partitions_and_chunks_by_filters() {
+        let mut snapshot = index_snapshot_with_partitions(0);
+        let id = |v: i64| Some(Row::new(vec![TableValue::Int(v)]));
+        snapshot.partitions = vec![
+            PartitionSnapshot::new(
+                IdRow::new(1, Partition::new(1, id(0), id(10))),
+                vec![
+                    IdRow::new(1, Chunk::new(1, 10).with_min_max(id(0), id(4))),
+                    IdRow::new(2, Chunk::new(1, 10).with_min_max(id(5), id(9))),
+                    IdRow::new(3, Chunk::new(1, 10)),
+                ],
+            ),
+            PartitionSnapshot::new(IdRow::new(2, Partition::new(1, id(10), id(20))), vec![]),
+            PartitionSnapshot::new(IdRow::new(3, Partition::new(1, None, None)), vec![]),
+        ];
+        let all = vec![1, 2, 3].into_iter().collect::<HashSet<_>>();
+        let to_scan = |partition_ids: &HashSet<u64>, filter: Expr| {
+            snapshot
+                .to_scan(partition_ids, &[filter])
+                .into_iter()
+                .map(|(p, chunks)| (p.get_id(), chunks.iter().map(|c| c.get_id()).collect()))
+                .collect::<Vec<(u64, Vec<u64>)>>()
+        };
+
+        assert_eq!(
+            to_scan(&all, col("id").lt(lit(3i64))),
+            vec![(1, vec![1, 3]), (3, vec![])]
+        );
+        assert_eq!(
+            to_scan(&all, col("id").gt(lit(15i64))),
+            vec![(2, vec![]), (3, vec![])]
+        );
+        let only_first = vec![1].into_iter().collect::<HashSet<_>>();
+        assert_eq!(
+            to_scan(&only_first, col("id").gt_eq(lit(5i64))),
+            vec![(1, vec![2, 3])]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use datafusion::physical_plan::hash_aggregate::HashAggregateExec;
+use datafusion::physical_plan::limit::GlobalLimitExec;
+use datafusion::physical_plan::sort::SortExec;
+use datafusion::physical_plan::ExecutionPlan;
+use std::sync::Arc;
+
+/// Node type the plan is split at: the node runs on the router and its input on workers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplitPoint {
+    Aggregate,
+    Sort,
+    Limit,
+}
+
+impl SplitPoint {
+    /// In order of the default preference.
+    pub fn all() -> Vec<SplitPoint> {
+        vec![SplitPoint::Aggregate, SplitPoint::Sort, SplitPoint::Limit]
+    }
+
+    pub fn matches(&self, node: &dyn ExecutionPlan) -> bool {
+        match self {
+            SplitPoint::Aggregate => node.as_any().downcast_ref::<HashAggregateExec>().is_some(),
+            SplitPoint::Sort => node.as_any().downcast_ref::<SortExec>().is_some(),
+            SplitPoint::Limit => node.as_any().downcast_ref::<GlobalLimitExec>().is_some(),
+        }
+    }
+
+    /// Whether the plan is split at `node`. An aggregate over results of another aggregate or
+    /// a limit, e.g. over a subquery with `HAVING`, can't be computed from results of workers
+    /// so the plan is split at the inner aggregate then.
+    pub fn is_split_node(&self, node: &dyn ExecutionPlan) -> bool {
+        self.matches(node)
+            && (*self != SplitPoint::Aggregate
+                || !node
+                    .children()
+                    .iter()
+                    .any(|c| needs_all_rows(c.as_ref(), true)))
+    }
+}
+
+/// Split point of the plan: aggregates first, then sorts and limits. `None` means the plan is
+/// split at its root. Partial aggregates never send more rows than their input, so with row
+/// counts as the only statistics this order also minimizes rows sent to the router. The router
+/// and workers plan the same query, so both compute it on their own.
+pub fn default_split_point(plan: &Arc<dyn ExecutionPlan>) -> Option<SplitPoint> {
+    SplitPoint::all()
+        .into_iter()
+        .find(|p| find_split_node(plan, *p).is_some())
+}
+
+/// The top-most node of the split point type, the one split plan builders stop at.
+fn find_split_node(
+    plan: &Arc<dyn ExecutionPlan>,
+    point: SplitPoint,
+) -> Option<Arc<dyn ExecutionPlan>> {
+    if point.is_split_node(plan.as_ref()) {
+        Some(plan.clone())
+    } else {
+        plan.children()
+            .iter()
+            .find_map(|c| find_split_node(c, point))
+    }
+}
+
+/// Aggregates and limits produce results out of all rows of their input, except for the partial
+/// aggregate under the split node which runs on workers by design.
+fn needs_all_rows(plan: &dyn ExecutionPlan, allow_partial_aggregate: bool) -> bool {
+    if SplitPoint::Aggregate.matches(plan) {
+        return !allow_partial_aggregate
+            || plan
+                .children()
+                .iter()
+                .any(|c| needs_all_rows(c.as_ref(), false));
+    }
+    SplitPoint::Limit.matches(plan)
+        || plan
+            .children()
+            .iter()
+            .any(|c| needs_all_rows(c.as_ref(), allow_partial_aggregate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use datafusion::datasource::MemTable;
+    use datafusion::logical_plan::{col, count, lit};
+    use datafusion::physical_plan::filter::FilterExec;
+    use datafusion::prelude::ExecutionContext;
+
+    /// Plan of `SELECT a, count(a) FROM (SELECT a FROM t ORDER BY a) GROUP BY a`
+    fn aggregate_over_sort() -> Arc<dyn ExecutionPlan> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 2, 3]))],
+        )
+        .unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.register_table(
+            "t",
+            Box::new(MemTable::try_new(schema, vec![vec![batch]]).unwrap()),
+        );
+        let logical_plan = ctx
+            .table("t")
+            .unwrap()
+            .sort(vec![col("a").sort(true, false)])
+            .unwrap()
+            .aggregate(vec![col("a")], vec![count(col("a"))])
+            .unwrap()
+            .to_logical_plan();
+        ctx.create_physical_plan(&logical_plan).unwrap()
+    }
+
+    /// Plan of `SELECT count(a) FROM (SELECT a, count(a) FROM t GROUP BY a HAVING count(a) > 1)`
+    fn aggregate_over_having() -> Arc<dyn ExecutionPlan> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int64, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int64Array::from(vec![1, 2, 2, 3]))],
+        )
+        .unwrap();
+        let mut ctx = ExecutionContext::new();
+        ctx.register_table(
+            "t",
+            Box::new(MemTable::try_new(schema, vec![vec![batch]]).unwrap()),
+        );
+        let logical_plan = ctx
+            .table("t")
+            .unwrap()
+            .aggregate(vec![col("a")], vec![count(col("a"))])
+            .unwrap()
+            .filter(col("COUNT(a)").gt(lit(1)))
+            .unwrap()
+            .aggregate(vec![], vec![count(col("a"))])
+            .unwrap()
+            .to_logical_plan();
+        ctx.create_physical_plan(&logical_plan).unwrap()
+    }
+
+    fn contains_filter(plan: &Arc<dyn ExecutionPlan>) -> bool {
+        plan.as_any().downcast_ref::<FilterExec>().is_some()
+            || plan.children().iter().any(|c| contains_filter(c))
+    }
+
+    #[test]
+    fn default_prefers_aggregate() {
+        let plan = aggregate_over_sort();
+        assert_eq!(default_split_point(&plan), Some(SplitPoint::Aggregate));
+    }
+
+    #[test]
+    fn subtree_below_split_node_uses_its_own_default() {
+        let plan = aggregate_over_sort();
+        let sort = find_split_node(&plan, SplitPoint::Sort).unwrap();
+        assert_eq!(default_split_point(&sort), Some(SplitPoint::Sort));
+        assert_eq!(default_split_point(&sort.children()[0]), None);
+    }
+
+    #[test]
+    fn having_stays_on_router() {
+        let plan = aggregate_over_having();
+        assert_eq!(default_split_point(&plan), Some(SplitPoint::Aggregate));
+        // Outer aggregate can't be split as workers would filter their own groups
+        assert!(!SplitPoint::Aggregate.is_split_node(plan.as_ref()));
+        let split_node = find_split_node(&plan, SplitPoint::Aggregate).unwrap();
+        assert!(!Arc::ptr_eq(&split_node, &plan));
+        assert!(!contains_filter(&split_node));
+        assert!(contains_filter(&plan));
+    }
+}
@@ -0,0 +1,361 @@
+use crate::queryplanner::date_arithmetic::{date_arithmetic_call, extract_call};
+use crate::queryplanner::udfs::{CubeAggregateUDFKind, CubeScalarUDFKind, CASE_WHEN_MAX_ARGS};
+use sqlparser::ast::{
+    BinaryOperator, DataType, Expr, Function, Ident, ObjectName, Query, Select, SelectItem,
+    SetExpr, Statement, TableFactor, Value,
+};
+
+/// Settings of `rewrite_statement`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RewriteOptions {
+    /// Casts fail on values that can't be converted instead of turning them into nulls.
+    pub strict_casts: bool,
+    /// Bytes values of a `COUNT(DISTINCT)` group can take, `0` meaning no limit.
+    pub count_distinct_memory_limit: usize,
+}
+
+/// Rewrites expressions DataFusion can't plan or execute over CubeStore types into calls of
+/// Cube scalar functions before planning:
+/// - `<expr> +/- INTERVAL '...'` into `date_add`/`date_sub`,
+/// - `EXTRACT(part FROM expr)` into `date_part`,
+/// - `CASE` into `case_when` so branches of different types are cast to a common one. Output
+///   columns keep the name of the original expression,
+/// - `CAST` to numbers, decimals, timestamps, dates and strings into `cast_to_<type>` as
+///   DataFusion can't cast `Int64Decimal` values,
+/// - `COUNT(DISTINCT expr)` into `count_distinct` which deduplicates values on workers first.
+pub fn rewrite_statement(statement: &mut Statement, options: RewriteOptions) {
+    if let Statement::Query(query) = statement {
+        rewrite_query(query, options);
+    }
+}
+
+fn rewrite_query(query: &mut Query, options: RewriteOptions) {
+    rewrite_set_expr(&mut query.body, options);
+    for order_by in query.order_by.iter_mut() {
+        rewrite_expr(&mut order_by.expr, options);
+    }
+}
+
+fn rewrite_set_expr(set_expr: &mut SetExpr, options: RewriteOptions) {
+    match set_expr {
+        SetExpr::Select(select) => rewrite_select(select, options),
+        SetExpr::Query(query) => rewrite_query(query, options),
+        SetExpr::SetOperation { left, right, .. } => {
+            rewrite_set_expr(left, options);
+            rewrite_set_expr(right, options);
+        }
+        _ => {}
+    }
+}
+
+fn rewrite_select(select: &mut Select, options: RewriteOptions) {
+    for item in select.projection.iter_mut() {
+        match item {
+            SelectItem::UnnamedExpr(expr) if contains_case(expr) => {
+                let alias = Ident::with_quote('"', expr.to_string());
+                let mut expr = expr.clone();
+                rewrite_expr(&mut expr, options);
+                *item = SelectItem::ExprWithAlias { expr, alias };
+            }
+            SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                rewrite_expr(expr, options)
+            }
+            _ => {}
+        }
+    }
+    for table in select.from.iter_mut() {
+        rewrite_table_factor(&mut table.relation, options);
+        for join in table.joins.iter_mut() {
+            rewrite_table_factor(&mut join.relation, options);
+        }
+    }
+    if let Some(selection) = select.selection.as_mut() {
+        rewrite_expr(selection, options);
+    }
+    for expr in select.group_by.iter_mut() {
+        rewrite_expr(expr, options);
+    }
+    if let Some(having) = select.having.as_mut() {
+        rewrite_expr(having, options);
+    }
+}
+
+fn rewrite_table_factor(table_factor: &mut TableFactor, options: RewriteOptions) {
+    if let TableFactor::Derived { subquery, .. } = table_factor {
+        rewrite_query(subquery, options);
+    }
+}
+
+fn rewrite_expr(expr: &mut Expr, options: RewriteOptions) {
+    let replacement = match expr {
+        Expr::BinaryOp { left, op, right } => {
+            rewrite_expr(left, options);
+            rewrite_expr(right, options);
+            date_arithmetic_call(left, op, right)
+        }
+        Expr::Nested(e) | Expr::UnaryOp { expr: e, .. } | Expr::IsNull(e) | Expr::IsNotNull(e) => {
+            rewrite_expr(e, options);
+            None
+        }
+        Expr::Cast { expr: e, data_type } => {
+            rewrite_expr(e, options);
+            cast_call(e, data_type, options)
+        }
+        Expr::Between {
+            expr: e, low, high, ..
+        } => {
+            rewrite_expr(e, options);
+            rewrite_expr(low, options);
+            rewrite_expr(high, options);
+            None
+        }
+        Expr::InList { expr: e, list, .. } => {
+            rewrite_expr(e, options);
+            list.iter_mut().for_each(|e| rewrite_expr(e, options));
+            None
+        }
+        Expr::InSubquery {
+            expr: e, subquery, ..
+        } => {
+            rewrite_expr(e, options);
+            rewrite_query(subquery, options);
+            None
+        }
+        Expr::Function(function) => {
+            function
+                .args
+                .iter_mut()
+                .for_each(|e| rewrite_expr(e, options));
+            count_distinct_call(function, options)
+        }
+        Expr::Extract { field, expr: e } => {
+            rewrite_expr(e, options);
+            Some(extract_call(field, e))
+        }
+        Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            if let Some(operand) = operand.as_mut() {
+                rewrite_expr(operand, options);
+            }
+            conditions.iter_mut().for_each(|e| rewrite_expr(e, options));
+            results.iter_mut().for_each(|e| rewrite_expr(e, options));
+            if let Some(else_result) = else_result.as_mut() {
+                rewrite_expr(else_result, options);
+            }
+            case_call(operand, conditions, results, else_result)
+        }
+        Expr::Subquery(query) | Expr::Exists(query) => {
+            rewrite_query(query, options);
+            None
+        }
+        _ => None,
+    };
+    if let Some(replacement) = replacement {
+        *expr = replacement;
+    }
+}
+
+/// Whether a projection item would be renamed by rewriting a `CASE` inside of it.
+fn contains_case(expr: &Expr) -> bool {
+    match expr {
+        Expr::Case { .. } => true,
+        Expr::BinaryOp { left, right, .. } => contains_case(left) || contains_case(right),
+        Expr::Nested(e)
+        | Expr::UnaryOp { expr: e, .. }
+        | Expr::IsNull(e)
+        | Expr::IsNotNull(e)
+        | Expr::Cast { expr: e, .. }
+        | Expr::Extract { expr: e, .. } => contains_case(e),
+        Expr::Between {
+            expr: e, low, high, ..
+        } => contains_case(e) || contains_case(low) || contains_case(high),
+        Expr::InList { expr: e, list, .. } => contains_case(e) || list.iter().any(contains_case),
+        Expr::Function(function) => function.args.iter().any(contains_case),
+        _ => false,
+    }
+}
+
+/// `CASE [operand] WHEN c1 THEN r1 ... [ELSE e] END` rewritten into
+/// `case_when(c1, r1, ...[, e])`. Omitted and `NULL` else branches are dropped as `case_when`
+/// returns nulls for unmatched rows anyway and a `NULL` literal would be typed as a string.
+/// Expressions with more arms than `case_when` accepts are left as is.
+fn case_call(
+    operand: &Option<Box<Expr>>,
+    conditions: &[Expr],
+    results: &[Expr],
+    else_result: &Option<Box<Expr>>,
+) -> Option<Expr> {
+    let mut args = Vec::with_capacity(conditions.len() * 2 + 1);
+    for (condition, result) in conditions.iter().zip(results) {
+        args.push(match operand {
+            Some(operand) => Expr::BinaryOp {
+                left: operand.clone(),
+                op: BinaryOperator::Eq,
+                right: Box::new(condition.clone()),
+            },
+            None => condition.clone(),
+        });
+        args.push(result.clone());
+    }
+    match else_result.as_deref() {
+        None | Some(Expr::Value(Value::Null)) => {}
+        Some(else_result) => args.push(else_result.clone()),
+    }
+    if args.len() > CASE_WHEN_MAX_ARGS {
+        return None;
+    }
+    Some(function_call(CubeScalarUDFKind::CaseWhen, args))
+}
+
+fn cast_call(expr: &Expr, data_type: &DataType, options: RewriteOptions) -> Option<Expr> {
+    let kind = match data_type {
+        DataType::SmallInt | DataType::Int | DataType::BigInt => CubeScalarUDFKind::CastToInt64,
+        DataType::Float(_) | DataType::Real | DataType::Double => CubeScalarUDFKind::CastToFloat64,
+        // Same scales as decimal columns get
+        DataType::Decimal(_, scale) => CubeScalarUDFKind::CastToDecimal(match scale.unwrap_or(5) {
+            s if s > 5 => 10,
+            s => s as usize,
+        }),
+        DataType::Timestamp => CubeScalarUDFKind::CastToTimestamp,
+        DataType::Date => CubeScalarUDFKind::CastToDate,
+        DataType::Char(_) | DataType::Varchar(_) | DataType::Text => {
+            CubeScalarUDFKind::CastToString
+        }
+        _ => return None,
+    };
+    Some(function_call(
+        kind,
+        vec![
+            expr.clone(),
+            Expr::Value(Value::Boolean(options.strict_casts)),
+        ],
+    ))
+}
+
+fn count_distinct_call(function: &Function, options: RewriteOptions) -> Option<Expr> {
+    let is_count = function.name.0.len() == 1
+        && function.name.0[0].value.eq_ignore_ascii_case("count")
+        && function.distinct
+        && function.over.is_none();
+    match function.args.as_slice() {
+        [arg] if is_count => Some(Expr::Function(Function {
+            name: ObjectName(vec![Ident::new(CubeAggregateUDFKind::CountDistinct.name())]),
+            args: vec![
+                arg.clone(),
+                Expr::Value(Value::Number(
+                    options.count_distinct_memory_limit.to_string(),
+                )),
+            ],
+            over: None,
+            distinct: false,
+        })),
+        _ => None,
+    }
+}
+
+pub fn function_call(kind: CubeScalarUDFKind, args: Vec<Expr>) -> Expr {
+    Expr::Function(Function {
+        name: ObjectName(vec![Ident::new(kind.name())]),
+        args,
+        over: None,
+        distinct: false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn rewrite(sql: &str) -> String {
+        let mut statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .pop()
+            .unwrap();
+        rewrite_statement(&mut statement, RewriteOptions::default());
+        statement.to_string()
+    }
+
+    #[test]
+    fn rewrites_interval_arithmetic() {
+        assert_eq!(
+            rewrite("SELECT t + INTERVAL '1 day' FROM s.t WHERE t > now() - INTERVAL '7 days'"),
+            "SELECT date_add(t, '1 day') FROM s.t WHERE t > date_sub(now(), '7 days')"
+        );
+        assert_eq!(
+            rewrite("SELECT INTERVAL '2' HOUR + t FROM s.t"),
+            "SELECT date_add(t, '2 HOUR') FROM s.t"
+        );
+        assert_eq!(
+            rewrite("SELECT a + b FROM (SELECT t - INTERVAL '1 month' a, 1 b FROM s.t) x"),
+            "SELECT a + b FROM (SELECT date_sub(t, '1 month') AS a, 1 AS b FROM s.t) AS x"
+        );
+    }
+
+    #[test]
+    fn rewrites_extract() {
+        assert_eq!(
+            rewrite("SELECT EXTRACT(HOUR FROM t + INTERVAL '1 hour') FROM s.t"),
+            "SELECT date_part('HOUR', date_add(t, '1 hour')) FROM s.t"
+        );
+    }
+
+    #[test]
+    fn rewrites_casts() {
+        assert_eq!(
+            rewrite("SELECT CAST(a AS DOUBLE), CAST(b AS DECIMAL(18, 2)), CAST(c AS BOOLEAN) FROM s.t"),
+            "SELECT cast_to_double(a, false), cast_to_decimal_2(b, false), CAST(c AS BOOLEAN) FROM s.t"
+        );
+        assert_eq!(
+            rewrite("SELECT CAST(t + INTERVAL '1 day' AS DATE) FROM s.t WHERE CAST(s AS BIGINT) > 1"),
+            "SELECT cast_to_date(date_add(t, '1 day'), false) FROM s.t WHERE cast_to_bigint(s, false) > 1"
+        );
+    }
+
+    #[test]
+    fn rewrites_case() {
+        assert_eq!(
+            rewrite("SELECT CASE WHEN a > 1 THEN b WHEN a > 0 THEN c ELSE NULL END x FROM s.t"),
+            "SELECT case_when(a > 1, b, a > 0, c) AS x FROM s.t"
+        );
+        assert_eq!(
+            rewrite("SELECT CASE a WHEN 1 THEN t + INTERVAL '1 day' ELSE t END AS x FROM s.t"),
+            "SELECT case_when(a = 1, date_add(t, '1 day'), t) AS x FROM s.t"
+        );
+        assert_eq!(
+            rewrite("SELECT a FROM s.t WHERE CASE WHEN a > 1 THEN b END > 0"),
+            "SELECT a FROM s.t WHERE case_when(a > 1, b) > 0"
+        );
+    }
+
+    #[test]
+    fn case_keeps_column_name() {
+        assert_eq!(
+            rewrite("SELECT sum(CASE WHEN a > 1 THEN b END) FROM s.t"),
+            "SELECT sum(case_when(a > 1, b)) AS \"sum(CASE WHEN a > 1 THEN b END)\" FROM s.t"
+        );
+    }
+
+    #[test]
+    fn long_case_is_not_rewritten() {
+        let arms = (0..CASE_WHEN_MAX_ARGS / 2 + 1)
+            .map(|i| format!("WHEN a = {} THEN {}", i, i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let sql = format!("SELECT CASE {} END AS x FROM s.t", arms);
+        assert_eq!(rewrite(&sql), sql);
+    }
+
+    #[test]
+    fn rewrites_count_distinct() {
+        assert_eq!(
+            rewrite("SELECT a, COUNT(DISTINCT b), count(b), count(*) FROM s.t GROUP BY a"),
+            "SELECT a, count_distinct(b, 0), count(b), count(*) FROM s.t GROUP BY a"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::queryplanner::query_executor::batches_to_rows;
+use crate::table::Row;
+use crate::CubeError;
+use arrow::array::BooleanArray;
+use arrow::compute::kernels::filter::filter_record_batch;
+use arrow::datatypes::SchemaRef;
+use arrow::error::{ArrowError, Result as ArrowResult};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::error::DataFusionError;
+use datafusion::logical_plan::DFSchemaRef;
+use datafusion::physical_plan::parquet::ParquetExec;
+use datafusion::physical_plan::{collect, ExecutionPlan, Partitioning, RecordBatchStream};
+use futures::{Stream, StreamExt};
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::sync::Mutex;
+
+/// Rows deleted by tombstone chunks of a partition. A tombstone deletes only matching rows
+/// written before it: rows of the partition file and of chunks with smaller sequences, see
+/// `IdRow::sequence`. Each tombstone row deletes a single matching row so duplicates are deleted
+/// as many times as they were selected for deletion. Tombstones of tables with a unique key
+/// delete all older rows of the key instead as only the last one of them is visible.
+#[derive(Debug, Clone)]
+pub struct DeletedRows {
+    key_columns: Option<Vec<usize>>,
+    /// Sequences of tombstones deleting a row, or a key, in ascending order.
+    deleted: HashMap<Row, Vec<u64>>,
+}
+
+impl DeletedRows {
+    pub fn new(key_columns: Option<Vec<usize>>) -> DeletedRows {
+        DeletedRows {
+            key_columns,
+            deleted: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deleted.is_empty()
+    }
+
+    /// Adds `row` of the tombstone written as `sequence`.
+    pub fn add(&mut self, row: Row, sequence: u64) {
+        let key = match &self.key_columns {
+            Some(key_columns) => Self::key(&row, key_columns),
+            None => row,
+        };
+        let sequences = self.deleted.entry(key).or_insert_with(Vec::new);
+        let position = match sequences.binary_search(&sequence) {
+            Ok(i) | Err(i) => i,
+        };
+        sequences.insert(position, sequence);
+    }
+
+    /// Whether `row` written as `sequence` is deleted. The earliest tombstone row written after
+    /// it is used up unless the table has a unique key.
+    pub fn take(&mut self, row: &Row, sequence: u64) -> bool {
+        let sequences = match &self.key_columns {
+            Some(key_columns) => self.deleted.get_mut(&Self::key(row, key_columns)),
+            None => self.deleted.get_mut(row),
+        };
+        let sequences = match sequences {
+            Some(sequences) => sequences,
+            None => return false,
+        };
+        match sequences.iter().position(|s| *s > sequence) {
+            Some(i) => {
+                if self.key_columns.is_none() {
+                    sequences.remove(i);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn key(row: &Row, key_columns: &[usize]) -> Row {
+        Row::new(
+            key_columns
+                .iter()
+                .map(|i| row.values()[*i].clone())
+                .collect(),
+        )
+    }
+}
+
+/// Tombstone files of a partition along with their sequences. Files are read once per plan and
+/// each execution gets its own copy of deleted rows to use up.
+#[derive(Debug)]
+pub struct Tombstones {
+    files: Vec<(String, u64)>,
+    key_columns: Option<Vec<usize>>,
+    batch_size: usize,
+    loaded: Mutex<Option<DeletedRows>>,
+}
+
+impl Tombstones {
+    pub fn new(
+        files: Vec<(String, u64)>,
+        key_columns: Option<Vec<usize>>,
+        batch_size: usize,
+    ) -> Arc<Tombstones> {
+        Arc::new(Tombstones {
+            files,
+            key_columns,
+            batch_size,
+            loaded: Mutex::new(None),
+        })
+    }
+
+    pub async fn load(&self) -> Result<DeletedRows, CubeError> {
+        let mut loaded = self.loaded.lock().await;
+        if let Some(deleted) = loaded.as_ref() {
+            return Ok(deleted.clone());
+        }
+        let mut deleted = DeletedRows::new(self.key_columns.clone());
+        for (file, sequence) in self.files.iter() {
+            let batches = collect(Arc::new(ParquetExec::try_from_path(
+                file,
+                None,
+                self.batch_size,
+                1,
+            )?))
+            .await?;
+            for row in batches_to_rows(&batches) {
+                deleted.add(row?, *sequence);
+            }
+        }
+        *loaded = Some(deleted.clone());
+        Ok(deleted)
+    }
+}
+
+/// Scan of a partition with tombstones. `inputs` are the partition file and chunks along with
+/// the sequences they were written with and are read one after another. Every row is converted
+/// to a `Row` to be matched to deleted ones by all columns, so inputs are read with all columns
+/// and `projection` is applied after filtering. Such scans are slower than plain ones until
+/// compaction drops deleted rows for good.
+#[derive(Debug)]
+pub struct TombstoneExec {
+    inputs: Vec<(Arc<dyn ExecutionPlan>, u64)>,
+    tombstones: Arc<Tombstones>,
+    projection: Vec<usize>,
+    schema: DFSchemaRef,
+}
+
+impl TombstoneExec {
+    pub fn new(
+        inputs: Vec<(Arc<dyn ExecutionPlan>, u64)>,
+        tombstones: Arc<Tombstones>,
+        projection: Vec<usize>,
+        schema: DFSchemaRef,
+    ) -> TombstoneExec {
+        TombstoneExec {
+            inputs,
+            tombstones,
+            projection,
+            schema,
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for TombstoneExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> DFSchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        self.inputs.iter().map(|(input, _)| input.clone()).collect()
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        Ok(Arc::new(TombstoneExec::new(
+            children
+                .into_iter()
+                .zip(self.inputs.iter().map(|(_, sequence)| *sequence))
+                .collect(),
+            self.tombstones.clone(),
+            self.projection.clone(),
+            self.schema.clone(),
+        )))
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
+        if partition != 0 {
+            return Err(DataFusionError::Internal(format!(
+                "TombstoneExec invalid partition {}",
+                partition
+            )));
+        }
+        let deleted = self.tombstones.load().await?;
+        let mut inputs = VecDeque::new();
+        for (input, sequence) in self.inputs.iter() {
+            for p in 0..input.output_partitioning().partition_count() {
+                inputs.push_back((input.execute(p).await?, *sequence));
+            }
+        }
+        Ok(Box::pin(TombstoneStream {
+            inputs,
+            deleted,
+            projection: self.projection.clone(),
+            schema: self.schema.to_schema_ref(),
+        }))
+    }
+}
+
+struct TombstoneStream {
+    inputs: VecDeque<(Pin<Box<dyn RecordBatchStream + Send>>, u64)>,
+    deleted: DeletedRows,
+    projection: Vec<usize>,
+    schema: SchemaRef,
+}
+
+impl TombstoneStream {
+    fn apply(&mut self, batch: &RecordBatch, sequence: u64) -> Result<RecordBatch, CubeError> {
+        let keep = batches_to_rows(std::slice::from_ref(batch))
+            .map(|row| Ok(!self.deleted.take(&row?, sequence)))
+            .collect::<Result<Vec<_>, CubeError>>()?;
+        let batch = filter_record_batch(batch, &BooleanArray::from(keep))?;
+        Ok(RecordBatch::try_new(
+            self.schema.clone(),
+            self.projection
+                .iter()
+                .map(|i| batch.column(*i).clone())
+                .collect(),
+        )?)
+    }
+}
+
+impl Stream for TombstoneStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let (input, sequence) = match self.inputs.front_mut() {
+                Some((input, sequence)) => (input, *sequence),
+                None => return Poll::Ready(None),
+            };
+            match input.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(batch))) => {
+                    return Poll::Ready(Some(
+                        self.apply(&batch, sequence)
+                            .map_err(|e| ArrowError::ExternalError(Box::new(e))),
+                    ))
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    self.inputs.pop_front();
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl RecordBatchStream for TombstoneStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::TableValue;
+
+    fn row(id: i64, value: i64) -> Row {
+        Row::new(vec![TableValue::Int(id), TableValue::Int(value)])
+    }
+
+    #[test]
+    fn deletes_only_rows_written_before() {
+        let mut deleted = DeletedRows::new(None);
+        deleted.add(row(2, 20), 5);
+        deleted.add(row(2, 20), 9);
+
+        // Partition file and the chunk before the first tombstone
+        assert!(deleted.take(&row(2, 20), 0));
+        assert!(!deleted.take(&row(1, 10), 0));
+        // Re-inserted after the first tombstone and deleted by the second one
+        assert!(deleted.take(&row(2, 20), 7));
+        // Re-inserted after both
+        assert!(!deleted.take(&row(2, 20), 10));
+        // Each tombstone row deletes a single row
+        assert!(!deleted.take(&row(2, 20), 0));
+    }
+
+    #[test]
+    fn deletes_older_rows_of_unique_key() {
+        let mut deleted = DeletedRows::new(Some(vec![0]));
+        deleted.add(row(1, 11), 5);
+
+        assert!(deleted.take(&row(1, 10), 0));
+        assert!(deleted.take(&row(1, 11), 3));
+        assert!(!deleted.take(&row(1, 12), 6));
+        assert!(!deleted.take(&row(2, 20), 0));
+    }
+}
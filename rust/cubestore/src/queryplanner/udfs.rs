@@ -0,0 +1,2061 @@
+use crate::hash::StableHasher;
+use crate::queryplanner::date_arithmetic::{add_months, parse_interval, Interval};
+use crate::queryplanner::hll::HyperLogLog;
+use arrow::array::{
+    Array, ArrayRef, BooleanArray, Float64Array, Int64Array, Int64Decimal0Array,
+    Int64Decimal10Array, Int64Decimal1Array, Int64Decimal2Array, Int64Decimal3Array,
+    Int64Decimal4Array, Int64Decimal5Array, StringArray, TimestampMicrosecondArray,
+    TimestampMillisecondArray, TimestampNanosecondArray, TimestampSecondArray,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, TimeUnit};
+use arrow::util::display::array_value_to_string;
+use bigdecimal::{BigDecimal, ToPrimitive};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, Timelike};
+use datafusion::error::DataFusionError;
+use datafusion::logical_plan::create_udf;
+use datafusion::physical_plan::aggregates::{AccumulatorFunctionImplementation, StateTypeFunction};
+use datafusion::physical_plan::datetime_expressions::string_to_timestamp_nanos;
+use datafusion::physical_plan::functions::{make_scalar_function, ReturnTypeFunction, Signature};
+use datafusion::physical_plan::udaf::AggregateUDF;
+use datafusion::physical_plan::udf::ScalarUDF;
+use datafusion::physical_plan::Accumulator;
+use datafusion::scalar::ScalarValue;
+use num::BigInt;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::hash::Hasher;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// Scalar functions provided by CubeStore on top of DataFusion built-ins.
+/// Only the kind travels inside serialized plans, workers rebuild the function from it.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum CubeScalarUDFKind {
+    ConvertTz,
+    DateAdd,
+    DateSub,
+    DatePart,
+    ToTimestampSeconds,
+    ToTimestampMillis,
+    Substr,
+    Btrim,
+    CharLength,
+    Coalesce,
+    NullIf,
+    IfNull,
+    CaseWhen,
+    CastToInt64,
+    CastToFloat64,
+    /// Scale is one of `CAST_DECIMAL_SCALES`.
+    CastToDecimal(usize),
+    CastToTimestamp,
+    CastToDate,
+    CastToString,
+}
+
+/// Scales `Int64Decimal` arrays exist for.
+pub const CAST_DECIMAL_SCALES: [usize; 7] = [0, 1, 2, 3, 4, 5, 10];
+
+impl CubeScalarUDFKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CubeScalarUDFKind::ConvertTz => "convert_tz",
+            CubeScalarUDFKind::DateAdd => "date_add",
+            CubeScalarUDFKind::DateSub => "date_sub",
+            CubeScalarUDFKind::DatePart => "date_part",
+            CubeScalarUDFKind::ToTimestampSeconds => "to_timestamp_seconds",
+            CubeScalarUDFKind::ToTimestampMillis => "to_timestamp_millis",
+            CubeScalarUDFKind::Substr => "substr",
+            CubeScalarUDFKind::Btrim => "btrim",
+            CubeScalarUDFKind::CharLength => "char_length",
+            CubeScalarUDFKind::Coalesce => "coalesce",
+            CubeScalarUDFKind::NullIf => "nullif",
+            CubeScalarUDFKind::IfNull => "ifnull",
+            CubeScalarUDFKind::CaseWhen => "case_when",
+            CubeScalarUDFKind::CastToInt64 => "cast_to_bigint",
+            CubeScalarUDFKind::CastToFloat64 => "cast_to_double",
+            CubeScalarUDFKind::CastToDecimal(0) => "cast_to_decimal_0",
+            CubeScalarUDFKind::CastToDecimal(1) => "cast_to_decimal_1",
+            CubeScalarUDFKind::CastToDecimal(2) => "cast_to_decimal_2",
+            CubeScalarUDFKind::CastToDecimal(3) => "cast_to_decimal_3",
+            CubeScalarUDFKind::CastToDecimal(4) => "cast_to_decimal_4",
+            CubeScalarUDFKind::CastToDecimal(5) => "cast_to_decimal_5",
+            CubeScalarUDFKind::CastToDecimal(_) => "cast_to_decimal_10",
+            CubeScalarUDFKind::CastToTimestamp => "cast_to_timestamp",
+            CubeScalarUDFKind::CastToDate => "cast_to_date",
+            CubeScalarUDFKind::CastToString => "cast_to_string",
+        }
+    }
+
+    pub fn all() -> Vec<CubeScalarUDFKind> {
+        let mut kinds = vec![
+            CubeScalarUDFKind::ConvertTz,
+            CubeScalarUDFKind::DateAdd,
+            CubeScalarUDFKind::DateSub,
+            CubeScalarUDFKind::DatePart,
+            CubeScalarUDFKind::ToTimestampSeconds,
+            CubeScalarUDFKind::ToTimestampMillis,
+            CubeScalarUDFKind::Substr,
+            CubeScalarUDFKind::Btrim,
+            CubeScalarUDFKind::CharLength,
+            CubeScalarUDFKind::Coalesce,
+            CubeScalarUDFKind::NullIf,
+            CubeScalarUDFKind::IfNull,
+            CubeScalarUDFKind::CaseWhen,
+            CubeScalarUDFKind::CastToInt64,
+            CubeScalarUDFKind::CastToFloat64,
+            CubeScalarUDFKind::CastToTimestamp,
+            CubeScalarUDFKind::CastToDate,
+            CubeScalarUDFKind::CastToString,
+        ];
+        kinds.extend(
+            CAST_DECIMAL_SCALES
+                .iter()
+                .map(|scale| CubeScalarUDFKind::CastToDecimal(*scale)),
+        );
+        kinds
+    }
+
+    pub fn from_name(name: &str) -> Option<CubeScalarUDFKind> {
+        Self::all()
+            .into_iter()
+            .find(|k| k.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn udf(&self) -> ScalarUDF {
+        match self {
+            CubeScalarUDFKind::ConvertTz => convert_tz_udf(),
+            CubeScalarUDFKind::DateAdd => date_arithmetic_udf(*self, false),
+            CubeScalarUDFKind::DateSub => date_arithmetic_udf(*self, true),
+            CubeScalarUDFKind::DatePart => date_part_udf(),
+            CubeScalarUDFKind::ToTimestampSeconds => to_timestamp_udf(*self, 1_000_000_000),
+            CubeScalarUDFKind::ToTimestampMillis => to_timestamp_udf(*self, 1_000_000),
+            CubeScalarUDFKind::Substr => substr_udf(),
+            CubeScalarUDFKind::Btrim => btrim_udf(),
+            CubeScalarUDFKind::CharLength => char_length_udf(),
+            CubeScalarUDFKind::Coalesce => null_handling_udf(
+                *self,
+                Signature::OneOf((1..=16).map(Signature::Any).collect()),
+            ),
+            CubeScalarUDFKind::NullIf | CubeScalarUDFKind::IfNull => {
+                null_handling_udf(*self, Signature::Any(2))
+            }
+            CubeScalarUDFKind::CaseWhen => case_when_udf(),
+            CubeScalarUDFKind::CastToInt64 => cast_udf(*self, DataType::Int64),
+            CubeScalarUDFKind::CastToFloat64 => cast_udf(*self, DataType::Float64),
+            CubeScalarUDFKind::CastToDecimal(scale) => {
+                cast_udf(*self, DataType::Int64Decimal(*scale))
+            }
+            CubeScalarUDFKind::CastToTimestamp | CubeScalarUDFKind::CastToDate => {
+                cast_udf(*self, DataType::Timestamp(TimeUnit::Nanosecond, None))
+            }
+            CubeScalarUDFKind::CastToString => cast_udf(*self, DataType::Utf8),
+        }
+    }
+}
+
+/// Aggregate functions provided by CubeStore, serialized the same way as `CubeScalarUDFKind`.
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum CubeAggregateUDFKind {
+    ApproxDistinct,
+    CountDistinct,
+}
+
+impl CubeAggregateUDFKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CubeAggregateUDFKind::ApproxDistinct => "approx_distinct",
+            CubeAggregateUDFKind::CountDistinct => "count_distinct",
+        }
+    }
+
+    pub fn all() -> Vec<CubeAggregateUDFKind> {
+        vec![
+            CubeAggregateUDFKind::ApproxDistinct,
+            CubeAggregateUDFKind::CountDistinct,
+        ]
+    }
+
+    pub fn from_name(name: &str) -> Option<CubeAggregateUDFKind> {
+        Self::all()
+            .into_iter()
+            .find(|k| k.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn udaf(&self) -> AggregateUDF {
+        match self {
+            CubeAggregateUDFKind::ApproxDistinct => approx_distinct_udaf(),
+            CubeAggregateUDFKind::CountDistinct => count_distinct_udaf(),
+        }
+    }
+}
+
+/// `CONVERT_TZ(timestamp, from_tz, to_tz)` shifts a timestamp between fixed UTC offsets.
+/// Timestamps are stored in UTC so `CONVERT_TZ(t, '+00:00', '+02:00')` gives the local time.
+/// Named time zones like `Europe/Berlin` are rejected as their offset depends on DST rules.
+fn convert_tz_udf() -> ScalarUDF {
+    let timestamp = DataType::Timestamp(TimeUnit::Microsecond, None);
+    create_udf(
+        CubeScalarUDFKind::ConvertTz.name(),
+        vec![timestamp.clone(), DataType::Utf8, DataType::Utf8],
+        Arc::new(timestamp),
+        make_scalar_function(convert_tz),
+    )
+}
+
+fn convert_tz(args: &[ArrayRef]) -> Result<ArrayRef, DataFusionError> {
+    let timestamps = args[0]
+        .as_any()
+        .downcast_ref::<TimestampMicrosecondArray>()
+        .unwrap();
+    let from_tz = args[1].as_any().downcast_ref::<StringArray>().unwrap();
+    let to_tz = args[2].as_any().downcast_ref::<StringArray>().unwrap();
+    let mut result = Vec::with_capacity(timestamps.len());
+    for i in 0..timestamps.len() {
+        if timestamps.is_null(i) || from_tz.is_null(i) || to_tz.is_null(i) {
+            result.push(None);
+            continue;
+        }
+        let shift = parse_tz_offset(to_tz.value(i))? - parse_tz_offset(from_tz.value(i))?;
+        result.push(Some(timestamps.value(i) + shift * 1_000_000));
+    }
+    Ok(Arc::new(TimestampMicrosecondArray::from(result)))
+}
+
+/// `DATE_ADD(timestamp, interval)` and `DATE_SUB(timestamp, interval)` where interval is a
+/// string like `7 days`. Timestamps of any unit are accepted and returned in the same unit.
+fn date_arithmetic_udf(kind: CubeScalarUDFKind, subtract: bool) -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|args| Ok(Arc::new(args[0].clone())));
+    ScalarUDF::new(
+        kind.name(),
+        &Signature::Any(2),
+        &return_type,
+        &make_scalar_function(move |args: &[ArrayRef]| date_arithmetic(args, subtract)),
+    )
+}
+
+macro_rules! shift_timestamp_array {
+    ($ARRAY:expr, $INTERVALS:expr, $SUBTRACT:expr, $ARRAY_TYPE:ident, $UNITS_PER_SECOND:expr) => {{
+        let timestamps = $ARRAY.as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
+        let mut result = Vec::with_capacity(timestamps.len());
+        for i in 0..timestamps.len() {
+            if timestamps.is_null(i) || $INTERVALS.is_null(i) {
+                result.push(None);
+                continue;
+            }
+            let mut interval = parse_interval($INTERVALS.value(i))?;
+            if $SUBTRACT {
+                interval = interval.negate();
+            }
+            result.push(Some(shift_timestamp(
+                timestamps.value(i),
+                $UNITS_PER_SECOND,
+                interval,
+            )?));
+        }
+        Ok(Arc::new($ARRAY_TYPE::from(result)) as ArrayRef)
+    }};
+}
+
+fn date_arithmetic(args: &[ArrayRef], subtract: bool) -> Result<ArrayRef, DataFusionError> {
+    let intervals = args[1]
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "Interval is expected to be a string but {:?} found",
+                args[1].data_type()
+            ))
+        })?;
+    match args[0].data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            shift_timestamp_array!(args[0], intervals, subtract, TimestampSecondArray, 1)
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => shift_timestamp_array!(
+            args[0],
+            intervals,
+            subtract,
+            TimestampMillisecondArray,
+            1_000
+        ),
+        DataType::Timestamp(TimeUnit::Microsecond, _) => shift_timestamp_array!(
+            args[0],
+            intervals,
+            subtract,
+            TimestampMicrosecondArray,
+            1_000_000
+        ),
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => shift_timestamp_array!(
+            args[0],
+            intervals,
+            subtract,
+            TimestampNanosecondArray,
+            1_000_000_000
+        ),
+        x => Err(DataFusionError::Execution(format!(
+            "Interval arithmetic isn't supported for {:?}",
+            x
+        ))),
+    }
+}
+
+fn shift_timestamp(
+    value: i64,
+    units_per_second: i64,
+    interval: Interval,
+) -> Result<i64, DataFusionError> {
+    let out_of_range = || {
+        DataFusionError::Execution(format!(
+            "Timestamp {} shifted by {:?} is out of range",
+            value, interval
+        ))
+    };
+    let mut shifted = value;
+    if interval.months != 0 {
+        let seconds = value.div_euclid(units_per_second);
+        let fraction = value.rem_euclid(units_per_second);
+        let date_time = NaiveDateTime::from_timestamp_opt(seconds, 0)
+            .and_then(|d| add_months(d, interval.months))
+            .ok_or_else(out_of_range)?;
+        shifted = date_time
+            .timestamp()
+            .checked_mul(units_per_second)
+            .and_then(|v| v.checked_add(fraction))
+            .ok_or_else(out_of_range)?;
+    }
+    let shift = if units_per_second >= 1_000 {
+        interval.millis.checked_mul(units_per_second / 1_000)
+    } else {
+        Some(interval.millis / 1_000)
+    };
+    shift
+        .and_then(|s| shifted.checked_add(s))
+        .ok_or_else(out_of_range)
+}
+
+/// `DATE_PART(part, timestamp)` where part is one of `year`, `quarter`, `month`, `week`,
+/// `day`, `dow`, `doy`, `hour`, `minute` or `second`. `EXTRACT` is rewritten into it.
+fn date_part_udf() -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Int64)));
+    ScalarUDF::new(
+        CubeScalarUDFKind::DatePart.name(),
+        &Signature::Any(2),
+        &return_type,
+        &make_scalar_function(date_part),
+    )
+}
+
+macro_rules! timestamps_to_date_times {
+    ($ARRAY:expr, $ARRAY_TYPE:ident, $UNITS_PER_SECOND:expr) => {{
+        let timestamps = $ARRAY.as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
+        (0..timestamps.len())
+            .map(|i| {
+                if timestamps.is_null(i) {
+                    return None;
+                }
+                let value: i64 = timestamps.value(i);
+                NaiveDateTime::from_timestamp_opt(
+                    value.div_euclid($UNITS_PER_SECOND),
+                    (value.rem_euclid($UNITS_PER_SECOND) * (1_000_000_000 / $UNITS_PER_SECOND))
+                        as u32,
+                )
+            })
+            .collect::<Vec<_>>()
+    }};
+}
+
+fn date_times(array: &ArrayRef) -> Result<Vec<Option<NaiveDateTime>>, DataFusionError> {
+    Ok(match array.data_type() {
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            timestamps_to_date_times!(array, TimestampSecondArray, 1)
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            timestamps_to_date_times!(array, TimestampMillisecondArray, 1_000)
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            timestamps_to_date_times!(array, TimestampMicrosecondArray, 1_000_000)
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            timestamps_to_date_times!(array, TimestampNanosecondArray, 1_000_000_000)
+        }
+        x => {
+            return Err(DataFusionError::Execution(format!(
+                "Timestamp is expected but {:?} found",
+                x
+            )))
+        }
+    })
+}
+
+fn date_part(args: &[ArrayRef]) -> Result<ArrayRef, DataFusionError> {
+    let parts = args[0]
+        .as_any()
+        .downcast_ref::<StringArray>()
+        .ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "Date part is expected to be a string but {:?} found",
+                args[0].data_type()
+            ))
+        })?;
+    let mut result = Vec::with_capacity(parts.len());
+    for (i, date_time) in date_times(&args[1])?.into_iter().enumerate() {
+        result.push(match date_time {
+            Some(date_time) if !parts.is_null(i) => Some(extract_part(parts.value(i), &date_time)?),
+            _ => None,
+        });
+    }
+    Ok(Arc::new(Int64Array::from(result)))
+}
+
+fn extract_part(part: &str, date_time: &NaiveDateTime) -> Result<i64, DataFusionError> {
+    Ok(match part.to_lowercase().as_str() {
+        "year" => date_time.year() as i64,
+        "quarter" => (date_time.month0() / 3 + 1) as i64,
+        "month" => date_time.month() as i64,
+        "week" => date_time.iso_week().week() as i64,
+        "day" => date_time.day() as i64,
+        "dow" => date_time.weekday().num_days_from_sunday() as i64,
+        "doy" => date_time.ordinal() as i64,
+        "hour" => date_time.hour() as i64,
+        "minute" => date_time.minute() as i64,
+        "second" => date_time.second() as i64,
+        _ => {
+            return Err(DataFusionError::Execution(format!(
+                "Unsupported date part: '{}'",
+                part
+            )))
+        }
+    })
+}
+
+/// `TO_TIMESTAMP_SECONDS(seconds)` and `TO_TIMESTAMP_MILLIS(millis)` convert Unix time.
+/// Built-in `TO_TIMESTAMP` only parses strings.
+fn to_timestamp_udf(kind: CubeScalarUDFKind, nanos_per_unit: i64) -> ScalarUDF {
+    create_udf(
+        kind.name(),
+        vec![DataType::Int64],
+        Arc::new(DataType::Timestamp(TimeUnit::Nanosecond, None)),
+        make_scalar_function(move |args: &[ArrayRef]| to_timestamp(args, nanos_per_unit)),
+    )
+}
+
+fn to_timestamp(args: &[ArrayRef], nanos_per_unit: i64) -> Result<ArrayRef, DataFusionError> {
+    let values = args[0].as_any().downcast_ref::<Int64Array>().unwrap();
+    let mut result = Vec::with_capacity(values.len());
+    for i in 0..values.len() {
+        if values.is_null(i) {
+            result.push(None);
+            continue;
+        }
+        let value = values.value(i);
+        result.push(Some(value.checked_mul(nanos_per_unit).ok_or_else(
+            || DataFusionError::Execution(format!("Timestamp {} is out of range", value)),
+        )?));
+    }
+    Ok(Arc::new(TimestampNanosecondArray::from(result)))
+}
+
+/// Parses `UTC`, `Z` or `[+-]HH:MM` into an offset in seconds.
+pub fn parse_tz_offset(tz: &str) -> Result<i64, DataFusionError> {
+    let invalid = || {
+        DataFusionError::Execution(format!(
+            "Unsupported time zone: '{}'. Only 'UTC' and fixed offsets like '+02:00' are supported, named time zones are not",
+            tz
+        ))
+    };
+    let tz = tz.trim();
+    if tz.eq_ignore_ascii_case("UTC") || tz == "Z" {
+        return Ok(0);
+    }
+    let sign = match tz.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return Err(invalid()),
+    };
+    let mut parts = tz[1..].split(':');
+    let hours = parts
+        .next()
+        .and_then(|h| h.parse::<i64>().ok())
+        .ok_or_else(invalid)?;
+    let minutes = match parts.next() {
+        Some(m) => m.parse::<i64>().map_err(|_| invalid())?,
+        None => 0,
+    };
+    if parts.next().is_some() || hours > 14 || minutes >= 60 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 3600 + minutes * 60))
+}
+
+/// `SUBSTR(string, start[, length])` with MySQL semantics: `start` is 1-based and counts from
+/// the end of the string if negative. Zero start or start outside of the string gives ''.
+fn substr_udf() -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Utf8)));
+    ScalarUDF::new(
+        CubeScalarUDFKind::Substr.name(),
+        &Signature::OneOf(vec![
+            Signature::Exact(vec![DataType::Utf8, DataType::Int64]),
+            Signature::Exact(vec![DataType::Utf8, DataType::Int64, DataType::Int64]),
+        ]),
+        &return_type,
+        &make_scalar_function(substr),
+    )
+}
+
+fn substr(args: &[ArrayRef]) -> Result<ArrayRef, DataFusionError> {
+    let strings = args[0].as_any().downcast_ref::<StringArray>().unwrap();
+    let starts = args[1].as_any().downcast_ref::<Int64Array>().unwrap();
+    let lengths = args
+        .get(2)
+        .map(|a| a.as_any().downcast_ref::<Int64Array>().unwrap());
+    let mut result = Vec::with_capacity(strings.len());
+    for i in 0..strings.len() {
+        if strings.is_null(i) || starts.is_null(i) || lengths.map_or(false, |l| l.is_null(i)) {
+            result.push(None);
+            continue;
+        }
+        result.push(Some(substring(
+            strings.value(i),
+            starts.value(i),
+            lengths.map(|l| l.value(i)),
+        )));
+    }
+    Ok(Arc::new(StringArray::from(
+        result.iter().map(|s| s.as_deref()).collect::<Vec<_>>(),
+    )))
+}
+
+fn substring(string: &str, start: i64, length: Option<i64>) -> String {
+    let char_count = string.chars().count() as i64;
+    let start = match start {
+        0 => return String::new(),
+        s if s > 0 => s - 1,
+        s => char_count + s,
+    };
+    if start < 0 || start >= char_count {
+        return String::new();
+    }
+    let length = length.unwrap_or(char_count).max(0);
+    string
+        .chars()
+        .skip(start as usize)
+        .take(length as usize)
+        .collect()
+}
+
+/// `BTRIM(string[, characters])` removes any of the characters, spaces by default, from both
+/// ends of the string.
+fn btrim_udf() -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Utf8)));
+    ScalarUDF::new(
+        CubeScalarUDFKind::Btrim.name(),
+        &Signature::OneOf(vec![
+            Signature::Exact(vec![DataType::Utf8]),
+            Signature::Exact(vec![DataType::Utf8, DataType::Utf8]),
+        ]),
+        &return_type,
+        &make_scalar_function(btrim),
+    )
+}
+
+fn btrim(args: &[ArrayRef]) -> Result<ArrayRef, DataFusionError> {
+    let strings = args[0].as_any().downcast_ref::<StringArray>().unwrap();
+    let characters = args
+        .get(1)
+        .map(|a| a.as_any().downcast_ref::<StringArray>().unwrap());
+    let mut result = Vec::with_capacity(strings.len());
+    for i in 0..strings.len() {
+        if strings.is_null(i) || characters.map_or(false, |c| c.is_null(i)) {
+            result.push(None);
+            continue;
+        }
+        let trimmed = match characters {
+            Some(c) => {
+                let c = c.value(i);
+                strings.value(i).trim_matches(|ch| c.contains(ch))
+            }
+            None => strings.value(i).trim_matches(' '),
+        };
+        result.push(Some(trimmed));
+    }
+    Ok(Arc::new(StringArray::from(result)))
+}
+
+/// `CHAR_LENGTH(string)` counts characters rather than bytes.
+fn char_length_udf() -> ScalarUDF {
+    create_udf(
+        CubeScalarUDFKind::CharLength.name(),
+        vec![DataType::Utf8],
+        Arc::new(DataType::Int64),
+        make_scalar_function(char_length),
+    )
+}
+
+fn char_length(args: &[ArrayRef]) -> Result<ArrayRef, DataFusionError> {
+    let strings = args[0].as_any().downcast_ref::<StringArray>().unwrap();
+    let mut result = Vec::with_capacity(strings.len());
+    for i in 0..strings.len() {
+        result.push(if strings.is_null(i) {
+            None
+        } else {
+            Some(strings.value(i).chars().count() as i64)
+        });
+    }
+    Ok(Arc::new(Int64Array::from(result)))
+}
+
+/// `COALESCE(value, ...)`, `IFNULL(value, default)` and `NULLIF(value, other)`. Arguments are
+/// cast to a common type first, so e.g. decimals of different scales are rescaled to the largest
+/// one and `COALESCE(sum(decimal), 0)` keeps the decimal type. `NULLIF` only compares in the
+/// common type and returns the type of its first argument.
+fn null_handling_udf(kind: CubeScalarUDFKind, signature: Signature) -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(move |types| {
+        let data_type = common_type(kind, types)?;
+        Ok(Arc::new(match kind {
+            CubeScalarUDFKind::NullIf => types[0].clone(),
+            _ => data_type,
+        }))
+    });
+    ScalarUDF::new(
+        kind.name(),
+        &signature,
+        &return_type,
+        &make_scalar_function(move |args: &[ArrayRef]| {
+            let data_type = common_type(
+                kind,
+                &args
+                    .iter()
+                    .map(|a| a.data_type().clone())
+                    .collect::<Vec<_>>(),
+            )?;
+            let cast_args = args
+                .iter()
+                .map(|a| cast_to(a, &data_type))
+                .collect::<Result<Vec<_>, _>>()?;
+            match kind {
+                CubeScalarUDFKind::NullIf => null_if(&args[0], &cast_args, &data_type),
+                _ => coalesce(&cast_args, &data_type),
+            }
+        }),
+    )
+}
+
+/// `coerced_type` of function arguments, fails if they have none.
+fn common_type(kind: CubeScalarUDFKind, types: &[DataType]) -> Result<DataType, DataFusionError> {
+    if let Some(data_type) = coerced_type(types) {
+        return Ok(data_type);
+    }
+    let arguments = match kind {
+        CubeScalarUDFKind::CaseWhen => "CASE branches".to_string(),
+        _ => format!("{} arguments", kind.name().to_uppercase()),
+    };
+    Err(DataFusionError::Plan(format!(
+        "{} have incompatible types: {:?}",
+        arguments, types
+    )))
+}
+
+/// Type values of all `types` can be cast to with `cast_to`, `None` if there's no such type.
+/// Numbers are always widened to `Int64`, `Float64` or `Int64Decimal` as the only numeric types
+/// results are built for.
+pub fn coerced_type(types: &[DataType]) -> Option<DataType> {
+    if types.iter().all(is_numeric) {
+        if types.iter().any(is_float) {
+            return Some(DataType::Float64);
+        }
+        let decimal_scale = types
+            .iter()
+            .filter_map(|t| match t {
+                DataType::Int64Decimal(scale) => Some(*scale),
+                _ => None,
+            })
+            .max();
+        return Some(match decimal_scale {
+            Some(scale) => DataType::Int64Decimal(scale),
+            None => DataType::Int64,
+        });
+    }
+    let first = &types[0];
+    if types.iter().all(|t| t == first) {
+        return Some(first.clone());
+    }
+    if types.iter().all(|t| matches!(t, DataType::Timestamp(_, _))) {
+        return Some(DataType::Timestamp(TimeUnit::Nanosecond, None));
+    }
+    None
+}
+
+fn is_numeric(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::UInt8
+        | DataType::UInt16
+        | DataType::UInt32
+        | DataType::UInt64
+        | DataType::Float16
+        | DataType::Float32
+        | DataType::Float64
+        | DataType::Int64Decimal(_) => true,
+        _ => false,
+    }
+}
+
+fn is_float(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Float16 | DataType::Float32 | DataType::Float64
+    )
+}
+
+macro_rules! primitive_values {
+    ($ARRAY:expr, $ARRAY_TYPE:ident) => {{
+        let a = $ARRAY.as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
+        (0..a.len())
+            .map(|i| if a.is_null(i) { None } else { Some(a.value(i)) })
+            .collect::<Vec<_>>()
+    }};
+}
+
+/// Unscaled values of an integer or `Int64Decimal` array along with its scale.
+fn scaled_values(array: &ArrayRef) -> Result<(Vec<Option<i64>>, usize), DataFusionError> {
+    Ok(match array.data_type() {
+        DataType::Int64Decimal(0) => (primitive_values!(array, Int64Decimal0Array), 0),
+        DataType::Int64Decimal(1) => (primitive_values!(array, Int64Decimal1Array), 1),
+        DataType::Int64Decimal(2) => (primitive_values!(array, Int64Decimal2Array), 2),
+        DataType::Int64Decimal(3) => (primitive_values!(array, Int64Decimal3Array), 3),
+        DataType::Int64Decimal(4) => (primitive_values!(array, Int64Decimal4Array), 4),
+        DataType::Int64Decimal(5) => (primitive_values!(array, Int64Decimal5Array), 5),
+        DataType::Int64Decimal(10) => (primitive_values!(array, Int64Decimal10Array), 10),
+        _ => {
+            let ints = cast(array, &DataType::Int64)?;
+            (primitive_values!(ints, Int64Array), 0)
+        }
+    })
+}
+
+pub fn int64_decimal_array(
+    values: Vec<Option<i64>>,
+    scale: usize,
+) -> Result<ArrayRef, DataFusionError> {
+    Ok(match scale {
+        0 => Arc::new(Int64Decimal0Array::from(values)),
+        1 => Arc::new(Int64Decimal1Array::from(values)),
+        2 => Arc::new(Int64Decimal2Array::from(values)),
+        3 => Arc::new(Int64Decimal3Array::from(values)),
+        4 => Arc::new(Int64Decimal4Array::from(values)),
+        5 => Arc::new(Int64Decimal5Array::from(values)),
+        10 => Arc::new(Int64Decimal10Array::from(values)),
+        x => {
+            return Err(DataFusionError::Execution(format!(
+                "Unsupported Int64Decimal scale: {}",
+                x
+            )))
+        }
+    })
+}
+
+/// Arrow casts don't know about `Int64Decimal` so conversions to and from it are done here.
+pub fn cast_to(array: &ArrayRef, data_type: &DataType) -> Result<ArrayRef, DataFusionError> {
+    if array.data_type() == data_type {
+        return Ok(array.clone());
+    }
+    match (array.data_type(), data_type) {
+        (_, DataType::Int64Decimal(scale)) => {
+            let (values, from_scale) = scaled_values(array)?;
+            let multiplier = scale
+                .checked_sub(from_scale)
+                .map(|d| 10i64.pow(d as u32))
+                .ok_or_else(|| {
+                    DataFusionError::Execution(format!(
+                        "Can't cast {:?} to {:?}: scale would be lost",
+                        array.data_type(),
+                        data_type
+                    ))
+                })?;
+            let values = values
+                .into_iter()
+                .map(|v| {
+                    v.map(|v| {
+                        v.checked_mul(multiplier).ok_or_else(|| {
+                            DataFusionError::Execution(format!(
+                                "Can't cast {} to {:?}: out of range",
+                                v, data_type
+                            ))
+                        })
+                    })
+                    .transpose()
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            int64_decimal_array(values, *scale)
+        }
+        (DataType::Int64Decimal(_), DataType::Float64) => {
+            let (values, scale) = scaled_values(array)?;
+            let divisor = 10f64.powi(scale as i32);
+            Ok(Arc::new(Float64Array::from(
+                values
+                    .into_iter()
+                    .map(|v| v.map(|v| v as f64 / divisor))
+                    .collect::<Vec<_>>(),
+            )))
+        }
+        _ => Ok(cast(array, data_type)?),
+    }
+}
+
+macro_rules! pick_array {
+    ($ARGS:expr, $ARRAY_TYPE:ident) => {{
+        let (values, picks): (&[ArrayRef], &[Option<usize>]) = $ARGS;
+        let arrays = values
+            .iter()
+            .map(|a| a.as_any().downcast_ref::<$ARRAY_TYPE>().unwrap())
+            .collect::<Vec<_>>();
+        let result = picks
+            .iter()
+            .enumerate()
+            .map(|(row, pick)| {
+                pick.map(|i| arrays[i])
+                    .filter(|a| !a.is_null(row))
+                    .map(|a| a.value(row))
+            })
+            .collect::<Vec<_>>();
+        Arc::new($ARRAY_TYPE::from(result)) as ArrayRef
+    }};
+}
+
+macro_rules! null_if_picks {
+    ($ARGS:expr, $ARRAY_TYPE:ident) => {{
+        let value = $ARGS[0].as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
+        let other = $ARGS[1].as_any().downcast_ref::<$ARRAY_TYPE>().unwrap();
+        (0..value.len())
+            .map(|i| {
+                if !value.is_null(i) && !other.is_null(i) && value.value(i) == other.value(i) {
+                    None
+                } else {
+                    Some(0)
+                }
+            })
+            .collect::<Vec<_>>()
+    }};
+}
+
+macro_rules! dispatch_by_type {
+    ($MACRO:ident, $ARGS:expr, $DATA_TYPE:expr) => {{
+        Ok(match $DATA_TYPE {
+            DataType::Int64 => $MACRO!($ARGS, Int64Array),
+            DataType::Float64 => $MACRO!($ARGS, Float64Array),
+            DataType::Int64Decimal(0) => $MACRO!($ARGS, Int64Decimal0Array),
+            DataType::Int64Decimal(1) => $MACRO!($ARGS, Int64Decimal1Array),
+            DataType::Int64Decimal(2) => $MACRO!($ARGS, Int64Decimal2Array),
+            DataType::Int64Decimal(3) => $MACRO!($ARGS, Int64Decimal3Array),
+            DataType::Int64Decimal(4) => $MACRO!($ARGS, Int64Decimal4Array),
+            DataType::Int64Decimal(5) => $MACRO!($ARGS, Int64Decimal5Array),
+            DataType::Int64Decimal(10) => $MACRO!($ARGS, Int64Decimal10Array),
+            DataType::Utf8 => $MACRO!($ARGS, StringArray),
+            DataType::Boolean => $MACRO!($ARGS, BooleanArray),
+            DataType::Timestamp(TimeUnit::Second, _) => $MACRO!($ARGS, TimestampSecondArray),
+            DataType::Timestamp(TimeUnit::Millisecond, _) => {
+                $MACRO!($ARGS, TimestampMillisecondArray)
+            }
+            DataType::Timestamp(TimeUnit::Microsecond, _) => {
+                $MACRO!($ARGS, TimestampMicrosecondArray)
+            }
+            DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                $MACRO!($ARGS, TimestampNanosecondArray)
+            }
+            x => {
+                return Err(DataFusionError::Execution(format!(
+                    "Unsupported argument type: {:?}",
+                    x
+                )))
+            }
+        })
+    }};
+}
+
+/// Takes each row from the value picked for it, rows without a pick are null.
+fn pick(
+    values: &[ArrayRef],
+    picks: &[Option<usize>],
+    data_type: &DataType,
+) -> Result<ArrayRef, DataFusionError> {
+    dispatch_by_type!(pick_array, (values, picks), data_type)
+}
+
+fn coalesce(args: &[ArrayRef], data_type: &DataType) -> Result<ArrayRef, DataFusionError> {
+    let picks = (0..args[0].len())
+        .map(|row| args.iter().position(|a| !a.is_null(row)))
+        .collect::<Vec<_>>();
+    pick(args, &picks, data_type)
+}
+
+/// Rows of `value` except for the ones where `args` cast to `data_type` are equal.
+fn null_if(
+    value: &ArrayRef,
+    args: &[ArrayRef],
+    data_type: &DataType,
+) -> Result<ArrayRef, DataFusionError> {
+    let picks: Result<Vec<Option<usize>>, DataFusionError> =
+        dispatch_by_type!(null_if_picks, args, data_type);
+    pick(&[value.clone()], &picks?, value.data_type())
+}
+
+/// Most arguments `CASE_WHEN` accepts, longer `CASE` expressions are planned by DataFusion.
+pub const CASE_WHEN_MAX_ARGS: usize = 64;
+
+/// `CASE_WHEN(condition, value, ...[, else])`, see `sql_rewrite::rewrite_statement`. DataFusion
+/// requires all `CASE` branches to have the same type and can't build decimal or timestamp
+/// results, so branches are cast to a common type the same way as `COALESCE` arguments.
+fn case_when_udf() -> ScalarUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|types| {
+        Ok(Arc::new(common_type(
+            CubeScalarUDFKind::CaseWhen,
+            &case_values(types),
+        )?))
+    });
+    ScalarUDF::new(
+        CubeScalarUDFKind::CaseWhen.name(),
+        &Signature::OneOf((2..=CASE_WHEN_MAX_ARGS).map(Signature::Any).collect()),
+        &return_type,
+        &make_scalar_function(case_when),
+    )
+}
+
+/// Branch values among `CASE_WHEN` arguments: every second one and the trailing else branch.
+fn case_values<T: Clone>(args: &[T]) -> Vec<T> {
+    args.iter()
+        .enumerate()
+        .filter(|(i, _)| i % 2 == 1 || i + 1 == args.len())
+        .map(|(_, a)| a.clone())
+        .collect()
+}
+
+fn case_when(args: &[ArrayRef]) -> Result<ArrayRef, DataFusionError> {
+    let values = case_values(args);
+    let data_type = common_type(
+        CubeScalarUDFKind::CaseWhen,
+        &values
+            .iter()
+            .map(|a| a.data_type().clone())
+            .collect::<Vec<_>>(),
+    )?;
+    let values = values
+        .iter()
+        .map(|a| cast_to(a, &data_type))
+        .collect::<Result<Vec<_>, _>>()?;
+    let conditions = args
+        .chunks_exact(2)
+        .map(|c| {
+            c[0].as_any().downcast_ref::<BooleanArray>().ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "CASE conditions must be boolean, got {:?}",
+                    c[0].data_type()
+                ))
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let else_branch = if args.len() % 2 == 1 {
+        Some(conditions.len())
+    } else {
+        None
+    };
+    let picks = (0..args[0].len())
+        .map(|row| {
+            conditions
+                .iter()
+                .position(|c| !c.is_null(row) && c.value(row))
+                .or(else_branch)
+        })
+        .collect::<Vec<_>>();
+    pick(&values, &picks, &data_type)
+}
+
+/// `CAST(value AS type)` to types CubeStore stores, see `sql_rewrite::rewrite_statement`.
+/// Values that can't be converted become nulls unless the second argument is true, in which
+/// case they fail the query. Fractional digits that don't fit the target type are rounded half
+/// away from zero and dates are timestamps truncated to days.
+fn cast_udf(kind: CubeScalarUDFKind, data_type: DataType) -> ScalarUDF {
+    let return_type: ReturnTypeFunction = {
+        let data_type = data_type.clone();
+        Arc::new(move |_| Ok(Arc::new(data_type.clone())))
+    };
+    ScalarUDF::new(
+        kind.name(),
+        &Signature::Any(2),
+        &return_type,
+        &make_scalar_function(move |args: &[ArrayRef]| {
+            let strict = args[1]
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .map_or(false, |a| a.len() > 0 && !a.is_null(0) && a.value(0));
+            let result = cast_value(&args[0], &data_type)?;
+            if strict {
+                check_strict_cast(&args[0], &result)?;
+            }
+            match kind {
+                CubeScalarUDFKind::CastToDate => truncate_to_days(&result),
+                _ => Ok(result),
+            }
+        }),
+    )
+}
+
+fn cast_value(array: &ArrayRef, data_type: &DataType) -> Result<ArrayRef, DataFusionError> {
+    if array.data_type() == data_type {
+        return Ok(array.clone());
+    }
+    Ok(match (array.data_type(), data_type) {
+        (DataType::Utf8, DataType::Int64) => {
+            Arc::new(Int64Array::from(parse_strings(array, |s| {
+                parse_decimal(s, 0)
+            })))
+        }
+        (DataType::Utf8, DataType::Float64) => {
+            Arc::new(Float64Array::from(parse_strings(array, |s| {
+                s.parse::<f64>().ok()
+            })))
+        }
+        (DataType::Utf8, DataType::Int64Decimal(scale)) => {
+            int64_decimal_array(parse_strings(array, |s| parse_decimal(s, *scale)), *scale)?
+        }
+        (DataType::Utf8, DataType::Timestamp(TimeUnit::Nanosecond, None)) => Arc::new(
+            TimestampNanosecondArray::from(parse_strings(array, parse_timestamp)),
+        ),
+        (from, DataType::Int64) if is_float(from) => {
+            Arc::new(Int64Array::from(float_to_scaled(array, 0)?))
+        }
+        (from, DataType::Int64Decimal(scale)) if is_float(from) => {
+            int64_decimal_array(float_to_scaled(array, *scale)?, *scale)?
+        }
+        (DataType::Int64Decimal(_), DataType::Float64) => cast_to(array, data_type)?,
+        (DataType::Int64Decimal(_), DataType::Int64) => {
+            let (values, scale) = scaled_values(array)?;
+            Arc::new(Int64Array::from(rescale(values, scale, 0)))
+        }
+        (from, DataType::Int64Decimal(scale)) if is_numeric(from) => {
+            let (values, from_scale) = scaled_values(array)?;
+            int64_decimal_array(rescale(values, from_scale, *scale), *scale)?
+        }
+        (DataType::Int64Decimal(_), DataType::Utf8) => {
+            let (values, scale) = scaled_values(array)?;
+            let strings = values
+                .into_iter()
+                .map(|v| v.map(|v| BigDecimal::new(BigInt::from(v), scale as i64).to_string()))
+                .collect::<Vec<_>>();
+            Arc::new(StringArray::from(
+                strings.iter().map(|s| s.as_deref()).collect::<Vec<_>>(),
+            ))
+        }
+        (DataType::Timestamp(_, _), DataType::Utf8) => {
+            let strings = date_times(array)?
+                .into_iter()
+                .map(|d| d.map(|d| d.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string()))
+                .collect::<Vec<_>>();
+            Arc::new(StringArray::from(
+                strings.iter().map(|s| s.as_deref()).collect::<Vec<_>>(),
+            ))
+        }
+        _ => cast(array, data_type)?,
+    })
+}
+
+/// Fails on the first value a cast turned into null.
+fn check_strict_cast(array: &ArrayRef, result: &ArrayRef) -> Result<(), DataFusionError> {
+    match (0..array.len()).find(|i| !array.is_null(*i) && result.is_null(*i)) {
+        Some(i) => Err(DataFusionError::Execution(format!(
+            "Can't cast '{}' to {:?}",
+            array_value_to_string(array, i).unwrap_or_else(|_| format!("row {}", i)),
+            result.data_type()
+        ))),
+        None => Ok(()),
+    }
+}
+
+fn parse_strings<T>(array: &ArrayRef, parse: impl Fn(&str) -> Option<T>) -> Vec<Option<T>> {
+    let strings = array.as_any().downcast_ref::<StringArray>().unwrap();
+    (0..strings.len())
+        .map(|i| {
+            if strings.is_null(i) {
+                None
+            } else {
+                parse(strings.value(i).trim())
+            }
+        })
+        .collect()
+}
+
+/// Unscaled value of a decimal string. One extra digit is kept so it can be rounded.
+fn parse_decimal(s: &str, scale: usize) -> Option<i64> {
+    let (value, _) = BigDecimal::from_str(s)
+        .ok()?
+        .with_scale(scale as i64 + 1)
+        .as_bigint_and_exponent();
+    rescale(vec![Some(value.to_i64()?)], scale + 1, scale)[0]
+}
+
+fn parse_timestamp(s: &str) -> Option<i64> {
+    string_to_timestamp_nanos(s).ok().or_else(|| {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()
+            .map(|d| d.and_hms(0, 0, 0).timestamp_nanos())
+    })
+}
+
+/// Unscaled values of floats rounded to `scale`, ones out of `i64` range become nulls.
+fn float_to_scaled(array: &ArrayRef, scale: usize) -> Result<Vec<Option<i64>>, DataFusionError> {
+    let floats = cast(array, &DataType::Float64)?;
+    let multiplier = 10f64.powi(scale as i32);
+    Ok(primitive_values!(floats, Float64Array)
+        .into_iter()
+        .map(|v| {
+            let scaled = (v? * multiplier).round();
+            if scaled.is_finite() && scaled >= i64::MIN as f64 && scaled < i64::MAX as f64 {
+                Some(scaled as i64)
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+/// Rescales unscaled decimal values rounding half away from zero, ones that don't fit become
+/// nulls.
+fn rescale(values: Vec<Option<i64>>, from_scale: usize, to_scale: usize) -> Vec<Option<i64>> {
+    values
+        .into_iter()
+        .map(|v| {
+            let v = v?;
+            if to_scale >= from_scale {
+                v.checked_mul(10i64.checked_pow((to_scale - from_scale) as u32)?)
+            } else {
+                let divisor = 10i64.checked_pow((from_scale - to_scale) as u32)?;
+                let (quotient, remainder) = (v / divisor, v % divisor);
+                Some(if remainder.abs() * 2 >= divisor {
+                    quotient + v.signum()
+                } else {
+                    quotient
+                })
+            }
+        })
+        .collect()
+}
+
+fn truncate_to_days(array: &ArrayRef) -> Result<ArrayRef, DataFusionError> {
+    const NANOS_IN_DAY: i64 = 86_400_000_000_000;
+    Ok(Arc::new(TimestampNanosecondArray::from(
+        primitive_values!(array, TimestampNanosecondArray)
+            .into_iter()
+            .map(|v| v.map(|v| v - v.rem_euclid(NANOS_IN_DAY)))
+            .collect::<Vec<_>>(),
+    )))
+}
+
+/// `APPROX_DISTINCT(value)` estimates the number of distinct non-null values with a
+/// HyperLogLog sketch. Workers ship sketches of their partitions as partial aggregate states and
+/// the router merges them, so memory doesn't grow with the number of distinct values.
+fn approx_distinct_udaf() -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Int64)));
+    let accumulator: AccumulatorFunctionImplementation =
+        Arc::new(|| Ok(Box::new(ApproxDistinctAccumulator::new())));
+    // Partial states have to be scalars arrow can build arrays of, so sketches are hex strings
+    let state_type: StateTypeFunction = Arc::new(|_| Ok(Arc::new(vec![DataType::Utf8])));
+    AggregateUDF::new(
+        CubeAggregateUDFKind::ApproxDistinct.name(),
+        &Signature::Any(1),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+#[derive(Debug)]
+struct ApproxDistinctAccumulator {
+    sketch: HyperLogLog,
+}
+
+impl ApproxDistinctAccumulator {
+    fn new() -> ApproxDistinctAccumulator {
+        ApproxDistinctAccumulator {
+            sketch: HyperLogLog::new(),
+        }
+    }
+}
+
+impl Accumulator for ApproxDistinctAccumulator {
+    fn state(&self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        Ok(vec![ScalarValue::Utf8(Some(self.sketch.to_hex()))])
+    }
+
+    fn update(&mut self, values: &Vec<ScalarValue>) -> Result<(), DataFusionError> {
+        self.update_batch(&vec![values[0].to_array()])
+    }
+
+    fn update_batch(&mut self, values: &Vec<ArrayRef>) -> Result<(), DataFusionError> {
+        for hash in value_hashes(&values[0])? {
+            if let Some(hash) = hash {
+                self.sketch.add_hash(hash);
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, states: &Vec<ScalarValue>) -> Result<(), DataFusionError> {
+        match &states[0] {
+            ScalarValue::Utf8(Some(hex)) => {
+                self.sketch.merge(&HyperLogLog::from_hex(hex)?);
+                Ok(())
+            }
+            ScalarValue::Utf8(None) => Ok(()),
+            x => Err(DataFusionError::Internal(format!(
+                "Unexpected approx_distinct state: {:?}",
+                x
+            ))),
+        }
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        Ok(ScalarValue::Int64(Some(self.sketch.count() as i64)))
+    }
+}
+
+/// `SUM` of `Int64` or `Int64Decimal` values of `data_type` accumulated in i128 so the result
+/// doesn't depend on the order of values and partial sums, e.g. `MAX + 1 - 2` gives `MAX - 1`.
+/// Partial sums are shipped as decimal strings as they may not fit into i64 on their own. A total
+/// which doesn't fit into the i64 result fails the query instead of wrapping around as the
+/// built-in one does. It's named after the built-in so output columns keep their names.
+pub fn checked_sum_udaf(data_type: DataType) -> AggregateUDF {
+    let output_type = Arc::new(data_type.clone());
+    let return_type: ReturnTypeFunction = Arc::new(move |_| Ok(output_type.clone()));
+    let state_type: StateTypeFunction = Arc::new(|_| Ok(Arc::new(vec![DataType::Utf8])));
+    let sum_type = data_type.clone();
+    let accumulator: AccumulatorFunctionImplementation = Arc::new(move || {
+        Ok(Box::new(CheckedSumAccumulator {
+            data_type: sum_type.clone(),
+            sum: None,
+        }))
+    });
+    AggregateUDF::new(
+        "SUM",
+        &Signature::Exact(vec![data_type]),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+#[derive(Debug)]
+struct CheckedSumAccumulator {
+    data_type: DataType,
+    sum: Option<i128>,
+}
+
+impl CheckedSumAccumulator {
+    fn add(&mut self, value: i128) {
+        self.sum = Some(self.sum.unwrap_or(0) + value);
+    }
+
+    fn value(&self) -> Result<ScalarValue, DataFusionError> {
+        let sum = match self.sum {
+            Some(sum) => Some(i64::try_from(sum).map_err(|_| {
+                DataFusionError::Execution(format!(
+                    "SUM of {:?} values is out of range: {} doesn't fit into 64 bits",
+                    self.data_type, sum
+                ))
+            })?),
+            None => None,
+        };
+        let array: ArrayRef = match &self.data_type {
+            DataType::Int64Decimal(scale) => int64_decimal_array(vec![sum], *scale)?,
+            _ => Arc::new(Int64Array::from(vec![sum])),
+        };
+        ScalarValue::try_from_array(&array, 0)
+    }
+}
+
+impl Accumulator for CheckedSumAccumulator {
+    fn state(&self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        Ok(vec![ScalarValue::Utf8(self.sum.map(|s| s.to_string()))])
+    }
+
+    fn update(&mut self, values: &Vec<ScalarValue>) -> Result<(), DataFusionError> {
+        self.update_batch(&vec![values[0].to_array()])
+    }
+
+    fn update_batch(&mut self, values: &Vec<ArrayRef>) -> Result<(), DataFusionError> {
+        for value in scaled_values(&values[0])?.0.into_iter().flatten() {
+            self.add(value as i128);
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, states: &Vec<ScalarValue>) -> Result<(), DataFusionError> {
+        match &states[0] {
+            ScalarValue::Utf8(Some(sum)) => {
+                self.add(sum.parse::<i128>().map_err(|e| {
+                    DataFusionError::Internal(format!("Unexpected SUM state {}: {}", sum, e))
+                })?);
+                Ok(())
+            }
+            ScalarValue::Utf8(None) => Ok(()),
+            x => Err(DataFusionError::Internal(format!(
+                "Unexpected SUM state: {:?}",
+                x
+            ))),
+        }
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        self.value()
+    }
+}
+
+/// Hashes of non-null values. A value gets the same hash on every node and in every release so
+/// sketches can be merged across them.
+fn value_hashes(array: &ArrayRef) -> Result<Vec<Option<u64>>, DataFusionError> {
+    let hash = |write: &dyn Fn(&mut StableHasher)| {
+        let mut hasher = StableHasher::new();
+        write(&mut hasher);
+        hasher.finish()
+    };
+    Ok(match array.data_type() {
+        DataType::Utf8 => {
+            let strings = array.as_any().downcast_ref::<StringArray>().unwrap();
+            (0..strings.len())
+                .map(|i| {
+                    if strings.is_null(i) {
+                        None
+                    } else {
+                        Some(hash(&|h| h.write(strings.value(i).as_bytes())))
+                    }
+                })
+                .collect()
+        }
+        t if is_float(t) => {
+            let floats = cast(array, &DataType::Float64)?;
+            primitive_values!(floats, Float64Array)
+                .into_iter()
+                .map(|v| v.map(|v| hash(&|h| h.write_u64(v.to_bits()))))
+                .collect()
+        }
+        _ => scaled_values(array)?
+            .0
+            .into_iter()
+            .map(|v| v.map(|v| hash(&|h| h.write_i64(v))))
+            .collect(),
+    })
+}
+
+/// `COUNT(DISTINCT value)` is rewritten into `count_distinct(value, memory_limit)` to count
+/// exactly in two phases: workers deduplicate values of their partitions and ship them as
+/// partial aggregate states, the router deduplicates the union. Unlike `approx_distinct` memory
+/// grows with the number of values so a group fails once its values take more than
+/// `memory_limit` bytes, `0` meaning no limit.
+fn count_distinct_udaf() -> AggregateUDF {
+    let return_type: ReturnTypeFunction = Arc::new(|_| Ok(Arc::new(DataType::Int64)));
+    let accumulator: AccumulatorFunctionImplementation =
+        Arc::new(|| Ok(Box::new(CountDistinctAccumulator::new())));
+    // Values are shipped as a single string and the limit goes along so the router applies it
+    let state_type: StateTypeFunction =
+        Arc::new(|_| Ok(Arc::new(vec![DataType::Utf8, DataType::Int64])));
+    AggregateUDF::new(
+        CubeAggregateUDFKind::CountDistinct.name(),
+        &Signature::Any(2),
+        &return_type,
+        &accumulator,
+        &state_type,
+    )
+}
+
+/// Rough hash set memory taken by a value in addition to its bytes.
+const DISTINCT_VALUE_OVERHEAD: usize = 32;
+
+#[derive(Debug)]
+struct CountDistinctAccumulator {
+    values: HashSet<String>,
+    memory_used: usize,
+    memory_limit: usize,
+}
+
+impl CountDistinctAccumulator {
+    fn new() -> CountDistinctAccumulator {
+        CountDistinctAccumulator {
+            values: HashSet::new(),
+            memory_used: 0,
+            memory_limit: 0,
+        }
+    }
+
+    fn add(&mut self, value: &str) -> Result<(), DataFusionError> {
+        if self.values.contains(value) {
+            return Ok(());
+        }
+        self.memory_used += value.len() + DISTINCT_VALUE_OVERHEAD;
+        if self.memory_limit != 0 && self.memory_used > self.memory_limit {
+            return Err(DataFusionError::Execution(format!(
+                "COUNT(DISTINCT) values take more than {} bytes of memory. Please use approx_distinct() to estimate the count instead.",
+                self.memory_limit
+            )));
+        }
+        self.values.insert(value.to_string());
+        Ok(())
+    }
+
+    fn set_memory_limit(&mut self, limit: Option<i64>) {
+        if let Some(limit) = limit {
+            self.memory_limit = limit.max(0) as usize;
+        }
+    }
+}
+
+impl Accumulator for CountDistinctAccumulator {
+    /// Values are encoded as `<byte length>:<value>` one after another.
+    fn state(&self) -> Result<Vec<ScalarValue>, DataFusionError> {
+        let mut encoded = String::with_capacity(self.memory_used);
+        for value in self.values.iter() {
+            write!(encoded, "{}:{}", value.len(), value).unwrap();
+        }
+        Ok(vec![
+            ScalarValue::Utf8(Some(encoded)),
+            ScalarValue::Int64(Some(self.memory_limit as i64)),
+        ])
+    }
+
+    fn update(&mut self, values: &Vec<ScalarValue>) -> Result<(), DataFusionError> {
+        self.update_batch(&values.iter().map(|v| v.to_array()).collect())
+    }
+
+    fn update_batch(&mut self, values: &Vec<ArrayRef>) -> Result<(), DataFusionError> {
+        let limits = cast(&values[1], &DataType::Int64)?;
+        self.set_memory_limit(
+            primitive_values!(limits, Int64Array)
+                .into_iter()
+                .flatten()
+                .next(),
+        );
+        for key in distinct_keys(&values[0])? {
+            if let Some(key) = key {
+                self.add(&key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn merge(&mut self, states: &Vec<ScalarValue>) -> Result<(), DataFusionError> {
+        match &states[1] {
+            ScalarValue::Int64(limit) => self.set_memory_limit(*limit),
+            x => {
+                return Err(DataFusionError::Internal(format!(
+                    "Unexpected count_distinct limit state: {:?}",
+                    x
+                )))
+            }
+        }
+        match &states[0] {
+            ScalarValue::Utf8(Some(encoded)) => {
+                let mut rest = encoded.as_str();
+                while !rest.is_empty() {
+                    let invalid = || {
+                        DataFusionError::Internal(format!(
+                            "Invalid count_distinct state: {}",
+                            encoded
+                        ))
+                    };
+                    let (len, tail) = rest.split_at(rest.find(':').ok_or_else(invalid)?);
+                    let len = len.parse::<usize>().map_err(|_| invalid())?;
+                    let value = tail.get(1..len + 1).ok_or_else(invalid)?;
+                    self.add(value)?;
+                    rest = &tail[len + 1..];
+                }
+                Ok(())
+            }
+            ScalarValue::Utf8(None) => Ok(()),
+            x => Err(DataFusionError::Internal(format!(
+                "Unexpected count_distinct state: {:?}",
+                x
+            ))),
+        }
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue, DataFusionError> {
+        Ok(ScalarValue::Int64(Some(self.values.len() as i64)))
+    }
+}
+
+/// Non-null values as strings equal only for equal values of the array type.
+fn distinct_keys(array: &ArrayRef) -> Result<Vec<Option<String>>, DataFusionError> {
+    Ok(match array.data_type() {
+        DataType::Utf8 => {
+            let strings = array.as_any().downcast_ref::<StringArray>().unwrap();
+            (0..strings.len())
+                .map(|i| {
+                    if strings.is_null(i) {
+                        None
+                    } else {
+                        Some(strings.value(i).to_string())
+                    }
+                })
+                .collect()
+        }
+        t if is_float(t) => {
+            let floats = cast(array, &DataType::Float64)?;
+            primitive_values!(floats, Float64Array)
+                .into_iter()
+                .map(|v| v.map(|v| v.to_bits().to_string()))
+                .collect()
+        }
+        _ => scaled_values(array)?
+            .0
+            .into_iter()
+            .map(|v| v.map(|v| v.to_string()))
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tz_offsets() {
+        assert_eq!(parse_tz_offset("UTC").unwrap(), 0);
+        assert_eq!(parse_tz_offset("Z").unwrap(), 0);
+        assert_eq!(parse_tz_offset("+02:00").unwrap(), 7200);
+        assert_eq!(parse_tz_offset("-05:30").unwrap(), -19800);
+        assert!(parse_tz_offset("Europe/Berlin").is_err());
+        assert!(parse_tz_offset("+02:75").is_err());
+    }
+
+    #[test]
+    fn date_add_and_sub() {
+        // 2020-01-31T10:00:00Z
+        let micros = 1_580_464_800_000_000i64;
+        let args: Vec<ArrayRef> = vec![
+            Arc::new(TimestampMicrosecondArray::from(vec![
+                Some(micros),
+                Some(micros),
+                None,
+            ])),
+            Arc::new(StringArray::from(vec!["1 month", "2 days 1 hour", "1 day"])),
+        ];
+        let added = date_arithmetic(&args, false).unwrap();
+        let added = added
+            .as_any()
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .unwrap();
+        // 2020-02-29T10:00:00Z
+        assert_eq!(added.value(0), 1_582_970_400_000_000);
+        assert_eq!(added.value(1), micros + (2 * 86_400 + 3_600) * 1_000_000);
+        assert!(added.is_null(2));
+
+        let subtracted = date_arithmetic(&args, true).unwrap();
+        let subtracted = subtracted
+            .as_any()
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .unwrap();
+        // 2019-12-31T10:00:00Z
+        assert_eq!(subtracted.value(0), 1_577_786_400_000_000);
+        assert_eq!(
+            subtracted.value(1),
+            micros - (2 * 86_400 + 3_600) * 1_000_000
+        );
+    }
+
+    #[test]
+    fn date_parts() {
+        // 2020-01-01T23:59:59.999Z
+        let micros = 1_577_923_199_999_000i64;
+        let part = |part: &str| {
+            let args: Vec<ArrayRef> = vec![
+                Arc::new(StringArray::from(vec![part, part])),
+                Arc::new(TimestampMicrosecondArray::from(vec![Some(micros), None])),
+            ];
+            let result = date_part(&args).unwrap();
+            let result = result.as_any().downcast_ref::<Int64Array>().unwrap();
+            assert!(result.is_null(1));
+            result.value(0)
+        };
+        assert_eq!(part("year"), 2020);
+        assert_eq!(part("quarter"), 1);
+        assert_eq!(part("month"), 1);
+        assert_eq!(part("week"), 1);
+        assert_eq!(part("day"), 1);
+        assert_eq!(part("dow"), 3);
+        assert_eq!(part("doy"), 1);
+        assert_eq!(part("HOUR"), 23);
+        assert_eq!(part("minute"), 59);
+        assert_eq!(part("second"), 59);
+    }
+
+    #[test]
+    fn to_timestamp_from_unix_time() {
+        let args: Vec<ArrayRef> = vec![Arc::new(Int64Array::from(vec![Some(1_577_836_800), None]))];
+        let result = to_timestamp(&args, 1_000_000_000).unwrap();
+        let result = result
+            .as_any()
+            .downcast_ref::<TimestampNanosecondArray>()
+            .unwrap();
+        assert_eq!(result.value(0), 1_577_836_800_000_000_000);
+        assert!(result.is_null(1));
+
+        let overflow: Vec<ArrayRef> = vec![Arc::new(Int64Array::from(vec![i64::MAX]))];
+        assert!(to_timestamp(&overflow, 1_000_000).is_err());
+    }
+
+    #[test]
+    fn convert_tz_shifts_timestamps() {
+        let utc = 1_577_836_800_000_000i64;
+        let args: Vec<ArrayRef> = vec![
+            Arc::new(TimestampMicrosecondArray::from(vec![Some(utc), None])),
+            Arc::new(StringArray::from(vec!["+00:00", "+00:00"])),
+            Arc::new(StringArray::from(vec!["+02:00", "+02:00"])),
+        ];
+        let result = convert_tz(&args).unwrap();
+        let result = result
+            .as_any()
+            .downcast_ref::<TimestampMicrosecondArray>()
+            .unwrap();
+        assert_eq!(result.value(0), utc + 7200 * 1_000_000);
+        assert!(result.is_null(1));
+    }
+
+    fn strings(values: Vec<Option<&str>>) -> Vec<Option<String>> {
+        values
+            .into_iter()
+            .map(|s| s.map(|s| s.to_string()))
+            .collect()
+    }
+
+    fn string_values(array: ArrayRef) -> Vec<Option<String>> {
+        let array = array.as_any().downcast_ref::<StringArray>().unwrap();
+        (0..array.len())
+            .map(|i| {
+                if array.is_null(i) {
+                    None
+                } else {
+                    Some(array.value(i).to_string())
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn substr_handles_negative_start() {
+        let args: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec![
+                Some("Sakila"),
+                Some("Sakila"),
+                Some("Sakila"),
+                Some("Sakila"),
+                Some("Zürich"),
+                None,
+            ])),
+            Arc::new(Int64Array::from(vec![2, -3, 0, -10, 2, 1])),
+        ];
+        assert_eq!(
+            string_values(substr(&args).unwrap()),
+            strings(vec![
+                Some("akila"),
+                Some("ila"),
+                Some(""),
+                Some(""),
+                Some("ürich"),
+                None
+            ])
+        );
+
+        let args: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["Sakila", "Sakila", "Sakila"])),
+            Arc::new(Int64Array::from(vec![2, -5, 1])),
+            Arc::new(Int64Array::from(vec![Some(3), Some(3), None])),
+        ];
+        assert_eq!(
+            string_values(substr(&args).unwrap()),
+            strings(vec![Some("aki"), Some("aki"), None])
+        );
+    }
+
+    #[test]
+    fn btrim_trims_both_ends() {
+        let args: Vec<ArrayRef> = vec![Arc::new(StringArray::from(vec![
+            Some("  a b  "),
+            Some("a"),
+            None,
+        ]))];
+        assert_eq!(
+            string_values(btrim(&args).unwrap()),
+            strings(vec![Some("a b"), Some("a"), None])
+        );
+
+        let args: Vec<ArrayRef> = vec![
+            Arc::new(StringArray::from(vec!["xyaxy", "xyaxy"])),
+            Arc::new(StringArray::from(vec![Some("xy"), None])),
+        ];
+        assert_eq!(
+            string_values(btrim(&args).unwrap()),
+            strings(vec![Some("a"), None])
+        );
+    }
+
+    #[test]
+    fn char_length_counts_characters() {
+        let args: Vec<ArrayRef> = vec![Arc::new(StringArray::from(vec![
+            Some("Zürich"),
+            Some(""),
+            None,
+        ]))];
+        let result = char_length(&args).unwrap();
+        let result = result.as_any().downcast_ref::<Int64Array>().unwrap();
+        assert_eq!(result.value(0), 6);
+        assert_eq!(result.value(1), 0);
+        assert!(result.is_null(2));
+    }
+
+    fn call(kind: CubeScalarUDFKind, args: Vec<ArrayRef>) -> ArrayRef {
+        let data_type = common_type(
+            kind,
+            &args
+                .iter()
+                .map(|a| a.data_type().clone())
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+        let cast_args = args
+            .iter()
+            .map(|a| cast_to(a, &data_type).unwrap())
+            .collect::<Vec<_>>();
+        match kind {
+            CubeScalarUDFKind::NullIf => null_if(&args[0], &cast_args, &data_type).unwrap(),
+            _ => coalesce(&cast_args, &data_type).unwrap(),
+        }
+    }
+
+    #[test]
+    fn coalesce_unifies_decimal_scales() {
+        let result = call(
+            CubeScalarUDFKind::Coalesce,
+            vec![
+                Arc::new(Int64Decimal2Array::from(vec![Some(150), None, None])),
+                Arc::new(Int64Decimal5Array::from(vec![Some(1), Some(2), None])),
+                Arc::new(Int64Array::from(vec![7, 7, 7])),
+            ],
+        );
+        assert_eq!(result.data_type(), &DataType::Int64Decimal(5));
+        assert_eq!(
+            primitive_values!(result, Int64Decimal5Array),
+            vec![Some(150_000), Some(2), Some(700_000)]
+        );
+
+        let result = call(
+            CubeScalarUDFKind::IfNull,
+            vec![
+                Arc::new(StringArray::from(vec![Some("a"), None])),
+                Arc::new(StringArray::from(vec!["b", "b"])),
+            ],
+        );
+        let result = result.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(result.value(0), "a");
+        assert_eq!(result.value(1), "b");
+
+        assert!(common_type(
+            CubeScalarUDFKind::Coalesce,
+            &[DataType::Int64, DataType::Utf8]
+        )
+        .is_err());
+        assert_eq!(
+            common_type(
+                CubeScalarUDFKind::Coalesce,
+                &[DataType::Int64Decimal(2), DataType::Float64]
+            )
+            .unwrap(),
+            DataType::Float64
+        );
+    }
+
+    #[test]
+    fn case_when_casts_branches() {
+        let conditions: ArrayRef = Arc::new(BooleanArray::from(vec![
+            Some(true),
+            Some(false),
+            None,
+            Some(false),
+        ]));
+        let other_conditions: ArrayRef = Arc::new(BooleanArray::from(vec![
+            Some(true),
+            Some(true),
+            Some(false),
+            Some(false),
+        ]));
+        let result = case_when(&[
+            conditions.clone(),
+            Arc::new(Int64Decimal1Array::from(vec![
+                Some(15),
+                Some(15),
+                None,
+                None,
+            ])),
+            other_conditions.clone(),
+            Arc::new(Int64Decimal3Array::from(vec![
+                Some(1),
+                Some(2),
+                Some(3),
+                Some(4),
+            ])),
+            Arc::new(Int64Array::from(vec![7, 7, 7, 7])),
+        ])
+        .unwrap();
+        assert_eq!(result.data_type(), &DataType::Int64Decimal(3));
+        assert_eq!(
+            primitive_values!(result, Int64Decimal3Array),
+            vec![Some(1_500), Some(2), Some(7_000), Some(7_000)]
+        );
+
+        let result = case_when(&[
+            conditions,
+            Arc::new(TimestampSecondArray::from(vec![1, 1, 1, 1])),
+            other_conditions,
+            Arc::new(TimestampNanosecondArray::from(vec![5, 6, 7, 8])),
+        ])
+        .unwrap();
+        assert_eq!(
+            result.data_type(),
+            &DataType::Timestamp(TimeUnit::Nanosecond, None)
+        );
+        assert_eq!(
+            primitive_values!(result, TimestampNanosecondArray),
+            vec![Some(1_000_000_000), Some(6), None, None]
+        );
+
+        assert_eq!(
+            case_values(&[
+                DataType::Boolean,
+                DataType::Int64,
+                DataType::Boolean,
+                DataType::UInt64
+            ]),
+            vec![DataType::Int64, DataType::UInt64]
+        );
+        assert_eq!(
+            common_type(
+                CubeScalarUDFKind::CaseWhen,
+                &[DataType::UInt64, DataType::UInt64]
+            )
+            .unwrap(),
+            DataType::Int64
+        );
+    }
+
+    #[test]
+    fn casts_between_decimals_and_floats() {
+        let decimals: ArrayRef =
+            Arc::new(Int64Decimal2Array::from(vec![Some(115), Some(-125), None]));
+        let floats = cast_value(&decimals, &DataType::Float64).unwrap();
+        assert_eq!(
+            primitive_values!(floats, Float64Array),
+            vec![Some(1.15), Some(-1.25), None]
+        );
+        // 1.15 isn't exact as a float but rounds back to the original value
+        let decimals_again = cast_value(&floats, &DataType::Int64Decimal(2)).unwrap();
+        assert_eq!(
+            primitive_values!(decimals_again, Int64Decimal2Array),
+            vec![Some(115), Some(-125), None]
+        );
+
+        let rounded = cast_value(&decimals, &DataType::Int64Decimal(1)).unwrap();
+        assert_eq!(
+            primitive_values!(rounded, Int64Decimal1Array),
+            vec![Some(12), Some(-13), None]
+        );
+        let ints = cast_value(&decimals, &DataType::Int64).unwrap();
+        assert_eq!(
+            primitive_values!(ints, Int64Array),
+            vec![Some(1), Some(-1), None]
+        );
+        let widened = cast_value(&ints, &DataType::Int64Decimal(5)).unwrap();
+        assert_eq!(
+            primitive_values!(widened, Int64Decimal5Array),
+            vec![Some(100_000), Some(-100_000), None]
+        );
+    }
+
+    #[test]
+    fn casts_strings() {
+        let texts: ArrayRef = Arc::new(StringArray::from(vec![
+            Some(" 42 "),
+            Some("1.25"),
+            Some("abc"),
+            None,
+        ]));
+        let ints = cast_value(&texts, &DataType::Int64).unwrap();
+        assert_eq!(
+            primitive_values!(ints, Int64Array),
+            vec![Some(42), Some(1), None, None]
+        );
+        let decimals = cast_value(&texts, &DataType::Int64Decimal(1)).unwrap();
+        assert_eq!(
+            primitive_values!(decimals, Int64Decimal1Array),
+            vec![Some(420), Some(13), None, None]
+        );
+        let floats = cast_value(&texts, &DataType::Float64).unwrap();
+        assert_eq!(
+            primitive_values!(floats, Float64Array),
+            vec![Some(42.0), Some(1.25), None, None]
+        );
+        let err = check_strict_cast(&texts, &ints).unwrap_err();
+        assert!(err.to_string().contains("'abc'"), "{}", err);
+        assert!(check_strict_cast(&texts.slice(0, 2), &ints.slice(0, 2)).is_ok());
+
+        let texts: ArrayRef = Arc::new(StringArray::from(vec![
+            Some("2021-01-02T03:04:05.678Z"),
+            Some("2021-01-02"),
+            Some("yesterday"),
+        ]));
+        let timestamps =
+            cast_value(&texts, &DataType::Timestamp(TimeUnit::Nanosecond, None)).unwrap();
+        assert_eq!(
+            primitive_values!(timestamps, TimestampNanosecondArray),
+            vec![Some(1609556645678000000), Some(1609545600000000000), None]
+        );
+        let dates = truncate_to_days(&timestamps).unwrap();
+        assert_eq!(
+            primitive_values!(dates, TimestampNanosecondArray),
+            vec![Some(1609545600000000000), Some(1609545600000000000), None]
+        );
+        assert_eq!(
+            string_values(cast_value(&timestamps, &DataType::Utf8).unwrap()),
+            strings(vec![
+                Some("2021-01-02T03:04:05.678Z"),
+                Some("2021-01-02T00:00:00.000Z"),
+                None
+            ])
+        );
+    }
+
+    #[test]
+    fn approx_distinct_merges_partial_states() {
+        let values = |range: std::ops::Range<i64>| -> ArrayRef {
+            Arc::new(Int64Array::from(
+                range
+                    .map(|v| if v % 10 == 0 { None } else { Some(v % 500) })
+                    .collect::<Vec<_>>(),
+            ))
+        };
+        let mut whole = ApproxDistinctAccumulator::new();
+        whole.update_batch(&vec![values(0..2000)]).unwrap();
+
+        let mut merged = ApproxDistinctAccumulator::new();
+        for part in 0..4 {
+            let mut partial = ApproxDistinctAccumulator::new();
+            partial
+                .update_batch(&vec![values(part * 500..(part + 1) * 500)])
+                .unwrap();
+            merged.merge(&partial.state().unwrap()).unwrap();
+        }
+        merged.merge(&vec![ScalarValue::Utf8(None)]).unwrap();
+
+        assert_eq!(merged.evaluate().unwrap(), whole.evaluate().unwrap());
+        // Multiples of 10 are nulls
+        match whole.evaluate().unwrap() {
+            ScalarValue::Int64(Some(count)) => assert!((count - 450).abs() <= 9, "{}", count),
+            x => panic!("Unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn count_distinct_merges_partial_states() {
+        let values = |texts: Vec<Option<&str>>| -> Vec<ArrayRef> {
+            let limit = Int64Array::from(vec![Some(1000); texts.len()]);
+            vec![Arc::new(StringArray::from(texts)), Arc::new(limit)]
+        };
+        let mut merged = CountDistinctAccumulator::new();
+        for part in vec![
+            vec![Some("a"), Some("b:1"), None, Some("a")],
+            vec![Some("b:1"), Some(""), Some("12:c")],
+            vec![],
+        ] {
+            let mut partial = CountDistinctAccumulator::new();
+            partial.update_batch(&values(part)).unwrap();
+            merged.merge(&partial.state().unwrap()).unwrap();
+        }
+        assert_eq!(merged.evaluate().unwrap(), ScalarValue::Int64(Some(4)));
+        assert!(merged
+            .merge(&vec![
+                ScalarValue::Utf8(Some("5:abc".to_string())),
+                ScalarValue::Int64(Some(1000))
+            ])
+            .is_err());
+    }
+
+    #[test]
+    fn count_distinct_fails_over_memory_limit() {
+        let values: Vec<ArrayRef> = vec![
+            Arc::new(Int64Array::from((0..100).collect::<Vec<i64>>())),
+            Arc::new(Int64Array::from(vec![1000; 100])),
+        ];
+        let mut accumulator = CountDistinctAccumulator::new();
+        let err = accumulator.update_batch(&values).unwrap_err();
+        assert!(err.to_string().contains("approx_distinct"), "{}", err);
+
+        // Limit comes with partial states to the router
+        let mut partial = CountDistinctAccumulator::new();
+        partial
+            .update_batch(&vec![values[0].slice(0, 10), values[1].slice(0, 10)])
+            .unwrap();
+        let mut merged = CountDistinctAccumulator::new();
+        merged.merge(&partial.state().unwrap()).unwrap();
+        assert_eq!(merged.memory_limit, 1000);
+    }
+
+    #[test]
+    fn null_if_compares_cast_values() {
+        let result = call(
+            CubeScalarUDFKind::NullIf,
+            vec![
+                Arc::new(Int64Decimal1Array::from(vec![
+                    Some(10),
+                    Some(15),
+                    None,
+                    Some(20),
+                ])),
+                Arc::new(Int64Array::from(vec![Some(1), Some(1), Some(1), None])),
+            ],
+        );
+        assert_eq!(result.data_type(), &DataType::Int64Decimal(1));
+        assert_eq!(
+            primitive_values!(result, Int64Decimal1Array),
+            vec![None, Some(15), None, Some(20)]
+        );
+    }
+
+    #[test]
+    fn null_if_keeps_first_argument_type() {
+        let result = call(
+            CubeScalarUDFKind::NullIf,
+            vec![
+                Arc::new(Int64Array::from(vec![Some(1), Some(2), None, Some(3)])),
+                Arc::new(Int64Decimal1Array::from(vec![
+                    Some(10),
+                    Some(15),
+                    Some(5),
+                    None,
+                ])),
+            ],
+        );
+        assert_eq!(result.data_type(), &DataType::Int64);
+        assert_eq!(
+            primitive_values!(result, Int64Array),
+            vec![None, Some(2), None, Some(3)]
+        );
+
+        let udf = CubeScalarUDFKind::NullIf.udf();
+        assert_eq!(
+            (udf.return_type)(&[DataType::Int64, DataType::Float64]).unwrap(),
+            Arc::new(DataType::Int64)
+        );
+        assert!((udf.return_type)(&[DataType::Int64, DataType::Utf8]).is_err());
+    }
+}
@@ -0,0 +1,429 @@
+use crate::queryplanner::query_executor::batches_to_rows;
+use crate::table::Row;
+use crate::CubeError;
+use arrow::array::BooleanArray;
+use arrow::compute::kernels::filter::filter_record_batch;
+use arrow::datatypes::{Schema, SchemaRef};
+use arrow::error::{ArrowError, Result as ArrowResult};
+use arrow::record_batch::RecordBatch;
+use async_trait::async_trait;
+use datafusion::error::DataFusionError;
+use datafusion::logical_plan::DFSchemaRef;
+use datafusion::physical_plan::{ExecutionPlan, Partitioning, RecordBatchStream};
+use futures::{Stream, StreamExt};
+use std::any::Any;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Scan of a partition of a table with a unique key. `inputs` are files and chunks of the
+/// partition in the order they were written, each sorted by the key, and only the last written
+/// row of each key is kept. Inputs are merged by the key as they're read, so the output is
+/// sorted by the key too. Inputs are read with all columns and `projection` is applied after
+/// deduplication.
+#[derive(Debug)]
+pub struct LastRowByKeyExec {
+    inputs: Vec<Arc<dyn ExecutionPlan>>,
+    key_columns: Vec<usize>,
+    projection: Vec<usize>,
+    schema: DFSchemaRef,
+}
+
+impl LastRowByKeyExec {
+    pub fn new(
+        inputs: Vec<Arc<dyn ExecutionPlan>>,
+        key_columns: Vec<usize>,
+        projection: Vec<usize>,
+        schema: DFSchemaRef,
+    ) -> LastRowByKeyExec {
+        LastRowByKeyExec {
+            inputs,
+            key_columns,
+            projection,
+            schema,
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for LastRowByKeyExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> DFSchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        self.inputs.clone()
+    }
+
+    fn with_new_children(
+        &self,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>, DataFusionError> {
+        Ok(Arc::new(LastRowByKeyExec::new(
+            children,
+            self.key_columns.clone(),
+            self.projection.clone(),
+            self.schema.clone(),
+        )))
+    }
+
+    async fn execute(
+        &self,
+        partition: usize,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, DataFusionError> {
+        if partition != 0 {
+            return Err(DataFusionError::Internal(format!(
+                "LastRowByKeyExec invalid partition {}",
+                partition
+            )));
+        }
+        let mut inputs = Vec::new();
+        for input in self.inputs.iter() {
+            for p in 0..input.output_partitioning().partition_count() {
+                inputs.push(KeyedInput {
+                    stream: input.execute(p).await?,
+                    batch: None,
+                    keys: Vec::new(),
+                    position: 0,
+                    done: false,
+                });
+            }
+        }
+        Ok(Box::pin(LastRowByKeyStream {
+            inputs,
+            key_columns: self.key_columns.clone(),
+            projection: self.projection.clone(),
+            schema: self.schema.to_schema_ref(),
+            pending: None,
+        }))
+    }
+}
+
+struct KeyedInput {
+    stream: Pin<Box<dyn RecordBatchStream + Send>>,
+    /// Batch being read with rows left from `position` on.
+    batch: Option<RecordBatch>,
+    keys: Vec<Row>,
+    position: usize,
+    done: bool,
+}
+
+impl KeyedInput {
+    fn head(&self) -> Option<&Row> {
+        self.batch.as_ref().map(|_| &self.keys[self.position])
+    }
+
+    /// Position of the first row of the batch from `from` on with a key of at least `bound`.
+    fn first_not_less(&self, from: usize, bound: &Row) -> usize {
+        (from..self.keys.len())
+            .find(|i| self.keys[*i].values() >= bound.values())
+            .unwrap_or(self.keys.len())
+    }
+
+    fn advance(&mut self, position: usize) {
+        self.position = position;
+        if self.position == self.keys.len() {
+            self.batch = None;
+            self.keys = Vec::new();
+            self.position = 0;
+        }
+    }
+}
+
+/// Last row of a key read so far which ends the batch of its input. The next batch may have
+/// later rows of the same key.
+struct PendingRow {
+    batch: RecordBatch,
+    key: Row,
+    input: usize,
+}
+
+struct LastRowByKeyStream {
+    inputs: Vec<KeyedInput>,
+    key_columns: Vec<usize>,
+    projection: Vec<usize>,
+    schema: SchemaRef,
+    pending: Option<PendingRow>,
+}
+
+impl LastRowByKeyStream {
+    fn keys(&self, batch: &RecordBatch) -> Result<Vec<Row>, CubeError> {
+        let key_schema = Arc::new(Schema::new(
+            self.key_columns
+                .iter()
+                .map(|i| batch.schema().field(*i).clone())
+                .collect(),
+        ));
+        let key_batch = RecordBatch::try_new(
+            key_schema,
+            self.key_columns
+                .iter()
+                .map(|i| batch.column(*i).clone())
+                .collect(),
+        )?;
+        batches_to_rows(&[key_batch]).collect()
+    }
+
+    fn project(&self, batch: &RecordBatch) -> Result<RecordBatch, CubeError> {
+        Ok(RecordBatch::try_new(
+            self.schema.clone(),
+            self.projection
+                .iter()
+                .map(|i| batch.column(*i).clone())
+                .collect(),
+        )?)
+    }
+
+    /// Rows `from..to` of `batch` for which `keep` is true.
+    fn rows(
+        batch: &RecordBatch,
+        from: usize,
+        to: usize,
+        keep: Vec<bool>,
+    ) -> Result<RecordBatch, CubeError> {
+        let slice = RecordBatch::try_new(
+            batch.schema(),
+            batch
+                .columns()
+                .iter()
+                .map(|c| c.slice(from, to - from))
+                .collect(),
+        )?;
+        Ok(filter_record_batch(&slice, &BooleanArray::from(keep))?)
+    }
+
+    /// Next batch of rows once every input has a batch to read or is done. Returns `None` if
+    /// another input batch has to be read first.
+    fn merge(&mut self) -> Result<Option<RecordBatch>, CubeError> {
+        let min_key = self
+            .inputs
+            .iter()
+            .filter_map(|input| input.head())
+            .min_by(|a, b| a.values().cmp(b.values()))
+            .cloned();
+        let pending_is_last = match (&min_key, &self.pending) {
+            (_, None) => false,
+            (Some(key), Some(pending)) => key.values() > pending.key.values(),
+            (None, Some(_)) => true,
+        };
+        if pending_is_last {
+            // Nothing can replace the pending row anymore
+            let pending = self.pending.take().unwrap();
+            return Ok(Some(self.project(&pending.batch)?));
+        }
+        let key = min_key.ok_or_else(|| {
+            CubeError::internal("LastRowByKeyExec merge without rows".to_string())
+        })?;
+        let latest = self
+            .inputs
+            .iter()
+            .rposition(|input| input.head() == Some(&key))
+            .unwrap();
+        let pending_wins = match &self.pending {
+            Some(pending) => pending.input > latest,
+            None => false,
+        };
+        if !pending_wins {
+            self.pending = None;
+        }
+        // Older rows of the key are dropped. Their inputs are read further before the key is
+        // decided as the next batch may start with the same key.
+        let mut older_batch_ended = false;
+        for (i, input) in self.inputs.iter_mut().enumerate() {
+            if (i < latest || pending_wins && i == latest) && input.head() == Some(&key) {
+                let end = (input.position..input.keys.len())
+                    .find(|j| input.keys[*j] != key)
+                    .unwrap_or(input.keys.len());
+                input.advance(end);
+                older_batch_ended |= input.batch.is_none();
+            }
+        }
+        if pending_wins || older_batch_ended {
+            return Ok(None);
+        }
+
+        let bound = self
+            .inputs
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != latest)
+            .filter_map(|(_, input)| input.head())
+            .min_by(|a, b| a.values().cmp(b.values()))
+            .cloned();
+        let input = &mut self.inputs[latest];
+        let from = input.position;
+        let mut to = match &bound {
+            Some(bound) => input.first_not_less(from + 1, bound),
+            None => input.keys.len(),
+        };
+        if to == input.keys.len() {
+            // Rows of the last key of the batch may go on in the next one
+            let last_key_start = (from..to)
+                .rfind(|i| *i == from || input.keys[*i - 1] != input.keys[to - 1])
+                .unwrap();
+            if last_key_start == from {
+                let batch = input.batch.as_ref().unwrap();
+                self.pending = Some(PendingRow {
+                    batch: Self::rows(batch, to - 1, to, vec![true])?,
+                    key: input.keys[to - 1].clone(),
+                    input: latest,
+                });
+                input.advance(to);
+                return Ok(None);
+            }
+            to = last_key_start;
+        }
+        let keep = (from..to)
+            .map(|i| i + 1 == to || input.keys[i] != input.keys[i + 1])
+            .collect();
+        let batch = Self::rows(input.batch.as_ref().unwrap(), from, to, keep)?;
+        input.advance(to);
+        Ok(Some(self.project(&batch)?))
+    }
+}
+
+impl Stream for LastRowByKeyStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            for i in 0..self.inputs.len() {
+                while !self.inputs[i].done && self.inputs[i].batch.is_none() {
+                    match self.inputs[i].stream.poll_next_unpin(cx) {
+                        Poll::Ready(Some(Ok(batch))) => {
+                            if batch.num_rows() == 0 {
+                                continue;
+                            }
+                            let keys = match self.keys(&batch) {
+                                Ok(keys) => keys,
+                                Err(e) => {
+                                    return Poll::Ready(Some(Err(ArrowError::ExternalError(
+                                        Box::new(e),
+                                    ))))
+                                }
+                            };
+                            let input = &mut self.inputs[i];
+                            input.batch = Some(batch);
+                            input.keys = keys;
+                            input.position = 0;
+                        }
+                        Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                        Poll::Ready(None) => self.inputs[i].done = true,
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+            }
+            if self.pending.is_none() && self.inputs.iter().all(|input| input.done) {
+                return Poll::Ready(None);
+            }
+            match self.merge() {
+                Ok(Some(batch)) => return Poll::Ready(Some(Ok(batch))),
+                Ok(None) => continue,
+                Err(e) => return Poll::Ready(Some(Err(ArrowError::ExternalError(Box::new(e))))),
+            }
+        }
+    }
+}
+
+impl RecordBatchStream for LastRowByKeyStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::{Array, Int64Array};
+    use arrow::datatypes::{DataType, Field};
+    use datafusion::logical_plan::ToDFSchema;
+    use datafusion::physical_plan::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+
+    fn schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("id", DataType::Int64, false),
+            Field::new("value", DataType::Int64, false),
+        ]))
+    }
+
+    /// Input of batches of (id, value) pairs.
+    fn input(batches: Vec<Vec<(i64, i64)>>) -> Arc<dyn ExecutionPlan> {
+        let batches = batches
+            .into_iter()
+            .map(|rows| {
+                RecordBatch::try_new(
+                    schema(),
+                    vec![
+                        Arc::new(Int64Array::from(
+                            rows.iter().map(|r| r.0).collect::<Vec<_>>(),
+                        )),
+                        Arc::new(Int64Array::from(
+                            rows.iter().map(|r| r.1).collect::<Vec<_>>(),
+                        )),
+                    ],
+                )
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        Arc::new(MemoryExec::try_new(&vec![batches], schema(), None).unwrap())
+    }
+
+    async fn last_rows(inputs: Vec<Arc<dyn ExecutionPlan>>) -> Vec<(i64, i64)> {
+        let exec = LastRowByKeyExec::new(
+            inputs,
+            vec![0],
+            vec![0, 1],
+            schema().to_dfschema_ref().unwrap(),
+        );
+        let batches = collect(Arc::new(exec)).await.unwrap();
+        batches
+            .iter()
+            .flat_map(|b| {
+                let ids = b.column(0).as_any().downcast_ref::<Int64Array>().unwrap();
+                let values = b.column(1).as_any().downcast_ref::<Int64Array>().unwrap();
+                (0..b.num_rows())
+                    .map(|i| (ids.value(i), values.value(i)))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_last_written_row_of_key() {
+        let rows = last_rows(vec![
+            input(vec![vec![(1, 10), (2, 20), (2, 21), (4, 40)]]),
+            input(vec![vec![(1, 11), (3, 30)]]),
+        ])
+        .await;
+        assert_eq!(rows, vec![(1, 11), (2, 21), (3, 30), (4, 40)]);
+    }
+
+    #[tokio::test]
+    async fn keys_going_on_in_next_batch() {
+        let rows = last_rows(vec![
+            input(vec![
+                vec![(1, 10), (2, 20)],
+                vec![(2, 21), (3, 30)],
+                vec![(3, 31)],
+                vec![],
+                vec![(5, 50)],
+            ]),
+            input(vec![vec![(2, 22)], vec![(4, 40), (5, 51)], vec![(5, 52)]]),
+            input(vec![vec![(3, 32)], vec![(3, 33), (6, 60)]]),
+        ])
+        .await;
+        assert_eq!(
+            rows,
+            vec![(1, 10), (2, 22), (3, 33), (4, 40), (5, 52), (6, 60)]
+        );
+    }
+}
@@ -0,0 +1,101 @@
+use crate::queryplanner::row_group_scan::ParquetMetadataCache;
+use crate::CubeError;
+use futures::StreamExt;
+use log::{debug, warn};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Reads the footer of a parquet file.
+pub trait ParquetFooterReader: Send + Sync {
+    fn read_footer(&self, path: &str) -> Result<(), CubeError>;
+}
+
+impl ParquetFooterReader for ParquetMetadataCache {
+    fn read_footer(&self, path: &str) -> Result<(), CubeError> {
+        self.get(path)?;
+        Ok(())
+    }
+}
+
+/// Scans are planned by footers of the files they read one file at a time, so footers of all
+/// files are read concurrently beforehand into the `ParquetMetadataCache` the scans use.
+/// Failures are only logged as the scan reports them anyway.
+pub async fn warm_up_parquet_files(
+    paths: Vec<String>,
+    reader: Arc<dyn ParquetFooterReader>,
+    concurrency: usize,
+) {
+    if paths.is_empty() || concurrency == 0 {
+        return;
+    }
+    let start = SystemTime::now();
+    let files = paths.len();
+    let results = futures::stream::iter(paths)
+        .map(|path| {
+            let reader = reader.clone();
+            async move {
+                let result = tokio::task::spawn_blocking({
+                    let path = path.clone();
+                    move || reader.read_footer(&path)
+                })
+                .await
+                .map_err(CubeError::from)
+                .and_then(|r| r);
+                (path, result)
+            }
+        })
+        .buffer_unordered(concurrency)
+        .collect::<Vec<_>>()
+        .await;
+    for (path, result) in results {
+        if let Err(e) = result {
+            warn!("Can't warm up parquet file {}: {}", path, e);
+        }
+    }
+    debug!("Warmed up {} parquet files in {:?}", files, start.elapsed());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct CountingFooterReader {
+        open: AtomicUsize,
+        max_open: AtomicUsize,
+        read: Mutex<Vec<String>>,
+    }
+
+    impl ParquetFooterReader for CountingFooterReader {
+        fn read_footer(&self, path: &str) -> Result<(), CubeError> {
+            let open = self.open.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_open.fetch_max(open, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(50));
+            self.read.lock().unwrap().push(path.to_string());
+            self.open.fetch_sub(1, Ordering::SeqCst);
+            if path.ends_with("missing.parquet") {
+                return Err(CubeError::internal(format!("No such file: {}", path)));
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_files_concurrently() {
+        let reader = Arc::new(CountingFooterReader::default());
+        let mut paths = (0..7).map(|i| format!("{}.parquet", i)).collect::<Vec<_>>();
+        paths.push("missing.parquet".to_string());
+
+        warm_up_parquet_files(paths.clone(), reader.clone(), 4).await;
+
+        let mut read = reader.read.lock().unwrap().clone();
+        read.sort();
+        paths.sort();
+        assert_eq!(read, paths);
+        let max_open = reader.max_open.load(Ordering::SeqCst);
+        assert!(max_open > 1 && max_open <= 4, "{}", max_open);
+    }
+}
@@ -0,0 +1,546 @@
+use crate::store::DataFrame;
+use crate::table::{Row, TableValue};
+use crate::CubeError;
+use bigdecimal::BigDecimal;
+use sqlparser::ast::{
+    Expr, Function, Ident, OrderByExpr, Query, SelectItem, SetExpr, UnaryOperator, Value,
+};
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+/// Window functions of the outer select evaluated on the router over the query result.
+/// DataFusion can't plan `OVER` so window function calls are replaced in the query with their
+/// value arguments and window keys are selected as extra hidden columns. Windows are evaluated
+/// once the query including the worker aggregation is done, then `ORDER BY` and `LIMIT` of the
+/// query are applied as they can refer to window function results.
+#[derive(Debug)]
+pub struct WindowPlan {
+    windows: Vec<Window>,
+    order_by: Vec<SortKey>,
+    limit: Option<usize>,
+    /// Columns selected by the original query, the rest are hidden window keys.
+    visible_columns: usize,
+}
+
+#[derive(Debug)]
+struct Window {
+    /// Column holding the value argument, replaced with results of the function.
+    column: usize,
+    function: WindowFunction,
+    partition_by: Vec<usize>,
+    order_by: Vec<SortKey>,
+}
+
+#[derive(Debug)]
+enum WindowFunction {
+    RowNumber,
+    Rank,
+    DenseRank,
+    /// `LEAD(value, n)` is `LAG(value, -n)`.
+    Lag {
+        offset: i64,
+        default: TableValue,
+    },
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SortKey {
+    column: usize,
+    asc: bool,
+    nulls_first: bool,
+}
+
+impl SortKey {
+    /// NULLs go last in ascending order by default.
+    pub(crate) fn new(order_by: &OrderByExpr, column: usize) -> SortKey {
+        let asc = order_by.asc.unwrap_or(true);
+        SortKey {
+            column,
+            asc,
+            nulls_first: order_by.nulls_first.unwrap_or(!asc),
+        }
+    }
+}
+
+impl WindowPlan {
+    /// Takes window functions out of `query`. `None` if the query has none of them.
+    pub fn extract(query: &mut Query) -> Result<Option<WindowPlan>, CubeError> {
+        let select = match &mut query.body {
+            SetExpr::Select(select) => select,
+            _ => return Ok(None),
+        };
+        if !select
+            .projection
+            .iter()
+            .any(|item| window_call(item).is_some())
+        {
+            return Ok(None);
+        }
+        if select.distinct || query.offset.is_some() || query.fetch.is_some() {
+            return Err(CubeError::user(
+                "DISTINCT, OFFSET and FETCH can't be used along with window functions".to_string(),
+            ));
+        }
+        let visible_columns = select.projection.len();
+        let names = select
+            .projection
+            .iter()
+            .map(column_name)
+            .collect::<Vec<_>>();
+        let is_window = select
+            .projection
+            .iter()
+            .map(|item| window_call(item).is_some())
+            .collect::<Vec<_>>();
+        let mut hidden = Vec::<Expr>::new();
+        // Keys naming a selected column are taken from it, others are selected as hidden columns.
+        let mut key_column = |expr: &Expr, window_key: bool| -> usize {
+            if let Expr::Identifier(ident) = expr {
+                let column = names
+                    .iter()
+                    .position(|name| name.as_deref() == Some(ident.value.as_str()));
+                match column {
+                    Some(column) if !window_key || !is_window[column] => return column,
+                    _ => {}
+                }
+            }
+            let position = match hidden.iter().position(|e| e == expr) {
+                Some(position) => position,
+                None => {
+                    hidden.push(expr.clone());
+                    hidden.len() - 1
+                }
+            };
+            visible_columns + position
+        };
+
+        let mut windows = Vec::new();
+        for (column, item) in select.projection.iter_mut().enumerate() {
+            match item {
+                SelectItem::Wildcard | SelectItem::QualifiedWildcard(_) => {
+                    return Err(CubeError::user(
+                        "Wildcard can't be selected along with window functions".to_string(),
+                    ))
+                }
+                _ => {}
+            }
+            let function = match window_call(item) {
+                Some(function) => function.clone(),
+                None => continue,
+            };
+            let spec = function.over.as_ref().unwrap();
+            if spec.window_frame.is_some() {
+                return Err(CubeError::user(format!(
+                    "Window frames aren't supported: {}",
+                    function
+                )));
+            }
+            let (window_function, value) = window_function(&function)?;
+            windows.push(Window {
+                column,
+                function: window_function,
+                partition_by: spec
+                    .partition_by
+                    .iter()
+                    .map(|e| key_column(e, true))
+                    .collect(),
+                order_by: spec
+                    .order_by
+                    .iter()
+                    .map(|o| SortKey::new(o, key_column(&o.expr, true)))
+                    .collect(),
+            });
+            let alias = match item {
+                SelectItem::ExprWithAlias { alias, .. } => alias.clone(),
+                _ => Ident::new(function.to_string()),
+            };
+            *item = SelectItem::ExprWithAlias { expr: value, alias };
+        }
+
+        let mut order_by = Vec::new();
+        for o in query.order_by.iter() {
+            let column = match &o.expr {
+                Expr::Value(Value::Number(n)) => match n.parse::<usize>() {
+                    Ok(position) if position >= 1 && position <= visible_columns => position - 1,
+                    _ => {
+                        return Err(CubeError::user(format!(
+                            "ORDER BY position {} is out of range",
+                            n
+                        )))
+                    }
+                },
+                expr => key_column(expr, false),
+            };
+            order_by.push(SortKey::new(o, column));
+        }
+        query.order_by.clear();
+        let limit = match query.limit.take() {
+            Some(Expr::Value(Value::Number(n))) => Some(n.parse::<usize>().map_err(|_| {
+                CubeError::user(format!("LIMIT is expected to be a number but found {}", n))
+            })?),
+            Some(e) => {
+                return Err(CubeError::user(format!(
+                    "LIMIT is expected to be a number but found {}",
+                    e
+                )))
+            }
+            None => None,
+        };
+
+        select
+            .projection
+            .extend(
+                hidden
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, expr)| SelectItem::ExprWithAlias {
+                        expr,
+                        alias: Ident::new(format!("__window_{}", i)),
+                    }),
+            );
+        Ok(Some(WindowPlan {
+            windows,
+            order_by,
+            limit,
+            visible_columns,
+        }))
+    }
+
+    pub fn apply(&self, data_frame: DataFrame) -> Result<DataFrame, CubeError> {
+        let warnings = data_frame.get_warnings().clone();
+        let stats = data_frame.get_stats().clone();
+        let mut columns = data_frame.get_columns().clone();
+        let mut rows = data_frame
+            .into_rows()
+            .into_iter()
+            .map(|r| r.values().clone())
+            .collect::<Vec<_>>();
+        for window in self.windows.iter() {
+            let results = window.evaluate(&rows);
+            for (row, result) in rows.iter_mut().zip(results.into_iter()) {
+                row[window.column] = result;
+            }
+        }
+        rows.sort_by(|a, b| compare_rows(a, b, &self.order_by));
+        if let Some(limit) = self.limit {
+            rows.truncate(limit);
+        }
+        columns.truncate(self.visible_columns);
+        let rows = rows
+            .into_iter()
+            .map(|mut values| {
+                values.truncate(self.visible_columns);
+                Row::new(values)
+            })
+            .collect();
+        Ok(DataFrame::new(columns, rows)
+            .with_warnings(warnings)
+            .with_stats(stats))
+    }
+}
+
+impl Window {
+    fn evaluate(&self, rows: &[Vec<TableValue>]) -> Vec<TableValue> {
+        let partition_keys = self
+            .partition_by
+            .iter()
+            .map(|column| SortKey {
+                column: *column,
+                asc: true,
+                nulls_first: true,
+            })
+            .collect::<Vec<_>>();
+        let mut order = (0..rows.len()).collect::<Vec<_>>();
+        order.sort_by(|a, b| {
+            compare_rows(&rows[*a], &rows[*b], &partition_keys)
+                .then_with(|| compare_rows(&rows[*a], &rows[*b], &self.order_by))
+        });
+
+        let mut results = vec![TableValue::Null; rows.len()];
+        let mut start = 0;
+        while start < order.len() {
+            let end = start
+                + order[start..]
+                    .iter()
+                    .take_while(|i| {
+                        compare_rows(&rows[**i], &rows[order[start]], &partition_keys)
+                            == Ordering::Equal
+                    })
+                    .count();
+            self.evaluate_partition(rows, &order[start..end], &mut results);
+            start = end;
+        }
+        results
+    }
+
+    /// `partition` holds indices of rows of a partition in window order.
+    fn evaluate_partition(
+        &self,
+        rows: &[Vec<TableValue>],
+        partition: &[usize],
+        results: &mut [TableValue],
+    ) {
+        let mut rank = 0;
+        let mut dense_rank = 0;
+        for (position, row) in partition.iter().enumerate() {
+            let peer_of_previous = position > 0
+                && compare_rows(&rows[*row], &rows[partition[position - 1]], &self.order_by)
+                    == Ordering::Equal;
+            if !peer_of_previous {
+                rank = position + 1;
+                dense_rank += 1;
+            }
+            results[*row] = match &self.function {
+                WindowFunction::RowNumber => TableValue::Int(position as i64 + 1),
+                WindowFunction::Rank => TableValue::Int(rank as i64),
+                WindowFunction::DenseRank => TableValue::Int(dense_rank as i64),
+                WindowFunction::Lag { offset, default } => {
+                    let source = position as i64 - offset;
+                    if source >= 0 && source < partition.len() as i64 {
+                        rows[partition[source as usize]][self.column].clone()
+                    } else {
+                        default.clone()
+                    }
+                }
+            };
+        }
+    }
+}
+
+fn window_call(item: &SelectItem) -> Option<&Function> {
+    match item {
+        SelectItem::UnnamedExpr(Expr::Function(function))
+        | SelectItem::ExprWithAlias {
+            expr: Expr::Function(function),
+            ..
+        } if function.over.is_some() => Some(function),
+        _ => None,
+    }
+}
+
+/// Window function along with the value argument selected in its place.
+fn window_function(function: &Function) -> Result<(WindowFunction, Expr), CubeError> {
+    let name = function.name.to_string().to_lowercase();
+    let unsupported = || CubeError::user(format!("Unsupported window function: {}", function));
+    let ranking = |window_function| {
+        if function.args.is_empty() {
+            Ok((window_function, Expr::Value(Value::Number("0".to_string()))))
+        } else {
+            Err(unsupported())
+        }
+    };
+    match name.as_str() {
+        "row_number" => ranking(WindowFunction::RowNumber),
+        "rank" => ranking(WindowFunction::Rank),
+        "dense_rank" => ranking(WindowFunction::DenseRank),
+        "lag" | "lead" => {
+            let (value, offset, default) = match function.args.as_slice() {
+                [value] => (value, 1, TableValue::Null),
+                [value, offset] => (value, literal_offset(offset)?, TableValue::Null),
+                [value, offset, default] => {
+                    (value, literal_offset(offset)?, literal_value(default)?)
+                }
+                _ => return Err(unsupported()),
+            };
+            let offset = if name == "lag" { offset } else { -offset };
+            Ok((WindowFunction::Lag { offset, default }, value.clone()))
+        }
+        _ => Err(unsupported()),
+    }
+}
+
+fn literal_offset(expr: &Expr) -> Result<i64, CubeError> {
+    match expr {
+        Expr::Value(Value::Number(n)) => n.parse::<i64>().ok().filter(|n| *n >= 0),
+        _ => None,
+    }
+    .ok_or_else(|| {
+        CubeError::user(format!(
+            "Window function offset is expected to be a non-negative integer but found {}",
+            expr
+        ))
+    })
+}
+
+fn literal_value(expr: &Expr) -> Result<TableValue, CubeError> {
+    Ok(match expr {
+        Expr::Value(Value::Null) => TableValue::Null,
+        Expr::Value(Value::Number(n)) => match n.parse::<i64>() {
+            Ok(n) => TableValue::Int(n),
+            Err(_) => TableValue::Decimal(n.to_string()),
+        },
+        Expr::Value(Value::SingleQuotedString(s)) => TableValue::String(s.to_string()),
+        Expr::Value(Value::Boolean(b)) => TableValue::Boolean(*b),
+        Expr::UnaryOp {
+            op: UnaryOperator::Minus,
+            expr,
+        } => match literal_value(expr)? {
+            TableValue::Int(n) => TableValue::Int(-n),
+            TableValue::Decimal(n) => TableValue::Decimal(format!("-{}", n)),
+            _ => return Err(CubeError::user(format!("Unsupported default: {}", expr))),
+        },
+        _ => {
+            return Err(CubeError::user(format!(
+                "Window function default is expected to be a literal but found {}",
+                expr
+            )))
+        }
+    })
+}
+
+/// Name the column of `item` can be referred to by.
+pub(crate) fn column_name(item: &SelectItem) -> Option<String> {
+    match item {
+        SelectItem::ExprWithAlias { alias, .. } => Some(alias.value.clone()),
+        SelectItem::UnnamedExpr(Expr::Identifier(name)) => Some(name.value.clone()),
+        _ => None,
+    }
+}
+
+pub(crate) fn compare_rows(a: &[TableValue], b: &[TableValue], keys: &[SortKey]) -> Ordering {
+    for key in keys {
+        let (a, b) = (&a[key.column], &b[key.column]);
+        let ordering = match (a, b) {
+            (TableValue::Null, TableValue::Null) => Ordering::Equal,
+            (TableValue::Null, _) if key.nulls_first => Ordering::Less,
+            (TableValue::Null, _) => Ordering::Greater,
+            (_, TableValue::Null) if key.nulls_first => Ordering::Greater,
+            (_, TableValue::Null) => Ordering::Less,
+            _ if key.asc => compare_values(a, b),
+            _ => compare_values(b, a),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Decimals are kept as strings which don't compare numerically.
+fn compare_values(a: &TableValue, b: &TableValue) -> Ordering {
+    match (a, b) {
+        (TableValue::Decimal(a), TableValue::Decimal(b)) => {
+            match (BigDecimal::from_str(a), BigDecimal::from_str(b)) {
+                (Ok(a), Ok(b)) => a.cmp(&b),
+                _ => a.cmp(b),
+            }
+        }
+        _ => a.cmp(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::{Column, ColumnType};
+    use sqlparser::ast::Statement;
+    use sqlparser::dialect::GenericDialect;
+    use sqlparser::parser::Parser;
+
+    fn extract(sql: &str) -> (String, Option<WindowPlan>) {
+        let mut statement = Parser::parse_sql(&GenericDialect {}, sql)
+            .unwrap()
+            .pop()
+            .unwrap();
+        let plan = match &mut statement {
+            Statement::Query(query) => WindowPlan::extract(query).unwrap(),
+            _ => panic!("Query expected"),
+        };
+        (statement.to_string(), plan)
+    }
+
+    #[test]
+    fn extracts_window_functions() {
+        let (sql, plan) = extract(
+            "SELECT d, day, sum(v) AS value, LAG(sum(v), 1) OVER (PARTITION BY d ORDER BY max(t)) AS prev \
+             FROM s.t GROUP BY 1, 2 ORDER BY d, 2 LIMIT 10",
+        );
+        assert_eq!(
+            sql,
+            "SELECT d, day, sum(v) AS value, sum(v) AS prev, max(t) AS __window_0 \
+             FROM s.t GROUP BY 1, 2"
+        );
+        let plan = plan.unwrap();
+        assert_eq!(plan.visible_columns, 4);
+        assert_eq!(plan.limit, Some(10));
+        assert_eq!(
+            plan.order_by.iter().map(|k| k.column).collect::<Vec<_>>(),
+            vec![0, 1]
+        );
+        assert_eq!(plan.windows[0].partition_by, vec![0]);
+        assert_eq!(plan.windows[0].order_by[0].column, 4);
+
+        let (sql, plan) = extract("SELECT d FROM s.t ORDER BY d LIMIT 1");
+        assert_eq!(sql, "SELECT d FROM s.t ORDER BY d LIMIT 1");
+        assert!(plan.is_none());
+    }
+
+    #[test]
+    fn evaluates_window_functions() {
+        let (_, plan) = extract(
+            "SELECT d, v, \
+             ROW_NUMBER() OVER (PARTITION BY d ORDER BY v) AS n, \
+             RANK() OVER (PARTITION BY d ORDER BY v) AS r, \
+             DENSE_RANK() OVER (PARTITION BY d ORDER BY v) AS dr, \
+             LAG(v) OVER (PARTITION BY d ORDER BY v) AS prev, \
+             LEAD(v, 2, 0) OVER (PARTITION BY d ORDER BY v) AS next \
+             FROM s.t ORDER BY d, n",
+        );
+        let plan = plan.unwrap();
+        let columns = ["d", "v", "n", "r", "dr", "prev", "next"]
+            .iter()
+            .enumerate()
+            .map(|(i, name)| Column::new(name.to_string(), ColumnType::Int, i))
+            .collect::<Vec<_>>();
+        let int = |v: i64| TableValue::Int(v);
+        let rows = vec![(2, 5), (1, 3), (1, 1), (1, 3), (1, 7)]
+            .into_iter()
+            .map(|(d, v)| Row::new(vec![int(d), int(v), int(0), int(0), int(0), int(v), int(v)]))
+            .collect();
+        let result = plan.apply(DataFrame::new(columns, rows)).unwrap();
+        assert_eq!(result.get_columns().len(), 7);
+        assert_eq!(
+            result.get_rows(),
+            &vec![
+                Row::new(vec![
+                    int(1),
+                    int(1),
+                    int(1),
+                    int(1),
+                    int(1),
+                    TableValue::Null,
+                    int(3)
+                ]),
+                Row::new(vec![int(1), int(3), int(2), int(2), int(2), int(1), int(7)]),
+                Row::new(vec![int(1), int(3), int(3), int(2), int(2), int(3), int(0)]),
+                Row::new(vec![int(1), int(7), int(4), int(4), int(3), int(3), int(0)]),
+                Row::new(vec![
+                    int(2),
+                    int(5),
+                    int(1),
+                    int(1),
+                    int(1),
+                    TableValue::Null,
+                    int(0)
+                ]),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_windows() {
+        let error = |sql: &str| {
+            let mut statement = Parser::parse_sql(&GenericDialect {}, sql)
+                .unwrap()
+                .pop()
+                .unwrap();
+            match &mut statement {
+                Statement::Query(query) => WindowPlan::extract(query).unwrap_err().to_string(),
+                _ => panic!("Query expected"),
+            }
+        };
+        assert!(error("SELECT sum(v) OVER (PARTITION BY d) FROM s.t").contains("Unsupported"));
+        assert!(error("SELECT *, ROW_NUMBER() OVER () FROM s.t").contains("Wildcard"));
+        assert!(error("SELECT LAG(v, x) OVER () FROM s.t").contains("offset"));
+    }
+}
@@ -0,0 +1,134 @@
+//! Fields added to structs sent between nodes after the first wire format version. Payloads of
+//! older versions don't have them so they're written and read only if the version of the payload
+//! does. Fields are annotated with `#[serde(default, with = "...")]` using one of the modules
+//! below. Values of `since_v2` fields are dropped for version 1 while non-default values of
+//! `required_since_v2` fields fail serialization as older nodes would return wrong results
+//! without them.
+use crate::queryplanner::serialized_plan::{check_wire_format_version, WIRE_FORMAT_VERSION};
+use serde::de::{self, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Error, SerializeTuple, Serializer};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::fmt;
+use std::marker::PhantomData;
+
+thread_local! {
+    static PAYLOAD_VERSION: Cell<u32> = Cell::new(WIRE_FORMAT_VERSION);
+}
+
+/// Runs `f` writing or reading versioned fields as payloads of `version` have them. Payloads are
+/// of the current version otherwise.
+pub fn with_payload_version<R>(version: u32, f: impl FnOnce() -> R) -> R {
+    struct Reset(u32);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            PAYLOAD_VERSION.with(|v| v.set(self.0));
+        }
+    }
+    let _reset = Reset(PAYLOAD_VERSION.with(|v| v.replace(version)));
+    f()
+}
+
+/// Payload sent between processes or nodes along with the version it's written in, so the
+/// receiving side reads it as it was written without knowing the version beforehand.
+#[derive(Debug)]
+pub struct Versioned<T> {
+    pub version: u32,
+    pub payload: T,
+}
+
+impl<T: Serialize> Serialize for Versioned<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = s.serialize_tuple(2)?;
+        tuple.serialize_element(&self.version)?;
+        with_payload_version(self.version, || tuple.serialize_element(&self.payload))?;
+        tuple.end()
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Versioned<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct VersionedVisitor<T>(PhantomData<T>);
+
+        impl<'de, T: Deserialize<'de>> Visitor<'de> for VersionedVisitor<T> {
+            type Value = Versioned<T>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("wire format version followed by the payload")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                let version: u32 = seq
+                    .next_element()?
+                    .ok_or_else(|| de::Error::invalid_length(0, &self))?;
+                check_wire_format_version(version, "Payload")
+                    .map_err(|e| de::Error::custom(e.to_string()))?;
+                let payload = with_payload_version(version, || seq.next_element())?
+                    .ok_or_else(|| de::Error::invalid_length(1, &self))?;
+                Ok(Versioned { version, payload })
+            }
+        }
+
+        d.deserialize_tuple(2, VersionedVisitor(PhantomData))
+    }
+}
+
+fn payload_version() -> u32 {
+    PAYLOAD_VERSION.with(|v| v.get())
+}
+
+fn serialize_since<T, S>(since: u32, required: bool, value: &T, s: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize + Default + PartialEq,
+    S: Serializer,
+{
+    let version = payload_version();
+    if version >= since {
+        value.serialize(s)
+    } else if required && *value != T::default() {
+        Err(S::Error::custom(format!(
+            "Plan requires wire format version {} but version {} is used",
+            since, version
+        )))
+    } else {
+        // Nothing is written for units
+        s.serialize_unit()
+    }
+}
+
+fn deserialize_since<'de, T, D>(since: u32, d: D) -> Result<T, D::Error>
+where
+    T: Deserialize<'de> + Default,
+    D: Deserializer<'de>,
+{
+    if payload_version() >= since {
+        T::deserialize(d)
+    } else {
+        Ok(T::default())
+    }
+}
+
+macro_rules! versioned_field {
+    ($module:ident, $since:expr, $required:expr) => {
+        pub mod $module {
+            pub fn serialize<T, S>(value: &T, s: S) -> Result<S::Ok, S::Error>
+            where
+                T: serde::Serialize + Default + PartialEq,
+                S: serde::Serializer,
+            {
+                super::serialize_since($since, $required, value, s)
+            }
+
+            pub fn deserialize<'de, T, D>(d: D) -> Result<T, D::Error>
+            where
+                T: serde::Deserialize<'de> + Default,
+                D: serde::Deserializer<'de>,
+            {
+                super::deserialize_since($since, d)
+            }
+        }
+    };
+}
+
+versioned_field!(since_v2, 2, false);
+versioned_field!(required_since_v2, 2, true);
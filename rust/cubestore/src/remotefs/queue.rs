@@ -0,0 +1,341 @@
+use crate::remotefs::RemoteFs;
+use crate::CubeError;
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt;
+use log::error;
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+type Download = Shared<BoxFuture<'static, Result<String, CubeError>>>;
+
+/// Downloads of remote files shared by queries of a node. Queries queue all files their plan
+/// references and wait only for the ones they scan, the rest is fetched in the background for
+/// queries to come. At most `concurrency` files are downloaded at once and files queries wait for
+/// get free slots before background ones. Non-zero `bandwidth_limit` spaces downloads to keep the
+/// average rate under that many bytes per second.
+pub struct DownloadQueue {
+    remote_fs: Arc<dyn RemoteFs>,
+    slots: Arc<Mutex<Slots>>,
+    bandwidth: Arc<Mutex<Bandwidth>>,
+    downloads: Arc<Mutex<HashMap<String, Download>>>,
+}
+
+impl DownloadQueue {
+    pub fn new(
+        remote_fs: Arc<dyn RemoteFs>,
+        concurrency: usize,
+        bandwidth_limit: u64,
+    ) -> Arc<DownloadQueue> {
+        Arc::new(DownloadQueue {
+            remote_fs,
+            slots: Arc::new(Mutex::new(Slots {
+                free: max(concurrency, 1),
+                waiting: BTreeMap::new(),
+                next_seq: 0,
+                waited_on: HashSet::new(),
+            })),
+            bandwidth: Arc::new(Mutex::new(Bandwidth {
+                limit: bandwidth_limit,
+                next_start: Instant::now(),
+                last_size: 0,
+            })),
+            downloads: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Starts downloads of `remote_paths` in the background.
+    pub fn enqueue(&self, remote_paths: &[String]) {
+        for remote_path in remote_paths {
+            let download = self.download(remote_path, false);
+            let remote_path = remote_path.to_string();
+            tokio::spawn(async move {
+                if let Err(e) = download.await {
+                    error!("Error downloading {}: {}", remote_path, e);
+                }
+            });
+        }
+    }
+
+    /// Local path of `remote_path` once it's downloaded. Joins the queued download if any and
+    /// moves it ahead of background ones.
+    pub async fn wait_for(&self, remote_path: &str) -> Result<String, CubeError> {
+        self.download(remote_path, true).await
+    }
+
+    /// Files queued or being downloaded.
+    pub fn queue_depth(&self) -> usize {
+        self.downloads.lock().unwrap().len()
+    }
+
+    fn download(&self, remote_path: &str, waited_on: bool) -> Download {
+        let mut downloads = self.downloads.lock().unwrap();
+        if waited_on {
+            self.slots.lock().unwrap().wait_on(remote_path);
+        }
+        if let Some(download) = downloads.get(remote_path) {
+            return download.clone();
+        }
+        let remote_fs = self.remote_fs.clone();
+        let slots = self.slots.clone();
+        let bandwidth = self.bandwidth.clone();
+        let all_downloads = self.downloads.clone();
+        let path = remote_path.to_string();
+        let download = async move {
+            let result = {
+                let _slot = Slots::acquire(&slots, &path).await;
+                let (start, estimate) = bandwidth.lock().unwrap().reserve();
+                tokio::time::delay_until(start).await;
+                let result = remote_fs.download_file(&path).await;
+                let size = match &result {
+                    Ok(local_path) => tokio::fs::metadata(local_path)
+                        .await
+                        .map(|m| m.len())
+                        .unwrap_or(0),
+                    Err(_) => 0,
+                };
+                bandwidth.lock().unwrap().settle(estimate, size);
+                result
+            };
+            let mut all_downloads = all_downloads.lock().unwrap();
+            all_downloads.remove(&path);
+            slots.lock().unwrap().waited_on.remove(&path);
+            result
+        }
+        .boxed()
+        .shared();
+        downloads.insert(remote_path.to_string(), download.clone());
+        download
+    }
+}
+
+/// Download slots handed out to files queries wait for first and then in the order files were
+/// queued.
+struct Slots {
+    free: usize,
+    /// Keyed by whether the file is downloaded in the background and the order it was queued in.
+    waiting: BTreeMap<(bool, u64), (String, oneshot::Sender<()>)>,
+    next_seq: u64,
+    /// Files queries wait for.
+    waited_on: HashSet<String>,
+}
+
+impl Slots {
+    async fn acquire(slots: &Arc<Mutex<Slots>>, remote_path: &str) -> Slot {
+        let receiver = {
+            let mut guard = slots.lock().unwrap();
+            if guard.free > 0 {
+                guard.free -= 1;
+                None
+            } else {
+                let (sender, receiver) = oneshot::channel();
+                let key = (!guard.waited_on.contains(remote_path), guard.next_seq);
+                guard.next_seq += 1;
+                guard.waiting.insert(key, (remote_path.to_string(), sender));
+                Some(receiver)
+            }
+        };
+        if let Some(receiver) = receiver {
+            // Senders live as long as the queue does
+            let _ = receiver.await;
+        }
+        Slot {
+            slots: slots.clone(),
+        }
+    }
+
+    /// Moves a background download of `remote_path` waiting for a slot ahead of the others.
+    fn wait_on(&mut self, remote_path: &str) {
+        if !self.waited_on.insert(remote_path.to_string()) {
+            return;
+        }
+        let key = self
+            .waiting
+            .iter()
+            .find(|((background, _), (path, _))| *background && path == remote_path)
+            .map(|(key, _)| *key);
+        if let Some(key) = key {
+            let waiter = self.waiting.remove(&key).unwrap();
+            self.waiting.insert((false, key.1), waiter);
+        }
+    }
+
+    fn release(&mut self) {
+        while let Some(key) = self.waiting.keys().next().cloned() {
+            let (_, sender) = self.waiting.remove(&key).unwrap();
+            // Waiters whose download was dropped don't take the slot
+            if sender.send(()).is_ok() {
+                return;
+            }
+        }
+        self.free += 1;
+    }
+}
+
+struct Slot {
+    slots: Arc<Mutex<Slots>>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.lock().unwrap().release();
+    }
+}
+
+/// Start times of downloads under the bandwidth limit. Sizes are known only once files are
+/// downloaded so a download reserves the time the previous file took and the difference is
+/// settled when it completes.
+struct Bandwidth {
+    limit: u64,
+    /// Earliest time the next download can start without exceeding `limit`.
+    next_start: Instant,
+    last_size: u64,
+}
+
+impl Bandwidth {
+    /// Start time of a download and the size reserved for it.
+    fn reserve(&mut self) -> (Instant, u64) {
+        let start = max(self.next_start, Instant::now());
+        if self.limit == 0 {
+            return (start, 0);
+        }
+        self.next_start = start + self.duration(self.last_size);
+        (start, self.last_size)
+    }
+
+    fn settle(&mut self, estimate: u64, size: u64) {
+        if self.limit == 0 {
+            return;
+        }
+        if size >= estimate {
+            self.next_start += self.duration(size - estimate);
+        } else {
+            let refund = self.duration(estimate - size);
+            self.next_start = self
+                .next_start
+                .checked_sub(refund)
+                .unwrap_or(self.next_start);
+        }
+        self.last_size = size;
+    }
+
+    fn duration(&self, size: u64) -> Duration {
+        Duration::from_secs_f64(size as f64 / self.limit as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remotefs::RemoteFile;
+    use async_trait::async_trait;
+
+    /// Downloads take as many milliseconds as the number the path starts with.
+    #[derive(Debug)]
+    struct SlowRemoteFs;
+
+    #[async_trait]
+    impl RemoteFs for SlowRemoteFs {
+        async fn upload_file(&self, _remote_path: &str) -> Result<(), CubeError> {
+            unimplemented!()
+        }
+
+        async fn download_file(&self, remote_path: &str) -> Result<String, CubeError> {
+            let millis = remote_path
+                .split('.')
+                .next()
+                .unwrap()
+                .parse::<u64>()
+                .unwrap();
+            tokio::time::delay_for(Duration::from_millis(millis)).await;
+            Ok(format!("local/{}", remote_path))
+        }
+
+        async fn delete_file(&self, _remote_path: &str) -> Result<(), CubeError> {
+            unimplemented!()
+        }
+
+        async fn list(&self, _remote_prefix: &str) -> Result<Vec<String>, CubeError> {
+            unimplemented!()
+        }
+
+        async fn list_with_metadata(
+            &self,
+            _remote_prefix: &str,
+        ) -> Result<Vec<RemoteFile>, CubeError> {
+            unimplemented!()
+        }
+
+        async fn local_path(&self) -> String {
+            unimplemented!()
+        }
+
+        async fn local_file(&self, _remote_path: &str) -> Result<String, CubeError> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_only_for_requested_files() {
+        // Time advances as soon as all tasks wait for a delay
+        tokio::time::pause();
+        let start = Instant::now();
+        let queue = DownloadQueue::new(Arc::new(SlowRemoteFs), 2, 0);
+        queue.enqueue(&vec!["10.parquet".to_string(), "2000.parquet".to_string()]);
+        assert_eq!(queue.queue_depth(), 2);
+
+        assert_eq!(
+            queue.wait_for("10.parquet").await.unwrap(),
+            "local/10.parquet"
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(10));
+        assert_eq!(queue.queue_depth(), 1);
+
+        assert_eq!(
+            queue.wait_for("2000.parquet").await.unwrap(),
+            "local/2000.parquet"
+        );
+        assert_eq!(start.elapsed(), Duration::from_millis(2000));
+        assert_eq!(queue.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn waited_on_files_go_before_background_ones() {
+        tokio::time::pause();
+        let start = Instant::now();
+        let queue = DownloadQueue::new(Arc::new(SlowRemoteFs), 1, 0);
+        queue.enqueue(&vec![
+            "100.a".to_string(),
+            "100.b".to_string(),
+            "100.c".to_string(),
+        ]);
+        // Lets background downloads queue for the slot
+        tokio::task::yield_now().await;
+
+        assert_eq!(queue.wait_for("100.c").await.unwrap(), "local/100.c");
+        // Only 100.a was downloaded before
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+        assert_eq!(queue.queue_depth(), 1);
+    }
+
+    #[test]
+    fn bandwidth_reserves_slots_before_downloads() {
+        let now = Instant::now();
+        let mut bandwidth = Bandwidth {
+            limit: 100,
+            next_start: now,
+            last_size: 100,
+        };
+        let (first, estimate) = bandwidth.reserve();
+        // Started before the first completes, the second one waits for its estimated end
+        let (second, _) = bandwidth.reserve();
+        assert_eq!(second - first, Duration::from_secs(1));
+
+        bandwidth.settle(estimate, 300);
+        assert_eq!(bandwidth.next_start - first, Duration::from_secs(4));
+        assert_eq!(bandwidth.last_size, 300);
+    }
+}
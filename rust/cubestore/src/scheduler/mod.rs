@@ -1,12 +1,19 @@
 use crate::cluster::Cluster;
 use crate::config::ConfigObj;
 use crate::metastore::job::{Job, JobType};
-use crate::metastore::{MetaStore, MetaStoreEvent, RowKey, TableId};
-use crate::remotefs::RemoteFs;
+use crate::metastore::{
+    IdRow, MetaStore, MetaStoreEvent, MetaStoreTable, Partition, RowKey, TableId,
+};
+use crate::remotefs::{RemoteFile, RemoteFs};
 use crate::store::{ChunkStore, WALStore};
+use crate::table::{TableValue, TimestampValue};
 use crate::CubeError;
-use log::error;
+use chrono::Utc;
+use log::{error, info, warn};
+use regex::Regex;
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::{watch, Mutex};
 
@@ -17,9 +24,22 @@ pub struct SchedulerImpl {
     event_receiver: Mutex<Receiver<MetaStoreEvent>>,
     stop_sender: watch::Sender<bool>,
     stop_receiver: Mutex<watch::Receiver<bool>>,
+    retention_stop_receiver: Mutex<watch::Receiver<bool>>,
+    file_deletion_stop_receiver: Mutex<watch::Receiver<bool>>,
+    pending_file_deletions: Mutex<Vec<PendingFileDeletion>>,
     config: Arc<dyn ConfigObj>,
 }
 
+/// Remote file of a dropped table which is deleted once `delete_after` passes.
+#[derive(Debug)]
+struct PendingFileDeletion {
+    remote_path: String,
+    delete_after: Instant,
+    attempts: u32,
+}
+
+const MAX_FILE_DELETION_ATTEMPTS: u32 = 5;
+
 impl SchedulerImpl {
     pub fn new(
         meta_store: Arc<dyn MetaStore>,
@@ -35,7 +55,10 @@ impl SchedulerImpl {
             remote_fs,
             event_receiver: Mutex::new(event_receiver),
             stop_sender: tx,
-            stop_receiver: Mutex::new(rx),
+            stop_receiver: Mutex::new(rx.clone()),
+            retention_stop_receiver: Mutex::new(rx.clone()),
+            file_deletion_stop_receiver: Mutex::new(rx),
+            pending_file_deletions: Mutex::new(Vec::new()),
             config,
         }
     }
@@ -63,6 +86,215 @@ impl SchedulerImpl {
         }
     }
 
+    pub async fn run_retention_loop(&self) -> Result<(), CubeError> {
+        let mut stop_receiver = self.retention_stop_receiver.lock().await;
+        loop {
+            tokio::select! {
+                Some(stopped) = stop_receiver.recv() => {
+                    if stopped {
+                        return Ok(());
+                    } else {
+                        continue;
+                    }
+                }
+                _ = tokio::time::delay_for(Duration::from_secs(self.config.retention_check_interval())) => {}
+            };
+            if let Err(e) = self.expire_partitions().await {
+                error!("Error expiring partitions: {}", e);
+            }
+        }
+    }
+
+    pub async fn run_file_deletion_loop(&self) -> Result<(), CubeError> {
+        let mut stop_receiver = self.file_deletion_stop_receiver.lock().await;
+        if let Err(e) = self.delete_orphan_files().await {
+            error!("Error scheduling deletion of orphan files: {}", e);
+        }
+        loop {
+            tokio::select! {
+                Some(stopped) = stop_receiver.recv() => {
+                    if stopped {
+                        return Ok(());
+                    } else {
+                        continue;
+                    }
+                }
+                _ = tokio::time::delay_for(Duration::from_secs(1)) => {}
+            };
+            self.delete_pending_files().await;
+        }
+    }
+
+    /// Deletes remote files of dropped tables whose grace period has passed. Failed deletions
+    /// are retried with a backoff and given up after `MAX_FILE_DELETION_ATTEMPTS`, leaving the
+    /// file to `system.orphan_files`.
+    pub async fn delete_pending_files(&self) {
+        let now = Instant::now();
+        let due = {
+            let mut pending = self.pending_file_deletions.lock().await;
+            let (due, not_due): (Vec<_>, Vec<_>) =
+                pending.drain(..).partition(|f| f.delete_after <= now);
+            *pending = not_due;
+            due
+        };
+        let mut failed = Vec::new();
+        for mut file in due.into_iter() {
+            if let Err(e) = self.remote_fs.delete_file(file.remote_path.as_str()).await {
+                file.attempts += 1;
+                if file.attempts < MAX_FILE_DELETION_ATTEMPTS {
+                    warn!(
+                        "Error deleting {} (attempt {}): {}",
+                        file.remote_path, file.attempts, e
+                    );
+                    file.delete_after = now + Duration::from_secs(1 << file.attempts);
+                    failed.push(file);
+                } else {
+                    error!(
+                        "Giving up deleting {} after {} attempts: {}",
+                        file.remote_path, file.attempts, e
+                    );
+                }
+            }
+        }
+        self.pending_file_deletions.lock().await.extend(failed);
+    }
+
+    /// Queries planned before a table is dropped may still read its files, so they're deleted
+    /// only after the grace period.
+    async fn schedule_file_deletion(&self, remote_path: String) {
+        self.pending_file_deletions
+            .lock()
+            .await
+            .push(PendingFileDeletion {
+                remote_path,
+                delete_after: Instant::now()
+                    + Duration::from_secs(self.config.file_deletion_grace_period()),
+                attempts: 0,
+            });
+    }
+
+    /// Schedules deletion of orphan files older than the grace period. Pending deletions are
+    /// kept in memory only, so files of tables dropped before a restart are picked up here.
+    /// Newer files may still be uploaded and are left for the next start.
+    pub async fn delete_orphan_files(&self) -> Result<(), CubeError> {
+        let grace_period =
+            chrono::Duration::seconds(self.config.file_deletion_grace_period() as i64);
+        let files = orphan_files(self.meta_store.as_ref(), self.remote_fs.as_ref()).await?;
+        let now = Instant::now();
+        let mut pending = self.pending_file_deletions.lock().await;
+        for file in files {
+            if *file.updated() + grace_period > Utc::now()
+                || pending.iter().any(|f| f.remote_path == file.remote_path())
+            {
+                continue;
+            }
+            info!("Deleting orphan file {}", file.remote_path());
+            pending.push(PendingFileDeletion {
+                remote_path: file.remote_path().to_string(),
+                delete_after: now,
+                attempts: 0,
+            });
+        }
+        Ok(())
+    }
+
+    pub async fn pending_file_deletions(&self) -> Vec<String> {
+        self.pending_file_deletions
+            .lock()
+            .await
+            .iter()
+            .map(|f| f.remote_path.clone())
+            .collect()
+    }
+
+    /// Replaces partitions of tables with retention whose rows are all older than the retention
+    /// horizon with empty ones. Partitions expired before are deleted along with their files once
+    /// queries planned before the expiration don't use them anymore.
+    pub async fn expire_partitions(&self) -> Result<(), CubeError> {
+        for table in self.meta_store.get_tables().await? {
+            let retention = match table.get_row().retention() {
+                Some(retention) => retention.clone(),
+                None => continue,
+            };
+            let horizon = TableValue::Timestamp(TimestampValue::new(
+                Utc::now().timestamp_nanos() - retention.millis() as i64 * 1_000_000,
+            ));
+            let indexes = self.meta_store.get_table_indexes(table.get_id()).await?;
+            // Expiring some of the indexes would make results depend on the index a query uses
+            if let Some(index) = indexes.iter().find(|index| {
+                index.get_row().sort_key_size() == 0
+                    || index.get_row().get_columns()[0].get_name() != retention.column()
+            }) {
+                warn!(
+                    "Skipping retention of {}: index {} isn't sorted by {}",
+                    table.get_row().get_table_name(),
+                    index.get_row().get_name(),
+                    retention.column()
+                );
+                continue;
+            }
+            for index in indexes {
+                let partitions = self
+                    .meta_store
+                    .get_partitions_by_index_id(index.get_id())
+                    .await?;
+                for partition in partitions.iter() {
+                    if partition.get_row().is_active() {
+                        // Upper bound is exclusive so all rows are older than the horizon
+                        let expired = match partition.get_row().get_max_val() {
+                            Some(max) => max.values()[0] <= horizon,
+                            None => false,
+                        };
+                        if expired {
+                            self.meta_store.expire_partition(partition.get_id()).await?;
+                        }
+                    } else if !partitions
+                        .iter()
+                        .any(|p| p.get_row().parent_partition_id() == &Some(partition.get_id()))
+                    {
+                        // Compacted partitions have children, expired ones don't
+                        self.delete_expired_partition(partition).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn delete_expired_partition(
+        &self,
+        partition: &IdRow<Partition>,
+    ) -> Result<(), CubeError> {
+        let mut chunks_left = false;
+        for chunk in self
+            .meta_store
+            .get_chunks_by_partition(partition.get_id(), true)
+            .await?
+        {
+            // Active chunks are yet to be repartitioned and not uploaded ones are being written
+            if chunk.get_row().active()
+                || !chunk.get_row().uploaded()
+                || self.meta_store.is_chunk_used(chunk.get_id()).await?
+            {
+                chunks_left = true;
+            } else {
+                self.meta_store.delete_chunk(chunk.get_id()).await?;
+            }
+        }
+        if !chunks_left
+            && !self
+                .meta_store
+                .is_partition_used(partition.get_id())
+                .await?
+        {
+            self.meta_store
+                .partition_table()
+                .delete(partition.get_id())
+                .await?;
+        }
+        Ok(())
+    }
+
     pub fn stop_processing_loops(&self) -> Result<(), CubeError> {
         Ok(self.stop_sender.broadcast(true)?)
     }
@@ -96,10 +328,31 @@ impl SchedulerImpl {
                             .meta_store
                             .get_chunks_by_partition(chunk.get_row().get_partition_id(), false)
                             .await?;
+                        // Compaction also splits partitions which outgrow the thresholds
+                        let size_threshold = self.config.partition_size_split_threshold();
+                        let outgrown = partition.get_row().main_table_row_count() + chunk_sizes
+                            > self.config.partition_split_threshold()
+                            || (size_threshold > 0
+                                && partition
+                                    .get_row()
+                                    .estimated_file_size(chunk_sizes)
+                                    .map_or(false, |size| size > size_threshold));
                         if chunk_sizes > self.config.compaction_chunks_total_size_threshold()
                             || chunks.len()
                                 > self.config.compaction_chunks_count_threshold() as usize
+                            || outgrown
                         {
+                            let small_chunks = chunks
+                                .iter()
+                                .filter(|c| c.get_row().get_level() == 0)
+                                .count();
+                            info!(
+                                "Compaction backlog of partition {}: {} small chunks, {} medium chunks, {} rows",
+                                chunk.get_row().get_partition_id(),
+                                small_chunks,
+                                chunks.len() - small_chunks,
+                                chunk_sizes
+                            );
                             self.schedule_partition_to_compact(chunk.get_row().get_partition_id())
                                 .await?;
                         }
@@ -114,6 +367,16 @@ impl SchedulerImpl {
                 }
             }
         }
+        if let MetaStoreEvent::Insert(TableId::Indexes, row_id) = event {
+            let index = self
+                .meta_store
+                .index_table()
+                .row_by_id_or_not_found(row_id)
+                .await?;
+            if index.get_row().is_building() {
+                self.schedule_index_backfill(row_id).await?;
+            }
+        }
         if let MetaStoreEvent::Insert(TableId::Tables, row_id) = event {
             let table = self.meta_store.get_table_by_id(row_id).await?;
             if table.get_row().location().is_some() {
@@ -125,14 +388,35 @@ impl SchedulerImpl {
                 .delete_file(WALStore::wal_remote_path(row_id).as_str())
                 .await?
         }
-        if let MetaStoreEvent::Delete(TableId::Chunks, row_id) = event {
-            self.remote_fs
-                .delete_file(ChunkStore::chunk_remote_path(row_id).as_str())
-                .await?
+        if let MetaStoreEvent::DeleteChunk(chunk) = &event {
+            let remote_path = ChunkStore::chunk_remote_path(chunk.get_id());
+            // Chunks are deleted along with their partition when the table is dropped
+            if self
+                .meta_store
+                .partition_table()
+                .row_by_id_or_not_found(chunk.get_row().get_partition_id())
+                .await
+                .is_err()
+            {
+                self.schedule_file_deletion(remote_path).await;
+            } else {
+                self.remote_fs.delete_file(remote_path.as_str()).await?;
+            }
         }
         if let MetaStoreEvent::DeletePartition(partition) = &event {
             if let Some(file_name) = partition.get_row().get_full_name(partition.get_id()) {
-                self.remote_fs.delete_file(file_name.as_str()).await?;
+                // Indexes are deleted only when the table is dropped
+                if self
+                    .meta_store
+                    .index_table()
+                    .row_by_id_or_not_found(partition.get_row().get_index_id())
+                    .await
+                    .is_err()
+                {
+                    self.schedule_file_deletion(file_name).await;
+                } else {
+                    self.remote_fs.delete_file(file_name.as_str()).await?;
+                }
             }
         }
         if let MetaStoreEvent::Update(TableId::Partitions, row_id) = event {
@@ -149,6 +433,7 @@ impl SchedulerImpl {
                         self.remote_fs.delete_file(file_name.as_str()).await?;
                     }
                 }
+                self.warm_up_compacted_partitions(&partition).await?;
             }
         }
         if let MetaStoreEvent::DeleteJob(job) = event {
@@ -175,6 +460,70 @@ impl SchedulerImpl {
         Ok(())
     }
 
+    /// Compaction deactivates `partition` along with its compacted chunks when it activates the
+    /// new partitions. Nodes the new partitions are assigned to download their files right away
+    /// and drop local copies of the replaced files which aren't used by queries anymore.
+    async fn warm_up_compacted_partitions(
+        &self,
+        partition: &IdRow<Partition>,
+    ) -> Result<(), CubeError> {
+        let new_partitions = self
+            .meta_store
+            .get_active_partitions_by_index_id(partition.get_row().get_index_id())
+            .await?
+            .into_iter()
+            .filter(|p| p.get_row().parent_partition_id() == &Some(partition.get_id()))
+            .collect::<Vec<_>>();
+        if new_partitions.is_empty() {
+            return Ok(());
+        }
+        let mut superseded_files = Vec::new();
+        for chunk in self
+            .meta_store
+            .get_chunks_by_partition(partition.get_id(), true)
+            .await?
+        {
+            if !chunk.get_row().active() && !self.meta_store.is_chunk_used(chunk.get_id()).await? {
+                superseded_files.push(ChunkStore::chunk_remote_path(chunk.get_id()));
+            }
+        }
+        if !self
+            .meta_store
+            .is_partition_used(partition.get_id())
+            .await?
+        {
+            superseded_files.extend(partition.get_row().get_full_name(partition.get_id()));
+        }
+        let available_nodes = self.cluster.available_nodes().await?;
+        if available_nodes.is_empty() {
+            return Ok(());
+        }
+        for new_partition in new_partitions {
+            let partition_file = match new_partition
+                .get_row()
+                .get_full_name(new_partition.get_id())
+            {
+                Some(file) => file,
+                None => continue,
+            };
+            // Partitions are spread over nodes by their ids
+            let node = available_nodes[new_partition.get_id() as usize % available_nodes.len()]
+                .to_string();
+            let cluster = self.cluster.clone();
+            let superseded_files = superseded_files.clone();
+            // Downloads shouldn't hold processing of other events
+            tokio::spawn(async move {
+                if let Err(e) = cluster
+                    .warm_up_partition(node.clone(), partition_file.clone(), superseded_files)
+                    .await
+                {
+                    error!("Error warming up {} on {}: {}", partition_file, node, e);
+                }
+            });
+        }
+        Ok(())
+    }
+
     async fn schedule_repartition(&self, partition_id: u64) -> Result<(), CubeError> {
         let node = self.cluster.server_name().to_string(); // TODO find best node to run import
         let job = self
@@ -209,6 +558,23 @@ impl SchedulerImpl {
         Ok(())
     }
 
+    async fn schedule_index_backfill(&self, index_id: u64) -> Result<(), CubeError> {
+        let node = self.cluster.server_name().to_string(); // TODO find best node to run backfill
+        let job = self
+            .meta_store
+            .add_job(Job::new(
+                RowKey::Table(TableId::Indexes, index_id),
+                JobType::IndexBackfill,
+                node.to_string(),
+            ))
+            .await?;
+        if job.is_some() {
+            // TODO queue failover
+            self.cluster.notify_job_runner(node).await?;
+        }
+        Ok(())
+    }
+
     async fn schedule_wal_to_process(&self, wal_id: u64) -> Result<(), CubeError> {
         let wal_node_name = self.cluster.server_name().to_string(); // TODO move to WAL
         let job = self
@@ -243,3 +609,37 @@ impl SchedulerImpl {
         Ok(())
     }
 }
+
+lazy_static! {
+    static ref DATA_FILE_REGEX: Regex = Regex::new(r"^\d+\.(parquet|chunk\.parquet|wal)$").unwrap();
+}
+
+/// Data files in the remote storage which no partition, chunk or WAL refers to, e.g. files of
+/// dropped tables during the deletion grace period.
+pub async fn orphan_files(
+    meta_store: &dyn MetaStore,
+    remote_fs: &dyn RemoteFs,
+) -> Result<Vec<RemoteFile>, CubeError> {
+    let mut referenced = HashSet::new();
+    for partition in meta_store.partition_table().all_rows().await? {
+        if let Some(file_name) = partition.get_row().get_full_name(partition.get_id()) {
+            referenced.insert(file_name);
+        }
+    }
+    for chunk in meta_store.chunks_table().all_rows().await? {
+        referenced.insert(ChunkStore::chunk_remote_path(chunk.get_id()));
+    }
+    for table in meta_store.get_tables().await? {
+        for wal in meta_store.get_wals_for_table(table.get_id()).await? {
+            referenced.insert(WALStore::wal_remote_path(wal.get_id()));
+        }
+    }
+    Ok(remote_fs
+        .list_with_metadata("")
+        .await?
+        .into_iter()
+        .filter(|f| {
+            DATA_FILE_REGEX.is_match(f.remote_path()) && !referenced.contains(f.remote_path())
+        })
+        .collect())
+}
@@ -1,60 +1,171 @@
 mod parser;
+pub mod prepared;
 
-use log::trace;
+use log::{error, trace, warn};
 
 use async_trait::async_trait;
 use sqlparser::ast::*;
 use sqlparser::dialect::Dialect;
 
+use crate::import::http::is_url_location;
+use crate::import::s3::{is_s3_location, S3Location};
 use crate::metastore::{
-    table::Table, IdRow, ImportFormat, Index, IndexDef, MetaStoreTable, RowKey, Schema, TableId,
+    table::{Retention, Table},
+    CsvOptions, IdRow, ImportFormat, Index, IndexDef, JsonOptions, MetaStoreTable, RowKey, Schema,
+    TableId,
 };
 use crate::table::{Row, TableValue, TimestampValue};
 use crate::CubeError;
 use crate::{
     metastore::{Column, ColumnType, MetaStore},
-    store::{DataFrame, WALDataStore},
+    store::{ChunkDataStore, DataFrame, WALDataStore},
 };
+use std::collections::HashMap;
+use std::mem;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
 
 use crate::queryplanner::{QueryPlan, QueryPlanner};
 
 use crate::cluster::{Cluster, JobEvent};
+use crate::config::ConfigObj;
 
 use crate::metastore::job::JobType;
-use crate::queryplanner::query_executor::QueryExecutor;
+use crate::queryplanner::date_arithmetic::parse_interval;
+use crate::queryplanner::query_executor::{
+    arrow_to_column_type, batches_to_rows, dataframe_to_stream, QueryExecutor,
+};
+use crate::queryplanner::rollup::RollupPlan;
+use crate::queryplanner::serialized_plan::SerializedPlan;
+use crate::queryplanner::window::WindowPlan;
 use crate::sql::parser::CubeStoreParser;
+use crate::sql::prepared::PreparedStatement;
+use crate::store::insert_buffer::{BufferedRows, InsertBuffer};
 use datafusion::physical_plan::datetime_expressions::string_to_timestamp_nanos;
+use datafusion::physical_plan::RecordBatchStream;
 use datafusion::sql::parser::Statement as DFStatement;
+use futures::future::join_all;
+use futures::StreamExt;
+use itertools::Itertools;
 use parser::Statement as CubeStoreStatement;
 
+/// Settings of a single query.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueryOptions {
+    /// Partitions failing on workers are skipped and reported in warnings of the result instead
+    /// of failing the query.
+    pub best_effort: bool,
+    /// Connection running the query. Queries of a connection are cancelled together by
+    /// `SqlService::cancel_connection`.
+    pub connection_id: Option<String>,
+}
+
 #[async_trait]
 pub trait SqlService: Send + Sync {
-    async fn exec_query(&self, query: &str) -> Result<DataFrame, CubeError>;
+    async fn exec_query(&self, query: &str) -> Result<DataFrame, CubeError> {
+        self.exec_query_with_options(query, QueryOptions::default())
+            .await
+    }
+
+    async fn exec_query_with_options(
+        &self,
+        query: &str,
+        options: QueryOptions,
+    ) -> Result<DataFrame, CubeError>;
+
+    async fn exec_prepared(
+        &self,
+        statement: &PreparedStatement,
+        params: &[TableValue],
+        options: QueryOptions,
+    ) -> Result<DataFrame, CubeError>;
+
+    /// Runs a `SELECT` of CubeStore tables and streams its batches as they're produced without
+    /// converting them to rows. Results of window functions and `ROLLUP` are sent at once when
+    /// complete.
+    async fn exec_query_stream(
+        &self,
+        query: &str,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, CubeError>;
+
+    /// Schema of the `exec_query_stream` batches. Only planning is done except for window
+    /// functions and `ROLLUP` which are computed to get their columns.
+    async fn query_stream_schema(
+        &self,
+        query: &str,
+    ) -> Result<arrow::datatypes::SchemaRef, CubeError>;
+
+    /// Writes rows kept in the insert buffer longer than its age limit.
+    async fn run_insert_buffer_loop(&self) -> Result<(), CubeError>;
+
+    /// Cancels running queries of `connection_id`, e.g. after the client disconnected. Returns
+    /// the number of cancelled queries.
+    fn cancel_connection(&self, connection_id: &str) -> usize;
+
+    fn stop_processing_loops(&self) -> Result<(), CubeError>;
 }
 
 pub struct SqlServiceImpl {
     db: Arc<dyn MetaStore>,
     wal_store: Arc<dyn WALDataStore>,
+    chunk_store: Arc<dyn ChunkDataStore>,
     query_planner: Arc<dyn QueryPlanner>,
     query_executor: Arc<dyn QueryExecutor>,
     cluster: Arc<dyn Cluster>,
+    insert_buffer: Arc<InsertBuffer>,
+    booleans_as_ints: bool,
+    max_result_rows: u64,
+    stop_sender: watch::Sender<bool>,
+    stop_receiver: Mutex<watch::Receiver<bool>>,
 }
 
 impl SqlServiceImpl {
     pub fn new(
         db: Arc<dyn MetaStore>,
         wal_store: Arc<dyn WALDataStore>,
+        chunk_store: Arc<dyn ChunkDataStore>,
+        query_planner: Arc<dyn QueryPlanner>,
+        query_executor: Arc<dyn QueryExecutor>,
+        cluster: Arc<dyn Cluster>,
+        config_obj: Arc<dyn ConfigObj>,
+    ) -> Arc<SqlServiceImpl> {
+        SqlServiceImpl::with_insert_buffer(
+            db,
+            wal_store,
+            chunk_store,
+            query_planner,
+            query_executor,
+            cluster,
+            InsertBuffer::disabled(),
+            config_obj,
+        )
+    }
+
+    pub fn with_insert_buffer(
+        db: Arc<dyn MetaStore>,
+        wal_store: Arc<dyn WALDataStore>,
+        chunk_store: Arc<dyn ChunkDataStore>,
         query_planner: Arc<dyn QueryPlanner>,
         query_executor: Arc<dyn QueryExecutor>,
         cluster: Arc<dyn Cluster>,
+        insert_buffer: Arc<InsertBuffer>,
+        config_obj: Arc<dyn ConfigObj>,
     ) -> Arc<SqlServiceImpl> {
+        let (stop_sender, stop_receiver) = watch::channel(false);
         Arc::new(SqlServiceImpl {
             db,
             wal_store,
+            chunk_store,
             query_planner,
             query_executor,
             cluster,
+            insert_buffer,
+            booleans_as_ints: config_obj.booleans_as_ints(),
+            max_result_rows: config_obj.query_max_result_rows(),
+            stop_sender,
+            stop_receiver: Mutex::new(stop_receiver),
         })
     }
 
@@ -73,8 +184,14 @@ impl SqlServiceImpl {
         columns: &Vec<ColumnDef>,
         external: bool,
         location: Option<String>,
+        with_options: &Vec<SqlOption>,
         indexes: Vec<Statement>,
+        unique_key: Option<Vec<Ident>>,
+        tenant_key: Option<Ident>,
     ) -> Result<IdRow<Table>, CubeError> {
+        let unique_key_columns =
+            unique_key.map(|key| key.into_iter().map(|c| c.value).collect::<Vec<_>>());
+        let tenant_column = tenant_key.map(|c| c.value);
         let columns_to_set = convert_columns_type(columns)?;
         let mut indexes_to_create = Vec::new();
         for index in indexes.iter() {
@@ -85,7 +202,17 @@ impl SqlServiceImpl {
                 });
             }
         }
+        if !external && !with_options.is_empty() {
+            return Err(CubeError::user(
+                "WITH options are supported only for tables with LOCATION".to_string(),
+            ));
+        }
         if external {
+            let import_format = import_format(location.as_deref().unwrap_or(""), with_options)?;
+            if let Some(location) = location.as_ref().filter(|l| is_s3_location(l)) {
+                S3Location::parse(location)?;
+            }
+            let location_sha256 = location_sha256(location.as_deref().unwrap_or(""), with_options)?;
             let listener = self.cluster.job_result_listener();
             let table = self
                 .db
@@ -94,16 +221,22 @@ impl SqlServiceImpl {
                     table_name,
                     columns_to_set,
                     location,
-                    Some(ImportFormat::CSV),
+                    Some(import_format),
+                    location_sha256,
                     indexes_to_create,
+                    unique_key_columns,
+                    tenant_column,
                 )
                 .await?;
-            listener
+            let import_event = listener
                 .wait_for_job_result(
                     RowKey::Table(TableId::Tables, table.get_id()),
                     JobType::TableImport,
                 )
                 .await?;
+            if let JobEvent::Error(_, _, e) = import_event {
+                return Err(CubeError::user(format!("Create table failed: {}", e)));
+            }
             let wal_listener = self.cluster.job_result_listener();
             let wals = self.db.get_wals_for_table(table.get_id()).await?;
             let events = wal_listener
@@ -134,7 +267,10 @@ impl SqlServiceImpl {
                     columns_to_set,
                     None,
                     None,
+                    None,
                     indexes_to_create,
+                    unique_key_columns,
+                    tenant_column,
                 )
                 .await
         }
@@ -147,6 +283,20 @@ impl SqlServiceImpl {
         name: String,
         columns: &Vec<Ident>,
     ) -> Result<IdRow<Index>, CubeError> {
+        // Partitions of all indexes are expired by their key ranges, see `set_retention`
+        let table = self
+            .db
+            .get_table(schema_name.clone(), table_name.clone())
+            .await?;
+        if let Some(retention) = table.get_row().retention() {
+            if columns.first().map(|c| &c.value) != Some(retention.column()) {
+                return Err(CubeError::user(format!(
+                    "Retention column {} should be the first column of the '{}' index",
+                    retention.column(),
+                    name
+                )));
+            }
+        }
         Ok(self
             .db
             .create_index(
@@ -160,6 +310,191 @@ impl SqlServiceImpl {
             .await?)
     }
 
+    async fn add_column(
+        &self,
+        schema_name: String,
+        table_name: String,
+        column_def: &ColumnDef,
+    ) -> Result<IdRow<Table>, CubeError> {
+        let table = self.db.get_table(schema_name, table_name).await?;
+        let column = convert_columns_type(&vec![column_def.clone()])?.remove(0);
+        // Rows written before the column was added read it as its default
+        let default_value = match column_def.options.iter().find_map(|o| match &o.option {
+            ColumnOption::Default(expr) => Some(expr),
+            _ => None,
+        }) {
+            Some(expr) => extract_data(expr, &vec![&column], 0)?,
+            None => TableValue::Null,
+        };
+        self.db
+            .add_column(table.get_id(), column, default_value)
+            .await
+    }
+
+    async fn show_schemas(&self) -> Result<DataFrame, CubeError> {
+        let rows = self
+            .db
+            .get_schemas()
+            .await?
+            .into_iter()
+            .map(|s| s.get_row().get_name().to_string())
+            .sorted()
+            .map(|name| Row::new(vec![TableValue::String(name)]))
+            .collect();
+        Ok(DataFrame::new(
+            vec![Column::new("Database".to_string(), ColumnType::String, 0)],
+            rows,
+        ))
+    }
+
+    /// Tables of `schema_name` in a `Tables_in_<schema>` column or tables of all schemas with
+    /// their `Schema` if it isn't set.
+    async fn show_tables(&self, schema_name: Option<String>) -> Result<DataFrame, CubeError> {
+        if let Some(schema_name) = &schema_name {
+            self.db.get_schema_id(schema_name.to_string()).await?;
+        }
+        let tables = self
+            .db
+            .get_tables_with_path()
+            .await?
+            .into_iter()
+            .map(|t| {
+                (
+                    t.schema.get_row().get_name().to_string(),
+                    t.table.get_row().get_table_name().to_string(),
+                )
+            })
+            .filter(|(schema, _)| schema_name.as_ref().map(|s| s == schema).unwrap_or(true))
+            .sorted()
+            .collect::<Vec<_>>();
+        Ok(match schema_name {
+            Some(schema_name) => DataFrame::new(
+                vec![Column::new(
+                    format!("Tables_in_{}", schema_name),
+                    ColumnType::String,
+                    0,
+                )],
+                tables
+                    .into_iter()
+                    .map(|(_, table)| Row::new(vec![TableValue::String(table)]))
+                    .collect(),
+            ),
+            None => DataFrame::new(
+                vec![
+                    Column::new("Schema".to_string(), ColumnType::String, 0),
+                    Column::new("Table".to_string(), ColumnType::String, 1),
+                ],
+                tables
+                    .into_iter()
+                    .map(|(schema, table)| {
+                        Row::new(vec![TableValue::String(schema), TableValue::String(table)])
+                    })
+                    .collect(),
+            ),
+        })
+    }
+
+    async fn describe(
+        &self,
+        schema_name: String,
+        table_name: String,
+    ) -> Result<DataFrame, CubeError> {
+        let table = self.db.get_table(schema_name, table_name).await?;
+        let unique_key = table
+            .get_row()
+            .unique_key_columns()
+            .clone()
+            .unwrap_or_default();
+        let rows = table
+            .get_row()
+            .get_columns()
+            .iter()
+            .map(|c| {
+                let key = if unique_key.contains(c.get_name()) {
+                    "PRI"
+                } else {
+                    ""
+                };
+                Row::new(vec![
+                    TableValue::String(c.get_name().to_string()),
+                    TableValue::String(c.get_column_type().to_string()),
+                    TableValue::String("YES".to_string()),
+                    TableValue::String(key.to_string()),
+                ])
+            })
+            .collect();
+        Ok(DataFrame::new(
+            vec![
+                Column::new("Field".to_string(), ColumnType::String, 0),
+                Column::new("Type".to_string(), ColumnType::String, 1),
+                Column::new("Null".to_string(), ColumnType::String, 2),
+                Column::new("Key".to_string(), ColumnType::String, 3),
+            ],
+            rows,
+        ))
+    }
+
+    /// Partitions are expired by their key ranges so `column` has to lead sort keys of all
+    /// indexes of the table.
+    async fn set_retention(
+        &self,
+        schema_name: String,
+        table_name: String,
+        retention: &str,
+        column: &Ident,
+    ) -> Result<IdRow<Table>, CubeError> {
+        let interval = parse_interval(retention).map_err(|e| CubeError::user(e.to_string()))?;
+        if interval.months != 0 || interval.millis <= 0 {
+            return Err(CubeError::user(format!(
+                "Retention should be a positive interval of fixed length like '90 days' but found '{}'",
+                retention
+            )));
+        }
+        let table = self.db.get_table(schema_name, table_name).await?;
+        match table
+            .get_row()
+            .get_columns()
+            .iter()
+            .find(|c| c.get_name() == &column.value)
+        {
+            Some(c) if c.get_column_type() == &ColumnType::Timestamp => {}
+            Some(_) => {
+                return Err(CubeError::user(format!(
+                    "Retention column {} should be a timestamp",
+                    column.value
+                )))
+            }
+            None => {
+                return Err(CubeError::user(format!(
+                    "Column {} is not found in {}",
+                    column.value,
+                    table.get_row().get_table_name()
+                )))
+            }
+        }
+        for index in self.db.get_table_indexes(table.get_id()).await? {
+            if index.get_row().sort_key_size() == 0
+                || index.get_row().get_columns()[0].get_name() != &column.value
+            {
+                return Err(CubeError::user(format!(
+                    "Retention column {} should be the first column of the '{}' index",
+                    column.value,
+                    index.get_row().get_name()
+                )));
+            }
+        }
+        Ok(self
+            .db
+            .set_table_retention(
+                table.get_id(),
+                Some(Retention::new(
+                    column.value.to_string(),
+                    interval.millis as u64,
+                )),
+            )
+            .await?)
+    }
+
     async fn insert_data<'a>(
         &'a self,
         schema_name: String,
@@ -189,13 +524,36 @@ impl SqlServiceImpl {
             real_col.push(c);
         }
 
+        if self.insert_buffer.accepts(data.len()) {
+            let data_frame = parse_chunk(data, &real_col)?;
+            if let Some(rows) = self.insert_buffer.add(table.get_id(), data_frame).await? {
+                // Rows are logged by the buffer so the insert succeeds even if they aren't written
+                if let Err(e) = self.write_buffered(vec![rows]).await {
+                    error!("Error writing buffered inserts: {}", e);
+                }
+            }
+            return Ok(data.len() as u64);
+        }
+
         let chunk_len = self.wal_store.get_wal_chunk_size();
+        let data_frames = data
+            .chunks(chunk_len)
+            .map(|rows_chunk| parse_chunk(rows_chunk, &real_col))
+            .collect::<Result<Vec<_>, _>>()?;
+        self.write_wals(table, data_frames).await?;
+
+        Ok(data.len() as u64)
+    }
 
+    async fn write_wals(
+        &self,
+        table: IdRow<Table>,
+        data_frames: Vec<DataFrame>,
+    ) -> Result<(), CubeError> {
         let mut wal_ids = Vec::new();
 
         let listener = self.cluster.job_result_listener();
-        for rows_chunk in data.chunks(chunk_len) {
-            let data_frame = parse_chunk(rows_chunk, &real_col)?;
+        for data_frame in data_frames {
             wal_ids.push(
                 self.wal_store
                     .add_wal(table.clone(), data_frame)
@@ -219,54 +577,263 @@ impl SqlServiceImpl {
             }
         }
 
-        Ok(data.len() as u64)
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct MySqlDialectWithBackTicks {}
+    /// Writes rows taken from the insert buffer. Rows which fail to be written are returned to
+    /// the buffer and rows of dropped tables are discarded.
+    async fn write_buffered(&self, buffered: Vec<BufferedRows>) -> Result<(), CubeError> {
+        if buffered.is_empty() {
+            return Ok(());
+        }
+        let tables = self.db.get_tables().await?;
+        let chunk_len = self.wal_store.get_wal_chunk_size();
+        let mut result = Ok(());
+        for rows in buffered {
+            let table = match tables.iter().find(|t| t.get_id() == rows.table_id()) {
+                Some(table) => table.clone(),
+                None => {
+                    warn!(
+                        "Discarding {} buffered rows of dropped table {}",
+                        rows.data().len(),
+                        rows.table_id()
+                    );
+                    self.insert_buffer.written(rows).await?;
+                    continue;
+                }
+            };
+            let data_frames = rows
+                .data()
+                .get_rows()
+                .chunks(chunk_len)
+                .map(|c| DataFrame::new(rows.data().get_columns().clone(), c.to_vec()))
+                .collect();
+            match self.write_wals(table, data_frames).await {
+                Ok(()) => self.insert_buffer.written(rows).await?,
+                Err(e) => {
+                    self.insert_buffer.restore(rows).await;
+                    if result.is_ok() {
+                        result = Err(e);
+                    }
+                }
+            }
+        }
+        result
+    }
 
-impl Dialect for MySqlDialectWithBackTicks {
-    fn is_delimited_identifier_start(&self, ch: char) -> bool {
-        ch == '"' || ch == '`'
+    /// Rows a statement writes to the table go after rows of acknowledged inserts still in the
+    /// buffer, so buffered rows of the table are written first.
+    async fn write_buffered_of(&self, table_id: u64) -> Result<(), CubeError> {
+        if self.insert_buffer.read_your_writes() {
+            self.write_buffered(self.insert_buffer.take_table(table_id).await)
+                .await?;
+        }
+        Ok(())
     }
 
-    fn is_identifier_start(&self, ch: char) -> bool {
-        // See https://dev.mysql.com/doc/refman/8.0/en/identifiers.html.
-        // We don't yet support identifiers beginning with numbers, as that
-        // makes it hard to distinguish numeric literals.
-        (ch >= 'a' && ch <= 'z')
-            || (ch >= 'A' && ch <= 'Z')
-            || ch == '_'
-            || ch == '$'
-            || (ch >= '\u{0080}' && ch <= '\u{ffff}')
+    async fn streamed_query_plan(&self, query: Box<Query>) -> Result<SerializedPlan, CubeError> {
+        match self
+            .query_planner
+            .logical_plan(DFStatement::Statement(Statement::Query(query)))
+            .await?
+        {
+            QueryPlan::Select(plan) => Ok(plan),
+            QueryPlan::Meta(_) => Err(CubeError::user(
+                "Only queries of CubeStore tables can be streamed".to_string(),
+            )),
+        }
     }
 
-    fn is_identifier_part(&self, ch: char) -> bool {
-        self.is_identifier_start(ch) || (ch >= '0' && ch <= '9')
+    /// SELECTs see rows of acknowledged inserts still in the buffer along with the rows of
+    /// their tables.
+    async fn with_buffered_rows(&self, plan: SerializedPlan) -> Result<SerializedPlan, CubeError> {
+        if !self.insert_buffer.read_your_writes() {
+            return Ok(plan);
+        }
+        let mut buffered = HashMap::new();
+        for index_snapshot in plan.index_snapshots() {
+            let table = index_snapshot.table();
+            if buffered.contains_key(&table.get_id()) {
+                continue;
+            }
+            if let Some(rows) = self.insert_buffer.rows_of(table).await? {
+                buffered.insert(table.get_id(), rows);
+            }
+        }
+        plan.with_buffered_rows(&buffered)
     }
-}
 
-#[async_trait]
-impl SqlService for SqlServiceImpl {
-    async fn exec_query(&self, q: &str) -> Result<DataFrame, CubeError> {
-        if !q.to_lowercase().starts_with("insert") {
-            trace!("Query: '{}'", q);
+    /// Rows of `query` are streamed into new chunks of the table. Chunks are activated together
+    /// once all of them are written so a failed insert leaves none of its rows visible.
+    async fn insert_select(
+        &self,
+        schema_name: String,
+        table_name: String,
+        columns: &Vec<Ident>,
+        query: Box<Query>,
+    ) -> Result<u64, CubeError> {
+        let table = self
+            .db
+            .get_table(schema_name.clone(), table_name.clone())
+            .await?;
+        self.write_buffered_of(table.get_id()).await?;
+        let table_columns = table.get_row().get_columns();
+        let target_columns = if columns.is_empty() {
+            table_columns.clone()
+        } else {
+            columns
+                .iter()
+                .map(|column| {
+                    table_columns
+                        .iter()
+                        .find(|c| c.get_name() == &column.value)
+                        .cloned()
+                        .ok_or_else(|| {
+                            CubeError::user(format!(
+                                "Column {} is not found in {}.{}",
+                                column.value, schema_name, table_name
+                            ))
+                        })
+                })
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let stream = if combined_on_router(&query)? {
+            dataframe_to_stream(&self.select(query, &QueryOptions::default()).await?)?
+        } else {
+            let plan = match self
+                .query_planner
+                .logical_plan(DFStatement::Statement(Statement::Query(query)))
+                .await?
+            {
+                QueryPlan::Select(plan) => self.with_buffered_rows(plan).await?,
+                QueryPlan::Meta(_) => {
+                    return Err(CubeError::user(
+                        "INSERT ... SELECT should select from CubeStore tables".to_string(),
+                    ))
+                }
+            };
+            self.query_executor
+                .execute_router_plan_stream(plan, self.cluster.clone())
+                .await?
+        };
+        check_insert_types(&stream.schema(), &target_columns)?;
+        let columns = target_columns
+            .iter()
+            .enumerate()
+            .map(|(i, c)| c.replace_index(i))
+            .collect::<Vec<_>>();
+
+        let mut chunk_ids = Vec::new();
+        let inserted = match self
+            .write_selected_rows(stream, table.get_id(), columns, &mut chunk_ids)
+            .await
+        {
+            Ok(inserted) => self
+                .db
+                .swap_chunks(Vec::new(), chunk_ids.clone())
+                .await
+                .map(|_| inserted),
+            Err(e) => Err(e),
+        };
+        if inserted.is_err() {
+            for chunk_id in chunk_ids.iter() {
+                if let Err(e) = self.db.delete_chunk(*chunk_id).await {
+                    warn!("Can't delete chunk {} of failed insert: {}", chunk_id, e);
+                }
+            }
+            self.chunk_store.evict_in_memory_chunks(chunk_ids);
         }
-        if let Some(data_frame) = SqlServiceImpl::handle_workbench_queries(q) {
-            return Ok(data_frame);
+        inserted
+    }
+
+    /// Writes rows of `stream` to inactive chunks by portions of the WAL size. Ids of the written
+    /// chunks are collected to `chunk_ids` even if writing fails.
+    async fn write_selected_rows(
+        &self,
+        mut stream: Pin<Box<dyn RecordBatchStream + Send>>,
+        table_id: u64,
+        columns: Vec<Column>,
+        chunk_ids: &mut Vec<u64>,
+    ) -> Result<u64, CubeError> {
+        let chunk_len = self.wal_store.get_wal_chunk_size();
+        let mut rows = Vec::new();
+        let mut inserted = 0;
+        loop {
+            let batch = stream.next().await.transpose()?;
+            if let Some(batch) = &batch {
+                for row in batches_to_rows(std::slice::from_ref(batch)) {
+                    rows.push(row?);
+                }
+            }
+            if rows.len() >= chunk_len || (batch.is_none() && !rows.is_empty()) {
+                inserted += rows.len() as u64;
+                let data = DataFrame::new(columns.clone(), mem::take(&mut rows));
+                chunk_ids.append(&mut self.chunk_store.add_inactive_chunks(table_id, data).await?);
+            }
+            if batch.is_none() {
+                return Ok(inserted);
+            }
         }
-        let ast = {
-            let replaced_quote = q.replace("\\'", "''");
-            let mut parser = CubeStoreParser::new(&replaced_quote)?;
-            parser.parse_statement()?
+    }
+
+    /// Rows matching `selection` are selected and written to tombstone chunks as is, scans and
+    /// compaction drop rows equal to them.
+    async fn delete_data(
+        &self,
+        table_name: &ObjectName,
+        selection: &Option<Expr>,
+    ) -> Result<u64, CubeError> {
+        let nv = &table_name.0;
+        if nv.len() != 2 {
+            return Err(CubeError::user(format!(
+                "Schema's name should be present in query (boo.table1). Your table was '{}'",
+                table_name
+            )));
+        }
+        let table = self
+            .db
+            .get_table(nv[0].value.clone(), nv[1].value.clone())
+            .await?;
+        // Tombstones delete only rows written before them
+        self.write_buffered_of(table.get_id()).await?;
+        let select = match selection {
+            Some(selection) => format!("SELECT * FROM {} WHERE {}", table_name, selection),
+            None => format!("SELECT * FROM {}", table_name),
         };
-        // trace!("AST is: {:?}", ast);
+        let q = match CubeStoreParser::new(&select)?.parse_statement()? {
+            CubeStoreStatement::Statement(Statement::Query(q)) => q,
+            _ => {
+                return Err(CubeError::internal(format!(
+                    "Can't parse select of deleted rows: {}",
+                    select
+                )))
+            }
+        };
+        // Rows of failed partitions would stay undeleted
+        let rows = self.select(q, &QueryOptions::default()).await?.into_rows();
+        let deleted = rows.len() as u64;
+        if deleted > 0 {
+            self.chunk_store
+                .add_tombstones(
+                    table.get_id(),
+                    DataFrame::new(table.get_row().get_columns().clone(), rows),
+                )
+                .await?;
+        }
+        Ok(deleted)
+    }
+}
+
+impl SqlServiceImpl {
+    async fn exec_statement(
+        &self,
+        q: &str,
+        ast: CubeStoreStatement,
+        options: &QueryOptions,
+    ) -> Result<DataFrame, CubeError> {
         match ast {
             CubeStoreStatement::Statement(Statement::ShowVariable { variable }) => {
                 match variable.value.to_lowercase() {
-                    s if s == "schemas" => Ok(DataFrame::from(self.db.get_schemas().await?)),
-                    s if s == "tables" => Ok(DataFrame::from(self.db.get_tables().await?)),
                     s if s == "chunks" => {
                         Ok(DataFrame::from(self.db.chunks_table().all_rows().await?))
                     }
@@ -297,9 +864,12 @@ impl SqlService for SqlServiceImpl {
                         columns,
                         external,
                         location,
+                        with_options,
                         ..
                     },
                 indexes,
+                unique_key,
+                tenant_key,
             } => {
                 let nv = &name.0;
                 if nv.len() != 2 {
@@ -318,7 +888,10 @@ impl SqlService for SqlServiceImpl {
                         &columns,
                         external,
                         location,
+                        &with_options,
                         indexes,
+                        unique_key,
+                        tenant_key,
                     )
                     .await?;
                 Ok(DataFrame::from(vec![res]))
@@ -377,20 +950,75 @@ impl SqlService for SqlServiceImpl {
                 }
                 Ok(DataFrame::new(vec![], vec![]))
             }
+            CubeStoreStatement::Statement(Statement::AlterTable {
+                name,
+                operation: AlterTableOperation::AddColumn { column_def },
+            }) => {
+                if name.0.len() != 2 {
+                    return Err(CubeError::user(format!(
+                        "Schema's name should be present in table name but found: {}",
+                        name
+                    )));
+                }
+                let res = self
+                    .add_column(
+                        name.0[0].value.to_string(),
+                        name.0[1].value.to_string(),
+                        &column_def,
+                    )
+                    .await?;
+                Ok(DataFrame::from(vec![res]))
+            }
+            CubeStoreStatement::SetRetention {
+                table_name,
+                retention,
+                column,
+            } => {
+                if table_name.0.len() != 2 {
+                    return Err(CubeError::user(format!(
+                        "Schema's name should be present in table name but found: {}",
+                        table_name
+                    )));
+                }
+                let res = self
+                    .set_retention(
+                        table_name.0[0].value.to_string(),
+                        table_name.0[1].value.to_string(),
+                        &retention,
+                        &column,
+                    )
+                    .await?;
+                Ok(DataFrame::from(vec![res]))
+            }
+            CubeStoreStatement::ShowSchemas => self.show_schemas().await,
+            CubeStoreStatement::ShowTables { schema_name } => {
+                self.show_tables(schema_name.map(|s| s.value)).await
+            }
+            CubeStoreStatement::Describe { table_name } => {
+                if table_name.0.len() != 2 {
+                    return Err(CubeError::user(format!(
+                        "Schema's name should be present in table name but found: {}",
+                        table_name
+                    )));
+                }
+                self.describe(
+                    table_name.0[0].value.to_string(),
+                    table_name.0[1].value.to_string(),
+                )
+                .await
+            }
+            CubeStoreStatement::Explain {
+                statement: Statement::Query(q),
+            } => self.explain(q).await,
+            CubeStoreStatement::RestoreMetastore => {
+                self.db.restore_from_remote().await?;
+                Ok(DataFrame::new(vec![], vec![]))
+            }
             CubeStoreStatement::Statement(Statement::Insert {
                 table_name,
                 columns,
                 source,
             }) => {
-                let data = if let SetExpr::Values(Values(data_series)) = &source.body {
-                    data_series
-                } else {
-                    return Err(CubeError::user(format!(
-                        "Data should be present in query. Your query was '{}'",
-                        q
-                    )));
-                };
-
                 let nv = &table_name.0;
                 if nv.len() != 2 {
                     return Err(CubeError::user(format!("Schema's name should be present in query (boo.table1). Your query was '{}'", q)));
@@ -398,31 +1026,501 @@ impl SqlService for SqlServiceImpl {
                 let schema_name = &nv[0].value;
                 let table_name = &nv[1].value;
 
-                self.insert_data(schema_name.clone(), table_name.clone(), &columns, data)
-                    .await?;
-                Ok(DataFrame::new(vec![], vec![]))
-            }
-            CubeStoreStatement::Statement(Statement::Query(q)) => {
-                let logical_plan = self
-                    .query_planner
-                    .logical_plan(DFStatement::Statement(Statement::Query(q)))
-                    .await?;
-                // TODO distribute and combine
-                let res = match logical_plan {
-                    QueryPlan::Meta(logical_plan) => {
-                        self.query_planner.execute_meta_plan(logical_plan).await?
+                match &source.body {
+                    SetExpr::Values(Values(data)) => {
+                        self.insert_data(schema_name.clone(), table_name.clone(), &columns, data)
+                            .await?;
+                    }
+                    _ => {
+                        self.insert_select(
+                            schema_name.clone(),
+                            table_name.clone(),
+                            &columns,
+                            source,
+                        )
+                        .await?;
+                    }
+                }
+                Ok(DataFrame::new(vec![], vec![]))
+            }
+            CubeStoreStatement::Statement(Statement::Delete {
+                table_name,
+                selection,
+            }) => {
+                self.delete_data(&table_name, &selection).await?;
+                Ok(DataFrame::new(vec![], vec![]))
+            }
+            CubeStoreStatement::Statement(Statement::Query(q)) => {
+                let data_frame = match self.select(q.clone(), options).await {
+                    Err(e) if e.is_schema_drift() => {
+                        // Table has been altered between planning and execution: plan once again
+                        warn!("Re-planning query after schema drift: {}", e);
+                        self.select(q, options).await?
+                    }
+                    res => res?,
+                };
+                // Only results sent to clients are limited and converted, selects of DELETE and
+                // INSERT ... SELECT aren't
+                let rows = data_frame.len() as u64;
+                if self.max_result_rows > 0 && rows > self.max_result_rows {
+                    return Err(CubeError::user(format!(
+                        "Query result has {} rows which exceeds the limit of {} rows. Consider adding LIMIT to the query",
+                        rows, self.max_result_rows
+                    )));
+                }
+                if self.booleans_as_ints {
+                    Ok(data_frame.with_booleans_as_ints())
+                } else {
+                    Ok(data_frame)
+                }
+            }
+            _ => Err(CubeError::user(format!("Unsupported SQL: '{}'", q))),
+        }
+    }
+
+    async fn select(
+        &self,
+        mut q: Box<Query>,
+        options: &QueryOptions,
+    ) -> Result<DataFrame, CubeError> {
+        let window_plan = WindowPlan::extract(&mut q)?;
+        let res = match RollupPlan::extract(&q)? {
+            Some(rollup_plan) => {
+                let results = join_all(
+                    rollup_plan
+                        .branches()
+                        .iter()
+                        .map(|branch| self.execute_select(Box::new(branch.clone()), options)),
+                )
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()?;
+                rollup_plan.combine(results)?
+            }
+            None => self.execute_select(q, options).await?,
+        };
+        match window_plan {
+            Some(window_plan) => window_plan.apply(res),
+            None => Ok(res),
+        }
+    }
+
+    /// Split plan of the query in a `plan` column, a node per row. Queries of system tables are
+    /// executed by the router alone.
+    async fn explain(&self, q: Box<Query>) -> Result<DataFrame, CubeError> {
+        let logical_plan = self
+            .query_planner
+            .logical_plan(DFStatement::Statement(Statement::Query(q)))
+            .await?;
+        let lines = match logical_plan {
+            QueryPlan::Meta(_) => vec!["Meta query executed by the router".to_string()],
+            QueryPlan::Select(serialized) => {
+                let serialized = self.with_buffered_rows(serialized).await?;
+                self.query_executor
+                    .explain_router_plan(serialized, self.cluster.clone())
+                    .await?
+            }
+        };
+        Ok(DataFrame::new(
+            vec![Column::new("plan".to_string(), ColumnType::String, 0)],
+            lines
+                .into_iter()
+                .map(|line| Row::new(vec![TableValue::String(line)]))
+                .collect(),
+        ))
+    }
+
+    async fn execute_select(
+        &self,
+        q: Box<Query>,
+        options: &QueryOptions,
+    ) -> Result<DataFrame, CubeError> {
+        let logical_plan = self
+            .query_planner
+            .logical_plan(DFStatement::Statement(Statement::Query(q)))
+            .await?;
+        // TODO distribute and combine
+        let res = match logical_plan {
+            QueryPlan::Meta(logical_plan) => {
+                self.query_planner.execute_meta_plan(logical_plan).await?
+            }
+            QueryPlan::Select(serialized) => {
+                let serialized = self
+                    .with_buffered_rows(serialized)
+                    .await?
+                    .with_best_effort(options.best_effort);
+                match &options.connection_id {
+                    Some(connection_id) => {
+                        self.query_executor
+                            .execute_router_plan_for_connection(
+                                serialized,
+                                self.cluster.clone(),
+                                connection_id.clone(),
+                            )
+                            .await?
                     }
-                    QueryPlan::Select(serialized) => {
+                    None => {
                         self.query_executor
                             .execute_router_plan(serialized, self.cluster.clone())
                             .await?
                     }
-                };
-                Ok(res)
+                }
+            }
+        };
+        Ok(res)
+    }
+}
+
+#[derive(Debug)]
+pub struct MySqlDialectWithBackTicks {}
+
+impl Dialect for MySqlDialectWithBackTicks {
+    fn is_delimited_identifier_start(&self, ch: char) -> bool {
+        ch == '"' || ch == '`'
+    }
+
+    fn is_identifier_start(&self, ch: char) -> bool {
+        // See https://dev.mysql.com/doc/refman/8.0/en/identifiers.html.
+        // We don't yet support identifiers beginning with numbers, as that
+        // makes it hard to distinguish numeric literals.
+        (ch >= 'a' && ch <= 'z')
+            || (ch >= 'A' && ch <= 'Z')
+            || ch == '_'
+            || ch == '$'
+            || (ch >= '\u{0080}' && ch <= '\u{ffff}')
+    }
+
+    fn is_identifier_part(&self, ch: char) -> bool {
+        self.is_identifier_start(ch) || (ch >= '0' && ch <= '9')
+    }
+}
+
+#[async_trait]
+impl SqlService for SqlServiceImpl {
+    async fn exec_query_with_options(
+        &self,
+        q: &str,
+        options: QueryOptions,
+    ) -> Result<DataFrame, CubeError> {
+        if !q.to_lowercase().starts_with("insert") {
+            trace!("Query: '{}'", q);
+        }
+        if let Some(data_frame) = SqlServiceImpl::handle_workbench_queries(q) {
+            return Ok(data_frame);
+        }
+        let ast = {
+            let replaced_quote = q.replace("\\'", "''");
+            let mut parser = CubeStoreParser::new(&replaced_quote)?;
+            parser.parse_statement()?
+        };
+        // trace!("AST is: {:?}", ast);
+        self.exec_statement(q, ast, &options).await
+    }
+
+    async fn exec_prepared(
+        &self,
+        statement: &PreparedStatement,
+        params: &[TableValue],
+        options: QueryOptions,
+    ) -> Result<DataFrame, CubeError> {
+        trace!("Prepared query: '{}'", statement.query());
+        let ast = statement.bind(params)?;
+        self.exec_statement(statement.query(), ast, &options).await
+    }
+
+    async fn exec_query_stream(
+        &self,
+        q: &str,
+    ) -> Result<Pin<Box<dyn RecordBatchStream + Send>>, CubeError> {
+        trace!("Streamed query: '{}'", q);
+        let query = parse_streamed_query(q)?;
+        if combined_on_router(&query)? {
+            return dataframe_to_stream(&self.select(query, &QueryOptions::default()).await?);
+        }
+        let plan = self.streamed_query_plan(query).await?;
+        self.query_executor
+            .execute_router_plan_stream(self.with_buffered_rows(plan).await?, self.cluster.clone())
+            .await
+    }
+
+    async fn query_stream_schema(&self, q: &str) -> Result<arrow::datatypes::SchemaRef, CubeError> {
+        let query = parse_streamed_query(q)?;
+        if combined_on_router(&query)? {
+            return Ok(
+                dataframe_to_stream(&self.select(query, &QueryOptions::default()).await?)?.schema(),
+            );
+        }
+        let plan = self.streamed_query_plan(query).await?;
+        self.query_executor
+            .router_plan_schema(plan, self.cluster.clone())
+            .await
+    }
+
+    async fn run_insert_buffer_loop(&self) -> Result<(), CubeError> {
+        if !self.insert_buffer.is_enabled() {
+            return Ok(());
+        }
+        let mut stop_receiver = self.stop_receiver.lock().await;
+        loop {
+            tokio::select! {
+                Some(stopped) = stop_receiver.recv() => {
+                    if stopped {
+                        return Ok(());
+                    } else {
+                        continue;
+                    }
+                }
+                _ = tokio::time::delay_for(Duration::from_secs(1)) => {}
+            };
+            if let Err(e) = self
+                .write_buffered(self.insert_buffer.take_expired().await)
+                .await
+            {
+                error!("Error writing buffered inserts: {}", e);
+            }
+        }
+    }
+
+    fn cancel_connection(&self, connection_id: &str) -> usize {
+        self.query_executor.cancel_connection(connection_id)
+    }
+
+    fn stop_processing_loops(&self) -> Result<(), CubeError> {
+        Ok(self.stop_sender.broadcast(true)?)
+    }
+}
+
+fn parse_streamed_query(q: &str) -> Result<Box<Query>, CubeError> {
+    let replaced_quote = q.replace("\\'", "''");
+    match CubeStoreParser::new(&replaced_quote)?.parse_statement()? {
+        CubeStoreStatement::Statement(Statement::Query(q)) => Ok(q),
+        _ => Err(CubeError::user(format!(
+            "Only SELECT queries can be streamed: {}",
+            q
+        ))),
+    }
+}
+
+/// Window functions and `ROLLUP` are computed on the router over the complete result, so such
+/// queries are run by `select` and their results can't be streamed as they're produced.
+fn combined_on_router(query: &Query) -> Result<bool, CubeError> {
+    Ok(WindowPlan::extract(&mut query.clone())?.is_some() || RollupPlan::extract(query)?.is_some())
+}
+
+/// Columns of a select are inserted by position so their types should match types of the
+/// target columns. Decimals of any scale are converted to the scale of the target column.
+fn check_insert_types(
+    schema: &arrow::datatypes::SchemaRef,
+    columns: &Vec<Column>,
+) -> Result<(), CubeError> {
+    if schema.fields().len() != columns.len() {
+        return Err(CubeError::user(format!(
+            "INSERT has {} target columns but SELECT returns {} columns",
+            columns.len(),
+            schema.fields().len()
+        )));
+    }
+    for (field, column) in schema.fields().iter().zip(columns.iter()) {
+        let select_type = arrow_to_column_type(field.data_type().clone())?;
+        let matches = match (&select_type, column.get_column_type()) {
+            (ColumnType::Decimal { .. }, ColumnType::Decimal { .. }) => true,
+            (select_type, column_type) => select_type == column_type,
+        };
+        if !matches {
+            return Err(CubeError::user(format!(
+                "Can't insert {} column '{}' of SELECT into {} column '{}'",
+                select_type,
+                field.name(),
+                column.get_column_type(),
+                column.get_name()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Files are imported as CSV unless `format = 'parquet'` is set or the file has `.parquet` extension.
+fn import_format(location: &str, with_options: &Vec<SqlOption>) -> Result<ImportFormat, CubeError> {
+    let mut format = location_format(location);
+    let mut format_options = Vec::new();
+    for option in with_options.iter() {
+        match (option.name.value.to_lowercase().as_str(), &option.value) {
+            ("format", Value::SingleQuotedString(v)) => {
+                format = match v.to_lowercase().as_str() {
+                    "csv" => "csv",
+                    "parquet" => "parquet",
+                    "json" | "jsonl" | "ndjson" => "json",
+                    _ => return Err(CubeError::user(format!("Unsupported import format: {}", v))),
+                }
+            }
+            // Applies to the location rather than the format
+            ("sha256", _) => {}
+            _ => format_options.push(option),
+        }
+    }
+    match format {
+        "json" => json_import_format(&format_options),
+        "parquet" if !format_options.is_empty() => Err(CubeError::user(format!(
+            "CSV options can't be used to import parquet file {}: {}",
+            location,
+            format_options.iter().join(", ")
+        ))),
+        "parquet" => Ok(ImportFormat::Parquet),
+        _ => csv_import_format(&format_options),
+    }
+}
+
+/// Expected SHA-256 of an HTTP(S) location set by the `sha256` option.
+fn location_sha256(
+    location: &str,
+    with_options: &Vec<SqlOption>,
+) -> Result<Option<String>, CubeError> {
+    let mut res = None;
+    for option in with_options
+        .iter()
+        .filter(|o| o.name.value.to_lowercase() == "sha256")
+    {
+        if !is_url_location(location) {
+            return Err(CubeError::user(format!(
+                "sha256 option is supported only for HTTP(S) locations but {} found",
+                location
+            )));
+        }
+        match &option.value {
+            Value::SingleQuotedString(v)
+                if v.len() == 64 && v.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                res = Some(v.to_lowercase())
+            }
+            value => {
+                return Err(CubeError::user(format!(
+                    "Invalid sha256 option value {}: 64 hex digits expected",
+                    value
+                )))
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Format by the file extension preceding the compression one. CSV is the default.
+/// Query strings of URLs, e.g. signatures, are ignored.
+fn location_format(location: &str) -> &'static str {
+    let location = if is_url_location(location) {
+        location
+            .split(|c| c == '?' || c == '#')
+            .next()
+            .unwrap_or("")
+    } else {
+        location
+    };
+    let location = location.to_lowercase();
+    let location = [".gz", ".gzip", ".zst", ".zstd"]
+        .iter()
+        .find(|ext| location.ends_with(*ext))
+        .map(|ext| &location[..location.len() - ext.len()])
+        .unwrap_or(&location);
+    if location.ends_with(".parquet") {
+        "parquet"
+    } else if location.ends_with(".json")
+        || location.ends_with(".jsonl")
+        || location.ends_with(".ndjson")
+    {
+        "json"
+    } else {
+        "csv"
+    }
+}
+
+/// JSON lines options. `paths` maps columns to nested fields as `'column=field.nested, ...'`.
+fn json_import_format(with_options: &[&SqlOption]) -> Result<ImportFormat, CubeError> {
+    let mut options = JsonOptions::default();
+    for option in with_options.iter() {
+        match (option.name.value.to_lowercase().as_str(), &option.value) {
+            ("strict", Value::Boolean(v)) => options.strict = *v,
+            ("paths", Value::SingleQuotedString(v)) => {
+                for path in v.split(',').filter(|p| !p.trim().is_empty()) {
+                    match path
+                        .splitn(2, '=')
+                        .map(|s| s.trim())
+                        .collect_vec()
+                        .as_slice()
+                    {
+                        [column, field] if !column.is_empty() && !field.is_empty() => {
+                            options.paths.push((column.to_string(), field.to_string()))
+                        }
+                        _ => {
+                            return Err(CubeError::user(format!(
+                                "Invalid paths option: '{}' should be column=field.path",
+                                path
+                            )))
+                        }
+                    }
+                }
+            }
+            ("max_errors", Value::Number(v)) => {
+                options.max_errors = v.parse().map_err(|_| {
+                    CubeError::user(format!("Invalid max_errors option value: {}", v))
+                })?
+            }
+            (_, value) => {
+                return Err(CubeError::user(format!(
+                    "Unsupported import option {} = {}",
+                    option.name, value
+                )))
+            }
+        }
+    }
+    Ok(ImportFormat::JSONLines(options))
+}
+
+fn csv_import_format(with_options: &[&SqlOption]) -> Result<ImportFormat, CubeError> {
+    if with_options.is_empty() {
+        return Ok(ImportFormat::CSV);
+    }
+    let mut options = CsvOptions::default();
+    for option in with_options.iter() {
+        let name = option.name.value.to_lowercase();
+        match (name.as_str(), &option.value) {
+            ("delimiter", Value::SingleQuotedString(v)) => options.delimiter = csv_char(&name, v)?,
+            ("quote", Value::SingleQuotedString(v)) => options.quote = csv_char(&name, v)?,
+            ("escape", Value::SingleQuotedString(v)) => options.escape = Some(csv_char(&name, v)?),
+            ("with_header", Value::Boolean(v)) => options.with_header = *v,
+            ("max_errors", Value::Number(v)) => {
+                options.max_errors = v.parse().map_err(|_| {
+                    CubeError::user(format!("Invalid max_errors option value: {}", v))
+                })?
+            }
+            (_, value) => {
+                return Err(CubeError::user(format!(
+                    "Unsupported import option {} = {}",
+                    option.name, value
+                )))
             }
-            _ => Err(CubeError::user(format!("Unsupported SQL: '{}'", q))),
         }
     }
+    if Some(options.delimiter) == options.escape || options.delimiter == options.quote {
+        return Err(CubeError::user(format!(
+            "CSV delimiter should differ from quote and escape characters: {:?}",
+            options
+        )));
+    }
+    Ok(ImportFormat::CSVWithOptions(options))
+}
+
+/// Single character CSV option. `\t` stands for the tab character.
+fn csv_char(name: &str, value: &str) -> Result<char, CubeError> {
+    if value == "\\t" {
+        return Ok('\t');
+    }
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(c),
+        _ => Err(CubeError::user(format!(
+            "Import option {} should be a single character but '{}' found",
+            name, value
+        ))),
+    }
 }
 
 fn convert_columns_type(columns: &Vec<ColumnDef>) -> Result<Vec<Column>, CubeError> {
@@ -612,8 +1710,18 @@ fn extract_data(cell: &Expr, column: &Vec<&Column>, i: usize) -> Result<TableVal
                 }
             },
             ColumnType::Boolean => match cell {
-                Expr::Value(Value::SingleQuotedString(v)) => {
-                    TableValue::Boolean(v.to_lowercase() == "true")
+                // Unknown strings are rejected rather than stored as false
+                Expr::Value(Value::SingleQuotedString(v)) | Expr::Value(Value::Number(v)) => {
+                    match v.to_lowercase().as_str() {
+                        "true" | "1" => TableValue::Boolean(true),
+                        "false" | "0" => TableValue::Boolean(false),
+                        _ => {
+                            return Err(CubeError::user(format!(
+                                "Can't parse boolean from, {:?}",
+                                cell
+                            )))
+                        }
+                    }
                 }
                 Expr::Value(Value::Boolean(b)) => TableValue::Boolean(*b),
                 x => {
@@ -633,21 +1741,29 @@ mod tests {
     use super::*;
     use crate::cluster::MockCluster;
     use crate::config::Config;
+    use crate::import::http::test_server::serve;
+    use crate::import::s3::S3ImportProgress;
     use crate::metastore::RocksMetaStore;
-    use crate::queryplanner::query_executor::MockQueryExecutor;
-    use crate::queryplanner::MockQueryPlanner;
+    use crate::queryplanner::query_executor::{MockQueryExecutor, QueryExecutorImpl};
+    use crate::queryplanner::{MockQueryPlanner, QueryPlannerImpl};
     use crate::remotefs::LocalDirRemoteFs;
-    use crate::store::WALStore;
+    use crate::store::{ChunkStore, MockChunkDataStore, WALStore};
+    use crate::table::parquet::ParquetTableStore;
+    use crate::table::TableStore;
+    use async_compression::tokio_02::bufread::{GzipEncoder, ZstdEncoder};
     use itertools::Itertools;
     use rand::distributions::Alphanumeric;
     use rand::{thread_rng, Rng};
     use rocksdb::{Options, DB};
+    use sha2::{Digest, Sha256};
     use std::collections::HashSet;
     use std::fs::File;
     use std::io::Write;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::Ordering;
     use std::time::Duration;
     use std::{env, fs};
+    use tokio::io::AsyncReadExt;
     use uuid::Uuid;
 
     #[actix_rt::test]
@@ -670,9 +1786,11 @@ mod tests {
             let service = SqlServiceImpl::new(
                 meta_store,
                 store,
+                Arc::new(MockChunkDataStore::new()),
                 Arc::new(MockQueryPlanner::new()),
                 Arc::new(MockQueryExecutor::new()),
                 Arc::new(MockCluster::new()),
+                config.config_obj(),
             );
             let i = service.exec_query("CREATE SCHEMA foo").await.unwrap();
             assert_eq!(
@@ -707,9 +1825,11 @@ mod tests {
             let service = SqlServiceImpl::new(
                 meta_store,
                 store,
+                Arc::new(MockChunkDataStore::new()),
                 Arc::new(MockQueryPlanner::new()),
                 Arc::new(MockQueryExecutor::new()),
                 Arc::new(MockCluster::new()),
+                config.config_obj(),
             );
             let i = service.exec_query("CREATE SCHEMA Foo").await.unwrap();
             assert_eq!(
@@ -857,278 +1977,370 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn decimal() {
-        Config::test("decimal").update_config(|mut c| {
-            c.partition_split_threshold = 2;
-            c
-        }).start_test(async move |services| {
-            let service = services.sql_service;
-
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-            let _ = service
-                .exec_query("CREATE TABLE foo.values (id int, dec_value decimal, dec_value_1 decimal(18, 2))")
-                .await
-                .unwrap();
-
-            service
-                .exec_query("INSERT INTO foo.values (id, dec_value, dec_value_1) VALUES (1, -153, 1), (2, 20.01, 3.5), (3, 20.30, 12.3), (4, 120.30, 43.12), (5, NULL, NULL), (6, NULL, NULL), (7, NULL, NULL), (NULL, NULL, NULL)")
-                .await
-                .unwrap();
-
-            let result = service
-                .exec_query("SELECT sum(dec_value), sum(dec_value_1) from foo.values")
-                .await
-                .unwrap();
-
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("7.61".to_string()), TableValue::Decimal("59.92".to_string())]));
-
-            let result = service
-                .exec_query("SELECT sum(dec_value), sum(dec_value_1) from foo.values where dec_value > 10")
-                .await
-                .unwrap();
-
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("160.61".to_string()), TableValue::Decimal("58.92".to_string())]));
-
-            let result = service
-                .exec_query("SELECT sum(dec_value), sum(dec_value_1) / 10 from foo.values where dec_value > 10")
-                .await
-                .unwrap();
+    async fn tiny_query_local_execution() {
+        Config::test("tiny_query_local_execution")
+            .update_config(|mut c| {
+                c.local_execution_row_threshold = 1000;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("160.61".to_string()), TableValue::Decimal("5.892".to_string())]));
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            let result = service
-                .exec_query("SELECT sum(dec_value), sum(dec_value_1) / 10 from foo.values where dec_value_1 < 10")
-                .await
-                .unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (num int, name text)")
+                    .await
+                    .unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("-132.99".to_string()), TableValue::Decimal("0.45".to_string())]));
+                service
+                    .exec_query(
+                        "INSERT INTO foo.numbers (num, name) VALUES (1, 'a'), (2, 'b'), (3, 'a')",
+                    )
+                    .await
+                    .unwrap();
 
-            let result = service
-                .exec_query("SELECT sum(dec_value), sum(dec_value_1) / 10 from foo.values where dec_value_1 < '10'")
-                .await
-                .unwrap();
+                let result = service
+                    .exec_query("SELECT name, sum(num) FROM foo.numbers GROUP BY 1 ORDER BY 1")
+                    .await
+                    .unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("-132.99".to_string()), TableValue::Decimal("0.45".to_string())]));
-        })
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::String("a".to_string()),
+                            TableValue::Int(4)
+                        ]),
+                        Row::new(vec![
+                            TableValue::String("b".to_string()),
+                            TableValue::Int(2)
+                        ]),
+                    ]
+                );
+            })
             .await;
     }
 
     #[tokio::test]
-    async fn custom_types() {
-        Config::run_test("custom_types", async move |services| {
-            let service = services.sql_service;
+    async fn insert_buffer() {
+        Config::test("insert_buffer")
+            .update_config(|mut c| {
+                c.insert_buffer_max_rows = 1000;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (num int, name text)")
+                    .await
+                    .unwrap();
 
-            service
-                .exec_query("CREATE TABLE foo.values (int_value mediumint)")
-                .await
-                .unwrap();
+                for i in 0..10000 {
+                    service
+                        .exec_query(&format!(
+                            "INSERT INTO foo.numbers (num, name) VALUES ({}, 'n{}')",
+                            i, i
+                        ))
+                        .await
+                        .unwrap();
+                }
 
-            service
-                .exec_query("INSERT INTO foo.values (int_value) VALUES (-153)")
-                .await
-                .unwrap();
-        })
-        .await;
+                let chunks = services.meta_store.chunks_table().all_rows().await.unwrap();
+                assert!(chunks.len() <= 10, "{} chunks", chunks.len());
+
+                // Reads see buffered rows without writing them
+                for i in 10000..10020 {
+                    service
+                        .exec_query(&format!(
+                            "INSERT INTO foo.numbers (num, name) VALUES ({}, 'n{}')",
+                            i, i
+                        ))
+                        .await
+                        .unwrap();
+                    let result = service
+                        .exec_query("SELECT count(*) FROM foo.numbers")
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        result.get_rows(),
+                        &vec![Row::new(vec![TableValue::Int(i + 1)])]
+                    );
+                }
+                assert_eq!(
+                    services
+                        .meta_store
+                        .chunks_table()
+                        .all_rows()
+                        .await
+                        .unwrap()
+                        .len(),
+                    chunks.len()
+                );
+
+                let result = service
+                    .exec_query("SELECT count(*), sum(num) FROM foo.numbers WHERE num < 10000")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![
+                        TableValue::Int(10000),
+                        TableValue::Int(49995000)
+                    ])]
+                );
+
+                // Buffered rows are written before they're deleted
+                service
+                    .exec_query("DELETE FROM foo.numbers WHERE num >= 10010")
+                    .await
+                    .unwrap();
+                let result = service
+                    .exec_query("SELECT count(*) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(10010)])]
+                );
+            })
+            .await;
     }
 
     #[tokio::test]
-    async fn group_by_boolean() {
-        Config::run_test("group_by_boolean", async move |services| {
-            let service = services.sql_service;
+    async fn insert_buffer_without_read_your_writes() {
+        Config::test("insert_buffer_without_read_your_writes")
+            .update_config(|mut c| {
+                c.insert_buffer_max_rows = 3;
+                c.insert_buffer_read_your_writes = false;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (num int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (num) VALUES (1), (2)")
+                    .await
+                    .unwrap();
 
-            let _ = service.exec_query("CREATE TABLE foo.bool_group (bool_value boolean)").await.unwrap();
+                let result = service
+                    .exec_query("SELECT count(*) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(0)])]);
 
-            service.exec_query(
-                "INSERT INTO foo.bool_group (bool_value) VALUES (true), (false), (true), (false), (false)"
-            ).await.unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (num) VALUES (3)")
+                    .await
+                    .unwrap();
+                let result = service
+                    .exec_query("SELECT count(*) FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(3)])]);
+            })
+            .await;
+    }
 
-            // TODO compaction fails the test in between?
-            // service.exec_query(
-            //     "INSERT INTO foo.bool_group (bool_value) VALUES (true), (false), (true), (false), (false)"
-            // ).await.unwrap();
+    #[tokio::test]
+    async fn decimal() {
+        Config::test("decimal").update_config(|mut c| {
+            c.partition_split_threshold = 2;
+            c
+        }).start_test(async move |services| {
+            let service = services.sql_service;
 
-            let result = service.exec_query("SELECT count(*) from foo.bool_group").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(5)]));
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            let result = service.exec_query("SELECT count(*) from foo.bool_group where bool_value = true").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+            let _ = service
+                .exec_query("CREATE TABLE foo.values (id int, dec_value decimal, dec_value_1 decimal(18, 2))")
+                .await
+                .unwrap();
 
-            let result = service.exec_query("SELECT count(*) from foo.bool_group where bool_value = 'true'").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+            service
+                .exec_query("INSERT INTO foo.values (id, dec_value, dec_value_1) VALUES (1, -153, 1), (2, 20.01, 3.5), (3, 20.30, 12.3), (4, 120.30, 43.12), (5, NULL, NULL), (6, NULL, NULL), (7, NULL, NULL), (NULL, NULL, NULL)")
+                .await
+                .unwrap();
 
-            let result = service.exec_query("SELECT g.bool_value, count(*) from foo.bool_group g GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
+            let result = service
+                .exec_query("SELECT sum(dec_value), sum(dec_value_1) from foo.values")
+                .await
+                .unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Boolean(false), TableValue::Int(3)]));
-            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::Boolean(true), TableValue::Int(2)]));
-        }).await;
-    }
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("7.61".to_string()), TableValue::Decimal("59.92".to_string())]));
 
-    #[tokio::test]
-    async fn group_by_decimal() {
-        Config::run_test("group_by_decimal", async move |services| {
-            let service = services.sql_service;
+            let result = service
+                .exec_query("SELECT sum(dec_value), sum(dec_value_1) from foo.values where dec_value > 10")
+                .await
+                .unwrap();
 
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("160.61".to_string()), TableValue::Decimal("58.92".to_string())]));
 
-            let _ = service.exec_query("CREATE TABLE foo.decimal_group (id INT, decimal_value DECIMAL)").await.unwrap();
+            let result = service
+                .exec_query("SELECT sum(dec_value), sum(dec_value_1) / 10 from foo.values where dec_value > 10")
+                .await
+                .unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.decimal_group (id, decimal_value) VALUES (1, 100), (2, 200), (3, 100), (4, 100), (5, 200)"
-            ).await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("160.61".to_string()), TableValue::Decimal("5.892".to_string())]));
 
-            let result = service.exec_query("SELECT count(*) from foo.decimal_group").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(5)]));
+            let result = service
+                .exec_query("SELECT sum(dec_value), sum(dec_value_1) / 10 from foo.values where dec_value_1 < 10")
+                .await
+                .unwrap();
 
-            let result = service.exec_query("SELECT count(*) from foo.decimal_group where decimal_value = 200").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("-132.99".to_string()), TableValue::Decimal("0.45".to_string())]));
 
-            let result = service.exec_query("SELECT g.decimal_value, count(*) from foo.decimal_group g GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
+            let result = service
+                .exec_query("SELECT sum(dec_value), sum(dec_value_1) / 10 from foo.values where dec_value_1 < '10'")
+                .await
+                .unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("100".to_string()), TableValue::Int(3)]));
-            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::Decimal("200".to_string()), TableValue::Int(2)]));
-        }).await;
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("-132.99".to_string()), TableValue::Decimal("0.45".to_string())]));
+        })
+            .await;
     }
 
     #[tokio::test]
-    async fn float_decimal_scale() {
-        Config::run_test("float_decimal_scale", async move |services| {
+    async fn sum_near_i64_range() {
+        Config::run_test("sum_near_i64_range", async move |services| {
             let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
-            service.exec_query("CREATE TABLE foo.decimal_group (id INT, decimal_value FLOAT)").await.unwrap();
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.decimal_group (id, decimal_value) VALUES (1, 677863988852), (2, 677863988852.123e-10), (3, 6778639882.123e+3)"
-            ).await.unwrap();
-        }).await;
-    }
+            let _ = service
+                .exec_query("CREATE TABLE foo.values (id int, amount decimal(18, 2), hits int)")
+                .await
+                .unwrap();
 
-    #[tokio::test]
-    async fn over_2k_booleans() {
-        Config::test("over_2k_booleans").update_config(|mut c| {
-            c.partition_split_threshold = 1000000;
-            c.compaction_chunks_count_threshold = 0;
-            c
-        }).start_test(async move |services| {
-            let service = services.sql_service;
+            service
+                .exec_query("INSERT INTO foo.values (id, amount, hits) VALUES (1, 92233720368547758.00, 9223372036854775000), (2, 0.07, 807)")
+                .await
+                .unwrap();
 
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            let result = service
+                .exec_query("SELECT sum(amount), sum(hits) from foo.values")
+                .await
+                .unwrap();
 
-            let _ = service.exec_query("CREATE TABLE foo.bool_group (bool_value boolean)").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("92233720368547758.07".to_string()), TableValue::Int(i64::MAX)]));
 
-            for batch in 0..25 {
-                let mut bools = Vec::new();
-                for i in 0..1000 {
-                    bools.push(i % (batch + 1) == 0);
-                }
+            service
+                .exec_query("INSERT INTO foo.values (id, amount, hits) VALUES (3, 0.01, 1)")
+                .await
+                .unwrap();
 
-                let values = bools.into_iter().map(|b| format!("({})", b)).join(", ");
-                service.exec_query(
-                    &format!("INSERT INTO foo.bool_group (bool_value) VALUES {}", values)
-                ).await.unwrap();
-            }
+            let err = service
+                .exec_query("SELECT sum(amount) from foo.values")
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("out of range"), "{}", err);
 
-            let result = service.exec_query("SELECT count(*) from foo.bool_group").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(25000)]));
+            let err = service
+                .exec_query("SELECT sum(hits) from foo.values")
+                .await
+                .unwrap_err();
+            assert!(err.to_string().contains("out of range"), "{}", err);
 
-            let result = service.exec_query("SELECT count(*) from foo.bool_group where bool_value = true").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(3823)]));
+            let result = service
+                .exec_query("SELECT id, sum(hits) from foo.values GROUP BY 1 ORDER BY 1")
+                .await
+                .unwrap();
+            assert_eq!(result.get_rows().len(), 3);
 
-            let result = service.exec_query("SELECT g.bool_value, count(*) from foo.bool_group g GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
+            // Running sum goes beyond the range but the total doesn't
+            service
+                .exec_query("INSERT INTO foo.values (id, amount, hits) VALUES (4, -0.02, -2)")
+                .await
+                .unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Boolean(false), TableValue::Int(21177)]));
-            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::Boolean(true), TableValue::Int(3823)]));
-        }).await;
+            let result = service
+                .exec_query("SELECT sum(amount), sum(hits) from foo.values")
+                .await
+                .unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("92233720368547758.06".to_string()), TableValue::Int(i64::MAX - 1)]));
+        })
+            .await;
     }
 
     #[tokio::test]
-    async fn over_10k_join() {
-        Config::test("over_10k_join").update_config(|mut c| {
-            c.partition_split_threshold = 1000000;
-            c.compaction_chunks_count_threshold = 50;
-            c
-        }).start_test(async move |services| {
-            let service = services.sql_service;
+    async fn coalesce_over_aggregates() {
+        Config::test("coalesce_over_aggregates")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 2;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query("CREATE TABLE foo.orders (amount int, email text)").await.unwrap();
+                service
+                    .exec_query(
+                        "CREATE TABLE foo.values (id int, dec_value decimal, dec_value_1 decimal(18, 2))",
+                    )
+                    .await
+                    .unwrap();
 
-            service.exec_query("CREATE INDEX orders_by_email ON foo.orders (email)").await.unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.values (id, dec_value, dec_value_1) VALUES \
+                    (1, -153, 1), (2, 20.01, 3.5), (3, 20.30, 12.3), (4, 120.30, 43.12), \
+                    (5, NULL, NULL), (6, NULL, NULL), (7, NULL, NULL)",
+                    )
+                    .await
+                    .unwrap();
 
-            service.exec_query("CREATE TABLE foo.customers (email text, system text, uuid text)").await.unwrap();
+                // Only nulls pass the filter and most partitions produce no rows at all
+                let result = service
+                    .exec_query(
+                        "SELECT coalesce(sum(dec_value), 0), \
+                    coalesce(sum(dec_value_1), sum(dec_value), 1) \
+                    FROM foo.values WHERE id > 4",
+                    )
+                    .await
+                    .unwrap();
 
-            service.exec_query("CREATE INDEX customers_by_email ON foo.customers (email)").await.unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![
+                        TableValue::Decimal("0".to_string()),
+                        TableValue::Decimal("1".to_string()),
+                    ])]
+                );
 
-            let mut join_results = Vec::new();
+                let result = service
+                    .exec_query(
+                        "SELECT coalesce(sum(dec_value_1), sum(dec_value)), \
+                    nullif(sum(dec_value_1), 43.12), ifnull(sum(dec_value), 0) \
+                    FROM foo.values WHERE id > 3",
+                    )
+                    .await
+                    .unwrap();
 
-            for batch in 0..25 {
-                let mut orders = Vec::new();
-                let mut customers = Vec::new();
-                for i in 0..1000 {
-                    let email = String::from_utf8(thread_rng()
-                        .sample_iter(&Alphanumeric)
-                        .take(5)
-                        .collect()
-                    ).unwrap();
-                    let domain = String::from_utf8(thread_rng()
-                        .sample_iter(&Alphanumeric)
-                        .take(5)
-                        .collect()
-                    ).unwrap();
-                    let email = format!("{}@{}.com", email, domain);
-                    orders.push((i, email.clone()));
-                    if i % (batch + 1) == 0 {
-                        let uuid = Uuid::new_v4().to_string();
-                        customers.push((email.clone(), uuid.clone()));
-                        if i % (batch + 1 + 10) == 0 {
-                            customers.push((email.clone(), uuid.clone()));
-                            join_results.push(Row::new(vec![TableValue::String(email.clone()), TableValue::String(uuid), TableValue::Int(i * 2)]))
-                        } else {
-                            join_results.push(Row::new(vec![TableValue::String(email.clone()), TableValue::String(uuid), TableValue::Int(i)]))
-                        }
-                    } else {
-                        join_results.push(Row::new(vec![TableValue::String(email.clone()), TableValue::String("".to_string()), TableValue::Int(i)]))
-                    }
-                }
-
-                let values = orders.into_iter().map(|(amount, email)| format!("({}, '{}')", amount, email)).join(", ");
-
-                service.exec_query(
-                    &format!("INSERT INTO foo.orders (amount, email) VALUES {}", values)
-                ).await.unwrap();
-
-                let values = customers.into_iter().map(|(email, uuid)| format!("('{}', 'system', '{}')", email, uuid)).join(", ");
-
-                service.exec_query(
-                    &format!("INSERT INTO foo.customers (email, system, uuid) VALUES {}", values)
-                ).await.unwrap();
-            }
-
-            join_results.sort_by_key(|r| r.values()[0].clone());
-
-            let result = service.exec_query("SELECT o.email, c.uuid, sum(o.amount) from foo.orders o LEFT JOIN foo.customers c ON o.email = c.email GROUP BY 1, 2 ORDER BY 1 ASC").await.unwrap();
-
-            assert_eq!(result.get_rows().len(), join_results.len());
-            for i in 0..result.get_rows().len() {
-                // println!("Actual {}: {:?}", i, &result.get_rows()[i]);
-                // println!("Expected {}: {:?}", i, &join_results[i]);
-                assert_eq!(&result.get_rows()[i], &join_results[i]);
-            }
-        }).await;
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![
+                        TableValue::Decimal("43.12".to_string()),
+                        TableValue::Null,
+                        TableValue::Decimal("120.3".to_string()),
+                    ])]
+                );
+            })
+            .await;
     }
 
     #[tokio::test]
-    async fn high_frequency_inserts() {
-        Config::test("high_frequency_inserts")
+    async fn case_expressions() {
+        Config::test("case_expressions")
             .update_config(|mut c| {
-                c.partition_split_threshold = 1000000;
-                c.compaction_chunks_count_threshold = 100;
+                c.partition_split_threshold = 2;
                 c
             })
             .start_test(async move |services| {
@@ -1137,570 +2349,3764 @@ mod tests {
                 service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
                 service
-                    .exec_query("CREATE TABLE foo.numbers (num int)")
+                    .exec_query(
+                        "CREATE TABLE foo.orders (id int, amount decimal(18, 2), discount decimal, shipped_at timestamp)",
+                    )
                     .await
                     .unwrap();
 
-                for i in 0..300 {
-                    service
-                        .exec_query(&format!("INSERT INTO foo.numbers (num) VALUES ({})", i))
-                        .await
-                        .unwrap();
-                }
-
-                let result = service
-                    .exec_query("SELECT count(*) from foo.numbers")
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (id, amount, discount, shipped_at) VALUES \
+                    (1, 10.5, 1.25, '2021-01-01T00:00:00.000Z'), (2, 20, NULL, NULL), \
+                    (3, 5.75, 0.5, '2021-01-03T12:00:00.000Z')",
+                    )
                     .await
                     .unwrap();
-                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(300)]));
 
                 let result = service
-                    .exec_query("SELECT sum(num) from foo.numbers")
-                    .await
-                    .unwrap();
-                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(44850)]));
-            })
-            .await;
-    }
-
-    #[tokio::test]
-    async fn inactive_partitions_cleanup() {
-        Config::test("inactive_partitions_cleanup")
-            .update_config(|mut c| {
-                c.partition_split_threshold = 1000000;
-                c.compaction_chunks_count_threshold = 0;
-                c
-            })
-            .start_test(async move |services| {
-                let service = services.sql_service;
-
-                service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-                service
-                    .exec_query("CREATE TABLE foo.numbers (num int)")
+                    .exec_query(
+                        "SELECT id, CASE WHEN id = 1 THEN amount WHEN id = 2 THEN discount ELSE 3 END, \
+                    CASE id WHEN 3 THEN shipped_at END \
+                    FROM foo.orders ORDER BY id",
+                    )
                     .await
                     .unwrap();
 
-                for i in 0..10_u64 {
-                    service
-                        .exec_query(&format!("INSERT INTO foo.numbers (num) VALUES ({})", i))
-                        .await
-                        .unwrap();
-                }
-
-                // let listener = services.cluster.job_result_listener();
-                // let active_partitions = services
-                //     .meta_store
-                //     .get_active_partitions_by_index_id(1)
-                //     .await
-                //     .unwrap();
-                // let mut last_active_partition = active_partitions.iter().next().unwrap();
-                // listener
-                //     .wait_for_job_results(vec![(
-                //         RowKey::Table(TableId::Partitions, last_active_partition.get_id()),
-                //         JobType::Repartition,
-                //     )])
-                //     .await
-                //     .unwrap();
-
-                // TODO API to wait for all jobs to be completed and all events processed
-                tokio::time::delay_for(Duration::from_millis(500)).await;
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::Int(1),
+                            TableValue::Decimal("10.5".to_string()),
+                            TableValue::Null,
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(2),
+                            TableValue::Null,
+                            TableValue::Null,
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(3),
+                            TableValue::Decimal("3".to_string()),
+                            TableValue::Timestamp(TimestampValue::new(1609675200000000000)),
+                        ]),
+                    ]
+                );
 
                 let result = service
-                    .exec_query("SELECT count(*) from foo.numbers")
-                    .await
-                    .unwrap();
-                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(10)]));
-
-                let active_partitions = services
-                    .meta_store
-                    .get_active_partitions_by_index_id(1)
+                    .exec_query(
+                        "SELECT CASE WHEN shipped_at IS NULL THEN 'pending' ELSE 'shipped' END, \
+                    count(*) FROM foo.orders GROUP BY 1 ORDER BY 1",
+                    )
                     .await
                     .unwrap();
-                let last_active_partition = active_partitions.iter().next().unwrap();
 
-                let files = services
-                    .remote_fs
-                    .list("")
-                    .await
-                    .unwrap()
-                    .into_iter()
-                    .filter(|r| r.ends_with(".parquet"))
-                    .collect::<Vec<_>>();
                 assert_eq!(
-                    files,
-                    vec![format!("{}.parquet", last_active_partition.get_id())]
-                )
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::String("pending".to_string()),
+                            TableValue::Int(1),
+                        ]),
+                        Row::new(vec![
+                            TableValue::String("shipped".to_string()),
+                            TableValue::Int(2),
+                        ]),
+                    ]
+                );
             })
-            .await
-    }
-
-    #[tokio::test]
-    async fn join() {
-        Config::run_test("join", async move |services| {
-            let service = services.sql_service;
-
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-            let _ = service.exec_query("CREATE TABLE foo.orders (customer_id text, amount int)").await.unwrap();
-            let _ = service.exec_query("CREATE TABLE foo.customers (id text, city text, state text)").await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.orders (customer_id, amount) VALUES ('a', 10), ('b', 2), ('b', 3)"
-            ).await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.customers (id, city, state) VALUES ('a', 'San Francisco', 'CA'), ('b', 'New York', 'NY')"
-            ).await.unwrap();
-
-            let result = service.exec_query("SELECT c.city, sum(o.amount) from foo.orders o JOIN foo.customers c ON o.customer_id = c.id GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
-
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::String("San Francisco".to_string()), TableValue::Int(10)]));
-            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::String("New York".to_string()), TableValue::Int(5)]));
-        }).await;
+            .await;
     }
 
     #[tokio::test]
-    async fn three_tables_join() {
-        Config::run_test("three_tables_join", async move |services| {
+    async fn casts() {
+        Config::run_test("casts", async move |services| {
             let service = services.sql_service;
 
             service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query("CREATE TABLE foo.orders (customer_id text, product_id int, amount int)").await.unwrap();
-            service.exec_query("CREATE INDEX orders_by_product ON foo.orders (product_id)").await.unwrap();
-            service.exec_query("CREATE TABLE foo.customers (id text, city text, state text)").await.unwrap();
-            service.exec_query("CREATE TABLE foo.products (id int, name text)").await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.orders (customer_id, product_id, amount) VALUES ('a', 1, 10), ('b', 2, 2), ('b', 2, 3)"
-            ).await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.orders (customer_id, product_id, amount) VALUES ('b', 1, 10), ('c', 2, 2), ('c', 2, 3)"
-            ).await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.orders (customer_id, product_id, amount) VALUES ('c', 1, 10), ('d', 2, 2), ('d', 2, 3)"
-            ).await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.customers (id, city, state) VALUES ('a', 'San Francisco', 'CA'), ('b', 'New York', 'NY')"
-            ).await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.customers (id, city, state) VALUES ('c', 'San Francisco', 'CA'), ('d', 'New York', 'NY')"
-            ).await.unwrap();
-
-            service.exec_query(
-                "INSERT INTO foo.products (id, name) VALUES (1, 'Potato'), (2, 'Tomato')"
-            ).await.unwrap();
+            service
+                .exec_query(
+                    "CREATE TABLE foo.values (id int, amount decimal(18, 2), code text, t timestamp)",
+                )
+                .await
+                .unwrap();
 
-            let result = service.exec_query(
-                "SELECT c.city, p.name, sum(o.amount) FROM foo.orders o \
-                LEFT JOIN foo.customers c ON o.customer_id = c.id \
-                LEFT JOIN foo.products p ON o.product_id = p.id \
-                GROUP BY 1, 2 ORDER BY 3 DESC, 1 ASC, 2 ASC"
-            ).await.unwrap();
+            service
+                .exec_query(
+                    "INSERT INTO foo.values (id, amount, code, t) VALUES \
+                    (1, 1.15, '42', '2021-01-02T03:04:05.678Z'), (2, -2.5, 'n/a', NULL)",
+                )
+                .await
+                .unwrap();
 
-            let expected = vec![
-                Row::new(vec![TableValue::String("San Francisco".to_string()), TableValue::String("Potato".to_string()), TableValue::Int(20)]),
-                Row::new(vec![TableValue::String("New York".to_string()), TableValue::String("Potato".to_string()), TableValue::Int(10)]),
-                Row::new(vec![TableValue::String("New York".to_string()), TableValue::String("Tomato".to_string()), TableValue::Int(10)]),
-                Row::new(vec![TableValue::String("San Francisco".to_string()), TableValue::String("Tomato".to_string()), TableValue::Int(5)])
-            ];
+            let result = service
+                .exec_query(
+                    "SELECT id, CAST(CAST(amount AS DOUBLE) AS DECIMAL(18, 2)), \
+                    CAST(amount AS DECIMAL(18, 1)), CAST(code AS BIGINT), CAST(t AS DATE), \
+                    CAST(t AS TEXT) FROM foo.values ORDER BY id",
+                )
+                .await
+                .unwrap();
 
             assert_eq!(
                 result.get_rows(),
-                &expected
+                &vec![
+                    Row::new(vec![
+                        TableValue::Int(1),
+                        TableValue::Decimal("1.15".to_string()),
+                        TableValue::Decimal("1.2".to_string()),
+                        TableValue::Int(42),
+                        TableValue::Timestamp(TimestampValue::new(1609545600000000000)),
+                        TableValue::String("2021-01-02T03:04:05.678Z".to_string()),
+                    ]),
+                    Row::new(vec![
+                        TableValue::Int(2),
+                        TableValue::Decimal("-2.5".to_string()),
+                        TableValue::Decimal("-2.5".to_string()),
+                        TableValue::Null,
+                        TableValue::Null,
+                        TableValue::Null,
+                    ]),
+                ]
             );
+        })
+        .await;
+    }
 
-        }).await;
+    #[tokio::test]
+    async fn approx_distinct() {
+        approx_distinct_test("approx_distinct", 1000000).await;
     }
 
     #[tokio::test]
-    async fn in_list() {
-        Config::run_test("in_list", async move |services| {
+    async fn approx_distinct_partitioned() {
+        approx_distinct_test("approx_distinct_partitioned", 20).await;
+    }
+
+    async fn approx_distinct_test(name: &str, partition_split_threshold: u64) {
+        Config::test(name).update_config(|mut c| {
+            c.partition_split_threshold = partition_split_threshold;
+            c
+        }).start_test(async move |services| {
             let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query("CREATE TABLE foo.customers (id text, city text, state text)").await.unwrap();
+            let _ = service.exec_query("CREATE TABLE foo.visits (user_id int, city text)").await.unwrap();
 
+            let values = (0..1200).map(|i| format!("({}, '{}')", (i * 7) % 300, if i % 2 == 0 { "a" } else { "b" })).join(", ");
             service.exec_query(
-                "INSERT INTO foo.customers (id, city, state) VALUES ('a', 'San Francisco', 'CA'), ('b', 'New York', 'NY'), ('c', 'San Diego', 'CA'), ('d', 'Austin', 'TX')"
+                &format!("INSERT INTO foo.visits (user_id, city) VALUES {}", values)
             ).await.unwrap();
 
-            let result = service.exec_query("SELECT count(*) from foo.customers WHERE state in ('CA', 'TX')").await.unwrap();
+            let assert_near = |value: &TableValue, expected: i64| match value {
+                TableValue::Int(v) => assert!((v - expected).abs() * 50 <= expected, "{} estimated as {}", expected, v),
+                v => panic!("Unexpected value: {:?}", v),
+            };
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(3)]));
+            let result = service.exec_query("SELECT approx_distinct(user_id) from foo.visits").await.unwrap();
+            assert_near(&result.get_rows()[0].values()[0], 300);
+
+            let result = service.exec_query("SELECT city, approx_distinct(user_id) from foo.visits GROUP BY 1 ORDER BY 1").await.unwrap();
+            assert_eq!(result.get_rows().len(), 2);
+            for row in result.get_rows() {
+                assert_near(&row.values()[1], 150);
+            }
         }).await;
     }
 
     #[tokio::test]
-    async fn numeric_cast() {
-        Config::run_test("numeric_cast", async move |services| {
+    async fn count_distinct() {
+        Config::test("count_distinct").update_config(|mut c| {
+            c.partition_split_threshold = 20;
+            c
+        }).start_test(async move |services| {
             let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query("CREATE TABLE foo.managers (id text, department_id int)").await.unwrap();
+            let _ = service.exec_query("CREATE TABLE foo.events (user_id int, city text, amount decimal(18, 2))").await.unwrap();
 
+            // Skewed: user 0 makes half of the events
+            let values = (0..400).map(|i| {
+                let user_id = if i % 2 == 0 { 0 } else { i % 37 };
+                let city = if i < 300 { "a" } else { "b" };
+                format!("({}, '{}', {}.5)", user_id, city, i % 5)
+            }).join(", ");
             service.exec_query(
-                "INSERT INTO foo.managers (id, department_id) VALUES ('a', 1), ('b', 3), ('c', 3), ('d', 5)"
+                &format!("INSERT INTO foo.events (user_id, city, amount) VALUES {}, (NULL, 'b', NULL)", values)
             ).await.unwrap();
 
-            let result = service.exec_query("SELECT count(*) from foo.managers WHERE department_id in ('3', '5')").await.unwrap();
+            let result = service.exec_query("SELECT COUNT(DISTINCT user_id), COUNT(DISTINCT amount), count(*) from foo.events").await.unwrap();
+            assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(37), TableValue::Int(5), TableValue::Int(401)])]);
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(3)]));
+            let result = service.exec_query("SELECT city, COUNT(DISTINCT user_id) from foo.events GROUP BY 1 ORDER BY 1").await.unwrap();
+            let expected = |range: std::ops::Range<i64>| range.map(|i| if i % 2 == 0 { 0 } else { i % 37 }).collect::<HashSet<_>>().len() as i64;
+            assert_eq!(result.get_rows(), &vec![
+                Row::new(vec![TableValue::String("a".to_string()), TableValue::Int(expected(0..300))]),
+                Row::new(vec![TableValue::String("b".to_string()), TableValue::Int(expected(300..400))]),
+            ]);
         }).await;
     }
 
     #[tokio::test]
-    async fn union() {
-        Config::run_test("union", async move |services| {
-            let service = services.sql_service;
-
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+    async fn having_over_partitions() {
+        Config::test("having_over_partitions")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 20;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            let _ = service.exec_query("CREATE TABLE foo.orders1 (customer_id text, amount int)").await.unwrap();
-            let _ = service.exec_query("CREATE TABLE foo.orders2 (customer_id text, amount int)").await.unwrap();
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.orders1 (customer_id, amount) VALUES ('a', 10), ('b', 2), ('b', 3)"
-            ).await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, customer int, amount int)")
+                    .await
+                    .unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.orders2 (customer_id, amount) VALUES ('b', 20), ('c', 20), ('b', 30)"
-            ).await.unwrap();
+                // Orders of every customer are spread over partitions as these are split by id
+                let values = (0..200)
+                    .map(|i| format!("({}, {}, {})", i, i % 10, i % 10))
+                    .join(", ");
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.orders (id, customer, amount) VALUES {}",
+                        values
+                    ))
+                    .await
+                    .unwrap();
 
-            let result = service.exec_query(
-                "SELECT `u`.customer_id, sum(`u`.amount) FROM \
-                (select * from foo.orders1 union all select * from foo.orders2) `u` \
-                WHERE `u`.customer_id like '%' GROUP BY 1 ORDER BY 2 DESC"
-            ).await.unwrap();
+                let result = service
+                    .exec_query(
+                        "SELECT customer, sum(amount) FROM foo.orders GROUP BY 1 \
+                         HAVING sum(amount) > 100 ORDER BY 1",
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &(6..10)
+                        .map(|c| Row::new(vec![TableValue::Int(c), TableValue::Int(20 * c)]))
+                        .collect::<Vec<_>>()
+                );
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::String("b".to_string()), TableValue::Int(55)]));
-            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::String("c".to_string()), TableValue::Int(20)]));
-            assert_eq!(result.get_rows()[2], Row::new(vec![TableValue::String("a".to_string()), TableValue::Int(10)]));
-        }).await;
+                let result = service
+                    .exec_query(
+                        "SELECT count(*) FROM (SELECT customer, sum(amount) FROM foo.orders \
+                         GROUP BY 1 HAVING sum(amount) > 100) t",
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(4)])]);
+            })
+            .await;
     }
 
     #[tokio::test]
-    async fn timestamp_select() {
-        Config::run_test("timestamp_select", async move |services| {
-            let service = services.sql_service;
-
-            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-            let _ = service.exec_query("CREATE TABLE foo.timestamps (t timestamp)").await.unwrap();
+    async fn count_distinct_memory_limit() {
+        Config::test("count_distinct_memory_limit")
+            .update_config(|mut c| {
+                c.count_distinct_memory_limit = 1000;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            service.exec_query(
-                "INSERT INTO foo.timestamps (t) VALUES ('2020-01-01T00:00:00.000Z'), ('2020-01-02T00:00:00.000Z'), ('2020-01-03T00:00:00.000Z')"
-            ).await.unwrap();
+                let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.timestamps (t) VALUES ('2020-01-01T00:00:00.000Z'), ('2020-01-02T00:00:00.000Z'), ('2020-01-03T00:00:00.000Z')"
-            ).await.unwrap();
+                let _ = service
+                    .exec_query("CREATE TABLE foo.events (user_id int)")
+                    .await
+                    .unwrap();
 
-            let result = service.exec_query("SELECT count(*) from foo.timestamps WHERE t >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+                let values = (0..200).map(|i| format!("({})", i)).join(", ");
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.events (user_id) VALUES {}",
+                        values
+                    ))
+                    .await
+                    .unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(4)]));
-        }).await;
+                let err = service
+                    .exec_query("SELECT COUNT(DISTINCT user_id) from foo.events")
+                    .await
+                    .unwrap_err();
+                assert!(err.to_string().contains("approx_distinct"), "{}", err);
+            })
+            .await;
     }
 
     #[tokio::test]
-    async fn column_escaping() {
-        Config::run_test("column_escaping", async move |services| {
+    async fn custom_types() {
+        Config::run_test("custom_types", async move |services| {
             let service = services.sql_service;
 
             service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
             service
-                .exec_query("CREATE TABLE foo.timestamps (t timestamp, amount int)")
+                .exec_query("CREATE TABLE foo.values (int_value mediumint)")
                 .await
                 .unwrap();
 
             service
-                .exec_query(
-                    "INSERT INTO foo.timestamps (t, amount) VALUES \
-                ('2020-01-01T00:00:00.000Z', 1), \
-                ('2020-01-01T00:01:00.000Z', 2), \
-                ('2020-01-02T00:10:00.000Z', 3)",
-                )
-                .await
-                .unwrap();
-
-            let result = service
-                .exec_query(
-                    "SELECT date_trunc('day', `timestamp`.t) `day`, sum(`timestamp`.amount) \
-                FROM foo.timestamps `timestamp` \
-                WHERE `timestamp`.t >= to_timestamp('2020-01-02T00:00:00.000Z') GROUP BY 1",
-                )
+                .exec_query("INSERT INTO foo.values (int_value) VALUES (-153)")
                 .await
                 .unwrap();
-
-            assert_eq!(
-                result.get_rows()[0],
-                Row::new(vec![
-                    TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
-                    TableValue::Int(3)
-                ])
-            );
         })
         .await;
     }
 
     #[tokio::test]
-    async fn case_column_escaping() {
-        Config::run_test("case_column_escaping", async move |services| {
+    async fn group_by_boolean() {
+        Config::run_test("group_by_boolean", async move |services| {
             let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query(
-                "CREATE TABLE foo.timestamps (t timestamp, amount int)"
-            ).await.unwrap();
+            let _ = service.exec_query("CREATE TABLE foo.bool_group (bool_value boolean)").await.unwrap();
 
             service.exec_query(
-                "INSERT INTO foo.timestamps (t, amount) VALUES \
-                ('2020-01-01T00:00:00.000Z', 1), \
-                ('2020-01-01T00:01:00.000Z', 2), \
-                ('2020-01-02T00:10:00.000Z', 3)"
-            ).await.unwrap();
-
-            let result = service.exec_query(
-                "SELECT date_trunc('day', `timestamp`.t) `day`, sum(CASE WHEN `timestamp`.t > to_timestamp('2020-01-02T00:01:00.000Z') THEN `timestamp`.amount END) \
-                FROM foo.timestamps `timestamp` \
-                WHERE `timestamp`.t >= to_timestamp('2020-01-02T00:00:00.000Z') GROUP BY 1"
+                "INSERT INTO foo.bool_group (bool_value) VALUES (true), (false), (true), (false), (false)"
             ).await.unwrap();
 
-            assert_eq!(
-                result.get_rows()[0],
-                Row::new(vec![
-                    TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
-                    TableValue::Int(3)
-                ])
-            );
-        }).await;
-    }
-
-    #[tokio::test]
-    async fn inner_column_escaping() {
-        Config::run_test("inner_column_escaping", async move |services| {
-            let service = services.sql_service;
-
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-            service
-                .exec_query("CREATE TABLE foo.timestamps (t timestamp, amount int)")
-                .await
-                .unwrap();
-
-            service
-                .exec_query(
-                    "INSERT INTO foo.timestamps (t, amount) VALUES \
-                ('2020-01-01T00:00:00.000Z', 1), \
-                ('2020-01-01T00:01:00.000Z', 2), \
-                ('2020-01-02T00:10:00.000Z', 3)",
-                )
-                .await
-                .unwrap();
-
-            let result = service
-                .exec_query(
-                    "SELECT date_trunc('day', `t`) `day`, sum(`amount`) \
-                FROM foo.timestamps `timestamp` \
-                WHERE `t` >= to_timestamp('2020-01-02T00:00:00.000Z') GROUP BY 1",
-                )
-                .await
-                .unwrap();
-
-            assert_eq!(
-                result.get_rows()[0],
-                Row::new(vec![
-                    TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
-                    TableValue::Int(3)
-                ])
-            );
-        })
-        .await;
-    }
-
-    #[tokio::test]
-    async fn create_schema_if_not_exists() {
-        Config::run_test("create_schema_if_not_exists", async move |services| {
-            let service = services.sql_service;
-
-            let _ = service
-                .exec_query("CREATE SCHEMA IF NOT EXISTS Foo")
-                .await
-                .unwrap();
-            let _ = service
-                .exec_query("CREATE SCHEMA IF NOT EXISTS Foo")
-                .await
-                .unwrap();
-        })
-        .await;
-    }
-
-    #[tokio::test]
-    async fn create_index_before_ingestion() {
-        Config::run_test("create_index_before_ingestion", async move |services| {
-            let service = services.sql_service;
-
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            // TODO compaction fails the test in between?
+            // service.exec_query(
+            //     "INSERT INTO foo.bool_group (bool_value) VALUES (true), (false), (true), (false), (false)"
+            // ).await.unwrap();
 
-            service.exec_query("CREATE TABLE foo.timestamps (id int, t timestamp)").await.unwrap();
+            let result = service.exec_query("SELECT count(*) from foo.bool_group").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(5)]));
 
-            service.exec_query("CREATE INDEX by_timestamp ON foo.timestamps (`t`)").await.unwrap();
+            let result = service.exec_query("SELECT count(*) from foo.bool_group where bool_value = true").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
 
-            service.exec_query(
-                "INSERT INTO foo.timestamps (id, t) VALUES (1, '2020-01-01T00:00:00.000Z'), (2, '2020-01-02T00:00:00.000Z'), (3, '2020-01-03T00:00:00.000Z')"
-            ).await.unwrap();
+            let result = service.exec_query("SELECT count(*) from foo.bool_group where bool_value = 'true'").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
 
-            let result = service.exec_query("SELECT count(*) from foo.timestamps WHERE t >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+            let result = service.exec_query("SELECT g.bool_value, count(*) from foo.bool_group g GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Boolean(false), TableValue::Int(3)]));
+            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::Boolean(true), TableValue::Int(2)]));
         }).await;
     }
 
     #[tokio::test]
-    async fn ambiguous_join_sort() {
-        Config::run_test("ambiguous_join_sort", async move |services| {
+    async fn boolean_round_trip() {
+        Config::run_test("boolean_round_trip", async move |services| {
             let service = services.sql_service;
 
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-            service.exec_query("CREATE TABLE foo.sessions (t timestamp, id int)").await.unwrap();
-            service.exec_query("CREATE TABLE foo.page_views (session_id int, page_view_count int)").await.unwrap();
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query("CREATE INDEX by_id ON foo.sessions (id)").await.unwrap();
+            let _ = service.exec_query("CREATE TABLE foo.flags (id int, flag boolean)").await.unwrap();
 
             service.exec_query(
-                "INSERT INTO foo.sessions (t, id) VALUES ('2020-01-01T00:00:00.000Z', 1), ('2020-01-02T00:00:00.000Z', 2), ('2020-01-03T00:00:00.000Z', 3)"
+                "INSERT INTO foo.flags (id, flag) VALUES (1, true), (2, false), (3, NULL), (4, 'FALSE'), (5, 1)"
             ).await.unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.page_views (session_id, page_view_count) VALUES (1, 10), (2, 20), (3, 30)"
-            ).await.unwrap();
+            let result = service.exec_query("SELECT id, flag from foo.flags ORDER BY id").await.unwrap();
+            assert_eq!(
+                result.get_rows().iter().map(|r| r.values()[1].clone()).collect::<Vec<_>>(),
+                vec![
+                    TableValue::Boolean(true),
+                    TableValue::Boolean(false),
+                    TableValue::Null,
+                    TableValue::Boolean(false),
+                    TableValue::Boolean(true),
+                ]
+            );
 
-            let result = service.exec_query("SELECT sum(p.page_view_count) from foo.sessions s JOIN foo.page_views p ON s.id = p.session_id WHERE s.t >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+            let result = service.exec_query("SELECT count(*) from foo.flags where flag IS NULL").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(1)]));
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(50)]));
+            let result = service.exec_query(
+                "INSERT INTO foo.flags (id, flag) VALUES (6, 'maybe')"
+            ).await;
+            assert!(result.is_err(), "{:?}", result);
         }).await;
     }
 
     #[tokio::test]
-    async fn join_with_aliases() {
-        Config::run_test("join_with_aliases", async move |services| {
-            let service = services.sql_service;
-
-            service.exec_query("CREATE SCHEMA foo").await.unwrap();
-
-            service.exec_query("CREATE TABLE foo.sessions (t timestamp, id int)").await.unwrap();
-            service.exec_query("CREATE TABLE foo.page_views (session_id int, page_view_count int)").await.unwrap();
+    async fn booleans_as_ints() {
+        Config::test("booleans_as_ints")
+            .update_config(|mut c| {
+                c.booleans_as_ints = true;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
 
-            service.exec_query("CREATE INDEX by_id ON foo.sessions (id)").await.unwrap();
+                let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                let _ = service
+                    .exec_query("CREATE TABLE foo.flags (id int, flag boolean)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.flags (id, flag) VALUES (1, true), (2, false), (3, NULL)",
+                    )
+                    .await
+                    .unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.sessions (t, id) VALUES ('2020-01-01T00:00:00.000Z', 1), ('2020-01-02T00:00:00.000Z', 2), ('2020-01-03T00:00:00.000Z', 3)"
-            ).await.unwrap();
+                let result = service
+                    .exec_query("SELECT id, flag, id > 1 from foo.flags ORDER BY id")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result
+                        .get_columns()
+                        .iter()
+                        .map(|c| c.get_column_type().clone())
+                        .collect::<Vec<_>>(),
+                    vec![ColumnType::Int, ColumnType::Int, ColumnType::Int]
+                );
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::Int(1),
+                            TableValue::Int(1),
+                            TableValue::Int(0)
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(2),
+                            TableValue::Int(0),
+                            TableValue::Int(1)
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(3),
+                            TableValue::Null,
+                            TableValue::Int(1)
+                        ]),
+                    ]
+                );
+
+                // Deleted rows are selected with their boolean values to match stored ones
+                service
+                    .exec_query("DELETE FROM foo.flags WHERE flag = true")
+                    .await
+                    .unwrap();
+                let result = service
+                    .exec_query("SELECT id, flag from foo.flags ORDER BY id")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(2), TableValue::Int(0)]),
+                        Row::new(vec![TableValue::Int(3), TableValue::Null]),
+                    ]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn result_row_limit() {
+        Config::test("result_row_limit")
+            .update_config(|mut c| {
+                c.query_max_result_rows = 2;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.numbers (num int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.numbers (num) VALUES (1), (2), (3), (4)")
+                    .await
+                    .unwrap();
+
+                let err = service
+                    .exec_query("SELECT num FROM foo.numbers")
+                    .await
+                    .unwrap_err();
+                assert!(
+                    err.to_string().contains("exceeds the limit of 2 rows"),
+                    "{}",
+                    err
+                );
+                let result = service
+                    .exec_query("SELECT num FROM foo.numbers ORDER BY num LIMIT 2")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows().len(), 2);
+
+                // Rows selected for deletion aren't a query result
+                service
+                    .exec_query("DELETE FROM foo.numbers WHERE num > 1")
+                    .await
+                    .unwrap();
+                let result = service
+                    .exec_query("SELECT num FROM foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(1)])]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn group_by_decimal() {
+        Config::run_test("group_by_decimal", async move |services| {
+            let service = services.sql_service;
+
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            let _ = service.exec_query("CREATE TABLE foo.decimal_group (id INT, decimal_value DECIMAL)").await.unwrap();
 
             service.exec_query(
-                "INSERT INTO foo.page_views (session_id, page_view_count) VALUES (1, 10), (2, 20), (3, 30)"
+                "INSERT INTO foo.decimal_group (id, decimal_value) VALUES (1, 100), (2, 200), (3, 100), (4, 100), (5, 200)"
             ).await.unwrap();
 
-            let result = service.exec_query("SELECT sum(`page_view_count`) from foo.sessions `sessions` JOIN foo.page_views `page_views` ON `id` = `session_id` WHERE `t` >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+            let result = service.exec_query("SELECT count(*) from foo.decimal_group").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(5)]));
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(50)]));
+            let result = service.exec_query("SELECT count(*) from foo.decimal_group where decimal_value = 200").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+
+            let result = service.exec_query("SELECT g.decimal_value, count(*) from foo.decimal_group g GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Decimal("100".to_string()), TableValue::Int(3)]));
+            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::Decimal("200".to_string()), TableValue::Int(2)]));
         }).await;
     }
 
     #[tokio::test]
-    async fn create_table_with_location() {
-        Config::run_test("create_table_with_location", async move |services| {
+    async fn float_decimal_scale() {
+        Config::run_test("float_decimal_scale", async move |services| {
             let service = services.sql_service;
 
-            let path = {
-                let mut dir = env::temp_dir();
-                dir.push("foo.csv");
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service.exec_query("CREATE TABLE foo.decimal_group (id INT, decimal_value FLOAT)").await.unwrap();
 
-                let mut file = File::create(dir.clone()).unwrap();
+            service.exec_query(
+                "INSERT INTO foo.decimal_group (id, decimal_value) VALUES (1, 677863988852), (2, 677863988852.123e-10), (3, 6778639882.123e+3)"
+            ).await.unwrap();
+        }).await;
+    }
 
-                file.write_all("1,San Francisco\n".as_bytes()).unwrap();
-                file.write_all("2,New York\n".as_bytes()).unwrap();
+    #[tokio::test]
+    async fn over_2k_booleans() {
+        Config::test("over_2k_booleans").update_config(|mut c| {
+            c.partition_split_threshold = 1000000;
+            c.compaction_chunks_count_threshold = 0;
+            c
+        }).start_test(async move |services| {
+            let service = services.sql_service;
 
-                dir
-            };
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            let _ = service.exec_query("CREATE SCHEMA IF NOT EXISTS Foo").await.unwrap();
-            let _ = service.exec_query(&format!("CREATE TABLE Foo.Persons (id int, city text) INDEX persons_city (city, id) LOCATION '{}'", path.as_os_str().to_string_lossy())).await.unwrap();
-            let res = service.exec_query("CREATE INDEX by_city ON Foo.Persons (city)").await;
-            let error = format!("{:?}", res);
-            assert!(error.contains("has data"));
+            let _ = service.exec_query("CREATE TABLE foo.bool_group (bool_value boolean)").await.unwrap();
 
-            let result = service.exec_query("SELECT count(*) as cnt from Foo.Persons").await.unwrap();
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+            for batch in 0..25 {
+                let mut bools = Vec::new();
+                for i in 0..1000 {
+                    bools.push(i % (batch + 1) == 0);
+                }
+
+                let values = bools.into_iter().map(|b| format!("({})", b)).join(", ");
+                service.exec_query(
+                    &format!("INSERT INTO foo.bool_group (bool_value) VALUES {}", values)
+                ).await.unwrap();
+            }
+
+            let result = service.exec_query("SELECT count(*) from foo.bool_group").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(25000)]));
+
+            let result = service.exec_query("SELECT count(*) from foo.bool_group where bool_value = true").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(3823)]));
+
+            let result = service.exec_query("SELECT g.bool_value, count(*) from foo.bool_group g GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Boolean(false), TableValue::Int(21177)]));
+            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::Boolean(true), TableValue::Int(3823)]));
         }).await;
     }
 
     #[tokio::test]
-    async fn compaction() {
-        Config::test("compaction").update_config(|mut config| {
-            config.partition_split_threshold = 5;
-            config.compaction_chunks_count_threshold = 0;
-            config
+    async fn over_10k_join() {
+        Config::test("over_10k_join").update_config(|mut c| {
+            c.partition_split_threshold = 1000000;
+            c.compaction_chunks_count_threshold = 50;
+            c
         }).start_test(async move |services| {
             let service = services.sql_service;
 
             service.exec_query("CREATE SCHEMA foo").await.unwrap();
 
-            service.exec_query("CREATE TABLE foo.table (t int)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.orders (amount int, email text)").await.unwrap();
 
-            let listener = services.cluster.job_result_listener();
+            service.exec_query("CREATE INDEX orders_by_email ON foo.orders (email)").await.unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.table (t) VALUES (NULL), (1), (3), (5), (10), (20), (25), (25), (25), (25), (25)"
-            ).await.unwrap();
+            service.exec_query("CREATE TABLE foo.customers (email text, system text, uuid text)").await.unwrap();
 
-            service.exec_query(
-                "INSERT INTO foo.table (t) VALUES (NULL), (NULL), (NULL), (2), (4), (5), (27), (28), (29)"
-            ).await.unwrap();
+            service.exec_query("CREATE INDEX customers_by_email ON foo.customers (email)").await.unwrap();
 
-            listener.wait_for_job_results(vec![
-                (RowKey::Table(TableId::Partitions, 1), JobType::PartitionCompaction),
-                (RowKey::Table(TableId::Partitions, 2), JobType::PartitionCompaction),
-                (RowKey::Table(TableId::Partitions, 3), JobType::PartitionCompaction),
-                (RowKey::Table(TableId::Partitions, 1), JobType::Repartition),
-                (RowKey::Table(TableId::Partitions, 2), JobType::Repartition),
-                (RowKey::Table(TableId::Partitions, 3), JobType::Repartition),
-            ]).await.unwrap();
+            let mut join_results = Vec::new();
 
-            let partitions = services.meta_store.get_active_partitions_by_index_id(1).await.unwrap();
+            for batch in 0..25 {
+                let mut orders = Vec::new();
+                let mut customers = Vec::new();
+                for i in 0..1000 {
+                    let email = String::from_utf8(thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(5)
+                        .collect()
+                    ).unwrap();
+                    let domain = String::from_utf8(thread_rng()
+                        .sample_iter(&Alphanumeric)
+                        .take(5)
+                        .collect()
+                    ).unwrap();
+                    let email = format!("{}@{}.com", email, domain);
+                    orders.push((i, email.clone()));
+                    if i % (batch + 1) == 0 {
+                        let uuid = Uuid::new_v4().to_string();
+                        customers.push((email.clone(), uuid.clone()));
+                        if i % (batch + 1 + 10) == 0 {
+                            customers.push((email.clone(), uuid.clone()));
+                            join_results.push(Row::new(vec![TableValue::String(email.clone()), TableValue::String(uuid), TableValue::Int(i * 2)]))
+                        } else {
+                            join_results.push(Row::new(vec![TableValue::String(email.clone()), TableValue::String(uuid), TableValue::Int(i)]))
+                        }
+                    } else {
+                        join_results.push(Row::new(vec![TableValue::String(email.clone()), TableValue::String("".to_string()), TableValue::Int(i)]))
+                    }
+                }
 
-            assert_eq!(partitions.len(), 4);
-            let p_1 = partitions.iter().find(|r| r.get_id() == 5).unwrap();
-            let p_2 = partitions.iter().find(|r| r.get_id() == 6).unwrap();
-            let p_3 = partitions.iter().find(|r| r.get_id() == 7).unwrap();
-            let p_4 = partitions.iter().find(|r| r.get_id() == 8).unwrap();
-            let new_partitions = vec![p_1, p_2, p_3, p_4];
-            println!("{:?}", new_partitions);
-            let intervals_set = new_partitions.into_iter()
-                .map(|p| (p.get_row().get_min_val().clone(), p.get_row().get_max_val().clone()))
-                .collect::<HashSet<_>>();
-            assert_eq!(intervals_set, vec![
-                (None, Some(Row::new(vec![TableValue::Int(2)]))),
-                (Some(Row::new(vec![TableValue::Int(2)])), Some(Row::new(vec![TableValue::Int(10)]))),
-                (Some(Row::new(vec![TableValue::Int(10)])), Some(Row::new(vec![TableValue::Int(27)]))),
-                (Some(Row::new(vec![TableValue::Int(27)])), None),
-            ].into_iter().collect::<HashSet<_>>());
+                let values = orders.into_iter().map(|(amount, email)| format!("({}, '{}')", amount, email)).join(", ");
 
-            let result = service.exec_query("SELECT count(*) from foo.table").await.unwrap();
+                service.exec_query(
+                    &format!("INSERT INTO foo.orders (amount, email) VALUES {}", values)
+                ).await.unwrap();
 
-            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(20)]));
+                let values = customers.into_iter().map(|(email, uuid)| format!("('{}', 'system', '{}')", email, uuid)).join(", ");
+
+                service.exec_query(
+                    &format!("INSERT INTO foo.customers (email, system, uuid) VALUES {}", values)
+                ).await.unwrap();
+            }
+
+            join_results.sort_by_key(|r| r.values()[0].clone());
+
+            let result = service.exec_query("SELECT o.email, c.uuid, sum(o.amount) from foo.orders o LEFT JOIN foo.customers c ON o.email = c.email GROUP BY 1, 2 ORDER BY 1 ASC").await.unwrap();
+
+            assert_eq!(result.get_rows().len(), join_results.len());
+            for i in 0..result.get_rows().len() {
+                // println!("Actual {}: {:?}", i, &result.get_rows()[i]);
+                // println!("Expected {}: {:?}", i, &join_results[i]);
+                assert_eq!(&result.get_rows()[i], &join_results[i]);
+            }
         }).await;
     }
+
+    #[tokio::test]
+    async fn high_frequency_inserts() {
+        Config::test("high_frequency_inserts")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 1000000;
+                c.compaction_chunks_count_threshold = 100;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.numbers (num int)")
+                    .await
+                    .unwrap();
+
+                for i in 0..300 {
+                    service
+                        .exec_query(&format!("INSERT INTO foo.numbers (num) VALUES ({})", i))
+                        .await
+                        .unwrap();
+                }
+
+                let result = service
+                    .exec_query("SELECT count(*) from foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(300)]));
+
+                let result = service
+                    .exec_query("SELECT sum(num) from foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(44850)]));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn inactive_partitions_cleanup() {
+        Config::test("inactive_partitions_cleanup")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 1000000;
+                c.compaction_chunks_count_threshold = 0;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.numbers (num int)")
+                    .await
+                    .unwrap();
+
+                for i in 0..10_u64 {
+                    service
+                        .exec_query(&format!("INSERT INTO foo.numbers (num) VALUES ({})", i))
+                        .await
+                        .unwrap();
+                }
+
+                // let listener = services.cluster.job_result_listener();
+                // let active_partitions = services
+                //     .meta_store
+                //     .get_active_partitions_by_index_id(1)
+                //     .await
+                //     .unwrap();
+                // let mut last_active_partition = active_partitions.iter().next().unwrap();
+                // listener
+                //     .wait_for_job_results(vec![(
+                //         RowKey::Table(TableId::Partitions, last_active_partition.get_id()),
+                //         JobType::Repartition,
+                //     )])
+                //     .await
+                //     .unwrap();
+
+                // TODO API to wait for all jobs to be completed and all events processed
+                tokio::time::delay_for(Duration::from_millis(500)).await;
+
+                let result = service
+                    .exec_query("SELECT count(*) from foo.numbers")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(10)]));
+
+                let active_partitions = services
+                    .meta_store
+                    .get_active_partitions_by_index_id(1)
+                    .await
+                    .unwrap();
+                let last_active_partition = active_partitions.iter().next().unwrap();
+
+                let files = services
+                    .remote_fs
+                    .list("")
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .filter(|r| r.ends_with(".parquet"))
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    files,
+                    vec![format!("{}.parquet", last_active_partition.get_id())]
+                )
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn join() {
+        Config::run_test("join", async move |services| {
+            let service = services.sql_service;
+
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            let _ = service.exec_query("CREATE TABLE foo.orders (customer_id text, amount int)").await.unwrap();
+            let _ = service.exec_query("CREATE TABLE foo.customers (id text, city text, state text)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders (customer_id, amount) VALUES ('a', 10), ('b', 2), ('b', 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.customers (id, city, state) VALUES ('a', 'San Francisco', 'CA'), ('b', 'New York', 'NY')"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT c.city, sum(o.amount) from foo.orders o JOIN foo.customers c ON o.customer_id = c.id GROUP BY 1 ORDER BY 2 DESC").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::String("San Francisco".to_string()), TableValue::Int(10)]));
+            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::String("New York".to_string()), TableValue::Int(5)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn three_tables_join() {
+        Config::run_test("three_tables_join", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.orders (customer_id text, product_id int, amount int)").await.unwrap();
+            service.exec_query("CREATE INDEX orders_by_product ON foo.orders (product_id)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.customers (id text, city text, state text)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.products (id int, name text)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders (customer_id, product_id, amount) VALUES ('a', 1, 10), ('b', 2, 2), ('b', 2, 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders (customer_id, product_id, amount) VALUES ('b', 1, 10), ('c', 2, 2), ('c', 2, 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders (customer_id, product_id, amount) VALUES ('c', 1, 10), ('d', 2, 2), ('d', 2, 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.customers (id, city, state) VALUES ('a', 'San Francisco', 'CA'), ('b', 'New York', 'NY')"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.customers (id, city, state) VALUES ('c', 'San Francisco', 'CA'), ('d', 'New York', 'NY')"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.products (id, name) VALUES (1, 'Potato'), (2, 'Tomato')"
+            ).await.unwrap();
+
+            let result = service.exec_query(
+                "SELECT c.city, p.name, sum(o.amount) FROM foo.orders o \
+                LEFT JOIN foo.customers c ON o.customer_id = c.id \
+                LEFT JOIN foo.products p ON o.product_id = p.id \
+                GROUP BY 1, 2 ORDER BY 3 DESC, 1 ASC, 2 ASC"
+            ).await.unwrap();
+
+            let expected = vec![
+                Row::new(vec![TableValue::String("San Francisco".to_string()), TableValue::String("Potato".to_string()), TableValue::Int(20)]),
+                Row::new(vec![TableValue::String("New York".to_string()), TableValue::String("Potato".to_string()), TableValue::Int(10)]),
+                Row::new(vec![TableValue::String("New York".to_string()), TableValue::String("Tomato".to_string()), TableValue::Int(10)]),
+                Row::new(vec![TableValue::String("San Francisco".to_string()), TableValue::String("Tomato".to_string()), TableValue::Int(5)])
+            ];
+
+            assert_eq!(
+                result.get_rows(),
+                &expected
+            );
+
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn in_list() {
+        Config::run_test("in_list", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.customers (id text, city text, state text)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.customers (id, city, state) VALUES ('a', 'San Francisco', 'CA'), ('b', 'New York', 'NY'), ('c', 'San Diego', 'CA'), ('d', 'Austin', 'TX')"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT count(*) from foo.customers WHERE state in ('CA', 'TX')").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(3)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn in_list_filter() {
+        Config::test("in_list_filter")
+            .update_config(|mut c| {
+                // Chunks are kept apart to check which of them are scanned
+                c.compaction_chunks_count_threshold = 100;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.orders (tenant_id int, amount int)")
+                    .await
+                    .unwrap();
+
+                // Separate inserts create chunks with their own min/max
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (tenant_id, amount) VALUES (1, 1), (3, 2), (5, 3)",
+                    )
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (tenant_id, amount) VALUES (7, 4), (9, 5)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (tenant_id, amount) VALUES (19, 6), (21, 7)",
+                    )
+                    .await
+                    .unwrap();
+
+                let sum = |tenants: &str| {
+                    let service = service.clone();
+                    let query = format!(
+                        "SELECT sum(amount) FROM foo.orders WHERE tenant_id IN ({})",
+                        tenants
+                    );
+                    async move { service.exec_query(&query).await.unwrap().get_rows()[0].clone() }
+                };
+
+                assert_eq!(sum("2, 4, 100").await, Row::new(vec![TableValue::Null]));
+                assert_eq!(sum("3, 7, 19").await, Row::new(vec![TableValue::Int(12)]));
+                assert_eq!(
+                    sum("1, 9, 21, 50").await,
+                    Row::new(vec![TableValue::Int(13)])
+                );
+                let all = (0..3000).map(|i| i.to_string()).join(", ");
+                assert_eq!(sum(&all).await, Row::new(vec![TableValue::Int(28)]));
+
+                let planner = QueryPlannerImpl::new(
+                    services.meta_store.clone(),
+                    services.remote_fs.clone(),
+                    Config::test("in_list_filter").config_obj(),
+                    S3ImportProgress::new(),
+                );
+                let statement = match CubeStoreParser::new("SELECT sum(amount) FROM foo.orders")
+                    .unwrap()
+                    .parse_statement()
+                    .unwrap()
+                {
+                    CubeStoreStatement::Statement(statement) => statement,
+                    s => panic!("Unexpected statement: {:?}", s),
+                };
+                let plan = match planner
+                    .logical_plan(DFStatement::Statement(statement))
+                    .await
+                    .unwrap()
+                {
+                    QueryPlan::Select(plan) => plan,
+                    QueryPlan::Meta(plan) => panic!("Unexpected plan: {:?}", plan),
+                };
+                let chunks_to_scan = |tenants: Vec<i64>| {
+                    let filter = datafusion::logical_plan::Expr::InList {
+                        expr: Box::new(datafusion::logical_plan::col("tenant_id")),
+                        list: tenants
+                            .into_iter()
+                            .map(datafusion::logical_plan::lit)
+                            .collect(),
+                        negated: false,
+                    };
+                    plan.index_snapshots()[0]
+                        .to_scan(&plan.partition_ids_to_execute(), &[filter])
+                        .iter()
+                        .map(|(_, chunks)| chunks.len())
+                        .sum::<usize>()
+                };
+                assert_eq!(chunks_to_scan(vec![1, 3, 5, 7, 9, 19, 21]), 3);
+                assert_eq!(chunks_to_scan(vec![2, 4, 100]), 1);
+                assert_eq!(chunks_to_scan(vec![3, 7]), 2);
+                assert_eq!(chunks_to_scan(vec![6, 8, 10, 50]), 1);
+                assert_eq!(chunks_to_scan(vec![6, 50]), 0);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn like_prefix_filter() {
+        Config::run_test("like_prefix_filter", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.events (event text, amount int)").await.unwrap();
+
+            // Separate inserts create chunks with their own min/max
+            service.exec_query(
+                "INSERT INTO foo.events (event, amount) VALUES ('cart_add', 1), ('checkout', 2), ('checkout_done', 3)"
+            ).await.unwrap();
+            service.exec_query(
+                "INSERT INTO foo.events (event, amount) VALUES ('checkpoint', 4), ('click', 5)"
+            ).await.unwrap();
+            service.exec_query(
+                "INSERT INTO foo.events (event, amount) VALUES ('замовлення', 6), ('зворотний', 7), ('оплата', 8)"
+            ).await.unwrap();
+
+            let sum = |pattern: &str| {
+                let service = service.clone();
+                let query = format!("SELECT sum(amount) FROM foo.events WHERE event LIKE '{}'", pattern);
+                async move { service.exec_query(&query).await.unwrap().get_rows()[0].clone() }
+            };
+
+            assert_eq!(sum("checkout%").await, Row::new(vec![TableValue::Int(5)]));
+            assert_eq!(sum("check%").await, Row::new(vec![TableValue::Int(9)]));
+            assert_eq!(sum("c_ick").await, Row::new(vec![TableValue::Int(5)]));
+            assert_eq!(sum("за%").await, Row::new(vec![TableValue::Int(6)]));
+            assert_eq!(sum("з%").await, Row::new(vec![TableValue::Int(13)]));
+            assert_eq!(sum("%та").await, Row::new(vec![TableValue::Int(8)]));
+            assert_eq!(sum("%out%").await, Row::new(vec![TableValue::Int(5)]));
+            assert_eq!(sum("zzz%").await, Row::new(vec![TableValue::Null]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn numeric_cast() {
+        Config::run_test("numeric_cast", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.managers (id text, department_id int)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.managers (id, department_id) VALUES ('a', 1), ('b', 3), ('c', 3), ('d', 5)"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT count(*) from foo.managers WHERE department_id in ('3', '5')").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(3)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn union() {
+        Config::run_test("union", async move |services| {
+            let service = services.sql_service;
+
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            let _ = service.exec_query("CREATE TABLE foo.orders1 (customer_id text, amount int)").await.unwrap();
+            let _ = service.exec_query("CREATE TABLE foo.orders2 (customer_id text, amount int)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders1 (customer_id, amount) VALUES ('a', 10), ('b', 2), ('b', 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders2 (customer_id, amount) VALUES ('b', 20), ('c', 20), ('b', 30)"
+            ).await.unwrap();
+
+            let result = service.exec_query(
+                "SELECT `u`.customer_id, sum(`u`.amount) FROM \
+                (select * from foo.orders1 union all select * from foo.orders2) `u` \
+                WHERE `u`.customer_id like '%' GROUP BY 1 ORDER BY 2 DESC"
+            ).await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::String("b".to_string()), TableValue::Int(55)]));
+            assert_eq!(result.get_rows()[1], Row::new(vec![TableValue::String("c".to_string()), TableValue::Int(20)]));
+            assert_eq!(result.get_rows()[2], Row::new(vec![TableValue::String("a".to_string()), TableValue::Int(10)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn union_with_literal_branch() {
+        Config::run_test("union_with_literal_branch", async move |services| {
+            let service = services.sql_service;
+
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            let _ = service.exec_query("CREATE TABLE foo.orders (customer_id text, amount int)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.orders (customer_id, amount) VALUES ('a', 10), ('b', 2), ('b', 3)"
+            ).await.unwrap();
+
+            let result = service.exec_query(
+                "SELECT `u`.customer_id, sum(`u`.amount) FROM \
+                (select customer_id, amount from foo.orders union all select 'c' customer_id, 1 amount) `u` \
+                GROUP BY 1 ORDER BY 1"
+            ).await.unwrap();
+
+            assert_eq!(result.get_rows(), &vec![
+                Row::new(vec![TableValue::String("a".to_string()), TableValue::Int(10)]),
+                Row::new(vec![TableValue::String("b".to_string()), TableValue::Int(5)]),
+                Row::new(vec![TableValue::String("c".to_string()), TableValue::Int(1)]),
+            ]);
+
+            let result = service.exec_query(
+                "SELECT count(*) FROM (select amount from foo.orders union all select 1 amount) `u`"
+            ).await.unwrap();
+
+            assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(4)])]);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn timestamp_select() {
+        Config::run_test("timestamp_select", async move |services| {
+            let service = services.sql_service;
+
+            let _ = service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            let _ = service.exec_query("CREATE TABLE foo.timestamps (t timestamp)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.timestamps (t) VALUES ('2020-01-01T00:00:00.000Z'), ('2020-01-02T00:00:00.000Z'), ('2020-01-03T00:00:00.000Z')"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.timestamps (t) VALUES ('2020-01-01T00:00:00.000Z'), ('2020-01-02T00:00:00.000Z'), ('2020-01-03T00:00:00.000Z')"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT count(*) from foo.timestamps WHERE t >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(4)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn timestamp_with_offset() {
+        Config::run_test("timestamp_with_offset", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service
+                .exec_query("CREATE TABLE foo.timestamps (t timestamp)")
+                .await
+                .unwrap();
+
+            service
+                .exec_query(
+                    "INSERT INTO foo.timestamps (t) VALUES ('2020-01-01T02:00:00.000+02:00')",
+                )
+                .await
+                .unwrap();
+
+            let result = service
+                .exec_query("SELECT t, convert_tz(t, '+00:00', '+02:00') FROM foo.timestamps")
+                .await
+                .unwrap();
+
+            assert_eq!(
+                result.get_rows(),
+                &vec![Row::new(vec![
+                    TableValue::Timestamp(TimestampValue::new(1577836800000000000)),
+                    TableValue::Timestamp(TimestampValue::new(1577844000000000000)),
+                ])]
+            );
+
+            let result = service
+                .exec_query(
+                    "SELECT count(*) FROM foo.timestamps \
+                WHERE t = to_timestamp('2020-01-01T00:00:00.000Z')",
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(1)])]);
+
+            let err = service
+                .exec_query("SELECT convert_tz(t, 'UTC', 'Europe/Berlin') FROM foo.timestamps")
+                .await
+                .unwrap_err();
+            assert!(
+                err.to_string().contains(
+                    "Unsupported time zone: 'Europe/Berlin'. Only 'UTC' and fixed offsets"
+                ),
+                "{}",
+                err
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn interval_arithmetic() {
+        Config::test("interval_arithmetic")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 2;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.events (t timestamp)")
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query(
+                        "INSERT INTO foo.events (t) VALUES \
+                    ('2020-01-01T00:00:00.000Z'), ('2020-01-03T00:00:00.000Z'), \
+                    ('2020-01-05T00:00:00.000Z'), ('2020-01-08T00:00:00.000Z')",
+                    )
+                    .await
+                    .unwrap();
+
+                let result = service
+                    .exec_query(
+                        "SELECT t + INTERVAL '1 day' FROM foo.events \
+                    WHERE t >= to_timestamp('2020-01-10T00:00:00.000Z') - INTERVAL '7 days' \
+                    ORDER BY 1",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Timestamp(TimestampValue::new(
+                            1578096000000000000
+                        ))]),
+                        Row::new(vec![TableValue::Timestamp(TimestampValue::new(
+                            1578268800000000000
+                        ))]),
+                        Row::new(vec![TableValue::Timestamp(TimestampValue::new(
+                            1578528000000000000
+                        ))]),
+                    ]
+                );
+
+                let result = service
+                    .exec_query(
+                        "SELECT t - INTERVAL '1 month' FROM foo.events \
+                    WHERE t = to_timestamp('2020-01-08T00:00:00.000Z')",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Timestamp(TimestampValue::new(
+                        1575763200000000000
+                    ))])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn date_functions() {
+        Config::test("date_functions")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 2;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.events (t timestamp, amount int)")
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query(
+                        "INSERT INTO foo.events (t, amount) VALUES \
+                    ('2020-01-01T00:00:00.000Z', 1), ('2020-01-01T23:59:59.999Z', 2), \
+                    ('2020-01-02T00:00:00.000Z', 3), ('2020-01-02T00:00:00.001Z', 4)",
+                    )
+                    .await
+                    .unwrap();
+
+                let result = service
+                    .exec_query(
+                        "SELECT date_trunc('day', t), count(*), sum(amount) FROM foo.events \
+                    GROUP BY 1 ORDER BY 1",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::Timestamp(TimestampValue::new(1577836800000000000)),
+                            TableValue::Int(2),
+                            TableValue::Int(3),
+                        ]),
+                        Row::new(vec![
+                            TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
+                            TableValue::Int(2),
+                            TableValue::Int(7),
+                        ]),
+                    ]
+                );
+
+                let result = service
+                    .exec_query(
+                        "SELECT date_part('day', t), EXTRACT(HOUR FROM t) FROM foo.events \
+                    WHERE date_part('hour', t) = 23",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(1), TableValue::Int(23)])]
+                );
+
+                let result = service
+                    .exec_query(
+                        "SELECT count(*) FROM foo.events \
+                    WHERE t >= to_timestamp_seconds(1577923200) \
+                    AND t < to_timestamp_millis(1577923200001)",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(1)])]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn string_functions() {
+        Config::test("string_functions")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 2;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.orders (city text, amount int)")
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (city, amount) VALUES \
+                    ('Berlin', 1), ('BERLIN', 2), ('  berlin ', 3), \
+                    ('Zürich', 4), ('zürich', 5), (NULL, 6)",
+                    )
+                    .await
+                    .unwrap();
+
+                let result = service
+                    .exec_query(
+                        "SELECT lower(btrim(city)), sum(amount) FROM foo.orders \
+                    WHERE city IS NOT NULL GROUP BY 1 ORDER BY 1",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::String("berlin".to_string()),
+                            TableValue::Int(6)
+                        ]),
+                        Row::new(vec![
+                            TableValue::String("zürich".to_string()),
+                            TableValue::Int(9)
+                        ]),
+                    ]
+                );
+
+                let result = service
+                    .exec_query(
+                        "SELECT upper(substr(city, -3)), char_length(city), \
+                    concat(city, '!') FROM foo.orders \
+                    WHERE city = 'Zürich'",
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![
+                        TableValue::String("ICH".to_string()),
+                        TableValue::Int(6),
+                        TableValue::String("Zürich!".to_string()),
+                    ])]
+                );
+
+                let result = service
+                    .exec_query("SELECT concat(city, '!') FROM foo.orders WHERE amount = 6")
+                    .await
+                    .unwrap();
+
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Null])]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn column_escaping() {
+        Config::run_test("column_escaping", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service
+                .exec_query("CREATE TABLE foo.timestamps (t timestamp, amount int)")
+                .await
+                .unwrap();
+
+            service
+                .exec_query(
+                    "INSERT INTO foo.timestamps (t, amount) VALUES \
+                ('2020-01-01T00:00:00.000Z', 1), \
+                ('2020-01-01T00:01:00.000Z', 2), \
+                ('2020-01-02T00:10:00.000Z', 3)",
+                )
+                .await
+                .unwrap();
+
+            let result = service
+                .exec_query(
+                    "SELECT date_trunc('day', `timestamp`.t) `day`, sum(`timestamp`.amount) \
+                FROM foo.timestamps `timestamp` \
+                WHERE `timestamp`.t >= to_timestamp('2020-01-02T00:00:00.000Z') GROUP BY 1",
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                result.get_rows()[0],
+                Row::new(vec![
+                    TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
+                    TableValue::Int(3)
+                ])
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn case_column_escaping() {
+        Config::run_test("case_column_escaping", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query(
+                "CREATE TABLE foo.timestamps (t timestamp, amount int)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.timestamps (t, amount) VALUES \
+                ('2020-01-01T00:00:00.000Z', 1), \
+                ('2020-01-01T00:01:00.000Z', 2), \
+                ('2020-01-02T00:10:00.000Z', 3)"
+            ).await.unwrap();
+
+            let result = service.exec_query(
+                "SELECT date_trunc('day', `timestamp`.t) `day`, sum(CASE WHEN `timestamp`.t > to_timestamp('2020-01-02T00:01:00.000Z') THEN `timestamp`.amount END) \
+                FROM foo.timestamps `timestamp` \
+                WHERE `timestamp`.t >= to_timestamp('2020-01-02T00:00:00.000Z') GROUP BY 1"
+            ).await.unwrap();
+
+            assert_eq!(
+                result.get_rows()[0],
+                Row::new(vec![
+                    TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
+                    TableValue::Int(3)
+                ])
+            );
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn inner_column_escaping() {
+        Config::run_test("inner_column_escaping", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service
+                .exec_query("CREATE TABLE foo.timestamps (t timestamp, amount int)")
+                .await
+                .unwrap();
+
+            service
+                .exec_query(
+                    "INSERT INTO foo.timestamps (t, amount) VALUES \
+                ('2020-01-01T00:00:00.000Z', 1), \
+                ('2020-01-01T00:01:00.000Z', 2), \
+                ('2020-01-02T00:10:00.000Z', 3)",
+                )
+                .await
+                .unwrap();
+
+            let result = service
+                .exec_query(
+                    "SELECT date_trunc('day', `t`) `day`, sum(`amount`) \
+                FROM foo.timestamps `timestamp` \
+                WHERE `t` >= to_timestamp('2020-01-02T00:00:00.000Z') GROUP BY 1",
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                result.get_rows()[0],
+                Row::new(vec![
+                    TableValue::Timestamp(TimestampValue::new(1577923200000000000)),
+                    TableValue::Int(3)
+                ])
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_schema_if_not_exists() {
+        Config::run_test("create_schema_if_not_exists", async move |services| {
+            let service = services.sql_service;
+
+            let _ = service
+                .exec_query("CREATE SCHEMA IF NOT EXISTS Foo")
+                .await
+                .unwrap();
+            let _ = service
+                .exec_query("CREATE SCHEMA IF NOT EXISTS Foo")
+                .await
+                .unwrap();
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_index_before_ingestion() {
+        Config::run_test("create_index_before_ingestion", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.timestamps (id int, t timestamp)").await.unwrap();
+
+            service.exec_query("CREATE INDEX by_timestamp ON foo.timestamps (`t`)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.timestamps (id, t) VALUES (1, '2020-01-01T00:00:00.000Z'), (2, '2020-01-02T00:00:00.000Z'), (3, '2020-01-03T00:00:00.000Z')"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT count(*) from foo.timestamps WHERE t >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn ambiguous_join_sort() {
+        Config::run_test("ambiguous_join_sort", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.sessions (t timestamp, id int)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.page_views (session_id int, page_view_count int)").await.unwrap();
+
+            service.exec_query("CREATE INDEX by_id ON foo.sessions (id)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.sessions (t, id) VALUES ('2020-01-01T00:00:00.000Z', 1), ('2020-01-02T00:00:00.000Z', 2), ('2020-01-03T00:00:00.000Z', 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.page_views (session_id, page_view_count) VALUES (1, 10), (2, 20), (3, 30)"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT sum(p.page_view_count) from foo.sessions s JOIN foo.page_views p ON s.id = p.session_id WHERE s.t >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(50)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn join_with_aliases() {
+        Config::run_test("join_with_aliases", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.sessions (t timestamp, id int)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.page_views (session_id int, page_view_count int)").await.unwrap();
+
+            service.exec_query("CREATE INDEX by_id ON foo.sessions (id)").await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.sessions (t, id) VALUES ('2020-01-01T00:00:00.000Z', 1), ('2020-01-02T00:00:00.000Z', 2), ('2020-01-03T00:00:00.000Z', 3)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.page_views (session_id, page_view_count) VALUES (1, 10), (2, 20), (3, 30)"
+            ).await.unwrap();
+
+            let result = service.exec_query("SELECT sum(`page_view_count`) from foo.sessions `sessions` JOIN foo.page_views `page_views` ON `id` = `session_id` WHERE `t` >= to_timestamp('2020-01-02T00:00:00.000Z')").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(50)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn create_table_with_location() {
+        Config::run_test("create_table_with_location", async move |services| {
+            let service = services.sql_service;
+
+            let path = {
+                let mut dir = env::temp_dir();
+                dir.push("foo.csv");
+
+                let mut file = File::create(dir.clone()).unwrap();
+
+                file.write_all("1,San Francisco\n".as_bytes()).unwrap();
+                file.write_all("2,New York\n".as_bytes()).unwrap();
+
+                dir
+            };
+
+            let _ = service.exec_query("CREATE SCHEMA IF NOT EXISTS Foo").await.unwrap();
+            let _ = service.exec_query(&format!("CREATE TABLE Foo.Persons (id int, city text) INDEX persons_city (city, id) LOCATION '{}'", path.as_os_str().to_string_lossy())).await.unwrap();
+            let res = service.exec_query("CREATE INDEX by_city ON Foo.Persons (city)").await;
+            let error = format!("{:?}", res);
+            assert!(error.contains("has data"));
+
+            let result = service.exec_query("SELECT count(*) as cnt from Foo.Persons").await.unwrap();
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(2)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn create_table_with_csv_options() {
+        Config::run_test("create_table_with_csv_options", async move |services| {
+            let service = services.sql_service;
+
+            let path = {
+                let mut dir = env::temp_dir();
+                dir.push("persons.tsv");
+
+                let mut file = File::create(dir.clone()).unwrap();
+
+                file.write_all("city\tcount\tid\n".as_bytes()).unwrap();
+                file.write_all("\"San Francisco\tCA\"\t10\t1\n".as_bytes()).unwrap();
+                file.write_all("broken\n".as_bytes()).unwrap();
+                file.write_all("\"New\nYork\"\t20\t2\n".as_bytes()).unwrap();
+
+                dir
+            };
+
+            let _ = service.exec_query("CREATE SCHEMA IF NOT EXISTS Foo").await.unwrap();
+            let res = service.exec_query(&format!("CREATE TABLE Foo.Persons (id int, city text, count int) LOCATION '{}' WITH (delimiter = '\\t', with_header = true)", path.as_os_str().to_string_lossy())).await;
+            let error = format!("{:?}", res);
+            assert!(error.contains("line 3"), "{}", error);
+
+            let _ = service.exec_query(&format!("CREATE TABLE Foo.Persons2 (id int, city text, count int) LOCATION '{}' WITH (delimiter = '\\t', with_header = true, max_errors = 1)", path.as_os_str().to_string_lossy())).await.unwrap();
+
+            let result = service.exec_query("SELECT id, city, count from Foo.Persons2 ORDER BY id").await.unwrap();
+            assert_eq!(
+                result.get_rows(),
+                &vec![
+                    Row::new(vec![TableValue::Int(1), TableValue::String("San Francisco\tCA".to_string()), TableValue::Int(10)]),
+                    Row::new(vec![TableValue::Int(2), TableValue::String("New\nYork".to_string()), TableValue::Int(20)]),
+                ]
+            );
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn create_table_with_compressed_location() {
+        Config::run_test(
+            "create_table_with_compressed_location",
+            async move |services| {
+                let service = services.sql_service;
+
+                let csv = (0..1000)
+                    .map(|i| format!("{},city_{}\n", i, i % 7))
+                    .join("");
+                let mut gzip = Vec::new();
+                GzipEncoder::new(csv.as_bytes())
+                    .read_to_end(&mut gzip)
+                    .await
+                    .unwrap();
+                let mut zstd = Vec::new();
+                ZstdEncoder::new(csv.as_bytes())
+                    .read_to_end(&mut zstd)
+                    .await
+                    .unwrap();
+
+                let _ = service
+                    .exec_query("CREATE SCHEMA IF NOT EXISTS Foo")
+                    .await
+                    .unwrap();
+                let mut results = Vec::new();
+                for (table, file_name, content) in vec![
+                    ("plain", "compressed_location.csv", csv.as_bytes().to_vec()),
+                    ("gzip", "compressed_location.csv.gz", gzip),
+                    ("zstd", "compressed_location.csv.zst", zstd),
+                ] {
+                    let path = env::temp_dir().join(file_name);
+                    fs::write(&path, content).unwrap();
+                    service
+                        .exec_query(&format!(
+                            "CREATE TABLE Foo.{} (id int, city text) LOCATION '{}'",
+                            table,
+                            path.as_os_str().to_string_lossy()
+                        ))
+                        .await
+                        .unwrap();
+
+                    let result = service
+                        .exec_query(&format!("SELECT count(*) from Foo.{}", table))
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        result.get_rows(),
+                        &vec![Row::new(vec![TableValue::Int(1000)])]
+                    );
+                    let result = service
+                        .exec_query(&format!(
+                            "SELECT city, sum(id) from Foo.{} GROUP BY 1 ORDER BY 1",
+                            table
+                        ))
+                        .await
+                        .unwrap();
+                    results.push(result.get_rows().clone());
+                }
+                assert_eq!(results[0].len(), 7);
+                assert_eq!(results[0], results[1]);
+                assert_eq!(results[0], results[2]);
+            },
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn create_table_from_parquet() {
+        Config::run_test("create_table_from_parquet", async move |services| {
+            let service = services.sql_service;
+
+            let path = env::temp_dir().join("create_table_from_parquet.parquet").to_str().unwrap().to_string();
+            let file_index = Index::try_new(
+                "source".to_string(),
+                1,
+                vec![
+                    Column::new("id".to_string(), ColumnType::Int, 0),
+                    Column::new("amount".to_string(), ColumnType::Decimal { scale: 2, precision: 18 }, 1),
+                    Column::new("ts".to_string(), ColumnType::Timestamp, 2),
+                ],
+                1,
+            ).unwrap();
+            let rows = (0..100).map(|i| Row::new(vec![
+                TableValue::Int(i),
+                if i % 10 == 0 { TableValue::Null } else { TableValue::Decimal(format!("{}.25", i)) },
+                if i % 7 == 0 { TableValue::Null } else { TableValue::Timestamp(TimestampValue::new((1_600_000_000 + i) * 1_000_000_000)) },
+            ])).collect::<Vec<_>>();
+            ParquetTableStore::new(file_index, 16).merge_rows(None, vec![path.clone()], rows, 1).unwrap();
+
+            let _ = service.exec_query("CREATE SCHEMA IF NOT EXISTS Foo").await.unwrap();
+            service.exec_query(&format!("CREATE TABLE Foo.Imported (ts timestamp, id int, amount decimal) LOCATION '{}'", path)).await.unwrap();
+
+            let result = service.exec_query("SELECT count(*), count(amount), sum(amount), count(ts), max(ts) FROM Foo.Imported").await.unwrap();
+            assert_eq!(result.get_rows(), &vec![Row::new(vec![
+                TableValue::Int(100),
+                TableValue::Int(90),
+                TableValue::Decimal("4522.5".to_string()),
+                TableValue::Int(85),
+                TableValue::Timestamp(TimestampValue::new(1_600_000_099_000_000_000)),
+            ])]);
+
+            let res = service.exec_query(&format!("CREATE TABLE Foo.Mismatched (id int, amount boolean, missing text) LOCATION '{}'", path)).await;
+            let error = format!("{:?}", res);
+            assert!(error.contains(&path), "{}", error);
+            assert!(error.contains("column 'amount' of type"), "{}", error);
+            assert!(error.contains("column 'missing' is not found"), "{}", error);
+
+            fs::remove_file(path).unwrap();
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn create_table_from_json_lines() {
+        Config::run_test("create_table_from_json_lines", async move |services| {
+            let service = services.sql_service;
+
+            let path = env::temp_dir().join("create_table_from_json_lines.ndjson").to_str().unwrap().to_string();
+            let json = (0..10).map(|i| format!("{{\"id\": {}, \"user\": {{\"plan\": \"plan_{}\"}}, \"amount\": \"{}.5\"}}\n", i, i % 2, i)).join("") + "{\"id\": \"bad\", \"extra\": true}\n";
+            fs::write(&path, json).unwrap();
+
+            let _ = service.exec_query("CREATE SCHEMA IF NOT EXISTS Foo").await.unwrap();
+            service.exec_query(&format!("CREATE TABLE Foo.Events (id int, plan text, amount decimal) LOCATION '{}' WITH (paths = 'plan=user.plan')", path)).await.unwrap();
+
+            let result = service.exec_query("SELECT plan, count(*), sum(amount) FROM Foo.Events WHERE plan IS NOT NULL GROUP BY 1 ORDER BY 1").await.unwrap();
+            assert_eq!(result.get_rows(), &vec![
+                Row::new(vec![TableValue::String("plan_0".to_string()), TableValue::Int(5), TableValue::Decimal("22.5".to_string())]),
+                Row::new(vec![TableValue::String("plan_1".to_string()), TableValue::Int(5), TableValue::Decimal("27.5".to_string())]),
+            ]);
+            let result = service.exec_query("SELECT count(*), count(id), count(plan), count(amount) FROM Foo.Events").await.unwrap();
+            assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(11), TableValue::Int(10), TableValue::Int(10), TableValue::Int(10)])]);
+
+            let res = service.exec_query(&format!("CREATE TABLE Foo.Strict (id int, plan text, amount decimal) LOCATION '{}' WITH (format = 'json', strict = true, paths = 'plan=user.plan')", path)).await;
+            let error = format!("{:?}", res);
+            assert!(error.contains("line 11"), "{}", error);
+
+            fs::remove_file(path).unwrap();
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn create_table_from_url() {
+        Config::run_test("create_table_from_url", async move |services| {
+            let service = services.sql_service;
+
+            let csv = (0..1000).map(|i| format!("{},plan_{}\n", i, i % 4)).join("");
+            let mut body = Vec::new();
+            GzipEncoder::new(csv.as_bytes()).read_to_end(&mut body).await.unwrap();
+            let sha256 = format!("{:x}", Sha256::digest(&body));
+            // Connection drops in the middle of the first response so the body is resumed by a range request
+            let (url, responses) = serve(body.clone(), Some("gzip"), body.len() / 2).await;
+
+            let _ = service.exec_query("CREATE SCHEMA IF NOT EXISTS Foo").await.unwrap();
+            service.exec_query(&format!("CREATE TABLE Foo.Plans (id int, plan text) LOCATION '{}/redirect/plans.csv?signature=abc' WITH (sha256 = '{}')", url, sha256)).await.unwrap();
+            assert_eq!(responses.load(Ordering::SeqCst), 2);
+
+            let result = service.exec_query("SELECT plan, count(*), sum(id) FROM Foo.Plans GROUP BY 1 ORDER BY 1").await.unwrap();
+            assert_eq!(result.get_rows(), &(0..4).map(|p| Row::new(vec![TableValue::String(format!("plan_{}", p)), TableValue::Int(250), TableValue::Int((0..1000).filter(|i| i % 4 == p).sum())])).collect::<Vec<_>>());
+
+            let chunks = services.meta_store.chunks_table().all_rows().await.unwrap();
+            let res = service.exec_query(&format!("CREATE TABLE Foo.Corrupted (id int, plan text) LOCATION '{}/plans.csv' WITH (sha256 = '{}')", url, "0".repeat(64))).await;
+            let error = format!("{:?}", res);
+            assert!(error.contains("SHA-256"), "{}", error);
+            // Rows are kept in inactive chunks until the checksum matches
+            assert_eq!(services.meta_store.chunks_table().all_rows().await.unwrap().len(), chunks.len());
+
+            let res = service.exec_query(&format!("CREATE TABLE Foo.Local (id int, plan text) LOCATION '/tmp/plans.csv' WITH (sha256 = '{}')", sha256)).await;
+            let error = format!("{:?}", res);
+            assert!(error.contains("HTTP(S) locations"), "{}", error);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn compaction() {
+        Config::test("compaction").update_config(|mut config| {
+            config.partition_split_threshold = 5;
+            config.compaction_chunks_count_threshold = 0;
+            config
+        }).start_test(async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service.exec_query("CREATE TABLE foo.table (t int)").await.unwrap();
+
+            let listener = services.cluster.job_result_listener();
+
+            service.exec_query(
+                "INSERT INTO foo.table (t) VALUES (NULL), (1), (3), (5), (10), (20), (25), (25), (25), (25), (25)"
+            ).await.unwrap();
+
+            service.exec_query(
+                "INSERT INTO foo.table (t) VALUES (NULL), (NULL), (NULL), (2), (4), (5), (27), (28), (29)"
+            ).await.unwrap();
+
+            listener.wait_for_job_results(vec![
+                (RowKey::Table(TableId::Partitions, 1), JobType::PartitionCompaction),
+                (RowKey::Table(TableId::Partitions, 2), JobType::PartitionCompaction),
+                (RowKey::Table(TableId::Partitions, 3), JobType::PartitionCompaction),
+                (RowKey::Table(TableId::Partitions, 1), JobType::Repartition),
+                (RowKey::Table(TableId::Partitions, 2), JobType::Repartition),
+                (RowKey::Table(TableId::Partitions, 3), JobType::Repartition),
+            ]).await.unwrap();
+
+            let partitions = services.meta_store.get_active_partitions_by_index_id(1).await.unwrap();
+
+            assert_eq!(partitions.len(), 4);
+            let p_1 = partitions.iter().find(|r| r.get_id() == 5).unwrap();
+            let p_2 = partitions.iter().find(|r| r.get_id() == 6).unwrap();
+            let p_3 = partitions.iter().find(|r| r.get_id() == 7).unwrap();
+            let p_4 = partitions.iter().find(|r| r.get_id() == 8).unwrap();
+            let new_partitions = vec![p_1, p_2, p_3, p_4];
+            println!("{:?}", new_partitions);
+            let intervals_set = new_partitions.into_iter()
+                .map(|p| (p.get_row().get_min_val().clone(), p.get_row().get_max_val().clone()))
+                .collect::<HashSet<_>>();
+            assert_eq!(intervals_set, vec![
+                (None, Some(Row::new(vec![TableValue::Int(2)]))),
+                (Some(Row::new(vec![TableValue::Int(2)])), Some(Row::new(vec![TableValue::Int(10)]))),
+                (Some(Row::new(vec![TableValue::Int(10)])), Some(Row::new(vec![TableValue::Int(27)]))),
+                (Some(Row::new(vec![TableValue::Int(27)])), None),
+            ].into_iter().collect::<HashSet<_>>());
+
+            let result = service.exec_query("SELECT count(*) from foo.table").await.unwrap();
+
+            assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(20)]));
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn compaction_warms_up_partitions() {
+        Config::test("compaction_warms_up_partitions")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 5;
+                config.compaction_chunks_count_threshold = 0;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.table (t int)")
+                    .await
+                    .unwrap();
+
+                let listener = services.cluster.job_result_listener();
+
+                service.exec_query(
+                "INSERT INTO foo.table (t) VALUES (1), (3), (5), (10), (20), (25), (27), (28)"
+            ).await.unwrap();
+
+                listener
+                    .wait_for_job_results(vec![(
+                        RowKey::Table(TableId::Partitions, 1),
+                        JobType::PartitionCompaction,
+                    )])
+                    .await
+                    .unwrap();
+
+                let partitions = services
+                    .meta_store
+                    .get_active_partitions_by_index_id(1)
+                    .await
+                    .unwrap();
+                assert_eq!(partitions.len(), 2);
+
+                // Warm-up is sent once the scheduler sees the compacted partition deactivated
+                for partition in partitions.iter() {
+                    let remote_path = partition
+                        .get_row()
+                        .get_full_name(partition.get_id())
+                        .unwrap();
+                    let local_path = services.remote_fs.local_file(&remote_path).await.unwrap();
+                    let mut attempts = 0;
+                    while !Path::new(&local_path).exists() && attempts < 50 {
+                        tokio::time::delay_for(Duration::from_millis(100)).await;
+                        attempts += 1;
+                    }
+                    assert!(
+                        Path::new(&local_path).exists(),
+                        "{} isn't downloaded",
+                        local_path
+                    );
+                }
+
+                let result = service
+                    .exec_query("SELECT count(*) from foo.table")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(8)]));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn leveled_chunk_compaction() {
+        Config::test("leveled_chunk_compaction")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 1000000;
+                config.compaction_chunks_count_threshold = 3;
+                config.compaction_medium_chunks_count_threshold = 3;
+                config.compaction_chunks_total_size_threshold = 1000000;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.table (t int)")
+                    .await
+                    .unwrap();
+
+                for i in 0..20 {
+                    service
+                        .exec_query(&format!("INSERT INTO foo.table (t) VALUES ({})", 20 - i))
+                        .await
+                        .unwrap();
+                }
+
+                // Partition file, if any, and chunks a select of the table scans
+                let mut files_to_scan = usize::MAX;
+                let mut attempts = 0;
+                while files_to_scan > 4 && attempts < 50 {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    files_to_scan = services
+                        .meta_store
+                        .get_active_partitions_and_chunks_by_index_id_for_select(1)
+                        .await
+                        .unwrap()
+                        .iter()
+                        .map(|(p, chunks)| {
+                            chunks.len() + (p.get_row().main_table_row_count() > 0) as usize
+                        })
+                        .sum::<usize>();
+                    attempts += 1;
+                }
+                assert!(files_to_scan <= 4, "{} files to scan", files_to_scan);
+
+                let backlog = service
+                    .exec_query("SELECT small_chunks, medium_chunks FROM system.compaction_backlog")
+                    .await
+                    .unwrap();
+                let backlog_chunks = backlog
+                    .get_rows()
+                    .iter()
+                    .map(|r| match r.values().as_slice() {
+                        [TableValue::Int(small), TableValue::Int(medium)] => small + medium,
+                        x => panic!("Unexpected backlog row: {:?}", x),
+                    })
+                    .sum::<i64>();
+                assert!(backlog_chunks <= 4, "{} chunks in backlog", backlog_chunks);
+
+                let result = service
+                    .exec_query("SELECT count(*), sum(t), min(t), max(t) from foo.table")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![
+                        TableValue::Int(20),
+                        TableValue::Int(210),
+                        TableValue::Int(1),
+                        TableValue::Int(20)
+                    ])]
+                );
+
+                let result = service
+                    .exec_query("SELECT t from foo.table ORDER BY t LIMIT 3")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(1)]),
+                        Row::new(vec![TableValue::Int(2)]),
+                        Row::new(vec![TableValue::Int(3)])
+                    ]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn download_queue_depth() {
+        Config::test("download_queue_depth")
+            .update_config(|mut config| {
+                config.download_concurrency = 1;
+                config.download_bandwidth_limit = 1;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+                let queue = services.cluster.download_queue();
+                for file in ["1.parquet", "2.parquet"].iter() {
+                    let local_path = services.remote_fs.local_file(file).await.unwrap();
+                    fs::write(local_path, vec![0u8; 100]).unwrap();
+                }
+
+                queue.wait_for("1.parquet").await.unwrap();
+                // Waits for 100 seconds under the limit of 1 byte a second once the first file
+                // is downloaded
+                queue.enqueue(&vec!["2.parquet".to_string()]);
+
+                let result = service
+                    .exec_query("SELECT queue_depth FROM system.download_queue")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(1)])]);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn delete_with_tombstones() {
+        Config::test("delete_with_tombstones")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 1000000;
+                config.compaction_chunks_count_threshold = 3;
+                config.compaction_chunks_total_size_threshold = 1000000;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, amount int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (id, amount) VALUES (1, 10), (2, 20), (3, 30), (4, 40)",
+                    )
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (5, 50), (6, 60)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("DELETE FROM foo.orders WHERE amount >= 20 AND amount < 50")
+                    .await
+                    .unwrap();
+                // Rows inserted after the delete stay visible
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (3, 30)")
+                    .await
+                    .unwrap();
+
+                let query = "SELECT id, amount FROM foo.orders WHERE id < 7 ORDER BY id";
+                let before_compaction = service.exec_query(query).await.unwrap();
+                assert_eq!(
+                    before_compaction.get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(1), TableValue::Int(10)]),
+                        Row::new(vec![TableValue::Int(3), TableValue::Int(30)]),
+                        Row::new(vec![TableValue::Int(5), TableValue::Int(50)]),
+                        Row::new(vec![TableValue::Int(6), TableValue::Int(60)]),
+                    ]
+                );
+                for _ in 0..2 {
+                    // Deleted rows are counted per query
+                    let result = service
+                        .exec_query("SELECT count(*), sum(amount) FROM foo.orders")
+                        .await
+                        .unwrap();
+                    assert_eq!(
+                        result.get_rows(),
+                        &vec![Row::new(vec![TableValue::Int(4), TableValue::Int(150)])]
+                    );
+                }
+
+                // One more chunk triggers compaction
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (7, 70)")
+                    .await
+                    .unwrap();
+                let mut chunks = usize::MAX;
+                let mut attempts = 0;
+                while chunks > 0 && attempts < 50 {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    chunks = services
+                        .meta_store
+                        .get_active_partitions_and_chunks_by_index_id_for_select(1)
+                        .await
+                        .unwrap()
+                        .iter()
+                        .map(|(_, chunks)| chunks.len())
+                        .sum::<usize>();
+                    attempts += 1;
+                }
+                assert_eq!(chunks, 0);
+                let partitions = services
+                    .meta_store
+                    .get_active_partitions_by_index_id(1)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    partitions
+                        .iter()
+                        .map(|p| p.get_row().main_table_row_count())
+                        .sum::<u64>(),
+                    5
+                );
+
+                let after_compaction = service.exec_query(query).await.unwrap();
+                assert_eq!(after_compaction.get_rows(), before_compaction.get_rows());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn upsert_by_unique_key() {
+        Config::test("upsert_by_unique_key")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 1000000;
+                config.compaction_chunks_count_threshold = 3;
+                config.compaction_chunks_total_size_threshold = 1000000;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query(
+                        "CREATE TABLE foo.orders (id int, region text, amount int) UNIQUE KEY (id, region)",
+                    )
+                    .await
+                    .unwrap();
+                assert!(service
+                    .exec_query("CREATE INDEX by_amount ON foo.orders (amount)")
+                    .await
+                    .is_err());
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (id, region, amount) VALUES (1, 'us', 10), (1, 'eu', 20), (2, 'us', 30)",
+                    )
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (id, region, amount) VALUES (1, 'us', 15), (3, 'us', 40)",
+                    )
+                    .await
+                    .unwrap();
+
+                let query = "SELECT id, region, amount FROM foo.orders ORDER BY id, region";
+                let before_compaction = service.exec_query(query).await.unwrap();
+                assert_eq!(
+                    before_compaction.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::Int(1),
+                            TableValue::String("eu".to_string()),
+                            TableValue::Int(20)
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(1),
+                            TableValue::String("us".to_string()),
+                            TableValue::Int(15)
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(2),
+                            TableValue::String("us".to_string()),
+                            TableValue::Int(30)
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(3),
+                            TableValue::String("us".to_string()),
+                            TableValue::Int(40)
+                        ]),
+                    ]
+                );
+                let sum = "SELECT count(*), sum(amount) FROM foo.orders WHERE region = 'us'";
+                assert_eq!(
+                    service.exec_query(sum).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(3), TableValue::Int(85)])]
+                );
+
+                // One more chunk triggers compaction
+                service
+                    .exec_query("INSERT INTO foo.orders (id, region, amount) VALUES (2, 'us', 35)")
+                    .await
+                    .unwrap();
+                let mut chunks = usize::MAX;
+                let mut attempts = 0;
+                while chunks > 0 && attempts < 50 {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    chunks = services
+                        .meta_store
+                        .get_active_partitions_and_chunks_by_index_id_for_select(1)
+                        .await
+                        .unwrap()
+                        .iter()
+                        .map(|(_, chunks)| chunks.len())
+                        .sum::<usize>();
+                    attempts += 1;
+                }
+                assert_eq!(chunks, 0);
+                let partitions = services
+                    .meta_store
+                    .get_active_partitions_by_index_id(1)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    partitions
+                        .iter()
+                        .map(|p| p.get_row().main_table_row_count())
+                        .sum::<u64>(),
+                    4
+                );
+                assert_eq!(
+                    service.exec_query(sum).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(3), TableValue::Int(90)])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn join_unique_key_tables() {
+        Config::run_test("join_unique_key_tables", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service.exec_query("CREATE TABLE foo.orders (customer_id int, amount int) UNIQUE KEY (customer_id)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.customers (id int, city text) UNIQUE KEY (id)").await.unwrap();
+
+            // Replacing rows come in later chunks with keys in between the older ones
+            service.exec_query("INSERT INTO foo.orders (customer_id, amount) VALUES (1, 10), (3, 30), (5, 50)").await.unwrap();
+            service.exec_query("INSERT INTO foo.orders (customer_id, amount) VALUES (2, 20), (3, 31), (4, 40)").await.unwrap();
+            service.exec_query("INSERT INTO foo.customers (id, city) VALUES (5, 'Austin'), (4, 'Boston'), (3, 'Dallas')").await.unwrap();
+            service.exec_query("INSERT INTO foo.customers (id, city) VALUES (1, 'Denver'), (3, 'Chicago'), (2, 'Miami')").await.unwrap();
+
+            let result = service.exec_query("SELECT o.customer_id, c.city, o.amount FROM foo.orders o JOIN foo.customers c ON o.customer_id = c.id ORDER BY 1").await.unwrap();
+            assert_eq!(result.get_rows(), &vec![
+                Row::new(vec![TableValue::Int(1), TableValue::String("Denver".to_string()), TableValue::Int(10)]),
+                Row::new(vec![TableValue::Int(2), TableValue::String("Miami".to_string()), TableValue::Int(20)]),
+                Row::new(vec![TableValue::Int(3), TableValue::String("Chicago".to_string()), TableValue::Int(31)]),
+                Row::new(vec![TableValue::Int(4), TableValue::String("Boston".to_string()), TableValue::Int(40)]),
+                Row::new(vec![TableValue::Int(5), TableValue::String("Austin".to_string()), TableValue::Int(50)]),
+            ]);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn explain_join_on_workers() {
+        Config::run_test("explain_join_on_workers", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service.exec_query("CREATE TABLE foo.orders (customer_id int, amount int)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.customers (id int, city text)").await.unwrap();
+            service.exec_query("INSERT INTO foo.orders (customer_id, amount) VALUES (1, 10), (2, 20), (2, 30)").await.unwrap();
+            service.exec_query("INSERT INTO foo.customers (id, city) VALUES (1, 'Denver'), (2, 'Miami')").await.unwrap();
+
+            let result = service.exec_query("EXPLAIN SELECT c.city, sum(o.amount) FROM foo.orders o JOIN foo.customers c ON o.customer_id = c.id GROUP BY 1").await.unwrap();
+            assert_eq!(result.get_columns()[0].get_name(), "plan");
+            let lines = result.get_rows().iter().map(|r| match &r.values()[0] {
+                TableValue::String(line) => line.clone(),
+                v => panic!("Unexpected value: {:?}", v),
+            }).collect::<Vec<_>>();
+            let plan = lines.join("\n");
+            assert_eq!(lines[0], "Execution: cluster", "{}", plan);
+            assert!(!lines[1].ends_with("[worker]"), "{}", plan);
+            assert!(lines.iter().any(|l| l.trim_start().starts_with("ClusterSendExec")), "{}", plan);
+            // Join rows are aggregated by workers and never reach the router
+            let join_lines = lines.iter().filter(|l| l.contains("Join")).collect::<Vec<_>>();
+            assert!(!join_lines.is_empty(), "{}", plan);
+            assert!(join_lines.iter().all(|l| l.ends_with("[worker]")), "{}", plan);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn left_join_without_matching_partitions() {
+        Config::run_test("left_join_without_matching_partitions", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service.exec_query("CREATE TABLE foo.orders (customer_id int, amount int)").await.unwrap();
+            service.exec_query("CREATE TABLE foo.customers (id int, city text)").await.unwrap();
+            service.exec_query("INSERT INTO foo.orders (customer_id, amount) VALUES (1, 10), (2, 20), (3, 30)").await.unwrap();
+
+            let query = "SELECT o.customer_id, c.city, o.amount FROM foo.orders o LEFT JOIN foo.customers c ON o.customer_id = c.id ORDER BY 1";
+            let unmatched = vec![
+                Row::new(vec![TableValue::Int(1), TableValue::Null, TableValue::Int(10)]),
+                Row::new(vec![TableValue::Int(2), TableValue::Null, TableValue::Int(20)]),
+                Row::new(vec![TableValue::Int(3), TableValue::Null, TableValue::Int(30)]),
+            ];
+            // Customers table is empty
+            let result = service.exec_query(query).await.unwrap();
+            assert_eq!(result.get_rows(), &unmatched);
+
+            // None of the customers match the orders
+            service.exec_query("INSERT INTO foo.customers (id, city) VALUES (10, 'Denver'), (11, 'Miami')").await.unwrap();
+            let result = service.exec_query(query).await.unwrap();
+            assert_eq!(result.get_rows(), &unmatched);
+
+            let result = service.exec_query("SELECT o.customer_id, c.city FROM foo.orders o JOIN foo.customers c ON o.customer_id = c.id").await.unwrap();
+            assert!(result.get_rows().is_empty());
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn add_column_with_default() {
+        Config::test("add_column_with_default")
+            .update_config(|mut config| {
+                config.compaction_chunks_count_threshold = 0;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, amount int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (1, 10), (2, 20)")
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query("ALTER TABLE foo.orders ADD COLUMN status text DEFAULT 'paid'")
+                    .await
+                    .unwrap();
+                assert!(service
+                    .exec_query("ALTER TABLE foo.orders ADD COLUMN amount int")
+                    .await
+                    .is_err());
+                service
+                    .exec_query(
+                        "INSERT INTO foo.orders (id, amount, status) VALUES (3, 30, 'new'), (4, 40, 'paid')",
+                    )
+                    .await
+                    .unwrap();
+
+                let result = service
+                    .exec_query("SELECT id, status FROM foo.orders ORDER BY id")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::Int(1),
+                            TableValue::String("paid".to_string())
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(2),
+                            TableValue::String("paid".to_string())
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(3),
+                            TableValue::String("new".to_string())
+                        ]),
+                        Row::new(vec![
+                            TableValue::Int(4),
+                            TableValue::String("paid".to_string())
+                        ]),
+                    ]
+                );
+
+                let result = service
+                    .exec_query(
+                        "SELECT status, sum(amount) FROM foo.orders GROUP BY 1 ORDER BY 1",
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        Row::new(vec![
+                            TableValue::String("new".to_string()),
+                            TableValue::Int(30)
+                        ]),
+                        Row::new(vec![
+                            TableValue::String("paid".to_string()),
+                            TableValue::Int(70)
+                        ]),
+                    ]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn create_index_backfills_existing_rows() {
+        Config::test("create_index_backfills_existing_rows")
+            .update_config(|mut config| {
+                config.compaction_chunks_count_threshold = 1;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, region text, amount int)")
+                    .await
+                    .unwrap();
+                for i in 0..3 {
+                    service
+                        .exec_query(&format!(
+                            "INSERT INTO foo.orders (id, region, amount) VALUES ({}, 'us', 10), ({}, 'eu', 20), ({}, 'us', 30)",
+                            3 * i,
+                            3 * i + 1,
+                            3 * i + 2
+                        ))
+                        .await
+                        .unwrap();
+                }
+
+                service
+                    .exec_query("CREATE INDEX by_region ON foo.orders (region)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, region, amount) VALUES (9, 'eu', 40)")
+                    .await
+                    .unwrap();
+                let mut building = true;
+                let mut attempts = 0;
+                while building && attempts < 50 {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    building = services
+                        .meta_store
+                        .get_table_indexes(1)
+                        .await
+                        .unwrap()
+                        .iter()
+                        .any(|i| i.get_row().is_building());
+                    attempts += 1;
+                }
+                assert!(!building);
+
+                let query = "SELECT region, count(*), sum(amount) FROM foo.orders WHERE region = 'eu' GROUP BY 1";
+                let planner = QueryPlannerImpl::new(
+                    services.meta_store.clone(),
+                    services.remote_fs.clone(),
+                    Config::test("create_index_backfills_existing_rows").config_obj(),
+                    S3ImportProgress::new(),
+                );
+                let statement = match CubeStoreParser::new(query)
+                    .unwrap()
+                    .parse_statement()
+                    .unwrap()
+                {
+                    CubeStoreStatement::Statement(statement) => statement,
+                    s => panic!("Unexpected statement: {:?}", s),
+                };
+                match planner
+                    .logical_plan(DFStatement::Statement(statement))
+                    .await
+                    .unwrap()
+                {
+                    QueryPlan::Select(plan) => {
+                        assert_eq!(
+                            plan.index_snapshots()[0].index().get_row().get_name(),
+                            "by_region"
+                        );
+                        let explanation = plan.explain_index_selection();
+                        assert!(
+                            explanation.starts_with("foo.orders: index by_region covers columns"),
+                            "{}",
+                            explanation
+                        );
+                        assert!(
+                            explanation.ends_with(&format!(
+                                "join on: none; {} partitions",
+                                plan.index_snapshots()[0].partitions().len()
+                            )),
+                            "{}",
+                            explanation
+                        );
+                    }
+                    QueryPlan::Meta(plan) => panic!("Unexpected plan: {:?}", plan),
+                }
+                assert_eq!(
+                    service.exec_query(query).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![
+                        TableValue::String("eu".to_string()),
+                        TableValue::Int(4),
+                        TableValue::Int(100)
+                    ])]
+                );
+
+                // Projection of the default index only
+                let by_id = "SELECT count(*), sum(amount) FROM foo.orders WHERE id >= 0";
+                assert_eq!(
+                    service.exec_query(by_id).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(10), TableValue::Int(220)])]
+                );
+                let by_region = "SELECT count(*), sum(amount) FROM foo.orders WHERE region >= ''";
+                assert_eq!(
+                    service.exec_query(by_region).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(10), TableValue::Int(220)])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn tenant_column_partitions() {
+        Config::test("tenant_column_partitions")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 2;
+                config.compaction_chunks_count_threshold = 0;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                let error = service
+                    .exec_query("CREATE TABLE foo.bad (id int, tenant_id int) TENANT KEY (tenant)")
+                    .await
+                    .unwrap_err();
+                assert!(error.to_string().contains("Tenant column tenant not found"), "{}", error);
+                let error = service
+                    .exec_query("CREATE TABLE foo.bad (id int, tenant_id int) UNIQUE KEY (id) TENANT KEY (tenant_id)")
+                    .await
+                    .unwrap_err();
+                assert!(error.to_string().contains("should be a part of unique key"), "{}", error);
+
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, tenant_id int, amount int) TENANT KEY (tenant_id)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("CREATE INDEX by_amount ON foo.orders (amount)")
+                    .await
+                    .unwrap();
+                for index in services.meta_store.get_table_indexes(1).await.unwrap() {
+                    assert_eq!(index.get_row().get_columns()[0].get_name(), "tenant_id");
+                }
+
+                let listener = services.cluster.job_result_listener();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, tenant_id, amount) VALUES (1, 1, 10), (2, 1, 20), (3, 1, 30), (4, 2, 40), (5, 3, 50), (6, 3, 60), (7, 3, 70)")
+                    .await
+                    .unwrap();
+                listener
+                    .wait_for_job_results(vec![(
+                        RowKey::Table(TableId::Partitions, 1),
+                        JobType::PartitionCompaction,
+                    )])
+                    .await
+                    .unwrap();
+
+                // Split partitions start at the smallest row of their tenant
+                let partitions = services
+                    .meta_store
+                    .get_active_partitions_by_index_id(1)
+                    .await
+                    .unwrap();
+                assert!(partitions.len() >= 3, "{:?}", partitions);
+                for tenant in 2..=3 {
+                    assert!(partitions.iter().any(|p| p.get_row().get_min_val()
+                        == &Some(Row::new(vec![TableValue::Int(tenant), TableValue::Null, TableValue::Null]))));
+                }
+
+                let query = "SELECT id, amount FROM foo.orders WHERE tenant_id = 2 ORDER BY 1";
+                let planner = QueryPlannerImpl::new(
+                    services.meta_store.clone(),
+                    services.remote_fs.clone(),
+                    Config::test("tenant_column_partitions").config_obj(),
+                    S3ImportProgress::new(),
+                );
+                let statement = match CubeStoreParser::new(query)
+                    .unwrap()
+                    .parse_statement()
+                    .unwrap()
+                {
+                    CubeStoreStatement::Statement(statement) => statement,
+                    s => panic!("Unexpected statement: {:?}", s),
+                };
+                match planner
+                    .logical_plan(DFStatement::Statement(statement))
+                    .await
+                    .unwrap()
+                {
+                    QueryPlan::Select(plan) => {
+                        assert_eq!(plan.index_snapshots()[0].partitions().len(), 1);
+                        let explanation = plan.explain_index_selection();
+                        assert!(
+                            explanation.ends_with(&format!(
+                                "1 partitions of {} by tenant column tenant_id",
+                                partitions.len()
+                            )),
+                            "{}",
+                            explanation
+                        );
+                    }
+                    QueryPlan::Meta(plan) => panic!("Unexpected plan: {:?}", plan),
+                }
+                assert_eq!(
+                    service.exec_query(query).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(4), TableValue::Int(40)])]
+                );
+
+                // Rows written later are routed to their tenant's partitions
+                service
+                    .exec_query("INSERT INTO foo.orders (id, tenant_id, amount) VALUES (0, 2, 5), (8, 4, 80)")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    service.exec_query(query).await.unwrap().get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(0), TableValue::Int(5)]),
+                        Row::new(vec![TableValue::Int(4), TableValue::Int(40)])
+                    ]
+                );
+                assert_eq!(
+                    service
+                        .exec_query("SELECT id FROM foo.orders WHERE tenant_id IN (3, 4) ORDER BY 1")
+                        .await
+                        .unwrap()
+                        .get_rows(),
+                    &vec![
+                        Row::new(vec![TableValue::Int(5)]),
+                        Row::new(vec![TableValue::Int(6)]),
+                        Row::new(vec![TableValue::Int(7)]),
+                        Row::new(vec![TableValue::Int(8)])
+                    ]
+                );
+                assert_eq!(
+                    service
+                        .exec_query("SELECT count(*), sum(amount) FROM foo.orders WHERE tenant_id = 1")
+                        .await
+                        .unwrap()
+                        .get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(3), TableValue::Int(60)])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn router_plans_read_partitions_at_once() {
+        Config::test("router_plans_read_partitions_at_once")
+            .update_config(|mut config| {
+                config.compaction_chunks_count_threshold = 0;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.a (id int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.b (id int)")
+                    .await
+                    .unwrap();
+
+                let listener = services.cluster.job_result_listener();
+                service
+                    .exec_query("INSERT INTO foo.b (id) VALUES (1), (2)")
+                    .await
+                    .unwrap();
+                listener
+                    .wait_for_job_results(vec![(
+                        RowKey::Table(TableId::Partitions, 2),
+                        JobType::PartitionCompaction,
+                    )])
+                    .await
+                    .unwrap();
+
+                let config = Config::test("router_plans_read_partitions_at_once");
+                let planner = QueryPlannerImpl::new(
+                    services.meta_store.clone(),
+                    services.remote_fs.clone(),
+                    config.config_obj(),
+                    S3ImportProgress::new(),
+                );
+                async fn plan(planner: &QueryPlannerImpl, query: &str) -> SerializedPlan {
+                    let statement = match CubeStoreParser::new(query)
+                        .unwrap()
+                        .parse_statement()
+                        .unwrap()
+                    {
+                        CubeStoreStatement::Statement(statement) => statement,
+                        s => panic!("Unexpected statement: {:?}", s),
+                    };
+                    match planner
+                        .logical_plan(DFStatement::Statement(statement))
+                        .await
+                        .unwrap()
+                    {
+                        QueryPlan::Select(plan) => plan,
+                        QueryPlan::Meta(plan) => panic!("Unexpected plan: {:?}", plan),
+                    }
+                }
+
+                let a_plan = plan(&planner, "SELECT count(*) FROM foo.a").await;
+                // Partition of foo.a the first plan resolved is compacted before the second one
+                let listener = services.cluster.job_result_listener();
+                service
+                    .exec_query("INSERT INTO foo.a (id) VALUES (1), (2), (3)")
+                    .await
+                    .unwrap();
+                listener
+                    .wait_for_job_results(vec![(
+                        RowKey::Table(TableId::Partitions, 1),
+                        JobType::PartitionCompaction,
+                    )])
+                    .await
+                    .unwrap();
+                let b_plan = plan(&planner, "SELECT count(*) FROM foo.b").await;
+
+                let results = QueryExecutorImpl::new(config.config_obj())
+                    .execute_router_plans(
+                        vec![a_plan, b_plan],
+                        services.meta_store.clone(),
+                        services.cluster.clone(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    results[0].get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(3)])]
+                );
+                assert_eq!(
+                    results[1].get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(2)])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn drop_table_deletes_files_after_grace_period() {
+        Config::test("drop_table_deletes_files_after_grace_period")
+            .update_config(|mut config| {
+                config.file_deletion_grace_period = 2;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, amount int)")
+                    .await
+                    .unwrap();
+
+                let listener = services.cluster.job_result_listener();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (1, 10), (2, 20)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (3, 30), (4, 40)")
+                    .await
+                    .unwrap();
+                listener
+                    .wait_for_job_results(vec![(
+                        RowKey::Table(TableId::Partitions, 1),
+                        JobType::PartitionCompaction,
+                    )])
+                    .await
+                    .unwrap();
+
+                let config = Config::test("drop_table_deletes_files_after_grace_period");
+                let planner = QueryPlannerImpl::new(
+                    services.meta_store.clone(),
+                    services.remote_fs.clone(),
+                    config.config_obj(),
+                    S3ImportProgress::new(),
+                );
+                let statement =
+                    match CubeStoreParser::new("SELECT count(*), sum(amount) FROM foo.orders")
+                        .unwrap()
+                        .parse_statement()
+                        .unwrap()
+                    {
+                        CubeStoreStatement::Statement(statement) => statement,
+                        s => panic!("Unexpected statement: {:?}", s),
+                    };
+                let plan = match planner
+                    .logical_plan(DFStatement::Statement(statement))
+                    .await
+                    .unwrap()
+                {
+                    QueryPlan::Select(plan) => plan,
+                    QueryPlan::Meta(plan) => panic!("Unexpected plan: {:?}", plan),
+                };
+                let mut files = Vec::new();
+                for (partition, chunks) in services
+                    .meta_store
+                    .get_active_partitions_and_chunks_by_index_id_for_select(1)
+                    .await
+                    .unwrap()
+                {
+                    files.extend(partition.get_row().get_full_name(partition.get_id()));
+                    files.extend(
+                        chunks
+                            .iter()
+                            .map(|c| ChunkStore::chunk_remote_path(c.get_id())),
+                    );
+                }
+                assert!(!files.is_empty());
+
+                service.exec_query("DROP TABLE foo.orders").await.unwrap();
+
+                let mut pending = Vec::new();
+                let mut attempts = 0;
+                while !files.iter().all(|f| pending.contains(f)) && attempts < 50 {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    pending = services.scheduler.pending_file_deletions().await;
+                    attempts += 1;
+                }
+                assert!(files.iter().all(|f| pending.contains(f)));
+
+                // Query planned before the drop still reads the files during the grace period
+                let result = QueryExecutorImpl::new(config.config_obj())
+                    .execute_router_plan(plan, services.cluster.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(4), TableValue::Int(100)])]
+                );
+                let orphan_files = service
+                    .exec_query("SELECT file_name FROM system.orphan_files")
+                    .await
+                    .unwrap();
+                for file in files.iter() {
+                    assert!(orphan_files
+                        .get_rows()
+                        .contains(&Row::new(vec![TableValue::String(file.clone())])));
+                }
+
+                let mut attempts = 0;
+                while !services.scheduler.pending_file_deletions().await.is_empty() && attempts < 50
+                {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    attempts += 1;
+                }
+                for file in files.iter() {
+                    assert!(services.remote_fs.list(file).await.unwrap().is_empty());
+                }
+                assert!(service
+                    .exec_query("SELECT file_name FROM system.orphan_files")
+                    .await
+                    .unwrap()
+                    .get_rows()
+                    .is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn orphan_files_are_deleted_on_start() {
+        Config::test("orphan_files_are_deleted_on_start")
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, amount int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (1, 10), (2, 20)")
+                    .await
+                    .unwrap();
+
+                // Left by a table dropped before a restart
+                let local_path = services.remote_fs.local_path().await;
+                std::fs::write(format!("{}/1000000.chunk.parquet", local_path), "").unwrap();
+                services
+                    .remote_fs
+                    .upload_file("1000000.chunk.parquet")
+                    .await
+                    .unwrap();
+
+                services.scheduler.delete_orphan_files().await.unwrap();
+                let mut attempts = 0;
+                while !services
+                    .remote_fs
+                    .list("1000000.chunk.parquet")
+                    .await
+                    .unwrap()
+                    .is_empty()
+                    && attempts < 50
+                {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    attempts += 1;
+                }
+                assert!(services
+                    .remote_fs
+                    .list("1000000.chunk.parquet")
+                    .await
+                    .unwrap()
+                    .is_empty());
+                assert_eq!(
+                    service
+                        .exec_query("SELECT sum(amount) FROM foo.orders")
+                        .await
+                        .unwrap()
+                        .get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(30)])]
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn insert_select() {
+        Config::run_test("insert_select", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service
+                .exec_query("CREATE TABLE foo.orders (id int, city text, amount int)")
+                .await
+                .unwrap();
+            service
+                .exec_query("CREATE TABLE foo.city_totals (city text, total int)")
+                .await
+                .unwrap();
+            service
+                .exec_query(
+                    "INSERT INTO foo.orders (id, city, amount) VALUES \
+                     (1, 'London', 10), (2, 'Paris', 20), (3, 'London', 30), (4, 'Berlin', 40)",
+                )
+                .await
+                .unwrap();
+
+            service
+                .exec_query(
+                    "INSERT INTO foo.city_totals (city, total) \
+                     SELECT city, sum(amount) FROM foo.orders GROUP BY city",
+                )
+                .await
+                .unwrap();
+
+            let totals = "SELECT city, total FROM foo.city_totals ORDER BY city";
+            let expected = vec![
+                Row::new(vec![
+                    TableValue::String("Berlin".to_string()),
+                    TableValue::Int(40),
+                ]),
+                Row::new(vec![
+                    TableValue::String("London".to_string()),
+                    TableValue::Int(40),
+                ]),
+                Row::new(vec![
+                    TableValue::String("Paris".to_string()),
+                    TableValue::Int(20),
+                ]),
+            ];
+            assert_eq!(
+                service.exec_query(totals).await.unwrap().get_rows(),
+                &expected
+            );
+
+            let error = service
+                .exec_query(
+                    "INSERT INTO foo.city_totals (city, total) \
+                     SELECT amount, city FROM foo.orders",
+                )
+                .await
+                .unwrap_err();
+            assert!(
+                error.to_string().contains(
+                    "Can't insert INT column 'amount' of SELECT into STRING column 'city'"
+                ),
+                "{}",
+                error
+            );
+            let error = service
+                .exec_query("INSERT INTO foo.city_totals (city, total) SELECT city FROM foo.orders")
+                .await
+                .unwrap_err();
+            assert!(
+                error
+                    .to_string()
+                    .contains("INSERT has 2 target columns but SELECT returns 1 columns"),
+                "{}",
+                error
+            );
+            // Failed inserts don't leave any rows behind
+            assert_eq!(
+                service.exec_query(totals).await.unwrap().get_rows(),
+                &expected
+            );
+
+            // Rollups are combined on the router before they're inserted or streamed
+            let rollup = "SELECT city, sum(amount) FROM foo.orders GROUP BY ROLLUP(city)";
+            service
+                .exec_query("CREATE TABLE foo.rollup_totals (city text, total int)")
+                .await
+                .unwrap();
+            service
+                .exec_query(&format!(
+                    "INSERT INTO foo.rollup_totals (city, total) {}",
+                    rollup
+                ))
+                .await
+                .unwrap();
+            let mut expected_rollup = expected.clone();
+            expected_rollup.push(Row::new(vec![TableValue::Null, TableValue::Int(100)]));
+            assert_eq!(
+                service
+                    .exec_query("SELECT city, total FROM foo.rollup_totals ORDER BY city")
+                    .await
+                    .unwrap()
+                    .get_rows(),
+                &expected_rollup
+            );
+            let batches = service
+                .exec_query_stream(&format!("{} ORDER BY 1", rollup))
+                .await
+                .unwrap()
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            let streamed = batches_to_rows(&batches)
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap();
+            assert_eq!(streamed, expected_rollup);
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn show_tables_and_describe() {
+        Config::run_test("show_tables_and_describe", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+            service.exec_query("CREATE SCHEMA bar").await.unwrap();
+            service
+                .exec_query(
+                    "CREATE TABLE foo.orders (id int, city text, amount decimal(10, 2)) UNIQUE KEY (id)",
+                )
+                .await
+                .unwrap();
+            service
+                .exec_query("CREATE TABLE foo.customers (id int, created timestamp)")
+                .await
+                .unwrap();
+
+            let column_names = |df: &DataFrame| {
+                df.get_columns()
+                    .iter()
+                    .map(|c| c.get_name().to_string())
+                    .collect::<Vec<_>>()
+            };
+            let strings = |values: Vec<&str>| {
+                Row::new(
+                    values
+                        .into_iter()
+                        .map(|v| TableValue::String(v.to_string()))
+                        .collect(),
+                )
+            };
+
+            let schemas = service.exec_query("SHOW SCHEMAS").await.unwrap();
+            assert_eq!(column_names(&schemas), vec!["Database"]);
+            assert_eq!(
+                schemas.get_rows(),
+                &vec![strings(vec!["bar"]), strings(vec!["foo"])]
+            );
+
+            let tables = service.exec_query("SHOW TABLES FROM foo").await.unwrap();
+            assert_eq!(column_names(&tables), vec!["Tables_in_foo"]);
+            assert_eq!(
+                tables.get_rows(),
+                &vec![strings(vec!["customers"]), strings(vec!["orders"])]
+            );
+
+            let tables = service.exec_query("SHOW TABLES IN bar").await.unwrap();
+            assert_eq!(column_names(&tables), vec!["Tables_in_bar"]);
+            assert!(tables.get_rows().is_empty());
+
+            let tables = service.exec_query("SHOW TABLES").await.unwrap();
+            assert_eq!(column_names(&tables), vec!["Schema", "Table"]);
+            assert_eq!(
+                tables.get_rows(),
+                &vec![
+                    strings(vec!["foo", "customers"]),
+                    strings(vec!["foo", "orders"])
+                ]
+            );
+
+            let columns = service.exec_query("DESCRIBE foo.orders").await.unwrap();
+            assert_eq!(column_names(&columns), vec!["Field", "Type", "Null", "Key"]);
+            assert_eq!(
+                columns.get_rows(),
+                &vec![
+                    strings(vec!["id", "INT", "YES", "PRI"]),
+                    strings(vec!["city", "STRING", "YES", ""]),
+                    strings(vec!["amount", "DECIMAL(10, 2)", "YES", ""]),
+                ]
+            );
+
+            assert!(service.exec_query("SHOW TABLES FROM baz").await.is_err());
+            assert!(service.exec_query("DESCRIBE orders").await.is_err());
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn restore_metastore() {
+        Config::test("restore_metastore")
+            .update_config(|mut config| {
+                config.metastore_snapshot_interval = 1;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.orders (id int, amount int)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (1, 10), (2, 20)")
+                    .await
+                    .unwrap();
+
+                tokio::time::delay_for(Duration::from_millis(1100)).await;
+                services.meta_store.run_upload().await.unwrap();
+
+                // Written to the snapshot logs only
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (3, 30)")
+                    .await
+                    .unwrap();
+                services
+                    .meta_store
+                    .wait_for_current_seq_to_sync()
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query("SYSTEM RESTORE METASTORE")
+                    .await
+                    .unwrap();
+
+                let query = "SELECT count(*), sum(amount) FROM foo.orders";
+                assert_eq!(
+                    service.exec_query(query).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(3), TableValue::Int(60)])]
+                );
+
+                service
+                    .exec_query("INSERT INTO foo.orders (id, amount) VALUES (4, 40)")
+                    .await
+                    .unwrap();
+                service
+                    .exec_query("CREATE TABLE foo.customers (id int)")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    service.exec_query(query).await.unwrap().get_rows(),
+                    &vec![Row::new(vec![TableValue::Int(4), TableValue::Int(100)])]
+                );
+                assert_eq!(services.meta_store.get_tables().await.unwrap().len(), 2);
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn retention_expires_partitions() {
+        Config::test("retention_expires_partitions")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 4;
+                config.compaction_chunks_count_threshold = 0;
+                // Partitions used by queries in the last 2 seconds aren't deleted
+                config.query_timeout = 1;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.events (t timestamp, id int)")
+                    .await
+                    .unwrap();
+
+                let listener = services.cluster.job_result_listener();
+
+                let now = chrono::Utc::now();
+                let recent = (0..4)
+                    .map(|i| {
+                        let t = now - chrono::Duration::minutes(i);
+                        format!("('{}', {})", t.format("%Y-%m-%dT%H:%M:%S%.3fZ"), i)
+                    })
+                    .collect::<Vec<_>>();
+                let old = (1..=8)
+                    .map(|i| format!("('2000-01-0{}T00:00:00.000Z', {})", i, i))
+                    .collect::<Vec<_>>();
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.events (t, id) VALUES {}, {}",
+                        old.join(", "),
+                        recent.join(", ")
+                    ))
+                    .await
+                    .unwrap();
+
+                listener
+                    .wait_for_job_results(vec![(
+                        RowKey::Table(TableId::Partitions, 1),
+                        JobType::PartitionCompaction,
+                    )])
+                    .await
+                    .unwrap();
+
+                service
+                    .exec_query("ALTER TABLE foo.events SET RETENTION '30 days' ON t")
+                    .await
+                    .unwrap();
+
+                // Rows of an index not sorted by the retention column would outlive the others
+                let err = service
+                    .exec_query("CREATE INDEX by_id ON foo.events (id, t)")
+                    .await
+                    .unwrap_err();
+                assert!(
+                    err.to_string().contains(
+                        "Retention column t should be the first column of the 'by_id' index"
+                    ),
+                    "{}",
+                    err
+                );
+
+                // Query planned before the expiration holds partitions in its snapshot
+                let snapshot = services
+                    .meta_store
+                    .get_active_partitions_and_chunks_by_index_id_for_select(1)
+                    .await
+                    .unwrap();
+                assert_eq!(snapshot.len(), 3);
+                let expired = snapshot
+                    .iter()
+                    .map(|(p, _)| p.clone())
+                    .find(|p| p.get_row().get_min_val().is_none())
+                    .unwrap();
+                let expired_file = expired.get_row().get_full_name(expired.get_id()).unwrap();
+
+                services.scheduler.expire_partitions().await.unwrap();
+
+                // Partition with both old and recent rows is kept
+                let result = service
+                    .exec_query("SELECT count(*) from foo.events")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows(), &vec![Row::new(vec![TableValue::Int(8)])]);
+                assert_eq!(
+                    services
+                        .meta_store
+                        .get_active_partitions_by_index_id(1)
+                        .await
+                        .unwrap()
+                        .len(),
+                    3
+                );
+
+                // Files of the snapshot are there during the grace period
+                services.scheduler.expire_partitions().await.unwrap();
+                assert!(services
+                    .meta_store
+                    .get_partition(expired.get_id())
+                    .await
+                    .is_ok());
+                assert!(!services
+                    .remote_fs
+                    .list(&expired_file)
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                tokio::time::delay_for(Duration::from_millis(2500)).await;
+                services.scheduler.expire_partitions().await.unwrap();
+                assert!(services
+                    .meta_store
+                    .get_partition(expired.get_id())
+                    .await
+                    .is_err());
+                let mut attempts = 0;
+                while !services
+                    .remote_fs
+                    .list(&expired_file)
+                    .await
+                    .unwrap()
+                    .is_empty()
+                    && attempts < 50
+                {
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                    attempts += 1;
+                }
+                assert!(services
+                    .remote_fs
+                    .list(&expired_file)
+                    .await
+                    .unwrap()
+                    .is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn queries_during_partition_split() {
+        Config::test("queries_during_partition_split")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 10;
+                // Only outgrown partitions are compacted
+                config.compaction_chunks_count_threshold = 100;
+                config.compaction_chunks_total_size_threshold = 100;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.events (id int)")
+                    .await
+                    .unwrap();
+
+                let (mut count, mut sum) = (0, 0);
+                for batch in 0..4 {
+                    let ids = (batch * 8..(batch + 1) * 8).collect::<Vec<i64>>();
+                    service
+                        .exec_query(&format!(
+                            "INSERT INTO foo.events (id) VALUES {}",
+                            ids.iter().map(|id| format!("({})", id)).join(", ")
+                        ))
+                        .await
+                        .unwrap();
+                    count += ids.len() as i64;
+                    sum += ids.iter().sum::<i64>();
+
+                    // Splits run in the background and swap partitions under the queries
+                    for _ in 0..10 {
+                        let result = service
+                            .exec_query("SELECT count(*), sum(id) FROM foo.events")
+                            .await
+                            .unwrap();
+                        assert_eq!(
+                            result.get_rows()[0],
+                            Row::new(vec![TableValue::Int(count), TableValue::Int(sum)])
+                        );
+                    }
+                }
+
+                let mut attempts = 0;
+                loop {
+                    let partitions = services
+                        .meta_store
+                        .get_active_partitions_by_index_id(1)
+                        .await
+                        .unwrap();
+                    let mut split = partitions.len() > 1;
+                    for p in partitions.iter() {
+                        split = split
+                            && p.get_row().main_table_row_count() <= 10
+                            && services
+                                .meta_store
+                                .get_partition_chunk_sizes(p.get_id())
+                                .await
+                                .unwrap()
+                                == 0;
+                    }
+                    if split {
+                        break;
+                    }
+                    attempts += 1;
+                    assert!(attempts < 100, "Partitions aren't split: {:?}", partitions);
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                }
+
+                let result = service
+                    .exec_query("SELECT count(*), sum(id) FROM foo.events")
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    result.get_rows()[0],
+                    Row::new(vec![TableValue::Int(count), TableValue::Int(sum)])
+                );
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn partition_split_by_size() {
+        Config::test("partition_split_by_size")
+            .update_config(|mut config| {
+                config.partition_split_threshold = 1000000;
+                config.partition_size_split_threshold = 1;
+                config.compaction_chunks_count_threshold = 100;
+                config.compaction_chunks_total_size_threshold = 10;
+                config
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.events (id int)")
+                    .await
+                    .unwrap();
+
+                // Too many rows for the chunk thresholds: compacted into the first partition file
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.events (id) VALUES {}",
+                        (0..20).map(|id| format!("({})", id)).join(", ")
+                    ))
+                    .await
+                    .unwrap();
+                let mut attempts = 0;
+                loop {
+                    let partitions = services
+                        .meta_store
+                        .get_active_partitions_by_index_id(1)
+                        .await
+                        .unwrap();
+                    if partitions.len() == 1 && partitions[0].get_row().file_size().is_some() {
+                        break;
+                    }
+                    attempts += 1;
+                    assert!(
+                        attempts < 100,
+                        "Partition isn't compacted: {:?}",
+                        partitions
+                    );
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                }
+
+                // Under the chunk and row thresholds but the file outgrows the size threshold
+                service
+                    .exec_query("INSERT INTO foo.events (id) VALUES (20), (21), (22)")
+                    .await
+                    .unwrap();
+                let mut attempts = 0;
+                loop {
+                    let partitions = services
+                        .meta_store
+                        .get_active_partitions_by_index_id(1)
+                        .await
+                        .unwrap();
+                    if partitions.len() > 1 {
+                        break;
+                    }
+                    attempts += 1;
+                    assert!(attempts < 100, "Partition isn't split: {:?}", partitions);
+                    tokio::time::delay_for(Duration::from_millis(100)).await;
+                }
+
+                let result = service
+                    .exec_query("SELECT count(*) FROM foo.events")
+                    .await
+                    .unwrap();
+                assert_eq!(result.get_rows()[0], Row::new(vec![TableValue::Int(23)]));
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn window_functions() {
+        Config::run_test("window_functions", async move |services| {
+            let service = services.sql_service;
+
+            service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+            service
+                .exec_query("CREATE TABLE foo.sales (city text, t timestamp, amount int)")
+                .await
+                .unwrap();
+
+            service
+                .exec_query(
+                    "INSERT INTO foo.sales (city, t, amount) VALUES \
+                    ('a', '2020-01-01T00:00:00.000Z', 1), ('a', '2020-01-01T10:00:00.000Z', 2), \
+                    ('a', '2020-01-02T00:00:00.000Z', 5), ('a', '2020-01-03T00:00:00.000Z', 4), \
+                    ('b', '2020-01-02T12:00:00.000Z', 10)",
+                )
+                .await
+                .unwrap();
+
+            let day = |d: i64| {
+                TableValue::Timestamp(TimestampValue::new(
+                    1577836800000000000 + (d - 1) * 86400000000000,
+                ))
+            };
+            let row = |city: &str, d: i64, total: i64, prev: Option<i64>, n: i64, r: i64| {
+                Row::new(vec![
+                    TableValue::String(city.to_string()),
+                    day(d),
+                    TableValue::Int(total),
+                    prev.map(TableValue::Int).unwrap_or(TableValue::Null),
+                    TableValue::Int(n),
+                    TableValue::Int(r),
+                ])
+            };
+
+            let result = service
+                .exec_query(
+                    "SELECT city, date_trunc('day', t) `day`, sum(amount) total, \
+                    LAG(sum(amount), 1) OVER (PARTITION BY city ORDER BY `day`) prev, \
+                    ROW_NUMBER() OVER (PARTITION BY city ORDER BY `day`) n, \
+                    RANK() OVER (ORDER BY sum(amount) DESC) r \
+                    FROM foo.sales GROUP BY 1, 2 ORDER BY 1, 2",
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                result.get_rows(),
+                &vec![
+                    row("a", 1, 3, None, 1, 4),
+                    row("a", 2, 5, Some(3), 2, 2),
+                    row("a", 3, 4, Some(5), 3, 3),
+                    row("b", 2, 10, None, 1, 1),
+                ]
+            );
+
+            let result = service
+                .exec_query(
+                    "SELECT city, date_trunc('day', t) `day`, sum(amount) total, \
+                    LAG(sum(amount), 1) OVER (PARTITION BY city ORDER BY `day`) prev, \
+                    ROW_NUMBER() OVER (PARTITION BY city ORDER BY `day`) n, \
+                    RANK() OVER (ORDER BY sum(amount) DESC) r \
+                    FROM foo.sales GROUP BY 1, 2 ORDER BY r LIMIT 2",
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                result.get_rows(),
+                &vec![row("b", 2, 10, None, 1, 1), row("a", 2, 5, Some(3), 2, 2)]
+            );
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn union_all_of_aggregates() {
+        Config::test("union_all_of_aggregates")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 10;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                for table in vec!["foo.t1", "foo.t2"] {
+                    service
+                        .exec_query(&format!("CREATE TABLE {} (id int, day int, v int)", table))
+                        .await
+                        .unwrap();
+                }
+                // Tables differ in size to end up with different partition counts
+                let values = |count: i64| {
+                    (0..count)
+                        .map(|i| format!("({}, {}, {})", i, i % 3, 1))
+                        .join(", ")
+                };
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.t1 (id, day, v) VALUES {}",
+                        values(60)
+                    ))
+                    .await
+                    .unwrap();
+                service
+                    .exec_query(&format!(
+                        "INSERT INTO foo.t2 (id, day, v) VALUES {}",
+                        values(6)
+                    ))
+                    .await
+                    .unwrap();
+
+                let result = service
+                    .exec_query(
+                        "SELECT day, sum(v) FROM foo.t1 GROUP BY day \
+                         UNION ALL SELECT day, sum(v) FROM foo.t2 GROUP BY day",
+                    )
+                    .await
+                    .unwrap();
+                let mut rows = result.get_rows().clone();
+                rows.sort_by(|a, b| a.values().cmp(b.values()));
+                assert_eq!(
+                    rows,
+                    vec![(0, 2), (0, 20), (1, 2), (1, 20), (2, 2), (2, 20)]
+                        .into_iter()
+                        .map(|(day, v)| Row::new(vec![TableValue::Int(day), TableValue::Int(v)]))
+                        .collect::<Vec<_>>()
+                );
+
+                // Mismatching column types are rejected by the planner or the split
+                assert!(service
+                    .exec_query(
+                        "SELECT day, sum(v) FROM foo.t1 GROUP BY day \
+                         UNION ALL SELECT 'a', sum(v) FROM foo.t2 GROUP BY day",
+                    )
+                    .await
+                    .is_err());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn group_by_rollup() {
+        Config::test("group_by_rollup")
+            .update_config(|mut c| {
+                c.partition_split_threshold = 10;
+                c
+            })
+            .start_test(async move |services| {
+                let service = services.sql_service;
+
+                service.exec_query("CREATE SCHEMA foo").await.unwrap();
+
+                service
+                    .exec_query("CREATE TABLE foo.sales (country text, city text, amount int)")
+                    .await
+                    .unwrap();
+
+                // Separate inserts to aggregate over multiple chunks
+                for _ in 0..8 {
+                    service
+                        .exec_query(
+                            "INSERT INTO foo.sales (country, city, amount) VALUES \
+                             ('US', 'NY', 1), ('US', 'SF', 2), ('US', NULL, 3), \
+                             ('DE', 'Berlin', 4), ('DE', 'Munich', 5)",
+                        )
+                        .await
+                        .unwrap();
+                }
+
+                let result = service
+                    .exec_query(
+                        "SELECT country, city, sum(amount) total, GROUPING(country) gc, \
+                         GROUPING(city) gci FROM foo.sales GROUP BY ROLLUP(country, city) \
+                         ORDER BY 1, 2, 5",
+                    )
+                    .await
+                    .unwrap();
+                let row =
+                    |country: Option<&str>, city: Option<&str>, total: i64, gc: i64, gci: i64| {
+                        let string = |s: Option<&str>| {
+                            s.map(|s| TableValue::String(s.to_string()))
+                                .unwrap_or(TableValue::Null)
+                        };
+                        Row::new(vec![
+                            string(country),
+                            string(city),
+                            TableValue::Int(total),
+                            TableValue::Int(gc),
+                            TableValue::Int(gci),
+                        ])
+                    };
+                assert_eq!(
+                    result.get_rows(),
+                    &vec![
+                        row(Some("DE"), Some("Berlin"), 32, 0, 0),
+                        row(Some("DE"), Some("Munich"), 40, 0, 0),
+                        row(Some("DE"), None, 72, 0, 1),
+                        row(Some("US"), Some("NY"), 8, 0, 0),
+                        row(Some("US"), Some("SF"), 16, 0, 0),
+                        row(Some("US"), None, 24, 0, 0),
+                        row(Some("US"), None, 48, 0, 1),
+                        row(None, None, 120, 1, 1),
+                    ]
+                );
+            })
+            .await;
+    }
 }
 
 impl SqlServiceImpl {
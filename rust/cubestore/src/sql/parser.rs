@@ -1,4 +1,4 @@
-use sqlparser::ast::{ObjectName, Statement as SQLStatement};
+use sqlparser::ast::{Ident, ObjectName, Statement as SQLStatement};
 use sqlparser::dialect::keywords::Keyword;
 use sqlparser::dialect::Dialect;
 use sqlparser::parser::{Parser, ParserError};
@@ -31,11 +31,37 @@ pub enum Statement {
     CreateTable {
         create_table: SQLStatement,
         indexes: Vec<SQLStatement>,
+        /// `UNIQUE KEY (<columns>)` of the table
+        unique_key: Option<Vec<Ident>>,
+        /// `TENANT KEY (<column>)` of the table
+        tenant_key: Option<Ident>,
     },
     CreateSchema {
         schema_name: ObjectName,
         if_not_exists: bool,
     },
+    /// `ALTER TABLE <table_name> SET RETENTION '<retention>' ON <column>`
+    SetRetention {
+        table_name: ObjectName,
+        retention: String,
+        column: Ident,
+    },
+    /// `SYSTEM RESTORE METASTORE`
+    RestoreMetastore,
+    /// `SHOW SCHEMAS`
+    ShowSchemas,
+    /// `SHOW TABLES [FROM <schema>]`
+    ShowTables {
+        schema_name: Option<Ident>,
+    },
+    /// `DESCRIBE <table_name>`
+    Describe {
+        table_name: ObjectName,
+    },
+    /// `EXPLAIN <query>`
+    Explain {
+        statement: SQLStatement,
+    },
 }
 
 pub struct CubeStoreParser<'a> {
@@ -59,6 +85,30 @@ impl<'a> CubeStoreParser<'a> {
                     self.parser.next_token();
                     self.parse_create()
                 }
+                Keyword::ALTER => {
+                    self.parser.next_token();
+                    self.parse_alter()
+                }
+                Keyword::SHOW => {
+                    self.parser.next_token();
+                    self.parse_show()
+                }
+                _ if w.value.eq_ignore_ascii_case("describe") => {
+                    self.parser.next_token();
+                    Ok(Statement::Describe {
+                        table_name: self.parser.parse_object_name()?,
+                    })
+                }
+                _ if w.value.eq_ignore_ascii_case("explain") => {
+                    self.parser.next_token();
+                    Ok(Statement::Explain {
+                        statement: self.parser.parse_statement()?,
+                    })
+                }
+                _ if w.value.eq_ignore_ascii_case("system") => {
+                    self.parser.next_token();
+                    self.parse_system()
+                }
                 _ => Ok(Statement::Statement(self.parser.parse_statement()?)),
             },
             _ => Ok(Statement::Statement(self.parser.parse_statement()?)),
@@ -90,6 +140,29 @@ impl<'a> CubeStoreParser<'a> {
             ..
         } = statement
         {
+            let unique_key = if self.parser.parse_keywords(&[Keyword::UNIQUE, Keyword::KEY]) {
+                self.parser.expect_token(&Token::LParen)?;
+                let columns = self
+                    .parser
+                    .parse_comma_separated(Parser::parse_identifier)?;
+                self.parser.expect_token(&Token::RParen)?;
+                Some(columns)
+            } else {
+                None
+            };
+
+            let tenant_key = match self.parser.peek_token() {
+                Token::Word(w) if w.value.eq_ignore_ascii_case("tenant") => {
+                    self.parser.next_token();
+                    self.parser.expect_keyword(Keyword::KEY)?;
+                    self.parser.expect_token(&Token::LParen)?;
+                    let column = self.parser.parse_identifier()?;
+                    self.parser.expect_token(&Token::RParen)?;
+                    Some(column)
+                }
+                _ => None,
+            };
+
             let mut indexes = Vec::new();
 
             while self.parser.parse_keyword(Keyword::INDEX) {
@@ -102,6 +175,11 @@ impl<'a> CubeStoreParser<'a> {
                 None
             };
 
+            let mut with_options = with_options;
+            if location.is_some() {
+                with_options.extend(self.parser.parse_with_options()?);
+            }
+
             Ok(Statement::CreateTable {
                 create_table: SQLStatement::CreateTable {
                     or_replace,
@@ -117,6 +195,8 @@ impl<'a> CubeStoreParser<'a> {
                     without_rowid,
                 },
                 indexes,
+                unique_key,
+                tenant_key,
             })
         } else {
             Ok(Statement::Statement(statement))
@@ -142,6 +222,75 @@ impl<'a> CubeStoreParser<'a> {
         })
     }
 
+    fn parse_alter(&mut self) -> Result<Statement, ParserError> {
+        if !self.parser.parse_keyword(Keyword::TABLE) {
+            self.parser.prev_token();
+            return Ok(Statement::Statement(self.parser.parse_statement()?));
+        }
+        let table_name = self.parser.parse_object_name()?;
+        if !self.parser.parse_keyword(Keyword::SET) {
+            // Rewinds ALTER TABLE and the dot separated name for the generic ALTER TABLE parser
+            for _ in 0..2 * table_name.0.len() + 1 {
+                self.parser.prev_token();
+            }
+            return Ok(Statement::Statement(self.parser.parse_statement()?));
+        }
+        match self.parser.next_token() {
+            Token::Word(w) if w.value.eq_ignore_ascii_case("retention") => {}
+            t => {
+                return Err(ParserError::ParserError(format!(
+                    "Expected RETENTION, found: {}",
+                    t
+                )))
+            }
+        }
+        let retention = self.parser.parse_literal_string()?;
+        self.parser.expect_keyword(Keyword::ON)?;
+        let column = self.parser.parse_identifier()?;
+        Ok(Statement::SetRetention {
+            table_name,
+            retention,
+            column,
+        })
+    }
+
+    fn parse_show(&mut self) -> Result<Statement, ParserError> {
+        match self.parser.next_token() {
+            Token::Word(w) if w.value.eq_ignore_ascii_case("schemas") => Ok(Statement::ShowSchemas),
+            Token::Word(w) if w.value.eq_ignore_ascii_case("tables") => {
+                let schema_name = if self.parser.parse_keyword(Keyword::FROM)
+                    || self.parser.parse_keyword(Keyword::IN)
+                {
+                    Some(self.parser.parse_identifier()?)
+                } else {
+                    None
+                };
+                Ok(Statement::ShowTables { schema_name })
+            }
+            _ => {
+                // Rewinds SHOW for the generic SHOW parser
+                self.parser.prev_token();
+                self.parser.prev_token();
+                Ok(Statement::Statement(self.parser.parse_statement()?))
+            }
+        }
+    }
+
+    fn parse_system(&mut self) -> Result<Statement, ParserError> {
+        for expected in &["RESTORE", "METASTORE"] {
+            match self.parser.next_token() {
+                Token::Word(w) if w.value.eq_ignore_ascii_case(expected) => {}
+                t => {
+                    return Err(ParserError::ParserError(format!(
+                        "Expected {}, found: {}",
+                        expected, t
+                    )))
+                }
+            }
+        }
+        Ok(Statement::RestoreMetastore)
+    }
+
     fn parse_create_schema(&mut self) -> Result<Statement, ParserError> {
         let if_not_exists =
             self.parser
@@ -0,0 +1,414 @@
+use crate::sql::parser::{CubeStoreParser, Statement as CubeStoreStatement};
+use crate::table::TableValue;
+use crate::CubeError;
+use bigdecimal::BigDecimal;
+use chrono::{SecondsFormat, TimeZone, Utc};
+use sqlparser::ast::{
+    DataType, Expr, JoinConstraint, JoinOperator, Query, Select, SelectItem, SetExpr, Statement,
+    TableFactor, Value, Values,
+};
+use std::collections::HashSet;
+use std::mem;
+use std::str::FromStr;
+
+/// Statement with `?` placeholders parsed once and executed with different parameters. The
+/// parameters are bound into the parsed statement as typed literals, timestamps as casts so
+/// they compare to timestamp columns as is.
+#[derive(Debug)]
+pub struct PreparedStatement {
+    query: String,
+    /// Parsed query with `marker<i>` string literals in place of placeholders.
+    statement: CubeStoreStatement,
+    /// Prefix the query text doesn't contain.
+    marker: String,
+    param_count: usize,
+}
+
+impl PreparedStatement {
+    /// Parses `query` with placeholders replaced by marker literals and checks all of them are
+    /// in expressions parameters can be bound to.
+    pub fn parse(query: &str) -> Result<PreparedStatement, CubeError> {
+        // Same as for text queries
+        let query = query.replace("\\'", "''");
+        let parts = split_placeholders(&query);
+        let mut marker = "cubestore_param_".to_string();
+        while query.contains(&marker) {
+            marker.insert(0, '_');
+        }
+        let mut text = parts[0].clone();
+        for (i, part) in parts[1..].iter().enumerate() {
+            text += &quote(&format!("{}{}", marker, i));
+            text += part;
+        }
+        let statement = PreparedStatement {
+            statement: CubeStoreParser::new(&text)?.parse_statement()?,
+            param_count: parts.len() - 1,
+            marker,
+            query,
+        };
+        statement.bind(&vec![TableValue::Null; statement.param_count])?;
+        Ok(statement)
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    pub fn param_count(&self) -> usize {
+        self.param_count
+    }
+
+    /// Parsed statement with `params` in place of placeholders.
+    pub fn bind(&self, params: &[TableValue]) -> Result<CubeStoreStatement, CubeError> {
+        if params.len() != self.param_count {
+            return Err(CubeError::user(format!(
+                "Statement has {} parameters but {} are bound",
+                self.param_count,
+                params.len()
+            )));
+        }
+        let mut binder = Binder {
+            marker: &self.marker,
+            params,
+            bound: HashSet::new(),
+        };
+        let mut statement = self.statement.clone();
+        if let CubeStoreStatement::Statement(statement) = &mut statement {
+            binder.bind_statement(statement)?;
+        }
+        if binder.bound.len() != self.param_count {
+            return Err(CubeError::user(format!(
+                "Parameters are supported only in expressions of SELECT, INSERT and DELETE \
+                 statements: {}",
+                self.query
+            )));
+        }
+        Ok(statement)
+    }
+}
+
+struct Binder<'a> {
+    marker: &'a str,
+    params: &'a [TableValue],
+    /// Positions of the bound parameters.
+    bound: HashSet<usize>,
+}
+
+impl Binder<'_> {
+    fn bind_statement(&mut self, statement: &mut Statement) -> Result<(), CubeError> {
+        match statement {
+            Statement::Query(query) | Statement::Insert { source: query, .. } => {
+                self.bind_query(query)
+            }
+            Statement::Delete {
+                selection: Some(selection),
+                ..
+            } => self.bind_expr(selection, false),
+            _ => Ok(()),
+        }
+    }
+
+    fn bind_query(&mut self, query: &mut Query) -> Result<(), CubeError> {
+        self.bind_set_expr(&mut query.body)?;
+        for order_by in query.order_by.iter_mut() {
+            self.bind_expr(&mut order_by.expr, false)?;
+        }
+        Ok(())
+    }
+
+    fn bind_set_expr(&mut self, set_expr: &mut SetExpr) -> Result<(), CubeError> {
+        match set_expr {
+            SetExpr::Select(select) => self.bind_select(select),
+            SetExpr::Query(query) => self.bind_query(query),
+            SetExpr::SetOperation { left, right, .. } => {
+                self.bind_set_expr(left)?;
+                self.bind_set_expr(right)
+            }
+            // Values are converted to column types by INSERT
+            SetExpr::Values(Values(rows)) => rows
+                .iter_mut()
+                .flatten()
+                .try_for_each(|e| self.bind_expr(e, true)),
+        }
+    }
+
+    fn bind_select(&mut self, select: &mut Select) -> Result<(), CubeError> {
+        for item in select.projection.iter_mut() {
+            match item {
+                SelectItem::UnnamedExpr(expr) | SelectItem::ExprWithAlias { expr, .. } => {
+                    self.bind_expr(expr, false)?
+                }
+                _ => {}
+            }
+        }
+        for table in select.from.iter_mut() {
+            self.bind_table_factor(&mut table.relation)?;
+            for join in table.joins.iter_mut() {
+                self.bind_table_factor(&mut join.relation)?;
+                match &mut join.join_operator {
+                    JoinOperator::Inner(JoinConstraint::On(e))
+                    | JoinOperator::LeftOuter(JoinConstraint::On(e))
+                    | JoinOperator::RightOuter(JoinConstraint::On(e))
+                    | JoinOperator::FullOuter(JoinConstraint::On(e)) => self.bind_expr(e, false)?,
+                    _ => {}
+                }
+            }
+        }
+        if let Some(selection) = select.selection.as_mut() {
+            self.bind_expr(selection, false)?;
+        }
+        for expr in select.group_by.iter_mut() {
+            self.bind_expr(expr, false)?;
+        }
+        if let Some(having) = select.having.as_mut() {
+            self.bind_expr(having, false)?;
+        }
+        Ok(())
+    }
+
+    fn bind_table_factor(&mut self, table_factor: &mut TableFactor) -> Result<(), CubeError> {
+        match table_factor {
+            TableFactor::Derived { subquery, .. } => self.bind_query(subquery),
+            _ => Ok(()),
+        }
+    }
+
+    fn bind_expr(&mut self, expr: &mut Expr, in_values: bool) -> Result<(), CubeError> {
+        match expr {
+            Expr::Value(Value::SingleQuotedString(s)) => {
+                let position = match s
+                    .strip_prefix(self.marker)
+                    .and_then(|p| p.parse::<usize>().ok())
+                {
+                    Some(position) => position,
+                    None => return Ok(()),
+                };
+                *expr = param_expr(&self.params[position], in_values)?;
+                self.bound.insert(position);
+            }
+            Expr::BinaryOp { left, right, .. } => {
+                self.bind_expr(left, false)?;
+                self.bind_expr(right, false)?;
+            }
+            Expr::Nested(e)
+            | Expr::UnaryOp { expr: e, .. }
+            | Expr::IsNull(e)
+            | Expr::IsNotNull(e)
+            | Expr::Cast { expr: e, .. }
+            | Expr::Extract { expr: e, .. } => self.bind_expr(e, false)?,
+            Expr::Between {
+                expr: e, low, high, ..
+            } => {
+                self.bind_expr(e, false)?;
+                self.bind_expr(low, false)?;
+                self.bind_expr(high, false)?;
+            }
+            Expr::InList { expr: e, list, .. } => {
+                self.bind_expr(e, false)?;
+                for e in list.iter_mut() {
+                    self.bind_expr(e, false)?;
+                }
+            }
+            Expr::InSubquery {
+                expr: e, subquery, ..
+            } => {
+                self.bind_expr(e, false)?;
+                self.bind_query(subquery)?;
+            }
+            Expr::Function(function) => {
+                for e in function.args.iter_mut() {
+                    self.bind_expr(e, false)?;
+                }
+            }
+            Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                if let Some(operand) = operand.as_mut() {
+                    self.bind_expr(operand, false)?;
+                }
+                for e in conditions.iter_mut().chain(results.iter_mut()) {
+                    self.bind_expr(e, false)?;
+                }
+                if let Some(else_result) = else_result.as_mut() {
+                    self.bind_expr(else_result, false)?;
+                }
+            }
+            Expr::Subquery(query) | Expr::Exists(query) => self.bind_query(query)?,
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Literal of `value`. Timestamps are cast from strings except for `INSERT` values which are
+/// parsed by the column type.
+fn param_expr(value: &TableValue, in_values: bool) -> Result<Expr, CubeError> {
+    Ok(Expr::Value(match value {
+        TableValue::Null => Value::Null,
+        TableValue::Int(v) => Value::Number(v.to_string()),
+        // Number literals are parsed by decimal columns without going through floats
+        TableValue::Decimal(v) => Value::Number(
+            BigDecimal::from_str(v)
+                .map_err(|e| CubeError::user(format!("Can't bind decimal {}: {}", v, e)))?
+                .to_string(),
+        ),
+        TableValue::String(v) => Value::SingleQuotedString(v.clone()),
+        TableValue::Timestamp(v) => {
+            let value = Expr::Value(Value::SingleQuotedString(
+                Utc.timestamp_nanos(v.get_time_stamp())
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            ));
+            if in_values {
+                return Ok(value);
+            }
+            return Ok(Expr::Cast {
+                expr: Box::new(value),
+                data_type: DataType::Timestamp,
+            });
+        }
+        TableValue::Boolean(v) => Value::Boolean(*v),
+        TableValue::Bytes(_) => {
+            return Err(CubeError::user(
+                "Binary parameters aren't supported".to_string(),
+            ))
+        }
+    }))
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+/// Parts of `query` around `?` placeholders which aren't in quotes or comments.
+fn split_placeholders(query: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut part = String::new();
+    let mut quote = None;
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        if let Some(q) = quote {
+            // Doubled quotes close and reopen the string
+            if c == q {
+                quote = None;
+            }
+            part.push(c);
+            continue;
+        }
+        match c {
+            '?' => parts.push(mem::take(&mut part)),
+            '\'' | '"' | '`' => {
+                quote = Some(c);
+                part.push(c);
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                part.push(c);
+                while let Some(c) = chars.next() {
+                    part.push(c);
+                    if c == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                part.push(c);
+                part.extend(chars.next());
+                let mut prev = None;
+                while let Some(c) = chars.next() {
+                    part.push(c);
+                    if prev == Some('*') && c == '/' {
+                        break;
+                    }
+                    prev = Some(c);
+                }
+            }
+            _ => part.push(c),
+        }
+    }
+    parts.push(part);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::table::TimestampValue;
+
+    fn bind(statement: &PreparedStatement, params: &[TableValue]) -> String {
+        match statement.bind(params).unwrap() {
+            CubeStoreStatement::Statement(statement) => statement.to_string(),
+            s => panic!("Unexpected statement: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn placeholders_outside_of_quotes_and_comments() {
+        let statement = PreparedStatement::parse(
+            "SELECT '?', `a?` -- why?\n FROM s.t /* ? */ WHERE a = ? AND b IN (?, 'it''s ?')",
+        )
+        .unwrap();
+        assert_eq!(statement.param_count(), 2);
+        assert_eq!(
+            bind(
+                &statement,
+                &[
+                    TableValue::String("it's".to_string()),
+                    TableValue::Decimal("-1.50".to_string())
+                ]
+            ),
+            "SELECT '?', `a?` FROM s.t WHERE a = 'it''s' AND b IN (-1.50, 'it''s ?')"
+        );
+    }
+
+    #[test]
+    fn bind_literals() {
+        let statement =
+            PreparedStatement::parse("INSERT INTO s.t (a, b, c) VALUES (?, ?, ?)").unwrap();
+        let t = TableValue::Timestamp(TimestampValue::new(1_600_000_000_123_456_000));
+        assert_eq!(
+            bind(
+                &statement,
+                &[TableValue::Int(-5), TableValue::Null, t.clone()]
+            ),
+            "INSERT INTO s.t (a, b, c) VALUES (-5, NULL, '2020-09-13T12:26:40.123456Z')"
+        );
+        // Timestamps are typed outside of INSERT values
+        let statement = PreparedStatement::parse("SELECT a FROM s.t WHERE c > ?").unwrap();
+        assert_eq!(
+            bind(&statement, &[t]),
+            "SELECT a FROM s.t WHERE c > CAST('2020-09-13T12:26:40.123456Z' AS TIMESTAMP)"
+        );
+        // The parsed statement isn't changed by binding
+        assert_eq!(
+            bind(&statement, &[TableValue::Int(1)]),
+            "SELECT a FROM s.t WHERE c > 1"
+        );
+
+        let error = statement
+            .bind(&[TableValue::Int(1), TableValue::Int(2)])
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("1 parameters but 2 are bound"),
+            "{}",
+            error
+        );
+        let error = statement
+            .bind(&[TableValue::Decimal("1); DROP SCHEMA s; --".to_string())])
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("Can't bind decimal"),
+            "{}",
+            error
+        );
+
+        assert!(PreparedStatement::parse("SELECT FROM ? WHERE").is_err());
+        let error = PreparedStatement::parse("CREATE TABLE s.t (a int) LOCATION ?").unwrap_err();
+        assert!(
+            error.to_string().contains("supported only in expressions"),
+            "{}",
+            error
+        );
+    }
+}
@@ -1,9 +1,10 @@
 use crate::config::ConfigObj;
-use crate::metastore::{MetaStore, MetaStoreTable};
+use crate::metastore::{MetaStore, MetaStoreTable, Partition};
+use crate::queryplanner::tombstones::DeletedRows;
 use crate::remotefs::RemoteFs;
 use crate::store::ChunkDataStore;
 use crate::table::parquet::ParquetTableStore;
-use crate::table::TableStore;
+use crate::table::{Row, TableStore, TableValue};
 use crate::CubeError;
 use async_trait::async_trait;
 use itertools::{EitherOrBoth, Itertools};
@@ -49,33 +50,59 @@ impl CompactionService for CompactionServiceImpl {
             .meta_store
             .get_partition_for_compaction(partition_id)
             .await?;
+        // Backfill replaces chunks of a building index so they're compacted once it's built
+        if index.get_row().is_building() {
+            return Ok(());
+        }
         let partition_id = partition.get_id();
-        let chunks_row_count = chunks
+        let table = self
+            .meta_store
+            .get_table_by_id(index.get_row().table_id())
+            .await?;
+        let unique_key = table.get_row().unique_key_columns().is_some();
+        let tenant_column = table.get_row().tenant_column().is_some();
+        let (tombstones, mut data_chunks): (Vec<_>, Vec<_>) = chunks
+            .iter()
+            .cloned()
+            .partition(|c| c.get_row().is_tombstone());
+        // Chunks are written in the order of their sequences
+        data_chunks.sort_by_key(|c| c.sequence());
+        let chunks_row_count = data_chunks
             .iter()
             .map(|c| c.get_row().get_row_count())
             .sum::<u64>();
-        let total_count = partition.get_row().main_table_row_count() + chunks_row_count;
-        let new_partitions_count =
-            div_ceil(total_count, self.config.partition_split_threshold()) as usize;
-
-        let mut new_partitions = Vec::new();
-        for _ in 0..new_partitions_count {
-            new_partitions.push(
-                self.meta_store
-                    .create_partition(partition.get_row().child(partition.get_id()))
-                    .await?,
-            );
-        }
+        let deleted_row_count = tombstones
+            .iter()
+            .map(|c| c.get_row().get_row_count())
+            .sum::<u64>();
+        let total_count = (partition.get_row().main_table_row_count() + chunks_row_count)
+            .saturating_sub(deleted_row_count);
+        let row_threshold = self.config.partition_split_threshold();
 
-        let mut rows = Vec::new();
-        for chunk in chunks.iter() {
-            let mut data = self.chunk_store.get_chunk(chunk.clone()).await?;
-            rows.append(data.mut_rows());
+        // Small chunks are merged into a medium one until there are too many medium chunks or
+        // chunks get too large. Only then they're all merged into the partition file. Merged chunks
+        // get new ids so for tables with a unique key chunks are only merged into the partition.
+        // They take the sequence of the newest chunk they replace so tombstones written in between
+        // would no longer delete their rows.
+        let (small_chunks, medium_chunks): (Vec<_>, Vec<_>) = data_chunks
+            .iter()
+            .cloned()
+            .partition(|c| c.get_row().get_level() == 0);
+        if !unique_key
+            && tombstones.is_empty()
+            && small_chunks.len() > 1
+            && (medium_chunks.len() as u64) < self.config.compaction_medium_chunks_count_threshold()
+            && chunks_row_count <= self.config.compaction_chunks_total_size_threshold()
+            && total_count <= row_threshold
+        {
+            self.chunk_store
+                .merge_chunks(partition_id, small_chunks)
+                .await?;
+            return Ok(());
         }
-        let sort_key_size = index.get_row().sort_key_size();
-        rows.sort_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)));
 
-        let store = ParquetTableStore::new(index.get_row().clone(), 16384); // TODO config
+        let store = ParquetTableStore::new(index.get_row().clone(), 16384) // TODO config
+            .with_defaults_of(table.get_row());
         let old_partition_local =
             if let Some(f) = partition.get_row().get_full_name(partition.get_id()) {
                 Some(self.remote_fs.download_file(&f).await?)
@@ -83,43 +110,190 @@ impl CompactionService for CompactionServiceImpl {
                 None
             };
 
-        let mut new_partition_local_files = Vec::new();
-        for p in new_partitions.iter() {
-            let new_remote_path = p.get_row().get_full_name(p.get_id()).unwrap();
-            new_partition_local_files.push(self.remote_fs.local_file(&new_remote_path).await?)
+        let sort_key_size = index.get_row().sort_key_size();
+        // Deleted and replaced rows can be anywhere so the partition file is merged in memory to
+        // drop them. Its rows are older than rows of chunks. Rows of tables with a tenant column
+        // are merged in memory to be split by tenants.
+        let merge_in_memory = !tombstones.is_empty() || unique_key || tenant_column;
+        // Keys of tables with a unique key are their sort keys
+        let mut deleted = DeletedRows::new(if unique_key {
+            Some((0..sort_key_size as usize).collect())
+        } else {
+            None
+        });
+        for chunk in tombstones.iter() {
+            let sequence = chunk.sequence();
+            for row in self.chunk_store.get_chunk(chunk.clone()).await?.into_rows() {
+                deleted.add(row, sequence);
+            }
         }
+        let mut rows = Vec::new();
+        if let (Some(f), true) = (old_partition_local.clone(), merge_in_memory) {
+            let index = index.get_row().clone();
+            let table = table.get_row().clone();
+            rows = tokio::task::spawn_blocking(move || {
+                ParquetTableStore::new(index, 16384)
+                    .with_defaults_of(&table)
+                    .read_rows(&f)
+            })
+            .await??;
+            // The partition file is older than all chunks
+            rows.retain(|r| !deleted.take(r, 0));
+        }
+        for chunk in data_chunks.iter() {
+            let mut data = self.chunk_store.get_chunk(chunk.clone()).await?;
+            let sequence = chunk.sequence();
+            data.mut_rows().retain(|r| !deleted.take(r, sequence));
+            rows.append(data.mut_rows());
+        }
+        if unique_key {
+            rows.sort_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)));
+            rows = last_rows_by_sort_key(rows, sort_key_size);
+        }
+        let source_file = if merge_in_memory {
+            None
+        } else {
+            old_partition_local.clone()
+        };
+        if rows.is_empty() && source_file.is_none() {
+            // Everything is deleted: a partition without a file keeps the key range
+            let empty = self
+                .meta_store
+                .create_partition(
+                    Partition::new(
+                        index.get_id(),
+                        partition.get_row().get_min_val().clone(),
+                        partition.get_row().get_max_val().clone(),
+                    )
+                    .to_active(false),
+                )
+                .await?;
+            self.meta_store
+                .swap_active_partitions(
+                    vec![partition_id],
+                    vec![empty.get_id()],
+                    chunks.iter().map(|c| c.get_id()).collect(),
+                    vec![(
+                        0,
+                        (
+                            partition.get_row().get_min_val().clone(),
+                            partition.get_row().get_max_val().clone(),
+                        ),
+                    )],
+                    vec![None],
+                )
+                .await?;
+            self.chunk_store
+                .evict_in_memory_chunks(chunks.iter().map(|c| c.get_id()).collect());
+            return Ok(());
+        }
+        // Rows of chunks are assumed to take as much space as rows already in the partition
+        let estimated_size = match &old_partition_local {
+            Some(f) if partition.get_row().main_table_row_count() > 0 => Some(
+                tokio::fs::metadata(f).await?.len() * total_count
+                    / partition.get_row().main_table_row_count(),
+            ),
+            _ => None,
+        };
+        rows.sort_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)));
 
-        let new_partition_file_names = new_partition_local_files.clone();
-        let count_and_min_max = tokio::task::spawn_blocking(move || {
-            store.merge_rows(
-                old_partition_local.as_ref().map(|s| s.as_str()),
-                new_partition_file_names,
-                rows,
-                sort_key_size,
-            )
+        // Each tenant's rows are split into their own partitions so partitions never mix tenants
+        let groups = if tenant_column {
+            rows.into_iter()
+                .group_by(|r| r.values()[0].clone())
+                .into_iter()
+                .map(|(_, group)| {
+                    let group = group.collect::<Vec<_>>();
+                    let count = group.len() as u64;
+                    (group, count)
+                })
+                .collect::<Vec<_>>()
+        } else {
+            vec![(rows, total_count)]
+        };
+
+        let mut group_partitions = Vec::new();
+        let mut group_files = Vec::new();
+        for (_, count) in groups.iter() {
+            let new_partitions_count = split_partitions_count(
+                *count,
+                estimated_size.map(|s| s * count / total_count.max(1)),
+                row_threshold,
+                self.config.partition_size_split_threshold(),
+            );
+            let mut new_partitions = Vec::new();
+            let mut new_partition_local_files = Vec::new();
+            for _ in 0..new_partitions_count {
+                let p = self
+                    .meta_store
+                    .create_partition(partition.get_row().child(partition.get_id()))
+                    .await?;
+                let new_remote_path = p.get_row().get_full_name(p.get_id()).unwrap();
+                new_partition_local_files.push(self.remote_fs.local_file(&new_remote_path).await?);
+                new_partitions.push(p);
+            }
+            group_partitions.push(new_partitions);
+            group_files.push(new_partition_local_files);
+        }
+
+        let group_count_and_min_max = tokio::task::spawn_blocking(move || {
+            groups
+                .into_iter()
+                .zip(group_files.into_iter())
+                .map(|((rows, _), new_partition_file_names)| {
+                    store.merge_rows(
+                        source_file.as_ref().map(|s| s.as_str()),
+                        new_partition_file_names,
+                        rows,
+                        sort_key_size,
+                    )
+                })
+                .collect::<Result<Vec<_>, CubeError>>()
         })
         .await??;
 
         let mut filtered_partitions = Vec::new();
+        let mut count_and_min_max = Vec::new();
+        let mut file_sizes = Vec::new();
 
-        for p in new_partitions
+        for (new_partitions, group_count_and_min_max) in group_partitions
             .into_iter()
-            .zip_longest(count_and_min_max.iter())
+            .zip(group_count_and_min_max.into_iter())
         {
-            match p {
-                EitherOrBoth::Both(p, _) => {
-                    let new_remote_path = p.get_row().get_full_name(p.get_id()).unwrap();
-                    self.remote_fs.upload_file(new_remote_path.as_str()).await?;
-                    filtered_partitions.push(p);
-                }
-                EitherOrBoth::Left(p) => {
-                    self.meta_store.partition_table().delete(p.get_id()).await?;
-                }
-                EitherOrBoth::Right(_) => {
-                    return Err(CubeError::internal(format!(
-                        "Unexpected state during partitioning: {:?}",
-                        p
-                    )))
+            for (i, p) in new_partitions
+                .into_iter()
+                .zip_longest(group_count_and_min_max.into_iter())
+                .enumerate()
+            {
+                match p {
+                    EitherOrBoth::Both(p, (c, (min, max))) => {
+                        let new_remote_path = p.get_row().get_full_name(p.get_id()).unwrap();
+                        let local_file = self.remote_fs.local_file(&new_remote_path).await?;
+                        file_sizes.push(Some(tokio::fs::metadata(local_file).await?.len()));
+                        self.remote_fs.upload_file(new_remote_path.as_str()).await?;
+                        // Tenant's partitions start at its smallest possible row so rows of the
+                        // tenant written later are never routed to a partition of another one
+                        let min = if tenant_column && i == 0 && !count_and_min_max.is_empty() {
+                            let mut values = vec![TableValue::Null; min.len()];
+                            values[0] = min.values()[0].clone();
+                            Row::new(values)
+                        } else if tenant_column && i == 0 {
+                            partition.get_row().get_min_val().clone().unwrap_or(min)
+                        } else {
+                            min
+                        };
+                        filtered_partitions.push(p);
+                        count_and_min_max.push((c, (min, max)));
+                    }
+                    EitherOrBoth::Left(p) => {
+                        self.meta_store.partition_table().delete(p.get_id()).await?;
+                    }
+                    EitherOrBoth::Right(p) => {
+                        return Err(CubeError::internal(format!(
+                            "Unexpected state during partitioning: {:?}",
+                            p
+                        )))
+                    }
                 }
             }
         }
@@ -154,9 +328,13 @@ impl CompactionService for CompactionServiceImpl {
                             }
                             EitherOrBoth::Left((c, (min, _))) => {
                                 if i == 0 && filtered_partitions.len() == 1 {
-                                    assert_eq!(partition.get_row().get_min_val().is_none(), true);
-                                    assert_eq!(partition.get_row().get_max_val().is_none(), true);
-                                    Ok((*c, (None, None)))
+                                    Ok((
+                                        *c,
+                                        (
+                                            partition.get_row().get_min_val().clone(),
+                                            partition.get_row().get_max_val().clone(),
+                                        ),
+                                    ))
                                 } else if i == filtered_partitions.len() - 1 {
                                     Ok(((
                                         *c,
@@ -183,18 +361,54 @@ impl CompactionService for CompactionServiceImpl {
                         }
                     })
                     .collect::<Result<Vec<_>, CubeError>>()?,
+                file_sizes,
             )
             .await?;
+        self.chunk_store
+            .evict_in_memory_chunks(chunks.iter().map(|c| c.get_id()).collect());
 
         Ok(())
     }
 }
 
+/// Keeps the last one of rows with equal sort keys. Rows are expected to be sorted with a stable
+/// sort so rows with equal keys are in the order they were written.
+fn last_rows_by_sort_key(rows: Vec<Row>, sort_key_size: u64) -> Vec<Row> {
+    let mut result: Vec<Row> = Vec::with_capacity(rows.len());
+    for row in rows {
+        match result.last_mut() {
+            Some(last) if last.sort_key(sort_key_size) == row.sort_key(sort_key_size) => {
+                *last = row
+            }
+            _ => result.push(row),
+        }
+    }
+    result
+}
+
+/// Number of partitions to write `rows` into so each of them has at most `row_threshold` rows
+/// and, if the size is known and `size_threshold` is set, at most `size_threshold` bytes. Rows
+/// are split evenly in sort key order so two partitions are split at the median sort key.
+fn split_partitions_count(
+    rows: u64,
+    size: Option<u64>,
+    row_threshold: u64,
+    size_threshold: u64,
+) -> usize {
+    let by_rows = div_ceil(rows, row_threshold);
+    let by_size = match size {
+        Some(size) if size_threshold > 0 => div_ceil(size, size_threshold),
+        _ => 0,
+    };
+    // A partition can't be split into more parts than it has rows
+    by_rows.max(by_size.min(rows)) as usize
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::config::MockConfigObj;
-    use crate::metastore::{Column, ColumnType, RocksMetaStore};
+    use crate::metastore::{Chunk, Column, ColumnType, RocksMetaStore};
     use crate::store::{DataFrame, MockChunkDataStore};
     use crate::table::{Row, TableValue};
 
@@ -215,19 +429,22 @@ mod tests {
                 cols.clone(),
                 None,
                 None,
+                None,
                 vec![],
+                None,
+                None,
             )
             .await
             .unwrap();
         metastore.get_default_index(1).await.unwrap();
         let partition = metastore.get_partition(1).await.unwrap();
         metastore
-            .create_chunk(partition.get_id(), 10)
+            .create_chunk(Chunk::new(partition.get_id(), 10))
             .await
             .unwrap();
         metastore.chunk_uploaded(1).await.unwrap();
         metastore
-            .create_chunk(partition.get_id(), 16)
+            .create_chunk(Chunk::new(partition.get_id(), 16))
             .await
             .unwrap();
         metastore.chunk_uploaded(2).await.unwrap();
@@ -247,10 +464,24 @@ mod tests {
             ))
         });
 
+        chunk_store
+            .expect_evict_in_memory_chunks()
+            .withf(|ids| ids == &vec![1, 2])
+            .times(1)
+            .return_const(());
+
         config
             .expect_partition_split_threshold()
             .times(1)
             .returning(|| 20);
+        config
+            .expect_partition_size_split_threshold()
+            .times(1)
+            .returning(|| 0);
+        config
+            .expect_compaction_medium_chunks_count_threshold()
+            .times(1)
+            .returning(|| 0);
 
         let compaction_service = CompactionServiceImpl::new(
             metastore.clone(),
@@ -277,4 +508,165 @@ mod tests {
         assert_eq!(partition_2.get_row().get_max_val(), &None);
         RocksMetaStore::cleanup_test_metastore("compaction");
     }
+
+    #[actix_rt::test]
+    async fn compaction_merges_small_chunks() {
+        let (remote_fs, metastore) =
+            RocksMetaStore::prepare_test_metastore("compaction_merges_small_chunks");
+        let mut chunk_store = MockChunkDataStore::new();
+        let mut config = MockConfigObj::new();
+        metastore
+            .create_schema("foo".to_string(), false)
+            .await
+            .unwrap();
+        let cols = vec![Column::new("name".to_string(), ColumnType::String, 0)];
+        metastore
+            .create_table(
+                "foo".to_string(),
+                "bar".to_string(),
+                cols.clone(),
+                None,
+                None,
+                None,
+                vec![],
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        metastore.get_default_index(1).await.unwrap();
+        for (row_count, level) in vec![(10, 1), (10, 0), (16, 0)] {
+            let chunk = metastore
+                .create_chunk(Chunk::new(1, row_count).with_level(level))
+                .await
+                .unwrap();
+            metastore.chunk_uploaded(chunk.get_id()).await.unwrap();
+        }
+
+        chunk_store
+            .expect_merge_chunks()
+            .withf(|partition_id, chunks| {
+                *partition_id == 1 && chunks.iter().map(|c| c.get_id()).collect_vec() == vec![2, 3]
+            })
+            .times(1)
+            .returning(|_, chunks| Ok(chunks[0].clone()));
+
+        config
+            .expect_partition_split_threshold()
+            .times(1)
+            .returning(|| 100);
+        config
+            .expect_compaction_medium_chunks_count_threshold()
+            .times(1)
+            .returning(|| 2);
+        config
+            .expect_compaction_chunks_total_size_threshold()
+            .times(1)
+            .returning(|| 100);
+
+        let compaction_service = CompactionServiceImpl::new(
+            metastore.clone(),
+            Arc::new(chunk_store),
+            remote_fs,
+            Arc::new(config),
+        );
+        compaction_service.compact(1).await.unwrap();
+        assert!(metastore
+            .get_partition(1)
+            .await
+            .unwrap()
+            .get_row()
+            .is_active());
+        RocksMetaStore::cleanup_test_metastore("compaction_merges_small_chunks");
+    }
+
+    #[actix_rt::test]
+    async fn compaction_drops_deleted_rows() {
+        let (remote_fs, metastore) =
+            RocksMetaStore::prepare_test_metastore("compaction_drops_deleted_rows");
+        let mut chunk_store = MockChunkDataStore::new();
+        let mut config = MockConfigObj::new();
+        metastore
+            .create_schema("foo".to_string(), false)
+            .await
+            .unwrap();
+        let cols = vec![Column::new("name".to_string(), ColumnType::String, 0)];
+        metastore
+            .create_table(
+                "foo".to_string(),
+                "bar".to_string(),
+                cols.clone(),
+                None,
+                None,
+                None,
+                vec![],
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+        metastore.get_default_index(1).await.unwrap();
+        for (row_count, tombstone) in vec![(10, false), (2, true)] {
+            let chunk = metastore
+                .create_chunk(Chunk::new(1, row_count).with_tombstone(tombstone))
+                .await
+                .unwrap();
+            metastore.chunk_uploaded(chunk.get_id()).await.unwrap();
+        }
+
+        chunk_store.expect_get_chunk().times(2).returning(move |c| {
+            let values = if c.get_row().is_tombstone() {
+                vec![2, 5]
+            } else {
+                (0..10).collect()
+            };
+            Ok(DataFrame::new(
+                cols.clone(),
+                values
+                    .into_iter()
+                    .map(|i| Row::new(vec![TableValue::String(format!("foo{}", i))]))
+                    .collect::<Vec<_>>(),
+            ))
+        });
+        chunk_store
+            .expect_evict_in_memory_chunks()
+            .withf(|ids| ids == &vec![1, 2])
+            .times(1)
+            .return_const(());
+
+        config
+            .expect_partition_split_threshold()
+            .times(1)
+            .returning(|| 20);
+        config
+            .expect_partition_size_split_threshold()
+            .times(1)
+            .returning(|| 0);
+        let compaction_service = CompactionServiceImpl::new(
+            metastore.clone(),
+            Arc::new(chunk_store),
+            remote_fs,
+            Arc::new(config),
+        );
+        compaction_service.compact(1).await.unwrap();
+        assert!(!metastore
+            .get_partition(1)
+            .await
+            .unwrap()
+            .get_row()
+            .is_active());
+        let partition = metastore.get_partition(2).await.unwrap();
+        assert!(partition.get_row().is_active());
+        assert_eq!(partition.get_row().main_table_row_count(), 8);
+        RocksMetaStore::cleanup_test_metastore("compaction_drops_deleted_rows");
+    }
+
+    #[test]
+    fn split_by_rows_and_size() {
+        assert_eq!(split_partitions_count(26, None, 20, 0), 2);
+        assert_eq!(split_partitions_count(26, Some(1000), 20, 0), 2);
+        assert_eq!(split_partitions_count(26, Some(1000), 20, 300), 4);
+        assert_eq!(split_partitions_count(10, Some(100), 20, 300), 1);
+        assert_eq!(split_partitions_count(3, Some(10000), 20, 1), 3);
+    }
 }
@@ -0,0 +1,445 @@
+use crate::metastore::table::Table;
+use crate::metastore::{Column, IdRow};
+use crate::store::DataFrame;
+use crate::table::Row;
+use crate::CubeError;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Rows of small INSERTs collected per table until there're enough of them to be written as one
+/// chunk. Every insert is appended to a log in `dir` and synced to disk before it's acknowledged
+/// so buffered rows are restored after a restart or a machine crash.
+/// Queries read buffered rows along with the tables if `read_your_writes` is set. Zero
+/// `max_rows` disables the buffer.
+pub struct InsertBuffer {
+    dir: PathBuf,
+    max_rows: usize,
+    max_bytes: u64,
+    max_age: Duration,
+    read_your_writes: bool,
+    next_log_id: AtomicU64,
+    buffers: Mutex<HashMap<BufferKey, Buffer>>,
+}
+
+/// Table id and inserted columns.
+type BufferKey = (u64, Vec<Column>);
+
+struct Buffer {
+    rows: Vec<Row>,
+    size: u64,
+    created: Instant,
+    /// Logs holding `rows`. New inserts are appended to the last one if it's open.
+    logs: Vec<PathBuf>,
+    log: Option<tokio::fs::File>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    columns: Vec<Column>,
+    rows: Vec<Row>,
+}
+
+/// Rows taken out of the buffer to be written to the table.
+#[derive(Debug)]
+pub struct BufferedRows {
+    table_id: u64,
+    data: DataFrame,
+    size: u64,
+    logs: Vec<PathBuf>,
+}
+
+impl BufferedRows {
+    pub fn table_id(&self) -> u64 {
+        self.table_id
+    }
+
+    pub fn data(&self) -> &DataFrame {
+        &self.data
+    }
+}
+
+impl InsertBuffer {
+    pub fn new(
+        dir: PathBuf,
+        max_rows: usize,
+        max_bytes: u64,
+        max_age: Duration,
+        read_your_writes: bool,
+    ) -> Result<Arc<InsertBuffer>, CubeError> {
+        let mut buffer = InsertBuffer {
+            dir,
+            max_rows,
+            max_bytes,
+            max_age,
+            read_your_writes,
+            next_log_id: AtomicU64::new(1),
+            buffers: Mutex::new(HashMap::new()),
+        };
+        if buffer.is_enabled() {
+            fs::create_dir_all(&buffer.dir)?;
+            buffer.buffers = Mutex::new(buffer.replay_logs()?);
+        }
+        Ok(Arc::new(buffer))
+    }
+
+    pub fn disabled() -> Arc<InsertBuffer> {
+        InsertBuffer::new(PathBuf::new(), 0, 0, Duration::from_secs(0), false).unwrap()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_rows > 0
+    }
+
+    pub fn read_your_writes(&self) -> bool {
+        self.is_enabled() && self.read_your_writes
+    }
+
+    /// Inserts of `max_rows` rows or more are big enough to be written right away.
+    pub fn accepts(&self, rows: usize) -> bool {
+        rows < self.max_rows
+    }
+
+    /// Logs and buffers rows of `data`. Returns buffered rows of the table once they reach
+    /// `max_rows` or `max_bytes`.
+    pub async fn add(
+        &self,
+        table_id: u64,
+        data: DataFrame,
+    ) -> Result<Option<BufferedRows>, CubeError> {
+        let entry = LogEntry {
+            columns: data.get_columns().clone(),
+            rows: data.get_rows().clone(),
+        };
+        let bytes = bincode::serialize(&entry)?;
+        let mut record = (bytes.len() as u32).to_le_bytes().to_vec();
+        record.extend_from_slice(&bytes);
+
+        let key = (table_id, entry.columns);
+        let mut buffers = self.buffers.lock().await;
+        let buffer = buffers.entry(key.clone()).or_insert_with(Buffer::new);
+        if buffer.log.is_none() {
+            let path = self.new_log_path(table_id);
+            buffer.log = Some(
+                tokio::fs::OpenOptions::new()
+                    .create_new(true)
+                    .append(true)
+                    .open(&path)
+                    .await?,
+            );
+            // Entry of the new log in the directory has to survive a crash as well
+            tokio::fs::File::open(&self.dir).await?.sync_all().await?;
+            buffer.logs.push(path);
+        }
+        let log = buffer.log.as_mut().unwrap();
+        log.write_all(&record).await?;
+        // Writes of tokio files complete in the background until they're flushed
+        log.flush().await?;
+        log.sync_data().await?;
+        buffer.rows.extend(entry.rows);
+        buffer.size += bytes.len() as u64;
+
+        if buffer.rows.len() >= self.max_rows || buffer.size >= self.max_bytes {
+            let buffer = buffers.remove(&key).unwrap();
+            Ok(Some(buffer.into_rows(key)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Takes rows buffered longer than `max_age`.
+    pub async fn take_expired(&self) -> Vec<BufferedRows> {
+        let now = Instant::now();
+        self.take(|_, b| now.duration_since(b.created) >= self.max_age)
+            .await
+    }
+
+    pub async fn take_all(&self) -> Vec<BufferedRows> {
+        self.take(|_, _| true).await
+    }
+
+    /// Takes rows of `table_id` only.
+    pub async fn take_table(&self, table_id: u64) -> Vec<BufferedRows> {
+        self.take(|(id, _), _| *id == table_id).await
+    }
+
+    /// Copy of rows of `table` still in the buffer with all columns of the table. Columns
+    /// missing in inserts are filled with their defaults.
+    pub async fn rows_of(&self, table: &IdRow<Table>) -> Result<Option<DataFrame>, CubeError> {
+        let buffers = self.buffers.lock().await;
+        let mut rows = Vec::new();
+        for ((table_id, columns), buffer) in buffers.iter() {
+            if *table_id != table.get_id() {
+                continue;
+            }
+            let data = DataFrame::new(columns.clone(), buffer.rows.clone())
+                .with_missing_columns(table.get_row())
+                .remap_columns(table.get_row().get_columns().clone())?;
+            rows.extend(data.into_rows());
+        }
+        if rows.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(DataFrame::new(
+            table.get_row().get_columns().clone(),
+            rows,
+        )))
+    }
+
+    /// Removes logs of rows written to the table.
+    pub async fn written(&self, rows: BufferedRows) -> Result<(), CubeError> {
+        for log in rows.logs {
+            tokio::fs::remove_file(log).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns rows which couldn't be written back to the buffer so they're written with the
+    /// next flush.
+    pub async fn restore(&self, rows: BufferedRows) {
+        let BufferedRows {
+            table_id,
+            data,
+            size,
+            mut logs,
+        } = rows;
+        let DataFrame {
+            columns, mut data, ..
+        } = data;
+        let mut buffers = self.buffers.lock().await;
+        let buffer = buffers
+            .entry((table_id, columns))
+            .or_insert_with(Buffer::new);
+        data.extend(mem::take(&mut buffer.rows));
+        buffer.rows = data;
+        logs.extend(mem::take(&mut buffer.logs));
+        buffer.logs = logs;
+        buffer.size += size;
+        buffer.created = Instant::now();
+    }
+
+    async fn take(&self, filter: impl Fn(&BufferKey, &Buffer) -> bool) -> Vec<BufferedRows> {
+        let mut buffers = self.buffers.lock().await;
+        let keys = buffers
+            .iter()
+            .filter(|(k, b)| filter(k, b))
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<_>>();
+        keys.into_iter()
+            .map(|k| {
+                let buffer = buffers.remove(&k).unwrap();
+                buffer.into_rows(k)
+            })
+            .collect()
+    }
+
+    fn new_log_path(&self, table_id: u64) -> PathBuf {
+        let id = self.next_log_id.fetch_add(1, Ordering::SeqCst);
+        self.dir.join(format!("{}-{}.log", table_id, id))
+    }
+
+    /// Loads rows of logs left by the previous run. Appends to a log can be interrupted so its
+    /// incomplete last entry is ignored.
+    fn replay_logs(&self) -> Result<HashMap<BufferKey, Buffer>, CubeError> {
+        let mut logs = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if let Some((table_id, log_id)) = parse_log_name(&path) {
+                logs.push((log_id, table_id, path));
+            }
+        }
+        // Rows are restored in the order they were inserted
+        logs.sort();
+        if let Some((log_id, _, _)) = logs.last() {
+            self.next_log_id.store(log_id + 1, Ordering::SeqCst);
+        }
+
+        let mut buffers = HashMap::new();
+        for (_, table_id, path) in logs {
+            let mut file = File::open(&path)?;
+            let mut has_rows = false;
+            loop {
+                match read_entry(&mut file) {
+                    Ok(Some(entry)) => {
+                        let size = bincode::serialized_size(&entry)?;
+                        let buffer = buffers
+                            .entry((table_id, entry.columns))
+                            .or_insert_with(Buffer::new);
+                        if !has_rows {
+                            buffer.logs.push(path.clone());
+                            has_rows = true;
+                        }
+                        buffer.size += size;
+                        buffer.rows.extend(entry.rows);
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Ignoring the rest of insert log {:?}: {}", path, e);
+                        break;
+                    }
+                }
+            }
+            if !has_rows {
+                fs::remove_file(&path)?;
+            }
+        }
+        Ok(buffers)
+    }
+}
+
+impl Buffer {
+    fn new() -> Buffer {
+        Buffer {
+            rows: Vec::new(),
+            size: 0,
+            created: Instant::now(),
+            logs: Vec::new(),
+            log: None,
+        }
+    }
+
+    fn into_rows(self, (table_id, columns): BufferKey) -> BufferedRows {
+        BufferedRows {
+            table_id,
+            data: DataFrame::new(columns, self.rows),
+            size: self.size,
+            logs: self.logs,
+        }
+    }
+}
+
+/// Log names are `<table id>-<log id>.log`.
+fn parse_log_name(path: &Path) -> Option<(u64, u64)> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    let stem = path.file_stem()?.to_str()?;
+    let mut ids = stem.splitn(2, '-');
+    Some((ids.next()?.parse().ok()?, ids.next()?.parse().ok()?))
+}
+
+fn read_entry(file: &mut File) -> Result<Option<LogEntry>, CubeError> {
+    let mut len = [0; 4];
+    match file.read_exact(&mut len) {
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        r => r?,
+    }
+    let mut bytes = vec![0; u32::from_le_bytes(len) as usize];
+    file.read_exact(&mut bytes)?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metastore::ColumnType;
+    use crate::table::TableValue;
+    use std::env;
+
+    fn rows(columns: &Vec<Column>, values: &[i64]) -> DataFrame {
+        DataFrame::new(
+            columns.clone(),
+            values
+                .iter()
+                .map(|v| Row::new(vec![TableValue::Int(*v)]))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn buffered_rows_survive_restart() {
+        let dir = env::temp_dir().join("insert_buffer_survive_restart");
+        let _ = fs::remove_dir_all(&dir);
+        let columns = vec![Column::new("a".to_string(), ColumnType::Int, 0)];
+        let new_buffer =
+            || InsertBuffer::new(dir.clone(), 5, 1 << 20, Duration::from_secs(60), true).unwrap();
+
+        let buffer = new_buffer();
+        assert!(buffer.accepts(4));
+        assert!(!buffer.accepts(5));
+        assert!(buffer
+            .add(1, rows(&columns, &[1, 2]))
+            .await
+            .unwrap()
+            .is_none());
+        assert!(buffer
+            .add(2, rows(&columns, &[10]))
+            .await
+            .unwrap()
+            .is_none());
+        let full = buffer
+            .add(1, rows(&columns, &[3, 4, 5]))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(full.table_id(), 1);
+        assert_eq!(full.data(), &rows(&columns, &[1, 2, 3, 4, 5]));
+        buffer.written(full).await.unwrap();
+        assert!(buffer.add(1, rows(&columns, &[6])).await.unwrap().is_none());
+        assert!(buffer.take_expired().await.is_empty());
+        drop(buffer);
+
+        let buffer = new_buffer();
+        let mut restored = buffer.take_all().await;
+        restored.sort_by_key(|r| r.table_id());
+        assert_eq!(restored.len(), 2);
+        assert_eq!(restored[0].data(), &rows(&columns, &[6]));
+        assert_eq!(restored[1].data(), &rows(&columns, &[10]));
+
+        // Rows which failed to be written are kept in their logs
+        let first = restored.remove(0);
+        buffer.restore(first).await;
+        buffer.written(restored.remove(0)).await.unwrap();
+        assert!(buffer.add(1, rows(&columns, &[7])).await.unwrap().is_none());
+        drop(buffer);
+
+        let buffer = new_buffer();
+        let restored = buffer.take_all().await;
+        assert_eq!(restored.len(), 1);
+        assert_eq!(restored[0].data(), &rows(&columns, &[6, 7]));
+        buffer
+            .written(restored.into_iter().next().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn rows_are_taken_once_expired() {
+        tokio::time::pause();
+        let dir = env::temp_dir().join("insert_buffer_expired");
+        let _ = fs::remove_dir_all(&dir);
+        let columns = vec![Column::new("a".to_string(), ColumnType::Int, 0)];
+        let buffer =
+            InsertBuffer::new(dir.clone(), 5, 1 << 20, Duration::from_secs(5), true).unwrap();
+
+        assert!(buffer.add(1, rows(&columns, &[1])).await.unwrap().is_none());
+        tokio::time::advance(Duration::from_secs(3)).await;
+        assert!(buffer.add(2, rows(&columns, &[2])).await.unwrap().is_none());
+        assert!(buffer.take_expired().await.is_empty());
+
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let expired = buffer.take_expired().await;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].data(), &rows(&columns, &[1]));
+        buffer
+            .written(expired.into_iter().next().unwrap())
+            .await
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(3)).await;
+        let expired = buffer.take_expired().await;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].data(), &rows(&columns, &[2]));
+    }
+}
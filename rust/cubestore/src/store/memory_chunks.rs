@@ -0,0 +1,193 @@
+use arrow::record_batch::RecordBatch;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+/// Batches of chunks recently written by this process so they can be queried without reading
+/// their parquet files. Only queries executed in this process benefit: select worker processes
+/// and other nodes still read the files, and the batches are gone after a restart. Chunks are
+/// dropped once compacted or, oldest first, when their total size exceeds `max_size` bytes.
+/// Zero `max_size` disables the store.
+///
+/// Chunks marked pending are written to their partitions but not activated yet. Queries of this
+/// process scan them along with active chunks of the partitions so rows being ingested are seen
+/// before they're committed.
+#[derive(Debug)]
+pub struct MemoryChunkStore {
+    max_size: u64,
+    chunks: Mutex<MemoryChunks>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryChunks {
+    batches: HashMap<u64, (Vec<RecordBatch>, u64)>,
+    /// Chunk ids in the order they were added.
+    order: VecDeque<u64>,
+    size: u64,
+    /// Partition ids of pending chunks by chunk id.
+    pending: HashMap<u64, u64>,
+}
+
+impl MemoryChunkStore {
+    pub fn new(max_size: u64) -> Arc<MemoryChunkStore> {
+        Arc::new(MemoryChunkStore {
+            max_size,
+            chunks: Mutex::new(MemoryChunks::default()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size > 0
+    }
+
+    /// Chunks larger than `max_size` aren't kept.
+    pub fn add(&self, chunk_id: u64, batches: Vec<RecordBatch>) {
+        let size = batches_size(&batches);
+        if size > self.max_size {
+            return;
+        }
+        let mut chunks = self.chunks.lock().unwrap();
+        chunks.remove(chunk_id);
+        while chunks.size + size > self.max_size {
+            match chunks.order.front().cloned() {
+                Some(oldest) => chunks.remove(oldest),
+                None => break,
+            }
+        }
+        chunks.batches.insert(chunk_id, (batches, size));
+        chunks.order.push_back(chunk_id);
+        chunks.size += size;
+    }
+
+    pub fn get(&self, chunk_id: u64) -> Option<Vec<RecordBatch>> {
+        self.chunks
+            .lock()
+            .unwrap()
+            .batches
+            .get(&chunk_id)
+            .map(|(batches, _)| batches.clone())
+    }
+
+    /// Marks the chunk of `partition_id` as pending if it's kept in memory.
+    pub fn add_pending(&self, chunk_id: u64, partition_id: u64) {
+        let mut chunks = self.chunks.lock().unwrap();
+        if chunks.batches.contains_key(&chunk_id) {
+            chunks.pending.insert(chunk_id, partition_id);
+        }
+    }
+
+    /// Chunks are no longer pending once they're activated.
+    pub fn activated(&self, chunk_ids: &[u64]) {
+        let mut chunks = self.chunks.lock().unwrap();
+        for chunk_id in chunk_ids {
+            chunks.pending.remove(chunk_id);
+        }
+    }
+
+    /// Partition ids, chunk ids and batches of pending chunks of `partition_ids`.
+    pub fn pending_of(&self, partition_ids: &HashSet<u64>) -> Vec<(u64, u64, Vec<RecordBatch>)> {
+        let chunks = self.chunks.lock().unwrap();
+        chunks
+            .pending
+            .iter()
+            .filter(|(_, partition_id)| partition_ids.contains(partition_id))
+            .map(|(chunk_id, partition_id)| {
+                (*partition_id, *chunk_id, chunks.batches[chunk_id].0.clone())
+            })
+            .collect()
+    }
+
+    pub fn remove(&self, chunk_ids: &[u64]) {
+        let mut chunks = self.chunks.lock().unwrap();
+        for chunk_id in chunk_ids {
+            chunks.remove(*chunk_id);
+        }
+    }
+
+    /// Bytes taken by arrays of all chunks.
+    pub fn size(&self) -> u64 {
+        self.chunks.lock().unwrap().size
+    }
+}
+
+impl MemoryChunks {
+    fn remove(&mut self, chunk_id: u64) {
+        if let Some((_, size)) = self.batches.remove(&chunk_id) {
+            self.size -= size;
+            self.order.retain(|id| *id != chunk_id);
+        }
+        self.pending.remove(&chunk_id);
+    }
+}
+
+/// Memory taken by arrays of `batches` in bytes.
+pub(crate) fn batches_size(batches: &[RecordBatch]) -> u64 {
+    batches
+        .iter()
+        .flat_map(|b| b.columns().iter().map(|c| c.get_array_memory_size() as u64))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+
+    fn batches(rows: i64) -> Vec<RecordBatch> {
+        vec![RecordBatch::try_new(
+            Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, false)])),
+            vec![Arc::new(Int64Array::from((0..rows).collect::<Vec<_>>()))],
+        )
+        .unwrap()]
+    }
+
+    #[test]
+    fn evicts_oldest_chunks() {
+        let chunk_size = batches_size(&batches(100));
+        let store = MemoryChunkStore::new(chunk_size * 2);
+        store.add(1, batches(100));
+        store.add(2, batches(100));
+        assert_eq!(store.size(), chunk_size * 2);
+
+        store.add(3, batches(100));
+        assert!(store.get(1).is_none());
+        assert!(store.get(2).is_some());
+        assert_eq!(store.get(3).unwrap()[0].num_rows(), 100);
+        assert_eq!(store.size(), chunk_size * 2);
+
+        store.remove(&[2, 3]);
+        assert_eq!(store.size(), 0);
+
+        // Doesn't fit at all
+        store.add(4, batches(1000));
+        assert!(store.get(4).is_none());
+        assert!(!MemoryChunkStore::new(0).is_enabled());
+    }
+
+    #[test]
+    fn pending_chunks_until_activated() {
+        let store = MemoryChunkStore::new(1 << 20);
+        store.add(1, batches(10));
+        store.add(2, batches(20));
+        for chunk_id in 1..4 {
+            store.add_pending(chunk_id, 5);
+        }
+        let partitions = vec![5].into_iter().collect::<HashSet<_>>();
+        let mut pending = store
+            .pending_of(&partitions)
+            .into_iter()
+            .map(|(partition_id, chunk_id, batches)| {
+                (partition_id, chunk_id, batches[0].num_rows())
+            })
+            .collect::<Vec<_>>();
+        pending.sort();
+        assert_eq!(pending, vec![(5, 1, 10), (5, 2, 20)]);
+        assert!(store.pending_of(&vec![6].into_iter().collect()).is_empty());
+
+        store.activated(&[1]);
+        store.remove(&[2]);
+        assert!(store.pending_of(&partitions).is_empty());
+        // Activated chunks are still kept
+        assert!(store.get(1).is_some());
+    }
+}
@@ -1,8 +1,11 @@
 pub mod compaction;
+pub mod insert_buffer;
+pub mod memory_chunks;
 
 use async_trait::async_trait;
 use datafusion::physical_plan::memory::MemoryExec;
-use datafusion::physical_plan::ExecutionPlan;
+use datafusion::physical_plan::parquet::ParquetExec;
+use datafusion::physical_plan::{collect, ExecutionPlan};
 use serde::{de, Deserialize, Serialize};
 extern crate bincode;
 
@@ -12,11 +15,15 @@ use crate::metastore::{
     table::Table, Chunk, Column, ColumnType, IdRow, Index, MetaStore, MetaStoreTable, Partition,
     WAL,
 };
+use crate::queryplanner::scan_metrics::ScanStats;
+use crate::queryplanner::tombstones::DeletedRows;
 use crate::remotefs::RemoteFs;
+use crate::store::memory_chunks::MemoryChunkStore;
 use crate::table::{Row, TableStore, TableValue};
 use crate::CubeError;
 use arrow::datatypes::Schema;
 use std::{
+    collections::{BTreeSet, HashSet},
     fs::File,
     io::{BufReader, BufWriter, Write},
     sync::Arc,
@@ -32,11 +39,125 @@ use mockall::automock;
 pub struct DataFrame {
     columns: Vec<Column>,
     data: Vec<Row>,
+    #[serde(default)]
+    warnings: Vec<String>,
+    #[serde(default)]
+    stats: ExecutionStats,
+}
+
+/// Work done by the cluster to produce a query result.
+#[derive(Serialize, Deserialize, Clone, Default, Eq, PartialEq, Debug)]
+pub struct ExecutionStats {
+    partitions: BTreeSet<u64>,
+    nodes: BTreeSet<String>,
+    bytes_received: u64,
+    #[serde(default)]
+    rows_received: u64,
+    #[serde(default)]
+    batches_received: u64,
+    /// Scans executed on the router and on workers.
+    #[serde(default)]
+    scan: ScanStats,
+    /// Whether the router executed the query without sending anything to workers.
+    #[serde(default)]
+    local: bool,
+}
+
+impl ExecutionStats {
+    /// Partitions scanned on the router itself.
+    pub fn local(partition_ids: impl IntoIterator<Item = u64>) -> ExecutionStats {
+        ExecutionStats {
+            partitions: partition_ids.into_iter().collect(),
+            local: true,
+            ..ExecutionStats::default()
+        }
+    }
+
+    pub fn add_select(
+        &mut self,
+        partition_ids: impl IntoIterator<Item = u64>,
+        node: &str,
+        bytes_received: u64,
+    ) {
+        self.partitions.extend(partition_ids);
+        self.nodes.insert(node.to_string());
+        self.bytes_received += bytes_received;
+    }
+
+    pub fn add_received_batches(&mut self, rows: u64, batches: u64) {
+        self.rows_received += rows;
+        self.batches_received += batches;
+    }
+
+    pub fn add_scan(&mut self, scan: &ScanStats) {
+        self.scan.merge(scan);
+    }
+
+    pub fn merge(&mut self, other: &ExecutionStats) {
+        self.partitions.extend(other.partitions.iter().cloned());
+        self.nodes.extend(other.nodes.iter().cloned());
+        self.bytes_received += other.bytes_received;
+        self.rows_received += other.rows_received;
+        self.batches_received += other.batches_received;
+        self.scan.merge(&other.scan);
+    }
+
+    pub fn partition_count(&self) -> usize {
+        self.partitions.len()
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.local
+    }
+
+    /// Workers queries were sent to.
+    pub fn nodes(&self) -> &BTreeSet<String> {
+        &self.nodes
+    }
+
+    /// Size of results sent by workers. Partition file sizes aren't known to the router.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn rows_received(&self) -> u64 {
+        self.rows_received
+    }
+
+    pub fn batches_received(&self) -> u64 {
+        self.batches_received
+    }
+
+    pub fn scan(&self) -> &ScanStats {
+        &self.scan
+    }
 }
 
 impl DataFrame {
     pub fn new(columns: Vec<Column>, data: Vec<Row>) -> DataFrame {
-        DataFrame { columns, data }
+        DataFrame {
+            columns,
+            data,
+            warnings: Vec::new(),
+            stats: ExecutionStats::default(),
+        }
+    }
+
+    pub fn with_warnings(self, warnings: Vec<String>) -> DataFrame {
+        DataFrame { warnings, ..self }
+    }
+
+    /// Non fatal errors happened during query execution, e.g. partitions skipped in best effort mode.
+    pub fn get_warnings(&self) -> &Vec<String> {
+        &self.warnings
+    }
+
+    pub fn with_stats(self, stats: ExecutionStats) -> DataFrame {
+        DataFrame { stats, ..self }
+    }
+
+    pub fn get_stats(&self) -> &ExecutionStats {
+        &self.stats
     }
 
     pub fn len(&self) -> usize {
@@ -82,6 +203,73 @@ impl DataFrame {
         Ok(DataFrame::new(new_columns, data))
     }
 
+    /// Appends `columns` of `table` absent in this frame filled with their defaults.
+    pub fn with_missing_columns(self, table: &Table) -> DataFrame {
+        let mut columns = self.columns;
+        let mut data = self.data;
+        for column in table.get_columns().iter() {
+            if columns.iter().any(|c| c.get_name() == column.get_name()) {
+                continue;
+            }
+            let default = table.column_default(column.get_name());
+            for row in data.iter_mut() {
+                row.push(default.clone());
+            }
+            columns.push(column.replace_index(columns.len()));
+        }
+        DataFrame {
+            columns,
+            data,
+            ..self
+        }
+    }
+
+    /// Boolean columns converted to integer ones with 1 for true and 0 for false. NULLs are kept.
+    pub fn with_booleans_as_ints(self) -> DataFrame {
+        let booleans = self
+            .columns
+            .iter()
+            .filter(|c| c.get_column_type() == &ColumnType::Boolean)
+            .map(|c| c.get_index())
+            .collect::<Vec<_>>();
+        if booleans.is_empty() {
+            return self;
+        }
+        let columns = self
+            .columns
+            .into_iter()
+            .map(|c| match c.get_column_type() {
+                ColumnType::Boolean => {
+                    Column::new(c.get_name().clone(), ColumnType::Int, c.get_index())
+                }
+                _ => c,
+            })
+            .collect();
+        let data = self
+            .data
+            .into_iter()
+            .map(|row| {
+                Row::new(
+                    row.values()
+                        .iter()
+                        .enumerate()
+                        .map(|(i, v)| match v {
+                            TableValue::Boolean(b) if booleans.contains(&i) => {
+                                TableValue::Int(*b as i64)
+                            }
+                            v => v.clone(),
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+        DataFrame {
+            columns,
+            data,
+            ..self
+        }
+    }
+
     pub fn to_execution_plan(
         &self,
         columns: &Vec<Column>,
@@ -166,6 +354,7 @@ pub struct ChunkStore {
     wal_store: Arc<dyn WALDataStore>,
     remote_fs: Arc<dyn RemoteFs>,
     chunk_size: usize,
+    memory_chunks: Arc<MemoryChunkStore>,
 }
 
 fn save<T: Serialize>(path: String, data: T) -> Result<(), CubeError> {
@@ -200,6 +389,25 @@ pub trait ChunkDataStore: Send + Sync {
     async fn get_chunk(&self, chunk: IdRow<Chunk>) -> Result<DataFrame, CubeError>;
     async fn download_chunk(&self, chunk: IdRow<Chunk>) -> Result<String, CubeError>;
     async fn delete_remote_chunk(&self, chunk: IdRow<Chunk>) -> Result<(), CubeError>;
+    /// Replaces `chunks` of `partition_id` with a single level 1 chunk sorted by the index key.
+    async fn merge_chunks(
+        &self,
+        partition_id: u64,
+        chunks: Vec<IdRow<Chunk>>,
+    ) -> Result<IdRow<Chunk>, CubeError>;
+    /// Writes tombstone chunks of deleted `data` rows of `table_id` to partitions of every index.
+    async fn add_tombstones(&self, table_id: u64, data: DataFrame) -> Result<(), CubeError>;
+    /// Writes `data` rows of `table_id` to partitions of every index. Chunks are left inactive
+    /// until they're activated by `swap_chunks` so rows of several calls can be committed at once.
+    async fn add_inactive_chunks(
+        &self,
+        table_id: u64,
+        data: DataFrame,
+    ) -> Result<Vec<u64>, CubeError>;
+    /// Copies rows of the default index into building `index_id` and activates it.
+    async fn backfill_index(&self, index_id: u64) -> Result<(), CubeError>;
+    /// Drops chunks replaced by compaction from memory of this node.
+    fn evict_in_memory_chunks(&self, chunk_ids: Vec<u64>);
 }
 
 impl WALStore {
@@ -279,12 +487,14 @@ impl ChunkStore {
         remote_fs: Arc<dyn RemoteFs>,
         wal_store: Arc<dyn WALDataStore>,
         chunk_size: usize,
+        memory_chunks: Arc<MemoryChunkStore>,
     ) -> Arc<ChunkStore> {
         let store = ChunkStore {
             meta_store,
             remote_fs,
             wal_store,
             chunk_size,
+            memory_chunks,
         };
 
         Arc::new(store)
@@ -294,6 +504,56 @@ impl ChunkStore {
         self.chunk_size
     }
 
+    /// Writes chunks of the WAL rows to partitions of every index of the table and activates
+    /// them. Chunks are pending in memory until then, see `MemoryChunkStore::add_pending`.
+    async fn partition_wal(
+        &self,
+        wal_id: u64,
+        table_id: u64,
+        data: DataFrame,
+        new_chunks: &mut Vec<IdRow<Chunk>>,
+    ) -> Result<(), CubeError> {
+        let mut partitioned_indexes = HashSet::new();
+        loop {
+            let indexes = self.meta_store.get_table_indexes(table_id).await?;
+            for index in indexes.iter() {
+                if !partitioned_indexes.insert(index.get_id()) {
+                    continue;
+                }
+                let chunks = self
+                    .partition_data_frame(
+                        index.get_id(),
+                        data.remap_columns(index.get_row().columns().clone())?,
+                        false,
+                        None,
+                    )
+                    .await?; // TODO dataframe clone
+                for chunk in chunks.iter() {
+                    self.memory_chunks
+                        .add_pending(chunk.get_id(), chunk.get_row().get_partition_id());
+                }
+                new_chunks.extend(chunks);
+            }
+
+            let activated = self
+                .meta_store
+                .activate_wal(
+                    wal_id,
+                    new_chunks.iter().map(|c| c.get_id()).collect(),
+                    indexes.len() as u64,
+                )
+                .await;
+            // Index created meanwhile is backfilled without rows of this WAL so they're written
+            // to it as well
+            if activated.is_err()
+                && self.meta_store.get_table_indexes(table_id).await?.len() != indexes.len()
+            {
+                continue;
+            }
+            return activated;
+        }
+    }
+
     pub fn chunk_file_name(chunk: IdRow<Chunk>) -> String {
         Self::chunk_remote_path(chunk.get_id())
     }
@@ -308,29 +568,25 @@ impl ChunkDataStore for ChunkStore {
     async fn partition(&self, wal_id: u64) -> Result<(), CubeError> {
         let wal = self.meta_store.get_wal(wal_id).await?;
         let table_id = wal.get_row().table_id();
-        let data = self.wal_store.get_wal(wal_id).await?;
-        let indexes = self.meta_store.get_table_indexes(table_id).await?;
+        let table = self.meta_store.get_table_by_id(table_id).await?;
+        // WAL may be written before columns were added to the table
+        let data = self
+            .wal_store
+            .get_wal(wal_id)
+            .await?
+            .with_missing_columns(table.get_row());
         let mut new_chunks = Vec::new();
-        for index in indexes.iter() {
-            new_chunks.append(
-                &mut self
-                    .partition_data_frame(
-                        index.get_id(),
-                        data.remap_columns(index.get_row().columns().clone())?,
-                    )
-                    .await?,
-            ); // TODO dataframe clone
+        let result = self
+            .partition_wal(wal_id, table_id, data, &mut new_chunks)
+            .await;
+        let chunk_ids = new_chunks.iter().map(|c| c.get_id()).collect::<Vec<_>>();
+        // Chunks of a failed WAL are never activated so their rows are dropped from memory
+        if result.is_ok() {
+            self.memory_chunks.activated(&chunk_ids);
+        } else {
+            self.memory_chunks.remove(&chunk_ids);
         }
-
-        self.meta_store
-            .activate_wal(
-                wal_id,
-                new_chunks.into_iter().map(|c| c.get_id()).collect(),
-                indexes.len() as u64,
-            )
-            .await?;
-
-        Ok(())
+        result
     }
 
     async fn repartition(&self, partition_id: u64) -> Result<(), CubeError> {
@@ -350,20 +606,29 @@ impl ChunkDataStore for ChunkStore {
         for chunk in chunks.into_iter() {
             let chunk_id = chunk.get_id();
             old_chunks.push(chunk_id);
+            let tombstone = chunk.get_row().is_tombstone();
+            // Moved rows keep their place among writes to the new partitions
+            let sequence = chunk.sequence();
             let data = self.get_chunk(chunk).await?;
             new_chunks.append(
                 &mut self
-                    .partition_data_frame(partition.get_row().get_index_id(), data)
+                    .partition_data_frame(
+                        partition.get_row().get_index_id(),
+                        data,
+                        tombstone,
+                        Some(sequence),
+                    )
                     .await?,
             )
         }
 
         self.meta_store
             .swap_chunks(
-                old_chunks,
+                old_chunks.clone(),
                 new_chunks.into_iter().map(|c| c.get_id()).collect(),
             )
             .await?;
+        self.evict_in_memory_chunks(old_chunks);
 
         Ok(())
     }
@@ -385,12 +650,17 @@ impl ChunkDataStore for ChunkStore {
             .index_table()
             .row_by_id_or_not_found(partition.get_row().get_index_id())
             .await?;
+        let table = self
+            .meta_store
+            .get_table_by_id(index.get_row().table_id())
+            .await?;
         let remote_path = ChunkStore::chunk_file_name(chunk);
         self.remote_fs.download_file(&remote_path).await?;
         let local_file = self.remote_fs.local_file(&remote_path).await?;
         Ok(
             tokio::task::spawn_blocking(move || -> Result<DataFrame, CubeError> {
-                let parquet = ParquetTableStore::new(index.get_row().clone(), 16384); // TODO config
+                let parquet = ParquetTableStore::new(index.get_row().clone(), 16384) // TODO config
+                    .with_defaults_of(table.get_row());
                 let rows = parquet.read_rows(&local_file)?;
                 Ok(DataFrame::new(index.get_row().get_columns().clone(), rows))
             })
@@ -424,6 +694,160 @@ impl ChunkDataStore for ChunkStore {
         self.remote_fs.delete_file(&remote_path).await?;
         Ok(())
     }
+
+    async fn merge_chunks(
+        &self,
+        partition_id: u64,
+        chunks: Vec<IdRow<Chunk>>,
+    ) -> Result<IdRow<Chunk>, CubeError> {
+        let partition = self.meta_store.get_partition(partition_id).await?;
+        let index = self
+            .meta_store
+            .index_table()
+            .row_by_id_or_not_found(partition.get_row().get_index_id())
+            .await?;
+        let columns = index.get_row().get_columns().clone();
+        let mut rows = Vec::new();
+        for chunk in chunks.iter() {
+            let mut data = self.get_chunk(chunk.clone()).await?;
+            rows.append(data.mut_rows());
+        }
+        let sort_key_size = index.get_row().sort_key_size();
+        rows.sort_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)));
+
+        let old_chunks = chunks.iter().map(|c| c.get_id()).collect::<Vec<_>>();
+        let new_chunk = self
+            .add_chunk(
+                index,
+                partition,
+                DataFrame::new(columns, rows),
+                1,
+                false,
+                chunks.iter().map(|c| c.sequence()).max(),
+            )
+            .await?;
+        self.meta_store
+            .swap_chunks(old_chunks.clone(), vec![new_chunk.get_id()])
+            .await?;
+        self.evict_in_memory_chunks(old_chunks);
+
+        Ok(new_chunk)
+    }
+
+    async fn add_tombstones(&self, table_id: u64, data: DataFrame) -> Result<(), CubeError> {
+        let indexes = self.meta_store.get_table_indexes(table_id).await?;
+        let mut new_chunks = Vec::new();
+        for index in indexes.iter() {
+            new_chunks.append(
+                &mut self
+                    .partition_data_frame(
+                        index.get_id(),
+                        data.remap_columns(index.get_row().columns().clone())?,
+                        true,
+                        None,
+                    )
+                    .await?,
+            );
+        }
+        self.meta_store
+            .swap_chunks(vec![], new_chunks.into_iter().map(|c| c.get_id()).collect())
+            .await
+    }
+
+    async fn add_inactive_chunks(
+        &self,
+        table_id: u64,
+        data: DataFrame,
+    ) -> Result<Vec<u64>, CubeError> {
+        let table = self.meta_store.get_table_by_id(table_id).await?;
+        let data = data.with_missing_columns(table.get_row());
+        let indexes = self.meta_store.get_table_indexes(table_id).await?;
+        let mut new_chunks = Vec::new();
+        for index in indexes.iter() {
+            new_chunks.append(
+                &mut self
+                    .partition_data_frame(
+                        index.get_id(),
+                        data.remap_columns(index.get_row().columns().clone())?,
+                        false,
+                        None,
+                    )
+                    .await?,
+            );
+        }
+        Ok(new_chunks.into_iter().map(|c| c.get_id()).collect())
+    }
+
+    async fn backfill_index(&self, index_id: u64) -> Result<(), CubeError> {
+        let index = self
+            .meta_store
+            .index_table()
+            .row_by_id_or_not_found(index_id)
+            .await?;
+        if !index.get_row().is_building() {
+            return Ok(());
+        }
+        let table = self
+            .meta_store
+            .get_table_by_id(index.get_row().table_id())
+            .await?;
+        let default_index = self.meta_store.get_default_index(table.get_id()).await?;
+        let mut new_chunks = Vec::new();
+        for (partition, chunks) in self.meta_store.start_index_backfill(index_id).await? {
+            let mut rows = Vec::new();
+            if let Some(remote_path) = partition.get_row().get_full_name(partition.get_id()) {
+                let local_file = self.remote_fs.download_file(&remote_path).await?;
+                let default_index = default_index.get_row().clone();
+                let table = table.get_row().clone();
+                rows = tokio::task::spawn_blocking(move || {
+                    ParquetTableStore::new(default_index, 16384) // TODO config
+                        .with_defaults_of(&table)
+                        .read_rows(&local_file)
+                })
+                .await??;
+            }
+            // Deleted rows are dropped instead of copying tombstones
+            let (tombstones, mut data_chunks): (Vec<_>, Vec<_>) =
+                chunks.into_iter().partition(|c| c.get_row().is_tombstone());
+            let mut deleted = DeletedRows::new(None);
+            for chunk in tombstones.into_iter() {
+                let sequence = chunk.sequence();
+                for row in self.get_chunk(chunk).await?.into_rows() {
+                    deleted.add(row, sequence);
+                }
+            }
+            // The partition file is older than all chunks
+            rows.retain(|r| !deleted.take(r, 0));
+            data_chunks.sort_by_key(|c| c.sequence());
+            for chunk in data_chunks.into_iter() {
+                let sequence = chunk.sequence();
+                let mut data = self.get_chunk(chunk).await?;
+                data.mut_rows().retain(|r| !deleted.take(r, sequence));
+                rows.append(data.mut_rows());
+            }
+            if rows.is_empty() {
+                continue;
+            }
+            let data = DataFrame::new(default_index.get_row().get_columns().clone(), rows)
+                .remap_columns(index.get_row().get_columns().clone())?;
+            new_chunks.append(
+                &mut self
+                    .partition_data_frame(index_id, data, false, None)
+                    .await?,
+            );
+        }
+        self.meta_store
+            .finish_index_backfill(
+                index_id,
+                new_chunks.into_iter().map(|c| c.get_id()).collect(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn evict_in_memory_chunks(&self, chunk_ids: Vec<u64>) {
+        self.memory_chunks.remove(&chunk_ids);
+    }
 }
 
 #[cfg(test)]
@@ -488,7 +912,10 @@ mod tests {
                     col.clone(),
                     None,
                     None,
+                    None,
                     Vec::new(),
+                    None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -534,8 +961,13 @@ mod tests {
             );
             let meta_store = RocksMetaStore::new(path, remote_fs.clone(), config.config_obj());
             let wal_store = WALStore::new(meta_store.clone(), remote_fs.clone(), 10);
-            let chunk_store =
-                ChunkStore::new(meta_store.clone(), remote_fs.clone(), wal_store.clone(), 10);
+            let chunk_store = ChunkStore::new(
+                meta_store.clone(),
+                remote_fs.clone(),
+                wal_store.clone(),
+                10,
+                MemoryChunkStore::new(0),
+            );
 
             let col = vec![
                 Column::new("foo_int".to_string(), ColumnType::Int, 0),
@@ -564,7 +996,10 @@ mod tests {
                     col.clone(),
                     None,
                     None,
+                    None,
                     vec![],
+                    None,
+                    None,
                 )
                 .await
                 .unwrap();
@@ -588,7 +1023,7 @@ mod tests {
             let partition = partitions[0].clone();
 
             let chunk = chunk_store
-                .add_chunk(index, partition, restored_wal)
+                .add_chunk(index, partition, restored_wal, 0, false, None)
                 .await
                 .unwrap();
             meta_store
@@ -607,13 +1042,127 @@ mod tests {
         let _ = fs::remove_dir_all(chunk_store_path.clone());
         let _ = fs::remove_dir_all(chunk_remote_store_path.clone());
     }
+
+    /// Writes a chunk of 35 rows with `foo_int` values from 34 down to 0 and activates it.
+    async fn write_test_chunk(name: &str, memory_chunks: Arc<MemoryChunkStore>) -> IdRow<Chunk> {
+        let config = Config::test(name);
+        let path = format!("/tmp/test_{}", name);
+        let store_path = path.clone() + "_store";
+        let remote_store_path = path.clone() + "_remote_store";
+        let _ = DB::destroy(&Options::default(), &path);
+        let _ = fs::remove_dir_all(store_path.clone());
+        let _ = fs::remove_dir_all(remote_store_path.clone());
+        let chunk = {
+            let remote_fs = LocalDirRemoteFs::new(
+                PathBuf::from(store_path.clone()),
+                PathBuf::from(remote_store_path.clone()),
+            );
+            let meta_store = RocksMetaStore::new(&path, remote_fs.clone(), config.config_obj());
+            let wal_store = WALStore::new(meta_store.clone(), remote_fs.clone(), 10);
+            let chunk_store = ChunkStore::new(
+                meta_store.clone(),
+                remote_fs.clone(),
+                wal_store.clone(),
+                10,
+                memory_chunks,
+            );
+
+            let col = vec![
+                Column::new("foo_int".to_string(), ColumnType::Int, 0),
+                Column::new("foo".to_string(), ColumnType::String, 1),
+            ];
+            let rows = (0..35)
+                .map(|i| {
+                    Row::new(vec![
+                        TableValue::Int(34 - i),
+                        TableValue::String(format!("Foo {}", 34 - i)),
+                    ])
+                })
+                .collect::<Vec<_>>();
+            meta_store
+                .create_schema("foo".to_string(), false)
+                .await
+                .unwrap();
+            let table = meta_store
+                .create_table(
+                    "foo".to_string(),
+                    "bar".to_string(),
+                    col.clone(),
+                    None,
+                    None,
+                    None,
+                    vec![],
+                    None,
+                    None,
+                )
+                .await
+                .unwrap();
+            let index = meta_store.get_default_index(table.get_id()).await.unwrap();
+            let partition = meta_store
+                .get_active_partitions_by_index_id(index.get_id())
+                .await
+                .unwrap()[0]
+                .clone();
+            let chunk = chunk_store
+                .add_chunk(index, partition, DataFrame::new(col, rows), 0, false, None)
+                .await
+                .unwrap();
+            meta_store
+                .swap_chunks(Vec::new(), vec![chunk.get_id()])
+                .await
+                .unwrap();
+            meta_store.get_chunk(chunk.get_id()).await.unwrap()
+        };
+        let _ = DB::destroy(&Options::default(), &path);
+        let _ = fs::remove_dir_all(store_path);
+        let _ = fs::remove_dir_all(remote_store_path);
+        chunk
+    }
+
+    #[actix_rt::test]
+    async fn chunk_min_max_values() {
+        let chunk = write_test_chunk("chunk_min_max_values", MemoryChunkStore::new(0)).await;
+        let first_value = |row: &Option<Row>| row.as_ref().map(|r| r.values()[0].clone());
+        assert_eq!(
+            first_value(chunk.get_row().get_min_val()),
+            Some(TableValue::Int(0))
+        );
+        assert_eq!(
+            first_value(chunk.get_row().get_max_val()),
+            Some(TableValue::Int(34))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn written_chunk_kept_in_memory() {
+        let memory_chunks = MemoryChunkStore::new(1 << 20);
+        let chunk = write_test_chunk("written_chunk_kept_in_memory", memory_chunks.clone()).await;
+        assert_eq!(
+            memory_chunks
+                .get(chunk.get_id())
+                .unwrap()
+                .iter()
+                .map(|b| b.num_rows())
+                .sum::<usize>(),
+            35
+        );
+
+        let memory_chunks = MemoryChunkStore::new(0);
+        let chunk =
+            write_test_chunk("written_chunk_not_kept_in_memory", memory_chunks.clone()).await;
+        assert!(memory_chunks.get(chunk.get_id()).is_none());
+    }
 }
 
 impl ChunkStore {
+    /// Writes `data` to chunks of the active partitions of `index_id`. Chunks moved from another
+    /// partition pass the `sequence` of the chunk they replace.
     async fn partition_data_frame(
         &self,
         index_id: u64,
         data: DataFrame,
+        tombstone: bool,
+        sequence: Option<u64>,
     ) -> Result<Vec<IdRow<Chunk>>, CubeError> {
         let index = self
             .meta_store
@@ -653,6 +1202,9 @@ impl ChunkStore {
                         index.clone(),
                         partition,
                         DataFrame::new(columns.clone(), to_write),
+                        0,
+                        tombstone,
+                        sequence,
                     )
                     .await?,
                 );
@@ -670,19 +1222,43 @@ impl ChunkStore {
         index: IdRow<Index>,
         partition: IdRow<Partition>,
         data: DataFrame,
+        level: u64,
+        tombstone: bool,
+        sequence: Option<u64>,
     ) -> Result<IdRow<Chunk>, CubeError> {
+        let sort_key_size = index.get_row().sort_key_size();
+        let sort_key = |row: &Row| Row::new(row.values()[..sort_key_size as usize].to_vec());
+        let min_value = data
+            .get_rows()
+            .iter()
+            .min_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)))
+            .map(sort_key);
+        let max_value = data
+            .get_rows()
+            .iter()
+            .max_by(|a, b| a.sort_key(sort_key_size).cmp(&b.sort_key(sort_key_size)))
+            .map(sort_key);
+        // Tombstones are read from their files by scans
+        let in_memory = self.memory_chunks.is_enabled() && !tombstone;
         let chunk = self
             .meta_store
-            .create_chunk(partition.get_id(), data.len())
+            .create_chunk(
+                Chunk::new(partition.get_id(), data.len())
+                    .with_min_max(min_value, max_value)
+                    .with_level(level)
+                    .with_tombstone(tombstone)
+                    .with_sequence(sequence),
+            )
             .await?;
         trace!("New chunk allocated during partitioning: {:?}", chunk);
         let remote_path = ChunkStore::chunk_file_name(chunk.clone()).clone();
         let local_file = self.remote_fs.local_file(&remote_path).await?;
+        let file_to_write = local_file.clone();
         tokio::task::spawn_blocking(move || -> Result<(), CubeError> {
             let parquet = ParquetTableStore::new(index.get_row().clone(), 16384); // TODO config
             parquet.merge_rows(
                 None,
-                vec![local_file],
+                vec![file_to_write],
                 data.into_rows(),
                 index.get_row().sort_key_size(),
             )?;
@@ -692,6 +1268,17 @@ impl ChunkStore {
         self.remote_fs
             .upload_file(&ChunkStore::chunk_file_name(chunk.clone()))
             .await?;
+        if in_memory {
+            // Read back while the file is hot so batches match the ones scans of the file produce
+            let batches = collect(Arc::new(ParquetExec::try_from_path(
+                &local_file,
+                None,
+                4096,
+                1,
+            )?))
+            .await?;
+            self.memory_chunks.add(chunk.get_id(), batches);
+        }
         Ok(chunk)
     }
 }
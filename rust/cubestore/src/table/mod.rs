@@ -1,4 +1,5 @@
 use crate::CubeError;
+use arrow::datatypes::TimeUnit;
 use chrono::{SecondsFormat, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
@@ -26,6 +27,18 @@ impl TimestampValue {
         TimestampValue { unix_nano }
     }
 
+    /// Timestamp from a value of arrow `Timestamp(unit, _)` type. Arrow timestamps are UTC
+    /// instants, time zones are display hints only. Returns `None` if nanoseconds overflow.
+    pub fn from_arrow(value: i64, unit: &TimeUnit) -> Option<TimestampValue> {
+        let nanos_in_unit = match unit {
+            TimeUnit::Second => 1_000_000_000,
+            TimeUnit::Millisecond => 1_000_000,
+            TimeUnit::Microsecond => 1_000,
+            TimeUnit::Nanosecond => 1,
+        };
+        Some(TimestampValue::new(value.checked_mul(nanos_in_unit)?))
+    }
+
     pub fn get_time_stamp(&self) -> i64 {
         self.unix_nano
     }
@@ -134,3 +147,59 @@ pub trait TableStore {
     //     row_group_filter: Option<Arc<dyn Fn(&RowGroupMetaData) -> bool + Send + Sync>>,
     // ) -> Result<Arc<dyn ExecutionPlan + Send + Sync>, CubeError>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamp_from_arrow_units() {
+        let nanos = 1_609_459_200_000_000_000;
+        let cases = vec![
+            (1_609_459_200, TimeUnit::Second),
+            (1_609_459_200_000, TimeUnit::Millisecond),
+            (1_609_459_200_000_000, TimeUnit::Microsecond),
+            (1_609_459_200_000_000_000, TimeUnit::Nanosecond),
+        ];
+        for (value, unit) in cases.iter() {
+            assert_eq!(
+                TimestampValue::from_arrow(*value, unit),
+                Some(TimestampValue::new(nanos)),
+                "{:?}",
+                unit
+            );
+            assert_eq!(
+                TimestampValue::from_arrow(-value, unit),
+                Some(TimestampValue::new(-nanos)),
+                "{:?}",
+                unit
+            );
+        }
+        assert_eq!(
+            TimestampValue::from_arrow(-1, &TimeUnit::Microsecond)
+                .unwrap()
+                .to_string(),
+            "1969-12-31T23:59:59.999Z"
+        );
+    }
+
+    #[test]
+    fn timestamp_from_arrow_overflow() {
+        assert_eq!(
+            TimestampValue::from_arrow(i64::MAX / 1_000, &TimeUnit::Microsecond),
+            Some(TimestampValue::new(i64::MAX / 1_000 * 1_000))
+        );
+        assert_eq!(
+            TimestampValue::from_arrow(i64::MAX / 1_000 + 1, &TimeUnit::Microsecond),
+            None
+        );
+        assert_eq!(
+            TimestampValue::from_arrow(i64::MIN / 1_000_000_000 - 1, &TimeUnit::Second),
+            None
+        );
+        assert_eq!(
+            TimestampValue::from_arrow(i64::MIN, &TimeUnit::Nanosecond),
+            Some(TimestampValue::new(i64::MIN))
+        );
+    }
+}
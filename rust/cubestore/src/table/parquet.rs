@@ -1,7 +1,9 @@
 use super::TimestampValue;
+use crate::metastore::table::Table;
 use crate::metastore::{Column, ColumnType, Index};
 use crate::table::{Row, RowSortKey, TableStore, TableValue};
 use crate::CubeError;
+use arrow::datatypes::TimeUnit;
 use parquet::column::reader::ColumnReader;
 use parquet::column::writer::ColumnWriter;
 use parquet::data_type::*;
@@ -20,6 +22,8 @@ use std::sync::Arc;
 pub struct ParquetTableStore {
     table: Index,
     row_group_size: usize,
+    /// Values of index columns for files written before the columns were added.
+    defaults: Vec<TableValue>,
 }
 
 pub struct RowParquetWriter {
@@ -35,6 +39,8 @@ enum ColumnAccessor {
     Int(Vec<i64>),
     Boolean(Vec<bool>),
     // Decimal(Vec<i64>),
+    /// Column the file was written without.
+    Missing(TableValue),
 }
 
 pub struct RowParquetReader<'a> {
@@ -65,7 +71,8 @@ impl TableStore for ParquetTableStore {
             return Ok(split_writer.close()?);
         }
 
-        let mut reader = RowParquetReader::open(&self.table, source_file.unwrap(), None)?;
+        let mut reader =
+            RowParquetReader::open(&self.table, source_file.unwrap(), None, &self.defaults)?;
         let mut right_position = 0;
         let total_row_number =
             reader.parquet_reader.metadata().file_metadata().num_rows() as usize + rows.len();
@@ -88,7 +95,7 @@ impl TableStore for ParquetTableStore {
 
     fn read_rows(&self, file: &str) -> Result<Vec<Row>, CubeError> {
         let mut result = Vec::<Row>::new();
-        let mut reader = RowParquetReader::open(&self.table, file, None)?;
+        let mut reader = RowParquetReader::open(&self.table, file, None, &self.defaults)?;
         for row_group_index in 0..reader.parquet_reader.num_row_groups() {
             let mut rows = reader.read_rows(row_group_index)?;
             result.append(&mut rows);
@@ -103,7 +110,7 @@ impl TableStore for ParquetTableStore {
         limit: usize,
     ) -> Result<Vec<Row>, CubeError> {
         let mut result = Vec::<Row>::new();
-        let mut reader = RowParquetReader::open(&self.table, file, Some(columns))?;
+        let mut reader = RowParquetReader::open(&self.table, file, Some(columns), &self.defaults)?;
         'outer: for row_group_index in 0..reader.parquet_reader.num_row_groups() {
             let row_group = reader.read_rows(row_group_index)?;
             for row in &row_group {
@@ -133,12 +140,25 @@ impl TableStore for ParquetTableStore {
 
 impl ParquetTableStore {
     pub fn new(table: Index, row_group_size: usize) -> ParquetTableStore {
+        let defaults = vec![TableValue::Null; table.get_columns().len()];
         ParquetTableStore {
             table,
             row_group_size,
+            defaults,
         }
     }
 
+    /// Reads columns added to `table` after a file was written as their defaults.
+    pub fn with_defaults_of(self, table: &Table) -> ParquetTableStore {
+        let defaults = self
+            .table
+            .get_columns()
+            .iter()
+            .map(|c| table.column_default(c.get_name()))
+            .collect();
+        ParquetTableStore { defaults, ..self }
+    }
+
     fn merge_sort(
         left: Vec<Row>,
         right: &Vec<Row>,
@@ -181,14 +201,28 @@ impl<'a> RowParquetReader<'a> {
         table: &'a Index,
         file: &'a str,
         columns_to_read: Option<&'a Vec<Column>>,
+        defaults: &[TableValue],
     ) -> Result<RowParquetReader<'a>, CubeError> {
         let file = File::open(file)?;
         let parquet_reader = SerializedFileReader::new(file)?;
+        // Columns are only ever added after existing ones
+        let file_columns = parquet_reader
+            .metadata()
+            .file_metadata()
+            .schema_descr()
+            .num_columns();
 
         let column_with_buffer = columns_to_read
             .unwrap_or(table.get_columns())
             .iter()
             .map(|c| {
+                if c.get_index() >= file_columns {
+                    let default = defaults
+                        .get(c.get_index())
+                        .cloned()
+                        .unwrap_or(TableValue::Null);
+                    return (c, c.get_index(), ColumnAccessor::Missing(default), None);
+                }
                 (
                     c,
                     c.get_index(),
@@ -215,6 +249,9 @@ impl<'a> RowParquetReader<'a> {
         let row_group = self.parquet_reader.get_row_group(row_group_index)?;
         let mut values_read = 0;
         for (_, index, column_accessor, def_levels) in &mut self.column_with_buffer {
+            if let ColumnAccessor::Missing(_) = column_accessor {
+                continue;
+            }
             let mut col_reader = row_group.get_column_reader(*index).unwrap();
             match column_accessor {
                 ColumnAccessor::Bytes(buffer) => {
@@ -262,6 +299,7 @@ impl<'a> RowParquetReader<'a> {
                         );
                     }
                 }
+                ColumnAccessor::Missing(_) => {}
             };
         }
         Ok(values_read)
@@ -274,6 +312,12 @@ impl<'a> RowParquetReader<'a> {
             vec_result.push(Row::new(Vec::with_capacity(self.column_with_buffer.len())))
         }
         for (col, _, column_accessor, def_levels) in &self.column_with_buffer {
+            if let ColumnAccessor::Missing(value) = column_accessor {
+                for row in vec_result.iter_mut() {
+                    row.push(value.clone());
+                }
+                continue;
+            }
             let mut cur_value_index = 0;
             match def_levels {
                 Some(levels) => {
@@ -341,9 +385,18 @@ impl<'a> RowParquetReader<'a> {
                                 for i in 0..values_read {
                                     if levels[i] == 1 {
                                         let value = buffer[cur_value_index];
-                                        vec_result[i].push(TableValue::Timestamp(
-                                            TimestampValue::new(value * 1000 as i64),
-                                        ));
+                                        let timestamp = TimestampValue::from_arrow(
+                                            value,
+                                            &TimeUnit::Microsecond,
+                                        )
+                                        .ok_or_else(|| {
+                                            CubeError::corrupted_data(format!(
+                                                "Timestamp {} of column '{}' is out of range",
+                                                value,
+                                                col.get_name()
+                                            ))
+                                        })?;
+                                        vec_result[i].push(TableValue::Timestamp(timestamp));
                                         cur_value_index += 1;
                                     } else {
                                         vec_result[i].push(TableValue::Null);
@@ -830,8 +883,8 @@ mod tests {
 
     #[test]
     fn gutter() {
-        let store = ParquetTableStore {
-            table: Index::try_new(
+        let store = ParquetTableStore::new(
+            Index::try_new(
                 "foo".to_string(),
                 1,
                 vec![
@@ -851,8 +904,8 @@ mod tests {
                 1,
             )
             .unwrap(),
-            row_group_size: 7,
-        };
+            7,
+        );
         let file_name = "foo.parquet";
 
         let mut first_rows = (0..40)
@@ -1005,9 +1058,13 @@ mod tests {
     #[bench]
     fn filter_count(b: &mut Bencher) {
         if let Ok((store, columns_to_read)) = prepare_donors() {
-            let mut reader =
-                RowParquetReader::open(&store.table, "Donors.parquet", Some(&columns_to_read))
-                    .unwrap();
+            let mut reader = RowParquetReader::open(
+                &store.table,
+                "Donors.parquet",
+                Some(&columns_to_read),
+                &store.defaults,
+            )
+            .unwrap();
 
             b.iter(|| {
                 let start = SystemTime::now();
@@ -1120,8 +1177,8 @@ mod tests {
      */
 
     fn prepare_donors() -> Result<(ParquetTableStore, Vec<Column>), io::Error> {
-        let store = ParquetTableStore {
-            table: Index::try_new(
+        let store = ParquetTableStore::new(
+            Index::try_new(
                 "donors".to_string(),
                 1,
                 vec![
@@ -1134,8 +1191,8 @@ mod tests {
                 6,
             )
             .unwrap(),
-            row_group_size: 16384,
-        };
+            16384,
+        );
 
         let column_mapping = vec![1, 0, 2, 3, 4];
 